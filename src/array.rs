@@ -13,13 +13,13 @@ pub use stickyimmix::{AllocObject, ArraySize};
 
 use crate::containers::{
     AnyContainerFromPairList, AnyContainerFromSlice, Container, ContainerFromSlice,
-    FillAnyContainer, FillContainer, IndexedAnyContainer, IndexedContainer, SliceableContainer,
-    StackAnyContainer, StackContainer,
+    ContainerVersion, FillAnyContainer, FillContainer, IndexedAnyContainer, IndexedContainer,
+    SliceGuard, SliceableContainer, StackAnyContainer, StackContainer, VersionedContainer,
 };
 use crate::error::{ErrorKind, RuntimeError};
 use crate::headers::TypeList;
 use crate::memory::MutatorView;
-use crate::printer::Print;
+use crate::printer::{print_elements, Print};
 use crate::rawarray::{default_array_growth, RawArray, DEFAULT_ARRAY_SIZE};
 use crate::safeptr::{MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
 use crate::taggedptr::Value;
@@ -35,11 +35,15 @@ const EXPOSED_MUTABLY: isize = 1;
 /// Since SliceableContainer allows mutable access to the interior
 /// of the array, RefCell-style runtime semantics are employed to
 /// prevent the array being modified outside of the slice borrow.
+///
+/// Implements VersionedContainer too: each push, pop, fill or clear, and each reallocation,
+/// changes the version, so that an `ArrayIter` can detect a change made while it is in use.
 #[derive(Clone)]
 pub struct Array<T: Sized + Clone> {
     length: Cell<ArraySize>,
     data: Cell<RawArray<T>>,
     borrow: Cell<BorrowFlag>,
+    version: Cell<ContainerVersion>,
 }
 
 /// Internal implementation
@@ -62,7 +66,9 @@ impl<T: Sized + Clone> Array<T> {
     where
         Array<T>: AllocObject<TypeList> + ContainerFromSlice<T>,
     {
-        from_array.access_slice(mem, |items| ContainerFromSlice::from_slice(mem, items))
+        // copy the items out first so that the new array is not allocated while the slice is live
+        let items = from_array.access_slice(mem, |_, items| items.to_vec());
+        ContainerFromSlice::from_slice(mem, &items)
     }
 
     /// Allocate a new instance on the heap with pre-allocated capacity
@@ -76,6 +82,28 @@ impl<T: Sized + Clone> Array<T> {
         mem.alloc(Array::with_capacity(mem, capacity)?)
     }
 
+    /// Return an error if the array is exposed as a slice, otherwise record that the array is
+    /// about to be changed
+    fn begin_change(&self) -> Result<(), RuntimeError> {
+        if self.borrow.get() != INTERIOR_ONLY {
+            return Err(RuntimeError::new(ErrorKind::MutableBorrowError));
+        }
+
+        self.version.set(self.version.get().wrapping_add(1));
+        Ok(())
+    }
+
+    /// Return an iterator over copies of the items of the array
+    pub fn iter<'guard>(&'guard self, guard: &'guard dyn MutatorScope) -> ArrayIter<'guard, T> {
+        ArrayIter {
+            array: self,
+            guard,
+            version: self.version.get(),
+            index: 0,
+            done: false,
+        }
+    }
+
     /// Return a bounds-checked pointer to the object at the given index
     fn get_offset(&self, index: ArraySize) -> Result<*mut T, RuntimeError> {
         if index >= self.length.get() {
@@ -163,6 +191,7 @@ impl<T: Sized + Clone> Container<T> for Array<T> {
             length: Cell::new(0),
             data: Cell::new(RawArray::new()),
             borrow: Cell::new(INTERIOR_ONLY),
+            version: Cell::new(0),
         }
     }
 
@@ -174,16 +203,14 @@ impl<T: Sized + Clone> Container<T> for Array<T> {
             length: Cell::new(0),
             data: Cell::new(RawArray::with_capacity(mem, capacity)?),
             borrow: Cell::new(INTERIOR_ONLY),
+            version: Cell::new(0),
         })
     }
 
     fn clear<'guard>(&self, _guard: &'guard MutatorView) -> Result<(), RuntimeError> {
-        if self.borrow.get() != INTERIOR_ONLY {
-            Err(RuntimeError::new(ErrorKind::MutableBorrowError))
-        } else {
-            self.length.set(0);
-            Ok(())
-        }
+        self.begin_change()?;
+        self.length.set(0);
+        Ok(())
     }
 
     fn length(&self) -> ArraySize {
@@ -203,16 +230,15 @@ impl<T: Sized + Clone> FillContainer<T> for Array<T> {
         if length > size {
             Ok(())
         } else {
+            self.begin_change()?;
+
             let mut array = self.data.get(); // Takes a copy
 
             let capacity = array.capacity();
 
             if size > capacity {
-                if capacity == 0 {
-                    array.resize(mem, DEFAULT_ARRAY_SIZE)?;
-                } else {
-                    array.resize(mem, default_array_growth(capacity)?)?;
-                }
+                // grow by at least the usual amount, or to the requested size if that is larger
+                array.resize(mem, default_array_growth(capacity)?.max(size))?;
                 // Replace the struct's copy with the resized RawArray object
                 self.data.set(array);
             }
@@ -231,9 +257,7 @@ impl<T: Sized + Clone> FillContainer<T> for Array<T> {
 impl<T: Sized + Clone> StackContainer<T> for Array<T> {
     /// Push can trigger an underlying array resize, hence it requires the ability to allocate
     fn push<'guard>(&self, mem: &'guard MutatorView, item: T) -> Result<(), RuntimeError> {
-        if self.borrow.get() != INTERIOR_ONLY {
-            return Err(RuntimeError::new(ErrorKind::MutableBorrowError));
-        }
+        self.begin_change()?;
 
         let length = self.length.get();
         let mut array = self.data.get(); // Takes a copy
@@ -258,15 +282,12 @@ impl<T: Sized + Clone> StackContainer<T> for Array<T> {
     /// Pop returns None if the container is empty, otherwise moves the last item of the array
     /// out to the caller.
     fn pop<'guard>(&self, guard: &'guard dyn MutatorScope) -> Result<T, RuntimeError> {
-        if self.borrow.get() != INTERIOR_ONLY {
-            return Err(RuntimeError::new(ErrorKind::MutableBorrowError));
-        }
-
         let length = self.length.get();
 
         if length == 0 {
             Err(RuntimeError::new(ErrorKind::BoundsError))
         } else {
+            self.begin_change()?;
             let last = length - 1;
             let item = self.read(guard, last)?;
             self.length.set(last);
@@ -313,26 +334,96 @@ impl<T: Sized + Clone> IndexedContainer<T> for Array<T> {
 impl<T: Sized + Clone> SliceableContainer<T> for Array<T> {
     fn access_slice<'guard, F, R>(&self, guard: &'guard dyn MutatorScope, f: F) -> R
     where
-        F: FnOnce(&mut [T]) -> R,
+        F: FnOnce(&'guard SliceGuard, &mut [T]) -> R,
     {
+        // Restore the flag on drop so that a panic inside `f` does not leave the array
+        // permanently borrowed. The previous value is restored rather than INTERIOR_ONLY so that
+        // the end of a nested access doesn't release the outer one.
+        let _reset = BorrowReset(&self.borrow, self.borrow.get());
         self.borrow.set(EXPOSED_MUTABLY);
         let slice = unsafe { self.as_slice(guard) };
-        let result = f(slice);
-        self.borrow.set(INTERIOR_ONLY);
-        result
+        f(SliceGuard::get(guard), slice)
+    }
+}
+
+impl<T: Sized + Clone> VersionedContainer<T> for Array<T> {
+    fn version(&self) -> ContainerVersion {
+        self.version.get()
     }
 }
 
+/// An iterator over the items of an Array, made by `Array::iter()`. If the array is changed after
+/// the iterator is made, the next item is a MutatedDuringIteration error and the iteration ends.
+pub struct ArrayIter<'guard, T: Sized + Clone> {
+    array: &'guard Array<T>,
+    guard: &'guard dyn MutatorScope,
+    version: ContainerVersion,
+    index: ArraySize,
+    done: bool,
+}
+
+impl<'guard, T: Sized + Clone> Iterator for ArrayIter<'guard, T> {
+    type Item = Result<T, RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if let Err(e) = self.array.check_version(self.version) {
+            self.done = true;
+            return Some(Err(e));
+        }
+
+        if self.index >= self.array.length() {
+            self.done = true;
+            return None;
+        }
+
+        let item = self.array.read(self.guard, self.index);
+        self.index += 1;
+        Some(item)
+    }
+}
+
+/// Resets an Array borrow flag to its previous value when dropped
+struct BorrowReset<'a>(&'a Cell<BorrowFlag>, BorrowFlag);
+
+impl<'a> Drop for BorrowReset<'a> {
+    fn drop(&mut self) {
+        self.0.set(self.1);
+    }
+}
+
+/// Print an array of unboxed integers as `#<tag>(1 2 3)`
+fn print_integers<'guard, T>(
+    array: &Array<T>,
+    guard: &'guard dyn MutatorScope,
+    tag: &str,
+    f: &mut fmt::Formatter,
+) -> fmt::Result
+where
+    T: Sized + Clone + fmt::Display,
+{
+    let open = format!("#{}(", tag);
+    let length = array.length() as usize;
+
+    print_elements(f, &open, " ", ")", length, 0..array.length(), |f, i| {
+        let item = IndexedContainer::get(array, guard, i).expect("Failed to read from array");
+        write!(f, "{}", item)
+    })
+}
+
 /// Array of u8
 pub type ArrayU8 = Array<u8>;
 
 impl Print for ArrayU8 {
     fn print<'guard>(
         &self,
-        _guard: &'guard dyn MutatorScope,
+        guard: &'guard dyn MutatorScope,
         f: &mut fmt::Formatter,
     ) -> fmt::Result {
-        write!(f, "ArrayU8[...]")
+        print_integers(self, guard, "u8", f)
     }
 }
 
@@ -342,10 +433,10 @@ pub type ArrayU16 = Array<u16>;
 impl Print for ArrayU16 {
     fn print<'guard>(
         &self,
-        _guard: &'guard dyn MutatorScope,
+        guard: &'guard dyn MutatorScope,
         f: &mut fmt::Formatter,
     ) -> fmt::Result {
-        write!(f, "ArrayU16[...]")
+        print_integers(self, guard, "u16", f)
     }
 }
 
@@ -355,10 +446,10 @@ pub type ArrayU32 = Array<u32>;
 impl Print for ArrayU32 {
     fn print<'guard>(
         &self,
-        _guard: &'guard dyn MutatorScope,
+        guard: &'guard dyn MutatorScope,
         f: &mut fmt::Formatter,
     ) -> fmt::Result {
-        write!(f, "ArrayU32[...]")
+        print_integers(self, guard, "u32", f)
     }
 }
 
@@ -374,16 +465,15 @@ impl FillAnyContainer for Array<TaggedCellPtr> {
         if length > size {
             Ok(())
         } else {
+            self.begin_change()?;
+
             let mut array = self.data.get(); // Takes a copy
 
             let capacity = array.capacity();
 
             if size > capacity {
-                if capacity == 0 {
-                    array.resize(mem, DEFAULT_ARRAY_SIZE)?;
-                } else {
-                    array.resize(mem, default_array_growth(capacity)?)?;
-                }
+                // grow by at least the usual amount, or to the requested size if that is larger
+                array.resize(mem, default_array_growth(capacity)?.max(size))?;
                 // Replace the struct's copy with the resized RawArray object
                 self.data.set(array);
             }
@@ -459,6 +549,7 @@ impl AnyContainerFromPairList for Array<TaggedCellPtr> {
         mem: &'guard MutatorView,
         pair_list: TaggedScopedPtr<'guard>,
     ) -> Result<(), RuntimeError> {
+        self.begin_change()?;
         self.length.set(0);
 
         let mut head = pair_list;
@@ -511,28 +602,23 @@ impl Print for Array<TaggedCellPtr> {
         guard: &'guard dyn MutatorScope,
         f: &mut fmt::Formatter,
     ) -> fmt::Result {
-        write!(f, "[")?;
-
-        for i in 0..self.length() {
-            if i > 1 {
-                write!(f, ", ")?;
-            }
+        let length = self.length() as usize;
 
+        print_elements(f, "[", ", ", "]", length, 0..self.length(), |f, i| {
             let ptr =
                 IndexedAnyContainer::get(self, guard, i).expect("Failed to read ptr from array");
 
-            fmt::Display::fmt(&ptr.value(), f)?;
-        }
-
-        write!(f, "]")
+            fmt::Display::fmt(&ptr.value(), f)
+        })
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::{
-        AnyContainerFromPairList, Array, Container, IndexedAnyContainer, IndexedContainer,
-        StackAnyContainer, StackContainer,
+        AnyContainerFromPairList, Array, Container, FillContainer, IndexedAnyContainer,
+        IndexedContainer, SliceableContainer, StackAnyContainer, StackContainer,
+        VersionedContainer,
     };
     use crate::error::{ErrorKind, RuntimeError};
     use crate::memory::{Memory, Mutator, MutatorView};
@@ -612,6 +698,73 @@ mod test {
         mem.mutate(&test, ()).unwrap();
     }
 
+    #[test]
+    fn array_iter_detects_changes() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(
+                &self,
+                view: &MutatorView,
+                _input: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                let array: Array<i64> = Array::new();
+                for i in 0..4 {
+                    array.push(view, i)?;
+                }
+
+                let items: Result<Vec<i64>, RuntimeError> = array.iter(view).collect();
+                assert!(items? == vec![0, 1, 2, 3]);
+
+                // replacing an item in place is not a change of version
+                let version = array.version();
+                let mut iter = array.iter(view);
+                assert!(iter.next() == Some(Ok(0)));
+                array.set(view, 1, 10)?;
+                assert!(iter.next() == Some(Ok(10)));
+                assert!(array.check_version(version).is_ok());
+
+                // pushing is, and ends the iteration with an error
+                array.push(view, 4)?;
+                match iter.next() {
+                    Some(Err(e)) => assert!(*e.error_kind() == ErrorKind::MutatedDuringIteration),
+                    _ => panic!("expected a MutatedDuringIteration error"),
+                }
+                assert!(iter.next().is_none());
+                assert!(array.check_version(version).is_err());
+
+                let mut iter = array.iter(view);
+                array.pop(view)?;
+                assert!(iter.next().unwrap().is_err());
+
+                let mut iter = array.iter(view);
+                array.fill(view, 100, 0)?;
+                assert!(iter.next().unwrap().is_err());
+
+                // growing the array can't reallocate it while a slice of it is live, even in a
+                // nested access
+                let grown = array.access_slice(view, |_, _| {
+                    array.access_slice(view, |_, _| ());
+                    array.fill(view, 1000, 0)
+                });
+                match grown {
+                    Err(e) => assert!(*e.error_kind() == ErrorKind::MutableBorrowError),
+                    Ok(_) => panic!("fill should fail while the array is borrowed"),
+                }
+                assert!(array.length() == 100);
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
+
     #[test]
     fn arrayany_tagged_pointers() {
         let mem = Memory::new();
@@ -682,6 +835,12 @@ mod test {
                 // array storage should have been reallocated
                 assert!(ptr_before != ptr_realloc);
 
+                // an array with a capacity of one must grow too
+                let small: Array<TaggedCellPtr> = Array::with_capacity(view, 1)?;
+                StackAnyContainer::push(&small, view, view.nil())?;
+                StackAnyContainer::push(&small, view, view.nil())?;
+                assert!(small.data.get().capacity() >= 2);
+
                 Ok(())
             }
         }
@@ -739,4 +898,84 @@ mod test {
         let test = Test {};
         mem.mutate(&test, ()).unwrap();
     }
+
+    #[test]
+    fn array_any_print() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(
+                &self,
+                view: &MutatorView,
+                _input: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                let array: Array<TaggedCellPtr> = Array::new();
+                let array = view.alloc_tagged(array)?;
+                assert!(format!("{}", array) == "[]");
+
+                if let Value::List(list) = *array {
+                    StackAnyContainer::push(&*list, view, view.lookup_sym("a"))?;
+                    assert!(format!("{}", array) == "[a]");
+
+                    StackAnyContainer::push(&*list, view, view.number(2))?;
+                    StackAnyContainer::push(&*list, view, view.nil())?;
+                    assert!(format!("{}", array) == "[a, 2, nil]");
+                } else {
+                    panic!("expected a List!")
+                }
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
+
+    #[test]
+    fn array_print_elides_long_arrays() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(
+                &self,
+                view: &MutatorView,
+                _input: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                let list: Array<TaggedCellPtr> = Array::new();
+                let list = view.alloc(list)?;
+                let bytes: Array<u8> = Array::new();
+                let bytes = view.alloc(bytes)?;
+
+                for n in 0..150 {
+                    StackAnyContainer::push(&*list, view, view.number(n))?;
+                    StackContainer::push(&*bytes, view, n as u8)?;
+                }
+
+                let printed = format!("{}", list.as_tagged(view));
+                assert!(printed.starts_with("[0, 1, 2, "));
+                assert!(printed.ends_with(", 98, 99, \u{2026} (+50 more)]"));
+
+                let list = list.as_tagged(view);
+                let bytes = bytes.as_tagged(view);
+                assert!(format!("{:.3}", list) == "[0, 1, 2, \u{2026} (+147 more)]");
+                assert!(format!("{:.0}", list) == "[\u{2026} (+150 more)]");
+                assert!(format!("{:.200}", list).ends_with(", 148, 149]"));
+                assert!(format!("{:.2}", bytes) == "#u8(0 1 \u{2026} (+148 more))");
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
 }
@@ -0,0 +1,197 @@
+/// An assembler for writing Functions instruction by instruction in the language itself.
+///
+/// `(assemble code literals param ...)` builds a Function from a list of instructions, each a
+/// list of the instruction name and its integer operands as printed by the disassembler, for
+/// example `(load-literal 3 0)` or `(jump-if-true 2 1)`. The `literal_id` operand of
+/// `load-literal` is an index into the `literals` list. Any further arguments name the Function's
+/// parameters, which are in registers 2 onwards.
+///
+/// The code is checked by `BytecodeBuilder` as it is assembled, so a register read before it is
+/// written, an upvalue instruction, an arithmetic instruction the VM does not implement, a jump that
+/// lands outside the Function or code that doesn't end with a `return` is an error rather than a
+/// crash when the Function is called.
+use crate::builder::BytecodeBuilder;
+use crate::bytecode::{LiteralId, Opcode};
+use crate::convert::FromValue;
+use crate::error::{err_eval, ErrorKind, RuntimeError};
+use crate::memory::MutatorView;
+use crate::native::arg;
+use crate::native_module;
+use crate::pair::vec_from_pairs;
+use crate::safeptr::{TaggedCellPtr, TaggedScopedPtr};
+use crate::vm::Thread;
+
+/// Read one instruction, mapping a literal index to the id the literal was given in the constant
+/// pool
+fn read_instruction<'guard>(
+    mem: &'guard MutatorView,
+    instruction: TaggedScopedPtr<'guard>,
+    literal_ids: &[LiteralId],
+) -> Result<Opcode, RuntimeError> {
+    let parts = vec_from_pairs(mem, instruction)?;
+    let (name, operands) = match parts.split_first() {
+        Some((name, operands)) => (String::from_value(mem, *name)?, operands),
+        None => {
+            return Err(err_eval(
+                "An instruction must be a list of a name and operands",
+            ))
+        }
+    };
+
+    let operands = operands
+        .iter()
+        .map(|operand| isize::from_value(mem, *operand))
+        .collect::<Result<Vec<isize>, RuntimeError>>()?;
+
+    match Opcode::from_operands(&name, &operands)? {
+        Opcode::LoadLiteral { dest, literal_id } => match literal_ids.get(literal_id as usize) {
+            Some(literal_id) => Ok(Opcode::LoadLiteral {
+                dest,
+                literal_id: *literal_id,
+            }),
+            None => Err(err_eval(&format!(
+                "There is no literal {}, {} were given",
+                literal_id,
+                literal_ids.len()
+            ))),
+        },
+        op => Ok(op),
+    }
+}
+
+/// (assemble code literals param ...) - return a Function made from a list of instructions
+fn assemble<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let code = vec_from_pairs(mem, args[0].get(mem))
+        .map_err(|_| err_eval("Parameter code to assemble must be a list of instructions"))?;
+    let literals = vec_from_pairs(mem, args[1].get(mem))
+        .map_err(|_| err_eval("Parameter literals to assemble must be a list"))?;
+
+    let params = (2..args.len())
+        .map(|index| arg::<String>(mem, args, index, "assemble"))
+        .collect::<Result<Vec<String>, RuntimeError>>()?;
+    let params: Vec<&str> = params.iter().map(String::as_str).collect();
+
+    let mut builder = BytecodeBuilder::new(mem, &params)?;
+
+    let literal_ids = literals
+        .iter()
+        .map(|literal| builder.add_literal(*literal))
+        .collect::<Result<Vec<LiteralId>, RuntimeError>>()?;
+
+    for (index, instruction) in code.iter().enumerate() {
+        read_instruction(mem, *instruction, &literal_ids)
+            .and_then(|op| match op {
+                Opcode::Jump { .. } | Opcode::JumpIfTrue { .. } | Opcode::JumpIfNotTrue { .. } => {
+                    builder.emit_jump_with_offset(op)
+                }
+                _ => builder.emit(op),
+            })
+            .map_err(|e| match e.error_kind() {
+                ErrorKind::EvalError(reason) => err_eval(&format!(
+                    "Instruction {} of assemble, {}: {}",
+                    index, instruction, reason
+                )),
+                _ => e,
+            })?;
+    }
+
+    Ok(builder.finish("assembled")?.as_tagged(mem))
+}
+
+native_module! {
+    /// The bytecode assembler builtin function
+    pub ASSEMBLER_MODULE = "assembler" {
+        "assemble" => assemble(2..),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    #[test]
+    fn assemble_functions() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+                let error = |code| match eval(code) {
+                    Err(e) => match e.error_kind() {
+                        ErrorKind::EvalError(reason) => reason.clone(),
+                        _ => format!("{}", e),
+                    },
+                    Ok(value) => panic!("{} should fail, returned {}", code, value),
+                };
+
+                eval("(set 'hello (assemble '((load-literal 1 0) (return 1)) '(hello)))")?;
+                assert!(eval("(hello)")? == mem.lookup_sym("hello"));
+
+                // (def empty? (x) (cond (nil? x) 'yes true 'no))
+                eval(
+                    "(set 'empty? (assemble '((is-nil 3 2) \
+                                              (jump-if-not-true 3 2) \
+                                              (load-literal 3 0) \
+                                              (return 3) \
+                                              (load-literal 3 1) \
+                                              (return 3)) \
+                                            '(yes no) \
+                                            'x))",
+                )?;
+                assert!(eval("(empty? nil)")? == mem.lookup_sym("yes"));
+                assert!(eval("(empty? 'a)")? == mem.lookup_sym("no"));
+                assert!(eval("(empty? 'a 'b)").is_err());
+
+                assert!(
+                    error("(assemble '((return 3)) nil)")
+                        == "Instruction 0 of assemble, (return 3): \
+                            Return { reg: 3 } reads register 3 before it is written"
+                );
+                assert!(error("(assemble '((load-literal 1 2) (return 1)) '(a b))")
+                    .ends_with("There is no literal 2, 2 were given"));
+                assert!(error("(assemble '((frobnicate 1)) nil)")
+                    .ends_with("Unknown instruction frobnicate"));
+                assert!(error("(assemble '((return 256)) nil)").contains("out of range"));
+                assert!(error("(assemble '((jump -2) (return 1)) nil)").contains("outside"));
+                assert!(error("(assemble '((get-upvalue 1 5) (return 1)) nil)")
+                    .ends_with("cannot be used in a function without a closure environment"));
+                assert!(
+                    error("(assemble '((load-nil 1) (set-upvalue 0 1) (return 1)) nil)")
+                        .contains("without a closure environment")
+                );
+                assert!(
+                    error("(assemble '((load-nil 1) (close-upvalues 1 1 1) (return 1)) nil)")
+                        .contains("without a closure environment")
+                );
+                assert!(
+                    error("(assemble '((load-integer 2 1) (add 1 2 2) (return 1)) nil)")
+                        == "Instruction 1 of assemble, (add 1 2 2): \
+                            add is not implemented by the VM"
+                );
+                assert!(
+                    error("(assemble '((load-nil 2) (divide-integer 1 2 2) (return 1)) nil)")
+                        .ends_with("divide-integer is not implemented by the VM")
+                );
+                assert!(error("(assemble '((load-nil 1)) nil)").contains("must end with a Return"));
+                assert!(error("(assemble '(()) nil)").contains("a name and operands"));
+                assert!(error("(assemble 'x nil)").contains("list of instructions"));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+extern crate evalrus;
+
+use std::io;
+use std::process;
+
+use evalrus::lsp::{read_message, write_message, LanguageServer};
+
+/// Serve the Language Server Protocol on stdin and stdout until the client sends `exit`
+fn main() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+
+    let mut server = LanguageServer::new();
+
+    loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => message,
+            // the client went away without asking the server to exit
+            Ok(None) => process::exit(1),
+            Err(err) => {
+                eprintln!("evalrus-lsp: could not read a message: {}", err);
+                process::exit(1);
+            }
+        };
+
+        match server.handle(&message) {
+            Ok(replies) => {
+                for reply in replies {
+                    if let Err(err) = write_message(&mut output, &reply) {
+                        eprintln!("evalrus-lsp: could not write a message: {}", err);
+                        process::exit(1);
+                    }
+                }
+            }
+            Err(err) => eprintln!("evalrus-lsp: {}", err),
+        }
+
+        if let Some(status) = server.exit_status() {
+            process::exit(status);
+        }
+    }
+}
@@ -0,0 +1,514 @@
+/// Building Functions from bytecode without going through source code.
+///
+/// `BytecodeBuilder` is for embedders, and later macros, that generate code directly. Each
+/// instruction is checked as it is emitted, so that a mistake is reported where it was made rather
+/// than when the code runs:
+///
+/// * a register must be written before it is read, counting parameters as written. This is
+///   checked in emission order, so a register written in one branch counts as written after it.
+/// * literals must have been added with `add_literal()`
+/// * call arguments must fit in the register window
+/// * jumps are emitted through the methods that return a `JumpPatch`, and every patch must be
+///   applied before the Function is finished, or with a known offset through
+///   `emit_jump_with_offset()`, in which case the target must be inside the Function
+/// * the code must end with a `Return`
+use std::collections::HashMap;
+
+use crate::array::ArraySize;
+use crate::bytecode::{ByteCode, JumpOffset, JumpPatch, LiteralId, NumArgs, Opcode, Register};
+use crate::containers::StackAnyContainer;
+use crate::error::{err_eval, RuntimeError};
+use crate::function::Function;
+use crate::list::List;
+use crate::memory::MutatorView;
+use crate::native::NativeFunction;
+use crate::safeptr::{ScopedPtr, TaggedScopedPtr};
+use crate::taggedptr::TaggedPtr;
+use crate::vm::FIRST_ARG_REG;
+
+/// Count of registers in a register window
+const REGISTER_COUNT: usize = 256;
+
+/// Emits bytecode for a single Function, validating each instruction as it goes.
+///
+/// ```
+/// use evalrus::builder::BytecodeBuilder;
+/// use evalrus::bytecode::Opcode;
+/// use evalrus::error::RuntimeError;
+/// use evalrus::memory::{Memory, Mutator, MutatorView};
+/// use evalrus::vm::Thread;
+///
+/// struct Example {}
+///
+/// impl Mutator for Example {
+///     type Input = ();
+///     type Output = ();
+///
+///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+///         // (def empty? (x) (cond (nil? x) 'yes true 'no))
+///         let mut builder = BytecodeBuilder::new(mem, &["x"])?;
+///         builder.emit(Opcode::IsNil { dest: 3, test: 2 })?;
+///         let not_nil = builder.emit_jump_if_not_true(3)?;
+///         let yes = builder.add_literal(mem.lookup_sym("yes"))?;
+///         builder.emit_load_literal(3, yes)?;
+///         builder.emit(Opcode::Return { reg: 3 })?;
+///         builder.patch(not_nil)?;
+///         let no = builder.add_literal(mem.lookup_sym("no"))?;
+///         builder.emit_load_literal(3, no)?;
+///         builder.emit(Opcode::Return { reg: 3 })?;
+///
+///         let function = builder.finish("empty?")?;
+///
+///         let thread = Thread::alloc(mem)?;
+///         thread.set_global(mem, "empty?", function.as_tagged(mem))?;
+///         Ok(())
+///     }
+/// }
+///
+/// Memory::new().mutate(&Example {}, ()).unwrap();
+/// ```
+pub struct BytecodeBuilder<'guard> {
+    mem: &'guard MutatorView<'guard>,
+    code: ScopedPtr<'guard, ByteCode>,
+    params: ScopedPtr<'guard, List>,
+    /// Whether each register has been written by an instruction emitted so far
+    written: [bool; REGISTER_COUNT],
+    /// Index of each jump emitted and not yet patched
+    unpatched: Vec<ArraySize>,
+    /// Index of each jump emitted with a known offset, with the index of the instruction it jumps
+    /// to
+    jump_targets: Vec<(ArraySize, i64)>,
+    /// Literal id of each constant added
+    literal_ids: HashMap<TaggedPtr, LiteralId>,
+}
+
+impl<'guard> BytecodeBuilder<'guard> {
+    /// Begin a Function that takes parameters of the given names
+    pub fn new(
+        mem: &'guard MutatorView<'guard>,
+        params: &[&str],
+    ) -> Result<BytecodeBuilder<'guard>, RuntimeError> {
+        if FIRST_ARG_REG + params.len() > REGISTER_COUNT {
+            return Err(err_eval(&format!(
+                "A function cannot take {} parameters",
+                params.len()
+            )));
+        }
+
+        let param_list = List::alloc(mem)?;
+        for param in params {
+            param_list.push(mem, mem.lookup_sym(param))?;
+        }
+
+        // the return, closure environment and parameter registers are set by the caller
+        let mut written = [false; REGISTER_COUNT];
+        for reg in written.iter_mut().take(FIRST_ARG_REG + params.len()) {
+            *reg = true;
+        }
+
+        Ok(BytecodeBuilder {
+            mem,
+            code: ByteCode::alloc(mem)?,
+            params: param_list,
+            written,
+            unpatched: Vec::new(),
+            jump_targets: Vec::new(),
+            literal_ids: HashMap::new(),
+        })
+    }
+
+    /// Add a value to the literals of the Function, returning the id to load it with
+    pub fn add_literal(
+        &mut self,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<LiteralId, RuntimeError> {
+        let value = self.mem.constant(value);
+        if let Some(literal_id) = self.literal_ids.get(&value.get_ptr()) {
+            return Ok(*literal_id);
+        }
+
+        let literal_id = self.code.push_lit(self.mem, value)?;
+        self.literal_ids.insert(value.get_ptr(), literal_id);
+        Ok(literal_id)
+    }
+
+    /// Emit any instruction other than a jump
+    pub fn emit(&mut self, op: Opcode) -> Result<(), RuntimeError> {
+        match op {
+            Opcode::Jump { .. } | Opcode::JumpIfTrue { .. } | Opcode::JumpIfNotTrue { .. } => {
+                Err(err_eval(
+                    "Jumps must be emitted with emit_jump, emit_jump_if_true or emit_jump_if_not_true",
+                ))
+            }
+
+            Opcode::LoadLiteral { literal_id, .. } => {
+                self.code.literal(self.mem, literal_id)?;
+                self.push(op)
+            }
+
+            Opcode::Call {
+                dest, arg_count, ..
+            } if dest as usize + FIRST_ARG_REG + arg_count as usize >= REGISTER_COUNT => {
+                Err(err_eval(&format!(
+                    "A call to register {} cannot pass {} arguments",
+                    dest, arg_count
+                )))
+            }
+
+            _ => self.push(op),
+        }
+    }
+
+    /// Emit an instruction loading a literal added with `add_literal()`
+    pub fn emit_load_literal(
+        &mut self,
+        dest: Register,
+        literal_id: LiteralId,
+    ) -> Result<(), RuntimeError> {
+        self.emit(Opcode::LoadLiteral { dest, literal_id })
+    }
+
+    /// Emit instructions loading the value bound to a global name
+    pub fn emit_load_global(&mut self, dest: Register, name: &str) -> Result<(), RuntimeError> {
+        let literal_id = self.add_literal(self.mem.lookup_sym(name))?;
+        self.emit_load_literal(dest, literal_id)?;
+        self.emit(Opcode::LoadGlobal { dest, name: dest })
+    }
+
+    /// Emit a call to the function in register `function` with the arguments in the registers
+    /// following `dest`'s reserved registers, putting the result in `dest`
+    pub fn emit_call(
+        &mut self,
+        function: Register,
+        dest: Register,
+        arg_count: NumArgs,
+    ) -> Result<(), RuntimeError> {
+        self.emit(Opcode::Call {
+            function,
+            dest,
+            arg_count,
+        })
+    }
+
+    /// Emit an unconditional jump to a target that will be given by `patch()`
+    pub fn emit_jump(&mut self) -> Result<JumpPatch, RuntimeError> {
+        self.push_jump(Opcode::Jump { offset: 0 })
+    }
+
+    /// Emit a jump taken if register `test` is true
+    pub fn emit_jump_if_true(&mut self, test: Register) -> Result<JumpPatch, RuntimeError> {
+        self.push_jump(Opcode::JumpIfTrue { test, offset: 0 })
+    }
+
+    /// Emit a jump taken if register `test` is not true
+    pub fn emit_jump_if_not_true(&mut self, test: Register) -> Result<JumpPatch, RuntimeError> {
+        self.push_jump(Opcode::JumpIfNotTrue { test, offset: 0 })
+    }
+
+    /// Emit a jump instruction whose offset is already known, such as one written by hand. The
+    /// target, counted from the instruction after the jump, is checked by `finish()`.
+    pub fn emit_jump_with_offset(&mut self, op: Opcode) -> Result<(), RuntimeError> {
+        let offset: JumpOffset = match op {
+            Opcode::Jump { offset }
+            | Opcode::JumpIfTrue { offset, .. }
+            | Opcode::JumpIfNotTrue { offset, .. } => offset,
+            _ => return Err(err_eval(&format!("{:?} is not a jump", op))),
+        };
+
+        self.push(op)?;
+
+        let instruction = self.code.last_instruction();
+        self.jump_targets
+            .push((instruction, instruction as i64 + 1 + offset as i64));
+        Ok(())
+    }
+
+    /// Make a jump land on the next instruction to be emitted
+    pub fn patch(&mut self, jump: JumpPatch) -> Result<(), RuntimeError> {
+        self.unpatched
+            .retain(|instruction| *instruction != jump.instruction());
+        self.code.patch(self.mem, jump)
+    }
+
+    /// Finish the Function, giving it a name
+    pub fn finish(self, name: &str) -> Result<ScopedPtr<'guard, Function>, RuntimeError> {
+        if !self.unpatched.is_empty() {
+            return Err(err_eval(&format!(
+                "The jumps at instructions {:?} in function {} were not patched",
+                self.unpatched, name
+            )));
+        }
+
+        let length = self.code.next_instruction() as i64;
+        for (instruction, target) in &self.jump_targets {
+            if *target < 0 || *target >= length {
+                return Err(err_eval(&format!(
+                    "The jump at instruction {} in function {} jumps to {}, outside of the \
+                     function's {} instructions",
+                    instruction, name, target, length
+                )));
+            }
+        }
+
+        match self.code.opcodes(self.mem).last() {
+            Some(Opcode::Return { .. }) => (),
+            _ => {
+                return Err(err_eval(&format!(
+                    "Function {} must end with a Return",
+                    name
+                )))
+            }
+        }
+
+        let name = self.mem.lookup_sym(name);
+        Function::alloc(self.mem, name, self.params, self.code, None, None)
+    }
+
+    /// Check that an instruction only reads registers that have been written, then append it
+    fn push(&mut self, op: Opcode) -> Result<(), RuntimeError> {
+        self.check_registers(&op)?;
+        self.code.push(self.mem, op)
+    }
+
+    /// Append a jump, returning the patch to set its target with
+    fn push_jump(&mut self, op: Opcode) -> Result<JumpPatch, RuntimeError> {
+        self.check_registers(&op)?;
+        let jump = self.code.push_jump(self.mem, op, None)?;
+        self.unpatched.push(jump.instruction());
+        Ok(jump)
+    }
+
+    /// Check that an instruction only reads registers that have been written, and record the
+    /// registers it writes. Functions built here have no closure environment, so the upvalue
+    /// instructions cannot be used, and the arithmetic instructions are not implemented by the VM.
+    fn check_registers(&mut self, op: &Opcode) -> Result<(), RuntimeError> {
+        if let Opcode::GetUpvalue { .. }
+        | Opcode::SetUpvalue { .. }
+        | Opcode::CloseUpvalues { .. } = op
+        {
+            return Err(err_eval(&format!(
+                "{:?} cannot be used in a function without a closure environment",
+                op
+            )));
+        }
+
+        if let Opcode::Add { .. }
+        | Opcode::Subtract { .. }
+        | Opcode::Multiply { .. }
+        | Opcode::DivideInteger { .. } = op
+        {
+            return Err(err_eval(&format!(
+                "{} is not implemented by the VM",
+                op.name()
+            )));
+        }
+
+        let (reads, writes) = reads_and_writes(op);
+
+        if let Some(reg) = reads.iter().find(|reg| !self.written[**reg as usize]) {
+            return Err(err_eval(&format!(
+                "{:?} reads register {} before it is written",
+                op, reg
+            )));
+        }
+
+        for reg in writes {
+            self.written[reg as usize] = true;
+        }
+
+        Ok(())
+    }
+}
+
+/// Build a Function of the given name and arity that calls a native function with the given
+/// bound values followed by its own arguments and returns the result. A NativeFunction carries no
+/// state of its own, so this is how a native can be given some, such as a function it wraps.
+pub fn wrap_native<'guard>(
+    mem: &'guard MutatorView,
+    name: &str,
+    arity: NumArgs,
+    native: ScopedPtr<'guard, NativeFunction>,
+    bound: &[TaggedScopedPtr<'guard>],
+) -> Result<ScopedPtr<'guard, Function>, RuntimeError> {
+    let params: Vec<String> = (0..arity).map(|index| format!("arg{}", index)).collect();
+    let params: Vec<&str> = params.iter().map(String::as_str).collect();
+    let mut builder = BytecodeBuilder::new(mem, &params)?;
+
+    // the call window follows the parameter registers
+    let dest = (FIRST_ARG_REG + arity as usize) as Register;
+    let first_arg = dest as usize + FIRST_ARG_REG;
+    let arg_count = bound.len() + arity as usize;
+    if first_arg + arg_count >= REGISTER_COUNT {
+        return Err(err_eval(&format!(
+            "A wrapper of {} cannot pass {} arguments",
+            native.name(),
+            arg_count
+        )));
+    }
+
+    let literal_id = builder.add_literal(native.as_tagged(mem))?;
+    builder.emit_load_literal(dest, literal_id)?;
+
+    for (index, value) in bound.iter().enumerate() {
+        let literal_id = builder.add_literal(*value)?;
+        builder.emit_load_literal((first_arg + index) as Register, literal_id)?;
+    }
+
+    for index in 0..arity as usize {
+        builder.emit(Opcode::CopyRegister {
+            dest: (first_arg + bound.len() + index) as Register,
+            src: (FIRST_ARG_REG + index) as Register,
+        })?;
+    }
+
+    builder.emit_call(dest, dest, arg_count as NumArgs)?;
+    builder.emit(Opcode::Return { reg: dest })?;
+
+    builder.finish(name)
+}
+
+/// Split the registers an instruction uses into those it reads and those it writes
+fn reads_and_writes(op: &Opcode) -> (Vec<Register>, Vec<Register>) {
+    let registers = op.registers();
+
+    match *op {
+        // the callee and arguments are read, and the result written to `dest`
+        Opcode::Call { function, dest, .. } => {
+            let mut reads = vec![function];
+            reads.extend_from_slice(&registers[2..]);
+            (reads, vec![dest])
+        }
+
+        Opcode::UnpackPair { first, second, src } => (vec![src], vec![first, second]),
+
+        Opcode::NoOp
+        | Opcode::Return { .. }
+        | Opcode::ExpectNil { .. }
+        | Opcode::Jump { .. }
+        | Opcode::JumpIfTrue { .. }
+        | Opcode::JumpIfNotTrue { .. }
+        | Opcode::StoreGlobal { .. }
+        | Opcode::SetUpvalue { .. }
+        | Opcode::CloseUpvalues { .. }
+        | Opcode::PrintElapsed { .. }
+        | Opcode::CheckType { .. } => (registers, vec![]),
+
+        // every other instruction writes its first register and reads the rest
+        _ => (registers[1..].to_vec(), vec![registers[0]]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+    use crate::vm::Thread;
+
+    #[test]
+    fn builder_emits_callable_functions() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                // (def describe (x) (cond (nil? x) 'empty true (list-of x)))
+                let mut builder = BytecodeBuilder::new(mem, &["x"])?;
+                builder.emit(Opcode::IsNil { dest: 3, test: 2 })?;
+                let not_nil = builder.emit_jump_if_not_true(3)?;
+                let empty = builder.add_literal(mem.lookup_sym("empty"))?;
+                builder.emit_load_literal(3, empty)?;
+                let end = builder.emit_jump()?;
+                builder.patch(not_nil)?;
+                builder.emit_load_global(4, "list-of")?;
+                builder.emit(Opcode::CopyRegister { dest: 7, src: 2 })?;
+                builder.emit_call(4, 5, 1)?;
+                builder.emit(Opcode::CopyRegister { dest: 3, src: 5 })?;
+                builder.patch(end)?;
+                builder.emit(Opcode::Return { reg: 3 })?;
+
+                let function = builder.finish("describe")?;
+                assert!(function.arity() == 1);
+                t.set_global(mem, "describe", function.as_tagged(mem))?;
+
+                eval("(def list-of (x) (cons x nil))")?;
+                assert!(eval("(describe nil)")? == mem.lookup_sym("empty"));
+                assert!(format!("{}", eval("(describe 'a)")?) == "(a)");
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn builder_rejects_invalid_code() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let mut builder = BytecodeBuilder::new(mem, &["x"])?;
+
+                // register 3 has not been written, parameter register 2 has
+                assert!(builder.emit(Opcode::Return { reg: 3 }).is_err());
+                assert!(builder.emit(Opcode::IsNil { dest: 3, test: 2 }).is_ok());
+
+                assert!(builder.emit_load_literal(4, LiteralId::MAX).is_err());
+                assert!(builder.emit_call(3, 250, 10).is_err());
+                assert!(builder.emit(Opcode::Jump { offset: 1 }).is_err());
+
+                // a jump must be patched, and the code must end with a Return
+                let jump = builder.emit_jump()?;
+                assert!(builder.emit(Opcode::Return { reg: 3 }).is_ok());
+                match builder.finish("f") {
+                    Err(e) => assert!(format!("{}", e).contains("jumps at instructions [1]")),
+                    Ok(_) => panic!("an unpatched jump should be an error"),
+                }
+
+                let mut builder = BytecodeBuilder::new(mem, &[])?;
+                builder.emit(Opcode::LoadNil { dest: 2 })?;
+                assert!(builder.finish("g").is_err());
+
+                drop(jump);
+
+                // jumps with known offsets must land inside the function
+                let mut builder = BytecodeBuilder::new(mem, &["x"])?;
+                builder.emit_jump_with_offset(Opcode::JumpIfTrue { test: 2, offset: 1 })?;
+                builder.emit(Opcode::Return { reg: 2 })?;
+                assert!(builder.finish("h").is_err());
+
+                // no offset is special, the largest is checked like any other
+                let mut builder = BytecodeBuilder::new(mem, &["x"])?;
+                builder.emit_jump_with_offset(Opcode::Jump {
+                    offset: JumpOffset::MAX,
+                })?;
+                builder.emit(Opcode::Return { reg: 2 })?;
+                assert!(builder.finish("h").is_err());
+
+                let mut builder = BytecodeBuilder::new(mem, &["x"])?;
+                assert!(builder
+                    .emit_jump_with_offset(Opcode::Return { reg: 2 })
+                    .is_err());
+                builder.emit_jump_with_offset(Opcode::JumpIfTrue { test: 2, offset: 0 })?;
+                builder.emit(Opcode::Return { reg: 2 })?;
+                assert!(builder.finish("i").is_ok());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
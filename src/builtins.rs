@@ -0,0 +1,1634 @@
+/// The global bindings every Thread starts with: native functions and standard I/O ports.
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::str;
+
+use crate::array::{AllocObject, Array, ArraySize, ArrayU8};
+use crate::assembler::ASSEMBLER_MODULE;
+use crate::containers::{
+    Container, ContainerFromSlice, FillContainer, HashIndexedAnyContainer, IndexedContainer,
+    SliceableContainer,
+};
+use crate::convert::ToValue;
+use crate::dict::Dict;
+use crate::error::{err_eval, ErrorKind, RuntimeError, SourcePos};
+use crate::function::{keyword_arg_slots, unapplied_params, Function, Partial};
+use crate::global::bind_global;
+use crate::hashable::hash_value;
+use crate::headers::TypeList;
+use crate::json::JSON_MODULE;
+use crate::memo::MEMO_MODULE;
+use crate::memory::MutatorView;
+use crate::native::{arg, expect_text};
+use crate::native_module;
+use crate::number::NUMBER_MODULE;
+use crate::pair::{cons, vec_from_pairs};
+use crate::persistent::PERSISTENT_MODULE;
+use crate::port::{check_port_io, Port, FILE_PORT_MODULE, PORT_MODULE};
+use crate::printer::{describe, display};
+#[cfg(feature = "vm-profile")]
+use crate::profile::PROFILE_MODULE;
+use crate::promise::PROMISE_MODULE;
+use crate::safeptr::{MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::sort::SORT_MODULE;
+use crate::taggedptr::{Value, INLINE_INTEGER_MAX};
+use crate::trace::TRACE_MODULE;
+use crate::vm::Thread;
+use crate::weak::WEAK_MODULE;
+
+/// Bind all builtins into the given globals dict. The given stdout Port is bound as `stdout`.
+pub fn register<'guard>(
+    mem: &'guard MutatorView,
+    globals: ScopedPtr<'guard, Dict>,
+    stdout: ScopedPtr<'guard, Port>,
+) -> Result<(), RuntimeError> {
+    PORT_MODULE.bind(mem, globals)?;
+    FILE_PORT_MODULE.bind(mem, globals)?;
+    OUTPUT_MODULE.bind(mem, globals)?;
+    TEXT_MODULE.bind(mem, globals)?;
+    ARRAY_MODULE.bind(mem, globals)?;
+    SYSTEM_MODULE.bind(mem, globals)?;
+    RUNTIME_MODULE.bind(mem, globals)?;
+    FUNCTION_MODULE.bind(mem, globals)?;
+    TEST_MODULE.bind(mem, globals)?;
+    WEAK_MODULE.bind(mem, globals)?;
+    JSON_MODULE.bind(mem, globals)?;
+    TRACE_MODULE.bind(mem, globals)?;
+    MEMO_MODULE.bind(mem, globals)?;
+    PERSISTENT_MODULE.bind(mem, globals)?;
+    ASSEMBLER_MODULE.bind(mem, globals)?;
+    NUMBER_MODULE.bind(mem, globals)?;
+    PROMISE_MODULE.bind(mem, globals)?;
+    SORT_MODULE.bind(mem, globals)?;
+    #[cfg(feature = "vm-profile")]
+    PROFILE_MODULE.bind(mem, globals)?;
+
+    let stdin = Port::alloc_stdin(mem)?;
+    bind_global(mem, globals, mem.lookup_sym("stdin"), stdin.as_tagged(mem))?;
+    bind_global(
+        mem,
+        globals,
+        mem.lookup_sym("stdout"),
+        stdout.as_tagged(mem),
+    )?;
+    let stderr = Port::alloc_stderr(mem)?;
+    bind_global(
+        mem,
+        globals,
+        mem.lookup_sym("stderr"),
+        stderr.as_tagged(mem),
+    )?;
+
+    Ok(())
+}
+
+/// Join the display form of each argument, separated by spaces
+fn display_args(mem: &MutatorView, args: &[TaggedCellPtr]) -> String {
+    let strings: Vec<String> = args.iter().map(|arg| display(*arg.get(mem))).collect();
+    strings.join(" ")
+}
+
+/// (print x ...) - write the display form of each argument to the Thread output port
+fn print<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let port = thread.output_port(mem);
+    check_port_io(thread, port, "print")?;
+
+    port.write_str(mem, &display_args(mem, args))?;
+    Ok(mem.nil())
+}
+
+/// (println x ...) - as `print`, followed by a newline
+fn println<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let mut line = display_args(mem, args);
+    line.push('\n');
+
+    let port = thread.output_port(mem);
+    check_port_io(thread, port, "println")?;
+
+    port.write_str(mem, &line)?;
+    Ok(mem.nil())
+}
+
+/// Substitute the display form of each argument for each `{}` in the template. `{{` and `}}`
+/// produce literal braces.
+pub fn format_template(
+    mem: &MutatorView,
+    template: &str,
+    args: &[TaggedCellPtr],
+) -> Result<String, RuntimeError> {
+    let mut result = String::new();
+    let mut args = args.iter();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                result.push(c);
+            }
+
+            ('{', Some('}')) => {
+                chars.next();
+                match args.next() {
+                    Some(arg) => result.push_str(&display(*arg.get(mem))),
+                    None => return Err(err_eval("Too few arguments for format template")),
+                }
+            }
+
+            ('{', _) | ('}', _) => {
+                return Err(err_eval(
+                    "Unmatched brace in format template, use {{ or }} for a literal brace",
+                ))
+            }
+
+            _ => result.push(c),
+        }
+    }
+
+    if args.next().is_some() {
+        return Err(err_eval("Too many arguments for format template"));
+    }
+
+    Ok(result)
+}
+
+/// (format template args...) - return a string built from the template
+fn format<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let template = expect_text(mem, &args[0], "format", "template")?;
+    let result = format_template(mem, template.as_str(mem), &args[1..])?;
+    mem.text(&result)
+}
+
+native_module! {
+    /// Output builtin functions. These check that the sandbox allows I/O unless the Thread output
+    /// port is a string port.
+    OUTPUT_MODULE = "output" {
+        "print" => print(0..),
+        "println" => println(0..),
+    }
+}
+
+/// (symbol->string s) - return the name of a symbol as a new string
+fn symbol_to_string<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    match *args[0].get(mem) {
+        Value::Symbol(s) => mem.text(s.as_str(mem)),
+        _ => Err(err_eval("Parameter s to symbol->string must be a symbol")),
+    }
+}
+
+/// (string->symbol t) - return the symbol with the name given by a string, interning it if it
+/// does not exist yet
+fn string_to_symbol<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let name = expect_text(mem, &args[0], "string->symbol", "t")?;
+    Ok(mem.lookup_sym(name.as_str(mem)))
+}
+
+/// (string<? a b) - return true if string a sorts before string b, comparing by code point
+fn string_less<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let a = expect_text(mem, &args[0], "string<?", "a")?;
+    let b = expect_text(mem, &args[1], "string<?", "b")?;
+    match a.as_str(mem) < b.as_str(mem) {
+        true => Ok(mem.sym_true()),
+        false => Ok(mem.nil()),
+    }
+}
+
+/// (symbol<? a b) - return true if the name of symbol a sorts before the name of symbol b
+fn symbol_less<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    match (*args[0].get(mem), *args[1].get(mem)) {
+        (Value::Symbol(a), Value::Symbol(b)) if a.as_str(mem) < b.as_str(mem) => Ok(mem.sym_true()),
+        (Value::Symbol(_), Value::Symbol(_)) => Ok(mem.nil()),
+        _ => Err(err_eval("Parameters a and b to symbol<? must be symbols")),
+    }
+}
+
+/// (string-upcase t) - return a new string of the upper case of each character of a string
+fn string_upcase<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let text = expect_text(mem, &args[0], "string-upcase", "t")?;
+    mem.text(&text.as_str(mem).to_uppercase())
+}
+
+/// (string-downcase t) - return a new string of the lower case of each character of a string
+fn string_downcase<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let text = expect_text(mem, &args[0], "string-downcase", "t")?;
+    mem.text(&text.as_str(mem).to_lowercase())
+}
+
+/// (string->bytes t) - return a new byte array containing the UTF-8 encoding of a string
+fn string_to_bytes<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let text = expect_text(mem, &args[0], "string->bytes", "t")?;
+    let bytes: ScopedPtr<'_, ArrayU8> =
+        ContainerFromSlice::from_slice(mem, text.as_str(mem).as_bytes())?;
+    Ok(bytes.as_tagged(mem))
+}
+
+/// (bytes->string b) - return a new string decoded from a byte array of UTF-8
+fn bytes_to_string<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let bytes = match *args[0].get(mem) {
+        Value::ArrayU8(bytes) => bytes,
+        _ => {
+            return Err(err_eval(
+                "Parameter b to bytes->string must be a byte array",
+            ))
+        }
+    };
+
+    let decoded = bytes.access_slice(mem, |_, slice| match str::from_utf8(slice) {
+        Ok(decoded) => Ok(String::from(decoded)),
+        Err(e) => Err(err_eval(&format!(
+            "Parameter b to bytes->string is not valid UTF-8: {}",
+            e
+        ))),
+    })?;
+
+    mem.text(&decoded)
+}
+
+native_module! {
+    /// String builtin functions
+    TEXT_MODULE = "text" {
+        "format" => format(1..),
+        "symbol->string" => symbol_to_string(1),
+        "string->symbol" => string_to_symbol(1),
+        "string->bytes" => string_to_bytes(1),
+        "bytes->string" => bytes_to_string(1),
+        "string<?" => string_less(2),
+        "symbol<?" => symbol_less(2),
+        "string-upcase" => string_upcase(1),
+        "string-downcase" => string_downcase(1),
+    }
+}
+
+/// Return the value of an integer argument that must be a valid index or count for an array
+fn array_index(
+    mem: &MutatorView,
+    args: &[TaggedCellPtr],
+    index: usize,
+    fn_name: &str,
+) -> Result<ArraySize, RuntimeError> {
+    let value: isize = arg(mem, args, index, fn_name)?;
+    ArraySize::try_from(value).map_err(|_| {
+        err_eval(&format!(
+            "Parameter {} to {} must be between 0 and {}",
+            index + 1,
+            fn_name,
+            ArraySize::MAX
+        ))
+    })
+}
+
+/// Allocate an array of `length` zeroes
+fn make_array<'guard, T>(
+    mem: &'guard MutatorView,
+    length: ArraySize,
+    zero: T,
+) -> Result<ScopedPtr<'guard, Array<T>>, RuntimeError>
+where
+    T: Sized + Clone,
+    Array<T>: AllocObject<TypeList>,
+{
+    let array = Array::<T>::alloc_with_capacity(mem, length)?;
+    FillContainer::fill(&*array, mem, length, zero)?;
+    Ok(array)
+}
+
+/// (make-bytes n) - return a new array of n bytes, each set to 0
+fn make_bytes<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let length = array_index(mem, args, 0, "make-bytes")?;
+    Ok(make_array::<u8>(mem, length, 0)?.as_tagged(mem))
+}
+
+/// (make-words n) - return a new array of n unsigned 32 bit integers, each set to 0
+fn make_words<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let length = array_index(mem, args, 0, "make-words")?;
+    Ok(make_array::<u32>(mem, length, 0)?.as_tagged(mem))
+}
+
+/// Read an element of an integer array, checking the index is in range
+fn get_element<T>(
+    guard: &dyn MutatorScope,
+    array: &Array<T>,
+    index: ArraySize,
+    fn_name: &str,
+) -> Result<isize, RuntimeError>
+where
+    T: Sized + Clone + Into<i64>,
+{
+    if index >= array.length() {
+        return Err(err_index(index, array.length(), fn_name));
+    }
+
+    Ok(IndexedContainer::get(array, guard, index)?.into() as isize)
+}
+
+/// Write an element of an integer array, checking the index and the value are in range
+fn set_element<T>(
+    guard: &dyn MutatorScope,
+    array: &Array<T>,
+    index: ArraySize,
+    value: isize,
+    fn_name: &str,
+) -> Result<(), RuntimeError>
+where
+    T: Sized + Clone + TryFrom<isize>,
+{
+    if index >= array.length() {
+        return Err(err_index(index, array.length(), fn_name));
+    }
+
+    let item = T::try_from(value).map_err(|_| {
+        err_eval(&format!(
+            "Value {} is out of range for the elements of the array passed to {}",
+            value, fn_name
+        ))
+    })?;
+
+    IndexedContainer::set(array, guard, index, item)
+}
+
+/// Build an error for an index that is past the end of an array
+fn err_index(index: ArraySize, length: ArraySize, fn_name: &str) -> RuntimeError {
+    err_eval(&format!(
+        "Index {} passed to {} is out of range for an array of length {}",
+        index, fn_name, length
+    ))
+}
+
+/// Build an error for an argument that is not an integer array
+fn err_not_integer_array(fn_name: &str) -> RuntimeError {
+    err_eval(&format!(
+        "Parameter 1 to {} must be a byte or word array",
+        fn_name
+    ))
+}
+
+/// (array-length a) - return the number of elements in a byte or word array
+fn array_length<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let length = match *args[0].get(mem) {
+        Value::ArrayU8(array) => array.length(),
+        Value::ArrayU16(array) => array.length(),
+        Value::ArrayU32(array) => array.length(),
+        _ => return Err(err_not_integer_array("array-length")),
+    };
+
+    Ok(mem.number(length as isize))
+}
+
+/// (array-get a i) - return the element at index i of a byte or word array
+fn array_get<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let index = array_index(mem, args, 1, "array-get")?;
+
+    let value = match *args[0].get(mem) {
+        Value::ArrayU8(array) => get_element(mem, &array, index, "array-get")?,
+        Value::ArrayU16(array) => get_element(mem, &array, index, "array-get")?,
+        Value::ArrayU32(array) => get_element(mem, &array, index, "array-get")?,
+        _ => return Err(err_not_integer_array("array-get")),
+    };
+
+    value.to_value(mem)
+}
+
+/// (array-set a i v) - set the element at index i of a byte or word array to v, returning v
+fn array_set<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let index = array_index(mem, args, 1, "array-set")?;
+    let value: isize = arg(mem, args, 2, "array-set")?;
+
+    match *args[0].get(mem) {
+        Value::ArrayU8(array) => set_element(mem, &array, index, value, "array-set")?,
+        Value::ArrayU16(array) => set_element(mem, &array, index, value, "array-set")?,
+        Value::ArrayU32(array) => set_element(mem, &array, index, value, "array-set")?,
+        _ => return Err(err_not_integer_array("array-set")),
+    }
+
+    Ok(args[2].get(mem))
+}
+
+/// (array-fill a v) - set every element of a byte or word array to v, returning the array
+fn array_fill<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let value: isize = arg(mem, args, 1, "array-fill")?;
+
+    fn fill<T>(guard: &dyn MutatorScope, array: &Array<T>, value: isize) -> Result<(), RuntimeError>
+    where
+        T: Sized + Clone + TryFrom<isize>,
+    {
+        for index in 0..array.length() {
+            set_element(guard, array, index, value, "array-fill")?;
+        }
+        Ok(())
+    }
+
+    match *args[0].get(mem) {
+        Value::ArrayU8(array) => fill(mem, &array, value)?,
+        Value::ArrayU16(array) => fill(mem, &array, value)?,
+        Value::ArrayU32(array) => fill(mem, &array, value)?,
+        _ => return Err(err_not_integer_array("array-fill")),
+    }
+
+    Ok(args[0].get(mem))
+}
+
+native_module! {
+    /// Unboxed integer array builtin functions
+    ARRAY_MODULE = "array" {
+        "make-bytes" => make_bytes(1),
+        "make-words" => make_words(1),
+        "array-length" => array_length(1),
+        "array-get" => array_get(2),
+        "array-set" => array_set(3),
+        "array-fill" => array_fill(2),
+    }
+}
+
+/// (getenv name) - return the value of an environment variable as a string, or nil if it is not
+/// set or is not valid unicode
+fn getenv<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let name: String = arg(mem, args, 0, "getenv")?;
+    env::var(name).ok().to_value(mem)
+}
+
+/// (argv) - return the program command line arguments as a list of strings
+fn argv<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    _args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    Ok(thread.argv(mem))
+}
+
+/// (exit n) - stop the program, requesting that the process exit with status n. Evaluation
+/// unwinds with an Exit error and it is up to the embedding program to act on it.
+fn exit<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let status: i64 = arg(mem, args, 0, "exit")?;
+    let status = i32::try_from(status)
+        .map_err(|_| err_eval(&format!("Exit status {} is out of range", status)))?;
+
+    Err(RuntimeError::new(ErrorKind::Exit(status)))
+}
+
+/// (heap-dump path) - write a JSON summary of the objects in the heap, by type, to a file and
+/// return the number of objects
+fn heap_dump<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let path: String = arg(mem, args, 0, "heap-dump")?;
+
+    let dump = mem.dump_heap();
+    fs::write(path, dump.to_json())?;
+
+    (dump.objects as isize).to_value(mem)
+}
+
+/// (clock-monotonic) - return a monotonic clock time in nanoseconds. Only the difference between
+/// two readings is meaningful.
+fn clock_monotonic<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    _args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    Ok(mem.number(thread.clock_ns()))
+}
+
+/// (random n) - return a pseudo random integer in the range 0 to n-1, where n is greater than zero
+fn random<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let bound: isize = arg(mem, args, 0, "random")?;
+    if bound <= 0 {
+        return Err(err_eval("Parameter to random must be greater than 0"));
+    }
+
+    (thread.random_below(bound as u64) as isize).to_value(mem)
+}
+
+/// (random-seed s) - seed the Thread pseudo random number generator with an integer, returning nil
+fn random_seed<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let seed: i64 = arg(mem, args, 0, "random-seed")?;
+    thread.random_seed(seed as u64);
+    Ok(mem.nil())
+}
+
+native_module! {
+    /// Process environment builtin functions
+    SYSTEM_MODULE = "system" [io] {
+        "getenv" => getenv(1),
+        "argv" => argv(0),
+        "exit" => exit(1),
+        "heap-dump" => heap_dump(1),
+    }
+}
+
+/// (hash x) - return the hash of a symbol, integer or string, as a non-negative integer. A
+/// symbol and a string with the same name hash the same.
+fn hash<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let value = args[0].get(mem);
+    match hash_value(mem, value) {
+        Ok(hash) => Ok(mem.number((hash & INLINE_INTEGER_MAX as u64) as isize)),
+        Err(_) => Err(err_eval(&format!(
+            "Parameter x to hash must be a symbol, integer or string, got {}",
+            describe(*value)
+        ))),
+    }
+}
+
+native_module! {
+    /// Clock, random number and hashing builtin functions
+    RUNTIME_MODULE = "runtime" {
+        "clock-monotonic" => clock_monotonic(0),
+        "random" => random(1),
+        "random-seed" => random_seed(1),
+        "hash" => hash(1),
+    }
+}
+
+/// (doc f) - return the documentation string of a function or partial application, or nil if it
+/// has none
+fn doc<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let function = match *args[0].get(mem) {
+        Value::Function(f) => f,
+        Value::Partial(p) => p.function(mem),
+        Value::NativeFunction(_) => return Ok(mem.nil()),
+        _ => return Err(err_eval("Parameter f to doc must be a function")),
+    };
+
+    function.doc(mem).map(String::from).to_value(mem)
+}
+
+/// (source-of f) - return a dict of the file, line and column where a function was defined, or
+/// nil if it is not known. The file is omitted for functions that were not read from a file.
+fn source_of<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let function = match *args[0].get(mem) {
+        Value::Function(f) => f,
+        Value::Partial(p) => p.function(mem),
+        Value::NativeFunction(_) => return Ok(mem.nil()),
+        _ => return Err(err_eval("Parameter f to source-of must be a function")),
+    };
+
+    match function.source_pos() {
+        Some(pos) => source_dict(mem, function, pos),
+        None => Ok(mem.nil()),
+    }
+}
+
+/// (apply f args) - call f with the items of a Pair list as its arguments
+fn apply<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let call_args = vec_from_pairs(mem, args[1].get(mem))
+        .map_err(|_| err_eval("Parameter args to apply must be a list"))?;
+    let call_args: Vec<TaggedCellPtr> =
+        call_args.into_iter().map(TaggedCellPtr::new_with).collect();
+
+    // this Thread is busy calling apply, so the function is called on another that can see this
+    // one's closures
+    let runner = thread.alloc_nested(mem)?;
+    runner.quick_vm_apply(mem, args[0].get(mem), &call_args)
+}
+
+/// (bind-keywords f npos arg1 .. argn) - apply f to the arguments of a call with named arguments:
+/// the first npos are positional and the rest alternate keywords and values. The result is a
+/// Partial that is entered by calling it with no arguments once all of f's parameters are given.
+/// The compiler emits this for calls with named arguments to functions it does not know the
+/// parameters of.
+fn bind_keywords<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let function = args[0].get(mem);
+    let call_args = &args[2..];
+
+    let positional = match *args[1].get(mem) {
+        Value::Number(n) if n >= 0 && n as usize <= call_args.len() => n as usize,
+        _ => {
+            return Err(err_eval(
+                "bind-keywords expects a count of positional arguments",
+            ))
+        }
+    };
+
+    let (name, params) = match unapplied_params(mem, function) {
+        Some(params) => params,
+        None => {
+            return Err(err_eval(&format!(
+                "Cannot pass named arguments to {}",
+                describe(*function)
+            )))
+        }
+    };
+
+    let named = &call_args[positional..];
+    let mut keywords = Vec::with_capacity(named.len() / 2);
+    for pair in named.chunks(2) {
+        let keyword = pair[0].get(mem);
+        match *keyword {
+            Value::Symbol(s) if s.is_keyword(mem) && pair.len() == 2 => {
+                keywords.push(s.as_str(mem))
+            }
+            _ => {
+                return Err(err_eval(&format!(
+                    "Expected a keyword followed by an argument, got {}",
+                    keyword
+                )))
+            }
+        }
+    }
+
+    let slots = keyword_arg_slots(name, &params, positional, &keywords)?;
+
+    // put each argument in the position of its parameter
+    let values = call_args[..positional]
+        .iter()
+        .chain(named.iter().skip(1).step_by(2));
+    let mut ordered = vec![TaggedCellPtr::new_nil(); slots.len()];
+    for (slot, value) in slots.into_iter().zip(values) {
+        ordered[slot] = value.clone();
+    }
+
+    let partial = match *function {
+        Value::Partial(p) => Partial::alloc_clone(mem, p, &ordered)?,
+        Value::Function(f) => Partial::alloc(mem, f, None, &ordered)?,
+        _ => unreachable!(),
+    };
+
+    Ok(partial.as_tagged(mem))
+}
+
+/// Return a dict of the file, line and column of a source position in the given function. The
+/// file is omitted for functions that were not read from a file.
+fn source_dict<'guard>(
+    mem: &'guard MutatorView,
+    function: ScopedPtr<'guard, Function>,
+    pos: SourcePos,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let source = Dict::alloc(mem)?;
+    if let Some(file_name) = function.file_name(mem) {
+        source.assoc(mem, mem.lookup_sym("file"), mem.text(file_name)?)?;
+    }
+    source.assoc(mem, mem.lookup_sym("line"), mem.number(pos.line as isize))?;
+    source.assoc(
+        mem,
+        mem.lookup_sym("column"),
+        mem.number(pos.column as isize),
+    )?;
+
+    Ok(source.as_tagged(mem))
+}
+
+/// (backtrace) - return a list of the call frames of the running program, innermost first. Each
+/// is a list of the function name, the index of the instruction being executed and a dict of the
+/// source position of the instruction as returned by `source-of`, or nil if it is not known.
+fn backtrace<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    _args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let mut frames = Vec::new();
+
+    for (function, ip, span) in thread.backtrace(mem) {
+        let pos = match span {
+            Some(span) => source_dict(mem, function, span.start)?,
+            None => mem.nil(),
+        };
+
+        let frame = cons(mem, pos, mem.nil())?;
+        let frame = cons(mem, mem.number(ip as isize), frame)?;
+        frames.push(cons(mem, function.name_symbol(mem), frame)?);
+    }
+
+    list_from(mem, frames)
+}
+
+/// (stack-depth) - return the count of call frames of the running program
+fn stack_depth<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    _args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    Ok(mem.number(thread.stack_depth(mem) as isize))
+}
+
+/// Return a list from a Vec of values
+fn list_from<'guard>(
+    mem: &'guard MutatorView,
+    values: Vec<TaggedScopedPtr<'guard>>,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let mut list = mem.nil();
+    for value in values.into_iter().rev() {
+        list = cons(mem, value, list)?;
+    }
+    Ok(list)
+}
+
+/// (last-error) - return the snapshot taken of the last evaluation that failed while debug output
+/// was on, or nil. It is a dict of the error `message`, its `code` and the call `frames`, innermost
+/// first. Each frame is a dict of the `function`, the index `ip` of the instruction being executed,
+/// the `instruction` itself as a list in the form `assemble` reads, and the values of the frame's
+/// `registers`.
+fn last_error<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    _args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let post_mortem = match thread.last_error() {
+        Some(post_mortem) => post_mortem,
+        None => return Ok(mem.nil()),
+    };
+
+    let mut frames = Vec::new();
+    for frame in &post_mortem.frames {
+        let instruction = match frame.opcode {
+            Some(opcode) => {
+                let mut parts = vec![mem.lookup_sym(opcode.name())];
+                for operand in opcode.operands() {
+                    parts.push(mem.number(operand));
+                }
+                list_from(mem, parts)?
+            }
+            None => mem.nil(),
+        };
+
+        let registers = frame
+            .registers
+            .iter()
+            .map(|register| register.get(mem))
+            .collect();
+
+        let dict = Dict::alloc(mem)?;
+        dict.assoc(
+            mem,
+            mem.lookup_sym("function"),
+            frame.function.get(mem).as_tagged(mem),
+        )?;
+        dict.assoc(mem, mem.lookup_sym("ip"), mem.number(frame.ip as isize))?;
+        dict.assoc(mem, mem.lookup_sym("instruction"), instruction)?;
+        dict.assoc(mem, mem.lookup_sym("registers"), list_from(mem, registers)?)?;
+        frames.push(dict.as_tagged(mem));
+    }
+
+    let error = Dict::alloc(mem)?;
+    error.assoc(
+        mem,
+        mem.lookup_sym("message"),
+        mem.text(&post_mortem.message)?,
+    )?;
+    error.assoc(
+        mem,
+        mem.lookup_sym("code"),
+        mem.lookup_sym(post_mortem.code),
+    )?;
+    error.assoc(mem, mem.lookup_sym("frames"), list_from(mem, frames)?)?;
+
+    Ok(error.as_tagged(mem))
+}
+
+native_module! {
+    /// Function and call stack introspection builtin functions
+    FUNCTION_MODULE = "function" {
+        "doc" => doc(1),
+        "source-of" => source_of(1),
+        "backtrace" => backtrace(0),
+        "stack-depth" => stack_depth(0),
+        "last-error" => last_error(0),
+        "bind-keywords" => bind_keywords(2..),
+        "apply" => apply(2),
+    }
+}
+
+/// Return an assertion failure error, positioned at the assertion call if it is known
+fn assertion_failed(thread: &Thread, guard: &dyn MutatorScope, message: &str) -> RuntimeError {
+    let kind = ErrorKind::EvalError(format!("Assertion failed: {}", message));
+
+    match thread.current_source_span(guard) {
+        Some(span) => RuntimeError::with_pos(kind, span.start),
+        None => RuntimeError::new(kind),
+    }
+}
+
+/// Structural equality: lists are equal if their elements are equal, strings are equal if their
+/// contents are and floats are equal if their values are, however they are stored. Other values
+/// are equal if they are identical.
+fn values_equal(guard: &dyn MutatorScope, a: TaggedScopedPtr, b: TaggedScopedPtr) -> bool {
+    match (*a, *b) {
+        (Value::Pair(a), Value::Pair(b)) => {
+            values_equal(guard, a.first.get(guard), b.first.get(guard))
+                && values_equal(guard, a.second.get(guard), b.second.get(guard))
+        }
+        (Value::Text(a), Value::Text(b)) => a.as_str(guard) == b.as_str(guard),
+        (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+        _ => a == b,
+    }
+}
+
+/// (assert expr msg) - fail with the message unless expr is true
+fn assert<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    if args[0].get(mem) != mem.lookup_sym("true") {
+        return Err(assertion_failed(thread, mem, &display(*args[1].get(mem))));
+    }
+
+    Ok(mem.nil())
+}
+
+/// (assert-eq a b) - fail unless a and b are structurally equal
+fn assert_eq<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let (a, b) = (args[0].get(mem), args[1].get(mem));
+
+    if !values_equal(mem, a, b) {
+        let message = format!("{} is not equal to {}", a, b);
+        return Err(assertion_failed(thread, mem, &message));
+    }
+
+    Ok(mem.nil())
+}
+
+/// (register-test name f) - register the function f, which must take no arguments, as the test
+/// named name, replacing any test of the same name. This is what `deftest` compiles to.
+fn register_test<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let name = args[0].get(mem);
+    if !matches!(*name, Value::Symbol(_)) {
+        return Err(err_eval("Parameter name to register-test must be a symbol"));
+    }
+
+    let test = args[1].get(mem);
+    match *test {
+        Value::Function(f) if f.arity() == 0 => (),
+        _ => {
+            return Err(err_eval(
+                "Parameter f to register-test must be a function taking no arguments",
+            ))
+        }
+    }
+
+    thread.tests(mem).assoc(mem, name, test)?;
+    Ok(test)
+}
+
+/// (run-tests) - run every registered test in name order, writing each failure and a count of
+/// passes and failures to the Thread output port. Returns the number of tests that failed.
+fn run_tests<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    _args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let output = thread.output_port(mem);
+
+    let mut tests = thread.tests(mem).items(mem);
+    tests.sort_by(|(a, _), (b, _)| format!("{}", a).cmp(&format!("{}", b)));
+
+    // this Thread is busy calling run-tests, so each test is evaluated on another
+    let runner = thread.alloc_child(mem)?;
+    let mut failed = 0;
+
+    for (name, test) in &tests {
+        let function = match **test {
+            Value::Function(f) => f,
+            _ => unreachable!(),
+        };
+
+        match runner.quick_vm_eval(mem, function) {
+            Ok(_) => (),
+
+            Err(e) => match e.error_kind() {
+                // stopping the program stops the test run too
+                ErrorKind::Exit(_) | ErrorKind::Interrupted => return Err(e),
+
+                _ => {
+                    failed += 1;
+                    output.write_str(mem, &format!("FAIL {}: {}\n", name, e))?;
+                }
+            },
+        }
+    }
+
+    output.write_str(
+        mem,
+        &format!("{} passed, {} failed\n", tests.len() - failed, failed),
+    )?;
+
+    (failed as isize).to_value(mem)
+}
+
+native_module! {
+    /// Assertion and test runner builtin functions
+    TEST_MODULE = "test" {
+        "assert" => assert(2),
+        "assert-eq" => assert_eq(2),
+        "register-test" => register_test(2),
+        "run-tests" => run_tests(0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::{compile, compile_in_file};
+    use crate::memory::{HeapDump, Memory, Mutator};
+    use crate::parser::parse;
+    use std::fs;
+    use std::process;
+
+    fn eval_helper<'guard>(
+        mem: &'guard MutatorView,
+        thread: ScopedPtr<'guard, Thread>,
+        code: &str,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let compiled_code = compile(mem, parse(mem, code)?)?;
+        thread.quick_vm_eval(mem, compiled_code)
+    }
+
+    fn test_helper(test_fn: fn(&MutatorView) -> Result<(), RuntimeError>) {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = fn(&MutatorView) -> Result<(), RuntimeError>;
+            type Output = ();
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                test_fn: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                test_fn(mem)
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, test_fn).unwrap();
+    }
+
+    #[test]
+    fn builtin_format() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let result = eval_helper(mem, t, "(format \"{} + {} = {}\" 1 'b \"c\")")?;
+            assert!(format!("{}", result) == "\"1 + b = c\"");
+
+            let result = eval_helper(mem, t, "(format \"{{}} {}\" '(a b))")?;
+            assert!(format!("{}", result) == "\"{} (a b)\"");
+
+            assert!(eval_helper(mem, t, "(format \"{} {}\" 1)").is_err());
+            assert!(eval_helper(mem, t, "(format \"{}\" 1 2)").is_err());
+            assert!(eval_helper(mem, t, "(format \"{\" 1)").is_err());
+            assert!(eval_helper(mem, t, "(format 'a)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_symbol_string_conversion() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let result = eval_helper(mem, t, "(symbol->string 'hello)")?;
+            assert!(format!("{}", result) == "\"hello\"");
+
+            // the symbol is interned, so it is identical to one named in source code
+            let result = eval_helper(mem, t, "(string->symbol \"a-new-symbol\")")?;
+            assert!(result == mem.lookup_sym("a-new-symbol"));
+            let result = eval_helper(mem, t, "(is? (string->symbol \"x\") 'x)")?;
+            assert!(result == mem.lookup_sym("true"));
+
+            let result = eval_helper(mem, t, "(string->symbol (symbol->string 'round-trip))")?;
+            assert!(result == mem.lookup_sym("round-trip"));
+
+            assert!(eval_helper(mem, t, "(symbol->string \"a\")").is_err());
+            assert!(eval_helper(mem, t, "(string->symbol 'a)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_string_and_symbol_comparison() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            let eval = |code| -> Result<String, RuntimeError> {
+                Ok(format!("{}", eval_helper(mem, t, code)?))
+            };
+
+            assert!(eval("(string<? \"apple\" \"banana\")")? == "true");
+            assert!(eval("(string<? \"banana\" \"apple\")")? == "nil");
+            assert!(eval("(string<? \"a\" \"a\")")? == "nil");
+            assert!(eval("(string<? \"\" \"a\")")? == "true");
+            assert!(eval("(symbol<? 'abc 'abd)")? == "true");
+            assert!(eval("(symbol<? 'b 'a)")? == "nil");
+
+            assert!(eval("(string-upcase \"Hello, wörld\")")? == "\"HELLO, WÖRLD\"");
+            assert!(eval("(string-downcase \"Hello\")")? == "\"hello\"");
+
+            assert!(eval_helper(mem, t, "(string<? 'a \"b\")").is_err());
+            assert!(eval_helper(mem, t, "(symbol<? 'a \"b\")").is_err());
+            assert!(eval_helper(mem, t, "(string-upcase 'a)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_integer_arrays() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, "(set 'b (make-bytes 3))")?;
+            assert!(format!("{}", eval_helper(mem, t, "b")?) == "#u8(0 0 0)");
+            assert!(eval_helper(mem, t, "(array-length b)")? == mem.number(3));
+
+            assert!(eval_helper(mem, t, "(array-set b 1 255)")? == mem.number(255));
+            assert!(eval_helper(mem, t, "(array-get b 1)")? == mem.number(255));
+            assert!(format!("{}", eval_helper(mem, t, "b")?) == "#u8(0 255 0)");
+
+            // indexes and values are range checked
+            assert!(eval_helper(mem, t, "(array-get b 3)").is_err());
+            assert!(eval_helper(mem, t, "(array-set b 0 256)").is_err());
+            assert!(eval_helper(mem, t, "(array-set b 0 -1)").is_err());
+            assert!(eval_helper(mem, t, "(make-bytes -1)").is_err());
+            assert!(eval_helper(mem, t, "(array-length 'b)").is_err());
+
+            eval_helper(mem, t, "(set 'w (array-fill (make-words 2) 70000))")?;
+            assert!(format!("{}", eval_helper(mem, t, "w")?) == "#u32(70000 70000)");
+            assert!(eval_helper(mem, t, "(array-get w 0)")? == mem.number(70000));
+
+            assert!(format!("{}", eval_helper(mem, t, "(make-bytes 0)")?) == "#u8()");
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_bytes_string_conversion() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let result = eval_helper(mem, t, "(string->bytes \"hé\")")?;
+            assert!(format!("{}", result) == "#u8(104 195 169)");
+
+            let result = eval_helper(mem, t, "(bytes->string #u8(104 195 169))")?;
+            assert!(format!("{}", result) == "\"hé\"");
+
+            let result = eval_helper(mem, t, "(bytes->string (string->bytes \"round trip\"))")?;
+            assert!(format!("{}", result) == "\"round trip\"");
+
+            // invalid UTF-8 is a runtime error
+            assert!(eval_helper(mem, t, "(bytes->string #u8(104 195))").is_err());
+            assert!(eval_helper(mem, t, "(bytes->string \"a\")").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_print_to_output_port() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let mut path = env::temp_dir();
+            path.push(format!("evalrus-print-{}", process::id()));
+            let path = String::from(path.to_str().unwrap());
+
+            t.set_output_port(Port::open(mem, &path, "w")?);
+
+            let result = eval_helper(mem, t, "(print \"a\" 'b 3)")?;
+            assert!(result == mem.nil());
+            eval_helper(mem, t, "(println)")?;
+            eval_helper(mem, t, "(println \"x\" (cons 1 2))")?;
+
+            t.output_port(mem).close(mem)?;
+
+            assert!(fs::read_to_string(&path)? == "a b 3\nx (1 . 2)\n");
+            fs::remove_file(&path)?;
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_getenv() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            env::set_var("EVALRUS_GETENV_TEST", "some value");
+            let result = eval_helper(mem, t, "(getenv \"EVALRUS_GETENV_TEST\")")?;
+            assert!(format!("{}", result) == "\"some value\"");
+
+            let result = eval_helper(mem, t, "(getenv \"EVALRUS_GETENV_TEST_UNSET\")")?;
+            assert!(result == mem.nil());
+
+            assert!(eval_helper(mem, t, "(getenv 1)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_heap_dump() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let mut path = env::temp_dir();
+            path.push(format!("evalrus-heap-dump-{}", process::id()));
+            let path = path.to_str().unwrap();
+
+            let pairs = |dump: &HeapDump| dump.types.get("Pair").map_or(0, |pairs| pairs.count);
+            let before = mem.dump_heap();
+            eval_helper(mem, t, "(set 'kept (cons 'a (cons 'b nil)))")?;
+
+            let code = format!("(heap-dump \"{}\")", path);
+            let objects = eval_helper(mem, t, &code)?;
+
+            let json = fs::read_to_string(path)?;
+            fs::remove_file(path)?;
+
+            let after = mem.dump_heap();
+            assert!(objects == mem.number(after.objects as isize));
+            assert!(json == after.to_json());
+            assert!(json.contains("\"Pair\": {\"count\": "));
+            assert!(pairs(&after) >= pairs(&before) + 2);
+
+            assert!(eval_helper(mem, t, "(heap-dump 1)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_argv() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let result = eval_helper(mem, t, "(argv)")?;
+            assert!(result == mem.nil());
+
+            t.set_argv(mem, &[String::from("-n"), String::from("two words")])?;
+            let result = eval_helper(mem, t, "(argv)")?;
+            assert!(format!("{}", result) == "(\"-n\" \"two words\")");
+
+            let result = eval_helper(mem, t, "(car (cdr (argv)))")?;
+            assert!(format!("{}", result) == "\"two words\"");
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_exit() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, "(def f (n) (cons 'a (exit n)))")?;
+
+            match eval_helper(mem, t, "(f 3)") {
+                Err(e) => assert!(*e.error_kind() == ErrorKind::Exit(3)),
+                Ok(_) => panic!("expected an exit"),
+            }
+
+            // the thread was unwound and is usable afterwards
+            assert!(!t.is_evaluating(mem));
+            assert!(format!("{}", eval_helper(mem, t, "(cons 'a 'b)")?) == "(a . b)");
+
+            assert!(eval_helper(mem, t, "(exit 'a)").is_err());
+            assert!(eval_helper(mem, t, "(exit 4294967296)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_doc() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, "(def pick (a b) \"Return b\" b)")?;
+            assert!(format!("{}", eval_helper(mem, t, "(doc pick)")?) == "\"Return b\"");
+            assert!(format!("{}", eval_helper(mem, t, "(doc (pick 'a))")?) == "\"Return b\"");
+            assert!(eval_helper(mem, t, "(pick 'a 'b)")? == mem.lookup_sym("b"));
+
+            let result = eval_helper(mem, t, "(doc (lambda (x) \"Identity\" x))")?;
+            assert!(format!("{}", result) == "\"Identity\"");
+
+            // a string that is the only expression is the result, not documentation
+            eval_helper(mem, t, "(def name () \"evalrus\")")?;
+            assert!(eval_helper(mem, t, "(doc name)")? == mem.nil());
+            assert!(format!("{}", eval_helper(mem, t, "(name)")?) == "\"evalrus\"");
+
+            assert!(eval_helper(mem, t, "(doc doc)")? == mem.nil());
+            assert!(eval_helper(mem, t, "(doc 'pick)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_source_of() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            let source_of = |code| -> Result<String, RuntimeError> {
+                Ok(format!("{:#}", eval_helper(mem, t, code)?))
+            };
+
+            eval_helper(mem, t, "\n  (def pick (a b) b)")?;
+            assert!(source_of("(source-of pick)")? == "{column: 3, line: 2}");
+            assert!(source_of("(source-of (pick 'a))")? == "{column: 3, line: 2}");
+            assert!(source_of("(source-of (lambda (x) x))")? == "{column: 12, line: 1}");
+
+            let function =
+                compile_in_file(mem, parse(mem, "(def first (l) (car l))")?, "lib.lisp")?.0;
+            t.quick_vm_eval(mem, function)?;
+            assert!(source_of("(source-of first)")? == "{column: 1, file: \"lib.lisp\", line: 1}");
+
+            assert!(eval_helper(mem, t, "(source-of format)")? == mem.nil());
+            assert!(eval_helper(mem, t, "(source-of 'first)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_backtrace_and_stack_depth() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            let eval = |code| -> Result<String, RuntimeError> {
+                Ok(format!("{:#}", eval_helper(mem, t, code)?))
+            };
+
+            eval_helper(mem, t, "(def inner () (backtrace))")?;
+            eval_helper(mem, t, "(def outer ()\n  (cons 'x (inner)))")?;
+            // innermost first, ending with the toplevel expression, which has no name
+            assert!(
+                eval("(outer)")?
+                    == "(x (inner 2 {line: 1, column: 15}) (outer 3 {line: 2, column: 12}) \
+                        (nil 2 {line: 1, column: 1}))"
+            );
+
+            eval_helper(mem, t, "(def depth () (stack-depth))")?;
+            assert!(eval("(stack-depth)")? == "1");
+            assert!(eval("(depth)")? == "2");
+            assert!(eval("(cons (depth) (stack-depth))")? == "(2 . 1)");
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_last_error() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            let eval = |code| -> Result<String, RuntimeError> {
+                Ok(format!("{:#}", eval_helper(mem, t, code)?))
+            };
+
+            eval_helper(mem, t, "(def f (x) (car x))")?;
+            assert!(eval_helper(mem, t, "(f 'a)").is_err());
+            assert!(eval("(last-error)")? == "nil");
+
+            t.set_keep_failed_frames(true);
+            assert!(eval_helper(mem, t, "(f 'a)").is_err());
+            let error = eval("(last-error)")?;
+            assert!(error.contains("code: eval-error"));
+            assert!(error.contains("message: \"Evaluation error: Parameter to FirstOfPair"));
+
+            // the innermost frame is f's, failing on its first instruction, then the caller's
+            assert!(error.contains(
+                "{instruction: (first-of-pair 3 2), ip: 0, function: (Function f (x)), \
+                 registers: (nil nil a (Function f (x)))}"
+            ));
+            assert!(error.contains("{instruction: (call 5 2 1), ip: 3, function: (Function ())"));
+
+            // the snapshot is kept after later evaluations, until the next failure
+            assert!(eval("'ok")? == "ok");
+            assert!(eval("(last-error)")? == error);
+            assert!(eval_helper(mem, t, "(car 'b)").is_err());
+            assert!(eval("(last-error)")?.contains("not a list: b (symbol)"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_assertions() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let failure = |result: Result<TaggedScopedPtr, RuntimeError>| match result {
+                Err(e) => match e.error_kind() {
+                    ErrorKind::EvalError(reason) => (reason.clone(), e.error_pos()),
+                    _ => panic!("expected an evaluation error"),
+                },
+                Ok(_) => panic!("expected an assertion failure"),
+            };
+
+            assert!(eval_helper(mem, t, "(assert (is? 'a 'a) \"same\")")? == mem.nil());
+
+            let (reason, pos) = failure(eval_helper(mem, t, "(cons 'a (assert nil \"no\"))"));
+            assert!(reason == "Assertion failed: no");
+            assert!(pos.map(|pos| (pos.line, pos.column)) == Some((1, 10)));
+
+            // assert-eq compares lists and strings by content
+            let code = "(assert-eq '(a \"b\" 3) (cons 'a (cons \"b\" (cons 3 nil))))";
+            assert!(eval_helper(mem, t, code)? == mem.nil());
+
+            let (reason, _) = failure(eval_helper(mem, t, "(assert-eq '(a b) '(a c))"));
+            assert!(reason == "Assertion failed: (a b) is not equal to (a c)");
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_deftest_and_run_tests() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let mut path = env::temp_dir();
+            path.push(format!("evalrus-run-tests-{}", process::id()));
+            let path = String::from(path.to_str().unwrap());
+
+            t.set_output_port(Port::open(mem, &path, "w")?);
+
+            eval_helper(mem, t, "(def second (l) (car (cdr l)))")?;
+            eval_helper(
+                mem,
+                t,
+                "(deftest second-of-two (assert-eq (second '(a b)) 'b))",
+            )?;
+            eval_helper(
+                mem,
+                t,
+                "(deftest second-of-one (assert-eq (second '(a)) 'a))",
+            )?;
+            eval_helper(mem, t, "(deftest not-a-list (second 'a))")?;
+
+            // redefining a test replaces it
+            eval_helper(mem, t, "(deftest not-a-list (second '(a)))")?;
+
+            assert!(eval_helper(mem, t, "(run-tests)")? == mem.number(1));
+            assert!(format!("{}", eval_helper(mem, t, "(deftest x 'a)")?) == "(Function x ())");
+            assert!(eval_helper(mem, t, "(register-test 'y second)").is_err());
+
+            t.output_port(mem).close(mem)?;
+
+            let output = fs::read_to_string(&path)?;
+            fs::remove_file(&path)?;
+
+            assert!(
+                output
+                    == "FAIL second-of-one: Evaluation error: Assertion failed: nil is not equal \
+                        to a (line 1, column 24)\n\
+                        2 passed, 1 failed\n"
+            );
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_clock_and_time() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let first = eval_helper(mem, t, "(clock-monotonic)")?;
+            let second = eval_helper(mem, t, "(clock-monotonic)")?;
+            match (*first, *second) {
+                (Value::Number(first), Value::Number(second)) => assert!(second >= first),
+                _ => panic!("expected numbers"),
+            }
+
+            let mut path = env::temp_dir();
+            path.push(format!("evalrus-time-{}", process::id()));
+            let path = String::from(path.to_str().unwrap());
+
+            t.set_output_port(Port::open(mem, &path, "w")?);
+
+            let result = eval_helper(mem, t, "(time (cons 'a 'b))")?;
+            assert!(format!("{}", result) == "(a . b)");
+
+            t.output_port(mem).close(mem)?;
+
+            let report = fs::read_to_string(&path)?;
+            assert!(report.starts_with("Elapsed time: ") && report.ends_with(" ms\n"));
+            fs::remove_file(&path)?;
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_random_with_seed() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            fn sequence<'guard>(
+                mem: &'guard MutatorView,
+                t: ScopedPtr<'guard, Thread>,
+            ) -> Result<Vec<isize>, RuntimeError> {
+                let mut values = Vec::new();
+                for _ in 0..20 {
+                    match *eval_helper(mem, t, "(random 10)")? {
+                        Value::Number(n) => values.push(n),
+                        _ => panic!("expected a number"),
+                    }
+                }
+                Ok(values)
+            }
+
+            eval_helper(mem, t, "(random-seed 1234)")?;
+            let first = sequence(mem, t)?;
+
+            eval_helper(mem, t, "(random-seed 1234)")?;
+            let second = sequence(mem, t)?;
+
+            assert!(first == second);
+            assert!(first.iter().all(|n| *n >= 0 && *n < 10));
+
+            // an identical seed on another Thread gives the same sequence
+            let other = Thread::alloc(mem)?;
+            eval_helper(mem, other, "(random-seed 1234)")?;
+            assert!(sequence(mem, other)? == first);
+
+            assert!(eval_helper(mem, t, "(random 0)").is_err());
+            assert!(eval_helper(mem, t, "(random 'a)").is_err());
+            assert!(eval_helper(mem, t, "(random-seed \"x\")").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn builtin_hash() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            let hash = |code| -> Result<isize, RuntimeError> {
+                match *eval_helper(mem, t, code)? {
+                    Value::Number(n) => Ok(n),
+                    _ => panic!("expected a number"),
+                }
+            };
+
+            // the hash of a value is stable, and is the one Dicts use
+            assert!(hash("(hash 'key)")? == hash("(hash 'key)")?);
+            let runtime_hash = hash_value(mem, mem.lookup_sym("key"))?;
+            assert!(hash("(hash 'key)")? == (runtime_hash & INLINE_INTEGER_MAX as u64) as isize);
+            assert!(hash("(hash 'key)")? != hash("(hash 'other)")?);
+            assert!(hash("(hash 42)")? == 42);
+
+            // a symbol and a string with the same name hash the same
+            assert!(hash("(hash \"key\")")? == hash("(hash 'key)")?);
+            assert!(hash("(hash -1)")? >= 0);
+
+            assert!(eval_helper(mem, t, "(hash '(a b))").is_err());
+            assert!(eval_helper(mem, t, "(hash hash)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+}
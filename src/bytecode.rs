@@ -1,17 +1,21 @@
 use itertools::join;
 use std::cell::Cell;
+use std::convert::TryFrom;
 use std::fmt;
 
 use crate::array::{Array, ArraySize};
 use crate::containers::{
-    Container, IndexedContainer, SliceableContainer, StackAnyContainer, StackContainer,
+    Container, FillAnyContainer, IndexedAnyContainer, IndexedContainer, SliceableContainer,
+    StackAnyContainer, StackContainer,
 };
-use crate::error::{err_eval, RuntimeError};
+use crate::error::{err_eval, RuntimeError, SourcePos};
+use crate::global::GlobalCell;
 use crate::list::List;
 use crate::memory::MutatorView;
 use crate::printer::Print;
 use crate::safeptr::{CellPtr, MutatorScope, ScopedPtr, TaggedScopedPtr};
-use crate::taggedptr::TaggedPtr;
+use crate::taggedptr::Value;
+use crate::vm::FIRST_ARG_REG;
 
 /// A register can be in the range 0..255
 pub type Register = u8;
@@ -27,8 +31,6 @@ pub type UpvalueId = u8;
 
 /// An instruction jump target is a signed integer, relative to the jump instruction
 pub type JumpOffset = i16;
-/// Jump offset when the target is still unknown.
-pub const JUMP_UNKNOWN: i16 = 0x7fff;
 
 /// Argument count for a function call or partial application
 pub type NumArgs = u8;
@@ -36,6 +38,106 @@ pub type NumArgs = u8;
 /// Count of call frames to look back to find a nonlocal
 pub type FrameOffset = u8;
 
+/// A `ParamType` as an instruction operand
+pub type ParamTypeId = u8;
+
+/// A type that a function parameter can be annotated with, as in `(def f ((a number)) ...)`, and
+/// that is checked by a `CheckType` instruction when the function is entered
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParamType {
+    Number,
+    Integer,
+    Float,
+    Symbol,
+    Pair,
+    List,
+    String,
+    Char,
+    Function,
+    Dict,
+    Array,
+    Port,
+}
+
+impl ParamType {
+    /// Every ParamType, indexed by id
+    const ALL: [ParamType; 12] = [
+        ParamType::Number,
+        ParamType::Integer,
+        ParamType::Float,
+        ParamType::Symbol,
+        ParamType::Pair,
+        ParamType::List,
+        ParamType::String,
+        ParamType::Char,
+        ParamType::Function,
+        ParamType::Dict,
+        ParamType::Array,
+        ParamType::Port,
+    ];
+
+    /// Return the type with the given annotation name
+    pub fn from_name(name: &str) -> Option<ParamType> {
+        ParamType::ALL.iter().copied().find(|t| t.name() == name)
+    }
+
+    /// Return the type with the given instruction operand id
+    pub fn from_id(id: ParamTypeId) -> Option<ParamType> {
+        ParamType::ALL.get(id as usize).copied()
+    }
+
+    /// Return the instruction operand id of the type
+    pub fn id(self) -> ParamTypeId {
+        self as ParamTypeId
+    }
+
+    /// Return the annotation name of the type
+    pub fn name(self) -> &'static str {
+        match self {
+            ParamType::Number => "number",
+            ParamType::Integer => "integer",
+            ParamType::Float => "float",
+            ParamType::Symbol => "symbol",
+            ParamType::Pair => "pair",
+            ParamType::List => "list",
+            ParamType::String => "string",
+            ParamType::Char => "char",
+            ParamType::Function => "function",
+            ParamType::Dict => "dict",
+            ParamType::Array => "array",
+            ParamType::Port => "port",
+        }
+    }
+
+    /// Return true if the value is of this type. A list is nil or a pair: the rest of the list is
+    /// not checked.
+    pub fn matches(self, value: &Value) -> bool {
+        match self {
+            ParamType::Number => matches!(
+                value,
+                Value::Number(_) | Value::NumberObject(_) | Value::Float(_)
+            ),
+            ParamType::Integer => matches!(value, Value::Number(_) | Value::NumberObject(_)),
+            ParamType::Float => matches!(value, Value::Float(_)),
+            ParamType::Symbol => matches!(value, Value::Symbol(_)),
+            ParamType::Pair => matches!(value, Value::Pair(_)),
+            ParamType::List => matches!(value, Value::Nil | Value::Pair(_)),
+            ParamType::String => matches!(value, Value::Text(_)),
+            ParamType::Char => matches!(value, Value::Char(_)),
+            ParamType::Function => matches!(
+                value,
+                Value::Function(_) | Value::Partial(_) | Value::NativeFunction(_)
+            ),
+            ParamType::Dict => matches!(value, Value::Dict(_)),
+            ParamType::Array => matches!(
+                value,
+                Value::List(_) | Value::ArrayU8(_) | Value::ArrayU16(_) | Value::ArrayU32(_)
+            ),
+            ParamType::Port => matches!(value, Value::Port(_)),
+        }
+    }
+}
+
 /// VM opcodes. These enum variants should be designed to fit into 32 bits. Using
 /// u8 representation seems to make that happen, so long as the struct variants
 /// do not add up to more than 24 bits.
@@ -62,6 +164,10 @@ pub enum Opcode {
         dest: Register,
         test: Register,
     },
+    IsPair {
+        dest: Register,
+        test: Register,
+    },
     FirstOfPair {
         dest: Register,
         reg: Register,
@@ -75,6 +181,22 @@ pub enum Opcode {
         reg1: Register,
         reg2: Register,
     },
+    UnpackPair {
+        first: Register,
+        second: Register,
+        src: Register,
+    },
+    ListLength {
+        dest: Register,
+        reg: Register,
+    },
+    ListReverse {
+        dest: Register,
+        reg: Register,
+    },
+    ExpectNil {
+        test: Register,
+    },
     IsIdentical {
         dest: Register,
         test1: Register,
@@ -94,6 +216,9 @@ pub enum Opcode {
     LoadNil {
         dest: Register,
     },
+    LoadTrue {
+        dest: Register,
+    },
     LoadGlobal {
         dest: Register,
         name: Register,
@@ -111,6 +236,10 @@ pub enum Opcode {
         dest: Register,
         function: Register,
     },
+    MakePromise {
+        dest: Register,
+        thunk: Register,
+    },
     LoadInteger {
         dest: Register,
         integer: LiteralInteger,
@@ -139,6 +268,45 @@ pub enum Opcode {
         num: Register,
         denom: Register,
     },
+    BitAnd {
+        dest: Register,
+        reg1: Register,
+        reg2: Register,
+    },
+    BitOr {
+        dest: Register,
+        reg1: Register,
+        reg2: Register,
+    },
+    BitXor {
+        dest: Register,
+        reg1: Register,
+        reg2: Register,
+    },
+    ShiftLeft {
+        dest: Register,
+        value: Register,
+        count: Register,
+    },
+    ShiftRight {
+        dest: Register,
+        value: Register,
+        count: Register,
+    },
+    Min {
+        dest: Register,
+        reg1: Register,
+        reg2: Register,
+    },
+    Max {
+        dest: Register,
+        reg1: Register,
+        reg2: Register,
+    },
+    Abs {
+        dest: Register,
+        reg: Register,
+    },
     GetUpvalue {
         dest: Register,
         src: UpvalueId,
@@ -152,21 +320,237 @@ pub enum Opcode {
         reg2: Register,
         reg3: Register,
     },
+    ReadClock {
+        dest: Register,
+    },
+    PrintElapsed {
+        start: Register,
+    },
+    CheckType {
+        reg: Register,
+        param_type: ParamTypeId,
+    },
+}
+
+impl Opcode {
+    /// Return the registers the instruction reads or writes
+    pub fn registers(&self) -> Vec<Register> {
+        match *self {
+            Opcode::NoOp | Opcode::Jump { .. } => vec![],
+            Opcode::Return { reg } => vec![reg],
+            Opcode::LoadLiteral { dest, .. } => vec![dest],
+            Opcode::IsNil { dest, test } => vec![dest, test],
+            Opcode::IsAtom { dest, test } => vec![dest, test],
+            Opcode::IsPair { dest, test } => vec![dest, test],
+            Opcode::FirstOfPair { dest, reg } => vec![dest, reg],
+            Opcode::SecondOfPair { dest, reg } => vec![dest, reg],
+            Opcode::MakePair { dest, reg1, reg2 } => vec![dest, reg1, reg2],
+            Opcode::UnpackPair { first, second, src } => vec![first, second, src],
+            Opcode::ListLength { dest, reg } => vec![dest, reg],
+            Opcode::ListReverse { dest, reg } => vec![dest, reg],
+            Opcode::ExpectNil { test } => vec![test],
+            Opcode::IsIdentical { dest, test1, test2 } => vec![dest, test1, test2],
+            Opcode::JumpIfTrue { test, .. } => vec![test],
+            Opcode::JumpIfNotTrue { test, .. } => vec![test],
+            Opcode::LoadNil { dest } => vec![dest],
+            Opcode::LoadTrue { dest } => vec![dest],
+            Opcode::LoadGlobal { dest, name } => vec![dest, name],
+            Opcode::StoreGlobal { src, name } => vec![src, name],
+            Opcode::Call {
+                function,
+                dest,
+                arg_count,
+            } => {
+                // arguments are passed in the registers following the callee's reserved registers
+                let first_arg = dest + FIRST_ARG_REG as Register;
+                let mut registers = vec![function, dest];
+                registers.extend(first_arg..first_arg + arg_count);
+                registers
+            }
+            Opcode::MakeClosure { dest, function } => vec![dest, function],
+            Opcode::MakePromise { dest, thunk } => vec![dest, thunk],
+            Opcode::LoadInteger { dest, .. } => vec![dest],
+            Opcode::CopyRegister { dest, src } => vec![dest, src],
+            Opcode::Add { dest, reg1, reg2 } => vec![dest, reg1, reg2],
+            Opcode::Subtract { dest, left, right } => vec![dest, left, right],
+            Opcode::Multiply { dest, reg1, reg2 } => vec![dest, reg1, reg2],
+            Opcode::DivideInteger { dest, num, denom } => vec![dest, num, denom],
+            Opcode::BitAnd { dest, reg1, reg2 } => vec![dest, reg1, reg2],
+            Opcode::BitOr { dest, reg1, reg2 } => vec![dest, reg1, reg2],
+            Opcode::BitXor { dest, reg1, reg2 } => vec![dest, reg1, reg2],
+            Opcode::ShiftLeft { dest, value, count } => vec![dest, value, count],
+            Opcode::ShiftRight { dest, value, count } => vec![dest, value, count],
+            Opcode::Min { dest, reg1, reg2 } => vec![dest, reg1, reg2],
+            Opcode::Max { dest, reg1, reg2 } => vec![dest, reg1, reg2],
+            Opcode::Abs { dest, reg } => vec![dest, reg],
+            Opcode::GetUpvalue { dest, .. } => vec![dest],
+            Opcode::SetUpvalue { src, .. } => vec![src],
+            Opcode::CloseUpvalues { reg1, reg2, reg3 } => vec![reg1, reg2, reg3],
+            Opcode::ReadClock { dest } => vec![dest],
+            Opcode::PrintElapsed { start } => vec![start],
+            Opcode::CheckType { reg, .. } => vec![reg],
+        }
+    }
+}
+
+/// Generate the mapping between each Opcode variant and its name and list of integer operands,
+/// so that code can be written out and read back in a form independent of the in-memory layout
+macro_rules! opcode_codec {
+    ($($variant:ident $name:literal { $($field:ident: $ty:ty),* }),* $(,)?) => {
+        impl Opcode {
+            /// Return the name of the instruction, for example "load-literal"
+            pub fn name(&self) -> &'static str {
+                match *self {
+                    $(Opcode::$variant { .. } => $name,)*
+                }
+            }
+
+            /// Return the operands of the instruction, in field declaration order
+            pub fn operands(&self) -> Vec<isize> {
+                match *self {
+                    $(Opcode::$variant { $($field),* } => vec![$($field as isize),*],)*
+                }
+            }
+
+            /// Build an instruction from its name and operands, checking that each operand is in
+            /// range for its field
+            pub fn from_operands(name: &str, operands: &[isize]) -> Result<Opcode, RuntimeError> {
+                match name {
+                    $($name => {
+                        let fields: &[&str] = &[$(stringify!($field)),*];
+                        if operands.len() != fields.len() {
+                            return Err(err_eval(&format!(
+                                "Instruction {} takes {} operands, {} were given",
+                                name,
+                                fields.len(),
+                                operands.len()
+                            )));
+                        }
+
+                        // NoOp has no operands to read
+                        #[allow(unused_mut, unused_variables)]
+                        let mut operands = operands.iter();
+                        $(
+                            let operand = *operands.next().unwrap();
+                            let $field = <$ty>::try_from(operand).map_err(|_| {
+                                err_eval(&format!(
+                                    "Operand {} of {} is out of range: {}",
+                                    stringify!($field),
+                                    name,
+                                    operand
+                                ))
+                            })?;
+                        )*
+
+                        Ok(Opcode::$variant { $($field),* })
+                    })*
+                    _ => Err(err_eval(&format!("Unknown instruction {}", name))),
+                }
+            }
+        }
+    };
+}
+
+opcode_codec! {
+    NoOp "no-op" {},
+    Return "return" { reg: Register },
+    LoadLiteral "load-literal" { dest: Register, literal_id: LiteralId },
+    IsNil "is-nil" { dest: Register, test: Register },
+    IsAtom "is-atom" { dest: Register, test: Register },
+    IsPair "is-pair" { dest: Register, test: Register },
+    FirstOfPair "first-of-pair" { dest: Register, reg: Register },
+    SecondOfPair "second-of-pair" { dest: Register, reg: Register },
+    MakePair "make-pair" { dest: Register, reg1: Register, reg2: Register },
+    UnpackPair "unpack-pair" { first: Register, second: Register, src: Register },
+    ListLength "list-length" { dest: Register, reg: Register },
+    ListReverse "list-reverse" { dest: Register, reg: Register },
+    ExpectNil "expect-nil" { test: Register },
+    IsIdentical "is-identical" { dest: Register, test1: Register, test2: Register },
+    Jump "jump" { offset: JumpOffset },
+    JumpIfTrue "jump-if-true" { test: Register, offset: JumpOffset },
+    JumpIfNotTrue "jump-if-not-true" { test: Register, offset: JumpOffset },
+    LoadNil "load-nil" { dest: Register },
+    LoadTrue "load-true" { dest: Register },
+    LoadGlobal "load-global" { dest: Register, name: Register },
+    StoreGlobal "store-global" { src: Register, name: Register },
+    Call "call" { function: Register, dest: Register, arg_count: NumArgs },
+    MakeClosure "make-closure" { dest: Register, function: Register },
+    MakePromise "make-promise" { dest: Register, thunk: Register },
+    LoadInteger "load-integer" { dest: Register, integer: LiteralInteger },
+    CopyRegister "copy-register" { dest: Register, src: Register },
+    Add "add" { dest: Register, reg1: Register, reg2: Register },
+    Subtract "subtract" { dest: Register, left: Register, right: Register },
+    Multiply "multiply" { dest: Register, reg1: Register, reg2: Register },
+    DivideInteger "divide-integer" { dest: Register, num: Register, denom: Register },
+    BitAnd "bit-and" { dest: Register, reg1: Register, reg2: Register },
+    BitOr "bit-or" { dest: Register, reg1: Register, reg2: Register },
+    BitXor "bit-xor" { dest: Register, reg1: Register, reg2: Register },
+    ShiftLeft "shift-left" { dest: Register, value: Register, count: Register },
+    ShiftRight "shift-right" { dest: Register, value: Register, count: Register },
+    Min "min" { dest: Register, reg1: Register, reg2: Register },
+    Max "max" { dest: Register, reg1: Register, reg2: Register },
+    Abs "abs" { dest: Register, reg: Register },
+    GetUpvalue "get-upvalue" { dest: Register, src: UpvalueId },
+    SetUpvalue "set-upvalue" { dest: UpvalueId, src: Register },
+    CloseUpvalues "close-upvalues" { reg1: Register, reg2: Register, reg3: Register },
+    ReadClock "read-clock" { dest: Register },
+    PrintElapsed "print-elapsed" { start: Register },
+    CheckType "check-type" { reg: Register, param_type: ParamTypeId },
 }
 
 /// Bytecode is stored as fixed-width 32-bit values.
 /// This is not the most efficient format but it is easy to work with.
 pub type ArrayOpcode = Array<Opcode>;
 
+/// The range of source code an instruction was compiled from, from the position of the first
+/// token of an expression to the position of its last token
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SourceSpan {
+    pub start: SourcePos,
+    pub end: SourcePos,
+}
+
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.start.line, self.start.column)
+    }
+}
+
+/// Source spans are stored in parallel with the instructions they describe
+pub type ArraySourceSpan = Array<Option<SourceSpan>>;
+
+/// A jump instruction whose target is not known yet, returned by `ByteCode::push_jump()`. It must
+/// be given to `ByteCode::patch()` once the code to jump to is about to be pushed. Until then the
+/// jump lands on the instruction after it.
+#[must_use = "a jump must be patched before the Function is finished"]
+#[derive(Debug)]
+pub struct JumpPatch {
+    /// Index of the jump instruction
+    instruction: ArraySize,
+}
+
+impl JumpPatch {
+    /// Return the index of the jump instruction
+    pub fn instruction(&self) -> ArraySize {
+        self.instruction
+    }
+}
+
 /// Literals are stored in a separate list of machine-word-width pointers.
 /// This is also not the most efficient scheme but it is easy to work with.
 pub type Literals = List;
 
-/// Byte code consists of the code and any literals used.
+/// Byte code consists of the code, any literals used and a map from each instruction back to
+/// source code. Literals are interned in the Memory constant pool before they are added, so that
+/// equivalent literals of different functions are the same object.
 #[derive(Clone)]
 pub struct ByteCode {
     code: ArrayOpcode,
     literals: Literals,
+    spans: ArraySourceSpan,
+    /// The GlobalCell last found by each `LoadGlobal` instruction, indexed by instruction. Only as
+    /// long as the last instruction that has cached a cell.
+    global_cells: List,
 }
 
 impl ByteCode {
@@ -177,12 +561,58 @@ impl ByteCode {
         mem.alloc(ByteCode {
             code: ArrayOpcode::new(),
             literals: Literals::new(),
+            spans: ArraySourceSpan::new(),
+            global_cells: List::new(),
         })
     }
 
     /// Append an instuction to the back of the sequence
     pub fn push<'guard>(&self, mem: &'guard MutatorView, op: Opcode) -> Result<(), RuntimeError> {
-        self.code.push(mem, op)
+        self.push_with_span(mem, op, None)
+    }
+
+    /// Append an instruction to the back of the sequence, recording the source code it was
+    /// compiled from
+    pub fn push_with_span<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        op: Opcode,
+        span: Option<SourceSpan>,
+    ) -> Result<(), RuntimeError> {
+        self.code.push(mem, op)?;
+        self.spans.push(mem, span)
+    }
+
+    /// Return the source code span the instruction at the given index was compiled from, if known
+    pub fn source_span<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        instruction: ArraySize,
+    ) -> Option<SourceSpan> {
+        self.spans.get(guard, instruction).ok().flatten()
+    }
+
+    /// Return the GlobalCell cached by the `LoadGlobal` instruction at the given index, if any
+    pub fn cached_global<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        instruction: ArraySize,
+    ) -> Option<ScopedPtr<'guard, GlobalCell>> {
+        match *IndexedAnyContainer::get(&self.global_cells, guard, instruction).ok()? {
+            Value::GlobalCell(cell) => Some(cell),
+            _ => None,
+        }
+    }
+
+    /// Cache the GlobalCell found by the `LoadGlobal` instruction at the given index
+    pub fn cache_global<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        instruction: ArraySize,
+        cell: ScopedPtr<'guard, GlobalCell>,
+    ) -> Result<(), RuntimeError> {
+        FillAnyContainer::fill(&self.global_cells, mem, instruction + 1, mem.nil())?;
+        IndexedAnyContainer::set(&self.global_cells, mem, instruction, cell.as_tagged(mem))
     }
 
     /// Set the jump offset of an existing jump instruction to a new value
@@ -207,27 +637,110 @@ impl ByteCode {
         Ok(())
     }
 
-    /// Append a literal-load operation to the back of the sequence
-    pub fn push_loadlit<'guard>(
+    /// Replace the instruction at the given index, keeping the source code span recorded for it
+    pub fn replace<'guard>(
         &self,
         mem: &'guard MutatorView,
-        dest: Register,
-        literal_id: LiteralId,
+        instruction: ArraySize,
+        op: Opcode,
     ) -> Result<(), RuntimeError> {
-        // TODO clone anything mutable
-        self.code
-            .push(mem, Opcode::LoadLiteral { dest, literal_id })
+        self.code.set(mem, instruction, op)
+    }
+
+    /// Append a jump instruction whose target is not known yet, recording the source code it was
+    /// compiled from, and return the patch that sets the target. The offset of the given
+    /// instruction is replaced.
+    pub fn push_jump<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        op: Opcode,
+        span: Option<SourceSpan>,
+    ) -> Result<JumpPatch, RuntimeError> {
+        let op = match op {
+            Opcode::Jump { .. } => Opcode::Jump { offset: 0 },
+            Opcode::JumpIfTrue { test, .. } => Opcode::JumpIfTrue { test, offset: 0 },
+            Opcode::JumpIfNotTrue { test, .. } => Opcode::JumpIfNotTrue { test, offset: 0 },
+            _ => return Err(err_eval(&format!("{:?} is not a jump", op))),
+        };
+
+        self.push_with_span(mem, op, span)?;
+        Ok(JumpPatch {
+            instruction: self.last_instruction(),
+        })
     }
 
-    /// Push a literal pointer/value to the back of the literals list and return it's index
+    /// Set the offset of a jump instruction pushed by `push_jump()` so that it jumps to the next
+    /// instruction that will be pushed, or return an error if that is too far away to encode
+    pub fn patch<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        jump: JumpPatch,
+    ) -> Result<(), RuntimeError> {
+        let instruction = jump.instruction;
+        let distance = self.next_instruction() - instruction - 1;
+
+        let offset = JumpOffset::try_from(distance).map_err(|_| {
+            err_eval(&format!(
+                "A jump over {} instructions is too long, the maximum is {}",
+                distance,
+                JumpOffset::MAX
+            ))
+        })?;
+
+        self.update_jump_offset(mem, instruction, offset)
+    }
+
+    /// Push the constant a literal pointer/value is interned as to the back of the literals list
+    /// and return its index. The same literal can be pushed more than once, so callers that load
+    /// a literal repeatedly should keep its index.
     pub fn push_lit<'guard>(
         &self,
         mem: &'guard MutatorView,
         literal: TaggedScopedPtr<'guard>,
     ) -> Result<LiteralId, RuntimeError> {
-        let lit_id = self.literals.length() as u16;
+        let literal = mem.constant(literal);
+
+        let lit_id = self.literals.length();
+        if lit_id > LiteralId::MAX as ArraySize {
+            return Err(err_eval(&format!(
+                "A function can load at most {} different literals",
+                LiteralId::MAX as usize + 1
+            )));
+        }
         StackAnyContainer::push(&self.literals, mem, literal)?;
-        Ok(lit_id)
+        Ok(lit_id as LiteralId)
+    }
+
+    /// Return the literal with the given index into the literals list
+    pub fn literal<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        lit_id: LiteralId,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        IndexedAnyContainer::get(&self.literals, guard, lit_id as ArraySize)
+            .map_err(|_| err_eval(&format!("There is no literal {}", lit_id)))
+    }
+
+    /// Return the number of registers the code needs: one more than the highest register any
+    /// instruction reads or writes
+    pub fn register_count<'guard>(&self, guard: &'guard dyn MutatorScope) -> ArraySize {
+        let mut count = 0;
+        self.code.access_slice(guard, |_, code| {
+            for opcode in code {
+                for reg in opcode.registers() {
+                    count = count.max(reg as ArraySize + 1);
+                }
+            }
+        });
+        count
+    }
+
+    /// Return a copy of the instruction sequence
+    pub fn opcodes<'guard>(&self, guard: &'guard dyn MutatorScope) -> Vec<Opcode> {
+        let mut opcodes = Vec::new();
+        self.code
+            .access_slice(guard, |_, code| opcodes.extend_from_slice(code));
+        opcodes
     }
 
     /// Get the index into the bytecode array of the last instruction
@@ -249,8 +762,19 @@ impl Print for ByteCode {
     ) -> fmt::Result {
         let mut instr_str = String::new();
 
-        self.code.access_slice(guard, |code| {
-            instr_str = join(code.iter().map(|opcode| format!("{:?}", opcode)), "\n")
+        // annotate each instruction with the source code location it was compiled from
+        self.code.access_slice(guard, |_, code| {
+            self.spans.access_slice(guard, |_, spans| {
+                instr_str = join(
+                    code.iter()
+                        .zip(spans.iter())
+                        .map(|(opcode, span)| match span {
+                            Some(span) => format!("{:?}    ; {}", opcode, span),
+                            None => format!("{:?}", opcode),
+                        }),
+                    "\n",
+                )
+            })
         });
 
         write!(f, "{}", instr_str)
@@ -282,6 +806,14 @@ impl InstructionStream {
         self.ip.set(ip);
     }
 
+    /// Retrieve the next instruction without incrementing the instruction pointer
+    pub fn peek_next_opcode<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+    ) -> Result<Opcode, RuntimeError> {
+        self.instructions.get(guard).code.get(guard, self.ip.get())
+    }
+
     /// Retrieve the next instruction and return it, incrementing the instruction pointer
     pub fn get_next_opcode<'guard>(
         &self,
@@ -296,18 +828,9 @@ impl InstructionStream {
         Ok(instr)
     }
 
-    /// Given an index into the literals list, return the pointer in the list at that index.
-    pub fn get_literal<'guard>(
-        &self,
-        guard: &'guard dyn MutatorScope,
-        lit_id: LiteralId,
-    ) -> Result<TaggedPtr, RuntimeError> {
-        Ok(IndexedContainer::get(
-            &self.instructions.get(guard).literals,
-            guard,
-            lit_id as ArraySize,
-        )?
-        .get_ptr())
+    /// Return the ByteCode being executed
+    pub fn code<'guard>(&self, guard: &'guard dyn MutatorScope) -> ScopedPtr<'guard, ByteCode> {
+        self.instructions.get(guard)
     }
 
     /// Return the next instruction pointer
@@ -326,13 +849,122 @@ impl InstructionStream {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::compiler::compile;
     use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
     use std::mem::size_of;
 
+    #[test]
+    fn opcode_names_and_operands() {
+        let call = Opcode::Call {
+            function: 1,
+            dest: 2,
+            arg_count: 3,
+        };
+        assert!(call.name() == "call");
+        assert!(call.operands() == vec![1, 2, 3]);
+        assert!(Opcode::from_operands("call", &[1, 2, 3]).unwrap() == call);
+
+        let jump = Opcode::Jump { offset: -5 };
+        assert!(Opcode::from_operands(jump.name(), &jump.operands()).unwrap() == jump);
+        assert!(Opcode::from_operands("no-op", &[]).unwrap() == Opcode::NoOp);
+
+        let reason = |result: Result<Opcode, RuntimeError>| match result {
+            Err(e) => format!("{}", e),
+            Ok(_) => panic!("expected an error"),
+        };
+        assert!(reason(Opcode::from_operands("return", &[256]))
+            .ends_with("Operand reg of return is out of range: 256"));
+        assert!(reason(Opcode::from_operands("return", &[]))
+            .ends_with("Instruction return takes 1 operands, 0 were given"));
+        assert!(reason(Opcode::from_operands("jump-far", &[1]))
+            .ends_with("Unknown instruction jump-far"));
+    }
+
     #[test]
     fn test_opcode_is_32_bits() {
         // An Opcode should be 32 bits; anything bigger and we've mis-defined some
         // discriminant
         assert!(size_of::<Opcode>() == 4);
     }
+
+    #[test]
+    fn bytecode_jump_patches() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let bytecode = ByteCode::alloc(mem)?;
+
+                // an unpatched jump lands on the instruction after it
+                let jump =
+                    bytecode.push_jump(mem, Opcode::JumpIfTrue { test: 2, offset: 9 }, None)?;
+                assert!(jump.instruction() == 0);
+                assert!(bytecode.opcodes(mem)[0] == Opcode::JumpIfTrue { test: 2, offset: 0 });
+
+                bytecode.push(mem, Opcode::LoadNil { dest: 2 })?;
+                bytecode.push(mem, Opcode::LoadNil { dest: 3 })?;
+                bytecode.patch(mem, jump)?;
+                assert!(bytecode.opcodes(mem)[0] == Opcode::JumpIfTrue { test: 2, offset: 2 });
+
+                assert!(bytecode
+                    .push_jump(mem, Opcode::Return { reg: 2 }, None)
+                    .is_err());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn bytecode_source_spans() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let code = "(cons (car nil)\n      (cdr nil))";
+                let function = compile(mem, parse(mem, code)?)?;
+                let bytecode = function.code(mem);
+
+                // LoadNil, FirstOfPair, LoadNil, SecondOfPair, MakePair, Return
+                let span = |ip| bytecode.source_span(mem, ip);
+
+                let car = span(1).unwrap();
+                assert!(car.start.line == 1 && car.start.column == 7);
+                assert!(car.end.line == 1 && car.end.column == 11);
+                assert!(span(0) == Some(car));
+
+                let cdr = span(3).unwrap();
+                assert!(cdr.start.line == 2 && cdr.start.column == 7);
+
+                let cons = span(4).unwrap();
+                assert!(cons.start.line == 1 && cons.start.column == 1);
+                assert!(cons.end.line == 2);
+
+                assert!(span(5) == None);
+                assert!(span(6) == None);
+
+                // the disassembly is annotated with source locations
+                let disassembly = format!("{:?}", bytecode);
+                assert!(disassembly
+                    .lines()
+                    .any(|line| line.starts_with("SecondOfPair")
+                        && line.ends_with("    ; line 2, column 7")));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
 }
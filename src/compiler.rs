@@ -1,17 +1,27 @@
 use std::cell::{Cell, RefCell};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::rc::Rc;
 
 use crate::array::{Array, ArraySize, ArrayU16};
-use crate::bytecode::{ByteCode, JumpOffset, Opcode, Register, UpvalueId, JUMP_UNKNOWN};
+use crate::bytecode::{
+    ByteCode, FrameOffset, JumpPatch, LiteralId, Opcode, ParamType, Register, SourceSpan, UpvalueId,
+};
 use crate::containers::{AnyContainerFromSlice, StackContainer};
-use crate::error::{err_eval, RuntimeError};
-use crate::function::Function;
+use crate::convert::ToValue;
+use crate::dict::Dict;
+use crate::error::{err_eval, Diagnostic, ErrorKind, RuntimeError, SourcePos};
+use crate::function::{keyword_arg_slots, unapplied_params, Function};
+use crate::global::lookup_global_cell;
 use crate::list::List;
 use crate::memory::MutatorView;
-use crate::pair::{value_from_1_pair, values_from_2_pairs, vec_from_pairs};
-use crate::safeptr::{CellPtr, ScopedPtr, TaggedScopedPtr};
-use crate::taggedptr::Value;
-use crate::vm::FIRST_ARG_REG;
+use crate::native::Arity;
+use crate::number::{number_abs, number_min_max, Extreme};
+use crate::pair::{cons, value_from_1_pair, values_from_2_pairs, vec_from_pairs, Pair};
+use crate::peephole;
+use crate::safeptr::{CellPtr, MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::{TaggedPtr, Value};
+use crate::vm::{Thread, FIRST_ARG_REG};
 
 /// A binding can be either local or via an upvalue depending on how a closure refers to it.
 #[derive(Copy, Clone, PartialEq)]
@@ -24,78 +34,49 @@ enum Binding {
 
 /// A variable is a named register. It has compile time metadata about how it is used by closures.
 struct Variable {
+    /// The Symbol the variable is bound to, compared by pointer
+    name: TaggedPtr,
     register: Register,
     closed_over: Cell<bool>,
+    used: Cell<bool>,
 }
 
 impl Variable {
-    fn new(register: Register) -> Variable {
+    fn new(name: TaggedPtr, register: Register) -> Variable {
         Variable {
+            name,
             register,
             closed_over: Cell::new(false),
+            used: Cell::new(false),
         }
     }
 
-    fn register(&self) -> Register {
-        self.register
+    fn name<'guard>(&self, guard: &'guard dyn MutatorScope) -> TaggedScopedPtr<'guard> {
+        TaggedScopedPtr::new(guard, self.name)
     }
 
-    fn close_over(&self) {
-        self.closed_over.set(true);
+    fn binds(&self, name: TaggedScopedPtr<'_>) -> bool {
+        self.name == name.get_ptr()
     }
 
-    fn is_closed_over(&self) -> bool {
-        self.closed_over.get()
+    fn register(&self) -> Register {
+        self.register
     }
-}
-
-/// A Scope contains a set of local variable to register bindings
-struct Scope {
-    /// symbol -> variable mapping
-    bindings: HashMap<String, Variable>,
-}
 
-impl Scope {
-    fn new() -> Scope {
-        Scope {
-            bindings: HashMap::new(),
-        }
+    fn mark_used(&self) {
+        self.used.set(true);
     }
 
-    /// Add a Symbol->Register binding to this scope
-    fn push_binding<'guard>(
-        &mut self,
-        name: TaggedScopedPtr<'guard>,
-        reg: Register,
-    ) -> Result<(), RuntimeError> {
-        let name_string = match *name {
-            Value::Symbol(s) => String::from(s.as_str(&name)),
-            _ => return Err(err_eval("A binding name must be a symbol")),
-        };
-
-        self.bindings.insert(name_string, Variable::new(reg));
-
-        Ok(())
+    fn is_used(&self) -> bool {
+        self.used.get()
     }
 
-    /// Push a block of bindings into this scope, returning the next register available
-    /// after these bound registers. All these variables will be Unclosed by default.
-    fn push_bindings<'guard>(
-        &mut self,
-        names: &[TaggedScopedPtr<'guard>],
-        start_reg: Register,
-    ) -> Result<Register, RuntimeError> {
-        let mut reg = start_reg;
-        for name in names {
-            self.push_binding(*name, reg)?;
-            reg += 1;
-        }
-        Ok(reg)
+    fn close_over(&self) {
+        self.closed_over.set(true);
     }
 
-    /// Find a Symbol->Register binding in this scope
-    fn lookup_binding<'guard>(&self, name: &str) -> Option<&Variable> {
-        self.bindings.get(name)
+    fn is_closed_over(&self) -> bool {
+        self.closed_over.get()
     }
 }
 
@@ -103,13 +84,13 @@ impl Scope {
 /// This struct stores the non-zero frame offset and register values of a parent function call
 /// frame where a binding will be located.
 struct Nonlocal {
-    upvalue_id: u8,
-    frame_offset: u8,
-    frame_register: u8,
+    upvalue_id: UpvalueId,
+    frame_offset: FrameOffset,
+    frame_register: Register,
 }
 
 impl Nonlocal {
-    fn new(upvalue_id: UpvalueId, frame_offset: u8, frame_register: Register) -> Nonlocal {
+    fn new(upvalue_id: UpvalueId, frame_offset: FrameOffset, frame_register: Register) -> Nonlocal {
         Nonlocal {
             upvalue_id,
             frame_offset,
@@ -118,17 +99,20 @@ impl Nonlocal {
     }
 }
 
-/// A Scope instance represents a set of nested variable binding scopes for a single function
-/// definition.
+/// A Variables instance represents the nested variable binding scopes of a single function
+/// definition. The variables of all the scopes are kept in one list in the order they were bound,
+/// which is searched backwards so that the innermost binding of a name is found first.
 struct Variables<'parent> {
     /// The parent function's variables.
     parent: Option<&'parent Variables<'parent>>,
-    /// Nested scopes, starting with parameters/arguments on the outermost scope and let scopes on
-    /// the inside.
-    scopes: Vec<Scope>,
+    /// The variables in scope, starting with the parameters and followed by those of each nested
+    /// let or match arm scope.
+    locals: Vec<Variable>,
+    /// The index in `locals` at which each nested scope begins, innermost last.
+    scope_marks: Vec<usize>,
     /// Mapping of referenced nonlocal nonglobal variables and their upvalue indexes and where to
     /// find them on the stack.
-    nonlocals: RefCell<HashMap<String, Nonlocal>>,
+    nonlocals: RefCell<HashMap<TaggedPtr, Nonlocal>>,
     /// The next upvalue index to assign when a new nonlocal is encountered.
     next_upvalue: Cell<u8>,
 }
@@ -137,73 +121,183 @@ impl<'parent> Variables<'parent> {
     fn new(parent: Option<&'parent Variables<'parent>>) -> Variables<'parent> {
         Variables {
             parent,
-            scopes: Vec::new(),
+            locals: Vec::new(),
+            scope_marks: Vec::new(),
             nonlocals: RefCell::new(HashMap::new()),
             next_upvalue: Cell::new(0),
         }
     }
 
+    /// Begin a new innermost scope
+    fn push_scope(&mut self) {
+        self.scope_marks.push(self.locals.len());
+    }
+
+    /// Add a Symbol->Register binding to the innermost scope
+    fn push_binding<'guard>(
+        &mut self,
+        name: TaggedScopedPtr<'guard>,
+        reg: Register,
+    ) -> Result<(), RuntimeError> {
+        match *name {
+            Value::Symbol(s) => {
+                let name_str = s.as_str(&name);
+                if name_str == "true" || name_str == "nil" {
+                    return Err(err_eval(&format!(
+                        "Cannot bind the constant {} as a variable",
+                        name_str
+                    )));
+                }
+
+                if s.is_keyword(&name) {
+                    return Err(err_eval(&format!(
+                        "Cannot bind the keyword {} as a variable",
+                        name_str
+                    )));
+                }
+            }
+            Value::Nil => return Err(err_eval("Cannot bind the constant nil as a variable")),
+            _ => return Err(err_eval("A binding name must be a symbol")),
+        }
+
+        self.locals.push(Variable::new(name.get_ptr(), reg));
+
+        Ok(())
+    }
+
+    /// Push a block of bindings into the innermost scope, returning the next register available
+    /// after these bound registers. All these variables will be Unclosed by default.
+    fn push_bindings<'guard>(
+        &mut self,
+        names: &[TaggedScopedPtr<'guard>],
+        start_reg: Register,
+    ) -> Result<Register, RuntimeError> {
+        let mut reg = start_reg;
+        for name in names {
+            self.push_binding(*name, reg)?;
+            reg += 1;
+        }
+        Ok(reg)
+    }
+
+    /// Return the variables bound in the innermost scope
+    fn innermost_scope(&self) -> &[Variable] {
+        let start = self.scope_marks.last().copied().unwrap_or(0);
+        &self.locals[start..]
+    }
+
+    /// Find the innermost binding of a Symbol in this function's scopes
+    fn find_local<'guard>(&self, name: TaggedScopedPtr<'guard>) -> Option<&Variable> {
+        self.locals.iter().rev().find(|var| var.binds(name))
+    }
+
     /// Search for a binding, following parent scopes.
     fn lookup_binding<'guard>(
         &self,
         name: TaggedScopedPtr<'guard>,
     ) -> Result<Option<Binding>, RuntimeError> {
         //  return value should be (count-of-parent-functions-followed, Variable)
-        let name_string = match *name {
-            Value::Symbol(s) => String::from(s.as_str(&name)),
+        match *name {
+            Value::Symbol(_) => (),
             _ => {
                 return Err(err_eval(
                     "Cannot lookup a variable bound to a non-symbol type",
                 ))
             }
-        };
+        }
+        let key = name.get_ptr();
 
         // The frame_offset is the number of parent nesting functions searched for a variable
-        let mut frame_offset: u8 = 0;
+        let mut frame_offset: FrameOffset = 0;
 
         let mut locals = Some(self);
         while let Some(l) = locals {
-            for scope in l.scopes.iter().rev() {
-                if let Some(var) = scope.lookup_binding(&name_string) {
-                    if frame_offset == 0 {
-                        // At depth 0, this is a local binding
-                        return Ok(Some(Binding::Local(var.register())));
-                    } else {
-                        // Otherwise it is a nonlocal and needs to be referenced as an upvalue.
-                        // Create a new upvalue reference if one does not exist.
-                        let mut nonlocals = self.nonlocals.borrow_mut();
-
-                        if let None = nonlocals.get(&name_string) {
-                            // Create a new non-local descriptor and add it
-                            let nonlocal = Nonlocal::new(
-                                self.acquire_upvalue_id(),
-                                frame_offset,
-                                var.register(),
-                            );
-                            nonlocals.insert(name_string.clone(), nonlocal);
-
-                            // Mark the variable as closed-over, as in, a closure will refer to it
-                            // and it's upvalue must be closed at runtime
-                            var.close_over();
-                        }
+            if let Some(var) = l.find_local(name) {
+                var.mark_used();
+
+                if frame_offset == 0 {
+                    // At depth 0, this is a local binding
+                    return Ok(Some(Binding::Local(var.register())));
+                } else {
+                    // Otherwise it is a nonlocal and needs to be referenced as an upvalue.
+                    // Create a new upvalue reference if one does not exist.
+                    let mut nonlocals = self.nonlocals.borrow_mut();
+
+                    if nonlocals.get(&key).is_none() {
+                        // Create a new non-local descriptor and add it
+                        let nonlocal =
+                            Nonlocal::new(self.acquire_upvalue_id(), frame_offset, var.register());
+                        nonlocals.insert(key, nonlocal);
+
+                        // Mark the variable as closed-over, as in, a closure will refer to it
+                        // and it's upvalue must be closed at runtime
+                        var.close_over();
                     }
+
+                    // the nearest binding is the one referred to, stop searching outwards
+                    return Ok(Some(Binding::Upvalue(nonlocals[&key].upvalue_id)));
                 }
             }
 
             locals = l.parent;
-            frame_offset += 1;
+
+            // a frame offset must fit the 8 bits given to it in a nonlocal reference
+            if locals.is_some() {
+                frame_offset = frame_offset.checked_add(1).ok_or_else(|| {
+                    err_eval(&format!(
+                        "Functions are too deeply nested, a maximum of {} levels is supported",
+                        FrameOffset::MAX as usize + 1
+                    ))
+                })?;
+            }
         }
 
         // We've reached the end of the scopes at this point so we can check if we
         // know about this binding as an upvalue and return it
         let nonlocals = self.nonlocals.borrow();
-        if let Some(nonlocal) = nonlocals.get(&name_string) {
+        if let Some(nonlocal) = nonlocals.get(&key) {
             return Ok(Some(Binding::Upvalue(nonlocal.upvalue_id)));
         }
 
         Ok(None)
     }
 
+    /// Return true if the name is bound in any scope of this or a parent function. Unlike
+    /// `lookup_binding()` this does not create an upvalue for a nonlocal binding.
+    fn is_bound<'guard>(&self, name: TaggedScopedPtr<'guard>) -> bool {
+        let mut locals = Some(self);
+        while let Some(l) = locals {
+            if l.find_local(name).is_some() {
+                return true;
+            }
+            locals = l.parent;
+        }
+        false
+    }
+
+    /// Return the names of the variables in the innermost scope that were never referenced, in
+    /// binding order. Names starting with `_` are intentionally unused and are not included.
+    fn unused_names<'guard>(&self, guard: &'guard dyn MutatorScope) -> Vec<&'guard str> {
+        let scope = self.innermost_scope();
+
+        scope
+            .iter()
+            .enumerate()
+            // a name bound twice in one scope refers to the later binding
+            .filter(|(index, var)| {
+                !var.is_used()
+                    && !scope[index + 1..]
+                        .iter()
+                        .any(|later| later.name == var.name)
+            })
+            .filter_map(|(_, var)| match *var.name(guard) {
+                Value::Symbol(s) => Some(s.as_str(guard)),
+                _ => None,
+            })
+            .filter(|name| !name.starts_with('_'))
+            .collect()
+    }
+
     /// Return the next upvalue id and increment the counter
     fn acquire_upvalue_id(&self) -> UpvalueId {
         let id = self.next_upvalue.get();
@@ -235,23 +329,166 @@ impl<'parent> Variables<'parent> {
         }
     }
 
-    /// Pop the last scoped variables and create close-upvalue instructions for any closed over
+    /// Pop the innermost scope's variables and create close-upvalue instructions for any closed
+    /// over
     fn pop_scope<'guard>(&mut self) -> Vec<Opcode> {
-        let mut closings = Vec::new();
-
-        if let Some(scope) = self.scopes.pop() {
-            for var in scope.bindings.values() {
-                if var.is_closed_over() {
-                    closings.push(Opcode::CloseUpvalues {
-                        reg1: var.register(),
-                        reg2: 0,
-                        reg3: 0,
-                    });
-                }
-            }
+        let start = match self.scope_marks.pop() {
+            Some(start) => start,
+            None => return Vec::new(),
+        };
+
+        self.locals
+            .drain(start..)
+            .filter(|var| var.is_closed_over())
+            .map(|var| Opcode::CloseUpvalues {
+                reg1: var.register(),
+                reg2: 0,
+                reg3: 0,
+            })
+            .collect()
+    }
+}
+
+/// The global names a sequence of forms refers to and defines, shared by the compilers of every
+/// function in the forms when they are checked with `check_toplevel()`
+#[derive(Default)]
+struct GlobalNames {
+    /// Each global lookup, in the order compiled, with the position of the enclosing expression
+    referenced: Vec<(String, Option<SourcePos>)>,
+    /// Names given a value by `def` or by `set` of a quoted symbol
+    defined: HashSet<String>,
+}
+
+/// How calls to global functions that are already bound are checked against the function arity
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ArityChecks {
+    /// Calls are not checked
+    Off,
+    /// A call with the wrong number of arguments is a warning
+    Warn,
+    /// A call with too many arguments is an error, with too few, which makes a partial
+    /// application, a warning
+    Error,
+}
+
+/// How much the compiler optimizes the code it generates. The result of evaluating code is the
+/// same at every level.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OptLevel {
+    /// Compile every expression as written, which is the fastest to compile
+    O0,
+    /// Fold the pure builtin operations on constants into their result, leave out cond arms that
+    /// can never be taken and thread jumps with the peephole optimizer
+    O1,
+}
+
+impl OptLevel {
+    /// Return the level of the given number, as written after `-O`
+    pub fn from_name(name: &str) -> Option<OptLevel> {
+        match name {
+            "0" => Some(OptLevel::O0),
+            "1" => Some(OptLevel::O1),
+            _ => None,
+        }
+    }
+}
+
+/// Settings that change the code the compiler generates
+#[derive(Copy, Clone, Debug)]
+pub struct CompileOptions {
+    /// Check the types of annotated function parameters when the function is entered. When false,
+    /// annotations are accepted but not checked.
+    pub type_checks: bool,
+    /// Check the argument count of calls to known global functions. Only takes effect when the
+    /// `CompileContext` has the globals the code will run with.
+    pub arity_checks: ArityChecks,
+    /// How much the generated code is optimized. Optimized by default, the REPL compiles each
+    /// line unoptimized.
+    pub opt_level: OptLevel,
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            type_checks: true,
+            arity_checks: ArityChecks::Off,
+            opt_level: OptLevel::O1,
+        }
+    }
+}
+
+/// What the compiler knows about where the code will run: the code generation options and,
+/// optionally, the globals of the Thread that will run it
+#[derive(Copy, Clone)]
+pub struct CompileContext<'guard> {
+    pub options: CompileOptions,
+    pub globals: Option<ScopedPtr<'guard, Dict>>,
+}
+
+impl<'guard> CompileContext<'guard> {
+    /// A context with the given options and no globals
+    pub fn new(options: CompileOptions) -> CompileContext<'guard> {
+        CompileContext {
+            options,
+            globals: None,
+        }
+    }
+
+    /// A context with the given options and the globals of the given Thread
+    pub fn for_thread(
+        guard: &'guard dyn MutatorScope,
+        options: CompileOptions,
+        thread: &Thread,
+    ) -> CompileContext<'guard> {
+        CompileContext {
+            options,
+            globals: Some(thread.globals(guard)),
+        }
+    }
+}
+
+/// State shared by the compilers of every function in one compilation: the options, the source
+/// file, where warnings are collected and what is known about globals. The compiler of a nested
+/// function refers to the same context as the compiler of the function it is in, so anything
+/// added here reaches every function without being passed down by hand.
+struct CompilerContext {
+    /// Code generation settings
+    options: CompileOptions,
+    /// Name of the source file being compiled as Text, or nil
+    file_name: TaggedCellPtr,
+    /// Warnings found so far, in the order they were found
+    diagnostics: RefCell<Vec<Diagnostic>>,
+    /// Global names referenced and defined, recorded only when checking
+    global_names: Option<Rc<RefCell<GlobalNames>>>,
+    /// Globals of the Thread the code will run with, if known
+    thread_globals: Option<CellPtr<Dict>>,
+}
+
+impl CompilerContext {
+    /// A context for compiling code from the given file, or nil, as the public context describes
+    fn new<'guard>(
+        file_name: TaggedScopedPtr<'guard>,
+        context: CompileContext<'guard>,
+    ) -> CompilerContext {
+        CompilerContext {
+            options: context.options,
+            file_name: TaggedCellPtr::new_with(file_name),
+            diagnostics: RefCell::new(Vec::new()),
+            global_names: None,
+            thread_globals: context.globals.map(CellPtr::new_with),
         }
+    }
+
+    /// Record a warning at the given position
+    fn warn(&self, code: &'static str, message: &str, pos: Option<SourcePos>) {
+        self.diagnostics
+            .borrow_mut()
+            .push(Diagnostic::warning(code, message, pos));
+    }
 
-        closings
+    /// Return the warnings found
+    fn into_diagnostics(self) -> Vec<Diagnostic> {
+        self.diagnostics.into_inner()
     }
 }
 
@@ -268,6 +505,14 @@ struct Compiler<'parent> {
     name: Option<String>,
     /// Function-local nested scopes bindings list (including parameters at outer level)
     vars: Variables<'parent>,
+    /// Source code span of the expression being compiled, recorded against each instruction
+    span: Option<SourceSpan>,
+    /// Settings and state shared with the compilers of enclosing and nested functions
+    context: &'parent CompilerContext,
+    /// Index of each jump pushed whose target has not been patched yet
+    unpatched: Vec<ArraySize>,
+    /// Literal id of each constant the function loads
+    literal_ids: HashMap<TaggedPtr, LiteralId>,
 }
 
 impl<'parent> Compiler<'parent> {
@@ -275,6 +520,8 @@ impl<'parent> Compiler<'parent> {
     fn new<'guard>(
         mem: &'guard MutatorView,
         parent: Option<&'parent Variables<'parent>>,
+        span: Option<SourceSpan>,
+        context: &'parent CompilerContext,
     ) -> Result<Compiler<'parent>, RuntimeError> {
         Ok(Compiler {
             bytecode: CellPtr::new_with(ByteCode::alloc(mem)?),
@@ -282,10 +529,14 @@ impl<'parent> Compiler<'parent> {
             next_reg: FIRST_ARG_REG as u8,
             name: None,
             vars: Variables::new(parent),
+            span,
+            context,
+            unpatched: Vec::new(),
+            literal_ids: HashMap::new(),
         })
     }
 
-    /// Compile an expression that has parameters and possibly a name
+    /// Compile an expression that has parameters and possibly a name, returning the Function
     fn compile_function<'guard>(
         mut self,
         mem: &'guard MutatorView,
@@ -304,24 +555,60 @@ impl<'parent> Compiler<'parent> {
             }
         };
         let fn_name = name;
+        let fn_pos = self.span.map(|span| span.start);
 
         // validate arity
         if params.len() > 254 {
             return Err(err_eval("A function cannot have more than 254 parameters"));
         }
+
+        // a parameter is a name or a (name type) annotated name, checked on entry
+        let mut names = Vec::with_capacity(params.len());
+        let mut type_checks = Vec::new();
+        for (index, param) in params.iter().enumerate() {
+            if let Value::Pair(_) = **param {
+                let (name, type_name) = values_from_2_pairs(mem, *param)?;
+                let param_type = match *type_name {
+                    Value::Symbol(s) => ParamType::from_name(s.as_str(mem)),
+                    _ => None,
+                }
+                .ok_or_else(|| err_eval(&format!("Unknown parameter type {}", type_name)))?;
+
+                names.push(name);
+                type_checks.push(Opcode::CheckType {
+                    reg: (FIRST_ARG_REG + index) as Register,
+                    param_type: param_type.id(),
+                });
+            } else {
+                names.push(*param);
+            }
+        }
+        let params = &names[..];
         // put params into a list for the Function object
         let fn_params = List::from_slice(mem, params)?;
 
         // also assign params to the first level function scope and give each one a register
-        let mut param_scope = Scope::new();
-        self.next_reg = param_scope.push_bindings(params, self.next_reg)?;
-        self.vars.scopes.push(param_scope);
+        self.warn_shadowed(mem, params);
+        self.vars.push_scope();
+        self.next_reg = self.vars.push_bindings(params, self.next_reg)?;
+
+        if self.context.options.type_checks {
+            for check in type_checks {
+                self.push(mem, check)?;
+            }
+        }
 
         // validate expression list
         if exprs.len() == 0 {
             return Err(err_eval("A function must have at least one expression"));
         }
 
+        // a string followed by at least one more expression is a documentation string
+        let (fn_doc, exprs) = match *exprs[0] {
+            Value::Text(text) if exprs.len() > 1 => (Some(text), &exprs[1..]),
+            _ => (None, exprs),
+        };
+
         // compile expressions
         let mut result_reg = 0;
         for expr in exprs.iter() {
@@ -329,6 +616,7 @@ impl<'parent> Compiler<'parent> {
         }
 
         // pop parameter scope
+        self.warn_unused(mem, "Parameter");
         let closing_instructions = self.vars.pop_scope();
         for opcode in &closing_instructions {
             self.push(mem, *opcode)?;
@@ -337,16 +625,53 @@ impl<'parent> Compiler<'parent> {
         // finish with a return
         let fn_bytecode = self.bytecode.get(mem);
         fn_bytecode.push(mem, Opcode::Return { reg: result_reg })?;
+        self.assert_patched();
+        if self.optimizing() {
+            peephole::optimize(mem, &fn_bytecode)?;
+        }
 
         let fn_nonlocals = self.vars.get_nonlocals(mem)?;
 
-        Ok(Function::alloc(
-            mem,
-            fn_name,
-            fn_params,
-            fn_bytecode,
-            fn_nonlocals,
-        )?)
+        let function = Function::alloc(mem, fn_name, fn_params, fn_bytecode, fn_nonlocals, fn_doc)?;
+        function.set_source(fn_pos, self.context.file_name.get(mem));
+
+        Ok(function)
+    }
+
+    /// Compile a sequence of top level forms into one Function, taking no arguments, that
+    /// evaluates them in order and returns the result of the last
+    fn compile_toplevel<'guard>(
+        mut self,
+        mem: &'guard MutatorView,
+        forms: &[TaggedScopedPtr<'guard>],
+    ) -> Result<ScopedPtr<'guard, Function>, RuntimeError> {
+        self.vars.push_scope();
+
+        let mut result_reg = 0;
+        for form in forms {
+            // the result of a form is not used once the next begins, so its registers are reused
+            self.reset_reg(FIRST_ARG_REG as Register);
+            result_reg = self.compile_eval(mem, *form)?;
+        }
+
+        if forms.is_empty() {
+            result_reg = self.compile_eval(mem, mem.nil())?;
+        }
+
+        self.vars.pop_scope();
+
+        let fn_bytecode = self.bytecode.get(mem);
+        fn_bytecode.push(mem, Opcode::Return { reg: result_reg })?;
+        self.assert_patched();
+        if self.optimizing() {
+            peephole::optimize(mem, &fn_bytecode)?;
+        }
+
+        let fn_params = List::alloc(mem)?;
+        let function = Function::alloc(mem, mem.nil(), fn_params, fn_bytecode, None, None)?;
+        function.set_source(None, self.context.file_name.get(mem));
+
+        Ok(function)
     }
 
     /// Compile an expression - this can be an 'atomic' value or a nested function application
@@ -356,44 +681,50 @@ impl<'parent> Compiler<'parent> {
         ast_node: TaggedScopedPtr<'guard>,
     ) -> Result<Register, RuntimeError> {
         match *ast_node {
-            Value::Pair(p) => self.compile_apply(mem, p.first.get(mem), p.second.get(mem)),
+            Value::Pair(p) => {
+                // instructions take the span of the innermost expression they belong to
+                let outer_span = self.span;
+                self.span = source_span(mem, p).or(outer_span);
 
-            Value::Symbol(s) => {
-                match s.as_str(mem) {
-                    "nil" => {
+                let result = self.compile_apply(mem, p.first.get(mem), p.second.get(mem));
+
+                self.span = outer_span;
+                result
+            }
+
+            // the constants are compared by pointer, they cannot be bound as variables
+            Value::Symbol(_) if ast_node == mem.sym_nil() => self.push_load_literal(mem, mem.nil()),
+
+            Value::Symbol(_) if ast_node == mem.sym_true() => self.push_load_literal(mem, ast_node),
+
+            // keywords evaluate to themselves
+            Value::Symbol(s) if s.is_keyword(mem) => self.push_load_literal(mem, ast_node),
+
+            // Search scopes for a binding; if none do a global lookup
+            Value::Symbol(_) => {
+                match self.vars.lookup_binding(ast_node)? {
+                    Some(Binding::Local(register)) => Ok(register),
+
+                    Some(Binding::Upvalue(upvalue_id)) => {
+                        // Retrieve the value via Upvalue indirection
                         let dest = self.acquire_reg();
-                        self.push(mem, Opcode::LoadNil { dest })?;
+                        self.push(
+                            mem,
+                            Opcode::GetUpvalue {
+                                dest,
+                                src: upvalue_id,
+                            },
+                        )?;
                         Ok(dest)
                     }
 
-                    "true" => self.push_load_literal(mem, mem.lookup_sym("true")),
-
-                    // Search scopes for a binding; if none do a global lookup
-                    _ => {
-                        match self.vars.lookup_binding(ast_node)? {
-                            Some(Binding::Local(register)) => Ok(register),
-
-                            Some(Binding::Upvalue(upvalue_id)) => {
-                                // Retrieve the value via Upvalue indirection
-                                let dest = self.acquire_reg();
-                                self.push(
-                                    mem,
-                                    Opcode::GetUpvalue {
-                                        dest,
-                                        src: upvalue_id,
-                                    },
-                                )?;
-                                Ok(dest)
-                            }
-
-                            None => {
-                                // Otherwise do a late-binding global lookup
-                                let name = self.push_load_literal(mem, ast_node)?;
-                                let dest = name; // reuse the register
-                                self.push(mem, Opcode::LoadGlobal { dest, name })?;
-                                Ok(dest)
-                            }
-                        }
+                    None => {
+                        // Otherwise do a late-binding global lookup
+                        self.reference_global(mem, ast_node);
+                        let name = self.push_load_literal(mem, ast_node)?;
+                        let dest = name; // reuse the register
+                        self.push(mem, Opcode::LoadGlobal { dest, name })?;
+                        Ok(dest)
                     }
                 }
             }
@@ -402,6 +733,21 @@ impl<'parent> Compiler<'parent> {
         }
     }
 
+    /// Compile an expression whose result must end up in the given register. A variable's value
+    /// is in the register it is bound to, so it is copied.
+    fn compile_eval_into<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        ast_node: TaggedScopedPtr<'guard>,
+        dest: Register,
+    ) -> Result<(), RuntimeError> {
+        let src = self.compile_eval(mem, ast_node)?;
+        if src != dest {
+            self.push(mem, Opcode::CopyRegister { dest, src })?;
+        }
+        Ok(())
+    }
+
     /// Compile a function or special-form application
     fn compile_apply<'guard>(
         &mut self,
@@ -409,6 +755,12 @@ impl<'parent> Compiler<'parent> {
         function: TaggedScopedPtr<'guard>,
         args: TaggedScopedPtr<'guard>,
     ) -> Result<Register, RuntimeError> {
+        if self.optimizing() {
+            if let Some(value) = self.fold_constant(mem, function, args) {
+                return self.push_load_literal(mem, value);
+            }
+        }
+
         match *function {
             Value::Symbol(s) => match s.as_str(mem) {
                 "quote" => self.push_load_literal(mem, value_from_1_pair(mem, args)?),
@@ -416,22 +768,67 @@ impl<'parent> Compiler<'parent> {
                 "nil?" => self.push_op2(mem, args, |dest, test| Opcode::IsNil { dest, test }),
                 "car" => self.push_op2(mem, args, |dest, reg| Opcode::FirstOfPair { dest, reg }),
                 "cdr" => self.push_op2(mem, args, |dest, reg| Opcode::SecondOfPair { dest, reg }),
+                "length" => self.push_op2(mem, args, |dest, reg| Opcode::ListLength { dest, reg }),
+                "reverse" => {
+                    self.push_op2(mem, args, |dest, reg| Opcode::ListReverse { dest, reg })
+                }
                 "cons" => self.push_op3(mem, args, |dest, reg1, reg2| Opcode::MakePair {
                     dest,
                     reg1,
                     reg2,
                 }),
                 "cond" => self.compile_apply_cond(mem, args),
+                "delay" => self.compile_apply_delay(mem, args),
+                "cons-stream" => self.compile_apply_cons_stream(mem, args),
+                "time" => self.compile_apply_time(mem, args),
                 "is?" => self.push_op3(mem, args, |dest, test1, test2| Opcode::IsIdentical {
                     dest,
                     test1,
                     test2,
                 }),
+                "bit-and" => self.push_op3(mem, args, |dest, reg1, reg2| Opcode::BitAnd {
+                    dest,
+                    reg1,
+                    reg2,
+                }),
+                "bit-or" => self.push_op3(mem, args, |dest, reg1, reg2| Opcode::BitOr {
+                    dest,
+                    reg1,
+                    reg2,
+                }),
+                "bit-xor" => self.push_op3(mem, args, |dest, reg1, reg2| Opcode::BitXor {
+                    dest,
+                    reg1,
+                    reg2,
+                }),
+                "shl" => self.push_op3(mem, args, |dest, value, count| Opcode::ShiftLeft {
+                    dest,
+                    value,
+                    count,
+                }),
+                "shr" => self.push_op3(mem, args, |dest, value, count| Opcode::ShiftRight {
+                    dest,
+                    value,
+                    count,
+                }),
+                "min" => self.push_fold(mem, args, "min", |dest, reg1, reg2| Opcode::Min {
+                    dest,
+                    reg1,
+                    reg2,
+                }),
+                "max" => self.push_fold(mem, args, "max", |dest, reg1, reg2| Opcode::Max {
+                    dest,
+                    reg1,
+                    reg2,
+                }),
+                "abs" => self.push_op2(mem, args, |dest, reg| Opcode::Abs { dest, reg }),
                 "set" => self.compile_apply_assign(mem, args),
                 "def" => self.compile_named_function(mem, args),
+                "deftest" => self.compile_deftest(mem, args),
                 "lambda" => self.compile_anonymous_function(mem, args),
                 "\\" => self.compile_anonymous_function(mem, args),
                 "let" => self.compile_apply_let(mem, args),
+                "match" => self.compile_apply_match(mem, args),
                 _ => self.compile_apply_call(mem, function, args),
             },
 
@@ -440,82 +837,262 @@ impl<'parent> Compiler<'parent> {
         }
     }
 
-    /// Compile a 'cond' application
-    /// (cond
-    ///   (<if-expr-is-true?>) (<then-expr>)
-    ///   (<or-expr-is-true?) (<then-expr>)
-    /// )
-    /// result is nil if no expression evaluates to true
-    fn compile_apply_cond<'guard>(
-        &mut self,
+    /// Return the result of a pure builtin operation whose arguments are all constants, if the
+    /// expression is one. Arguments the operation would fail on are left to fail when the code is
+    /// run, as they would without optimization.
+    fn fold_constant<'guard>(
+        &self,
         mem: &'guard MutatorView,
+        function: TaggedScopedPtr<'guard>,
         args: TaggedScopedPtr<'guard>,
-    ) -> Result<Register, RuntimeError> {
-        //
-        //   for each arg:
-        //     eval cond
-        //     if false then jmp -> next
-        //     else eval expr
-        //     jmp -> end
-        //
-        let bytecode = self.bytecode.get(mem);
-
-        let mut end_jumps: Vec<ArraySize> = Vec::new();
-        let mut last_cond_jump: Option<ArraySize> = None;
-
-        let dest = self.next_reg;
-
-        let mut head = args;
-        while let Value::Pair(p) = *head {
-            let cond = p.first.get(mem);
-            head = p.second.get(mem);
-            match *head {
-                Value::Pair(p) => {
-                    let expr = p.first.get(mem);
-                    head = p.second.get(mem);
+    ) -> Option<TaggedScopedPtr<'guard>> {
+        let name = match *function {
+            Value::Symbol(s) => s.as_str(mem),
+            _ => return None,
+        };
 
-                    // if this is not the first condition, set the offset of the last
-                    // condition-not-true jump to the beginning of this condition
-                    if let Some(address) = last_cond_jump {
-                        let offset = bytecode.next_instruction() - address - 1;
-                        bytecode.update_jump_offset(mem, address, offset as JumpOffset)?;
-                    }
+        match name {
+            "nil?" | "atom?" | "bit-and" | "bit-or" | "bit-xor" | "min" | "max" | "abs" => (),
+            _ => return None,
+        }
 
-                    // We have a condition to evaluate. If the resut is Not True, jump to the
-                    // next condition.
-                    self.reset_reg(dest); // reuse this register for condition and dest
-                    let test = self.compile_eval(mem, cond)?;
-                    let offset = JUMP_UNKNOWN;
-                    self.push(mem, Opcode::JumpIfNotTrue { test, offset })?;
-                    last_cond_jump = Some(bytecode.last_instruction());
-
-                    // Compile the expression and jump to the end of the entire cond
-                    self.reset_reg(dest); // reuse this register for condition and dest
-                    let _expr_result = self.compile_eval(mem, expr)?;
-                    let offset = JUMP_UNKNOWN;
-                    bytecode.push(mem, Opcode::Jump { offset })?;
-                    end_jumps.push(bytecode.last_instruction());
-                }
+        // a malformed argument list is reported by compiling it as usual
+        let values = vec_from_pairs(mem, args)
+            .ok()?
+            .into_iter()
+            .map(|arg| self.constant_value(mem, arg))
+            .collect::<Option<Vec<_>>>()?;
 
-                _ => return Err(err_eval("Unexpected end of cond list")),
+        let truth = |test: bool| match test {
+            true => mem.sym_true(),
+            false => mem.nil(),
+        };
+        let bitwise = |a: TaggedScopedPtr<'guard>,
+                       b: TaggedScopedPtr<'guard>,
+                       f: fn(isize, isize) -> isize| {
+            match (*a, *b) {
+                (Value::Number(a), Value::Number(b)) => f(a, b).to_value(mem).ok(),
+                _ => None,
             }
-        }
+        };
+        // min and max fold a single argument with itself, as the compiled instructions do
+        let fold = |values: &[TaggedScopedPtr<'guard>], extreme: Extreme| {
+            let (first, rest) = values.split_first()?;
+            let rest = if rest.is_empty() { values } else { rest };
+            rest.iter()
+                .try_fold(*first, |acc, value| {
+                    number_min_max(acc, *value, extreme, name)
+                })
+                .ok()
+        };
 
-        // Close out with a default nil result if none of the conditions passed
-        if let Some(address) = last_cond_jump {
-            self.reset_reg(dest);
-            self.push(mem, Opcode::LoadNil { dest })?;
-            let offset = bytecode.next_instruction() - address - 1;
-            bytecode.update_jump_offset(mem, address, offset as JumpOffset)?;
+        match (name, values.as_slice()) {
+            ("nil?", [value]) => Some(truth(matches!(**value, Value::Nil))),
+            ("atom?", [value]) => Some(truth(!matches!(**value, Value::Nil | Value::Pair(_)))),
+            ("bit-and", [a, b]) => bitwise(*a, *b, |a, b| a & b),
+            ("bit-or", [a, b]) => bitwise(*a, *b, |a, b| a | b),
+            ("bit-xor", [a, b]) => bitwise(*a, *b, |a, b| a ^ b),
+            ("min", values) => fold(values, Extreme::Least),
+            ("max", values) => fold(values, Extreme::Greatest),
+            ("abs", [value]) => number_abs(mem, *value).ok(),
+            _ => None,
         }
+    }
 
-        // Update all the post-expr jumps to point at the next instruction after the entire cond
-        for address in end_jumps.iter() {
-            let offset = bytecode.next_instruction() - address - 1;
-            bytecode.update_jump_offset(mem, *address, offset as JumpOffset)?;
+    /// Return the value of an expression that is a constant or that folds into one
+    fn constant_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        expr: TaggedScopedPtr<'guard>,
+    ) -> Option<TaggedScopedPtr<'guard>> {
+        match *expr {
+            Value::Symbol(_) if expr == mem.sym_nil() => Some(mem.nil()),
+            Value::Symbol(_) if expr == mem.sym_true() || is_keyword(mem, expr) => Some(expr),
+            Value::Symbol(_) => None,
+            Value::Pair(p) if is_quoted(mem, expr) => {
+                value_from_1_pair(mem, p.second.get(mem)).ok()
+            }
+            Value::Pair(p) => self.fold_constant(mem, p.first.get(mem), p.second.get(mem)),
+            _ => Some(expr),
         }
-
-        Ok(dest)
+    }
+
+    /// Compile a 'time' application. The expression result is returned after the wall time it
+    /// took to evaluate is printed.
+    /// (time <expr>)
+    fn compile_apply_time<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        args: TaggedScopedPtr<'guard>,
+    ) -> Result<Register, RuntimeError> {
+        let expr = value_from_1_pair(mem, args)?;
+
+        let start = self.acquire_reg();
+        self.push(mem, Opcode::ReadClock { dest: start })?;
+
+        let result = self.compile_eval(mem, expr)?;
+        self.push(mem, Opcode::PrintElapsed { start })?;
+
+        Ok(result)
+    }
+
+    /// Compile a 'delay' application. The expression is compiled as a function of no parameters,
+    /// which is returned in a Promise without being called.
+    /// (delay <expr>)
+    fn compile_apply_delay<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        args: TaggedScopedPtr<'guard>,
+    ) -> Result<Register, RuntimeError> {
+        let expr = value_from_1_pair(mem, args)?;
+
+        // (lambda () <expr>)
+        let lambda = cons(mem, mem.nil(), cons(mem, expr, mem.nil())?)?;
+        let thunk = self.compile_anonymous_function(mem, lambda)?;
+
+        let dest = thunk; // reuse the register
+        self.push(mem, Opcode::MakePromise { dest, thunk })?;
+        Ok(dest)
+    }
+
+    /// Compile a 'cons-stream' application, a pair of the first expression's value and a promise
+    /// of the second's
+    /// (cons-stream <first-expr> <rest-expr>)
+    fn compile_apply_cons_stream<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        args: TaggedScopedPtr<'guard>,
+    ) -> Result<Register, RuntimeError> {
+        let (first, rest) = values_from_2_pairs(mem, args)?;
+
+        let dest = self.acquire_reg();
+        let reg1 = self.compile_eval(mem, first)?;
+        let reg2 = self.compile_apply_delay(mem, cons(mem, rest, mem.nil())?)?;
+        self.push(mem, Opcode::MakePair { dest, reg1, reg2 })?;
+
+        Ok(dest)
+    }
+
+    /// Compile a 'cond' application
+    /// (cond
+    ///   (<if-expr-is-true?>) (<then-expr>)
+    ///   (<or-expr-is-true?) (<then-expr>)
+    /// )
+    /// result is nil if no expression evaluates to true
+    ///
+    /// Arms whose condition is a constant are resolved at compile time: an arm that can never be
+    /// taken is dropped, and an arm that is always taken ends the cond, dropping the arms after it
+    /// and the default nil result.
+    fn compile_apply_cond<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        args: TaggedScopedPtr<'guard>,
+    ) -> Result<Register, RuntimeError> {
+        //
+        //   for each arg:
+        //     eval cond
+        //     if false then jmp -> next
+        //     else eval expr
+        //     jmp -> end
+        //
+        let mut end_jumps: Vec<JumpPatch> = Vec::new();
+        let mut last_cond_jump: Option<JumpPatch> = None;
+
+        let dest = self.next_reg;
+
+        // collect the (condition, expression) arms
+        let mut arms = Vec::new();
+        let mut head = args;
+        while let Value::Pair(p) = *head {
+            let cond = p.first.get(mem);
+            head = p.second.get(mem);
+            match *head {
+                Value::Pair(p) => {
+                    arms.push((cond, p.first.get(mem)));
+                    head = p.second.get(mem);
+                }
+
+                _ => return Err(err_eval("Unexpected end of cond list")),
+            }
+        }
+
+        // arms that can never be taken are reported at every optimization level
+        for (index, (cond, _)) in arms.iter().enumerate() {
+            match constant_truth(mem, *cond) {
+                Some(false) => self.warn(
+                    "unreachable-code",
+                    "A cond arm whose condition is never true can never be taken",
+                ),
+                Some(true) => {
+                    if index + 1 < arms.len() {
+                        self.warn(
+                            "unreachable-code",
+                            "Cond arms after a condition that is always true can never be taken",
+                        );
+                    }
+                    break;
+                }
+                None => (),
+            }
+        }
+
+        let optimize = self.optimizing();
+        let mut always_taken = false;
+
+        for (cond, expr) in arms.iter() {
+            // without optimization every condition is compiled as a test
+            let truth = match optimize {
+                true => constant_truth(mem, *cond),
+                false => None,
+            };
+
+            if truth == Some(false) {
+                continue;
+            }
+
+            // if this is not the first condition, set the offset of the last
+            // condition-not-true jump to the beginning of this condition
+            if let Some(jump) = last_cond_jump.take() {
+                self.patch_jump(mem, jump)?;
+            }
+
+            if truth == Some(true) {
+                // The condition always passes: evaluate the expression and fall through to the
+                // end of the cond. Nothing after this arm can be reached.
+                self.reset_reg(dest);
+                self.compile_eval_into(mem, *expr, dest)?;
+                always_taken = true;
+                break;
+            }
+
+            // We have a condition to evaluate. If the resut is Not True, jump to the
+            // next condition.
+            self.reset_reg(dest); // reuse this register for condition and dest
+            let test = self.compile_eval(mem, *cond)?;
+            last_cond_jump = Some(self.push_jump(mem, Opcode::JumpIfNotTrue { test, offset: 0 })?);
+
+            // Compile the expression and jump to the end of the entire cond
+            self.reset_reg(dest); // reuse this register for condition and dest
+            self.compile_eval_into(mem, *expr, dest)?;
+            end_jumps.push(self.push_jump(mem, Opcode::Jump { offset: 0 })?);
+        }
+
+        // Close out with a default nil result if none of the conditions passed
+        if !always_taken {
+            self.reset_reg(dest);
+            self.push(mem, Opcode::LoadNil { dest })?;
+
+            if let Some(jump) = last_cond_jump {
+                self.patch_jump(mem, jump)?;
+            }
+        }
+
+        // Update all the post-expr jumps to point at the next instruction after the entire cond
+        for jump in end_jumps {
+            self.patch_jump(mem, jump)?;
+        }
+
+        Ok(dest)
     }
 
     /// Assignment expression - evaluate the two expressions, binding the result of the first
@@ -530,6 +1107,14 @@ impl<'parent> Compiler<'parent> {
         let src = self.compile_eval(mem, second)?;
         let name = self.compile_eval(mem, first)?;
         self.push(mem, Opcode::StoreGlobal { src, name })?;
+
+        // only a quoted symbol names the global at compile time
+        if let Value::Pair(pair) = *first {
+            if pair.first.get(mem) == mem.lookup_sym("quote") {
+                self.define_global(mem, value_from_1_pair(mem, pair.second.get(mem))?)?;
+            }
+        }
+
         Ok(src)
     }
 
@@ -554,7 +1139,7 @@ impl<'parent> Compiler<'parent> {
         let fn_exprs = &items[1..];
 
         // compile the function to a Function object
-        let fn_object = compile_function(mem, Some(&self.vars), mem.nil(), &fn_params, fn_exprs)?;
+        let fn_object = self.compile_nested_function(mem, mem.nil(), &fn_params, fn_exprs)?;
 
         // load the function object as a literal
         let dest = self.push_load_literal(mem, fn_object)?;
@@ -600,25 +1185,71 @@ impl<'parent> Compiler<'parent> {
         let fn_exprs = &items[2..];
 
         // compile the function to a Function object
-        let fn_object = compile_function(mem, Some(&self.vars), fn_name, &fn_params, fn_exprs)?;
+        let fn_object = self.compile_nested_function(mem, fn_name, &fn_params, fn_exprs)?;
 
         // load the function object as a literal and associate it with a global name
         // TODO store in local scope if we're nested in an expression
         let name = self.push_load_literal(mem, fn_name)?;
         let src = self.push_load_literal(mem, fn_object)?;
         self.push(mem, Opcode::StoreGlobal { src, name })?;
+        self.define_global(mem, fn_name)?;
 
         Ok(src)
 
         // TODO if fn_object has nonlocal refs, compile a MakeClosure instruction in addition
     }
 
+    /// (deftest name expr1 .. exprn) - compile a test function taking no arguments and register it
+    /// with the Thread, to be run by `(run-tests)`
+    fn compile_deftest<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        params: TaggedScopedPtr<'guard>,
+    ) -> Result<Register, RuntimeError> {
+        let items = vec_from_pairs(mem, params)?;
+
+        if items.len() < 2 {
+            return Err(err_eval(
+                "A test definition must have at least (deftest name expr)",
+            ));
+        }
+
+        let test_name = items[0];
+        let fn_object = self.compile_nested_function(mem, test_name, &[], &items[1..])?;
+
+        // compile as (register-test 'name <function>)
+        let quoted_name = cons(
+            mem,
+            mem.lookup_sym("quote"),
+            cons(mem, test_name, mem.nil())?,
+        )?;
+        let args = cons(mem, quoted_name, cons(mem, fn_object, mem.nil())?)?;
+
+        self.compile_apply_call(mem, mem.lookup_sym("register-test"), args)
+    }
+
     /// (name <arg-expr-1> <arg-expr-n>)
     fn compile_apply_call<'guard>(
         &mut self,
         mem: &'guard MutatorView,
         function_expr: TaggedScopedPtr<'guard>,
         args: TaggedScopedPtr<'guard>,
+    ) -> Result<Register, RuntimeError> {
+        let arg_list = vec_from_pairs(mem, args)?;
+        if arg_list.iter().any(|arg| is_keyword(mem, *arg)) {
+            return self.compile_keyword_call(mem, function_expr, &arg_list);
+        }
+
+        self.compile_positional_call(mem, function_expr, &arg_list)
+    }
+
+    /// Compile a call with the arguments in the order they are given, any keywords among them
+    /// being passed as values
+    fn compile_positional_call<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        function_expr: TaggedScopedPtr<'guard>,
+        arg_list: &[TaggedScopedPtr<'guard>],
     ) -> Result<Register, RuntimeError> {
         // allocate a register for the return value
         let dest = self.acquire_reg();
@@ -626,11 +1257,12 @@ impl<'parent> Compiler<'parent> {
         let _closure_env = self.acquire_reg();
 
         // evaluate arguments first
-        let arg_list = vec_from_pairs(mem, args)?;
         let arg_count = arg_list.len() as u8;
 
+        self.check_arity(mem, function_expr, arg_count)?;
+
         for arg in arg_list {
-            let src = self.compile_eval(mem, arg)?;
+            let src = self.compile_eval(mem, *arg)?;
             // if a local variable register was returned, we need to copy the register to the arg
             // list. Bound registers are necessarily lower indexes than where the function call is
             // situated because expression scope and register acquisition progresses the register
@@ -657,12 +1289,189 @@ impl<'parent> Compiler<'parent> {
         Ok(dest)
     }
 
+    /// Compile a call with named arguments, which follow any positional arguments
+    /// (name <arg-expr-1> <arg-expr-n> :<param-name> <arg-expr> :<param-name> <arg-expr>)
+    ///
+    /// A keyword is only passed as a value if it is quoted. The arguments are mapped to parameters
+    /// at runtime, by compiling a call with no arguments of the Partial application returned by
+    /// (bind-keywords <function-expr> <positional-count> <arg-expr-1> :<param-name> <arg-expr>)
+    ///
+    /// When the function is a global that is already bound to a Function, the arguments are
+    /// instead copied into the registers of the parameters they are passed as and the function is
+    /// called directly, as long as the global is still bound to that Function when the call is
+    /// made. The global may have been redefined since, with its parameters in another order.
+    fn compile_keyword_call<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        function_expr: TaggedScopedPtr<'guard>,
+        arg_list: &[TaggedScopedPtr<'guard>],
+    ) -> Result<Register, RuntimeError> {
+        let positional = arg_list
+            .iter()
+            .position(|arg| is_keyword(mem, *arg))
+            .unwrap_or(arg_list.len());
+
+        let mut keywords = Vec::new();
+        let mut keyword_names = Vec::new();
+        let mut values = Vec::from(&arg_list[..positional]);
+        for named in arg_list[positional..].chunks(2) {
+            match *named[0] {
+                Value::Symbol(s) if s.is_keyword(mem) => keyword_names.push(s.as_str(mem)),
+                _ => {
+                    return Err(err_eval(&format!(
+                        "Expected a keyword naming a parameter, got {}",
+                        named[0]
+                    )))
+                }
+            }
+            if named.len() < 2 {
+                return Err(err_eval(&format!("No argument follows {}", named[0])));
+            }
+            keywords.push(named[0]);
+            values.push(named[1]);
+        }
+
+        // bind-keywords is passed the function, the positional count, the positional arguments and
+        // each keyword and its argument, all of which must fit the 8 bit argument count of a call
+        let bind_arg_count = keywords
+            .len()
+            .checked_mul(2)
+            .and_then(|count| count.checked_add(positional + 2))
+            .and_then(|count| u8::try_from(count).ok())
+            .ok_or_else(|| {
+                err_eval(&format!(
+                    "Too many arguments, a call with keyword arguments can pass at most {} \
+                     arguments and keywords",
+                    u8::MAX - 2
+                ))
+            })?;
+
+        if let Some((callee, name, params)) = self.known_callee(mem, function_expr)? {
+            let slots = keyword_arg_slots(name, &params, positional, &keyword_names)?;
+            let arg_count = values.len() as u8;
+
+            self.check_arity(mem, function_expr, arg_count)?;
+
+            let dest = self.acquire_reg();
+            let _closure_env = self.acquire_reg();
+
+            // reserve the argument registers, then evaluate each argument above them
+            let first_arg = self.next_reg;
+            let args_end = first_arg + arg_count;
+            self.reset_reg(args_end);
+            let mut sources = Vec::with_capacity(values.len());
+            for value in &values {
+                let mut src = self.compile_eval(mem, *value)?;
+                if src < args_end {
+                    let dest = self.acquire_reg();
+                    self.push(mem, Opcode::CopyRegister { dest, src })?;
+                    src = dest;
+                }
+                sources.push(src);
+            }
+
+            // only call directly if the global is still bound to the function compiled against
+            let function = self.compile_eval(mem, function_expr)?;
+            let known = self.push_load_literal(mem, callee)?;
+            let test = self.acquire_reg();
+            self.push(
+                mem,
+                Opcode::IsIdentical {
+                    dest: test,
+                    test1: function,
+                    test2: known,
+                },
+            )?;
+            let rebound = self.push_jump(mem, Opcode::JumpIfNotTrue { test, offset: 0 })?;
+
+            // copy each argument into the register of its parameter
+            for (src, slot) in sources.iter().zip(slots) {
+                let dest = first_arg + slot as Register;
+                self.push(mem, Opcode::CopyRegister { dest, src: *src })?;
+            }
+            self.push(
+                mem,
+                Opcode::Call {
+                    function,
+                    dest,
+                    arg_count,
+                },
+            )?;
+            let end = self.push_jump(mem, Opcode::Jump { offset: 0 })?;
+
+            // otherwise bind the evaluated arguments at runtime
+            self.patch_jump(mem, rebound)?;
+            let bound = self.acquire_reg();
+            let _closure_env = self.acquire_reg();
+            self.push_copy(mem, function)?;
+            self.push_load_literal(mem, mem.number(positional as isize))?;
+            for src in &sources[..positional] {
+                self.push_copy(mem, *src)?;
+            }
+            for (keyword, src) in keywords.iter().zip(&sources[positional..]) {
+                self.push_load_literal(mem, *keyword)?;
+                self.push_copy(mem, *src)?;
+            }
+            let bind_keywords = self.compile_eval(mem, mem.lookup_sym("bind-keywords"))?;
+            self.push(
+                mem,
+                Opcode::Call {
+                    function: bind_keywords,
+                    dest: bound,
+                    arg_count: bind_arg_count,
+                },
+            )?;
+            self.push(
+                mem,
+                Opcode::Call {
+                    function: bound,
+                    dest,
+                    arg_count: 0,
+                },
+            )?;
+            self.patch_jump(mem, end)?;
+
+            self.reset_reg(dest + 1);
+            return Ok(dest);
+        }
+
+        let dest = self.acquire_reg();
+        let _closure_env = self.acquire_reg();
+
+        // the keywords are passed to bind-keywords as values
+        let mut bind_args = vec![function_expr, mem.number(positional as isize)];
+        bind_args.extend_from_slice(&values[..positional]);
+        for (keyword, value) in keywords.iter().zip(&values[positional..]) {
+            bind_args.push(*keyword);
+            bind_args.push(*value);
+        }
+        let function =
+            self.compile_positional_call(mem, mem.lookup_sym("bind-keywords"), &bind_args)?;
+
+        self.push(
+            mem,
+            Opcode::Call {
+                function,
+                dest,
+                arg_count: 0,
+            },
+        )?;
+
+        self.reset_reg(dest + 1);
+        Ok(dest)
+    }
+
     /// Basic non-recursive let expressions
     /// (let
     ///   ((<name> <expr>)
     ///    (<name> <expr>))
     ///   (<expr>)
     /// )
+    ///
+    /// A name may also be a list pattern, which destructures the value of the expression:
+    ///   ((<name> <name>) <expr>)         exactly two values
+    ///   ((<name> (<name> <name>)) <expr>)  nested lists
+    ///   ((<name> . <name>) <expr>)       the first value and the rest of the list
     fn compile_apply_let<'guard>(
         &mut self,
         mem: &'guard MutatorView,
@@ -689,18 +1498,24 @@ impl<'parent> Compiler<'parent> {
 
         // get the names of each binding to push a scope, assigning registers post-result for
         // each binding
-        let names: Vec<TaggedScopedPtr<'guard>> = let_exprs.iter().map(|tup| tup.0).collect();
+        let mut names: Vec<TaggedScopedPtr<'guard>> = Vec::new();
+        for (pattern, _) in &let_exprs {
+            pattern_names(mem, *pattern, &mut names)?;
+        }
 
-        let mut let_scope = Scope::new();
-        self.next_reg = let_scope.push_bindings(&names, self.next_reg)?;
-        self.vars.scopes.push(let_scope);
+        self.warn_shadowed(mem, &names);
+        let first_binding = self.next_reg;
+        self.vars.push_scope();
+        self.next_reg = self.vars.push_bindings(&names, self.next_reg)?;
+        let after_bindings = self.next_reg;
 
-        // compile each binding expression
-        for (name, expr) in let_exprs {
+        // compile each binding expression. The binding registers are assigned in order, so the
+        // destination is found without a lookup that would count as a use of the variable.
+        let mut next_binding = first_binding;
+        for (pattern, expr) in let_exprs {
             let src = self.compile_eval(mem, expr)?;
-            let dest = self.compile_eval(mem, name)?;
-            // TODO - more efficient to be able to write the result directly to the let binding reg
-            self.push(mem, Opcode::CopyRegister { dest, src })?;
+            self.compile_destructure(mem, pattern, src, &mut next_binding)?;
+            self.reset_reg(after_bindings);
         }
 
         // compile the expressions after the bindings
@@ -713,6 +1528,7 @@ impl<'parent> Compiler<'parent> {
         }
 
         // finish up - pop the scope, de-scope all registers except the result, return the result
+        self.warn_unused(mem, "Variable");
         let closing_instructions = self.vars.pop_scope();
         for opcode in &closing_instructions {
             self.push(mem, *opcode)?;
@@ -722,58 +1538,599 @@ impl<'parent> Compiler<'parent> {
         Ok(dest)
     }
 
-    /// Push an instruction to the function bytecode list
-    fn push<'guard>(&mut self, mem: &'guard MutatorView, op: Opcode) -> Result<(), RuntimeError> {
-        self.bytecode.get(mem).push(mem, op)
-    }
-
-    /// Push an instruction with a result and a single argument to the function bytecode list
-    fn push_op2<'guard, F>(
+    /// Pattern matching
+    /// (match <expr>
+    ///   (<pattern> <expr>)
+    ///   (<pattern> <expr>)
+    /// )
+    /// The result is that of the expression of the first arm whose pattern matches, or nil if
+    /// none do. A pattern is one of:
+    ///   _                     matches anything
+    ///   <name>                matches anything, binding it to the name
+    ///   nil, true, 'x, 1, ""  matches a value identical to the literal, as with `is?`
+    ///   (<pattern> ...)       matches a list of exactly as many values
+    ///   (<pattern> . <pattern>)  matches a pair, the second pattern matching the rest of the list
+    fn compile_apply_match<'guard>(
         &mut self,
         mem: &'guard MutatorView,
-        params: TaggedScopedPtr<'guard>,
-        f: F,
-    ) -> Result<Register, RuntimeError>
-    where
-        F: Fn(Register, Register) -> Opcode,
-    {
-        let result = self.acquire_reg();
-        let reg1 = self.compile_eval(mem, value_from_1_pair(mem, params)?)?;
-        self.bytecode.get(mem).push(mem, f(result, reg1))?;
-        Ok(result)
+        args: TaggedScopedPtr<'guard>,
+    ) -> Result<Register, RuntimeError> {
+        let match_expr = vec_from_pairs(mem, args)?;
+        if match_expr.is_empty() {
+            return Err(err_eval("A match expression must have a value to match"));
+        }
+
+        let dest = self.acquire_reg();
+        let value = self.compile_eval(mem, match_expr[0])?;
+        let after_value = self.next_reg;
+
+        let mut end_jumps: Vec<JumpPatch> = Vec::new();
+
+        for arm in &match_expr[1..] {
+            let (pattern, expr) = values_from_2_pairs(mem, *arm)?;
+
+            // bind the pattern variables in a new scope
+            let mut names = Vec::new();
+            match_pattern_names(mem, pattern, &mut names)?;
+            self.warn_shadowed(mem, &names);
+
+            self.vars.push_scope();
+            self.next_reg = self.vars.push_bindings(&names, after_value)?;
+
+            // test and destructure the value, jumping to the next arm on the first failed test
+            let mut fail_jumps: Vec<JumpPatch> = Vec::new();
+            self.compile_match_pattern(mem, pattern, value, &mut fail_jumps)?;
+
+            let src = self.compile_eval(mem, expr)?;
+            self.push(mem, Opcode::CopyRegister { dest, src })?;
+
+            self.warn_unused(mem, "Pattern variable");
+            let closing_instructions = self.vars.pop_scope();
+            for opcode in &closing_instructions {
+                self.push(mem, *opcode)?;
+            }
+
+            end_jumps.push(self.push_jump(mem, Opcode::Jump { offset: 0 })?);
+
+            for jump in fail_jumps {
+                self.patch_jump(mem, jump)?;
+            }
+
+            self.reset_reg(after_value);
+        }
+
+        // no pattern matched
+        self.push(mem, Opcode::LoadNil { dest })?;
+
+        for jump in end_jumps {
+            self.patch_jump(mem, jump)?;
+        }
+
+        self.reset_reg(dest + 1);
+        Ok(dest)
     }
 
-    /// Push an instruction with a result and two arguments to the function bytecode list
-    fn push_op3<'guard, F>(
+    /// Compile the tests of a match pattern against the value in register `src`, pushing the
+    /// address of each jump taken when a test fails, and copy matched values into the pattern
+    /// variables of the innermost scope
+    fn compile_match_pattern<'guard>(
         &mut self,
         mem: &'guard MutatorView,
-        params: TaggedScopedPtr<'guard>,
-        f: F,
-    ) -> Result<Register, RuntimeError>
-    where
-        F: Fn(Register, Register, Register) -> Opcode,
-    {
-        let result = self.acquire_reg();
-        let (first, second) = values_from_2_pairs(mem, params)?;
-        let reg1 = self.compile_eval(mem, first)?;
-        let reg2 = self.compile_eval(mem, second)?;
-        self.bytecode.get(mem).push(mem, f(result, reg1, reg2))?;
-        Ok(result)
+        pattern: TaggedScopedPtr<'guard>,
+        src: Register,
+        fail_jumps: &mut Vec<JumpPatch>,
+    ) -> Result<(), RuntimeError> {
+        match *pattern {
+            Value::Symbol(s) if s.as_str(mem) == "_" => (),
+
+            Value::Symbol(_) if pattern != mem.sym_true() => {
+                let dest = match self
+                    .vars
+                    .innermost_scope()
+                    .iter()
+                    .rev()
+                    .find(|var| var.binds(pattern))
+                {
+                    Some(var) => var.register(),
+                    None => unreachable!(),
+                };
+                self.push(mem, Opcode::CopyRegister { dest, src })?;
+            }
+
+            Value::Pair(p) if !is_quoted(mem, pattern) => {
+                let test = self.acquire_reg();
+                self.push(
+                    mem,
+                    Opcode::IsPair {
+                        dest: test,
+                        test: src,
+                    },
+                )?;
+                fail_jumps.push(self.push_jump(mem, Opcode::JumpIfNotTrue { test, offset: 0 })?);
+
+                let first = self.acquire_reg();
+                self.push(
+                    mem,
+                    Opcode::FirstOfPair {
+                        dest: first,
+                        reg: src,
+                    },
+                )?;
+                self.compile_match_pattern(mem, p.first.get(mem), first, fail_jumps)?;
+
+                let second = self.acquire_reg();
+                self.push(
+                    mem,
+                    Opcode::SecondOfPair {
+                        dest: second,
+                        reg: src,
+                    },
+                )?;
+                self.compile_match_pattern(mem, p.second.get(mem), second, fail_jumps)?;
+            }
+
+            // everything else is a literal value, compared by identity
+            _ => {
+                let test = self.acquire_reg();
+                let literal = match *pattern {
+                    Value::Pair(p) => value_from_1_pair(mem, p.second.get(mem))?,
+                    Value::Symbol(_) => mem.sym_true(),
+                    _ => pattern,
+                };
+
+                let test2 = match *literal {
+                    Value::Nil => {
+                        self.push(
+                            mem,
+                            Opcode::IsNil {
+                                dest: test,
+                                test: src,
+                            },
+                        )?;
+                        None
+                    }
+                    _ => Some(self.push_load_literal(mem, literal)?),
+                };
+
+                if let Some(test2) = test2 {
+                    self.push(
+                        mem,
+                        Opcode::IsIdentical {
+                            dest: test,
+                            test1: src,
+                            test2,
+                        },
+                    )?;
+                }
+
+                fail_jumps.push(self.push_jump(mem, Opcode::JumpIfNotTrue { test, offset: 0 })?);
+            }
+        }
+
+        Ok(())
     }
 
-    // Push a literal onto the literals list and a load instruction onto the bytecode list
-    fn push_load_literal<'guard>(
+    /// Copy the value in register `src` into the binding registers of the names in `pattern`,
+    /// taking apart lists to match list patterns. `next_binding` is the register of the next name
+    /// in the pattern, in the order given by `pattern_names()`.
+    fn compile_destructure<'guard>(
         &mut self,
         mem: &'guard MutatorView,
-        literal: TaggedScopedPtr<'guard>,
-    ) -> Result<Register, RuntimeError> {
-        let result = self.acquire_reg();
-        let lit_id = self.bytecode.get(mem).push_lit(mem, literal)?;
-        self.bytecode.get(mem).push_loadlit(mem, result, lit_id)?;
-        Ok(result)
-    }
+        pattern: TaggedScopedPtr<'guard>,
+        src: Register,
+        next_binding: &mut Register,
+    ) -> Result<(), RuntimeError> {
+        match *pattern {
+            Value::Symbol(_) => {
+                let dest = *next_binding;
+                *next_binding += 1;
+                // TODO - more efficient to be able to write the result directly to the let binding reg
+                self.push(mem, Opcode::CopyRegister { dest, src })?;
+            }
 
-    // this is a naive way of allocating registers - every result gets it's own register
+            Value::Pair(_) => {
+                let mut list = src;
+                let mut head = pattern;
+
+                loop {
+                    match *head {
+                        Value::Pair(p) => {
+                            // a name is unpacked directly into its binding register, a nested
+                            // pattern into a temporary register first
+                            let element = p.first.get(mem);
+                            let first = match *element {
+                                Value::Symbol(_) => {
+                                    let reg = *next_binding;
+                                    *next_binding += 1;
+                                    reg
+                                }
+                                _ => self.acquire_reg(),
+                            };
+                            let second = self.acquire_reg();
+
+                            self.push(
+                                mem,
+                                Opcode::UnpackPair {
+                                    first,
+                                    second,
+                                    src: list,
+                                },
+                            )?;
+
+                            if let Value::Pair(_) = *element {
+                                self.compile_destructure(mem, element, first, next_binding)?;
+                            }
+
+                            list = second;
+                            head = p.second.get(mem);
+                        }
+
+                        // the list must have exactly as many values as the pattern
+                        Value::Nil => {
+                            self.push(mem, Opcode::ExpectNil { test: list })?;
+                            break;
+                        }
+
+                        // a dotted pattern binds the rest of the list
+                        _ => {
+                            self.compile_destructure(mem, head, list, next_binding)?;
+                            break;
+                        }
+                    }
+                }
+            }
+
+            _ => {
+                return Err(err_eval(
+                    "A binding name must be a symbol or a list pattern",
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record a global lookup of the given symbol, if global names are being recorded
+    fn reference_global<'guard>(&self, mem: &'guard MutatorView, name: TaggedScopedPtr<'guard>) {
+        if let (Some(globals), Value::Symbol(s)) = (&self.context.global_names, *name) {
+            let pos = self.span.map(|span| span.start);
+            globals
+                .borrow_mut()
+                .referenced
+                .push((String::from(s.as_str(mem)), pos));
+        }
+    }
+
+    /// Record that the given symbol is defined as a global, if global names are being recorded.
+    /// The constants nil and true and keywords cannot be defined.
+    fn define_global<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        name: TaggedScopedPtr<'guard>,
+    ) -> Result<(), RuntimeError> {
+        if name == mem.nil() || name == mem.sym_nil() || name == mem.sym_true() {
+            return Err(err_eval(&format!(
+                "Cannot bind the constant {} as a global",
+                name
+            )));
+        }
+
+        if is_keyword(mem, name) {
+            return Err(err_eval(&format!(
+                "Cannot bind the keyword {} as a global",
+                name
+            )));
+        }
+
+        if let (Some(globals), Value::Symbol(s)) = (&self.context.global_names, *name) {
+            globals
+                .borrow_mut()
+                .defined
+                .insert(String::from(s.as_str(mem)));
+        }
+
+        Ok(())
+    }
+
+    /// Check the argument count of a call to a global function that is already bound, according
+    /// to the arity checking option
+    fn check_arity<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        function_expr: TaggedScopedPtr<'guard>,
+        arg_count: u8,
+    ) -> Result<(), RuntimeError> {
+        let globals = match self.context.thread_globals {
+            Some(ref globals) if self.context.options.arity_checks != ArityChecks::Off => {
+                globals.get(mem)
+            }
+            _ => return Ok(()),
+        };
+
+        let name = match *function_expr {
+            Value::Symbol(s) if !self.vars.is_bound(function_expr) => s.as_str(mem),
+            _ => return Ok(()),
+        };
+
+        let function = match lookup_global_cell(mem, globals, function_expr)? {
+            Some(cell) => cell.get(mem),
+            None => return Ok(()),
+        };
+
+        let (arity, variadic) = match *function {
+            Value::Function(f) => (f.arity(), false),
+            Value::Partial(p) => (p.arity(), false),
+            Value::NativeFunction(n) => match n.arity() {
+                Arity::Exact(arity) => (arity, false),
+                Arity::AtLeast(arity) => (arity, true),
+            },
+            _ => return Ok(()),
+        };
+
+        let message = format!(
+            "{} takes {}{} argument{}, {} given",
+            name,
+            if variadic { "at least " } else { "" },
+            arity,
+            if arity == 1 { "" } else { "s" },
+            arg_count
+        );
+
+        if arg_count > arity && !variadic {
+            if self.context.options.arity_checks == ArityChecks::Error {
+                let kind = ErrorKind::EvalError(message);
+                return Err(match self.span {
+                    Some(span) => RuntimeError::with_pos(kind, span.start),
+                    None => RuntimeError::new(kind),
+                });
+            }
+            self.warn("arity", &message);
+        } else if arg_count < arity {
+            // a native function cannot be partially applied
+            match *function {
+                Value::NativeFunction(_) => self.warn("arity", &message),
+                _ => self.warn(
+                    "arity",
+                    &format!("{}: the call makes a partial application", message),
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the function a global is bound to, with its name and unapplied parameter names, if
+    /// the function expression is the name of a global that is already bound to a Function or
+    /// Partial in the globals of the Thread being compiled for
+    fn known_callee<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        function_expr: TaggedScopedPtr<'guard>,
+    ) -> Result<Option<Callee<'guard>>, RuntimeError> {
+        let globals = match self.context.thread_globals {
+            Some(ref globals) => globals.get(mem),
+            None => return Ok(None),
+        };
+
+        match *function_expr {
+            Value::Symbol(_) if !self.vars.is_bound(function_expr) => (),
+            _ => return Ok(None),
+        }
+
+        match lookup_global_cell(mem, globals, function_expr)? {
+            Some(cell) => {
+                let callee = cell.get(mem);
+                Ok(unapplied_params(mem, callee).map(|(name, params)| (callee, name, params)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Return true if the code is being optimized
+    fn optimizing(&self) -> bool {
+        self.context.options.opt_level != OptLevel::O0
+    }
+
+    /// Record a warning at the start of the expression being compiled
+    fn warn(&mut self, code: &'static str, message: &str) {
+        self.context
+            .warn(code, message, self.span.map(|span| span.start));
+    }
+
+    /// Warn about any of the names that hide a variable bound in an enclosing scope
+    fn warn_shadowed<'guard>(
+        &mut self,
+        guard: &'guard dyn MutatorScope,
+        names: &[TaggedScopedPtr<'guard>],
+    ) {
+        for name in names {
+            if let Value::Symbol(s) = **name {
+                if self.vars.is_bound(*name) {
+                    self.warn(
+                        "shadowed-binding",
+                        &format!(
+                            "Binding of {} shadows a variable in an enclosing scope",
+                            s.as_str(guard)
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Warn about each variable in the innermost scope that was never referenced
+    fn warn_unused<'guard>(&mut self, guard: &'guard dyn MutatorScope, kind: &str) {
+        let warnings: Vec<String> = self
+            .vars
+            .unused_names(guard)
+            .iter()
+            .map(|name| format!("{} {} is never used", kind, name))
+            .collect();
+
+        for warning in warnings {
+            self.warn("unused-variable", &warning);
+        }
+    }
+
+    /// Copy a register into the next one, returning it
+    fn push_copy<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        src: Register,
+    ) -> Result<Register, RuntimeError> {
+        let dest = self.acquire_reg();
+        self.push(mem, Opcode::CopyRegister { dest, src })?;
+        Ok(dest)
+    }
+
+    /// Push an instruction to the function bytecode list
+    fn push<'guard>(&mut self, mem: &'guard MutatorView, op: Opcode) -> Result<(), RuntimeError> {
+        self.bytecode.get(mem).push_with_span(mem, op, self.span)
+    }
+
+    /// Push a jump instruction whose target is not known yet, returning the patch that sets it
+    fn push_jump<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        op: Opcode,
+    ) -> Result<JumpPatch, RuntimeError> {
+        let jump = self.bytecode.get(mem).push_jump(mem, op, self.span)?;
+        self.unpatched.push(jump.instruction());
+        Ok(jump)
+    }
+
+    /// Make a jump land on the next instruction to be pushed
+    fn patch_jump<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        jump: JumpPatch,
+    ) -> Result<(), RuntimeError> {
+        self.unpatched
+            .retain(|instruction| *instruction != jump.instruction());
+        self.bytecode.get(mem).patch(mem, jump)
+    }
+
+    /// Every jump must have been patched by the time the Function is finished, or it would land
+    /// on the instruction after it instead of its target
+    fn assert_patched(&self) {
+        debug_assert!(
+            self.unpatched.is_empty(),
+            "the jumps at instructions {:?} of {} were not patched",
+            self.unpatched,
+            self.name.as_deref().unwrap_or("<lambda>")
+        );
+    }
+
+    /// Push an instruction with a result and a single argument to the function bytecode list
+    fn push_op2<'guard, F>(
+        &mut self,
+        mem: &'guard MutatorView,
+        params: TaggedScopedPtr<'guard>,
+        f: F,
+    ) -> Result<Register, RuntimeError>
+    where
+        F: Fn(Register, Register) -> Opcode,
+    {
+        let result = self.acquire_reg();
+        let reg1 = self.compile_eval(mem, value_from_1_pair(mem, params)?)?;
+        self.push(mem, f(result, reg1))?;
+        Ok(result)
+    }
+
+    /// Push an instruction with a result and two arguments to the function bytecode list
+    fn push_op3<'guard, F>(
+        &mut self,
+        mem: &'guard MutatorView,
+        params: TaggedScopedPtr<'guard>,
+        f: F,
+    ) -> Result<Register, RuntimeError>
+    where
+        F: Fn(Register, Register, Register) -> Opcode,
+    {
+        let result = self.acquire_reg();
+        let (first, second) = values_from_2_pairs(mem, params)?;
+        let reg1 = self.compile_eval(mem, first)?;
+        let reg2 = self.compile_eval(mem, second)?;
+        self.push(mem, f(result, reg1, reg2))?;
+        Ok(result)
+    }
+
+    /// Push a chain of instructions with a result and two arguments that fold one or more
+    /// arguments from left to right: `(f a b c)` is `(f (f a b) c)`. A single argument is folded
+    /// with itself so that its type is still checked.
+    fn push_fold<'guard, F>(
+        &mut self,
+        mem: &'guard MutatorView,
+        params: TaggedScopedPtr<'guard>,
+        name: &str,
+        f: F,
+    ) -> Result<Register, RuntimeError>
+    where
+        F: Fn(Register, Register, Register) -> Opcode,
+    {
+        let params = vec_from_pairs(mem, params)?;
+        let (first, rest) = match params.split_first() {
+            Some(split) => split,
+            None => {
+                return Err(err_eval(&format!(
+                    "{} takes at least 1 argument, 0 given",
+                    name
+                )))
+            }
+        };
+
+        let result = self.acquire_reg();
+        let reg1 = self.compile_eval(mem, *first)?;
+        if rest.is_empty() {
+            self.push(mem, f(result, reg1, reg1))?;
+            return Ok(result);
+        }
+
+        let mut acc = reg1;
+        for param in rest {
+            let reg2 = self.compile_eval(mem, *param)?;
+            self.push(mem, f(result, acc, reg2))?;
+            acc = result;
+        }
+        Ok(result)
+    }
+
+    // Push a literal onto the literals list and a load instruction onto the bytecode list
+    fn push_load_literal<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        literal: TaggedScopedPtr<'guard>,
+    ) -> Result<Register, RuntimeError> {
+        let result = self.acquire_reg();
+
+        // nil and true have instructions of their own rather than constant pool entries
+        if literal == mem.nil() {
+            self.push(mem, Opcode::LoadNil { dest: result })?;
+        } else if literal == mem.sym_true() {
+            self.push(mem, Opcode::LoadTrue { dest: result })?;
+        } else {
+            let literal = mem.constant(literal);
+            let literal_id = match self.literal_ids.get(&literal.get_ptr()) {
+                Some(literal_id) => *literal_id,
+                None => {
+                    let literal_id = self.bytecode.get(mem).push_lit(mem, literal)?;
+                    self.literal_ids.insert(literal.get_ptr(), literal_id);
+                    literal_id
+                }
+            };
+            self.push(
+                mem,
+                Opcode::LoadLiteral {
+                    dest: result,
+                    literal_id,
+                },
+            )?;
+        }
+
+        Ok(result)
+    }
+
+    // this is a naive way of allocating registers - every result gets it's own register
     fn acquire_reg(&mut self) -> Register {
         // TODO check overflow
         let reg = self.next_reg;
@@ -803,29 +2160,285 @@ impl<'parent> Compiler<'parent> {
     fn reset_reg(&mut self, reg: Register) {
         self.next_reg = reg
     }
+
+    /// Compile a function nested in the current one - parameters and expressions, returning a
+    /// tagged Function object. Its warnings are collected with this compiler's.
+    fn compile_nested_function<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        name: TaggedScopedPtr<'guard>,
+        params: &[TaggedScopedPtr<'guard>],
+        exprs: &[TaggedScopedPtr<'guard>],
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let compiler = Compiler::new(mem, Some(&self.vars), self.span, self.context)?;
+        let function = compiler.compile_function(mem, name, params, exprs)?;
+        Ok(function.as_tagged(mem))
+    }
 }
 
-/// Compile a function - parameters and expression, returning a tagged Function object
-fn compile_function<'guard, 'scope>(
-    mem: &'guard MutatorView,
-    parent: Option<&'scope Variables<'scope>>,
-    name: TaggedScopedPtr<'guard>,
-    params: &[TaggedScopedPtr<'guard>],
-    exprs: &[TaggedScopedPtr<'guard>],
-) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
-    let compiler = Compiler::new(mem, parent)?;
-    Ok(compiler
-        .compile_function(mem, name, params, exprs)?
-        .as_tagged(mem))
+/// Append the names bound by a let binding pattern, in order from left to right: a symbol binds
+/// itself, a list pattern binds the names it contains.
+fn pattern_names<'guard>(
+    guard: &'guard dyn MutatorScope,
+    pattern: TaggedScopedPtr<'guard>,
+    names: &mut Vec<TaggedScopedPtr<'guard>>,
+) -> Result<(), RuntimeError> {
+    match *pattern {
+        Value::Symbol(_) => names.push(pattern),
+
+        Value::Pair(p) => {
+            pattern_names(guard, p.first.get(guard), names)?;
+
+            // the end of a list pattern binds nothing
+            let rest = p.second.get(guard);
+            match *rest {
+                Value::Nil => (),
+                _ => pattern_names(guard, rest, names)?,
+            }
+        }
+
+        _ => {
+            return Err(err_eval(
+                "A binding name must be a symbol or a list pattern",
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+/// Append the variables bound by a match pattern, in order from left to right. Each variable
+/// may appear only once in a pattern.
+fn match_pattern_names<'guard>(
+    guard: &'guard dyn MutatorScope,
+    pattern: TaggedScopedPtr<'guard>,
+    names: &mut Vec<TaggedScopedPtr<'guard>>,
+) -> Result<(), RuntimeError> {
+    match *pattern {
+        Value::Symbol(s) => match s.as_str(guard) {
+            "_" | "true" => (),
+            name => {
+                if names.contains(&pattern) {
+                    return Err(err_eval(&format!(
+                        "Pattern variable {} is bound more than once",
+                        name
+                    )));
+                }
+                names.push(pattern)
+            }
+        },
+
+        Value::Pair(p) if !is_quoted(guard, pattern) => {
+            match_pattern_names(guard, p.first.get(guard), names)?;
+            match_pattern_names(guard, p.second.get(guard), names)?;
+        }
+
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// A function a global is bound to, its name and the names of its unapplied parameters
+type Callee<'guard> = (TaggedScopedPtr<'guard>, &'guard str, Vec<&'guard str>);
+
+/// Return true if the expression is a keyword symbol
+fn is_keyword<'guard>(guard: &'guard dyn MutatorScope, expr: TaggedScopedPtr<'guard>) -> bool {
+    match *expr {
+        Value::Symbol(s) => s.is_keyword(guard),
+        _ => false,
+    }
+}
+
+/// Return true if the expression is of the form (quote x)
+fn is_quoted<'guard>(guard: &'guard dyn MutatorScope, expr: TaggedScopedPtr<'guard>) -> bool {
+    match *expr {
+        Value::Pair(p) => match *p.first.get(guard) {
+            Value::Symbol(s) => s.as_str(guard) == "quote",
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Return whether an expression is always true or never true, or None if that is only known at
+/// runtime. Only the symbol `true` is true.
+fn constant_truth<'guard>(
+    guard: &'guard dyn MutatorScope,
+    expr: TaggedScopedPtr<'guard>,
+) -> Option<bool> {
+    match *expr {
+        // a symbol is a variable unless it is one of the constants compiled as literals
+        Value::Symbol(s) => match s.as_str(guard) {
+            "true" => Some(true),
+            "nil" => Some(false),
+            _ => None,
+        },
+
+        Value::Pair(p) => match *p.first.get(guard) {
+            Value::Symbol(s) if s.as_str(guard) == "quote" => match *p.second.get(guard) {
+                Value::Pair(quoted) => match *quoted.first.get(guard) {
+                    Value::Symbol(s) => Some(s.as_str(guard) == "true"),
+                    _ => Some(false),
+                },
+                _ => None,
+            },
+            _ => None,
+        },
+
+        // any other literal value evaluates to itself
+        _ => Some(false),
+    }
 }
 
-/// Compile the given AST and return an anonymous Function object
+/// Return the span of source code a list expression covers, from the position of its first
+/// element to the position of its last
+fn source_span<'guard>(
+    guard: &'guard dyn MutatorScope,
+    pair: ScopedPtr<'guard, Pair>,
+) -> Option<SourceSpan> {
+    let start = pair.first_pos.get()?;
+    let mut end = start;
+
+    let mut current = pair;
+    loop {
+        if let Some(pos) = current.first_pos.get() {
+            end = pos;
+        }
+
+        match *current.second.get(guard) {
+            Value::Pair(next) => current = next,
+            _ => {
+                // a dotted pair ends with its second value
+                if let Some(pos) = current.second_pos.get() {
+                    end = pos;
+                }
+                break;
+            }
+        }
+    }
+
+    Some(SourceSpan { start, end })
+}
+
+/// Compile the given AST and return an anonymous Function object, discarding any warnings
 pub fn compile<'guard>(
     mem: &'guard MutatorView,
     ast: TaggedScopedPtr<'guard>,
 ) -> Result<ScopedPtr<'guard, Function>, RuntimeError> {
-    let compiler = Compiler::new(mem, None)?;
-    compiler.compile_function(mem, mem.nil(), &[], &[ast])
+    Ok(compile_with_diagnostics(mem, ast)?.0)
+}
+
+/// Compile the given AST and return an anonymous Function object along with any warnings
+pub fn compile_with_diagnostics<'guard>(
+    mem: &'guard MutatorView,
+    ast: TaggedScopedPtr<'guard>,
+) -> Result<(ScopedPtr<'guard, Function>, Vec<Diagnostic>), RuntimeError> {
+    let context = CompilerContext::new(mem.nil(), CompileContext::new(CompileOptions::default()));
+    let function =
+        Compiler::new(mem, None, None, &context)?.compile_function(mem, mem.nil(), &[], &[ast])?;
+    Ok((function, context.into_diagnostics()))
+}
+
+/// Compile the given AST, read from the named source file, and return an anonymous Function
+/// object along with any warnings. Functions defined in the AST record the file name.
+pub fn compile_in_file<'guard>(
+    mem: &'guard MutatorView,
+    ast: TaggedScopedPtr<'guard>,
+    file_name: &str,
+) -> Result<(ScopedPtr<'guard, Function>, Vec<Diagnostic>), RuntimeError> {
+    let context = CompilerContext::new(
+        mem.text(file_name)?,
+        CompileContext::new(CompileOptions::default()),
+    );
+    let function =
+        Compiler::new(mem, None, None, &context)?.compile_function(mem, mem.nil(), &[], &[ast])?;
+    Ok((function, context.into_diagnostics()))
+}
+
+/// Compile a sequence of top level forms, such as the contents of a source file, into a single
+/// anonymous Function that evaluates them in order and returns the result of the last. Functions
+/// defined in the forms record the file name, if one is given.
+pub fn compile_toplevel<'guard>(
+    mem: &'guard MutatorView,
+    forms: &[TaggedScopedPtr<'guard>],
+    file_name: Option<&str>,
+) -> Result<(ScopedPtr<'guard, Function>, Vec<Diagnostic>), RuntimeError> {
+    let context = CompileContext::new(CompileOptions::default());
+    compile_toplevel_in_context(mem, forms, file_name, context)
+}
+
+/// As `compile_toplevel()`, generating code according to the given context
+pub fn compile_toplevel_in_context<'guard>(
+    mem: &'guard MutatorView,
+    forms: &[TaggedScopedPtr<'guard>],
+    file_name: Option<&str>,
+    context: CompileContext<'guard>,
+) -> Result<(ScopedPtr<'guard, Function>, Vec<Diagnostic>), RuntimeError> {
+    let file_name = match file_name {
+        Some(file_name) => mem.text(file_name)?,
+        None => mem.nil(),
+    };
+
+    let context = CompilerContext::new(file_name, context);
+    let function = Compiler::new(mem, None, None, &context)?.compile_toplevel(mem, forms)?;
+    Ok((function, context.into_diagnostics()))
+}
+
+/// Compile each of a sequence of top level forms without evaluating them and return every
+/// problem found, in source order. A form that fails to compile is reported as an error and the
+/// check continues with the next. A global that is referenced but neither defined by the forms
+/// nor bound according to `is_bound` is also an error.
+pub fn check_toplevel<'guard>(
+    mem: &'guard MutatorView,
+    forms: &[TaggedScopedPtr<'guard>],
+    file_name: Option<&str>,
+    is_bound: impl Fn(&str) -> bool,
+) -> Result<Vec<Diagnostic>, RuntimeError> {
+    let file_name = match file_name {
+        Some(file_name) => mem.text(file_name)?,
+        None => mem.nil(),
+    };
+
+    let globals = Rc::new(RefCell::new(GlobalNames::default()));
+    let mut diagnostics = Vec::new();
+
+    for form in forms {
+        // the warnings of a form that fails to compile are left out with the form
+        let mut context =
+            CompilerContext::new(file_name, CompileContext::new(CompileOptions::default()));
+        context.global_names = Some(globals.clone());
+
+        match Compiler::new(mem, None, None, &context)?.compile_toplevel(mem, &[*form]) {
+            Ok(_) => diagnostics.extend(context.into_diagnostics()),
+
+            Err(e) => match e.error_kind() {
+                ErrorKind::EvalError(reason) | ErrorKind::ParseError(reason) => {
+                    let pos = e.error_pos().or_else(|| match **form {
+                        Value::Pair(pair) => source_span(mem, pair).map(|span| span.start),
+                        _ => None,
+                    });
+                    diagnostics.push(Diagnostic::error(e.error_kind().code(), reason, pos));
+                }
+                // anything else, such as running out of memory, is not a problem with the source
+                _ => return Err(e),
+            },
+        }
+    }
+
+    let globals = globals.borrow();
+    for (name, pos) in &globals.referenced {
+        if !globals.defined.contains(name) && !is_bound(name) {
+            diagnostics.push(Diagnostic::error(
+                "undefined-global",
+                &format!("Global {} is not defined", name),
+                *pos,
+            ));
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.pos().map(|pos| pos.offset));
+    Ok(diagnostics)
 }
 
 /// INTEGRATION TESTS
@@ -833,9 +2446,14 @@ pub fn compile<'guard>(
 #[cfg(test)]
 mod integration {
     use super::*;
-    use crate::memory::{Memory, Mutator};
-    use crate::parser::parse;
+    use crate::containers::StackAnyContainer;
+    use crate::error::ErrorKind;
+    use crate::lexer::lex_reader;
+    use crate::memory::{Memory, Mutator, POISON_STORAGE_ENV_VAR};
+    use crate::parser::{parse, Parser};
     use crate::vm::Thread;
+    use std::env;
+    use std::io::Cursor;
 
     fn eval_helper<'guard>(
         mem: &'guard MutatorView,
@@ -857,30 +2475,677 @@ mod integration {
             type Input = fn(&MutatorView) -> Result<(), RuntimeError>;
             type Output = ();
 
-            fn run(
-                &self,
-                mem: &MutatorView,
-                test_fn: Self::Input,
-            ) -> Result<Self::Output, RuntimeError> {
-                test_fn(mem)
-            }
+            fn run(
+                &self,
+                mem: &MutatorView,
+                test_fn: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                test_fn(mem)
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, test_fn).unwrap();
+    }
+
+    #[test]
+    fn compile_cond_first_is_true() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // testing 'cond'
+            // (nil? nil) == true, so result should be x
+            let code = "(cond (nil? nil) 'x (nil? 'a) 'y)";
+
+            let t = Thread::alloc(mem)?;
+
+            let result = eval_helper(mem, t, code)?;
+
+            assert!(result == mem.lookup_sym("x"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_cond_second_is_true() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // testing 'cond'
+            // (nil? 'a) == nil, (nil? nil) == true, so result should be y
+            let code = "(cond (nil? 'a) 'x (nil? nil) 'y)";
+
+            let t = Thread::alloc(mem)?;
+
+            let result = eval_helper(mem, t, code)?;
+
+            assert!(result == mem.lookup_sym("y"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_cond_arm_returns_variable() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // the value of the taken arm is a variable, not the value of its test
+            let t = Thread::alloc(mem)?;
+            eval_helper(mem, t, "(def h (x y) (cond true y))")?;
+            assert!(eval_helper(mem, t, "(h 'a 'b)")? == mem.lookup_sym("b"));
+
+            eval_helper(mem, t, "(def k (x) (cond (nil? x) 'one true x))")?;
+            assert!(eval_helper(mem, t, "(k 'two)")? == mem.lookup_sym("two"));
+            assert!(eval_helper(mem, t, "(k nil)")? == mem.lookup_sym("one"));
+
+            eval_helper(mem, t, "(def m (x y) (cond (nil? x) y true 'other))")?;
+            assert!(eval_helper(mem, t, "(m nil 'b)")? == mem.lookup_sym("b"));
+
+            // and the same unoptimized
+            let options = CompileOptions {
+                opt_level: OptLevel::O0,
+                ..CompileOptions::default()
+            };
+            let forms = [parse(mem, "((lambda (x y) (cond true y)) 'a 'b)")?];
+            let function =
+                compile_toplevel_in_context(mem, &forms, None, CompileContext::new(options))?.0;
+            assert!(t.quick_vm_eval(mem, function)? == mem.lookup_sym("b"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_cond_none_is_true() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // testing 'cond'
+            // (nil? 'a) == nil, (nil? 'b) == nil, result should be nil
+            let code = "(cond (nil? 'a) 'x (nil? 'b) 'y)";
+
+            let t = Thread::alloc(mem)?;
+
+            let result = eval_helper(mem, t, code)?;
+
+            assert!(result == mem.nil());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_same_results_at_every_opt_level() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let programs = [
+                "(abs (min -3 2 (bit-and 12 10)))",
+                "(max 1 (abs -7) 4)",
+                "(min 5)",
+                "(bit-xor (bit-or 1 2) 7)",
+                "(cons (nil? '()) (nil? 'a))",
+                "(cons (atom? '(a)) (atom? :key))",
+                "(cond nil 'a 'x 'b true 'c 'd)",
+                "(cond (nil? nil) 'a true 'b)",
+                "(def pick (a b) (cond (nil? a) (cond (nil? b) 'x true 'y) true 'z))",
+                "(cons (pick nil nil) (cons (pick nil 'b) (pick 'a nil)))",
+                "((lambda (n) (min n (abs -2))) 3)",
+                "(min 1 'a)",
+                "(abs 'a)",
+                "(bit-and 1 'b)",
+                "(nil?)",
+            ];
+
+            let results = |opt_level| -> Result<Vec<String>, RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let options = CompileOptions {
+                    opt_level,
+                    ..CompileOptions::default()
+                };
+
+                let mut results = Vec::new();
+                for code in &programs {
+                    let context = CompileContext::new(options);
+                    let result =
+                        compile_toplevel_in_context(mem, &[parse(mem, code)?], None, context)
+                            .and_then(|(function, _)| t.quick_vm_eval(mem, function));
+                    results.push(match result {
+                        Ok(value) => format!("{}", value),
+                        Err(e) => format!("error: {}", e),
+                    });
+                }
+                Ok(results)
+            };
+
+            let unoptimized = results(OptLevel::O0)?;
+            assert!(unoptimized == results(OptLevel::O1)?);
+            assert!(unoptimized[0] == "3");
+            assert!(unoptimized[9] == "(x y . z)");
+            assert!(unoptimized[11].starts_with("error"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_cond_drops_unreachable_arms() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let compiled = |code| -> Result<(String, Vec<Diagnostic>), RuntimeError> {
+                let (function, warnings) = compile_with_diagnostics(mem, parse(mem, code)?)?;
+                Ok((format!("{:?}", function.code(mem)), warnings))
+            };
+
+            // an always-true arm needs no test and ends the cond
+            let (code, warnings) = compiled("(cond (nil? x) 'a true 'b 'c 'd)")?;
+            assert!(code.matches("JumpIfNotTrue").count() == 1);
+            assert!(!code.contains("LoadNil"));
+            assert!(warnings.len() == 1);
+
+            let (code, warnings) = compiled("(cond true 'a)")?;
+            assert!(!code.contains("Jump"));
+            assert!(warnings.is_empty());
+
+            // an arm that can never be taken is dropped and the default nil remains
+            let (code, warnings) = compiled("(cond nil 'a 'x 'b)")?;
+            assert!(!code.contains("Jump"));
+            assert!(code.contains("LoadNil"));
+            assert!(warnings.len() == 2);
+
+            assert!(
+                eval_helper(mem, t, "(cond (nil? 'x) 'a true 'b 'c 'd)")? == mem.lookup_sym("b")
+            );
+            assert!(eval_helper(mem, t, "(cond nil 'a 'x 'b)")? == mem.nil());
+            assert!(eval_helper(mem, t, "(cond nil 'a 'true 'b)")? == mem.lookup_sym("b"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_call_functions() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // this test calls a function from another function
+            let compare_fn = "(def is_it (ask expect) (is? ask expect))";
+            let curried_fn = "(def is_it_a (ask) (is_it ask 'a))";
+            let query1 = "(is_it_a nil)";
+            let query2 = "(is_it_a 'a)";
+
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, compare_fn)?;
+            eval_helper(mem, t, curried_fn)?;
+
+            let result1 = eval_helper(mem, t, query1)?;
+            assert!(result1 == mem.nil());
+
+            let result2 = eval_helper(mem, t, query2)?;
+            assert!(result2 == mem.lookup_sym("true"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_map_function_over_list() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // this test passes a function as a parameter through recursive function calls
+            let compare_fn = "(def is_y (ask) (is? ask 'y))";
+            let map_fn =
+                "(def map (f l) (cond (nil? l) nil true (cons (f (car l)) (map f (cdr l)))))";
+
+            let query = "(map is_y '(x y z z y))";
+
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, compare_fn)?;
+            eval_helper(mem, t, map_fn)?;
+
+            let result = eval_helper(mem, t, query)?;
+
+            let result = vec_from_pairs(mem, result)?;
+            let sym_nil = mem.nil();
+            let sym_true = mem.lookup_sym("true");
+            assert!(result == &[sym_nil, sym_true, sym_nil, sym_nil, sym_true]);
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_eval_nested_partials() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // this test evaluates nested Partial applications in function position
+            let a_fn = "(def isit (a b) (is? a b))";
+
+            let query1 = "((isit 'x) 'x)";
+            let query2 = "((isit 'x) 'y)";
+
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, a_fn)?;
+
+            let result = eval_helper(mem, t, query1)?;
+            assert!(result == mem.lookup_sym("true"));
+
+            let result = eval_helper(mem, t, query2)?;
+            assert!(result == mem.nil());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_pass_partial_as_param() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // this test passes a Partial as an argument of another function that will call it
+            // with it's last argument.
+            let isit_fn = "(def isit (a b) (is? a b))";
+            let map_fn = "(def map (f v) (f v))";
+
+            let query1 = "(map (isit 'x) 'x)";
+            let query2 = "(map (isit 'x) 'y)";
+
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, isit_fn)?;
+            eval_helper(mem, t, map_fn)?;
+
+            let result = eval_helper(mem, t, query1)?;
+            assert!(result == mem.lookup_sym("true"));
+
+            let result = eval_helper(mem, t, query2)?;
+            assert!(result == mem.nil());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_simple_let() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // this test compiles a basic let expression
+            let expr = "(let ((x 'y)) x)";
+
+            let t = Thread::alloc(mem)?;
+
+            let result = eval_helper(mem, t, expr)?;
+            assert!(result == mem.lookup_sym("y"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_destructuring_let() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let result = eval_helper(mem, t, "(let (((a b) '(x y))) (cons b a))")?;
+            assert!(format!("{}", result) == "(y . x)");
+
+            let result = eval_helper(mem, t, "(let (((a (b c)) '(x (y z)))) (cons a (cons b c)))")?;
+            assert!(format!("{}", result) == "(x y . z)");
+
+            let result = eval_helper(mem, t, "(let (((a . rest) '(x y z)) (b 'w)) (cons b rest))")?;
+            assert!(format!("{}", result) == "(w y z)");
+
+            // functions can return several values in a list
+            eval_helper(mem, t, "(def split (l) (cons (car l) (cons (cdr l) nil)))")?;
+            let result = eval_helper(mem, t, "(let (((head tail) (split '(x y)))) tail)")?;
+            assert!(format!("{}", result) == "(y)");
+
+            // the value must match the shape of the pattern
+            assert!(eval_helper(mem, t, "(let (((a b) '(x))) a)").is_err());
+            assert!(eval_helper(mem, t, "(let (((a b) '(x y z))) a)").is_err());
+            assert!(eval_helper(mem, t, "(let (((a b) 'x)) a)").is_err());
+            assert!(eval_helper(mem, t, "(let (((a 1) '(x y))) a)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_match() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            let describe = "(def describe (v)
+                (match v
+                  (nil 'empty)
+                  ('x 'the-symbol-x)
+                  (3 'three)
+                  ((a) (cons 'one a))
+                  ((a (b . _)) (cons 'nested (cons a b)))
+                  ((a . rest) (cons 'many rest))
+                  (_ 'other)))";
+            eval_helper(mem, t, describe)?;
+
+            let check = |code, expected| -> Result<(), RuntimeError> {
+                let result = eval_helper(mem, t, code)?;
+                assert!(format!("{}", result) == expected);
+                Ok(())
+            };
+
+            check("(describe nil)", "empty")?;
+            check("(describe 'x)", "the-symbol-x")?;
+            check("(describe 'y)", "other")?;
+            check("(describe 3)", "three")?;
+            check("(describe '(z))", "(one . z)")?;
+            // the nested pattern only matches if the second value is a list
+            check("(describe '(z (y w)))", "(nested z . y)")?;
+            check("(describe '(z y))", "(many y)")?;
+
+            // a failed arm falls through to the next and no match results in nil
+            check("(match '(a b) ((x) x) ((x y z) z))", "nil")?;
+            check("(match '(a b) ((x) x) ((x true) x) ((x y) y))", "b")?;
+            check("(match '(a true) ((x y z) z) ((x true) x))", "a")?;
+
+            // pattern variables are local to their arm and may be captured by closures
+            eval_helper(mem, t, "(def pick (l) (match l ((a b) (\\ () b))))")?;
+            check("((pick '(x y)))", "y")?;
+
+            assert!(eval_helper(mem, t, "(match 'a ((x x) x))").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_parameter_type_annotations() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, "(def swap ((a symbol) (b pair)) (cons b a))")?;
+            let result = eval_helper(mem, t, "(swap 'x '(y))")?;
+            assert!(format!("{}", result) == "((y) . x)");
+
+            // a mismatch names the parameter, the function, the type and the value
+            match eval_helper(mem, t, "(swap 'x 3)") {
+                Err(e) => match e.error_kind() {
+                    ErrorKind::EvalError(reason) => {
+                        assert!(reason == "Parameter b to swap must be a pair, got 3 (integer)")
+                    }
+                    _ => panic!("expected an evaluation error"),
+                },
+                Ok(_) => panic!("expected a type error"),
+            }
+
+            // annotated and plain parameters mix, in lambdas too
+            let code = "((lambda (a (b list)) b) 'x nil)";
+            assert!(eval_helper(mem, t, code)? == mem.nil());
+            assert!(eval_helper(mem, t, "((lambda (a (f function)) a) 'x 'y)").is_err());
+
+            assert!(eval_helper(mem, t, "(def f ((a widget)) a)").is_err());
+            assert!(eval_helper(mem, t, "(def f ((a)) a)").is_err());
+
+            // checks can be left out of generated code
+            let forms = [parse(mem, "((lambda ((a integer)) a) 'x)")?];
+            let options = CompileOptions {
+                type_checks: false,
+                ..CompileOptions::default()
+            };
+            let context = CompileContext::new(options);
+            let function = compile_toplevel_in_context(mem, &forms, None, context)?.0;
+            assert!(t.quick_vm_eval(mem, function)? == mem.lookup_sym("x"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_checks_arity_of_known_globals() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            eval_helper(mem, t, "(def pair-of (a b) (cons a b))")?;
+
+            let compile_with = |code, arity_checks| {
+                let options = CompileOptions {
+                    arity_checks,
+                    ..CompileOptions::default()
+                };
+                let context = CompileContext::for_thread(mem, options, &t);
+                compile_toplevel_in_context(mem, &[parse(mem, code)?], None, context)
+            };
+            let warnings = |code| -> Result<Vec<String>, RuntimeError> {
+                let found = compile_with(code, ArityChecks::Warn)?.1;
+                Ok(found.iter().map(|w| String::from(w.message())).collect())
+            };
+
+            assert!(warnings("(pair-of 'a 'b)")?.is_empty());
+            assert!(warnings("(pair-of 'a 'b 'c)")? == ["pair-of takes 2 arguments, 3 given"]);
+            assert!(
+                warnings("(pair-of 'a)")?
+                    == ["pair-of takes 2 arguments, 1 given: the call makes a partial application"]
+            );
+
+            // native functions, including variadic ones
+            let found = warnings("(symbol->string 'a 'b)")?;
+            assert!(found == ["symbol->string takes 1 argument, 2 given"]);
+            assert!(warnings("(format)")? == ["format takes at least 1 argument, 0 given"]);
+            assert!(warnings("(print 'a 'b 'c)")?.is_empty());
+
+            // calls to locals and to unknown globals are not checked
+            assert!(warnings("(def f (pair-of) (pair-of 'a 'b 'c))")?.is_empty());
+            assert!(warnings("(unknown 'a 'b 'c)")?.is_empty());
+
+            // too many arguments is an error if asked for, too few still a warning
+            match compile_with("(pair-of 'a 'b 'c)", ArityChecks::Error) {
+                Err(e) => match e.error_kind() {
+                    ErrorKind::EvalError(reason) => {
+                        assert!(reason == "pair-of takes 2 arguments, 3 given")
+                    }
+                    _ => panic!("expected an evaluation error"),
+                },
+                Ok(_) => panic!("expected an arity error"),
+            }
+            assert!(compile_with("(pair-of 'a)", ArityChecks::Error)?.1.len() == 1);
+
+            // checks are off by default
+            assert!(compile_with("(pair-of 'a 'b 'c)", ArityChecks::Off)?
+                .1
+                .is_empty());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_keyword_arguments() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            eval_helper(
+                mem,
+                t,
+                "(def triple (a b c) (cons a (cons b (cons c nil))))",
+            )?;
+            let abc = "(a b c)";
+
+            // without the Thread's globals to look in, arguments are bound at runtime
+            let result = eval_helper(mem, t, "(triple :c 'c :a 'a :b 'b)")?;
+            assert!(format!("{}", result) == abc);
+            let result = eval_helper(mem, t, "(triple 'a :c 'c :b 'b)")?;
+            assert!(format!("{}", result) == abc);
+
+            // a function in a local variable, a closure and a partial application
+            eval_helper(mem, t, "(def call-with (f) (f :b 'b :c 'c :a 'a))")?;
+            assert!(format!("{}", eval_helper(mem, t, "(call-with triple)")?) == abc);
+            let result = eval_helper(
+                mem,
+                t,
+                "(let ((x 'x)) (call-with (lambda (c a b) (cons x (cons a (cons b c))))))",
+            )?;
+            assert!(format!("{}", result) == "(x a b . c)");
+            assert!(format!("{}", eval_helper(mem, t, "((triple 'a) :c 'c :b 'b)")?) == abc);
+
+            // leaving off trailing parameters makes a partial application
+            let result = eval_helper(mem, t, "((triple :b 'b :a 'a) 'c)")?;
+            assert!(format!("{}", result) == abc);
+
+            // keywords evaluate to themselves and are passed as values when quoted
+            assert!(eval_helper(mem, t, ":k")? == mem.lookup_sym(":k"));
+            let result = eval_helper(mem, t, "(triple ':k :c :c :b ':b)")?;
+            assert!(format!("{}", result) == "(:k :b :c)");
+
+            // with the globals known, arguments are reordered when compiled and the function is
+            // called directly while the global is still bound to it
+            let context = CompileContext::for_thread(mem, CompileOptions::default(), &t);
+            let code = "(triple :c 'c 'x :b 'b)";
+            assert!(compile_toplevel_in_context(mem, &[parse(mem, code)?], None, context).is_err());
+            let context = CompileContext::for_thread(mem, CompileOptions::default(), &t);
+            let code = "(triple :c 'c :a 'a :b 'b)";
+            let (function, _) =
+                compile_toplevel_in_context(mem, &[parse(mem, code)?], None, context)?;
+            assert!(function
+                .code(mem)
+                .opcodes(mem)
+                .iter()
+                .any(|op| matches!(op, Opcode::IsIdentical { .. })));
+            assert!(format!("{}", t.quick_vm_eval(mem, function)?) == abc);
+
+            // a caller compiled against a global keeps passing arguments by name once the global
+            // is redefined with its parameters in another order
+            let define = |code| {
+                let context = CompileContext::for_thread(mem, CompileOptions::default(), &t);
+                let function =
+                    compile_toplevel_in_context(mem, &[parse(mem, code)?], None, context)?.0;
+                t.quick_vm_eval(mem, function)
+            };
+            define("(def f (a b) (cons a b))")?;
+            define("(def g () (f :b 'one :a 'two))")?;
+            assert!(format!("{}", define("(g)")?) == "(two . one)");
+            define("(def f (b a) (cons a b))")?;
+            assert!(format!("{}", define("(g)")?) == "(two . one)");
+            define("(def f (b a c) (cons a (cons b c)))")?;
+            assert!(format!("{}", define("((g) 'three)")?) == "(two one . three)");
+            define("(def f (b c) c)")?;
+            assert!(define("(g)").is_err());
+
+            for code in &[
+                "(triple :d 'd)",
+                "(triple :b 'b)",
+                "(triple :a 'a :a 'a)",
+                "(triple 'a :a 'a)",
+                "(triple 'a 'b 'c 'd :a 'a)",
+                "(call-with symbol->string)",
+                "(let ((:k 'x)) :k)",
+                "(def f (:k) :k)",
+                "(set ':k 'x)",
+            ] {
+                assert!(eval_helper(mem, t, code).is_err());
+            }
+
+            // a keyword must be followed by an argument, and may only be followed by more
+            // named arguments
+            assert!(compile(mem, parse(mem, "(triple :a)")?).is_err());
+            assert!(compile(mem, parse(mem, "(triple :a 'a 'b)")?).is_err());
+
+            // the keywords and arguments passed to bind-keywords must fit a call's argument count
+            let named = (0..127)
+                .map(|n| format!(":k{} {}", n, n))
+                .collect::<Vec<_>>()
+                .join(" ");
+            match compile(mem, parse(mem, &format!("(triple {})", named))?) {
+                Err(e) => match e.error_kind() {
+                    ErrorKind::EvalError(reason) => assert!(
+                        reason
+                            == "Too many arguments, a call with keyword arguments can pass at \
+                                most 253 arguments and keywords"
+                    ),
+                    _ => panic!("expected an EvalError"),
+                },
+                Ok(_) => panic!("expected an error"),
+            }
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_function_with_simple_let() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // this test compiles a let expression that deconstructs and reconstructs a pair list
+            let a_fn = "(def deconrecon (list) (let ((a (car list)) (b (cdr list))) (cons a b)))";
+            let query = "(deconrecon '(x y z z y))";
+
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, a_fn)?;
+
+            let result = eval_helper(mem, t, query)?;
+
+            let result = vec_from_pairs(mem, result)?;
+            let sym_x = mem.lookup_sym("x");
+            let sym_y = mem.lookup_sym("y");
+            let sym_z = mem.lookup_sym("z");
+            assert!(result == &[sym_x, sym_y, sym_z, sym_z, sym_y]);
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_function_with_lambda_with_nonlocal_ref() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // this test compiles a function containing a lambda that references a nonlocal
+            let head_fn = "(def head (a) (let ((inner (\\ () (car a)))) (inner)))";
+            let query = "(head '(x y z z y))";
+
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, head_fn)?;
+
+            let result = eval_helper(mem, t, query)?;
+            assert!(result == mem.lookup_sym("x"));
+
+            Ok(())
         }
 
-        let test = Test {};
-        mem.mutate(&test, test_fn).unwrap();
+        test_helper(test_inner);
     }
 
     #[test]
-    fn compile_cond_first_is_true() {
+    fn compile_function_returning_lambda_with_nonlocal_ref() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // testing 'cond'
-            // (nil? nil) == true, so result should be x
-            let code = "(cond (nil? nil) 'x (nil? 'a) 'y)";
+            // this test compiles a function that returns a lambda that references a nonlocal
+            let head_fn = "(def head (a) (let ((inner (\\ () (car a)))) inner))";
+            let inner_fn = "(set 'inner (head '(x y z z y)))";
+            let query = "(inner)";
 
             let t = Thread::alloc(mem)?;
 
-            let result = eval_helper(mem, t, code)?;
+            eval_helper(mem, t, head_fn)?;
+            eval_helper(mem, t, inner_fn)?;
 
+            let result = eval_helper(mem, t, query)?;
             assert!(result == mem.lookup_sym("x"));
 
             Ok(())
@@ -890,17 +3155,15 @@ mod integration {
     }
 
     #[test]
-    fn compile_cond_second_is_true() {
+    fn compile_let_with_lambda_with_nested_call() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // testing 'cond'
-            // (nil? 'a) == nil, (nil? nil) == true, so result should be y
-            let code = "(cond (nil? 'a) 'x (nil? nil) 'y)";
+            // this test compiles a let containing a lambda that is referenced in a sub-let scope
+            let f = "(let ((f (\\ (a) a))) (let ((g (f 'b))) g))";
 
             let t = Thread::alloc(mem)?;
 
-            let result = eval_helper(mem, t, code)?;
-
-            assert!(result == mem.lookup_sym("y"));
+            let result = eval_helper(mem, t, f)?;
+            assert!(result == mem.lookup_sym("b"));
 
             Ok(())
         }
@@ -909,17 +3172,25 @@ mod integration {
     }
 
     #[test]
-    fn compile_cond_none_is_true() {
+    fn compile_bitwise_ops() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // testing 'cond'
-            // (nil? 'a) == nil, (nil? 'b) == nil, result should be nil
-            let code = "(cond (nil? 'a) 'x (nil? 'b) 'y)";
-
+            // this test compiles each of the bitwise operations on inline integers
             let t = Thread::alloc(mem)?;
 
-            let result = eval_helper(mem, t, code)?;
+            let result = eval_helper(mem, t, "(bit-and 12 10)")?;
+            assert!(result == mem.number(8));
 
-            assert!(result == mem.nil());
+            let result = eval_helper(mem, t, "(bit-or 12 10)")?;
+            assert!(result == mem.number(14));
+
+            let result = eval_helper(mem, t, "(bit-xor 12 10)")?;
+            assert!(result == mem.number(6));
+
+            let result = eval_helper(mem, t, "(shl 3 4)")?;
+            assert!(result == mem.number(48));
+
+            let result = eval_helper(mem, t, "(shr -64 3)")?;
+            assert!(result == mem.number(-8));
 
             Ok(())
         }
@@ -928,24 +3199,51 @@ mod integration {
     }
 
     #[test]
-    fn compile_call_functions() {
+    fn compile_list_length_and_reverse() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // this test calls a function from another function
-            let compare_fn = "(def is_it (ask expect) (is? ask expect))";
-            let curried_fn = "(def is_it_a (ask) (is_it ask 'a))";
-            let query1 = "(is_it_a nil)";
-            let query2 = "(is_it_a 'a)";
-
+            // this test compiles length and reverse of each kind of list
             let t = Thread::alloc(mem)?;
+            let eval = |code| -> Result<String, RuntimeError> {
+                Ok(format!("{}", eval_helper(mem, t, code)?))
+            };
+
+            assert!(eval("(length nil)")? == "0");
+            assert!(eval("(length '(a b c))")? == "3");
+            assert!(eval("(reverse nil)")? == "nil");
+            assert!(eval("(reverse '(a (b c) d))")? == "(d (b c) a)");
+
+            assert!(eval("(length (plist 'a 'b))")? == "2");
+            assert!(eval("(length (pmap 'a 1 'b 2 'c 3))")? == "3");
+            assert!(eval("(reverse (plist 'a 'b))")? == "(plist b a)");
+
+            let list = List::alloc(mem)?;
+            StackAnyContainer::push(&*list, mem, mem.lookup_sym("a"))?;
+            StackAnyContainer::push(&*list, mem, mem.number(2))?;
+            t.set_global(mem, "xs", list.as_tagged(mem))?;
+            assert!(eval("(length xs)")? == "2");
+            assert!(eval("(reverse xs)")? == "[2, a]");
+            // the List is copied
+            assert!(eval("(is? xs (reverse (reverse xs)))")? == "nil");
+
+            assert!(eval("(length '(a . b))").is_err());
+            assert!(eval("(length 'a)").is_err());
+            assert!(eval("(reverse (pmap 'a 1))").is_err());
 
-            eval_helper(mem, t, compare_fn)?;
-            eval_helper(mem, t, curried_fn)?;
+            Ok(())
+        }
 
-            let result1 = eval_helper(mem, t, query1)?;
-            assert!(result1 == mem.nil());
+        test_helper(test_inner);
+    }
 
-            let result2 = eval_helper(mem, t, query2)?;
-            assert!(result2 == mem.lookup_sym("true"));
+    #[test]
+    fn compile_bitwise_ops_bad_operands() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // this test checks that non-integer operands and out of range shifts are errors
+            let t = Thread::alloc(mem)?;
+
+            assert!(eval_helper(mem, t, "(bit-and 'a 1)").is_err());
+            assert!(eval_helper(mem, t, "(shl 1 -1)").is_err());
+            assert!(eval_helper(mem, t, "(shr 1 62)").is_err());
 
             Ok(())
         }
@@ -954,26 +3252,74 @@ mod integration {
     }
 
     #[test]
-    fn compile_map_function_over_list() {
+    fn compile_rejects_too_deeply_nested_functions() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // this test passes a function as a parameter through recursive function calls
-            let compare_fn = "(def is_y (ask) (is? ask 'y))";
-            let map_fn =
-                "(def map (f l) (cond (nil? l) nil true (cons (f (car l)) (map f (cdr l)))))";
+            // build (lambda (x) (lambda (y) ... x)) with the given number of nested functions
+            let nested = |depth| {
+                let mut code = String::from("(lambda (x) ");
+                for _ in 1..depth {
+                    code.push_str("(lambda (y) ");
+                }
+                code.push('x');
+                code.push_str(&")".repeat(depth));
+                code
+            };
+
+            // the innermost function refers to x in a frame 255 levels out
+            assert!(compile(mem, parse(mem, &nested(256))?).is_ok());
+
+            match compile(mem, parse(mem, &nested(257))?) {
+                Err(e) => match e.error_kind() {
+                    ErrorKind::EvalError(reason) => assert!(
+                        reason == "Functions are too deeply nested, a maximum of 256 levels is supported"
+                    ),
+                    _ => panic!("expected an EvalError"),
+                },
+                Ok(_) => panic!("expected an error"),
+            }
 
-            let query = "(map is_y '(x y z z y))";
+            Ok(())
+        }
+
+        // compiling recurses for each level of nesting, more deeply than a test thread's
+        // default stack allows in a debug build
+        std::thread::Builder::new()
+            .stack_size(64 * 1024 * 1024)
+            .spawn(|| test_helper(test_inner))
+            .unwrap()
+            .join()
+            .unwrap();
+    }
 
+    #[test]
+    fn compile_toplevel_forms() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
             let t = Thread::alloc(mem)?;
 
-            eval_helper(mem, t, compare_fn)?;
-            eval_helper(mem, t, map_fn)?;
+            let eval_forms = |code| -> Result<TaggedScopedPtr, RuntimeError> {
+                let mut parser = Parser::new(lex_reader(Cursor::new(String::from(code))));
+                let mut forms = Vec::new();
+                while let Some(form) = parser.next_expr(mem)? {
+                    forms.push(form);
+                }
 
-            let result = eval_helper(mem, t, query)?;
+                let (function, _) = compile_toplevel(mem, &forms, Some("forms.lisp"))?;
+                t.quick_vm_eval(mem, function)
+            };
 
-            let result = vec_from_pairs(mem, result)?;
-            let sym_nil = mem.nil();
-            let sym_true = mem.lookup_sym("true");
-            assert!(result == &[sym_nil, sym_true, sym_nil, sym_nil, sym_true]);
+            // a leading string is a form, not documentation
+            let result = eval_forms("\"first\" (def f (x) (cons x x)) (f 'a)")?;
+            assert!(format!("{}", result) == "(a . a)");
+
+            let result = eval_forms("(def g () 'b)")?;
+            assert!(format!("{}", result) == "(Function g ())");
+            assert!(t.lookup_global(mem, "g").is_some());
+
+            assert!(eval_forms("")? == mem.nil());
+
+            // registers are reused from one form to the next
+            let forms = "(cons (cons 'a 'b) (cons 'c 'd)) ".repeat(300);
+            assert!(format!("{}", eval_forms(&forms)?) == "((a . b) c . d)");
 
             Ok(())
         }
@@ -982,23 +3328,63 @@ mod integration {
     }
 
     #[test]
-    fn compile_eval_nested_partials() {
+    fn compile_warns_about_shadowing() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // this test evaluates nested Partial applications in function position
-            let a_fn = "(def isit (a b) (is? a b))";
+            let warnings = |code| -> Result<Vec<Diagnostic>, RuntimeError> {
+                Ok(compile_with_diagnostics(mem, parse(mem, code)?)?.1)
+            };
 
-            let query1 = "((isit 'x) 'x)";
-            let query2 = "((isit 'x) 'y)";
+            assert!(warnings("(def f (a) (let ((b a)) b))")?.is_empty());
 
-            let t = Thread::alloc(mem)?;
+            // the outer a is also reported as unused as every reference finds the inner binding
+            let found = warnings("(def f (a)\n  (let ((a 'x)) a))")?;
+            assert!(found.len() == 2);
+            assert!(found[0].message() == "Binding of a shadows a variable in an enclosing scope");
+            assert!(found[1].message() == "Parameter a is never used");
+            let pos = found[0].pos().unwrap();
+            assert!(pos.line == 2 && pos.column == 3);
 
-            eval_helper(mem, t, a_fn)?;
+            // parameters of a nested function are checked against the enclosing function
+            let found = warnings("(def f (a) (\\ (a) a))")?;
+            assert!(found[0].message() == "Binding of a shadows a variable in an enclosing scope");
 
-            let result = eval_helper(mem, t, query1)?;
-            assert!(result == mem.lookup_sym("true"));
+            Ok(())
+        }
 
-            let result = eval_helper(mem, t, query2)?;
-            assert!(result == mem.nil());
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn compile_warns_about_unused_variables() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let warnings = |code| -> Result<Vec<String>, RuntimeError> {
+                let found = compile_with_diagnostics(mem, parse(mem, code)?)?.1;
+                Ok(found.iter().map(|d| String::from(d.message())).collect())
+            };
+
+            assert!(warnings("(def f (a b) (cons a b))")?.is_empty());
+            assert!(warnings("(def f (x) (let ((y x)) y))")?.is_empty());
+
+            assert!(
+                warnings("(def f (a b c) b)")?
+                    == vec!["Parameter a is never used", "Parameter c is never used"]
+            );
+            assert!(
+                warnings("(def f () (let ((a 'x) (b 'y)) b))")? == vec!["Variable a is never used"]
+            );
+
+            // a reference from a nested function counts as a use
+            assert!(warnings("(def f (a) (\\ () a))")?.is_empty());
+
+            // the warnings of a nested function are collected with those of the function it is
+            // in, in the order they are found
+            assert!(
+                warnings("(def f (a) (\\ (b) 'x))")?
+                    == vec!["Parameter b is never used", "Parameter a is never used"]
+            );
+
+            // names beginning with an underscore are intentionally unused
+            assert!(warnings("(def f (_a) (let ((_b 'x)) 'y))")?.is_empty());
 
             Ok(())
         }
@@ -1007,68 +3393,229 @@ mod integration {
     }
 
     #[test]
-    fn compile_pass_partial_as_param() {
-        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // this test passes a Partial as an argument of another function that will call it
-            // with it's last argument.
-            let isit_fn = "(def isit (a b) (is? a b))";
-            let map_fn = "(def map (f v) (f v))";
+    fn compile_eval_with_poisoned_storage() {
+        // this test runs a program that calls functions and closures, growing the register stack
+        // and so abandoning its old storage, with poisoning enabled by the environment variable.
+        // Other tests that start meanwhile may also poison, which is harmless.
+        env::set_var(POISON_STORAGE_ENV_VAR, "1");
+        let mem = Memory::new();
+        env::remove_var(POISON_STORAGE_ENV_VAR);
 
-            let query1 = "(map (isit 'x) 'x)";
-            let query2 = "(map (isit 'x) 'y)";
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
 
-            let t = Thread::alloc(mem)?;
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                assert!(mem.poisons_storage());
+                let t = Thread::alloc(mem)?;
 
-            eval_helper(mem, t, isit_fn)?;
-            eval_helper(mem, t, map_fn)?;
+                let head_fn = "(def head (a) (let ((inner (\\ () (car a)))) inner))";
+                eval_helper(mem, t, head_fn)?;
+                eval_helper(mem, t, "(set 'inner (head '(x y z)))")?;
 
-            let result = eval_helper(mem, t, query1)?;
-            assert!(result == mem.lookup_sym("true"));
+                let map_fn =
+                    "(def map (f l) (cond (nil? l) nil true (cons (f (car l)) (map f (cdr l)))))";
+                eval_helper(mem, t, map_fn)?;
 
-            let result = eval_helper(mem, t, query2)?;
-            assert!(result == mem.nil());
+                let result = eval_helper(mem, t, "(map (\\ (x) (cons x x)) '(a b c d e f))")?;
+                assert!(
+                    format!("{}", result) == "((a . a) (b . b) (c . c) (d . d) (e . e) (f . f))"
+                );
 
-            Ok(())
+                let result = eval_helper(mem, t, "(inner)")?;
+                assert!(result == mem.lookup_sym("x"));
+
+                Ok(())
+            }
         }
 
-        test_helper(test_inner);
+        mem.mutate(&Test {}, ()).unwrap();
     }
 
+    // Time compiling deeply nested lets, where each variable reference searches the enclosing
+    // scopes. This is a timing report rather than a test:
+    // `cargo test --release scope_lookup_timing -- --ignored --nocapture`
     #[test]
-    fn compile_simple_let() {
+    #[ignore]
+    fn scope_lookup_timing() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // this test compiles a basic let expression
-            let expr = "(let ((x 'y)) x)";
+            const DEPTH: usize = 60;
+            const COUNT: usize = 1_000;
+
+            // each let binds two names from those of the let around it and the outermost
+            let mut code = String::from("(let ((a0 'x) (b0 'y)) ");
+            for level in 1..DEPTH {
+                code.push_str(&format!(
+                    "(let ((a{} (cons a{} b0)) (b{} (cons b{} a0))) ",
+                    level,
+                    level - 1,
+                    level,
+                    level - 1
+                ));
+            }
+            code.push_str(&format!("(cons a{} b{})", DEPTH - 1, DEPTH - 1));
+            code.push_str(&")".repeat(DEPTH));
 
-            let t = Thread::alloc(mem)?;
+            let ast = parse(mem, &code)?;
 
-            let result = eval_helper(mem, t, expr)?;
-            assert!(result == mem.lookup_sym("y"));
+            let start = std::time::Instant::now();
+            for _ in 0..COUNT {
+                compile(mem, ast)?;
+            }
+            let elapsed = start.elapsed();
+
+            println!(
+                "{} nested lets: {:.2}us per compile",
+                DEPTH,
+                elapsed.as_micros() as f64 / COUNT as f64
+            );
 
             Ok(())
         }
 
         test_helper(test_inner);
     }
+}
+
+/// Tests of the instruction sequences the compiler emits
+#[cfg(test)]
+mod codegen {
+    use super::*;
+    use crate::bytecode::Opcode::*;
+    use crate::error::ErrorKind;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    /// Compile the code without optimization and return the instructions of the top level
+    /// function
+    fn compile_helper(mem: &MutatorView, code: &str) -> Result<Vec<Opcode>, RuntimeError> {
+        Ok(compile_bytecode(mem, code)?.opcodes(mem))
+    }
+
+    /// Compile the code without optimization and return the ByteCode of the top level function
+    fn compile_bytecode<'guard>(
+        mem: &'guard MutatorView,
+        code: &str,
+    ) -> Result<ScopedPtr<'guard, ByteCode>, RuntimeError> {
+        compile_ast(mem, parse(mem, code)?, OptLevel::O0)
+    }
+
+    /// Compile an expression at the given optimization level and return the ByteCode of the top
+    /// level function
+    fn compile_ast<'guard>(
+        mem: &'guard MutatorView,
+        ast: TaggedScopedPtr<'guard>,
+        opt_level: OptLevel,
+    ) -> Result<ScopedPtr<'guard, ByteCode>, RuntimeError> {
+        let options = CompileOptions {
+            opt_level,
+            ..CompileOptions::default()
+        };
+        let context = CompileContext::new(options);
+        let function = compile_toplevel_in_context(mem, &[ast], None, context)?.0;
+        Ok(function.code(mem))
+    }
+
+    /// Return the printed value of each literal loaded by the instructions, in order
+    fn literals(
+        mem: &MutatorView,
+        code: ScopedPtr<'_, ByteCode>,
+    ) -> Result<Vec<String>, RuntimeError> {
+        let mut literals = Vec::new();
+        for opcode in code.opcodes(mem) {
+            if let LoadLiteral { literal_id, .. } = opcode {
+                literals.push(format!("{}", code.literal(mem, literal_id)?));
+            }
+        }
+        Ok(literals)
+    }
+
+    fn test_helper(test_fn: fn(&MutatorView) -> Result<(), RuntimeError>) {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = fn(&MutatorView) -> Result<(), RuntimeError>;
+            type Output = ();
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                test_fn: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                test_fn(mem)
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, test_fn).unwrap();
+    }
 
     #[test]
-    fn compile_function_with_simple_let() {
+    fn codegen_cond() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // this test compiles a let expression that deconstructs and reconstructs a pair list
-            let a_fn = "(def deconrecon (list) (let ((a (car list)) (b (cdr list))) (cons a b)))";
-            let query = "(deconrecon '(x y z z y))";
+            let bytecode = compile_bytecode(mem, "(cond (nil? x) 'a (atom? y) 'b)")?;
+            let code = bytecode.opcodes(mem);
+
+            // each arm jumps to the next test if not taken, otherwise past the default nil
+            assert_eq!(
+                code,
+                vec![
+                    LoadLiteral {
+                        dest: 3,
+                        literal_id: 0
+                    },
+                    LoadGlobal { dest: 3, name: 3 },
+                    IsNil { dest: 2, test: 3 },
+                    JumpIfNotTrue { test: 2, offset: 2 },
+                    LoadLiteral {
+                        dest: 2,
+                        literal_id: 1
+                    },
+                    Jump { offset: 7 },
+                    LoadLiteral {
+                        dest: 3,
+                        literal_id: 2
+                    },
+                    LoadGlobal { dest: 3, name: 3 },
+                    IsAtom { dest: 2, test: 3 },
+                    JumpIfNotTrue { test: 2, offset: 3 },
+                    LoadLiteral {
+                        dest: 2,
+                        literal_id: 3
+                    },
+                    Jump { offset: 1 },
+                    LoadNil { dest: 2 },
+                    Return { reg: 2 },
+                ]
+            );
+            assert_eq!(literals(mem, bytecode)?, vec!["x", "a", "y", "b"]);
 
-            let t = Thread::alloc(mem)?;
+            Ok(())
+        }
 
-            eval_helper(mem, t, a_fn)?;
+        test_helper(test_inner);
+    }
 
-            let result = eval_helper(mem, t, query)?;
+    #[test]
+    fn codegen_rejects_long_jumps() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let cond = |arms| format!("(cond {})", "(nil? x) 'a ".repeat(arms));
 
-            let result = vec_from_pairs(mem, result)?;
-            let sym_x = mem.lookup_sym("x");
-            let sym_y = mem.lookup_sym("y");
-            let sym_z = mem.lookup_sym("z");
-            assert!(result == &[sym_x, sym_y, sym_z, sym_z, sym_y]);
+            // the jumps from the end of the first arms cross the whole cond
+            assert!(compile_helper(mem, &cond(1000)).is_ok());
+
+            match compile_helper(mem, &cond(10000)) {
+                Err(e) => match e.error_kind() {
+                    ErrorKind::EvalError(reason) => {
+                        assert!(reason.starts_with("A jump over"));
+                        assert!(reason.ends_with("instructions is too long, the maximum is 32767"));
+                    }
+                    _ => panic!("expected an EvalError"),
+                },
+                Ok(_) => panic!("expected an error"),
+            }
 
             Ok(())
         }
@@ -1077,18 +3624,82 @@ mod integration {
     }
 
     #[test]
-    fn compile_function_with_lambda_with_nonlocal_ref() {
+    fn codegen_let() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // this test compiles a function containing a lambda that references a nonlocal
-            let head_fn = "(def head (a) (let ((inner (\\ () (car a)))) (inner)))";
-            let query = "(head '(x y z z y))";
+            let bytecode = compile_bytecode(mem, "(let ((a 'x) (b a)) (cons a b))")?;
+            let code = bytecode.opcodes(mem);
+
+            // bindings are allocated registers in order and the body result is copied out
+            assert_eq!(
+                code,
+                vec![
+                    LoadLiteral {
+                        dest: 5,
+                        literal_id: 0
+                    },
+                    CopyRegister { dest: 3, src: 5 },
+                    CopyRegister { dest: 4, src: 3 },
+                    MakePair {
+                        dest: 5,
+                        reg1: 3,
+                        reg2: 4
+                    },
+                    CopyRegister { dest: 2, src: 5 },
+                    Return { reg: 2 },
+                ]
+            );
+            assert_eq!(literals(mem, bytecode)?, vec!["x"]);
 
-            let t = Thread::alloc(mem)?;
+            Ok(())
+        }
 
-            eval_helper(mem, t, head_fn)?;
+        test_helper(test_inner);
+    }
 
-            let result = eval_helper(mem, t, query)?;
-            assert!(result == mem.lookup_sym("x"));
+    #[test]
+    fn codegen_constants() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            // true and nil are loaded by their own instructions, quoted or not
+            let bytecode = compile_bytecode(mem, "(cons true 'true)")?;
+            let code = bytecode.opcodes(mem);
+            assert_eq!(
+                code,
+                vec![
+                    LoadTrue { dest: 3 },
+                    LoadTrue { dest: 4 },
+                    MakePair {
+                        dest: 2,
+                        reg1: 3,
+                        reg2: 4
+                    },
+                    Return { reg: 2 },
+                ]
+            );
+            assert!(literals(mem, bytecode)?.is_empty());
+
+            // the symbol nil, which the parser never produces, is the nil value too
+            let nil_sym = cons(mem, mem.sym_nil(), mem.nil())?;
+            let ast = cons(mem, mem.lookup_sym("atom?"), nil_sym)?;
+            let code = compile_ast(mem, ast, OptLevel::O0)?.opcodes(mem);
+            assert!(code[0] == LoadNil { dest: 3 });
+
+            // neither can be bound, locally or globally
+            for code in &[
+                "(let ((true 'x)) true)",
+                "(def f (true) 'x)",
+                "(lambda (a nil) a)",
+                "(def true () 'x)",
+                "(set 'true 'x)",
+                "(set 'nil 'x)",
+            ] {
+                match compile_helper(mem, code) {
+                    Err(e) => match e.error_kind() {
+                        ErrorKind::EvalError(reason) => assert!(reason.starts_with("Cannot bind")),
+                        _ => panic!("expected an evaluation error"),
+                    },
+                    Ok(_) => panic!("expected {} not to compile", code),
+                }
+            }
 
             Ok(())
         }
@@ -1097,20 +3708,97 @@ mod integration {
     }
 
     #[test]
-    fn compile_function_returning_lambda_with_nonlocal_ref() {
+    fn codegen_optimization_levels() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // this test compiles a function that returns a lambda that references a nonlocal
-            let head_fn = "(def head (a) (let ((inner (\\ () (car a)))) inner))";
-            let inner_fn = "(set 'inner (head '(x y z z y)))";
-            let query = "(inner)";
+            let compile_at = |code, opt_level| compile_ast(mem, parse(mem, code)?, opt_level);
+            let opcodes_at = |code, opt_level| -> Result<Vec<Opcode>, RuntimeError> {
+                Ok(compile_at(code, opt_level)?.opcodes(mem))
+            };
+
+            // pure operations on constants are folded into their result
+            let code = "(abs (min -3 2 (bit-and 12 10)))";
+            assert!(opcodes_at(code, OptLevel::O0)?.len() == 9);
+            let folded = compile_at(code, OptLevel::O1)?;
+            assert!(matches!(
+                folded.opcodes(mem)[..],
+                [LoadLiteral { dest: 2, .. }, Return { reg: 2 }]
+            ));
+            assert!(literals(mem, folded)? == vec!["3"]);
+
+            let folded = opcodes_at("(cons (nil? '()) (atom? '(a)))", OptLevel::O1)?;
+            assert!(folded[..2] == [LoadTrue { dest: 3 }, LoadNil { dest: 4 }]);
+
+            // operations on variables, or that would fail, are left for when the code is run
+            assert!(opcodes_at("(min 1 x)", OptLevel::O1)?.contains(&Min {
+                dest: 2,
+                reg1: 3,
+                reg2: 4
+            }));
+            assert!(opcodes_at("(abs 'a)", OptLevel::O1)?.contains(&Abs { dest: 2, reg: 3 }));
+
+            // cond arms whose conditions are constant are only left out when optimizing
+            let code = "(cond nil 'a 'x 'b (nil? y) 'c)";
+            let jumps = |code: Vec<Opcode>| {
+                code.iter()
+                    .filter(|op| matches!(op, JumpIfNotTrue { .. }))
+                    .count()
+            };
+            assert!(jumps(opcodes_at(code, OptLevel::O0)?) == 3);
+            assert!(jumps(opcodes_at(code, OptLevel::O1)?) == 1);
 
-            let t = Thread::alloc(mem)?;
+            Ok(())
+        }
 
-            eval_helper(mem, t, head_fn)?;
-            eval_helper(mem, t, inner_fn)?;
+        test_helper(test_inner);
+    }
 
-            let result = eval_helper(mem, t, query)?;
-            assert!(result == mem.lookup_sym("x"));
+    #[test]
+    fn codegen_min_max_fold() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let code = compile_helper(mem, "(max 3 1 2)")?;
+
+            // the result register accumulates the fold
+            assert_eq!(
+                code,
+                vec![
+                    LoadLiteral {
+                        dest: 3,
+                        literal_id: 0
+                    },
+                    LoadLiteral {
+                        dest: 4,
+                        literal_id: 1
+                    },
+                    Max {
+                        dest: 2,
+                        reg1: 3,
+                        reg2: 4
+                    },
+                    LoadLiteral {
+                        dest: 5,
+                        literal_id: 2
+                    },
+                    Max {
+                        dest: 2,
+                        reg1: 2,
+                        reg2: 5
+                    },
+                    Return { reg: 2 },
+                ]
+            );
+
+            // a single argument is compared with itself
+            let code = compile_helper(mem, "(min 3)")?;
+            assert_eq!(
+                code[1],
+                Min {
+                    dest: 2,
+                    reg1: 3,
+                    reg2: 3
+                }
+            );
+
+            assert!(compile_helper(mem, "(min)").is_err());
 
             Ok(())
         }
@@ -1119,15 +3807,93 @@ mod integration {
     }
 
     #[test]
-    fn compile_let_with_lambda_with_nested_call() {
+    fn codegen_call() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
-            // this test compiles a let containing a lambda that is referenced in a sub-let scope
-            let f = "(let ((f (\\ (a) a))) (let ((g (f 'b))) g))";
+            let bytecode = compile_bytecode(mem, "(f 'a (g 'b))")?;
+            let code = bytecode.opcodes(mem);
+
+            // arguments are evaluated into the registers following the result register, the
+            // function last of all
+            assert_eq!(
+                code,
+                vec![
+                    LoadLiteral {
+                        dest: 4,
+                        literal_id: 0
+                    },
+                    LoadLiteral {
+                        dest: 7,
+                        literal_id: 1
+                    },
+                    LoadLiteral {
+                        dest: 8,
+                        literal_id: 2
+                    },
+                    LoadGlobal { dest: 8, name: 8 },
+                    Call {
+                        function: 8,
+                        dest: 5,
+                        arg_count: 1
+                    },
+                    LoadLiteral {
+                        dest: 6,
+                        literal_id: 3
+                    },
+                    LoadGlobal { dest: 6, name: 6 },
+                    Call {
+                        function: 6,
+                        dest: 2,
+                        arg_count: 2
+                    },
+                    Return { reg: 2 },
+                ]
+            );
+            assert_eq!(literals(mem, bytecode)?, vec!["a", "b", "g", "f"]);
 
-            let t = Thread::alloc(mem)?;
+            Ok(())
+        }
 
-            let result = eval_helper(mem, t, f)?;
-            assert!(result == mem.lookup_sym("b"));
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn codegen_function_definition() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let bytecode = compile_bytecode(mem, "(def f (x) (g x))")?;
+            let code = bytecode.opcodes(mem);
+
+            assert!(matches!(
+                code[..],
+                [
+                    LoadLiteral { .. },
+                    LoadLiteral { .. },
+                    StoreGlobal { .. },
+                    Return { .. }
+                ]
+            ));
+            assert_eq!(literals(mem, bytecode)?, vec!["f", "(Function f (x))"]);
+
+            // the function is compiled separately and its body refers only to its own registers
+            let function = match code[1] {
+                LoadLiteral { literal_id, .. } => match *bytecode.literal(mem, literal_id)? {
+                    Value::Function(function) => function,
+                    _ => panic!("expected a Function literal"),
+                },
+                _ => unreachable!(),
+            };
+            let body = function.code(mem).opcodes(mem);
+
+            assert!(matches!(
+                body[..],
+                [
+                    CopyRegister { src: 2, .. },
+                    LoadLiteral { .. },
+                    LoadGlobal { .. },
+                    Call { arg_count: 1, .. },
+                    Return { .. }
+                ]
+            ));
+            assert_eq!(literals(mem, function.code(mem))?, vec!["g"]);
 
             Ok(())
         }
@@ -0,0 +1,296 @@
+/// A per-Memory pool of literal values referenced by compiled code.
+///
+/// Each ByteCode keeps a list of the literals its `LoadLiteral` instructions refer to by index, but
+/// before a literal is added to the list it is interned here. Text literals are immutable and are
+/// shared by content, as are quoted lists of hashable values: every `'(a "b" 1)` in compiled code
+/// is the same object.
+///
+/// Like the root table, the pool is part of the root set and nothing in it is ever released, so
+/// only values that can be shared by content are kept. The pool grows with the number of distinct
+/// Text and list literals compiled, not with the number of times code is compiled: a REPL or
+/// server evaluating the same source over and over adds nothing after the first time. Symbols are
+/// already interned and immediate values are not on the heap, so neither is pooled. Any other
+/// literal, a boxed number or a list that cannot be hashed, is returned as it is and is kept alive
+/// only by the literal list of the ByteCode that loads it.
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::hashable::{structural_hash, structurally_equal};
+use crate::safeptr::{MutatorScope, TaggedScopedPtr};
+use crate::taggedptr::{TaggedPtr, Value};
+
+/// The constant pool belonging to a `Memory` instance
+pub(crate) struct ConstantPool {
+    /// Constant values, in the order they were added
+    values: RefCell<Vec<TaggedPtr>>,
+    /// Index of constants by pointer identity
+    by_identity: RefCell<HashSet<TaggedPtr>>,
+    /// Index of Text constants by content
+    by_text: RefCell<HashMap<String, TaggedPtr>>,
+    /// Index of Pair list constants by structural hash
+    by_structure: RefCell<HashMap<u64, Vec<TaggedPtr>>>,
+}
+
+impl ConstantPool {
+    pub(crate) fn new() -> ConstantPool {
+        ConstantPool {
+            values: RefCell::new(Vec::new()),
+            by_identity: RefCell::new(HashSet::new()),
+            by_text: RefCell::new(HashMap::new()),
+            by_structure: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Return an equivalent constant already in the pool, or add the value and return it. A value
+    /// that cannot be shared by content is returned without being added.
+    pub(crate) fn intern(&self, guard: &dyn MutatorScope, value: TaggedScopedPtr<'_>) -> TaggedPtr {
+        let ptr = value.get_ptr();
+
+        if self.by_identity.borrow().contains(&ptr) {
+            return ptr;
+        }
+
+        let text = match *value {
+            Value::Text(text) => Some(text.as_str(guard)),
+            _ => None,
+        };
+
+        if let Some(text) = text {
+            if let Some(existing) = self.by_text.borrow().get(text) {
+                return *existing;
+            }
+        }
+
+        let structure = match *value {
+            Value::Pair(_) => structural_hash(guard, value),
+            _ => None,
+        };
+
+        if let Some(hash) = structure {
+            if let Some(existing) = self.by_structure.borrow().get(&hash) {
+                for existing in existing {
+                    if structurally_equal(guard, TaggedScopedPtr::new(guard, *existing), value) {
+                        return *existing;
+                    }
+                }
+            }
+        }
+
+        if text.is_none() && structure.is_none() {
+            return ptr;
+        }
+
+        self.values.borrow_mut().push(ptr);
+        self.by_identity.borrow_mut().insert(ptr);
+        if let Some(text) = text {
+            self.by_text.borrow_mut().insert(String::from(text), ptr);
+        }
+        if let Some(hash) = structure {
+            self.by_structure
+                .borrow_mut()
+                .entry(hash)
+                .or_default()
+                .push(ptr);
+        }
+
+        ptr
+    }
+
+    /// Return the count of constants
+    pub(crate) fn len(&self) -> usize {
+        self.values.borrow().len()
+    }
+
+    /// Call the given function with every constant
+    pub(crate) fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(TaggedPtr),
+    {
+        for ptr in self.values.borrow().iter() {
+            f(*ptr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::builder::BytecodeBuilder;
+    use crate::bytecode::{LiteralId, Opcode};
+    use crate::compiler::compile;
+    use crate::error::{ErrorKind, RuntimeError};
+    use crate::memory::{Memory, Mutator, MutatorView};
+    use crate::parser::parse;
+    use crate::vm::Thread;
+
+    #[test]
+    fn constants_are_shared_between_functions() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                let a = mem.constant(mem.lookup_sym("a"));
+                assert!(mem.constant(mem.lookup_sym("a")) == a);
+                assert!(mem.constant(mem.text("a")?) != a);
+
+                let text = mem.constant(mem.text("hello")?);
+                assert!(mem.constant(mem.text("hello")?) == text);
+                assert!(format!("{}", text) == "\"hello\"");
+
+                let count = mem.constant_count();
+
+                // recompiling code that refers only to pooled constants adds nothing
+                eval("(cons 'a \"hello\")")?;
+                eval("(cons 'a \"hello\")")?;
+                assert!(mem.constant_count() == count);
+
+                // and the text literals of both functions are the same object
+                assert!(eval("(is? \"hello\" \"hello\")")? == mem.lookup_sym("true"));
+                assert!(format!("{}", eval("(car (cons 'a \"hello\"))")?) == "a");
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn quoted_lists_are_hash_consed() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                let list = mem.constant(parse(mem, "(a (b \"c\") 1)")?);
+                assert!(mem.constant(parse(mem, "(a (b \"c\") 1)")?) == list);
+                assert!(mem.constant(parse(mem, "(a (b \"c\") 2)")?) != list);
+                assert!(mem.constant(parse(mem, "(a (b c) 1)")?) != list);
+
+                // structurally identical quoted lists are the same object
+                assert!(eval("(is? '(x (y)) '(x (y)))")? == mem.lookup_sym("true"));
+                assert!(eval("(is? '(x (y)) '(x y))")? == mem.nil());
+
+                let count = mem.constant_count();
+                eval("(cons '(x (y)) '(a (b \"c\") 1))")?;
+                assert!(mem.constant_count() == count);
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn repeated_evaluation_does_not_grow_pool() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code: &str| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                let code = "(cons \"text\" (cons '(a \"b\" 1) (cons '(1.25 (c)) (cons 0.1 (cons 99999999999999999 'sym)))))";
+
+                eval(code)?;
+                let count = mem.constant_count();
+                assert!(count == 2);
+
+                for _ in 0..100 {
+                    eval(code)?;
+                }
+                assert!(mem.constant_count() == count);
+
+                // new symbols, integers and floats are not pooled
+                for n in 0..100 {
+                    eval(&format!(
+                        "(cons 'sym-{} (cons {} (cons {}.5 0.{})))",
+                        n, n, n, n
+                    ))?;
+                }
+                assert!(mem.constant_count() == count);
+
+                // each distinct text literal is kept once
+                for n in 0..100 {
+                    eval(&format!("\"text {}\"", n % 10))?;
+                }
+                assert!(mem.constant_count() == count + 10);
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn literal_limit_is_per_function() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                // fill the literals of one function, each number is a distinct constant
+                let mut builder = BytecodeBuilder::new(mem, &[])?;
+                let mut n = 0;
+                while n <= LiteralId::MAX as isize {
+                    assert!(builder.add_literal(mem.number(n))? == n as LiteralId);
+                    n += 1;
+                }
+
+                match builder.add_literal(mem.number(n)) {
+                    Err(e) => match e.error_kind() {
+                        ErrorKind::EvalError(reason) => assert!(
+                            reason == "A function can load at most 65536 different literals"
+                        ),
+                        _ => panic!("expected an EvalError"),
+                    },
+                    Ok(_) => panic!("expected an error"),
+                }
+
+                // literals already in the function can still be referred to
+                assert!(builder.add_literal(mem.number(0))? == 0);
+
+                // other functions can load new literals, and integers never enter the pool
+                let mut other = BytecodeBuilder::new(mem, &[])?;
+                let literal_id = other.add_literal(mem.number(n))?;
+                assert!(literal_id == 0);
+                other.emit_load_literal(2, literal_id)?;
+                other.emit(Opcode::Return { reg: 2 })?;
+                let function = other.finish("other")?;
+                assert!(mem.constant_count() == 0);
+
+                let t = Thread::alloc(mem)?;
+                assert!(t.quick_vm_eval(mem, function)? == mem.number(n));
+                assert!(
+                    t.quick_vm_eval(mem, compile(mem, parse(mem, "'never-seen-before")?)?)?
+                        == mem.lookup_sym("never-seen-before")
+                );
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -1,9 +1,7 @@
 /// Container traits
-///
-/// TODO iterators/views
 use stickyimmix::ArraySize;
 
-use crate::error::RuntimeError;
+use crate::error::{ErrorKind, RuntimeError};
 use crate::memory::MutatorView;
 use crate::safeptr::{MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
 
@@ -107,6 +105,51 @@ pub trait IndexedContainer<T: Sized + Clone>: Container<T> {
     ) -> Result<(), RuntimeError>;
 }
 
+/// The scope guard given to a slice access closure in place of the mutator.
+///
+/// A `SliceGuard` is a `MutatorScope`, so pointers can be dereferenced through it, but it has no
+/// way to allocate or to resize a container. A closure written against the guard it is given
+/// rather than a captured `MutatorView` cannot do either while the slice is live.
+///
+/// ```compile_fail
+/// use evalrus::array::ArrayU8;
+/// use evalrus::containers::{Container, SliceableContainer};
+/// use evalrus::error::RuntimeError;
+/// use evalrus::memory::{Memory, Mutator, MutatorView};
+///
+/// struct Example {}
+///
+/// impl Mutator for Example {
+///     type Input = ();
+///     type Output = ();
+///
+///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+///         let array = mem.alloc(ArrayU8::new())?;
+///         // the guard cannot allocate
+///         array.access_slice(mem, |guard, _slice| guard.alloc(ArrayU8::new()))?;
+///         Ok(())
+///     }
+/// }
+///
+/// Memory::new().mutate(&Example {}, ()).unwrap();
+/// ```
+pub struct SliceGuard {
+    _private: (),
+}
+
+impl MutatorScope for SliceGuard {}
+
+/// The one guard instance, handed out by `SliceGuard::get()`
+static SLICE_GUARD: SliceGuard = SliceGuard { _private: () };
+
+impl SliceGuard {
+    /// Return a guard for the duration of a slice access. Only container implementations should
+    /// need to call this.
+    pub fn get<'guard>(_guard: &'guard dyn MutatorScope) -> &'guard SliceGuard {
+        &SLICE_GUARD
+    }
+}
+
 /// A trait that is implemented for containers that can represent their contents as a slice.
 pub trait SliceableContainer<T: Sized + Clone>: IndexedContainer<T> {
     /// This function allows access to the interior of a container as a slice by way of a
@@ -117,13 +160,16 @@ pub trait SliceableContainer<T: Sized + Clone>: IndexedContainer<T> {
     /// as the slice lifetime - the slice may be invalidated during the 'guard lifetime
     /// by operations on the container that cause reallocation.
     ///
+    /// The function is given a `SliceGuard` to dereference pointers with. Any allocation or
+    /// container growth should be done before or after the call, not inside the function.
+    ///
     /// To prevent the function from modifying the container outside of the slice reference,
     /// the implementing container must maintain a RefCell-style flag to catch runtime
     /// container modifications that would render the slice invalid or cause undefined
     /// behavior.
     fn access_slice<'guard, F, R>(&self, _guard: &'guard dyn MutatorScope, f: F) -> R
     where
-        F: FnOnce(&mut [T]) -> R;
+        F: FnOnce(&'guard SliceGuard, &mut [T]) -> R;
 }
 
 /// Specialized indexable interface for where TaggedCellPtr is used as T
@@ -202,8 +248,25 @@ pub trait AnyContainerFromSlice: Container<TaggedCellPtr> {
     ) -> Result<ScopedPtr<'guard, Self>, RuntimeError>;
 }
 
+/// A count of the structural changes made to a container
+pub type ContainerVersion = u32;
+
 /// The implementor represents mutable changes via an internal version count
 /// such that the use of any references to an older version return an error
-pub trait VersionedContainer<T: Sized + Clone>: Container<T> {}
+pub trait VersionedContainer<T: Sized + Clone>: Container<T> {
+    /// Return the current version. It changes whenever items are added or removed or the
+    /// backing storage is reallocated, but not when an item is replaced in place.
+    fn version(&self) -> ContainerVersion;
+
+    /// Return a MutatedDuringIteration error if the container has changed since the given
+    /// version was read
+    fn check_version(&self, version: ContainerVersion) -> Result<(), RuntimeError> {
+        if self.version() == version {
+            Ok(())
+        } else {
+            Err(RuntimeError::new(ErrorKind::MutatedDuringIteration))
+        }
+    }
+}
 
 pub trait ImmutableContainer<T: Sized + Clone>: Container<T> {}
@@ -0,0 +1,414 @@
+/// Conversions between Rust types and interpreter values.
+///
+/// `ToValue` builds an interpreter value from a Rust value, `FromValue` does the reverse, checking
+/// the type of the interpreter value. The mapping is:
+///  * `i64`, `isize` - inline integer. Values outside of the inline integer range are an error
+///  * `f64`, `f32` - float, immediate if single precision represents it exactly, otherwise a
+///    heap-allocated FloatObject
+///  * `bool` - immediate bool
+///  * `char` - immediate char
+///  * `&str`, `String` - Text. `String` can also be converted from a Symbol.
+///  * `Vec<T>` - List. `Vec<T>` can also be converted from a Pair list or nil.
+///  * `HashMap<String, V>` - Dict with Symbol keys
+///  * `Option<T>` - nil for `None`
+use std::collections::HashMap;
+
+use crate::containers::{
+    Container, HashIndexedAnyContainer, SliceableContainer, StackAnyContainer,
+};
+use crate::dict::Dict;
+use crate::error::{err_eval, RuntimeError};
+use crate::list::List;
+use crate::memory::MutatorView;
+use crate::number::FloatObject;
+use crate::pair::vec_from_pairs;
+use crate::printer::describe;
+use crate::safeptr::TaggedScopedPtr;
+use crate::taggedptr::{TaggedPtr, Value, INLINE_INTEGER_MAX, INLINE_INTEGER_MIN};
+
+/// Conversion of a Rust value to an interpreter value
+pub trait ToValue {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError>;
+}
+
+/// Conversion of an interpreter value to a Rust value
+pub trait FromValue: Sized {
+    fn from_value<'guard>(
+        mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<Self, RuntimeError>;
+}
+
+/// Build a type mismatch error
+fn expected(what: &str, value: TaggedScopedPtr) -> RuntimeError {
+    err_eval(&format!("Expected {}, got {}", what, describe(*value)))
+}
+
+impl ToValue for isize {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        if !(INLINE_INTEGER_MIN..=INLINE_INTEGER_MAX).contains(self) {
+            return Err(err_eval(&format!(
+                "Integer {} is out of the range {} to {}",
+                self, INLINE_INTEGER_MIN, INLINE_INTEGER_MAX
+            )));
+        }
+
+        Ok(mem.number(*self))
+    }
+}
+
+impl FromValue for isize {
+    fn from_value<'guard>(
+        _mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<isize, RuntimeError> {
+        match *value {
+            Value::Number(n) => Ok(n),
+            _ => Err(expected("an integer", value)),
+        }
+    }
+}
+
+impl ToValue for i64 {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        (*self as isize).to_value(mem)
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value<'guard>(
+        mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<i64, RuntimeError> {
+        Ok(isize::from_value(mem, value)? as i64)
+    }
+}
+
+impl ToValue for f32 {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        Ok(TaggedScopedPtr::new(mem, TaggedPtr::float(*self)))
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value<'guard>(
+        mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<f32, RuntimeError> {
+        Ok(f64::from_value(mem, value)? as f32)
+    }
+}
+
+impl ToValue for f64 {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let single = *self as f32;
+
+        // NaNs are all the same to the interpreter, so any NaN is immediate
+        if single as f64 == *self || self.is_nan() {
+            Ok(TaggedScopedPtr::new(mem, TaggedPtr::float(single)))
+        } else {
+            mem.alloc_tagged(FloatObject::new(*self))
+        }
+    }
+}
+
+/// Integers are accepted and converted
+impl FromValue for f64 {
+    fn from_value<'guard>(
+        _mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<f64, RuntimeError> {
+        match *value {
+            Value::Float(n) => Ok(n),
+            Value::Number(n) => Ok(n as f64),
+            _ => Err(expected("a number", value)),
+        }
+    }
+}
+
+impl ToValue for bool {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        Ok(TaggedScopedPtr::new(mem, TaggedPtr::boolean(*self)))
+    }
+}
+
+impl FromValue for bool {
+    fn from_value<'guard>(
+        _mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<bool, RuntimeError> {
+        match *value {
+            Value::Bool(b) => Ok(b),
+            _ => Err(expected("a bool", value)),
+        }
+    }
+}
+
+impl ToValue for char {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        Ok(TaggedScopedPtr::new(mem, TaggedPtr::character(*self)))
+    }
+}
+
+impl FromValue for char {
+    fn from_value<'guard>(
+        _mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<char, RuntimeError> {
+        match *value {
+            Value::Char(c) => Ok(c),
+            _ => Err(expected("a char", value)),
+        }
+    }
+}
+
+impl ToValue for str {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        mem.text(self)
+    }
+}
+
+impl ToValue for String {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        mem.text(self)
+    }
+}
+
+impl FromValue for String {
+    fn from_value<'guard>(
+        mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<String, RuntimeError> {
+        match *value {
+            Value::Text(t) => Ok(String::from(t.as_str(mem))),
+            Value::Symbol(s) => Ok(String::from(s.as_str(mem))),
+            _ => Err(expected("a string", value)),
+        }
+    }
+}
+
+impl<T: ToValue> ToValue for [T] {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let list = List::alloc_with_capacity(mem, self.len() as u32)?;
+
+        for item in self {
+            StackAnyContainer::push(&*list, mem, item.to_value(mem)?)?;
+        }
+
+        Ok(list.as_tagged(mem))
+    }
+}
+
+impl<T: ToValue> ToValue for Vec<T> {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        self.as_slice().to_value(mem)
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value<'guard>(
+        mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<Vec<T>, RuntimeError> {
+        let items = match *value {
+            Value::List(list) => {
+                let mut items = Vec::with_capacity(list.length() as usize);
+                list.access_slice(mem, |guard, slice| {
+                    items.extend(slice.iter().map(|item| item.get(guard)))
+                });
+                items
+            }
+            Value::Pair(_) | Value::Nil => vec_from_pairs(mem, value)?,
+            _ => return Err(expected("a list", value)),
+        };
+
+        items
+            .into_iter()
+            .map(|item| T::from_value(mem, item))
+            .collect()
+    }
+}
+
+impl<V: ToValue> ToValue for HashMap<String, V> {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let dict = Dict::alloc(mem)?;
+
+        for (key, value) in self {
+            dict.assoc(mem, mem.lookup_sym(key), value.to_value(mem)?)?;
+        }
+
+        Ok(dict.as_tagged(mem))
+    }
+}
+
+impl<V: FromValue> FromValue for HashMap<String, V> {
+    fn from_value<'guard>(
+        mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<HashMap<String, V>, RuntimeError> {
+        match *value {
+            Value::Dict(dict) => dict
+                .items(mem)
+                .into_iter()
+                .map(|(key, value)| Ok((String::from_value(mem, key)?, V::from_value(mem, value)?)))
+                .collect(),
+            _ => Err(expected("a dict", value)),
+        }
+    }
+}
+
+impl<T: ToValue> ToValue for Option<T> {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        match self {
+            Some(value) => value.to_value(mem),
+            None => Ok(mem.nil()),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value<'guard>(
+        mem: &'guard MutatorView,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<Option<T>, RuntimeError> {
+        match *value {
+            Value::Nil => Ok(None),
+            _ => Ok(Some(T::from_value(mem, value)?)),
+        }
+    }
+}
+
+impl<T: ToValue + ?Sized> ToValue for &T {
+    fn to_value<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        (**self).to_value(mem)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    fn test_helper(test_fn: fn(&MutatorView) -> Result<(), RuntimeError>) {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = fn(&MutatorView) -> Result<(), RuntimeError>;
+            type Output = ();
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                test_fn: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                test_fn(mem)
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, test_fn).unwrap();
+    }
+
+    fn round_trip<T: ToValue + FromValue + PartialEq>(mem: &MutatorView, value: T) -> bool {
+        let converted = value.to_value(mem).unwrap();
+        T::from_value(mem, converted).unwrap() == value
+    }
+
+    #[test]
+    fn convert_scalars() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            assert!(round_trip(mem, 42i64));
+            assert!(round_trip(mem, -7isize));
+            assert!(round_trip(mem, 1.5f64));
+            assert!(round_trip(mem, true));
+            assert!(round_trip(mem, 'λ'));
+            assert!(round_trip(mem, String::from("text")));
+            assert!(round_trip(mem, Some(3i64)));
+            assert!(round_trip(mem, None::<i64>));
+
+            assert!(format!("{}", "abc".to_value(mem)?) == "\"abc\"");
+
+            // symbols convert to strings, integers to floats
+            assert!(String::from_value(mem, mem.lookup_sym("sym"))? == "sym");
+            assert!(f64::from_value(mem, mem.number(2))? == 2.0);
+
+            // out of range integers and mismatched types are errors
+            assert!(i64::max_value().to_value(mem).is_err());
+            assert!(i64::from_value(mem, mem.text("1")?).is_err());
+            assert!(bool::from_value(mem, mem.nil()).is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn convert_containers() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            assert!(round_trip(mem, vec![1i64, 2, 3]));
+            assert!(round_trip(mem, vec![vec![String::from("a")], vec![]]));
+
+            // Pair lists and nil convert to Vec
+            let pairs = parse(mem, "(a b c)")?;
+            assert!(Vec::<String>::from_value(mem, pairs)? == vec!["a", "b", "c"]);
+            assert!(Vec::<i64>::from_value(mem, mem.nil())?.is_empty());
+            assert!(Vec::<i64>::from_value(mem, parse(mem, "(1 a)")?).is_err());
+
+            let mut map = HashMap::new();
+            map.insert(String::from("one"), 1i64);
+            map.insert(String::from("two"), 2i64);
+            assert!(round_trip(mem, map));
+
+            assert!(HashMap::<String, i64>::from_value(mem, mem.number(1)).is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+}
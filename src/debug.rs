@@ -0,0 +1,304 @@
+/// Debugging hooks for embedders.
+///
+/// A `DebugHook` registered on a Thread with `Thread::set_debug_hook()` is notified of each
+/// instruction executed, each call into and return from a bytecode Function, and each evaluation
+/// error. A hook can pause evaluation before any instruction, which combined with
+/// `Thread::eval_with_budget()` and `Thread::resume_with_budget()` is enough to build steppers,
+/// breakpoints and tracers outside of this crate.
+///
+/// Whether a hook is registered is checked once per instruction batch, so there is no cost per
+/// instruction when none is.
+use std::io::Write;
+
+use crate::array::ArraySize;
+use crate::bytecode::Opcode;
+use crate::error::RuntimeError;
+use crate::function::Function;
+use crate::memory::MutatorView;
+use crate::safeptr::{ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+
+/// What the VM should do after a hook has seen the next instruction
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DebugAction {
+    /// Execute the instruction
+    Continue,
+    /// Stop before executing the instruction, returning `EvalStatus::Pending`. When evaluation is
+    /// resumed the instruction is executed without being passed to the hook again.
+    Pause,
+}
+
+/// Evaluation event callbacks. Every method has a default implementation that does nothing, so a
+/// hook only needs to implement the events it is interested in.
+///
+/// Native functions do not have call frames, so calls to them are only seen as `Call`
+/// instructions.
+pub trait DebugHook {
+    /// Called before an instruction is executed. `ip` is the index of the instruction in the
+    /// bytecode of `function` and `registers` is the function's register window, which can be
+    /// indexed by the registers named in `opcode`.
+    fn on_instruction(
+        &mut self,
+        _mem: &MutatorView,
+        _function: ScopedPtr<'_, Function>,
+        _ip: ArraySize,
+        _opcode: Opcode,
+        _registers: &[TaggedCellPtr],
+    ) -> DebugAction {
+        DebugAction::Continue
+    }
+
+    /// Called after a call frame for `function` has been entered. `depth` is the number of call
+    /// frames including the new one.
+    fn on_call(&mut self, _mem: &MutatorView, _function: ScopedPtr<'_, Function>, _depth: usize) {}
+
+    /// Called after `function` has returned `value`. `depth` is the number of call frames
+    /// remaining, zero when evaluation is complete.
+    fn on_return(
+        &mut self,
+        _mem: &MutatorView,
+        _function: ScopedPtr<'_, Function>,
+        _value: TaggedScopedPtr<'_>,
+        _depth: usize,
+    ) {
+    }
+
+    /// Called when evaluation fails, before the Thread is unwound
+    fn on_error(&mut self, _mem: &MutatorView, _error: &RuntimeError) {}
+}
+
+/// A hook that writes a line for every instruction executed: the function name and ip, the
+/// decoded instruction and the values of the registers it names, as they are before it executes.
+pub struct Tracer {
+    out: Box<dyn Write>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Tracer {
+        Tracer { out }
+    }
+}
+
+impl DebugHook for Tracer {
+    fn on_instruction(
+        &mut self,
+        mem: &MutatorView,
+        function: ScopedPtr<'_, Function>,
+        ip: ArraySize,
+        opcode: Opcode,
+        registers: &[TaggedCellPtr],
+    ) -> DebugAction {
+        let mut line = format!("{}:{}  {:?}", function.name(mem), ip, opcode);
+
+        let mut shown = Vec::new();
+        for reg in opcode.registers() {
+            if shown.contains(&reg) {
+                continue;
+            }
+            shown.push(reg);
+
+            line.push_str(&format!("  r{}={}", reg, registers[reg as usize].get(mem)));
+        }
+
+        // tracing is best effort, a failed write must not abort evaluation
+        let _ = writeln!(self.out, "{}", line);
+
+        DebugAction::Continue
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+    use crate::vm::{EvalStatus, Thread};
+
+    /// Records events as strings, optionally pausing before the first instruction
+    struct Recorder {
+        events: Rc<RefCell<Vec<String>>>,
+        pause: bool,
+    }
+
+    impl DebugHook for Recorder {
+        fn on_instruction(
+            &mut self,
+            _mem: &MutatorView,
+            _function: ScopedPtr<'_, Function>,
+            ip: ArraySize,
+            _opcode: Opcode,
+            _registers: &[TaggedCellPtr],
+        ) -> DebugAction {
+            self.events.borrow_mut().push(format!("instruction {}", ip));
+
+            if self.pause {
+                self.pause = false;
+                DebugAction::Pause
+            } else {
+                DebugAction::Continue
+            }
+        }
+
+        fn on_call(&mut self, _mem: &MutatorView, function: ScopedPtr<'_, Function>, depth: usize) {
+            self.events
+                .borrow_mut()
+                .push(format!("call {} {}", function, depth));
+        }
+
+        fn on_return(
+            &mut self,
+            _mem: &MutatorView,
+            function: ScopedPtr<'_, Function>,
+            value: TaggedScopedPtr<'_>,
+            depth: usize,
+        ) {
+            self.events
+                .borrow_mut()
+                .push(format!("return {} {} {}", function, value, depth));
+        }
+
+        fn on_error(&mut self, _mem: &MutatorView, error: &RuntimeError) {
+            self.events.borrow_mut().push(format!("error {}", error));
+        }
+    }
+
+    #[test]
+    fn debug_hook_events() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let function = |code| compile(mem, parse(mem, code)?);
+
+                t.quick_vm_eval(mem, function("(def f (x) (car x))")?)?;
+
+                let events = Rc::new(RefCell::new(Vec::new()));
+                t.set_debug_hook(Some(Box::new(Recorder {
+                    events: events.clone(),
+                    pause: false,
+                })));
+
+                t.quick_vm_eval(mem, function("(f '(a))")?)?;
+                {
+                    let events = events.borrow();
+                    assert!(events[0] == "instruction 0");
+                    assert!(events.contains(&String::from("call (Function f (x)) 2")));
+                    assert!(events.contains(&String::from("return (Function f (x)) a 1")));
+                    assert!(events.last().unwrap() == "return (Function ()) a 0");
+                }
+
+                events.borrow_mut().clear();
+                assert!(t.quick_vm_eval(mem, function("(f 'a)")?).is_err());
+                assert!(events.borrow().last().unwrap().starts_with("error "));
+
+                // once removed, the hook sees nothing more
+                assert!(t.set_debug_hook(None).is_some());
+                events.borrow_mut().clear();
+                t.quick_vm_eval(mem, function("(f '(a))")?)?;
+                assert!(events.borrow().is_empty());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn debug_hook_pause() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+
+                let events = Rc::new(RefCell::new(Vec::new()));
+                t.set_debug_hook(Some(Box::new(Recorder {
+                    events: events.clone(),
+                    pause: true,
+                })));
+
+                let code = compile(mem, parse(mem, "(car '(a))")?)?;
+                assert!(t.eval_with_budget(mem, code, 100)? == EvalStatus::Pending);
+                assert!(*events.borrow() == vec![String::from("instruction 0")]);
+
+                // the paused instruction is not passed to the hook again
+                match t.resume_with_budget(mem, 100)? {
+                    EvalStatus::Return(value) => assert!(value == mem.lookup_sym("a")),
+                    EvalStatus::Pending => panic!("expected evaluation to complete"),
+                }
+                assert!(events.borrow()[1] == "instruction 1");
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    /// A writer that appends to a buffer that the test can read afterwards
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tracer_output() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let function = |code| compile(mem, parse(mem, code)?);
+
+                t.quick_vm_eval(mem, function("(def f (x) (car x))")?)?;
+
+                let buffer = Rc::new(RefCell::new(Vec::new()));
+                t.set_debug_hook(Some(Box::new(Tracer::new(Box::new(SharedBuffer(
+                    buffer.clone(),
+                ))))));
+
+                t.quick_vm_eval(mem, function("(f '(a))")?)?;
+
+                let output = String::from_utf8(buffer.borrow().clone()).unwrap();
+                let lines: Vec<&str> = output.lines().collect();
+
+                assert!(lines[0].starts_with("<lambda>:0  LoadLiteral"));
+                assert!(lines[2].starts_with("<lambda>:2  LoadGlobal") && lines[2].ends_with("=f"));
+                assert!(lines
+                    .iter()
+                    .any(|line| line.starts_with("f:0  FirstOfPair") && line.contains("=(a)")));
+                assert!(lines.last().unwrap().contains("Return { reg: "));
+                assert!(lines.last().unwrap().ends_with("=a"));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+//! A heap backend for debugging the runtime, selected in place of the Sticky Immix heap by the
+//! `debug-heap` feature.
+//!
+//! Each object is a separate allocation from the system allocator, so that tools such as
+//! Valgrind and the address sanitizer see individual objects. Every allocation is filled with
+//! `POISON_BYTE` before the header and object are written, making reads of uninitialized array
+//! storage easy to spot. As with the Sticky Immix heap, objects are never dropped; their memory is
+//! released when the heap is.
+use std::alloc::{alloc, dealloc, Layout};
+use std::cell::RefCell;
+use std::mem::{align_of, size_of};
+use std::ptr::{self, NonNull};
+
+use stickyimmix::{
+    AllocError, AllocHeader, AllocObject, AllocRaw, ArraySize, Mark, RawPtr, SizeClass,
+};
+
+use crate::headers::{ObjectHeader, TypeList};
+use crate::memory::{HeapBackend, POISON_BYTE};
+
+/// Alignment of every allocation, which suits the header and every object type
+const ALIGN: usize = 16;
+
+/// Offset from the start of an allocation to the object, past the header
+fn header_offset() -> usize {
+    (size_of::<ObjectHeader>() + ALIGN - 1) & !(ALIGN - 1)
+}
+
+/// A heap that allocates each object separately from the system allocator
+pub struct DebugHeap {
+    /// Every allocation made, released when the heap is dropped
+    allocations: RefCell<Vec<(NonNull<u8>, Layout)>>,
+}
+
+impl DebugHeap {
+    /// Allocate poisoned space for a header and an object of the given size, write the header and
+    /// return a pointer to the object space
+    fn alloc_with_header(
+        &self,
+        size: usize,
+        header: ObjectHeader,
+    ) -> Result<NonNull<u8>, AllocError> {
+        let layout = Layout::from_size_align(header_offset() + size.max(1), ALIGN)
+            .map_err(|_| AllocError::BadRequest)?;
+
+        let base = NonNull::new(unsafe { alloc(layout) }).ok_or(AllocError::OOM)?;
+        self.allocations.borrow_mut().push((base, layout));
+
+        unsafe {
+            ptr::write_bytes(base.as_ptr(), POISON_BYTE, layout.size());
+            ptr::write(base.as_ptr() as *mut ObjectHeader, header);
+            Ok(NonNull::new_unchecked(base.as_ptr().add(header_offset())))
+        }
+    }
+}
+
+impl HeapBackend for DebugHeap {
+    const NAME: &'static str = "debug heap";
+
+    fn new() -> DebugHeap {
+        DebugHeap {
+            allocations: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl AllocRaw for DebugHeap {
+    type Header = ObjectHeader;
+
+    fn alloc<T>(&self, object: T) -> Result<RawPtr<T>, AllocError>
+    where
+        T: AllocObject<TypeList>,
+    {
+        if align_of::<T>() > ALIGN {
+            return Err(AllocError::BadRequest);
+        }
+
+        let size = size_of::<T>();
+        let header = ObjectHeader::new::<T>(
+            size as ArraySize,
+            SizeClass::get_for_size(size)?,
+            Mark::Allocated,
+        );
+
+        let object_ptr = self.alloc_with_header(size, header)?.cast::<T>();
+        unsafe { ptr::write(object_ptr.as_ptr(), object) };
+
+        Ok(RawPtr::new(object_ptr.as_ptr()))
+    }
+
+    fn alloc_array(&self, size_bytes: ArraySize) -> Result<RawPtr<u8>, AllocError> {
+        let header = ObjectHeader::new_array(
+            size_bytes,
+            SizeClass::get_for_size(size_bytes as usize)?,
+            Mark::Allocated,
+        );
+
+        let array_ptr = self.alloc_with_header(size_bytes as usize, header)?;
+
+        Ok(RawPtr::new(array_ptr.as_ptr()))
+    }
+
+    fn get_header(object: NonNull<()>) -> NonNull<ObjectHeader> {
+        unsafe {
+            let header_addr = object.cast::<u8>().as_ptr().sub(header_offset());
+            NonNull::new_unchecked(header_addr as *mut ObjectHeader)
+        }
+    }
+
+    fn get_object(header: NonNull<ObjectHeader>) -> NonNull<()> {
+        unsafe {
+            let object_addr = header.cast::<u8>().as_ptr().add(header_offset());
+            NonNull::new_unchecked(object_addr as *mut ())
+        }
+    }
+}
+
+impl Drop for DebugHeap {
+    fn drop(&mut self) {
+        for (base, layout) in self.allocations.get_mut().drain(..) {
+            unsafe { dealloc(base.as_ptr(), layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pair::Pair;
+
+    #[test]
+    fn debug_heap_headers_and_poison() {
+        let heap = DebugHeap::new();
+
+        let pair = heap.alloc(Pair::new()).unwrap();
+        let header = DebugHeap::get_header(pair.as_untyped());
+        assert!(unsafe { header.as_ref() }.type_id() == TypeList::Pair);
+        assert!(DebugHeap::get_object(header) == pair.as_untyped());
+
+        let array = heap.alloc_array(64).unwrap();
+        let header = DebugHeap::get_header(array.as_untyped());
+        assert!(unsafe { header.as_ref() }.type_id() == TypeList::Array);
+        assert!(unsafe { header.as_ref() }.size() == 64);
+
+        // array storage is not initialized, it reads as poison
+        let bytes = unsafe { std::slice::from_raw_parts(array.as_ptr(), 64) };
+        assert!(bytes.iter().all(|byte| *byte == POISON_BYTE));
+    }
+}
@@ -1,18 +1,16 @@
 /// Basic mutable dict type
 use std::cell::Cell;
+use std::cmp::Ordering;
 use std::fmt;
-use std::hash::Hasher;
 
-use fnv::FnvHasher;
-
-use crate::containers::{Container, HashIndexedAnyContainer};
+use crate::containers::{Container, ContainerVersion, HashIndexedAnyContainer, VersionedContainer};
 use crate::error::{ErrorKind, RuntimeError};
-use crate::hashable::Hashable;
+use crate::hashable::hash_value;
 use crate::memory::MutatorView;
-use crate::printer::Print;
+use crate::printer::{print_elements, Print};
 use crate::rawarray::{default_array_growth, ArraySize, RawArray};
 use crate::safeptr::{MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
-use crate::taggedptr::Value;
+use crate::taggedptr::{TaggedPtr, Value};
 
 // max load factor before resizing the table
 const LOAD_FACTOR: f32 = 0.80;
@@ -36,20 +34,15 @@ impl DictItem {
     }
 }
 
-/// Generate a hash value for a key
-/// TODO move this function somewhere more suitable
+/// Generate a hash value for a key. Keys are compared by identity, so Text, which is hashable but
+/// equal by content, cannot be a key.
 fn hash_key<'guard>(
     guard: &'guard dyn MutatorScope,
     key: TaggedScopedPtr<'guard>,
 ) -> Result<u64, RuntimeError> {
     match *key {
-        Value::Symbol(s) => {
-            let mut hasher = FnvHasher::default();
-            s.hash(guard, &mut hasher);
-            Ok(hasher.finish())
-        }
-        Value::Number(n) => Ok(n as u64),
-        _ => Err(RuntimeError::new(ErrorKind::UnhashableError)),
+        Value::Text(_) => Err(RuntimeError::new(ErrorKind::UnhashableError)),
+        _ => hash_value(guard, key),
     }
 }
 
@@ -98,9 +91,11 @@ fn fill_with_blank_entries<'guard>(
     _guard: &'guard dyn MutatorScope,
     data: &RawArray<DictItem>,
 ) -> Result<(), RuntimeError> {
-    let ptr = data
-        .as_ptr()
-        .ok_or(RuntimeError::new(ErrorKind::BoundsError))?;
+    // an unallocated backing array has no slots to reset
+    let ptr = match data.as_ptr() {
+        Some(ptr) => ptr,
+        None => return Ok(()),
+    };
 
     let blank_entry = DictItem::blank();
 
@@ -127,6 +122,10 @@ pub struct Dict {
     used_entries: Cell<ArraySize>,
     /// Backing array for key/value entries
     data: Cell<RawArray<DictItem>>,
+    /// Values are weak references, see `Dict::alloc_weak()`
+    weak_values: Cell<bool>,
+    /// Changed by each addition or removal of a key and each reallocation, see `DictIter`
+    version: Cell<ContainerVersion>,
 }
 
 impl Dict {
@@ -145,12 +144,57 @@ impl Dict {
         mem.alloc(Dict::with_capacity(mem, capacity)?)
     }
 
+    /// Allocate a new instance on the heap whose values are weak references: a value does not
+    /// keep its object alive, and its entry is removed when the object is collected. Keys are
+    /// held as normal.
+    pub fn alloc_weak<'guard>(
+        mem: &'guard MutatorView,
+    ) -> Result<ScopedPtr<'guard, Dict>, RuntimeError> {
+        let dict = Dict::new();
+        dict.weak_values.set(true);
+        mem.alloc(dict)
+    }
+
+    /// Return true if the values are weak references
+    pub fn has_weak_values(&self) -> bool {
+        self.weak_values.get()
+    }
+
+    /// Remove each entry whose value is not live, for clearing a weak-valued Dict after a
+    /// collection
+    pub(crate) fn remove_dead_values<F>(&self, is_live: &mut F)
+    where
+        F: FnMut(TaggedPtr) -> bool,
+    {
+        let data = self.data.get();
+
+        if let Some(ptr) = data.as_ptr() {
+            for index in 0..data.capacity() {
+                let entry =
+                    unsafe { &mut *(ptr.offset(index as isize) as *mut DictItem) as &mut DictItem };
+                if !entry.key.is_nil() && !is_live(entry.value.get_ptr()) {
+                    self.changed();
+                    self.length.set(self.length.get() - 1);
+                    // tombstone combo
+                    entry.key.set_to_nil();
+                    entry.hash = TOMBSTONE;
+                }
+            }
+        }
+    }
+
+    /// Record a structural change
+    fn changed(&self) {
+        self.version.set(self.version.get().wrapping_add(1));
+    }
+
     /// Scale capacity up if needed
     fn grow_capacity<'guard>(&self, mem: &'guard MutatorView) -> Result<(), RuntimeError> {
         let data = self.data.get();
 
         let new_capacity = default_array_growth(data.capacity())?;
         let new_data = RawArray::<DictItem>::with_capacity(mem, new_capacity)?;
+        fill_with_blank_entries(mem, &new_data)?;
 
         let maybe_ptr = data.as_ptr();
         if let Some(ptr) = maybe_ptr {
@@ -165,8 +209,83 @@ impl Dict {
         }
 
         self.data.set(new_data);
+        self.changed();
         Ok(())
     }
+
+    /// Return a list of all key/value pairs, in no particular order
+    pub fn items<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+    ) -> Vec<(TaggedScopedPtr<'guard>, TaggedScopedPtr<'guard>)> {
+        let data = self.data.get();
+        let mut items = Vec::with_capacity(self.length.get() as usize);
+
+        if let Some(ptr) = data.as_ptr() {
+            for index in 0..data.capacity() {
+                let entry = unsafe { &*(ptr.offset(index as isize)) as &DictItem };
+                if !entry.key.is_nil() {
+                    items.push((entry.key.get(guard), entry.value.get(guard)));
+                }
+            }
+        }
+
+        items
+    }
+
+    /// Return an iterator over the key/value pairs, in no particular order
+    pub fn iter<'guard>(&'guard self, guard: &'guard dyn MutatorScope) -> DictIter<'guard> {
+        DictIter {
+            dict: self,
+            guard,
+            version: self.version.get(),
+            index: 0,
+            done: false,
+        }
+    }
+}
+
+/// An iterator over the key/value pairs of a Dict, made by `Dict::iter()`. If a key is added to or
+/// removed from the dict after the iterator is made, the next item is a MutatedDuringIteration
+/// error and the iteration ends. Replacing the value of a key is not a change to the dict's
+/// structure and is seen by the iterator if it has not passed the key.
+pub struct DictIter<'guard> {
+    dict: &'guard Dict,
+    guard: &'guard dyn MutatorScope,
+    version: ContainerVersion,
+    /// The next slot of the backing array to look at
+    index: ArraySize,
+    done: bool,
+}
+
+impl<'guard> Iterator for DictIter<'guard> {
+    type Item = Result<(TaggedScopedPtr<'guard>, TaggedScopedPtr<'guard>), RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if let Err(e) = self.dict.check_version(self.version) {
+            self.done = true;
+            return Some(Err(e));
+        }
+
+        let data = self.dict.data.get();
+        if let Some(ptr) = data.as_ptr() {
+            while self.index < data.capacity() {
+                let entry = unsafe { &*(ptr.offset(self.index as isize)) as &DictItem };
+                self.index += 1;
+
+                if !entry.key.is_nil() {
+                    return Some(Ok((entry.key.get(self.guard), entry.value.get(self.guard))));
+                }
+            }
+        }
+
+        self.done = true;
+        None
+    }
 }
 
 impl Container<DictItem> for Dict {
@@ -175,6 +294,8 @@ impl Container<DictItem> for Dict {
             length: Cell::new(0),
             used_entries: Cell::new(0),
             data: Cell::new(RawArray::new()),
+            weak_values: Cell::new(false),
+            version: Cell::new(0),
         }
     }
 
@@ -186,6 +307,8 @@ impl Container<DictItem> for Dict {
             length: Cell::new(0),
             used_entries: Cell::new(0),
             data: Cell::new(RawArray::with_capacity(mem, capacity)?),
+            weak_values: Cell::new(false),
+            version: Cell::new(0),
         };
 
         let data = dict.data.get();
@@ -199,6 +322,7 @@ impl Container<DictItem> for Dict {
         fill_with_blank_entries(mem, &data)?;
         self.length.set(0);
         self.used_entries.set(0);
+        self.changed();
         Ok(())
     }
 
@@ -207,6 +331,12 @@ impl Container<DictItem> for Dict {
     }
 }
 
+impl VersionedContainer<DictItem> for Dict {
+    fn version(&self) -> ContainerVersion {
+        self.version.get()
+    }
+}
+
 /// Hashable-indexed interface. Objects used as keys must implement Hashable.
 impl HashIndexedAnyContainer for Dict {
     fn lookup<'guard>(
@@ -241,6 +371,7 @@ impl HashIndexedAnyContainer for Dict {
         let entry = find_entry(mem, &data, hash)?;
 
         if entry.key.is_nil() {
+            self.changed();
             self.length.set(self.length.get() + 1);
             if entry.hash == 0 {
                 self.used_entries.set(self.used_entries.get() + 1);
@@ -267,6 +398,7 @@ impl HashIndexedAnyContainer for Dict {
             return Err(RuntimeError::new(ErrorKind::KeyError));
         }
 
+        self.changed();
         self.length.set(self.length.get() - 1);
         // tombstone combo
         entry.key.set_to_nil();
@@ -287,23 +419,51 @@ impl HashIndexedAnyContainer for Dict {
     }
 }
 
+/// Order keys for printing: numbers ascending, then symbols by name
+pub(crate) fn key_order(
+    guard: &dyn MutatorScope,
+    a: TaggedScopedPtr,
+    b: TaggedScopedPtr,
+) -> Ordering {
+    match (*a, *b) {
+        (Value::Number(a), Value::Number(b)) => a.cmp(&b),
+        (Value::Number(_), _) => Ordering::Less,
+        (_, Value::Number(_)) => Ordering::Greater,
+        (Value::Symbol(a), Value::Symbol(b)) => a.as_str(guard).cmp(b.as_str(guard)),
+        _ => Ordering::Equal,
+    }
+}
+
 impl Print for Dict {
+    /// Prints `{key: value, ...}` in table order, or sorted by key with the alternate flag, `{:#}`,
+    /// for output that does not depend on the table capacity or insertion history
     fn print<'guard>(
         &self,
-        _guard: &'guard dyn MutatorScope,
+        guard: &'guard dyn MutatorScope,
         f: &mut fmt::Formatter,
     ) -> fmt::Result {
-        // TODO
-        write!(f, "Dict[...]")
+        let mut items = self.items(guard);
+        if f.alternate() {
+            items.sort_by(|(a, _), (b, _)| key_order(guard, *a, *b));
+        }
+
+        let length = items.len();
+
+        print_elements(f, "{", ", ", "}", length, items, |f, (key, value)| {
+            fmt::Display::fmt(&key.value(), f)?;
+            write!(f, ": ")?;
+            fmt::Display::fmt(&value.value(), f)
+        })
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Container, Dict, HashIndexedAnyContainer};
+    use super::{Container, Dict, HashIndexedAnyContainer, VersionedContainer};
     use crate::error::{ErrorKind, RuntimeError};
     use crate::memory::{Memory, Mutator, MutatorView};
     use crate::pair::Pair;
+    use crate::taggedptr::Value;
 
     #[test]
     fn dict_empty_assoc_lookup() {
@@ -649,4 +809,111 @@ mod test {
         let test = Test {};
         mem.mutate(&test, ()).unwrap();
     }
+
+    #[test]
+    fn dict_print() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                _input: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                let dict = mem.alloc_tagged(Dict::new())?;
+                assert!(format!("{}", dict) == "{}");
+
+                if let Value::Dict(d) = *dict {
+                    d.assoc(mem, mem.lookup_sym("zeta"), mem.number(1))?;
+                    assert!(format!("{}", dict) == "{zeta: 1}");
+
+                    d.assoc(mem, mem.lookup_sym("alpha"), mem.lookup_sym("b"))?;
+                    d.assoc(mem, mem.number(7), mem.nil())?;
+                    d.assoc(mem, mem.number(-3), mem.text("x")?)?;
+                    assert!(format!("{:#}", dict) == "{-3: \"x\", 7: nil, alpha: b, zeta: 1}");
+
+                    // nested dicts are sorted too
+                    let outer = Dict::alloc(mem)?;
+                    outer.assoc(mem, mem.lookup_sym("inner"), dict)?;
+                    assert!(
+                        format!("{:#}", outer.as_tagged(mem))
+                            == "{inner: {-3: \"x\", 7: nil, alpha: b, zeta: 1}}"
+                    );
+
+                    assert!(format!("{:#.2}", dict) == "{-3: \"x\", 7: nil, \u{2026} (+2 more)}");
+                } else {
+                    panic!("expected a Dict!")
+                }
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
+
+    #[test]
+    fn dict_iter_detects_changes() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                _input: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                let dict = Dict::alloc(mem)?;
+                for n in 0..10 {
+                    dict.assoc(mem, mem.number(n), mem.nil())?;
+                }
+
+                let items: Result<Vec<_>, RuntimeError> = dict.iter(mem).collect();
+                assert!(items?.len() == 10);
+
+                // replacing the value of a key is not a structural change
+                let version = dict.version();
+                let mut iter = dict.iter(mem);
+                assert!(iter.next().unwrap().is_ok());
+                dict.assoc(mem, mem.number(3), mem.lookup_sym("three"))?;
+                assert!(iter.next().unwrap().is_ok());
+                assert!(dict.check_version(version).is_ok());
+
+                // adding a key is
+                dict.assoc(mem, mem.number(10), mem.nil())?;
+                match iter.next() {
+                    Some(Err(e)) => assert!(*e.error_kind() == ErrorKind::MutatedDuringIteration),
+                    _ => panic!("expected a MutatedDuringIteration error"),
+                }
+                assert!(iter.next().is_none());
+
+                let mut iter = dict.iter(mem);
+                dict.dissoc(mem, mem.number(0))?;
+                assert!(iter.next().unwrap().is_err());
+
+                // removing a key that isn't there changes nothing
+                let mut iter = dict.iter(mem);
+                assert!(dict.dissoc(mem, mem.number(0)).is_err());
+                assert!(iter.next().unwrap().is_ok());
+
+                let mut iter = dict.iter(mem);
+                dict.clear(mem)?;
+                assert!(iter.next().unwrap().is_err());
+                assert!(dict.iter(mem).next().is_none());
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
 }
@@ -7,38 +7,128 @@ use rustyline::error::ReadlineError;
 use blockalloc::BlockError;
 use stickyimmix::AllocError;
 
+use crate::json::encode_str;
+use crate::port::flush_stdout;
+
 /// Source code position
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct SourcePos {
+    /// Line number, starting at 1
     pub line: u32,
+    /// Character count from the start of the line, starting at 0
     pub column: u32,
+    /// Byte offset from the start of the source
+    pub offset: u32,
 }
 
 impl SourcePos {
-    fn new(line: u32, column: u32) -> SourcePos {
-        SourcePos { line, column }
+    fn new(line: u32, column: u32, offset: u32) -> SourcePos {
+        SourcePos {
+            line,
+            column,
+            offset,
+        }
     }
 }
 
+impl fmt::Display for SourcePos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {} (byte offset {})",
+            self.line, self.column, self.offset
+        )
+    }
+}
+
+/// Every kind of error the interpreter can report. More kinds may be added, so matches on this
+/// type outside of the crate need a wildcard arm.
 #[derive(Debug, PartialEq)]
+#[non_exhaustive]
 pub enum ErrorKind {
+    /// Reading or writing a file or the terminal failed
     IOError(String),
+    /// Source code could not be split into tokens
     LexerError(String),
+    /// Tokens could not be parsed into an expression, or the expression could not be compiled
     ParseError(String),
+    /// Evaluation failed
     EvalError(String),
+    /// An allocation of an unsupported size was requested
     BadAllocationRequest,
+    /// The heap could not satisfy an allocation
     OutOfMemory,
+    /// A container index was out of range
     BoundsError,
+    /// A Dict lookup found no value for the key
     KeyError,
+    /// A value that cannot be hashed was used as a Dict key
     UnhashableError,
+    /// A container was modified while its contents were exposed as a slice
     MutableBorrowError,
+    /// A container was modified after an iterator over it was made
+    MutatedDuringIteration,
+    /// Evaluation was stopped by an interrupt request
+    Interrupted,
+    /// The program called `(exit n)`, requesting the process exit with the given status
+    Exit(i32),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::IOError(ref reason) => write!(f, "IO Error: {}", reason),
+            ErrorKind::LexerError(ref reason) => write!(f, "Parse error: {}", reason),
+            ErrorKind::ParseError(ref reason) => write!(f, "Parse error: {}", reason),
+            ErrorKind::EvalError(ref reason) => write!(f, "Evaluation error: {}", reason),
+            ErrorKind::OutOfMemory => write!(f, "Out of memory!"),
+            ErrorKind::BadAllocationRequest => {
+                write!(f, "An invalid memory size allocation was requested!")
+            }
+            ErrorKind::BoundsError => write!(f, "Indexing bounds error"),
+            ErrorKind::KeyError => write!(f, "Key does not exist in Dict"),
+            ErrorKind::UnhashableError => write!(f, "Attempt to access Dict with unhashable key"),
+            ErrorKind::MutableBorrowError => write!(
+                f,
+                "Attempt to modify a container that is already mutably borrowed"
+            ),
+            ErrorKind::MutatedDuringIteration => {
+                write!(f, "Container was modified during iteration")
+            }
+            ErrorKind::Interrupted => write!(f, "Evaluation interrupted"),
+            ErrorKind::Exit(status) => write!(f, "Exit requested with status {}", status),
+        }
+    }
+}
+
+impl ErrorKind {
+    /// A short stable name for the kind of error, for tools that read diagnostics
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::IOError(_) => "io-error",
+            ErrorKind::LexerError(_) => "lexer-error",
+            ErrorKind::ParseError(_) => "parse-error",
+            ErrorKind::EvalError(_) => "eval-error",
+            ErrorKind::BadAllocationRequest => "bad-allocation",
+            ErrorKind::OutOfMemory => "out-of-memory",
+            ErrorKind::BoundsError => "bounds-error",
+            ErrorKind::KeyError => "key-error",
+            ErrorKind::UnhashableError => "unhashable-key",
+            ErrorKind::MutableBorrowError => "mutable-borrow",
+            ErrorKind::MutatedDuringIteration => "mutated-during-iteration",
+            ErrorKind::Interrupted => "interrupted",
+            ErrorKind::Exit(_) => "exit",
+        }
+    }
 }
 
 /// An Eval-rs runtime error type
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct RuntimeError {
     kind: ErrorKind,
     pos: Option<SourcePos>,
+    /// The lower level error this one was converted from, if any
+    source: Option<Box<dyn Error + Send + Sync + 'static>>,
 }
 
 impl RuntimeError {
@@ -46,6 +136,7 @@ impl RuntimeError {
         RuntimeError {
             kind: kind,
             pos: None,
+            source: None,
         }
     }
 
@@ -53,6 +144,19 @@ impl RuntimeError {
         RuntimeError {
             kind: kind,
             pos: Some(pos),
+            source: None,
+        }
+    }
+
+    /// Build an error caused by a lower level error, which is returned by `Error::source()`
+    pub fn with_source<E>(kind: ErrorKind, source: E) -> RuntimeError
+    where
+        E: Error + Send + Sync + 'static,
+    {
+        RuntimeError {
+            kind,
+            pos: None,
+            source: Some(Box::new(source)),
         }
     }
 
@@ -66,43 +170,221 @@ impl RuntimeError {
 
     /// Given the relevant source code string, show the error in context
     pub fn print_with_source(&self, source: &str) {
-        if let Some(ref pos) = self.pos {
-            let mut iter = source.lines().enumerate();
-
-            while let Some((count, line)) = iter.next() {
-                // count starts at 0, line numbers start at 1
-                if count + 1 == pos.line as usize {
-                    println!("error: {}", self);
-                    println!("{:5}|{}", pos.line, line);
-                    println!("{:5}|{:width$}^", " ", " ", width = pos.column as usize);
-                    println!("{:5}|", " ");
-                    return;
-                }
+        print_in_context("error", &self.kind, self.pos, source);
+    }
+}
+
+/// Errors are equal if they are of the same kind at the same position, whatever their source
+impl PartialEq for RuntimeError {
+    fn eq(&self, other: &RuntimeError) -> bool {
+        self.kind == other.kind && self.pos == other.pos
+    }
+}
+
+/// Print a labeled message and, if the position is within the source, the line it refers to
+fn print_in_context(label: &str, message: &dyn fmt::Display, pos: Option<SourcePos>, source: &str) {
+    // after any output of the code that was evaluated
+    flush_stdout().ok();
+
+    if let Some(ref pos) = pos {
+        for (count, line) in source.lines().enumerate() {
+            // count starts at 0, line numbers start at 1
+            if count + 1 == pos.line as usize {
+                println!("{}: {}", label, message);
+                println!("{:5}--> {}", " ", pos);
+                println!("{:5}|{}", pos.line, line);
+                println!(
+                    "{:5}|{:width$}^",
+                    " ",
+                    " ",
+                    width = caret_offset(line, pos.column)
+                );
+                println!("{:5}|", " ");
+                return;
             }
-        } else {
-            println!("error: {}", self);
         }
+
+        // the position is not within the source, show it without the line
+        println!("{}: {}", label, message);
+        println!("{:5}--> {}", " ", pos);
+    } else {
+        println!("{}: {}", label, message);
+    }
+}
+
+/// Return the count of terminal cells taken by the first `column` characters of a line, so that a
+/// caret can be placed under the character at that column. Combining marks take no cells and
+/// East Asian wide characters take two.
+fn caret_offset(line: &str, column: u32) -> usize {
+    line.chars().take(column as usize).map(display_width).sum()
+}
+
+/// Return the approximate count of terminal cells a character takes
+fn display_width(c: char) -> usize {
+    match c as u32 {
+        // combining marks and joiners
+        0x0300..=0x036f
+        | 0x1ab0..=0x1aff
+        | 0x1dc0..=0x1dff
+        | 0x200b..=0x200d
+        | 0x20d0..=0x20ff
+        | 0xfe00..=0xfe0f
+        | 0xfe20..=0xfe2f => 0,
+        // East Asian wide and fullwidth characters, and emoji
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+/// A problem found in source code. A warning, such as an unused variable, does not stop
+/// compilation and it is up to the caller whether and how to report it. An error is a problem
+/// that would stop the program, reported by checks that go on to find the rest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// A short stable name for the kind of problem, such as `unused-variable`
+    code: &'static str,
+    message: String,
+    pos: Option<SourcePos>,
+    error: bool,
+}
+
+impl Diagnostic {
+    pub fn warning(code: &'static str, message: &str, pos: Option<SourcePos>) -> Diagnostic {
+        Diagnostic {
+            code,
+            message: String::from(message),
+            pos,
+            error: false,
+        }
+    }
+
+    pub fn error(code: &'static str, message: &str, pos: Option<SourcePos>) -> Diagnostic {
+        Diagnostic {
+            code,
+            message: String::from(message),
+            pos,
+            error: true,
+        }
+    }
+
+    /// An error diagnostic for an error that stopped parsing, compilation or evaluation
+    pub fn from_error(error: &RuntimeError) -> Diagnostic {
+        let message = match error.error_kind() {
+            ErrorKind::IOError(reason)
+            | ErrorKind::LexerError(reason)
+            | ErrorKind::ParseError(reason)
+            | ErrorKind::EvalError(reason) => reason.clone(),
+            other => format!("{}", other),
+        };
+
+        Diagnostic {
+            code: error.error_kind().code(),
+            message,
+            pos: error.error_pos(),
+            error: true,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.error
+    }
+
+    fn label(&self) -> &'static str {
+        match self.error {
+            true => "error",
+            false => "warning",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn pos(&self) -> Option<SourcePos> {
+        self.pos
+    }
+
+    /// Given the relevant source code string, show the diagnostic in context
+    pub fn print_with_source(&self, source: &str) {
+        print_in_context(self.label(), &self.message, self.pos, source);
+    }
+
+    /// Return the diagnostic as a single line JSON object with `code`, `message`, `file`, `line`,
+    /// `column` and `severity` members. The file, line and column are null when not known.
+    pub fn to_json(&self, file_name: Option<&str>) -> String {
+        let mut out = String::from("{\"code\":");
+        encode_str(self.code, &mut out);
+        out.push_str(",\"message\":");
+        encode_str(&self.message, &mut out);
+        out.push_str(",\"file\":");
+        match file_name {
+            Some(file_name) => encode_str(file_name, &mut out),
+            None => out.push_str("null"),
+        }
+        match self.pos {
+            Some(pos) => out.push_str(&format!(",\"line\":{},\"column\":{}", pos.line, pos.column)),
+            None => out.push_str(",\"line\":null,\"column\":null"),
+        }
+        out.push_str(",\"severity\":");
+        encode_str(self.label(), &mut out);
+        out.push('}');
+        out
     }
 }
 
+/// How diagnostics and errors are reported: as text for people to read, or as one JSON object per
+/// line for editors and other tools
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DiagnosticFormat {
+    Human,
+    Json,
+}
+
+/// Formats as the message followed by the position, if there is one, for output where the source
+/// code is not available
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pos {
+            Some(pos) => write!(
+                f,
+                "{}: {} (line {}, column {})",
+                self.label(),
+                self.message,
+                pos.line,
+                pos.column
+            ),
+            None => write!(f, "{}: {}", self.label(), self.message),
+        }
+    }
+}
+
+/// Formats as the error message followed by the position, if there is one, like `Diagnostic`
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.kind {
-            ErrorKind::IOError(ref reason) => write!(f, "IO Error: {}", reason),
-            ErrorKind::LexerError(ref reason) => write!(f, "Parse error: {}", reason),
-            ErrorKind::ParseError(ref reason) => write!(f, "Parse error: {}", reason),
-            ErrorKind::EvalError(ref reason) => write!(f, "Evaluation error: {}", reason),
-            ErrorKind::OutOfMemory => write!(f, "Out of memory!"),
-            ErrorKind::BadAllocationRequest => {
-                write!(f, "An invalid memory size allocation was requested!")
-            }
-            ErrorKind::BoundsError => write!(f, "Indexing bounds error"),
-            ErrorKind::KeyError => write!(f, "Key does not exist in Dict"),
-            ErrorKind::UnhashableError => write!(f, "Attempt to access Dict with unhashable key"),
-            ErrorKind::MutableBorrowError => write!(
+        match self.pos {
+            Some(pos) => write!(
                 f,
-                "Attempt to modify a container that is already mutably borrowed"
+                "{} (line {}, column {})",
+                self.kind, pos.line, pos.column
             ),
+            None => write!(f, "{}", self.kind),
         }
     }
 }
@@ -110,40 +392,69 @@ impl fmt::Display for RuntimeError {
 /// Convert from io::Error
 impl From<io::Error> for RuntimeError {
     fn from(other: io::Error) -> RuntimeError {
-        RuntimeError::new(ErrorKind::IOError(format!("{}", other)))
+        RuntimeError::with_source(ErrorKind::IOError(format!("{}", other)), other)
     }
 }
 
 /// Convert from ReadlineError
 impl From<ReadlineError> for RuntimeError {
     fn from(other: ReadlineError) -> RuntimeError {
-        RuntimeError::new(ErrorKind::IOError(format!("{}", other)))
+        RuntimeError::with_source(ErrorKind::IOError(format!("{}", other)), other)
+    }
+}
+
+/// An error returned by one of the allocator crates, whose error types do not implement `Error`
+/// themselves. It is kept as the source of the RuntimeError it is converted to.
+#[derive(Debug)]
+struct AllocatorError {
+    allocator: &'static str,
+    error: String,
+}
+
+impl AllocatorError {
+    fn new(allocator: &'static str, error: &dyn fmt::Debug) -> AllocatorError {
+        AllocatorError {
+            allocator,
+            error: format!("{:?}", error),
+        }
+    }
+}
+
+impl fmt::Display for AllocatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} returned {}", self.allocator, self.error)
     }
 }
 
+impl Error for AllocatorError {}
+
 /// Convert from BlockError
 impl From<BlockError> for RuntimeError {
     fn from(other: BlockError) -> RuntimeError {
-        match other {
-            BlockError::OOM => RuntimeError::new(ErrorKind::OutOfMemory),
-            BlockError::BadRequest => RuntimeError::new(ErrorKind::BadAllocationRequest),
-        }
+        let kind = match other {
+            BlockError::OOM => ErrorKind::OutOfMemory,
+            BlockError::BadRequest => ErrorKind::BadAllocationRequest,
+        };
+        RuntimeError::with_source(kind, AllocatorError::new("blockalloc", &other))
     }
 }
 
 /// Convert from AllocError
 impl From<AllocError> for RuntimeError {
     fn from(other: AllocError) -> RuntimeError {
-        match other {
-            AllocError::OOM => RuntimeError::new(ErrorKind::OutOfMemory),
-            AllocError::BadRequest => RuntimeError::new(ErrorKind::BadAllocationRequest),
-        }
+        let kind = match other {
+            AllocError::OOM => ErrorKind::OutOfMemory,
+            AllocError::BadRequest => ErrorKind::BadAllocationRequest,
+        };
+        RuntimeError::with_source(kind, AllocatorError::new("stickyimmix", &other))
     }
 }
 
 impl Error for RuntimeError {
-    fn cause(&self) -> Option<&dyn Error> {
-        None
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|source| source.as_ref() as &(dyn Error + 'static))
     }
 }
 
@@ -156,8 +467,8 @@ impl From<RuntimeError> for fmt::Error {
 }
 
 /// Convenience shorthand function for building a SourcePos
-pub fn spos(line: u32, column: u32) -> SourcePos {
-    SourcePos::new(line, column)
+pub fn spos(line: u32, column: u32, offset: u32) -> SourcePos {
+    SourcePos::new(line, column, offset)
 }
 
 /// Convenience shorthand function for building a lexer error
@@ -179,3 +490,56 @@ pub fn err_parser_wpos(pos: SourcePos, reason: &str) -> RuntimeError {
 pub fn err_eval(reason: &str) -> RuntimeError {
     RuntimeError::new(ErrorKind::EvalError(String::from(reason)))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_display_and_source() {
+        let plain = err_eval("oops");
+        assert!(format!("{}", plain) == "Evaluation error: oops");
+        assert!(plain.source().is_none());
+
+        let positioned = err_parser_wpos(spos(3, 4, 20), "Unexpected token");
+        assert!(format!("{}", positioned) == "Parse error: Unexpected token (line 3, column 4)");
+
+        let from_io = RuntimeError::from(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert!(from_io.error_kind() == &ErrorKind::IOError(String::from("no such file")));
+        assert!(format!("{}", from_io.source().unwrap()) == "no such file");
+
+        let from_alloc = RuntimeError::from(AllocError::OOM);
+        assert!(from_alloc == RuntimeError::new(ErrorKind::OutOfMemory));
+        assert!(format!("{}", from_alloc.source().unwrap()) == "stickyimmix returned OOM");
+    }
+
+    #[test]
+    fn error_caret_offset() {
+        assert!(caret_offset("(foo bar)", 5) == 5);
+        // the combining acute accent takes no space
+        assert!(caret_offset("(e\u{301}te x)", 6) == 5);
+        // each ideograph takes two cells
+        assert!(caret_offset("(名前 x)", 4) == 6);
+    }
+
+    #[test]
+    fn diagnostic_json_lines() {
+        let warning = Diagnostic::warning("unused-variable", "Variable x is never used", None);
+        assert!(
+            warning.to_json(None)
+                == "{\"code\":\"unused-variable\",\"message\":\"Variable x is never used\",\
+                    \"file\":null,\"line\":null,\"column\":null,\"severity\":\"warning\"}"
+        );
+
+        let error = Diagnostic::from_error(&err_parser_wpos(spos(2, 7, 12), "Unexpected \")\""));
+        assert!(error.is_error() && error.code() == "parse-error");
+        assert!(
+            error.to_json(Some("dir\\a.evr"))
+                == "{\"code\":\"parse-error\",\"message\":\"Unexpected \\\")\\\"\",\
+                    \"file\":\"dir\\\\a.evr\",\"line\":2,\"column\":7,\"severity\":\"error\"}"
+        );
+
+        let oom = Diagnostic::from_error(&RuntimeError::new(ErrorKind::OutOfMemory));
+        assert!(oom.code() == "out-of-memory" && oom.message() == "Out of memory!");
+    }
+}
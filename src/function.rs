@@ -1,15 +1,18 @@
 use itertools::join;
+use std::cell::Cell;
 use std::fmt;
 
-use crate::array::ArrayU16;
+use crate::array::{ArraySize, ArrayU16};
 use crate::bytecode::ByteCode;
 use crate::containers::{Container, ContainerFromSlice, SliceableContainer, StackContainer};
-use crate::error::RuntimeError;
+use crate::error::{err_eval, RuntimeError, SourcePos};
 use crate::list::List;
 use crate::memory::MutatorView;
 use crate::printer::Print;
 use crate::safeptr::{CellPtr, MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
 use crate::taggedptr::Value;
+use crate::text::Text;
+use crate::vm::FIRST_ARG_REG;
 
 /// A function object type
 #[derive(Clone)]
@@ -18,6 +21,9 @@ pub struct Function {
     name: TaggedCellPtr,
     /// Number of arguments required to activate the function
     arity: u8,
+    /// Size of the register window a call to the function needs: the reserved registers, the
+    /// arguments and every register the code uses
+    max_registers: ArraySize,
     /// Instructions comprising the function code
     code: CellPtr<ByteCode>,
     /// Param names are stored for introspection of a function signature
@@ -26,6 +32,13 @@ pub struct Function {
     /// declaration where nonlocal variables will be found. Needed when creating a closure. May be
     /// nil
     nonlocal_refs: TaggedCellPtr,
+    /// Documentation Text given as the first expression of the function body, or nil
+    doc: TaggedCellPtr,
+    /// Position of the function definition in its source code, if known
+    source_pos: Cell<Option<SourcePos>>,
+    /// Name of the file the function was defined in as Text, or nil if it was not read from a
+    /// file
+    file_name: TaggedCellPtr,
 }
 
 impl Function {
@@ -40,6 +53,7 @@ impl Function {
         param_names: ScopedPtr<'guard, List>,
         code: ScopedPtr<'guard, ByteCode>,
         nonlocal_refs: Option<ScopedPtr<'guard, ArrayU16>>,
+        doc: Option<ScopedPtr<'guard, Text>>,
     ) -> Result<ScopedPtr<'guard, Function>, RuntimeError> {
         // Store a nil ptr if no nonlocal references are given
         let nonlocal_refs = if let Some(refs_ptr) = nonlocal_refs {
@@ -48,15 +62,35 @@ impl Function {
             TaggedCellPtr::new_nil()
         };
 
+        let doc = match doc {
+            Some(text) => TaggedCellPtr::new_with(text.as_tagged(mem)),
+            None => TaggedCellPtr::new_nil(),
+        };
+
+        let arity = param_names.length() as u8;
+        let max_registers = code
+            .register_count(mem)
+            .max(FIRST_ARG_REG as ArraySize + arity as ArraySize);
+
         mem.alloc(Function {
             name: TaggedCellPtr::new_with(name),
-            arity: param_names.length() as u8,
+            arity,
+            max_registers,
             code: CellPtr::new_with(code),
             param_names: CellPtr::new_with(param_names),
             nonlocal_refs: nonlocal_refs,
+            doc,
+            source_pos: Cell::new(None),
+            file_name: TaggedCellPtr::new_nil(),
         })
     }
 
+    /// Record where the function was defined. The file name should be a Text or nil.
+    pub fn set_source(&self, pos: Option<SourcePos>, file_name: TaggedScopedPtr<'_>) {
+        self.source_pos.set(pos);
+        self.file_name.set(file_name);
+    }
+
     /// Return the Function's name as a string slice
     pub fn name<'guard>(&self, guard: &'guard dyn MutatorScope) -> &'guard str {
         let name = self.name.get(guard);
@@ -66,11 +100,51 @@ impl Function {
         }
     }
 
+    /// Return the Function's name Symbol, or nil if it is anonymous
+    pub fn name_symbol<'guard>(&self, guard: &'guard dyn MutatorScope) -> TaggedScopedPtr<'guard> {
+        self.name.get(guard)
+    }
+
     /// Return the number of arguments the Function can take
     pub fn arity(&self) -> u8 {
         self.arity
     }
 
+    /// Return the number of registers a call to the Function needs in its register window
+    pub fn max_registers(&self) -> ArraySize {
+        self.max_registers
+    }
+
+    /// Return the Function's documentation string, if it has one
+    pub fn doc<'guard>(&self, guard: &'guard dyn MutatorScope) -> Option<&'guard str> {
+        match *self.doc.get(guard) {
+            Value::Text(text) => Some(text.as_str(guard)),
+            _ => None,
+        }
+    }
+
+    /// Return the position of the function definition in its source code, if known
+    pub fn source_pos(&self) -> Option<SourcePos> {
+        self.source_pos.get()
+    }
+
+    /// Return the name of the file the function was defined in, if it was read from a file
+    pub fn file_name<'guard>(&self, guard: &'guard dyn MutatorScope) -> Option<&'guard str> {
+        match *self.file_name.get(guard) {
+            Value::Text(text) => Some(text.as_str(guard)),
+            _ => None,
+        }
+    }
+
+    /// Describe where the function was defined, for example "lib.lisp, line 3, column 1", if known
+    pub fn source_location<'guard>(&self, guard: &'guard dyn MutatorScope) -> Option<String> {
+        let pos = self.source_pos()?;
+        Some(match self.file_name(guard) {
+            Some(file_name) => format!("{}, line {}, column {}", file_name, pos.line, pos.column),
+            None => format!("line {}, column {}", pos.line, pos.column),
+        })
+    }
+
     /// Return the names of the parameters that the Function takes
     pub fn param_names<'guard>(&self, guard: &'guard dyn MutatorScope) -> ScopedPtr<'guard, List> {
         self.param_names.get(guard)
@@ -111,7 +185,7 @@ impl Print for Function {
         let params = self.param_names.get(guard);
 
         let mut param_string = String::new();
-        params.access_slice(guard, |items| {
+        params.access_slice(guard, |guard, items| {
             param_string = join(items.iter().map(|item| item.get(guard)), " ")
         });
 
@@ -133,6 +207,84 @@ impl Print for Function {
     }
 }
 
+/// Return the name of a Function or Partial application and the names of the parameters it has
+/// yet to be applied to, or None if the value is neither
+pub fn unapplied_params<'guard>(
+    guard: &'guard dyn MutatorScope,
+    value: TaggedScopedPtr<'guard>,
+) -> Option<(&'guard str, Vec<&'guard str>)> {
+    let (function, used) = match *value {
+        Value::Function(f) => (f, 0),
+        Value::Partial(p) => (p.function(guard), p.used() as usize),
+        _ => return None,
+    };
+
+    let params = function
+        .param_names(guard)
+        .access_slice(guard, |guard, items| {
+            items[used..]
+                .iter()
+                .filter_map(|item| match *item.get(guard) {
+                    Value::Symbol(s) => Some(s.as_str(guard)),
+                    _ => None,
+                })
+                .collect()
+        });
+
+    Some((function.name(guard), params))
+}
+
+/// Return the index of the parameter that each argument of a call with named arguments is passed
+/// as. The first `positional` arguments are passed in order and the rest are named by keywords,
+/// each the name of a parameter with a leading colon. Every parameter before the last one passed
+/// must be given an argument; any after it are left to a partial application.
+pub fn keyword_arg_slots(
+    function_name: &str,
+    param_names: &[&str],
+    positional: usize,
+    keywords: &[&str],
+) -> Result<Vec<usize>, RuntimeError> {
+    if positional > param_names.len() {
+        return Err(err_eval(&format!(
+            "Function {} expected {} arguments, got {} before the named arguments",
+            function_name,
+            param_names.len(),
+            positional
+        )));
+    }
+
+    let mut slots: Vec<usize> = (0..positional).collect();
+    for keyword in keywords {
+        let name = keyword.strip_prefix(':').unwrap_or(keyword);
+        let slot = param_names
+            .iter()
+            .position(|param| *param == name)
+            .ok_or_else(|| {
+                err_eval(&format!(
+                    "Function {} has no parameter named {}",
+                    function_name, name
+                ))
+            })?;
+
+        if slots.contains(&slot) {
+            return Err(err_eval(&format!(
+                "Argument {} to function {} is given more than once",
+                name, function_name
+            )));
+        }
+        slots.push(slot);
+    }
+
+    if let Some(missing) = (0..slots.len()).find(|slot| !slots.contains(slot)) {
+        return Err(err_eval(&format!(
+            "Argument {} to function {} is missing",
+            param_names[missing], function_name
+        )));
+    }
+
+    Ok(slots)
+}
+
 /// A partial function application object type
 #[derive(Clone)]
 pub struct Partial {
@@ -242,7 +394,7 @@ impl Print for Partial {
         let params = function.param_names.get(guard);
 
         let mut param_string = String::new();
-        params.access_slice(guard, |items| {
+        params.access_slice(guard, |guard, items| {
             let start = self.used as usize;
             param_string = join(items[start..].iter().map(|item| item.get(guard)), " ")
         });
@@ -0,0 +1,233 @@
+/// Global bindings.
+///
+/// A Thread's globals Dict maps each global name to a `GlobalCell`, a mutable box holding the
+/// bound value. Binding a name that is already bound sets the value of its existing cell, so a
+/// cell, once found, stays the binding of its name: `LoadGlobal` caches the cell it finds for
+/// each call site, and redefining a function with `def` or `set` takes effect for every caller,
+/// including those that have already been compiled and run.
+use std::fmt;
+use std::ptr;
+
+use crate::containers::{Container, HashIndexedAnyContainer};
+use crate::dict::Dict;
+use crate::error::{err_eval, ErrorKind, RuntimeError};
+use crate::memory::MutatorView;
+use crate::printer::Print;
+use crate::safeptr::{CellPtr, MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+
+/// The binding of a global name
+pub struct GlobalCell {
+    /// The Symbol this cell is bound to
+    name: TaggedCellPtr,
+    /// The value bound to the name
+    value: TaggedCellPtr,
+    /// The globals Dict this cell belongs to
+    globals: CellPtr<Dict>,
+}
+
+impl GlobalCell {
+    /// Allocate a cell binding the given value to a name in the given globals Dict. The cell is
+    /// not added to the Dict.
+    fn alloc<'guard>(
+        mem: &'guard MutatorView,
+        globals: ScopedPtr<'guard, Dict>,
+        name: TaggedScopedPtr<'guard>,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<ScopedPtr<'guard, GlobalCell>, RuntimeError> {
+        mem.alloc(GlobalCell {
+            name: TaggedCellPtr::new_with(name),
+            value: TaggedCellPtr::new_with(value),
+            globals: CellPtr::new_with(globals),
+        })
+    }
+
+    /// Return the name this cell binds
+    pub fn name<'guard>(&self, guard: &'guard dyn MutatorScope) -> TaggedScopedPtr<'guard> {
+        self.name.get(guard)
+    }
+
+    /// Return the bound value
+    pub fn get<'guard>(&self, guard: &'guard dyn MutatorScope) -> TaggedScopedPtr<'guard> {
+        self.value.get(guard)
+    }
+
+    /// Rebind the name to a new value
+    pub fn set(&self, value: TaggedScopedPtr<'_>) {
+        self.value.set(value)
+    }
+
+    /// Return true if this cell binds the given name in the given globals Dict
+    pub fn binds<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        globals: &Dict,
+        name: TaggedScopedPtr<'guard>,
+    ) -> bool {
+        self.name.get(guard) == name && ptr::eq(&*self.globals.get(guard), globals)
+    }
+}
+
+impl Print for GlobalCell {
+    /// Prints the name the cell binds, not the value, which may refer back to the cell
+    fn print<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "(GlobalCell {})", self.name.get(guard))
+    }
+}
+
+/// Return the cell binding a name in the given globals Dict, if the name is bound
+pub fn lookup_global_cell<'guard>(
+    mem: &'guard MutatorView,
+    globals: ScopedPtr<'guard, Dict>,
+    name: TaggedScopedPtr<'guard>,
+) -> Result<Option<ScopedPtr<'guard, GlobalCell>>, RuntimeError> {
+    // a Dict has no storage to look in until something is bound in it
+    if globals.length() == 0 {
+        return Ok(None);
+    }
+
+    let binding = match globals.lookup(mem, name) {
+        Ok(binding) => binding,
+        Err(e) if *e.error_kind() == ErrorKind::KeyError => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    match *binding {
+        Value::GlobalCell(cell) => Ok(Some(cell)),
+        other => Err(err_eval(&format!(
+            "Global {} is bound to a {} rather than a global cell",
+            name,
+            other.type_name()
+        ))),
+    }
+}
+
+/// Return the value bound to a name in the given globals Dict
+pub fn lookup_global<'guard>(
+    mem: &'guard MutatorView,
+    globals: ScopedPtr<'guard, Dict>,
+    name: TaggedScopedPtr<'guard>,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    match lookup_global_cell(mem, globals, name)? {
+        Some(cell) => Ok(cell.get(mem)),
+        None => Err(err_eval(&format!(
+            "Symbol {} is not bound to a value",
+            name
+        ))),
+    }
+}
+
+/// Bind a value to a name in the given globals Dict, updating the name's cell if it is already
+/// bound, and return the cell
+pub fn bind_global<'guard>(
+    mem: &'guard MutatorView,
+    globals: ScopedPtr<'guard, Dict>,
+    name: TaggedScopedPtr<'guard>,
+    value: TaggedScopedPtr<'guard>,
+) -> Result<ScopedPtr<'guard, GlobalCell>, RuntimeError> {
+    match lookup_global_cell(mem, globals, name)? {
+        Some(cell) => {
+            cell.set(value);
+            Ok(cell)
+        }
+
+        None => {
+            let cell = GlobalCell::alloc(mem, globals, name, value)?;
+            globals.assoc(mem, name, cell.as_tagged(mem))?;
+            Ok(cell)
+        }
+    }
+}
+
+/// Return every (name, value) binding in the given globals Dict, in no particular order
+pub fn global_items<'guard>(
+    guard: &'guard dyn MutatorScope,
+    globals: ScopedPtr<'guard, Dict>,
+) -> Vec<(TaggedScopedPtr<'guard>, TaggedScopedPtr<'guard>)> {
+    globals
+        .items(guard)
+        .into_iter()
+        .map(|(name, cell)| match *cell {
+            Value::GlobalCell(cell) => (name, cell.get(guard)),
+            _ => (name, cell),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+    use crate::vm::Thread;
+
+    #[test]
+    fn redefinition_updates_the_global_cell() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                eval("(def f () 'first)")?;
+                eval("(def g () (f))")?;
+                assert!(eval("(g)")? == mem.lookup_sym("first"));
+
+                let name = mem.lookup_sym("f");
+                let cell = lookup_global_cell(mem, t.globals(mem), name)?.unwrap();
+
+                // the cell cached by g's call site is updated in place
+                eval("(def f () 'second)")?;
+                assert!(eval("(g)")? == mem.lookup_sym("second"));
+                eval("(set 'f (lambda () 'third))")?;
+                assert!(eval("(g)")? == mem.lookup_sym("third"));
+
+                let same = lookup_global_cell(mem, t.globals(mem), name)?.unwrap();
+                assert!(ptr::eq(&*cell, &*same));
+                assert!(format!("{}", cell.as_tagged(mem)) == "(GlobalCell f)");
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn cached_cells_belong_to_one_thread() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let a = Thread::alloc(mem)?;
+                let b = Thread::alloc(mem)?;
+                a.set_global(mem, "x", mem.lookup_sym("a"))?;
+                b.set_global(mem, "x", mem.lookup_sym("b"))?;
+
+                // the same code run by Threads with separate globals sees each Thread's binding
+                let code = compile(mem, parse(mem, "x")?)?;
+                assert!(a.quick_vm_eval(mem, code)? == mem.lookup_sym("a"));
+                assert!(b.quick_vm_eval(mem, code)? == mem.lookup_sym("b"));
+                assert!(a.quick_vm_eval(mem, code)? == mem.lookup_sym("a"));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -1,9 +1,98 @@
-/// Scope-guard limited Hashable trait type
+/// Scope-guard limited Hashable trait type, and hashing of values
 use std::hash::Hasher;
 
-use crate::safeptr::MutatorScope;
+use fnv::FnvHasher;
+
+use crate::error::{ErrorKind, RuntimeError};
+use crate::safeptr::{MutatorScope, TaggedScopedPtr};
+use crate::taggedptr::Value;
 
 /// Similar to Hash but for use in a mutator lifetime-limited scope
 pub trait Hashable {
     fn hash<'guard, H: Hasher>(&self, _guard: &'guard dyn MutatorScope, hasher: &mut H);
 }
+
+/// Return the runtime hash of a value. Symbols and Text hash their names and contents through
+/// `Hashable`, an integer is its own hash. Other values are not hashable.
+pub fn hash_value<'guard>(
+    guard: &'guard dyn MutatorScope,
+    value: TaggedScopedPtr<'guard>,
+) -> Result<u64, RuntimeError> {
+    match *value {
+        Value::Symbol(s) => {
+            let mut hasher = FnvHasher::default();
+            s.hash(guard, &mut hasher);
+            Ok(hasher.finish())
+        }
+        Value::Text(t) => {
+            let mut hasher = FnvHasher::default();
+            t.hash(guard, &mut hasher);
+            Ok(hasher.finish())
+        }
+        Value::Number(n) => Ok(n as u64),
+        _ => Err(RuntimeError::new(ErrorKind::UnhashableError)),
+    }
+}
+
+/// The most Pairs `structural_hash()` will visit, which also stops it on a cyclic list
+const MAX_STRUCTURE_PAIRS: usize = 4096;
+
+fn hash_structure<'guard, H: Hasher>(
+    guard: &'guard dyn MutatorScope,
+    value: TaggedScopedPtr<'guard>,
+    hasher: &mut H,
+    budget: &mut usize,
+) -> Option<()> {
+    match *value {
+        Value::Nil => hasher.write_u8(0),
+
+        Value::Pair(pair) => {
+            if *budget == 0 {
+                return None;
+            }
+            *budget -= 1;
+
+            hasher.write_u8(1);
+            hash_structure(guard, pair.first.get(guard), hasher, budget)?;
+            hash_structure(guard, pair.second.get(guard), hasher, budget)?;
+        }
+
+        _ => {
+            hasher.write_u8(2);
+            hasher.write_u64(hash_value(guard, value).ok()?);
+        }
+    }
+
+    Some(())
+}
+
+/// Return a hash of the structure of a Pair list of hashable values, for hash-consing: values
+/// that are `structurally_equal()` hash the same. Returns None if the value contains anything
+/// that is not hashable or is too large to be worth sharing.
+pub(crate) fn structural_hash<'guard>(
+    guard: &'guard dyn MutatorScope,
+    value: TaggedScopedPtr<'guard>,
+) -> Option<u64> {
+    let mut hasher = FnvHasher::default();
+    let mut budget = MAX_STRUCTURE_PAIRS;
+    hash_structure(guard, value, &mut hasher, &mut budget)?;
+    Some(hasher.finish())
+}
+
+/// Return true if two values have the same structure: Pairs are compared element by element, Text
+/// by content and everything else by identity. Only call this for values that have a
+/// `structural_hash()`, which bounds their size.
+pub(crate) fn structurally_equal<'guard>(
+    guard: &'guard dyn MutatorScope,
+    a: TaggedScopedPtr<'guard>,
+    b: TaggedScopedPtr<'guard>,
+) -> bool {
+    match (*a, *b) {
+        (Value::Pair(x), Value::Pair(y)) => {
+            structurally_equal(guard, x.first.get(guard), y.first.get(guard))
+                && structurally_equal(guard, x.second.get(guard), y.second.get(guard))
+        }
+        (Value::Text(x), Value::Text(y)) => x.as_str(guard) == y.as_str(guard),
+        _ => a == b,
+    }
+}
@@ -8,15 +8,21 @@ use crate::array::{ArrayU16, ArrayU32, ArrayU8};
 use crate::bytecode::{ArrayOpcode, ByteCode, InstructionStream};
 use crate::dict::Dict;
 use crate::function::{Function, Partial};
+use crate::global::GlobalCell;
 use crate::list::List;
 use crate::memory::HeapStorage;
-use crate::number::NumberObject;
+use crate::native::NativeFunction;
+use crate::number::{FloatObject, NumberObject};
 use crate::pair::Pair;
+use crate::persistent::{PersistentList, PersistentMap};
 use crate::pointerops::{AsNonNull, Tagged};
+use crate::port::Port;
+use crate::promise::Promise;
 use crate::symbol::Symbol;
 use crate::taggedptr::FatPtr;
 use crate::text::Text;
 use crate::vm::{CallFrameList, Thread, Upvalue};
+use crate::weak::WeakRef;
 
 /// Recognized heap-allocated types.
 /// This should represent every type native to the runtime with the exception of tagged pointer inline value types.
@@ -41,6 +47,14 @@ pub enum TypeList {
     CallFrameList,
     Thread,
     Upvalue,
+    NativeFunction,
+    Port,
+    WeakRef,
+    GlobalCell,
+    PersistentList,
+    PersistentMap,
+    Promise,
+    FloatObject,
 }
 
 // Mark this as a Stickyimmix type-identifier type
@@ -68,6 +82,9 @@ impl ObjectHeader {
             TypeList::NumberObject => {
                 FatPtr::NumberObject(RawPtr::untag(object_addr.cast::<NumberObject>()))
             }
+            TypeList::FloatObject => {
+                FatPtr::FloatObject(RawPtr::untag(object_addr.cast::<FloatObject>()))
+            }
             TypeList::Text => FatPtr::Text(RawPtr::untag(object_addr.cast::<Text>())),
             TypeList::ArrayU8 => FatPtr::ArrayU8(RawPtr::untag(object_addr.cast::<ArrayU8>())),
             TypeList::ArrayU16 => FatPtr::ArrayU16(RawPtr::untag(object_addr.cast::<ArrayU16>())),
@@ -77,6 +94,21 @@ impl ObjectHeader {
             TypeList::Function => FatPtr::Function(RawPtr::untag(object_addr.cast::<Function>())),
             TypeList::Partial => FatPtr::Partial(RawPtr::untag(object_addr.cast::<Partial>())),
             TypeList::Upvalue => FatPtr::Upvalue(RawPtr::untag(object_addr.cast::<Upvalue>())),
+            TypeList::NativeFunction => {
+                FatPtr::NativeFunction(RawPtr::untag(object_addr.cast::<NativeFunction>()))
+            }
+            TypeList::Port => FatPtr::Port(RawPtr::untag(object_addr.cast::<Port>())),
+            TypeList::WeakRef => FatPtr::WeakRef(RawPtr::untag(object_addr.cast::<WeakRef>())),
+            TypeList::GlobalCell => {
+                FatPtr::GlobalCell(RawPtr::untag(object_addr.cast::<GlobalCell>()))
+            }
+            TypeList::PersistentList => {
+                FatPtr::PersistentList(RawPtr::untag(object_addr.cast::<PersistentList>()))
+            }
+            TypeList::PersistentMap => {
+                FatPtr::PersistentMap(RawPtr::untag(object_addr.cast::<PersistentMap>()))
+            }
+            TypeList::Promise => FatPtr::Promise(RawPtr::untag(object_addr.cast::<Promise>())),
 
             _ => panic!("Invalid ObjectHeader type tag {:?}!", self.type_id),
         }
@@ -143,6 +175,7 @@ macro_rules! declare_allocobject {
 declare_allocobject!(Symbol, Symbol);
 declare_allocobject!(Pair, Pair);
 declare_allocobject!(NumberObject, NumberObject);
+declare_allocobject!(FloatObject, FloatObject);
 declare_allocobject!(Text, Text);
 declare_allocobject!(List, List);
 declare_allocobject!(ArrayU8, ArrayU8);
@@ -157,3 +190,10 @@ declare_allocobject!(Partial, Partial);
 declare_allocobject!(CallFrameList, CallFrameList);
 declare_allocobject!(Thread, Thread);
 declare_allocobject!(Upvalue, Upvalue);
+declare_allocobject!(NativeFunction, NativeFunction);
+declare_allocobject!(Port, Port);
+declare_allocobject!(WeakRef, WeakRef);
+declare_allocobject!(GlobalCell, GlobalCell);
+declare_allocobject!(PersistentList, PersistentList);
+declare_allocobject!(PersistentMap, PersistentMap);
+declare_allocobject!(Promise, Promise);
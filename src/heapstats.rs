@@ -0,0 +1,206 @@
+//! Heap allocation statistics, enabled by the `heap-stats` feature, to guide the tuning of the
+//! collector.
+//!
+//! Every allocation is counted by type and size, with the number of the mutation scope it was made
+//! in. Each `Memory::mutate()` call is a mutation scope, which is the unit of object lifetime until
+//! there is a collector to measure it: an object's age is the number of scopes that have begun
+//! since the one it was allocated in. Objects that are young when the report is made were
+//! allocated by the most recent work, objects that are old have survived many scopes. Nothing is
+//! freed yet, so every object allocated appears in the report.
+//!
+//! If the `EVALRUS_HEAP_STATS` environment variable is set to anything other than an empty string
+//! or "0", the report is printed to stderr when the Memory is dropped.
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::headers::TypeList;
+
+/// Environment variable that enables the heap statistics report when a Memory is dropped
+pub const HEAP_STATS_ENV_VAR: &str = "EVALRUS_HEAP_STATS";
+
+/// Ages are counted in buckets of 0, 1, 2-3, 4-7, 8-15 and 16 or more scopes
+pub const AGE_BUCKETS: usize = 6;
+
+/// Return the age bucket of an object that has lived for the given number of scopes
+fn age_bucket(age: usize) -> usize {
+    match age {
+        0 => 0,
+        age => ((usize::BITS - age.leading_zeros()) as usize).min(AGE_BUCKETS - 1),
+    }
+}
+
+/// One allocation
+struct Allocation {
+    type_id: TypeList,
+    bytes: usize,
+    /// The mutation scope the allocation was made in
+    scope: usize,
+}
+
+/// The allocations of one type
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TypeStats {
+    pub allocations: usize,
+    /// Total size in bytes, excluding headers
+    pub bytes: usize,
+    /// Size in bytes of the largest allocation
+    pub largest: usize,
+    /// Count of allocations in each age bucket
+    pub ages: [usize; AGE_BUCKETS],
+}
+
+/// The statistics gathered so far, by type name, for example "Pair" or "Array" for array
+/// backing storage
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeapStatsReport {
+    /// Count of mutation scopes begun
+    pub scopes: usize,
+    pub types: BTreeMap<String, TypeStats>,
+}
+
+impl fmt::Display for HeapStatsReport {
+    /// A table with one line per type, most bytes first, and the count of allocations in each
+    /// age bucket
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap statistics after {} mutation scopes", self.scopes)?;
+        writeln!(
+            f,
+            "{:<18} {:>9} {:>10} {:>6} {:>7} | age {:>7} {:>7} {:>7} {:>7} {:>7} {:>7}",
+            "type", "allocs", "bytes", "mean", "max", "0", "1", "2-3", "4-7", "8-15", "16+"
+        )?;
+
+        let mut types: Vec<(&String, &TypeStats)> = self.types.iter().collect();
+        types.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(b.0)));
+
+        for (name, stats) in types {
+            write!(
+                f,
+                "{:<18} {:>9} {:>10} {:>6} {:>7} |    ",
+                name,
+                stats.allocations,
+                stats.bytes,
+                stats.bytes / stats.allocations.max(1),
+                stats.largest
+            )?;
+            for count in &stats.ages {
+                write!(f, " {:>7}", count)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The allocation record of a heap
+#[derive(Default)]
+pub struct HeapStats {
+    /// Count of mutation scopes begun, which numbers the current scope
+    scopes: Cell<usize>,
+    allocations: RefCell<Vec<Allocation>>,
+}
+
+impl HeapStats {
+    pub fn new() -> HeapStats {
+        HeapStats::default()
+    }
+
+    /// Begin a new mutation scope
+    pub fn enter_scope(&self) {
+        self.scopes.set(self.scopes.get() + 1);
+    }
+
+    /// Record an allocation in the current scope
+    pub fn record(&self, type_id: TypeList, bytes: usize) {
+        self.allocations.borrow_mut().push(Allocation {
+            type_id,
+            bytes,
+            scope: self.scopes.get(),
+        });
+    }
+
+    /// Summarize the allocations recorded so far by type
+    pub fn report(&self) -> HeapStatsReport {
+        let scopes = self.scopes.get();
+        let mut report = HeapStatsReport {
+            scopes,
+            types: BTreeMap::new(),
+        };
+
+        for allocation in self.allocations.borrow().iter() {
+            let stats = report
+                .types
+                .entry(format!("{:?}", allocation.type_id))
+                .or_default();
+
+            stats.allocations += 1;
+            stats.bytes += allocation.bytes;
+            stats.largest = stats.largest.max(allocation.bytes);
+            stats.ages[age_bucket(scopes - allocation.scope)] += 1;
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::RuntimeError;
+    use crate::memory::{Memory, Mutator, MutatorView};
+    use crate::pair::Pair;
+
+    #[test]
+    fn heap_stats_age_buckets() {
+        assert!(age_bucket(0) == 0);
+        assert!(age_bucket(1) == 1);
+        assert!(age_bucket(3) == 2);
+        assert!(age_bucket(4) == 3);
+        assert!(age_bucket(15) == 4);
+        assert!(age_bucket(16) == 5);
+        assert!(age_bucket(1000) == 5);
+    }
+
+    #[test]
+    fn heap_stats_count_allocations_by_scope() {
+        struct Allocate {}
+        impl Mutator for Allocate {
+            type Input = usize;
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, pairs: usize) -> Result<(), RuntimeError> {
+                for _ in 0..pairs {
+                    mem.alloc(Pair::new())?;
+                }
+                mem.text("hello")?;
+                Ok(())
+            }
+        }
+
+        let mem = Memory::new();
+        mem.mutate(&Allocate {}, 3).unwrap();
+        for _ in 0..4 {
+            mem.mutate(&Allocate {}, 0).unwrap();
+        }
+        mem.mutate(&Allocate {}, 2).unwrap();
+
+        let report = mem.heap_stats();
+        assert!(report.scopes == 6);
+
+        // three pairs from five scopes ago and two from the last
+        let pairs = report.types["Pair"];
+        assert!(pairs.allocations == 5);
+        assert!(pairs.largest == std::mem::size_of::<Pair>());
+        assert!(pairs.bytes == 5 * pairs.largest);
+        assert!(pairs.ages == [2, 0, 0, 3, 0, 0]);
+
+        // a Text was allocated in every scope
+        assert!(report.types["Text"].allocations == 6);
+        assert!(report.types["Text"].ages == [1, 1, 2, 2, 0, 0]);
+
+        let table = format!("{}", report);
+        assert!(table.starts_with("heap statistics after 6 mutation scopes\n"));
+        assert!(table.lines().any(|line| line.starts_with("Pair ")));
+    }
+}
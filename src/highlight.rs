@@ -0,0 +1,293 @@
+/// REPL line editing support: parentheses colored by nesting depth, the parenthesis matching the
+/// one at the cursor shown in reverse video, and input with an unmatched close parenthesis held
+/// back from submission. Input that is merely incomplete is submitted, and the REPL prompts for
+/// the rest of it: see `is_incomplete()`.
+///
+/// Parentheses are found by a scan that knows only enough of the lexer's rules to skip over text
+/// and quoted symbols, since input that is still being typed is rarely valid.
+use std::borrow::Cow;
+
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::Helper;
+
+/// Terminal colors cycled through for successive nesting depths
+const DEPTH_COLORS: [&str; 4] = ["\x1b[1;34m", "\x1b[1;32m", "\x1b[1;33m", "\x1b[1;35m"];
+
+/// Terminal color for a close parenthesis that has no open parenthesis
+const UNMATCHED_COLOR: &str = "\x1b[1;31m";
+
+/// Terminal attribute for the parenthesis matching the one at the cursor
+const MATCHING: &str = "\x1b[7m";
+
+/// Terminal attribute reset
+const RESET: &str = "\x1b[0m";
+
+/// A parenthesis found by `scan()`
+#[derive(Debug, PartialEq)]
+pub struct Paren {
+    /// Byte offset of the parenthesis in the input
+    pub offset: usize,
+    /// Nesting depth, 0 for the outermost parentheses
+    pub depth: usize,
+    /// The byte offset of the matching parenthesis, if there is one
+    pub matching: Option<usize>,
+}
+
+/// Whether input is ready to be evaluated
+#[derive(Debug, PartialEq)]
+pub enum Balance {
+    /// Every parenthesis, text and quoted symbol is closed
+    Complete,
+    /// A parenthesis, text or quoted symbol is still open, more input is expected
+    Incomplete,
+    /// The close parenthesis at the given byte offset has no open parenthesis
+    Unmatched(usize),
+}
+
+/// The lexical context of a character
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Code,
+    Text,
+    QuotedSymbol,
+    QuotedSymbolEscape,
+}
+
+/// Find every parenthesis in the input, skipping those in text and quoted symbols, and return
+/// them in order along with the balance of the input
+pub fn scan(input: &str) -> (Vec<Paren>, Balance) {
+    let mut parens: Vec<Paren> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    let mut unmatched = None;
+    let mut state = State::Code;
+
+    for (offset, c) in input.char_indices() {
+        state = match (state, c) {
+            (State::Code, '(') => {
+                open.push(parens.len());
+                parens.push(Paren {
+                    offset,
+                    depth: open.len() - 1,
+                    matching: None,
+                });
+                State::Code
+            }
+
+            (State::Code, ')') => {
+                match open.pop() {
+                    Some(index) => {
+                        parens[index].matching = Some(offset);
+                        parens.push(Paren {
+                            offset,
+                            depth: open.len(),
+                            matching: Some(parens[index].offset),
+                        });
+                    }
+                    None => {
+                        unmatched = unmatched.or(Some(offset));
+                        parens.push(Paren {
+                            offset,
+                            depth: 0,
+                            matching: None,
+                        });
+                    }
+                }
+                State::Code
+            }
+
+            (State::Code, '"') => State::Text,
+            (State::Code, '|') => State::QuotedSymbol,
+            (State::Text, '"') => State::Code,
+            (State::QuotedSymbol, '|') => State::Code,
+            (State::QuotedSymbol, '\\') => State::QuotedSymbolEscape,
+            (State::QuotedSymbolEscape, _) => State::QuotedSymbol,
+            (state, _) => state,
+        };
+    }
+
+    let balance = match unmatched {
+        Some(offset) => Balance::Unmatched(offset),
+        None if !open.is_empty() || state != State::Code => Balance::Incomplete,
+        None => Balance::Complete,
+    };
+
+    (parens, balance)
+}
+
+/// Return true if the input is an expression that is not yet complete, so more lines should be
+/// read before it is evaluated. REPL commands other than `:d expression` are always complete.
+pub fn is_incomplete(input: &str) -> bool {
+    if input.starts_with(':') && !input.starts_with(":d ") {
+        return false;
+    }
+    scan(input).1 == Balance::Incomplete
+}
+
+/// Return the input with parentheses colored by depth. If there is a parenthesis just before or
+/// at the cursor position, it and its match are shown in reverse video.
+pub fn highlight_parens(input: &str, cursor: usize) -> String {
+    let (parens, _) = scan(input);
+
+    let at_cursor = parens
+        .iter()
+        .find(|paren| paren.offset + 1 == cursor)
+        .or_else(|| parens.iter().find(|paren| paren.offset == cursor));
+
+    let highlighted: Vec<usize> = match at_cursor {
+        Some(Paren {
+            offset,
+            matching: Some(matching),
+            ..
+        }) => vec![*offset, *matching],
+        _ => Vec::new(),
+    };
+
+    let mut output = String::with_capacity(input.len() * 2);
+    let mut next = 0;
+
+    for paren in &parens {
+        output.push_str(&input[next..paren.offset]);
+
+        let color = match paren.matching {
+            Some(_) => DEPTH_COLORS[paren.depth % DEPTH_COLORS.len()],
+            None => UNMATCHED_COLOR,
+        };
+        output.push_str(color);
+        if highlighted.contains(&paren.offset) {
+            output.push_str(MATCHING);
+        }
+        output.push_str(&input[paren.offset..paren.offset + 1]);
+        output.push_str(RESET);
+
+        next = paren.offset + 1;
+    }
+
+    output.push_str(&input[next..]);
+    output
+}
+
+/// The rustyline helper for the REPL
+pub struct ReplHelper {}
+
+impl ReplHelper {
+    pub fn new() -> ReplHelper {
+        ReplHelper {}
+    }
+}
+
+impl Default for ReplHelper {
+    fn default() -> ReplHelper {
+        ReplHelper::new()
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+}
+
+impl Hinter for ReplHelper {}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        if line.contains(['(', ')']) {
+            Cow::Owned(highlight_parens(line, pos))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    fn highlight_char(&self, line: &str, _pos: usize) -> bool {
+        // redraw on cursor movement so that the matching parenthesis follows the cursor
+        line.contains(['(', ')'])
+    }
+}
+
+impl Validator for ReplHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        // REPL commands are not expressions
+        if ctx.input().starts_with(':') && !ctx.input().starts_with(":d ") {
+            return Ok(ValidationResult::Valid(None));
+        }
+
+        let (_, balance) = scan(ctx.input());
+
+        Ok(match balance {
+            Balance::Complete | Balance::Incomplete => ValidationResult::Valid(None),
+            Balance::Unmatched(offset) => ValidationResult::Invalid(Some(format!(
+                "  (unmatched close parenthesis at column {})",
+                ctx.input()[..offset].chars().count()
+            ))),
+        })
+    }
+}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scan_parens_and_balance() {
+        let (parens, balance) = scan("(a (b) \"(\" |)|)");
+        assert!(balance == Balance::Complete);
+        assert!(
+            parens
+                == vec![
+                    Paren {
+                        offset: 0,
+                        depth: 0,
+                        matching: Some(14)
+                    },
+                    Paren {
+                        offset: 3,
+                        depth: 1,
+                        matching: Some(5)
+                    },
+                    Paren {
+                        offset: 5,
+                        depth: 1,
+                        matching: Some(3)
+                    },
+                    Paren {
+                        offset: 14,
+                        depth: 0,
+                        matching: Some(0)
+                    },
+                ]
+        );
+
+        assert!(scan("(a (b)").1 == Balance::Incomplete);
+        assert!(scan("(print \"a)").1 == Balance::Incomplete);
+        assert!(scan("'|a\\|").1 == Balance::Incomplete);
+        assert!(scan("(a)) (b").1 == Balance::Unmatched(3));
+        assert!(scan("").1 == Balance::Complete);
+
+        assert!(is_incomplete("(a\n(b)"));
+        assert!(!is_incomplete("(a\n(b))"));
+        assert!(!is_incomplete(":save my(file"));
+        assert!(is_incomplete(":d (a"));
+    }
+
+    #[test]
+    fn highlight_matching_paren() {
+        let blue = DEPTH_COLORS[0];
+        let green = DEPTH_COLORS[1];
+
+        // cursor after the inner close paren: it and its match are reversed
+        assert!(
+            highlight_parens("(a (b))", 6)
+                == format!(
+                    "{}({}a {}{}({}b{}{}){}{}){}",
+                    blue, RESET, green, MATCHING, RESET, green, MATCHING, RESET, blue, RESET
+                )
+        );
+
+        // an unmatched close paren is shown in the error color
+        assert!(highlight_parens("a)", 0) == format!("a{}){}", UNMATCHED_COLOR, RESET));
+        assert!(highlight_parens("no parens", 0) == "no parens");
+    }
+}
@@ -0,0 +1,798 @@
+//! Saving the global bindings of a Thread to an image file and restoring them in a later session.
+//!
+//! An image holds the name and value of each global. Data is written out by structure and
+//! functions by their instructions, each `LoadLiteral` followed by the value of its literal so
+//! that the literal can be added to the literals of the loaded function, interned in the constant
+//! pool of the Memory the image is loaded into.
+//! Shared structure is not preserved: a value that is referred to twice is written out twice.
+//!
+//! Native functions and ports are not saved, a new Thread binds its own. Globals bound to any
+//! other value that can't be written out, such as a closure over local variables, are skipped and
+//! reported.
+use std::collections::HashMap;
+use std::fs;
+
+use crate::array::{ArrayU16, ArrayU32, ArrayU8};
+use crate::bytecode::{ByteCode, Opcode, SourceSpan};
+use crate::containers::{
+    Container, HashIndexedAnyContainer, SliceableContainer, StackAnyContainer, StackContainer,
+};
+use crate::convert::ToValue;
+use crate::dict::Dict;
+use crate::error::{err_eval, ErrorKind, RuntimeError, SourcePos};
+use crate::function::{Function, Partial};
+use crate::global::global_items;
+use crate::list::List;
+use crate::memory::MutatorView;
+use crate::pair::cons;
+use crate::safeptr::{ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::{TaggedPtr, Value};
+use crate::vm::Thread;
+
+/// Every image file starts with this line, which includes the format version
+const MAGIC: &[u8] = b"evalrus image 2\n";
+
+/// Maximum nesting depth of a saved value. Pair lists are written iteratively and don't count
+/// towards it, except for the values they contain.
+const MAX_DEPTH: usize = 512;
+
+/// Maximum length of a saved Pair list, beyond which it is assumed to be cyclic
+const MAX_PAIR_LIST: usize = 1 << 24;
+
+// Value type tags
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_CHAR: u8 = 2;
+const TAG_FLOAT: u8 = 3;
+const TAG_NUMBER: u8 = 4;
+const TAG_SYMBOL: u8 = 5;
+const TAG_TEXT: u8 = 6;
+const TAG_PAIR: u8 = 7;
+const TAG_LIST: u8 = 8;
+const TAG_ARRAY_U8: u8 = 9;
+const TAG_ARRAY_U16: u8 = 10;
+const TAG_ARRAY_U32: u8 = 11;
+const TAG_DICT: u8 = 12;
+const TAG_FUNCTION: u8 = 13;
+const TAG_PARTIAL: u8 = 14;
+
+/// The outcome of saving an image
+#[derive(Debug)]
+pub struct SavedImage {
+    /// Number of globals written to the image
+    pub saved: usize,
+    /// Name of each global that could not be saved, with the reason
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Write the globals of the Thread to an image file at the given path
+pub fn save_image(
+    mem: &MutatorView,
+    thread: &Thread,
+    path: &str,
+) -> Result<SavedImage, RuntimeError> {
+    let mut globals = Vec::new();
+    for (name, value) in global_items(mem, thread.globals(mem)) {
+        let name = match *name {
+            Value::Symbol(s) => s.as_str(mem),
+            _ => continue,
+        };
+
+        match *value {
+            Value::NativeFunction(_) | Value::Port(_) => (),
+            _ => globals.push((name, value)),
+        }
+    }
+    globals.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut image = Writer::new(mem);
+    let mut saved = Vec::new();
+    let mut skipped = Vec::new();
+
+    for (name, value) in globals {
+        // write each value separately so that one that fails doesn't leave a partial entry
+        let mut writer = Writer::new(mem);
+        match writer.value(value, 0) {
+            Ok(()) => saved.push((name, writer.bytes)),
+            Err(e) => skipped.push((String::from(name), reason(&e))),
+        }
+    }
+
+    image.bytes.extend_from_slice(MAGIC);
+    image.u32(saved.len() as u32);
+    for (name, bytes) in &saved {
+        image.str(name);
+        image.bytes.extend_from_slice(bytes);
+    }
+
+    fs::write(path, &image.bytes)?;
+
+    Ok(SavedImage {
+        saved: saved.len(),
+        skipped,
+    })
+}
+
+/// Read an image file and bind each global it holds in the Thread, replacing existing bindings
+/// of the same names. Nothing is bound if the image can't be read. Returns the number of globals
+/// bound.
+pub fn load_image(mem: &MutatorView, thread: &Thread, path: &str) -> Result<usize, RuntimeError> {
+    let bytes = fs::read(path)?;
+
+    if !bytes.starts_with(MAGIC) {
+        return Err(err_eval(&format!("{} is not an evalrus image", path)));
+    }
+
+    let mut reader = Reader {
+        bytes: &bytes,
+        pos: MAGIC.len(),
+    };
+
+    let count = reader.u32()?;
+    let mut globals = Vec::new();
+    for _ in 0..count {
+        let name = String::from(reader.str()?);
+        let value = reader.value(mem, 0)?;
+        globals.push((name, value));
+    }
+
+    if reader.pos != bytes.len() {
+        return Err(corrupt("unexpected data after the last global"));
+    }
+
+    for (name, value) in &globals {
+        thread.set_global(mem, name, *value)?;
+    }
+
+    Ok(globals.len())
+}
+
+/// Describe why a value could not be saved
+fn reason(error: &RuntimeError) -> String {
+    match error.error_kind() {
+        ErrorKind::EvalError(reason) => reason.clone(),
+        _ => format!("{}", error),
+    }
+}
+
+/// Build an error for an image that can't be decoded
+fn corrupt(reason: &str) -> RuntimeError {
+    err_eval(&format!("Image is corrupt: {}", reason))
+}
+
+/// Encodes values into a byte buffer
+struct Writer<'guard> {
+    mem: &'guard MutatorView<'guard>,
+    bytes: Vec<u8>,
+}
+
+impl<'guard> Writer<'guard> {
+    fn new(mem: &'guard MutatorView<'guard>) -> Writer<'guard> {
+        Writer {
+            mem,
+            bytes: Vec::new(),
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn pos(&mut self, pos: SourcePos) {
+        self.u32(pos.line);
+        self.u32(pos.column);
+        self.u32(pos.offset);
+    }
+
+    fn value(&mut self, value: TaggedScopedPtr<'guard>, depth: usize) -> Result<(), RuntimeError> {
+        if depth > MAX_DEPTH {
+            return Err(err_eval(
+                "Value is nested too deeply to be saved, it may be cyclic",
+            ));
+        }
+
+        let mem = self.mem;
+
+        match *value {
+            Value::Nil => self.u8(TAG_NIL),
+
+            Value::Bool(b) => {
+                self.u8(TAG_BOOL);
+                self.u8(b as u8);
+            }
+
+            Value::Char(c) => {
+                self.u8(TAG_CHAR);
+                self.u32(c as u32);
+            }
+
+            Value::Float(n) => {
+                self.u8(TAG_FLOAT);
+                self.i64(n.to_bits() as i64);
+            }
+
+            Value::Number(n) => {
+                self.u8(TAG_NUMBER);
+                self.i64(n as i64);
+            }
+
+            Value::Symbol(s) => {
+                self.u8(TAG_SYMBOL);
+                self.str(s.as_str(mem));
+            }
+
+            Value::Text(t) => {
+                self.u8(TAG_TEXT);
+                self.str(t.as_str(mem));
+            }
+
+            Value::Pair(_) => {
+                // follow the chain of pairs iteratively so that long lists can be saved
+                let mut items = Vec::new();
+                let mut tail = value;
+                while let Value::Pair(pair) = *tail {
+                    if items.len() == MAX_PAIR_LIST {
+                        return Err(err_eval("List is too long to be saved, it may be cyclic"));
+                    }
+                    items.push(pair.first.get(mem));
+                    tail = pair.second.get(mem);
+                }
+
+                self.u8(TAG_PAIR);
+                self.u32(items.len() as u32);
+                for item in items {
+                    self.value(item, depth + 1)?;
+                }
+                self.value(tail, depth + 1)?;
+            }
+
+            Value::List(list) => {
+                let mut items = Vec::new();
+                list.access_slice(mem, |guard, slice| {
+                    items.extend(slice.iter().map(|item| item.get(guard)))
+                });
+
+                self.u8(TAG_LIST);
+                self.u32(items.len() as u32);
+                for item in items {
+                    self.value(item, depth + 1)?;
+                }
+            }
+
+            Value::ArrayU8(array) => {
+                self.u8(TAG_ARRAY_U8);
+                self.u32(array.length());
+                array.access_slice(mem, |_, slice| self.bytes.extend_from_slice(slice));
+            }
+
+            Value::ArrayU16(array) => {
+                self.u8(TAG_ARRAY_U16);
+                self.u32(array.length());
+                array.access_slice(mem, |_, slice| {
+                    for n in slice.iter() {
+                        self.bytes.extend_from_slice(&n.to_le_bytes())
+                    }
+                });
+            }
+
+            Value::ArrayU32(array) => {
+                self.u8(TAG_ARRAY_U32);
+                self.u32(array.length());
+                array.access_slice(mem, |_, slice| {
+                    for n in slice.iter() {
+                        self.bytes.extend_from_slice(&n.to_le_bytes())
+                    }
+                });
+            }
+
+            Value::Dict(dict) => {
+                let items = dict.items(mem);
+
+                self.u8(TAG_DICT);
+                self.u32(items.len() as u32);
+                for (key, value) in items {
+                    self.value(key, depth + 1)?;
+                    self.value(value, depth + 1)?;
+                }
+            }
+
+            Value::Function(function) => {
+                self.u8(TAG_FUNCTION);
+                self.function(function, depth)?;
+            }
+
+            Value::Partial(partial) => {
+                if !partial.closure_env().is_nil() {
+                    return Err(err_eval(&format!(
+                        "{} closes over local variables and cannot be saved",
+                        value
+                    )));
+                }
+
+                let mut args = Vec::new();
+                partial.args(mem).access_slice(mem, |guard, slice| {
+                    args.extend(slice.iter().map(|arg| arg.get(guard)))
+                });
+
+                self.u8(TAG_PARTIAL);
+                self.function(partial.function(mem), depth)?;
+                self.u32(args.len() as u32);
+                for arg in args {
+                    self.value(arg, depth + 1)?;
+                }
+            }
+
+            _ => {
+                return Err(err_eval(&format!("{} cannot be saved", value)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn function(
+        &mut self,
+        function: ScopedPtr<'guard, Function>,
+        depth: usize,
+    ) -> Result<(), RuntimeError> {
+        let mem = self.mem;
+
+        self.value(function.name_symbol(mem), depth + 1)?;
+        self.value(function.param_names(mem).as_tagged(mem), depth + 1)?;
+
+        match function.is_closure() {
+            true => self.value(function.nonlocals(mem).as_tagged(mem), depth + 1)?,
+            false => self.u8(TAG_NIL),
+        }
+
+        match function.doc(mem) {
+            Some(doc) => {
+                self.u8(1);
+                self.str(doc);
+            }
+            None => self.u8(0),
+        }
+
+        match function.file_name(mem) {
+            Some(file_name) => {
+                self.u8(1);
+                self.str(file_name);
+            }
+            None => self.u8(0),
+        }
+
+        match function.source_pos() {
+            Some(pos) => {
+                self.u8(1);
+                self.pos(pos);
+            }
+            None => self.u8(0),
+        }
+
+        let code = function.code(mem);
+        let opcodes = code.opcodes(mem);
+
+        self.u32(opcodes.len() as u32);
+        for (index, opcode) in opcodes.iter().enumerate() {
+            let operands = opcode.operands();
+
+            self.str(opcode.name());
+            self.u8(operands.len() as u8);
+            for operand in operands {
+                self.i64(operand as i64);
+            }
+
+            // the literal itself is saved, the id is only meaningful in this ByteCode
+            if let Opcode::LoadLiteral { literal_id, .. } = opcode {
+                self.value(code.literal(mem, *literal_id)?, depth + 1)?;
+            }
+
+            match code.source_span(mem, index as u32) {
+                Some(span) => {
+                    self.u8(1);
+                    self.pos(span.start);
+                    self.pos(span.end);
+                }
+                None => self.u8(0),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes values from an image
+struct Reader<'bytes> {
+    bytes: &'bytes [u8],
+    pos: usize,
+}
+
+impl<'bytes> Reader<'bytes> {
+    fn take(&mut self, count: usize) -> Result<&'bytes [u8], RuntimeError> {
+        if self.bytes.len() - self.pos < count {
+            return Err(corrupt("the file is truncated"));
+        }
+
+        let slice = &self.bytes[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, RuntimeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, RuntimeError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn i64(&mut self) -> Result<i64, RuntimeError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(i64::from_le_bytes(bytes))
+    }
+
+    fn str(&mut self) -> Result<&'bytes str, RuntimeError> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| corrupt("a string is not valid UTF-8"))
+    }
+
+    fn flag(&mut self) -> Result<bool, RuntimeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(corrupt("invalid flag")),
+        }
+    }
+
+    fn pos(&mut self) -> Result<SourcePos, RuntimeError> {
+        Ok(SourcePos {
+            line: self.u32()?,
+            column: self.u32()?,
+            offset: self.u32()?,
+        })
+    }
+
+    fn value<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        depth: usize,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        if depth > MAX_DEPTH {
+            return Err(corrupt("values are nested too deeply"));
+        }
+
+        let value = match self.u8()? {
+            TAG_NIL => mem.nil(),
+
+            TAG_BOOL => TaggedScopedPtr::new(mem, TaggedPtr::boolean(self.flag()?)),
+
+            TAG_CHAR => {
+                let c =
+                    std::char::from_u32(self.u32()?).ok_or_else(|| corrupt("invalid character"))?;
+                TaggedScopedPtr::new(mem, TaggedPtr::character(c))
+            }
+
+            TAG_FLOAT => f64::from_bits(self.i64()? as u64).to_value(mem)?,
+
+            TAG_NUMBER => mem.number(self.i64()? as isize),
+
+            TAG_SYMBOL => mem.lookup_sym(self.str()?),
+
+            TAG_TEXT => mem.text(self.str()?)?,
+
+            TAG_PAIR => {
+                let count = self.u32()?;
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    items.push(self.value(mem, depth + 1)?);
+                }
+
+                let mut list = self.value(mem, depth + 1)?;
+                for item in items.into_iter().rev() {
+                    list = cons(mem, item, list)?;
+                }
+                list
+            }
+
+            TAG_LIST => {
+                let count = self.u32()?;
+                let list = List::alloc_with_capacity(mem, count)?;
+                for _ in 0..count {
+                    StackAnyContainer::push(&*list, mem, self.value(mem, depth + 1)?)?;
+                }
+                list.as_tagged(mem)
+            }
+
+            TAG_ARRAY_U8 => {
+                let count = self.u32()?;
+                let array = ArrayU8::alloc_with_capacity(mem, count)?;
+                for n in self.take(count as usize)? {
+                    array.push(mem, *n)?;
+                }
+                array.as_tagged(mem)
+            }
+
+            TAG_ARRAY_U16 => {
+                let count = self.u32()?;
+                let array = ArrayU16::alloc_with_capacity(mem, count)?;
+                for _ in 0..count {
+                    let mut bytes = [0; 2];
+                    bytes.copy_from_slice(self.take(2)?);
+                    array.push(mem, u16::from_le_bytes(bytes))?;
+                }
+                array.as_tagged(mem)
+            }
+
+            TAG_ARRAY_U32 => {
+                let count = self.u32()?;
+                let array = ArrayU32::alloc_with_capacity(mem, count)?;
+                for _ in 0..count {
+                    array.push(mem, self.u32()?)?;
+                }
+                array.as_tagged(mem)
+            }
+
+            TAG_DICT => {
+                let count = self.u32()?;
+                let dict = Dict::alloc(mem)?;
+                for _ in 0..count {
+                    let key = self.value(mem, depth + 1)?;
+                    let value = self.value(mem, depth + 1)?;
+                    dict.assoc(mem, key, value)?;
+                }
+                dict.as_tagged(mem)
+            }
+
+            TAG_FUNCTION => self.function(mem, depth)?.as_tagged(mem),
+
+            TAG_PARTIAL => {
+                let function = self.function(mem, depth)?;
+
+                let count = self.u32()?;
+                if count >= function.arity() as u32 {
+                    return Err(corrupt("a partial application has too many arguments"));
+                }
+
+                let mut args = Vec::new();
+                for _ in 0..count {
+                    args.push(TaggedCellPtr::new_with(self.value(mem, depth + 1)?));
+                }
+
+                Partial::alloc(mem, function, None, &args)?.as_tagged(mem)
+            }
+
+            tag => return Err(corrupt(&format!("unknown value type {}", tag))),
+        };
+
+        Ok(value)
+    }
+
+    fn function<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        depth: usize,
+    ) -> Result<ScopedPtr<'guard, Function>, RuntimeError> {
+        let name = self.value(mem, depth + 1)?;
+
+        let params = match *self.value(mem, depth + 1)? {
+            Value::List(params) => params,
+            _ => return Err(corrupt("function parameters are not a list")),
+        };
+
+        let nonlocals = match *self.value(mem, depth + 1)? {
+            Value::ArrayU16(nonlocals) => Some(nonlocals),
+            Value::Nil => None,
+            _ => return Err(corrupt("function nonlocals are not an array")),
+        };
+
+        let doc = match self.flag()? {
+            true => match *mem.text(self.str()?)? {
+                Value::Text(text) => Some(text),
+                _ => unreachable!(),
+            },
+            false => None,
+        };
+
+        let file_name = match self.flag()? {
+            true => mem.text(self.str()?)?,
+            false => mem.nil(),
+        };
+
+        let source_pos = match self.flag()? {
+            true => Some(self.pos()?),
+            false => None,
+        };
+
+        let code = ByteCode::alloc(mem)?;
+        let mut literal_ids = HashMap::new();
+
+        let count = self.u32()?;
+        for _ in 0..count {
+            let name = self.str()?;
+
+            let operand_count = self.u8()?;
+            let mut operands = Vec::new();
+            for _ in 0..operand_count {
+                operands.push(self.i64()? as isize);
+            }
+
+            let mut opcode = Opcode::from_operands(name, &operands)?;
+
+            // add the literal to this ByteCode, once, and refer to it by its new id
+            if let Opcode::LoadLiteral { dest, .. } = opcode {
+                let literal = mem.constant(self.value(mem, depth + 1)?);
+                let literal_id = match literal_ids.get(&literal.get_ptr()) {
+                    Some(literal_id) => *literal_id,
+                    None => code.push_lit(mem, literal)?,
+                };
+                literal_ids.insert(literal.get_ptr(), literal_id);
+                opcode = Opcode::LoadLiteral { dest, literal_id };
+            }
+
+            let span = match self.flag()? {
+                true => Some(SourceSpan {
+                    start: self.pos()?,
+                    end: self.pos()?,
+                }),
+                false => None,
+            };
+
+            code.push_with_span(mem, opcode, span)?;
+        }
+
+        let function = Function::alloc(mem, name, params, code, nonlocals, doc)?;
+        function.set_source(source_pos, file_name);
+
+        Ok(function)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+    use std::env;
+    use std::process;
+
+    /// Mutator that evaluates some code and saves or loads an image, then evaluates more code
+    struct Session {}
+
+    impl Mutator for Session {
+        type Input = (String, bool, Vec<&'static str>);
+        type Output = Vec<String>;
+
+        fn run(&self, mem: &MutatorView, input: Self::Input) -> Result<Vec<String>, RuntimeError> {
+            let (path, save, code) = input;
+            let thread = Thread::alloc(mem)?;
+            let eval = |code| -> Result<String, RuntimeError> {
+                let value = thread.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)?;
+                Ok(format!("{}", value))
+            };
+
+            let mut results = Vec::new();
+
+            if save {
+                for line in code {
+                    results.push(eval(line)?);
+                }
+
+                let saved = save_image(mem, &thread, &path)?;
+                results.push(format!("saved {}", saved.saved));
+                for (name, reason) in saved.skipped {
+                    results.push(format!("skipped {}: {}", name, reason));
+                }
+            } else {
+                results.push(format!("loaded {}", load_image(mem, &thread, &path)?));
+                for line in code {
+                    results.push(eval(line)?);
+                }
+            }
+
+            Ok(results)
+        }
+    }
+
+    #[test]
+    fn image_save_and_load() {
+        let mut path = env::temp_dir();
+        path.push(format!("evalrus-image-{}", process::id()));
+        let path = String::from(path.to_str().unwrap());
+
+        let saved = Memory::new()
+            .mutate(
+                &Session {},
+                (
+                    path.clone(),
+                    true,
+                    vec![
+                        "(def pair-of (a b) \"Make a pair\" (cons a b))",
+                        "(def choose (x) (cond (is? x 'a) \"first\" true (pair-of x 'other)))",
+                        "(def adder (n) (lambda (x) (pair-of n x)))",
+                        "(set 'data '(1 #\\z \"text\" (nested . dotted) 2.5 0.1))",
+                        "(set 'half (pair-of 'left))",
+                        "(set 'add-one (adder 1))",
+                    ],
+                ),
+            )
+            .unwrap();
+
+        assert!(saved[6] == "saved 5");
+        assert!(saved[7].starts_with("skipped add-one: "));
+        assert!(saved.len() == 8);
+
+        // a new Memory has a different constant pool and symbol table
+        let loaded = Memory::new()
+            .mutate(
+                &Session {},
+                (
+                    path.clone(),
+                    false,
+                    vec![
+                        "'unrelated-symbol",
+                        "(choose 'a)",
+                        "(choose 'b)",
+                        "data",
+                        "(half 'right)",
+                        "((adder 'x) 'y)",
+                        "(doc pair-of)",
+                    ],
+                ),
+            )
+            .unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(loaded[0] == "loaded 5");
+        assert!(loaded[2] == "\"first\"");
+        assert!(loaded[3] == "(b . other)");
+        assert!(loaded[4] == "(1 #\\z \"text\" (nested . dotted) 2.5 0.1)");
+        assert!(loaded[5] == "(left . right)");
+        assert!(loaded[6] == "(x . y)");
+        assert!(loaded[7] == "\"Make a pair\"");
+    }
+
+    #[test]
+    fn image_load_rejects_bad_files() {
+        let mut path = env::temp_dir();
+        path.push(format!("evalrus-bad-image-{}", process::id()));
+        let path = String::from(path.to_str().unwrap());
+
+        let load = |bytes: &[u8]| -> String {
+            std::fs::write(&path, bytes).unwrap();
+            match Memory::new().mutate(&Session {}, (path.clone(), false, vec![])) {
+                Err(e) => format!("{}", reason(&e)),
+                Ok(_) => String::from("loaded"),
+            }
+        };
+
+        assert!(load(b"(def a () 1)") == format!("{} is not an evalrus image", path));
+
+        let mut truncated = Vec::from(MAGIC);
+        truncated.extend_from_slice(&[1, 0, 0, 0, 1, 0]);
+        assert!(load(&truncated) == "Image is corrupt: the file is truncated");
+
+        let mut bad_tag = Vec::from(MAGIC);
+        bad_tag.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0, b'a', 99]);
+        assert!(load(&bad_tag) == "Image is corrupt: unknown value type 99");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,539 @@
+/// JSON encoding and decoding of interpreter values, and the builtin functions that expose them.
+///
+/// The mapping is:
+///  * nil - `null`. JSON `false` also decodes to nil, as nil is the false value
+///  * the symbol `true` - `true`
+///  * integers - numbers. A JSON number with a fraction or exponent decodes to a float
+///  * Text - strings
+///  * other symbols - strings beginning with `'`, so `'foo` encodes as `"'foo"`. Text that
+///    itself begins with `'` has a second one added, so `"'x"` encodes as `"''x"`
+///  * Lists and Pair lists - arrays, which decode to Lists
+///  * Dicts - objects. Keys, which are symbols or integers, are encoded as plain strings and
+///    decode to symbols
+///
+/// Encoding and decoding are recursive, so both stop with an error at `MAX_DEPTH` levels of
+/// nesting rather than exhausting the native stack on deep input or a container that contains
+/// itself.
+use std::fmt::Write;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use crate::containers::{HashIndexedAnyContainer, SliceableContainer, StackAnyContainer};
+use crate::convert::ToValue;
+use crate::dict::Dict;
+use crate::error::{err_eval, RuntimeError};
+use crate::list::List;
+use crate::memory::MutatorView;
+use crate::native::expect_text;
+use crate::native_module;
+use crate::pair::vec_from_pairs;
+use crate::printer::describe;
+use crate::safeptr::{MutatorScope, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+use crate::vm::Thread;
+
+/// The deepest nesting of arrays and objects that will be encoded or decoded
+pub const MAX_DEPTH: usize = 128;
+
+/// The prefix that marks a JSON string as a symbol
+const SYMBOL_PREFIX: char = '\'';
+
+fn too_deep() -> RuntimeError {
+    err_eval(&format!("JSON nesting is deeper than {} levels", MAX_DEPTH))
+}
+
+/// Append a JSON string literal for the given text
+pub(crate) fn encode_str(text: &str, out: &mut String) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Append the JSON encoding of each value in a sequence, as an array
+fn encode_array<'guard>(
+    guard: &'guard dyn MutatorScope,
+    items: &[TaggedScopedPtr<'guard>],
+    depth: usize,
+    out: &mut String,
+) -> Result<(), RuntimeError> {
+    out.push('[');
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        encode_value(guard, *item, depth + 1, out)?;
+    }
+    out.push(']');
+    Ok(())
+}
+
+/// Append the JSON encoding of a value
+fn encode_value<'guard>(
+    guard: &'guard dyn MutatorScope,
+    value: TaggedScopedPtr<'guard>,
+    depth: usize,
+    out: &mut String,
+) -> Result<(), RuntimeError> {
+    if depth > MAX_DEPTH {
+        return Err(too_deep());
+    }
+
+    match *value {
+        Value::Nil => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+        Value::Number(n) => {
+            let _ = write!(out, "{}", n);
+        }
+        Value::Float(n) if n.is_finite() => {
+            let _ = write!(out, "{:?}", n);
+        }
+
+        Value::Symbol(s) => match s.as_str(guard) {
+            "true" => out.push_str("true"),
+            name => encode_str(&format!("{}{}", SYMBOL_PREFIX, name), out),
+        },
+
+        Value::Text(t) => {
+            let text = t.as_str(guard);
+            if text.starts_with(SYMBOL_PREFIX) {
+                encode_str(&format!("{}{}", SYMBOL_PREFIX, text), out);
+            } else {
+                encode_str(text, out);
+            }
+        }
+
+        Value::List(list) => {
+            let mut items = Vec::new();
+            list.access_slice(guard, |guard, slice| {
+                items.extend(slice.iter().map(|item| item.get(guard)))
+            });
+            encode_array(guard, &items, depth, out)?;
+        }
+
+        Value::Pair(_) => encode_array(guard, &vec_from_pairs(guard, value)?, depth, out)?,
+
+        Value::Dict(dict) => {
+            let mut entries = Vec::new();
+            for (key, value) in dict.items(guard) {
+                let key = match *key {
+                    Value::Symbol(s) => String::from(s.as_str(guard)),
+                    Value::Number(n) => n.to_string(),
+                    _ => {
+                        return Err(err_eval(&format!(
+                            "Cannot encode Dict key {} as JSON",
+                            describe(*key)
+                        )))
+                    }
+                };
+                entries.push((key, value));
+            }
+
+            // Dicts are unordered, sorting the keys makes the encoding repeatable
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                encode_str(&key, out);
+                out.push(':');
+                encode_value(guard, value, depth + 1, out)?;
+            }
+            out.push('}');
+        }
+
+        _ => {
+            return Err(err_eval(&format!(
+                "Cannot encode {} as JSON",
+                describe(*value)
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+/// Encode a value as JSON text
+pub fn encode<'guard>(
+    guard: &'guard dyn MutatorScope,
+    value: TaggedScopedPtr<'guard>,
+) -> Result<String, RuntimeError> {
+    let mut out = String::new();
+    encode_value(guard, value, 0, &mut out)?;
+    Ok(out)
+}
+
+/// A recursive descent JSON parser over a string
+struct Decoder<'input> {
+    input: &'input str,
+    chars: Peekable<CharIndices<'input>>,
+}
+
+impl<'input> Decoder<'input> {
+    fn error(&mut self, message: &str) -> RuntimeError {
+        let offset = match self.chars.peek() {
+            Some((offset, _)) => *offset,
+            None => self.input.len(),
+        };
+        err_eval(&format!("Invalid JSON at offset {}: {}", offset, message))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some((_, ' ')) | Some((_, '\t')) | Some((_, '\n')) | Some((_, '\r')) =
+            self.chars.peek()
+        {
+            self.chars.next();
+        }
+    }
+
+    /// Consume the given character, after any whitespace, or return an error
+    fn expect(&mut self, expected: char) -> Result<(), RuntimeError> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some((_, c)) if *c == expected => {
+                self.chars.next();
+                Ok(())
+            }
+            _ => Err(self.error(&format!("expected '{}'", expected))),
+        }
+    }
+
+    /// Consume the given word, such as `null`, or return an error
+    fn expect_word(&mut self, word: &str) -> Result<(), RuntimeError> {
+        for expected in word.chars() {
+            match self.chars.peek() {
+                Some((_, c)) if *c == expected => {
+                    self.chars.next();
+                }
+                _ => return Err(self.error(&format!("expected '{}'", word))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read four hex digits of a `\u` escape
+    fn read_hex4(&mut self) -> Result<u32, RuntimeError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            match self.chars.peek().and_then(|(_, c)| c.to_digit(16)) {
+                Some(digit) => {
+                    self.chars.next();
+                    code = code * 16 + digit;
+                }
+                None => return Err(self.error("expected four hex digits")),
+            }
+        }
+        Ok(code)
+    }
+
+    /// Read a string literal, the opening quote not yet consumed
+    fn read_string(&mut self) -> Result<String, RuntimeError> {
+        self.expect('"')?;
+        let mut text = String::new();
+
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(text),
+
+                Some((_, '\\')) => {
+                    let escaped = match self.chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/')) => '/',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'u')) => {
+                            let mut code = self.read_hex4()?;
+
+                            // a surrogate pair encodes a character outside the basic plane
+                            if (0xd800..0xdc00).contains(&code) {
+                                self.expect_word("\\u")?;
+                                let low = self.read_hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error("expected a low surrogate"));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+
+                            match std::char::from_u32(code) {
+                                Some(c) => c,
+                                None => return Err(self.error("invalid unicode escape")),
+                            }
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    text.push(escaped);
+                }
+
+                Some((_, c)) if (c as u32) < 0x20 => {
+                    return Err(self.error("control character in string"))
+                }
+                Some((_, c)) => text.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// Read a number, returning an integer if it has no fraction or exponent
+    fn read_number<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let start = match self.chars.peek() {
+            Some((offset, _)) => *offset,
+            None => self.input.len(),
+        };
+        let mut end = start;
+        let mut integer = true;
+
+        while let Some((offset, c)) = self.chars.peek() {
+            match c {
+                '0'..='9' | '-' | '+' => (),
+                '.' | 'e' | 'E' => integer = false,
+                _ => break,
+            }
+            end = offset + c.len_utf8();
+            self.chars.next();
+        }
+
+        let number = &self.input[start..end];
+        if integer {
+            match number.parse::<isize>() {
+                Ok(n) => n.to_value(mem),
+                Err(_) => Err(self.error(&format!("invalid integer {}", number))),
+            }
+        } else {
+            match number.parse::<f64>() {
+                Ok(n) => n.to_value(mem),
+                Err(_) => Err(self.error(&format!("invalid number {}", number))),
+            }
+        }
+    }
+
+    /// Read any JSON value
+    fn read_value<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        depth: usize,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        if depth > MAX_DEPTH {
+            return Err(too_deep());
+        }
+
+        self.skip_whitespace();
+
+        match self.chars.peek().map(|(_, c)| *c) {
+            Some('n') => {
+                self.expect_word("null")?;
+                Ok(mem.nil())
+            }
+            Some('f') => {
+                self.expect_word("false")?;
+                Ok(mem.nil())
+            }
+            Some('t') => {
+                self.expect_word("true")?;
+                Ok(mem.lookup_sym("true"))
+            }
+
+            Some('"') => {
+                let text = self.read_string()?;
+                let mut chars = text.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(SYMBOL_PREFIX), Some(SYMBOL_PREFIX)) => mem.text(&text[1..]),
+                    (Some(SYMBOL_PREFIX), _) => Ok(mem.lookup_sym(&text[1..])),
+                    _ => mem.text(&text),
+                }
+            }
+
+            Some('[') => {
+                self.chars.next();
+                let list = List::alloc(mem)?;
+
+                self.skip_whitespace();
+                if let Some((_, ']')) = self.chars.peek() {
+                    self.chars.next();
+                    return Ok(list.as_tagged(mem));
+                }
+
+                loop {
+                    let item = self.read_value(mem, depth + 1)?;
+                    StackAnyContainer::push(&*list, mem, item)?;
+
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => (),
+                        Some((_, ']')) => return Ok(list.as_tagged(mem)),
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+
+            Some('{') => {
+                self.chars.next();
+                let dict = Dict::alloc(mem)?;
+
+                self.skip_whitespace();
+                if let Some((_, '}')) = self.chars.peek() {
+                    self.chars.next();
+                    return Ok(dict.as_tagged(mem));
+                }
+
+                loop {
+                    self.skip_whitespace();
+                    let key = self.read_string()?;
+                    self.expect(':')?;
+                    let value = self.read_value(mem, depth + 1)?;
+                    dict.assoc(mem, mem.lookup_sym(&key), value)?;
+
+                    self.skip_whitespace();
+                    match self.chars.next() {
+                        Some((_, ',')) => (),
+                        Some((_, '}')) => return Ok(dict.as_tagged(mem)),
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+
+            Some('-') | Some('0'..='9') => self.read_number(mem),
+
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+}
+
+/// Decode JSON text into a value. The text must hold exactly one JSON value.
+pub fn decode<'guard>(
+    mem: &'guard MutatorView,
+    input: &str,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let mut decoder = Decoder {
+        input,
+        chars: input.char_indices().peekable(),
+    };
+
+    let value = decoder.read_value(mem, 0)?;
+
+    decoder.skip_whitespace();
+    match decoder.chars.peek() {
+        None => Ok(value),
+        Some(_) => Err(decoder.error("unexpected text after the value")),
+    }
+}
+
+/// (json-encode x) - return the JSON encoding of x as a string
+fn json_encode<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    mem.text(&encode(mem, args[0].get(mem))?)
+}
+
+/// (json-decode s) - return the value the JSON string s encodes
+fn json_decode<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let text = expect_text(mem, &args[0], "json-decode", "s")?;
+    let input = String::from(text.as_str(mem));
+    decode(mem, &input)
+}
+
+native_module! {
+    /// The JSON builtin functions
+    pub JSON_MODULE = "json" {
+        "json-encode" => json_encode(1),
+        "json-decode" => json_decode(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    #[test]
+    fn json_encode_and_decode() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+                let encoded = |code| -> Result<String, RuntimeError> {
+                    match *eval(code)? {
+                        Value::Text(text) => Ok(String::from(text.as_str(mem))),
+                        _ => panic!("expected text"),
+                    }
+                };
+
+                assert!(
+                    encoded("(json-encode '(1 -2 nil true \"text\"))")?
+                        == "[1,-2,null,true,\"text\"]"
+                );
+                assert!(encoded("(json-encode '(sym \"'text\"))")? == "[\"'sym\",\"''text\"]");
+
+                let dict = Dict::alloc(mem)?;
+                dict.assoc(mem, mem.lookup_sym("b"), parse(mem, "(x)")?)?;
+                dict.assoc(mem, mem.number(1), mem.text("a\"\n")?)?;
+                t.set_global(mem, "d", dict.as_tagged(mem))?;
+                assert!(encoded("(json-encode d)")? == "{\"1\":\"a\\\"\\n\",\"b\":[\"'x\"]}");
+
+                // decoding reverses the encoding, Pair lists become Lists
+                let decoded = eval("(json-decode (json-encode '(1 sym \"'text\" (nil true))))")?;
+                assert!(format!("{}", decoded) == "[1, sym, \"'text\", [nil, true]]");
+
+                let json = "{\"k\": [false, 1.5e1, \"\\u00e9\\ud83d\\ude00\\\"\"]}";
+                match *decode(mem, json)? {
+                    Value::Dict(dict) => {
+                        let value = dict.lookup(mem, mem.lookup_sym("k"))?;
+                        assert!(format!("{}", value) == "[nil, 15.0, \"é😀\"\"]");
+                    }
+                    _ => panic!("expected a Dict"),
+                }
+
+                // functions cannot be encoded, malformed JSON cannot be decoded
+                assert!(eval("(json-encode json-encode)").is_err());
+                assert!(eval("(json-decode \"[1,]\")").is_err());
+                assert!(eval("(json-decode \"[1] 2\")").is_err());
+                assert!(eval("(json-decode 'sym)").is_err());
+                assert!(decode(mem, "\"open").is_err());
+
+                // nesting is limited in both directions
+                let deep = format!("{}{}", "[".repeat(MAX_DEPTH + 2), "]".repeat(MAX_DEPTH + 2));
+                assert!(decode(mem, &deep).is_err());
+                let ok = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+                assert!(encode(mem, decode(mem, &ok)?)? == ok);
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -1,8 +1,26 @@
 /// S-Expression lexer implementation.
 ///
-/// This isn't using any look-ahead yet and so always interprets
-/// (.symbol) as ( DOT SYMBOL )
-use crate::error::{err_lexer, spos, RuntimeError, SourcePos};
+/// Numbers are not distinguished from symbols here, the parser reinterprets symbols that are
+/// entirely a decimal number. That makes the rules for signs and dots:
+///
+///  * `-3` and `+3` are single symbols, which the parser reads as integers, while `-`, `+`, `-a`
+///    and `1+` are ordinary symbols
+///  * a `.` on its own, followed by whitespace, a parenthesis or the end of the source, is the
+///    dotted pair DOT token
+///  * a `.` followed by any other character begins a symbol, so `...` and `.foo` are symbols.
+///    `.5` and `1.5` are symbols too, which the parser reads as floats: see `is_fraction()`
+///
+/// Tokens carry the source positions of their first character and of the character after their
+/// last, so tooling can map each token back to the source text it was read from. `lex_str()` and
+/// `lex_reader()` return lexers that produce tokens one at a time; `tokenize()` collects them.
+/// Whitespace is skipped unless the lexer is asked to keep it with `Lexer::with_trivia()`, in
+/// which case each run of whitespace is a `Whitespace` token and the tokens together cover the
+/// whole source. The language has no comments, so whitespace is the only trivia.
+use std::io::{BufReader, Bytes, Read};
+use std::str;
+use std::str::Chars;
+
+use crate::error::{err_lexer, spos, ErrorKind, RuntimeError, SourcePos};
 
 // key characters
 const OPEN_PAREN: char = '(';
@@ -14,160 +32,464 @@ const LF: char = '\n';
 const DOT: char = '.';
 const DOUBLE_QUOTE: char = '"';
 const SINGLE_QUOTE: char = '\'';
+const BAR: char = '|';
+const BACKSLASH: char = '\\';
+
+// a symbol that opens a byte array literal when immediately followed by an open-paren
+const BYTES_PREFIX: &str = "#u8";
+
+// characters that terminate a symbol
+const TERMINATING: [char; 7] = [OPEN_PAREN, CLOSE_PAREN, SPACE, TAB, CR, LF, DOUBLE_QUOTE];
 
 #[derive(Debug, PartialEq)]
 pub enum TokenType {
     OpenParen,
+    /// `#u8(`, the start of a byte array literal
+    OpenBytes,
     CloseParen,
     Symbol(String),
+    QuotedSymbol(String),
     Dot,
     Text(String),
     Quote,
+    /// A run of spaces and line breaks, only produced by a lexer that keeps trivia
+    Whitespace(String),
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Token {
-    pub pos: SourcePos,
+    /// The position of the first character of the token
+    pub start: SourcePos,
+    /// The position just after the last character of the token
+    pub end: SourcePos,
     pub token: TokenType,
 }
 
 impl Token {
-    fn new(pos: SourcePos, token: TokenType) -> Token {
-        Token {
-            pos: pos,
-            token: token,
+    fn new(start: SourcePos, end: SourcePos, token: TokenType) -> Token {
+        Token { start, end, token }
+    }
+
+    /// Return true if the token is whitespace rather than part of an expression
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.token, TokenType::Whitespace(_))
+    }
+
+    /// Return the text of the token in the source it was read from
+    pub fn source_text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start.offset as usize..self.end.offset as usize]
+    }
+}
+
+/// An iterator over the chars of a &str, in the fallible form that the lexer consumes
+pub struct StrChars<'a> {
+    chars: Chars<'a>,
+}
+
+impl<'a> Iterator for StrChars<'a> {
+    type Item = Result<char, RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chars.next().map(Ok)
+    }
+}
+
+/// An iterator that decodes UTF-8 chars from an io::Read source as they are needed
+pub struct ReadChars<R: Read> {
+    bytes: Bytes<BufReader<R>>,
+}
+
+impl<R: Read> ReadChars<R> {
+    fn next_byte(&mut self) -> Result<Option<u8>, RuntimeError> {
+        match self.bytes.next() {
+            Some(Ok(byte)) => Ok(Some(byte)),
+            Some(Err(e)) => Err(RuntimeError::from(e)),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_next(&mut self) -> Result<Option<char>, RuntimeError> {
+        let first = match self.next_byte()? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+
+        // the count of bytes in the char is encoded in the high bits of the first byte
+        let width = match first {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return Err(err_utf8()),
+        };
+
+        let mut buf = [first, 0, 0, 0];
+        for byte in buf.iter_mut().take(width).skip(1) {
+            *byte = self.next_byte()?.ok_or_else(err_utf8)?;
+        }
+
+        match str::from_utf8(&buf[..width]) {
+            Ok(decoded) => Ok(decoded.chars().next()),
+            Err(_) => Err(err_utf8()),
         }
     }
 }
 
-// tokenize a String
-pub fn tokenize(input: &str) -> Result<Vec<Token>, RuntimeError> {
-    use self::TokenType::*;
+impl<R: Read> Iterator for ReadChars<R> {
+    type Item = Result<char, RuntimeError>;
 
-    // characters that terminate a symbol
-    let terminating = [OPEN_PAREN, CLOSE_PAREN, SPACE, TAB, CR, LF, DOUBLE_QUOTE];
-    let is_terminating = |c: char| terminating.iter().any(|t| c == *t);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decode_next().transpose()
+    }
+}
 
-    // return value
-    let mut tokens = Vec::new();
+fn err_utf8() -> RuntimeError {
+    RuntimeError::new(ErrorKind::LexerError(String::from(
+        "Source is not valid UTF-8",
+    )))
+}
 
-    // start line numbering at 1, the first character of each line being number 0
-    let mut lineno = 1;
-    let mut charno = 0;
+/// A character source that tracks the position of the current character. All position
+/// accounting happens in `advance()` so that line, column and byte offset cannot drift apart.
+struct Source<I> {
+    chars: I,
+    current: Option<char>,
+    pos: SourcePos,
+    started: bool,
+}
 
-    let mut chars = input.chars();
-    let mut current = chars.next();
+impl<I> Source<I>
+where
+    I: Iterator<Item = Result<char, RuntimeError>>,
+{
+    fn new(chars: I) -> Source<I> {
+        Source {
+            chars,
+            current: None,
+            // start line numbering at 1, the first character of each line being number 0
+            pos: spos(1, 0, 0),
+            started: false,
+        }
+    }
 
-    loop {
-        match current {
-            Some(TAB) => {
-                return Err(err_lexer(
-                    spos(lineno, charno),
-                    "tabs are not valid whitespace",
-                ));
-            }
+    /// Read the first character if that hasn't happened yet. Deferred so that nothing is read
+    /// from the underlying source until a token is asked for.
+    fn start(&mut self) -> Result<(), RuntimeError> {
+        if !self.started {
+            self.current = self.chars.next().transpose()?;
+            self.started = true;
+        }
+        Ok(())
+    }
 
-            Some(SPACE) => current = chars.next(),
+    /// The current character, or None at the end of the source
+    fn current(&self) -> Option<char> {
+        self.current
+    }
 
-            Some(CR) => {
-                current = chars.next();
+    /// The position of the current character
+    fn pos(&self) -> SourcePos {
+        self.pos
+    }
 
-                // consume \n if it follows \r
-                if let Some(LF) = current {
-                    current = chars.next();
-                }
+    /// Move on to the next character, accounting for the size and effect of the current one.
+    /// A "\r\n" pair counts as a single line break.
+    fn advance(&mut self) -> Result<(), RuntimeError> {
+        if let Some(c) = self.current {
+            let next = self.chars.next().transpose()?;
 
-                lineno += 1;
-                charno = 0;
-                continue;
-            }
+            self.pos.offset += c.len_utf8() as u32;
 
-            Some(LF) => {
-                current = chars.next();
-                lineno += 1;
-                charno = 0;
-                continue;
+            if c == LF || (c == CR && next != Some(LF)) {
+                self.pos.line += 1;
+                self.pos.column = 0;
+            } else if c != CR {
+                self.pos.column += 1;
             }
 
-            // this is not correct because it doesn't allow for a . to begin a number
-            // or a symbol. Will have to fix later.
-            Some(DOT) => {
-                tokens.push(Token::new(spos(lineno, charno), Dot));
-                current = chars.next();
-            }
+            self.current = next;
+        }
+        Ok(())
+    }
+}
 
-            Some(OPEN_PAREN) => {
-                tokens.push(Token::new(spos(lineno, charno), OpenParen));
-                current = chars.next();
-            }
+/// An incremental lexer: characters are consumed from the source only as far as is needed to
+/// produce the next token. Iteration ends after the first error.
+pub struct Lexer<I> {
+    source: Source<I>,
+    failed: bool,
+    keep_trivia: bool,
+}
 
-            Some(CLOSE_PAREN) => {
-                tokens.push(Token::new(spos(lineno, charno), CloseParen));
-                current = chars.next();
-            }
+impl<I> Lexer<I>
+where
+    I: Iterator<Item = Result<char, RuntimeError>>,
+{
+    pub fn new(chars: I) -> Lexer<I> {
+        Lexer {
+            source: Source::new(chars),
+            failed: false,
+            keep_trivia: false,
+        }
+    }
+
+    /// Produce a `Whitespace` token for each run of whitespace rather than skipping it
+    pub fn with_trivia(mut self) -> Lexer<I> {
+        self.keep_trivia = true;
+        self
+    }
+
+    /// Read the next token from the source, returning None at the end of the source
+    fn next_token(&mut self) -> Result<Option<Token>, RuntimeError> {
+        use self::TokenType::*;
 
-            Some(DOUBLE_QUOTE) => {
-                let text_begin = charno;
-
-                let mut text = String::from("");
-
-                loop {
-                    current = chars.next();
-                    if let Some(c) = current {
-                        if c == DOUBLE_QUOTE {
-                            current = chars.next();
-                            charno += 1;
-                            break;
-                        } else {
-                            text.push(c);
-                            charno += 1;
+        let is_terminating = |c: char| TERMINATING.contains(&c);
+
+        let keep_trivia = self.keep_trivia;
+        let source = &mut self.source;
+        source.start()?;
+
+        loop {
+            let pos = source.pos();
+
+            match source.current() {
+                Some(TAB) => {
+                    return Err(err_lexer(pos, "tabs are not valid whitespace"));
+                }
+
+                Some(SPACE) | Some(CR) | Some(LF) if keep_trivia => {
+                    let mut whitespace = String::new();
+                    while let Some(c @ (SPACE | CR | LF)) = source.current() {
+                        whitespace.push(c);
+                        source.advance()?;
+                    }
+                    return Ok(Some(Token::new(pos, source.pos(), Whitespace(whitespace))));
+                }
+
+                Some(SPACE) | Some(CR) | Some(LF) => source.advance()?,
+
+                // other whitespace, such as a no-break space, is easily mistaken for a space but
+                // would otherwise become part of a symbol
+                Some(c) if c.is_whitespace() => {
+                    return Err(err_lexer(
+                        pos,
+                        &format!("U+{:04X} is not valid whitespace", c as u32),
+                    ));
+                }
+
+                // a lone dot, otherwise the start of a symbol
+                Some(DOT) => {
+                    source.advance()?;
+                    match source.current() {
+                        Some(c) if !is_terminating(c) => {
+                            let symbol = read_symbol(source, String::from("."))?;
+                            return Ok(Some(Token::new(pos, source.pos(), Symbol(symbol))));
                         }
-                    } else {
-                        return Err(err_lexer(spos(lineno, charno), "Unterminated string"));
+                        _ => return Ok(Some(Token::new(pos, source.pos(), Dot))),
                     }
                 }
 
-                tokens.push(Token::new(spos(lineno, text_begin), Text(text)))
-            }
+                Some(OPEN_PAREN) => {
+                    source.advance()?;
+                    return Ok(Some(Token::new(pos, source.pos(), OpenParen)));
+                }
 
-            Some(SINGLE_QUOTE) => {
-                tokens.push(Token::new(spos(lineno, charno), Quote));
-                current = chars.next();
-            }
+                Some(CLOSE_PAREN) => {
+                    source.advance()?;
+                    return Ok(Some(Token::new(pos, source.pos(), CloseParen)));
+                }
+
+                Some(DOUBLE_QUOTE) => {
+                    let mut text = String::from("");
+
+                    loop {
+                        source.advance()?;
+                        match source.current() {
+                            Some(DOUBLE_QUOTE) => {
+                                source.advance()?;
+                                break;
+                            }
 
-            Some(non_terminating) => {
-                let symbol_begin = charno;
-
-                let mut symbol = String::from("");
-                symbol.push(non_terminating);
-
-                // consume symbol
-                loop {
-                    current = chars.next();
-                    if let Some(c) = current {
-                        if is_terminating(c) {
-                            break;
-                        } else {
-                            symbol.push(c);
-                            charno += 1;
+                            Some(c) => text.push(c),
+
+                            None => return Err(err_lexer(source.pos(), "Unterminated string")),
                         }
-                    } else {
-                        break;
                     }
+
+                    return Ok(Some(Token::new(pos, source.pos(), Text(text))));
+                }
+
+                Some(SINGLE_QUOTE) => {
+                    source.advance()?;
+                    return Ok(Some(Token::new(pos, source.pos(), Quote)));
+                }
+
+                // a |quoted symbol| may contain any character; '|' and '\' must be escaped by '\'
+                Some(BAR) => {
+                    let mut symbol = String::from("");
+
+                    loop {
+                        source.advance()?;
+                        match source.current() {
+                            Some(BAR) => {
+                                source.advance()?;
+                                break;
+                            }
+
+                            Some(BACKSLASH) => {
+                                source.advance()?;
+                                match source.current() {
+                                    Some(c) if c == BAR || c == BACKSLASH => symbol.push(c),
+                                    _ => {
+                                        return Err(err_lexer(
+                                            source.pos(),
+                                            "Only '|' and '\\' may be escaped in a quoted symbol",
+                                        ))
+                                    }
+                                }
+                            }
+
+                            Some(c) => symbol.push(c),
+
+                            None => return Err(err_lexer(pos, "Unterminated quoted symbol")),
+                        }
+                    }
+
+                    return Ok(Some(Token::new(pos, source.pos(), QuotedSymbol(symbol))));
+                }
+
+                Some(_) => {
+                    let symbol = read_symbol(source, String::from(""))?;
+
+                    if symbol == BYTES_PREFIX && source.current() == Some(OPEN_PAREN) {
+                        source.advance()?;
+                        return Ok(Some(Token::new(pos, source.pos(), OpenBytes)));
+                    }
+
+                    // complete symbol
+                    return Ok(Some(Token::new(pos, source.pos(), Symbol(symbol))));
                 }
 
-                // complete symbol
-                tokens.push(Token::new(spos(lineno, symbol_begin), Symbol(symbol)));
+                // EOL
+                None => return Ok(None),
             }
+        }
+    }
+}
+
+/// Consume symbol characters from the current one up to the next terminating character or
+/// whitespace, appending them to `symbol`. Any character other than those may be part of a
+/// symbol, including letters and marks from any script.
+fn read_symbol<I>(source: &mut Source<I>, mut symbol: String) -> Result<String, RuntimeError>
+where
+    I: Iterator<Item = Result<char, RuntimeError>>,
+{
+    while let Some(c) = source.current() {
+        if TERMINATING.contains(&c) || c.is_whitespace() {
+            break;
+        }
+        symbol.push(c);
+        source.advance()?;
+    }
+    Ok(symbol)
+}
+
+impl<I> Iterator for Lexer<I>
+where
+    I: Iterator<Item = Result<char, RuntimeError>>,
+{
+    type Item = Result<Token, RuntimeError>;
 
-            // EOL
-            None => break,
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
         }
 
-        charno += 1;
+        let result = self.next_token().transpose();
+        if let Some(Err(_)) = result {
+            self.failed = true;
+        }
+        result
     }
+}
+
+/// Return a lexer over a string
+pub fn lex_str(input: &str) -> Lexer<StrChars<'_>> {
+    Lexer::new(StrChars {
+        chars: input.chars(),
+    })
+}
+
+/// Return a lexer that reads the source incrementally from an io::Read instance
+pub fn lex_reader<R: Read>(reader: R) -> Lexer<ReadChars<R>> {
+    Lexer::new(ReadChars {
+        bytes: BufReader::new(reader).bytes(),
+    })
+}
 
-    Ok(tokens)
+// tokenize a String
+pub fn tokenize(input: &str) -> Result<Vec<Token>, RuntimeError> {
+    lex_str(input).collect()
+}
+
+/// Return true if the symbol name would not be read back as the same symbol unless it is written
+/// in `|quoted symbol|` syntax
+pub fn needs_quoting(name: &str) -> bool {
+    match name.chars().next() {
+        None => true,
+        Some(SINGLE_QUOTE) | Some(BAR) => true,
+        Some(_) => {
+            name.chars().any(|c| TERMINATING.contains(&c) || c.is_whitespace())
+                // a lone dot is the DOT token
+                || name == "."
+                // these are reinterpreted by the parser as other types, or rejected
+                || name == "nil"
+                || is_integer(name)
+                || is_fraction(name)
+        }
+    }
+}
+
+/// Return true if the symbol name is written as a decimal integer, such as `3` or `-12`, whether or
+/// not it is in range
+pub fn is_integer(name: &str) -> bool {
+    let unsigned = name.strip_prefix(['+', '-']).unwrap_or(name);
+    !unsigned.is_empty() && unsigned.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Return true if the symbol name is written as a fractional decimal number, such as `.5`, `-1.5`
+/// or `2.`
+pub fn is_fraction(name: &str) -> bool {
+    let unsigned = name.trim_start_matches(['+', '-']);
+    if unsigned.len() + 1 < name.len() {
+        return false;
+    }
+
+    match unsigned.find(DOT) {
+        Some(dot) => {
+            let (whole, fraction) = (&unsigned[..dot], &unsigned[dot + 1..]);
+            (!whole.is_empty() || !fraction.is_empty())
+                && whole.chars().all(|c| c.is_ascii_digit())
+                && fraction.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+/// Write a symbol name in `|quoted symbol|` syntax, escaping as needed
+pub fn quote_symbol(name: &str) -> String {
+    let mut quoted = String::with_capacity(name.len() + 2);
+
+    quoted.push(BAR);
+    for c in name.chars() {
+        if c == BAR || c == BACKSLASH {
+            quoted.push(BACKSLASH);
+        }
+        quoted.push(c);
+    }
+    quoted.push(BAR);
+
+    quoted
 }
 
 #[cfg(test)]
@@ -187,20 +509,38 @@ mod test {
     fn lexer_one_line() {
         if let Ok(tokens) = tokenize("(foo bar baz)") {
             assert!(tokens.len() == 5);
-            assert_eq!(tokens[0], Token::new(spos(1, 0), TokenType::OpenParen));
+            assert_eq!(
+                tokens[0],
+                Token::new(spos(1, 0, 0), spos(1, 1, 1), TokenType::OpenParen)
+            );
             assert_eq!(
                 tokens[1],
-                Token::new(spos(1, 1), TokenType::Symbol(String::from("foo")))
+                Token::new(
+                    spos(1, 1, 1),
+                    spos(1, 4, 4),
+                    TokenType::Symbol(String::from("foo"))
+                )
             );
             assert_eq!(
                 tokens[2],
-                Token::new(spos(1, 5), TokenType::Symbol(String::from("bar")))
+                Token::new(
+                    spos(1, 5, 5),
+                    spos(1, 8, 8),
+                    TokenType::Symbol(String::from("bar"))
+                )
             );
             assert_eq!(
                 tokens[3],
-                Token::new(spos(1, 9), TokenType::Symbol(String::from("baz")))
+                Token::new(
+                    spos(1, 9, 9),
+                    spos(1, 12, 12),
+                    TokenType::Symbol(String::from("baz"))
+                )
+            );
+            assert_eq!(
+                tokens[4],
+                Token::new(spos(1, 12, 12), spos(1, 13, 13), TokenType::CloseParen)
             );
-            assert_eq!(tokens[4], Token::new(spos(1, 12), TokenType::CloseParen));
         } else {
             assert!(false, "unexpected error");
         }
@@ -210,20 +550,38 @@ mod test {
     fn lexer_multi_line() {
         if let Ok(tokens) = tokenize("( foo\nbar\nbaz\n)") {
             assert!(tokens.len() == 5);
-            assert_eq!(tokens[0], Token::new(spos(1, 0), TokenType::OpenParen));
+            assert_eq!(
+                tokens[0],
+                Token::new(spos(1, 0, 0), spos(1, 1, 1), TokenType::OpenParen)
+            );
             assert_eq!(
                 tokens[1],
-                Token::new(spos(1, 2), TokenType::Symbol(String::from("foo")))
+                Token::new(
+                    spos(1, 2, 2),
+                    spos(1, 5, 5),
+                    TokenType::Symbol(String::from("foo"))
+                )
             );
             assert_eq!(
                 tokens[2],
-                Token::new(spos(2, 0), TokenType::Symbol(String::from("bar")))
+                Token::new(
+                    spos(2, 0, 6),
+                    spos(2, 3, 9),
+                    TokenType::Symbol(String::from("bar"))
+                )
             );
             assert_eq!(
                 tokens[3],
-                Token::new(spos(3, 0), TokenType::Symbol(String::from("baz")))
+                Token::new(
+                    spos(3, 0, 10),
+                    spos(3, 3, 13),
+                    TokenType::Symbol(String::from("baz"))
+                )
+            );
+            assert_eq!(
+                tokens[4],
+                Token::new(spos(4, 0, 14), spos(4, 1, 15), TokenType::CloseParen)
             );
-            assert_eq!(tokens[4], Token::new(spos(4, 0), TokenType::CloseParen));
         } else {
             assert!(false, "unexpected error");
         }
@@ -232,9 +590,15 @@ mod test {
     #[test]
     fn lexer_bad_whitespace() {
         if let Err(e) = tokenize("(foo\n\t(bar))") {
-            if let Some(SourcePos { line, column }) = e.error_pos() {
+            if let Some(SourcePos {
+                line,
+                column,
+                offset,
+            }) = e.error_pos()
+            {
                 assert_eq!(line, 2);
                 assert_eq!(column, 0);
+                assert_eq!(offset, 5);
             } else {
                 assert!(false, "Expected error position");
             }
@@ -243,6 +607,295 @@ mod test {
         }
     }
 
+    #[test]
+    fn lexer_crlf_line_endings() {
+        let tokens = tokenize("(a\r\nb\rc\n\r\nd)").unwrap();
+        assert!(tokens.len() == 6);
+        assert_eq!(tokens[1].start, spos(1, 1, 1));
+        assert_eq!(tokens[2].start, spos(2, 0, 4));
+        assert_eq!(tokens[3].start, spos(3, 0, 6));
+        assert_eq!(tokens[4].start, spos(5, 0, 10));
+        assert_eq!(tokens[5].start, spos(5, 1, 11));
+    }
+
+    #[test]
+    fn lexer_multi_byte_positions() {
+        // columns count characters, offsets count bytes
+        let tokens = tokenize("(λ \"é\nx\" ü)").unwrap();
+        assert!(tokens.len() == 5);
+        assert_eq!(tokens[1].start, spos(1, 1, 1));
+        assert_eq!(tokens[2].start, spos(1, 3, 4));
+        assert_eq!(tokens[3].start, spos(2, 3, 11));
+        assert_eq!(tokens[4].start, spos(2, 4, 13));
+    }
+
+    #[test]
+    fn lexer_unicode_symbols() {
+        // letters from any script, and combining marks, are symbol characters
+        let tokens = tokenize("(größe λ→ e\u{301}t\u{e9} 名前)").unwrap();
+        assert!(tokens.len() == 6);
+        assert_eq!(
+            tokens[1],
+            Token::new(
+                spos(1, 1, 1),
+                spos(1, 6, 8),
+                TokenType::Symbol(String::from("größe"))
+            )
+        );
+        assert_eq!(
+            tokens[2],
+            Token::new(
+                spos(1, 7, 9),
+                spos(1, 9, 14),
+                TokenType::Symbol(String::from("λ→"))
+            )
+        );
+        assert_eq!(
+            tokens[3],
+            Token::new(
+                spos(1, 10, 15),
+                spos(1, 14, 21),
+                TokenType::Symbol(String::from("e\u{301}t\u{e9}"))
+            )
+        );
+        assert_eq!(
+            tokens[4],
+            Token::new(
+                spos(1, 15, 22),
+                spos(1, 17, 28),
+                TokenType::Symbol(String::from("名前"))
+            )
+        );
+        assert_eq!(tokens[5].start, spos(1, 17, 28));
+
+        // a no-break space ends a symbol and is an error, rather than joining two names
+        let e = tokenize("(foo\u{a0}bar)").unwrap_err();
+        assert_eq!(e.error_pos(), Some(spos(1, 4, 4)));
+        assert_eq!(
+            e.error_kind(),
+            &ErrorKind::LexerError(String::from("U+00A0 is not valid whitespace"))
+        );
+        assert!(needs_quoting("foo\u{a0}bar"));
+    }
+
+    #[test]
+    fn lexer_from_reader() {
+        let source: &[u8] = "(λ \"text\")\n'x".as_bytes();
+        let tokens: Vec<Token> = lex_reader(source).collect::<Result<_, _>>().unwrap();
+
+        assert_eq!(tokens, tokenize("(λ \"text\")\n'x").unwrap());
+    }
+
+    #[test]
+    fn lexer_from_reader_bad_utf8() {
+        let source: &[u8] = &[b'(', b'a', b' ', 0xe2, 0x28, 0xa1, b')'];
+        let mut lexer = lex_reader(source);
+
+        assert!(lexer.next().unwrap().is_ok());
+        assert!(lexer.next().unwrap().is_ok());
+        assert!(lexer.next().unwrap().is_err());
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn lexer_is_incremental() {
+        // a reader that fails if it is read beyond the first line
+        struct OneLine {
+            data: &'static [u8],
+        }
+
+        impl Read for OneLine {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.data.is_empty() {
+                    panic!("read beyond the end of the line");
+                }
+                let count = self.data.len().min(buf.len());
+                buf[..count].copy_from_slice(&self.data[..count]);
+                self.data = &self.data[count..];
+                Ok(count)
+            }
+        }
+
+        let mut lexer = lex_reader(OneLine { data: b"(a b)\n" });
+        for _ in 0..4 {
+            assert!(lexer.next().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn lexer_quoted_symbol() {
+        if let Ok(tokens) = tokenize("(|foo bar| |a\\|b\\\\c| x)") {
+            assert!(tokens.len() == 5);
+            assert_eq!(
+                tokens[1],
+                Token::new(
+                    spos(1, 1, 1),
+                    spos(1, 10, 10),
+                    TokenType::QuotedSymbol(String::from("foo bar"))
+                )
+            );
+            assert_eq!(
+                tokens[2],
+                Token::new(
+                    spos(1, 11, 11),
+                    spos(1, 20, 20),
+                    TokenType::QuotedSymbol(String::from("a|b\\c"))
+                )
+            );
+            assert_eq!(
+                tokens[3],
+                Token::new(
+                    spos(1, 21, 21),
+                    spos(1, 22, 22),
+                    TokenType::Symbol(String::from("x"))
+                )
+            );
+        } else {
+            assert!(false, "unexpected error");
+        }
+    }
+
+    #[test]
+    fn lexer_quoted_symbol_errors() {
+        assert!(tokenize("|foo").is_err());
+        assert!(tokenize("|foo\\n|").is_err());
+    }
+
+    #[test]
+    fn lexer_symbol_quoting() {
+        assert!(!needs_quoting("foo"));
+        assert!(!needs_quoting("a'b"));
+        assert!(needs_quoting(""));
+        assert!(needs_quoting("foo bar"));
+        assert!(needs_quoting("(foo)"));
+        assert!(!needs_quoting(".foo"));
+        assert!(!needs_quoting("..."));
+        assert!(needs_quoting("."));
+        assert!(needs_quoting(".5"));
+        assert!(needs_quoting("nil"));
+        assert!(needs_quoting("123"));
+
+        assert_eq!(quote_symbol("a|b\\c"), "|a\\|b\\\\c|");
+    }
+
+    #[test]
+    fn lexer_signs_and_dots() {
+        let symbol = |name: &str| TokenType::Symbol(String::from(name));
+        let tokens = |input| -> Vec<TokenType> {
+            tokenize(input)
+                .unwrap()
+                .into_iter()
+                .map(|token| token.token)
+                .collect()
+        };
+
+        assert_eq!(
+            tokens("(- -3 + +3 -a)"),
+            vec![
+                TokenType::OpenParen,
+                symbol("-"),
+                symbol("-3"),
+                symbol("+"),
+                symbol("+3"),
+                symbol("-a"),
+                TokenType::CloseParen
+            ]
+        );
+
+        // a dot is the DOT token only when it stands alone
+        assert_eq!(
+            tokens("(a . b)"),
+            vec![
+                TokenType::OpenParen,
+                symbol("a"),
+                TokenType::Dot,
+                symbol("b"),
+                TokenType::CloseParen
+            ]
+        );
+        assert_eq!(
+            tokens("(a .(b))"),
+            vec![
+                TokenType::OpenParen,
+                symbol("a"),
+                TokenType::Dot,
+                TokenType::OpenParen,
+                symbol("b"),
+                TokenType::CloseParen,
+                TokenType::CloseParen
+            ]
+        );
+        assert_eq!(tokens("."), vec![TokenType::Dot]);
+        assert_eq!(
+            tokens("... .foo .5 1.5 -0.5"),
+            vec![
+                symbol("..."),
+                symbol(".foo"),
+                symbol(".5"),
+                symbol("1.5"),
+                symbol("-0.5")
+            ]
+        );
+
+        // positions are of the first character, the dot
+        let located = tokenize("(x .5)").unwrap();
+        assert_eq!(
+            located[2],
+            Token::new(spos(1, 3, 3), spos(1, 5, 5), symbol(".5"))
+        );
+
+        assert!(is_integer("3"));
+        assert!(is_integer("-12"));
+        assert!(is_integer("+99999999999999999999"));
+        assert!(!is_integer("-"));
+        assert!(!is_integer("--1"));
+        assert!(!is_integer("1.5"));
+        assert!(!is_integer("1a"));
+
+        assert!(is_fraction(".5"));
+        assert!(is_fraction("1.5"));
+        assert!(is_fraction("-0.5"));
+        assert!(is_fraction("-1.5"));
+        assert!(is_fraction("2."));
+        assert!(!is_fraction("."));
+        assert!(!is_fraction("..."));
+        assert!(!is_fraction("1.2.3"));
+        assert!(!is_fraction("--1.5"));
+        assert!(!is_fraction("a.5"));
+    }
+
+    #[test]
+    fn lexer_token_spans_and_trivia() {
+        let source = "(f \"é\"\r\n  'x)";
+
+        // the end of each token is where the next character begins
+        let tokens = tokenize(source).unwrap();
+        assert_eq!(tokens[2].end, spos(1, 6, 7));
+        assert_eq!(tokens[2].source_text(source), "\"é\"");
+        assert_eq!(tokens[3].start, spos(2, 2, 11));
+        assert!(!tokens.iter().any(Token::is_trivia));
+
+        // with trivia kept, the tokens cover the source
+        let tokens: Vec<Token> = lex_str(source)
+            .with_trivia()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let texts: Vec<&str> = tokens.iter().map(|t| t.source_text(source)).collect();
+        assert_eq!(texts, ["(", "f", " ", "\"é\"", "\r\n  ", "'", "x", ")"]);
+        assert_eq!(
+            tokens[4],
+            Token::new(
+                spos(1, 6, 7),
+                spos(2, 2, 11),
+                TokenType::Whitespace(String::from("\r\n  "))
+            )
+        );
+
+        for (token, next) in tokens.iter().zip(&tokens[1..]) {
+            assert_eq!(token.end, next.start);
+        }
+    }
+
     #[test]
     fn lexer_text() {
         if let Ok(_tokens) = tokenize("(foo \"text\" bar)") {
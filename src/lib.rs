@@ -0,0 +1,70 @@
+//! Eval-R-Us: an interpreter runtime built on the Sticky Immix allocator.
+//!
+//! The `evalrus` binary is a thin command line and REPL wrapper around this library.
+
+extern crate blockalloc;
+extern crate fnv;
+extern crate itertools;
+extern crate num;
+#[macro_use]
+extern crate num_derive;
+extern crate rustyline;
+extern crate stickyimmix;
+
+mod arena;
+pub mod array;
+pub mod assembler;
+pub mod builder;
+pub mod builtins;
+pub mod bytecode;
+pub mod compiler;
+mod constants;
+pub mod containers;
+pub mod convert;
+pub mod debug;
+#[cfg(feature = "debug-heap")]
+pub mod debugheap;
+pub mod dict;
+pub mod error;
+pub mod function;
+pub mod global;
+mod hashable;
+mod headers;
+#[cfg(feature = "heap-stats")]
+pub mod heapstats;
+pub mod highlight;
+pub mod image;
+pub mod json;
+pub mod lexer;
+pub mod list;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod memo;
+pub mod memory;
+pub mod native;
+pub mod number;
+pub mod pair;
+pub mod parser;
+pub mod peephole;
+pub mod persistent;
+mod pointerops;
+pub mod port;
+pub mod printer;
+#[cfg(feature = "vm-profile")]
+pub mod profile;
+pub mod promise;
+pub mod random;
+mod rawarray;
+pub mod repl;
+pub mod root;
+pub mod safeptr;
+pub mod sandbox;
+pub mod server;
+pub mod sort;
+pub mod symbol;
+mod symbolmap;
+pub mod taggedptr;
+pub mod text;
+pub mod trace;
+pub mod vm;
+pub mod weak;
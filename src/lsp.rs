@@ -0,0 +1,594 @@
+//! A minimal Language Server Protocol server, built with the `lsp` feature and run as the
+//! `evalrus-lsp` binary.
+//!
+//! Messages are JSON-RPC objects framed by a `Content-Length` header. The server keeps the text of
+//! each open document and supports:
+//!  * diagnostics, published when a document is opened or saved, from the same parse and compile
+//!    checks as `evalrus --check`
+//!  * document symbols: the named functions the document defines, nested as they are in the
+//!    source, taken from the names, source positions and documentation of the compiled Functions
+//!
+//! Documents are compiled but never evaluated.
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::bytecode::{ByteCode, Opcode};
+use crate::compiler::{compile_toplevel_in_context, CompileContext, CompileOptions};
+use crate::containers::{Container, HashIndexedAnyContainer, IndexedAnyContainer};
+use crate::error::{Diagnostic, ErrorKind, RuntimeError, SourcePos};
+use crate::json::{decode, encode_str};
+use crate::lexer::lex_str;
+use crate::memory::{Memory, Mutator, MutatorView};
+use crate::parser::Parser;
+use crate::repl::CheckStream;
+use crate::safeptr::{ScopedPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+
+/// The LSP `SymbolKind` of a function
+const SYMBOL_KIND_FUNCTION: u32 = 12;
+
+/// The JSON-RPC error code for a message that is not valid JSON
+const PARSE_ERROR: i32 = -32700;
+/// The JSON-RPC error code for a request the server does not support
+const METHOD_NOT_FOUND: i32 = -32601;
+
+/// Read one message framed by a `Content-Length` header, or None at the end of the input
+pub fn read_message(input: &mut dyn BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Write one message with a `Content-Length` header
+pub fn write_message(output: &mut dyn Write, message: &str) -> io::Result<()> {
+    write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        message.len(),
+        message
+    )?;
+    output.flush()
+}
+
+/// The parts of a client message the server uses
+#[derive(Debug, Default)]
+struct Message {
+    /// The request id as JSON text, for a request rather than a notification
+    id: Option<String>,
+    method: Option<String>,
+    /// The URI of the document the message is about
+    uri: Option<String>,
+    /// The full text of the document, if the message carries it
+    text: Option<String>,
+}
+
+/// Return the member of a JSON object with the given key, if the value is an object that has it
+fn member<'guard>(
+    mem: &'guard MutatorView,
+    object: Option<TaggedScopedPtr<'guard>>,
+    key: &str,
+) -> Result<Option<TaggedScopedPtr<'guard>>, RuntimeError> {
+    match object.map(|object| *object) {
+        // a Dict has no storage to look in until something is added to it
+        Some(Value::Dict(dict)) if dict.length() > 0 => {
+            match dict.lookup(mem, mem.lookup_sym(key)) {
+                Ok(value) => Ok(Some(value)),
+                Err(e) if *e.error_kind() == ErrorKind::KeyError => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Return the JSON string a decoded value came from. The decoder turns strings beginning with
+/// `'` into symbols and removes the first `'` of strings beginning with two, so both are put back.
+fn string_of<'guard>(
+    mem: &'guard MutatorView,
+    value: Option<TaggedScopedPtr<'guard>>,
+) -> Option<String> {
+    match value.map(|value| *value) {
+        Some(Value::Text(text)) => {
+            let text = text.as_str(mem);
+            match text.starts_with('\'') {
+                true => Some(format!("'{}", text)),
+                false => Some(String::from(text)),
+            }
+        }
+        Some(Value::Symbol(s)) => Some(format!("'{}", s.as_str(mem))),
+        _ => None,
+    }
+}
+
+/// A mutator that decodes a client message
+struct ReadMessage {}
+
+impl Mutator for ReadMessage {
+    type Input = String;
+    type Output = Message;
+
+    fn run(&self, mem: &MutatorView, input: String) -> Result<Message, RuntimeError> {
+        let message = Some(decode(mem, &input)?);
+
+        let id = match member(mem, message, "id")?.map(|id| *id) {
+            Some(Value::Number(n)) => Some(format!("{}", n)),
+            Some(Value::Text(_)) | Some(Value::Symbol(_)) => {
+                let mut id = String::new();
+                encode_str(
+                    &string_of(mem, member(mem, message, "id")?).unwrap(),
+                    &mut id,
+                );
+                Some(id)
+            }
+            _ => None,
+        };
+
+        let params = member(mem, message, "params")?;
+        let document = member(mem, params, "textDocument")?;
+
+        // opening a document sends its text with the document, saving sends it with the params
+        // and each change sends it, with full document sync, in the last content change
+        let mut text = string_of(mem, member(mem, document, "text")?)
+            .or(string_of(mem, member(mem, params, "text")?));
+        if let Some(Value::List(changes)) = member(mem, params, "contentChanges")?.map(|c| *c) {
+            if changes.length() > 0 {
+                let change = changes.get(mem, changes.length() - 1)?;
+                text = string_of(mem, member(mem, Some(change), "text")?);
+            }
+        }
+
+        Ok(Message {
+            id,
+            method: string_of(mem, member(mem, message, "method")?),
+            uri: string_of(mem, member(mem, document, "uri")?),
+            text,
+        })
+    }
+}
+
+/// A named function defined in a document
+#[derive(Debug, PartialEq)]
+struct DocumentSymbol {
+    name: String,
+    /// The first line of the function's documentation, if it has any
+    detail: Option<String>,
+    start: SourcePos,
+    end: SourcePos,
+    /// Named functions defined inside this one
+    children: Vec<DocumentSymbol>,
+}
+
+/// Return the named functions loaded as literals by the given code, with those they define in
+/// turn. Anonymous functions are not listed but the named functions inside them are.
+fn function_symbols<'guard>(
+    mem: &'guard MutatorView,
+    code: ScopedPtr<'guard, ByteCode>,
+) -> Result<Vec<DocumentSymbol>, RuntimeError> {
+    let mut symbols = Vec::new();
+
+    for (index, opcode) in code.opcodes(mem).iter().enumerate() {
+        let function = match opcode {
+            Opcode::LoadLiteral { literal_id, .. } => match *code.literal(mem, *literal_id)? {
+                Value::Function(function) => function,
+                _ => continue,
+            },
+            _ => continue,
+        };
+
+        let children = function_symbols(mem, function.code(mem))?;
+
+        let name = match *function.name_symbol(mem) {
+            Value::Symbol(s) => String::from(s.as_str(mem)),
+            _ => {
+                symbols.extend(children);
+                continue;
+            }
+        };
+
+        // the instruction that loads the function covers the form that defines it
+        let span = code.source_span(mem, index as u32);
+        let start = match (function.source_pos(), span) {
+            (Some(pos), _) => pos,
+            (None, Some(span)) => span.start,
+            (None, None) => continue,
+        };
+        let end = span.map_or(start, |span| span.end);
+
+        symbols.push(DocumentSymbol {
+            name,
+            detail: function
+                .doc(mem)
+                .and_then(|doc| doc.lines().next())
+                .map(String::from),
+            start,
+            end,
+            children,
+        });
+    }
+
+    Ok(symbols)
+}
+
+/// A mutator that compiles each top level form of a document, without evaluating any, and
+/// returns the functions they define. Forms that fail to parse or compile define nothing.
+struct DocumentSymbols {
+    uri: String,
+}
+
+impl Mutator for DocumentSymbols {
+    type Input = String;
+    type Output = Vec<DocumentSymbol>;
+
+    fn run(&self, mem: &MutatorView, source: String) -> Result<Vec<DocumentSymbol>, RuntimeError> {
+        let mut parser = Parser::new(lex_str(&source));
+        let mut symbols = Vec::new();
+
+        while let Ok(Some(form)) = parser.next_expr(mem) {
+            let context = CompileContext::new(CompileOptions::default());
+            match compile_toplevel_in_context(mem, &[form], Some(&self.uri), context) {
+                Ok((function, _)) => symbols.extend(function_symbols(mem, function.code(mem))?),
+
+                Err(e) => match e.error_kind() {
+                    ErrorKind::EvalError(_) | ErrorKind::ParseError(_) => (),
+                    _ => return Err(e),
+                },
+            }
+        }
+
+        Ok(symbols)
+    }
+}
+
+/// Return a source position as an LSP position: a zero based line number and a count of UTF-16
+/// code units from the start of the line
+fn position_json(source: &str, pos: SourcePos) -> String {
+    let offset = (pos.offset as usize).min(source.len());
+    let before = source.get(..offset).unwrap_or("");
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+
+    format!(
+        "{{\"line\":{},\"character\":{}}}",
+        pos.line.saturating_sub(1),
+        before[line_start..].encode_utf16().count()
+    )
+}
+
+fn range_json(source: &str, start: SourcePos, end: SourcePos) -> String {
+    format!(
+        "{{\"start\":{},\"end\":{}}}",
+        position_json(source, start),
+        position_json(source, end)
+    )
+}
+
+fn diagnostic_json(source: &str, diagnostic: &Diagnostic) -> String {
+    let pos = diagnostic.pos().unwrap_or(SourcePos {
+        line: 1,
+        column: 0,
+        offset: 0,
+    });
+
+    let mut out = format!(
+        "{{\"range\":{},\"severity\":{},\"source\":\"evalrus\",\"code\":",
+        range_json(source, pos, pos),
+        if diagnostic.is_error() { 1 } else { 2 }
+    );
+    encode_str(diagnostic.code(), &mut out);
+    out.push_str(",\"message\":");
+    encode_str(diagnostic.message(), &mut out);
+    out.push('}');
+    out
+}
+
+fn symbol_json(source: &str, symbol: &DocumentSymbol) -> String {
+    let mut out = String::from("{\"name\":");
+    encode_str(&symbol.name, &mut out);
+    if let Some(ref detail) = symbol.detail {
+        out.push_str(",\"detail\":");
+        encode_str(detail, &mut out);
+    }
+
+    let range = range_json(source, symbol.start, symbol.end);
+    let children: Vec<String> = symbol
+        .children
+        .iter()
+        .map(|child| symbol_json(source, child))
+        .collect();
+    out.push_str(&format!(
+        ",\"kind\":{},\"range\":{},\"selectionRange\":{},\"children\":[{}]}}",
+        SYMBOL_KIND_FUNCTION,
+        range,
+        range,
+        children.join(",")
+    ));
+    out
+}
+
+fn response(id: &str, result: &str) -> String {
+    format!(
+        "{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}",
+        id, result
+    )
+}
+
+fn error_response(id: &str, code: i32, message: &str) -> String {
+    let mut out = format!(
+        "{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":{},\"message\":",
+        id, code
+    );
+    encode_str(message, &mut out);
+    out.push_str("}}");
+    out
+}
+
+fn notification(method: &str, params: &str) -> String {
+    format!(
+        "{{\"jsonrpc\":\"2.0\",\"method\":\"{}\",\"params\":{}}}",
+        method, params
+    )
+}
+
+/// The server capabilities sent in reply to `initialize`: full document sync with the text sent
+/// on save, and document symbols
+const CAPABILITIES: &str = "{\"capabilities\":{\"textDocumentSync\":{\"openClose\":true,\
+                            \"change\":1,\"save\":{\"includeText\":true}},\
+                            \"documentSymbolProvider\":true},\
+                            \"serverInfo\":{\"name\":\"evalrus-lsp\"}}";
+
+/// A language server session: the text of each open document. Each message is decoded, and each
+/// document compiled, in a heap of its own that is dropped once it is done with, so that nothing
+/// from one check is kept for the next.
+pub struct LanguageServer {
+    documents: HashMap<String, String>,
+    /// Whether the client has requested shutdown
+    shutdown: bool,
+    /// The status to exit with, once the client has sent `exit`
+    exit_status: Option<i32>,
+}
+
+impl LanguageServer {
+    pub fn new() -> LanguageServer {
+        LanguageServer {
+            documents: HashMap::new(),
+            shutdown: false,
+            exit_status: None,
+        }
+    }
+
+    /// The status the process should exit with, once the client has asked the server to exit:
+    /// 0 if it asked for shutdown first, as the protocol requires, or 1
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// Handle one message from the client and return the messages to send in reply
+    pub fn handle(&mut self, message: &str) -> Result<Vec<String>, RuntimeError> {
+        let message = match Memory::new().mutate(&ReadMessage {}, String::from(message)) {
+            Ok(message) => message,
+            Err(e) => match e.error_kind() {
+                ErrorKind::EvalError(reason) => {
+                    return Ok(vec![error_response("null", PARSE_ERROR, reason)])
+                }
+                _ => return Err(e),
+            },
+        };
+
+        let method = message.method.as_deref().unwrap_or("");
+        let uri = message.uri.clone().unwrap_or_default();
+
+        let mut replies = Vec::new();
+        match method {
+            "initialize" => (),
+            "shutdown" => self.shutdown = true,
+            "exit" => self.exit_status = Some(if self.shutdown { 0 } else { 1 }),
+
+            "textDocument/didOpen" | "textDocument/didSave" => {
+                if let Some(text) = message.text {
+                    self.documents.insert(uri.clone(), text);
+                }
+                replies.push(self.publish_diagnostics(&uri)?);
+            }
+
+            "textDocument/didChange" => {
+                if let Some(text) = message.text {
+                    self.documents.insert(uri.clone(), text);
+                }
+            }
+
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                replies.push(notification(
+                    "textDocument/publishDiagnostics",
+                    &self.diagnostics_params(&uri, Vec::new()),
+                ));
+            }
+
+            _ => (),
+        }
+
+        if let Some(ref id) = message.id {
+            replies.push(match method {
+                "initialize" => response(id, CAPABILITIES),
+                "shutdown" => response(id, "null"),
+                "textDocument/documentSymbol" => response(id, &self.document_symbols(&uri)?),
+                _ => error_response(
+                    id,
+                    METHOD_NOT_FOUND,
+                    &format!("Method {} is not supported", method),
+                ),
+            });
+        }
+
+        Ok(replies)
+    }
+
+    fn diagnostics_params(&self, uri: &str, diagnostics: Vec<String>) -> String {
+        let mut params = String::from("{\"uri\":");
+        encode_str(uri, &mut params);
+        params.push_str(&format!(",\"diagnostics\":[{}]}}", diagnostics.join(",")));
+        params
+    }
+
+    /// Check an open document and return the notification publishing what was found
+    fn publish_diagnostics(&self, uri: &str) -> Result<String, RuntimeError> {
+        let source = self.documents.get(uri).cloned().unwrap_or_default();
+
+        let check = CheckStream::new().file_name(uri);
+        let diagnostics =
+            Memory::new().mutate(&check, Box::new(io::Cursor::new(source.clone())))?;
+
+        let diagnostics = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic_json(&source, diagnostic))
+            .collect();
+        Ok(notification(
+            "textDocument/publishDiagnostics",
+            &self.diagnostics_params(uri, diagnostics),
+        ))
+    }
+
+    /// Return the functions an open document defines as a JSON array of LSP document symbols
+    fn document_symbols(&self, uri: &str) -> Result<String, RuntimeError> {
+        let source = self.documents.get(uri).cloned().unwrap_or_default();
+
+        let symbols = Memory::new().mutate(
+            &DocumentSymbols {
+                uri: String::from(uri),
+            },
+            source.clone(),
+        )?;
+
+        let symbols: Vec<String> = symbols
+            .iter()
+            .map(|symbol| symbol_json(&source, symbol))
+            .collect();
+        Ok(format!("[{}]", symbols.join(",")))
+    }
+}
+
+impl Default for LanguageServer {
+    fn default() -> LanguageServer {
+        LanguageServer::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lsp_message_framing() {
+        let mut output = Vec::new();
+        write_message(&mut output, "{\"id\":1}").unwrap();
+        write_message(&mut output, "{\"x\":\"é\"}").unwrap();
+
+        let mut input = io::Cursor::new(output);
+        assert!(read_message(&mut input).unwrap().unwrap() == "{\"id\":1}");
+        assert!(read_message(&mut input).unwrap().unwrap() == "{\"x\":\"é\"}");
+        assert!(read_message(&mut input).unwrap().is_none());
+
+        let mut headerless = io::Cursor::new(&b"\r\n{}"[..]);
+        assert!(read_message(&mut headerless).is_err());
+    }
+
+    #[test]
+    fn lsp_session() {
+        let mut server = LanguageServer::new();
+
+        let replies = server
+            .handle("{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{}}")
+            .unwrap();
+        assert!(replies == vec![response("1", CAPABILITIES)]);
+
+        // the text is sent with a leading quote to check that it survives decoding
+        let replies = server
+            .handle(
+                "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didOpen\",\"params\":\
+                 {\"textDocument\":{\"uri\":\"file:///a.evr\",\"text\":\
+                 \"'x\\n(def f (a b) \\\"Return a.\\\" (def g () a) a)\\n(h)\\n\"}}}",
+            )
+            .unwrap();
+        assert!(
+            replies
+                == vec![String::from(
+                    "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\
+                     \"params\":{\"uri\":\"file:///a.evr\",\"diagnostics\":[\
+                     {\"range\":{\"start\":{\"line\":1,\"character\":1},\
+                     \"end\":{\"line\":1,\"character\":1}},\"severity\":2,\
+                     \"source\":\"evalrus\",\"code\":\"unused-variable\",\
+                     \"message\":\"Parameter b is never used\"},\
+                     {\"range\":{\"start\":{\"line\":2,\"character\":1},\
+                     \"end\":{\"line\":2,\"character\":1}},\"severity\":1,\
+                     \"source\":\"evalrus\",\"code\":\"undefined-global\",\
+                     \"message\":\"Global h is not defined\"}]}}"
+                )]
+        );
+
+        let replies = server
+            .handle(
+                "{\"jsonrpc\":\"2.0\",\"id\":\"s\",\"method\":\"textDocument/documentSymbol\",\
+                 \"params\":{\"textDocument\":{\"uri\":\"file:///a.evr\"}}}",
+            )
+            .unwrap();
+        assert!(replies.len() == 1);
+        assert!(replies[0].starts_with(
+            "{\"jsonrpc\":\"2.0\",\"id\":\"s\",\"result\":[{\"name\":\"f\",\
+             \"detail\":\"Return a.\",\"kind\":12,\"range\":{\"start\":{\"line\":1,\
+             \"character\":1}"
+        ));
+        assert!(replies[0].contains("\"children\":[{\"name\":\"g\","));
+
+        // changes are not checked until the document is saved
+        let change = "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didChange\",\"params\":\
+                      {\"textDocument\":{\"uri\":\"file:///a.evr\"},\
+                      \"contentChanges\":[{\"text\":\"(car\"}]}}";
+        assert!(server.handle(change).unwrap().is_empty());
+        let replies = server
+            .handle(
+                "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/didSave\",\"params\":\
+                 {\"textDocument\":{\"uri\":\"file:///a.evr\"}}}",
+            )
+            .unwrap();
+        assert!(replies[0].contains("\"code\":\"parse-error\""));
+
+        let replies = server
+            .handle("{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"textDocument/hover\"}")
+            .unwrap();
+        assert!(replies[0].contains("\"error\":{\"code\":-32601"));
+        let replies = server.handle("{\"id\":").unwrap();
+        assert!(replies[0].contains("\"error\":{\"code\":-32700"));
+
+        assert!(server.exit_status().is_none());
+        let replies = server
+            .handle("{\"jsonrpc\":\"2.0\",\"id\":3,\"method\":\"shutdown\"}")
+            .unwrap();
+        assert!(replies == vec![response("3", "null")]);
+        server
+            .handle("{\"jsonrpc\":\"2.0\",\"method\":\"exit\"}")
+            .unwrap();
+        assert!(server.exit_status() == Some(0));
+    }
+}
@@ -1,75 +1,161 @@
-extern crate blockalloc;
+extern crate atty;
 extern crate clap;
+extern crate ctrlc;
 extern crate dirs;
-extern crate fnv;
-extern crate itertools;
-extern crate num;
-#[macro_use]
-extern crate num_derive;
+extern crate evalrus;
 extern crate rustyline;
-extern crate stickyimmix;
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
-use std::io::prelude::*;
+use std::mem;
 use std::process;
+use std::sync::atomic::Ordering;
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-mod arena;
-mod array;
-mod bytecode;
-mod compiler;
-mod containers;
-mod dict;
-mod error;
-mod function;
-mod hashable;
-mod headers;
-mod lexer;
-mod list;
-mod memory;
-mod number;
-mod pair;
-mod parser;
-mod pointerops;
-mod printer;
-mod rawarray;
-mod repl;
-mod safeptr;
-mod symbol;
-mod symbolmap;
-mod taggedptr;
-mod text;
-mod vm;
-
-use crate::error::RuntimeError;
-use crate::memory::Memory;
-use crate::repl::RepMaker;
-
-/// Read a file into a String
-fn load_file(filename: &str) -> Result<String, io::Error> {
-    let mut contents = String::new();
-
-    File::open(filename)?.read_to_string(&mut contents)?;
-
-    Ok(contents)
+use evalrus::compiler::{ArityChecks, CompileOptions, OptLevel};
+use evalrus::error::{Diagnostic, DiagnosticFormat, ErrorKind, RuntimeError};
+use evalrus::highlight::{is_incomplete, ReplHelper};
+use evalrus::memory::Memory;
+use evalrus::port::{flush_stdout, set_stdout_buffer_size, DEFAULT_STDOUT_BUFFER_SIZE};
+use evalrus::repl::{
+    banner, CheckStream, FormatStream, ReadEvalStream, RepMaker, DEFAULT_CONTINUATION_PROMPT,
+    DEFAULT_PROMPT,
+};
+use evalrus::server::{read_request, write_response, EvalServer, Request};
+
+/// Read and evaluate an entire file, passing it the given command line arguments
+fn read_file(
+    filename: &str,
+    args: Vec<String>,
+    trace: bool,
+    type_checks: bool,
+    arity_checks: ArityChecks,
+    opt_level: OptLevel,
+    diagnostics: DiagnosticFormat,
+) -> Result<(), RuntimeError> {
+    let file = File::open(filename)?;
+
+    let mem = Memory::new();
+    let stream = ReadEvalStream::new(args)
+        .trace(trace)
+        .type_checks(type_checks)
+        .arity_checks(arity_checks)
+        .opt_level(opt_level)
+        .diagnostics(diagnostics)
+        .file_name(filename);
+    mem.mutate(&stream, Box::new(file))
 }
 
-/// Read and evaluate an entire file
-fn read_file(filename: &str) -> Result<(), RuntimeError> {
-    let _contents = load_file(&filename)?;
+/// Parse and compile an entire file without evaluating it, reporting every problem found. Returns
+/// true if any of them is an error.
+fn check_file(filename: &str, format: DiagnosticFormat) -> Result<bool, RuntimeError> {
+    let source = fs::read_to_string(filename)?;
+
+    let mem = Memory::new();
+    let check = CheckStream::new().file_name(filename);
+    let diagnostics = mem.mutate(&check, Box::new(io::Cursor::new(source.clone())))?;
+
+    for diagnostic in &diagnostics {
+        match format {
+            DiagnosticFormat::Human => diagnostic.print_with_source(&source),
+            DiagnosticFormat::Json => println!("{}", diagnostic.to_json(Some(filename))),
+        }
+    }
+
+    Ok(diagnostics.iter().any(|diagnostic| diagnostic.is_error()))
+}
+
+/// Print a file in canonical formatting. With `check`, print nothing and return whether the
+/// formatting differs instead.
+fn format_file(filename: &str, check: bool) -> Result<bool, RuntimeError> {
+    let source = fs::read_to_string(filename)?;
+
+    let mem = Memory::new();
+    let formatted = mem.mutate(
+        &FormatStream::new(),
+        Box::new(io::Cursor::new(source.clone())),
+    )?;
+
+    if check {
+        if formatted != source {
+            eprintln!("{} is not formatted", filename);
+            return Ok(true);
+        }
+    } else {
+        print!("{}", formatted);
+    }
+
+    Ok(false)
+}
+
+/// Evaluate expressions read from stdin, printing each result, without prompts or history
+fn read_batch(
+    trace: bool,
+    arity_checks: ArityChecks,
+    opt_level: OptLevel,
+    diagnostics: DiagnosticFormat,
+) -> Result<(), RuntimeError> {
+    let mem = Memory::new();
+    let batch = ReadEvalStream::new(Vec::new())
+        .trace(trace)
+        .arity_checks(arity_checks)
+        .opt_level(opt_level)
+        .diagnostics(diagnostics)
+        .batch(true);
+    mem.mutate(&batch, Box::new(io::stdin()))
+}
+
+/// Evaluate requests read from stdin and write a response to each to stdout, until the input ends
+/// or a request calls `exit`
+fn serve(arity_checks: ArityChecks, opt_level: OptLevel) -> Result<(), RuntimeError> {
+    let options = CompileOptions {
+        arity_checks,
+        opt_level,
+        ..CompileOptions::default()
+    };
+    let mut server = EvalServer::new(options)?;
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
 
-    // TODO
+    while let Some(request) = read_request(&mut input)? {
+        let response = match request {
+            Request::Source(source) => server.handle(&source)?,
+            Request::Rejected(reason) => server.reject(&reason),
+        };
+
+        // anything written to stdout outside of the requests' output goes before the response
+        flush_stdout()?;
+        write_response(&mut io::stdout().lock(), &response)?;
+
+        if let Some(status) = server.exit_status() {
+            process::exit(status);
+        }
+    }
 
     Ok(())
 }
 
-/// Read a line at a time, printing the input back out
-fn read_print_loop() -> Result<(), RuntimeError> {
+/// Save the REPL input history, if there is a history file
+fn save_history(reader: &mut Editor<ReplHelper>, history_file: &Option<String>) {
+    if let Some(ref path) = history_file {
+        reader.save_history(&path).unwrap_or_else(|err| {
+            eprintln!("could not save input history in {}: {}", path, err);
+        });
+    }
+}
+
+/// Read a line at a time, printing the input back out. An incomplete expression is continued
+/// on the following lines.
+fn read_print_loop(rep_maker: RepMaker, quiet: bool) -> Result<(), RuntimeError> {
+    if !quiet {
+        println!("{}", banner());
+    }
+
     // establish a repl input history file path
     let history_file = match dirs::home_dir() {
         Some(mut path) => {
@@ -79,9 +165,9 @@ fn read_print_loop() -> Result<(), RuntimeError> {
         None => None,
     };
 
-    // () means no completion support (TODO)
-    // Another TODO - find a more suitable alternative to rustyline
-    let mut reader = Editor::<()>::new();
+    // the helper highlights parentheses and holds back input with unmatched close parentheses
+    let mut reader = Editor::<ReplHelper>::new();
+    reader.set_helper(Some(ReplHelper::new()));
 
     // Try to load the repl history file
     if let Some(ref path) = history_file {
@@ -91,27 +177,54 @@ fn read_print_loop() -> Result<(), RuntimeError> {
     }
 
     let mem = Memory::new();
-    let rep_maker = RepMaker {};
-    let rep = mem.mutate(&rep_maker, ())?;
+    let mut rep = mem.mutate(&rep_maker, ())?;
+
+    // Ctrl-C during evaluation interrupts the program and returns to the prompt
+    let interrupt = rep.interrupt_flag();
+    ctrlc::set_handler(move || interrupt.store(true, Ordering::SeqCst)).unwrap_or_else(|err| {
+        eprintln!("Could not install Ctrl-C handler: {}", err);
+    });
+
+    // the lines of an expression that is not complete yet
+    let mut pending = String::new();
 
     // repl
     loop {
-        let readline = reader.readline("> ");
+        let prompt = if pending.is_empty() {
+            rep.prompt()
+        } else {
+            rep.continuation_prompt()
+        };
+        let readline = reader.readline(&prompt);
 
         match readline {
             // valid input
             Ok(line) => {
-                reader.add_history_entry(&line);
-                mem.mutate(&rep, line)?;
+                if !pending.is_empty() {
+                    pending.push('\n');
+                }
+                pending.push_str(&line);
+
+                if is_incomplete(&pending) {
+                    continue;
+                }
+
+                let input = mem::take(&mut pending);
+                reader.add_history_entry(&input);
+
+                // an exit request or fatal error ends the session
+                if let Err(e) = mem.mutate_with_state(&mut rep, input) {
+                    save_history(&mut reader, &history_file);
+                    return Err(e);
+                }
             }
 
+            // Ctrl-C at the prompt discards the line, and any incomplete expression
+            Err(ReadlineError::Interrupted) => pending.clear(),
+
             // some kind of program termination condition
             Err(e) => {
-                if let Some(ref path) = history_file {
-                    reader.save_history(&path).unwrap_or_else(|err| {
-                        eprintln!("could not save input history in {}: {}", path, err);
-                    });
-                }
+                save_history(&mut reader, &history_file);
 
                 // EOF is fine
                 if let ReadlineError::Eof = e {
@@ -124,8 +237,29 @@ fn read_print_loop() -> Result<(), RuntimeError> {
     }
 }
 
+/// Exit the process with the status the program requested with `(exit n)`, or report the error and
+/// exit with status 1
+fn terminate(err: RuntimeError, format: DiagnosticFormat, filename: Option<&str>) -> ! {
+    // there is nowhere left to report a failure to write out the program's output
+    flush_stdout().ok();
+
+    match err.error_kind() {
+        ErrorKind::Exit(status) => process::exit(*status),
+        _ => {
+            match format {
+                DiagnosticFormat::Human => eprintln!("Terminated: {}", err),
+                DiagnosticFormat::Json => {
+                    eprintln!("{}", Diagnostic::from_error(&err).to_json(filename))
+                }
+            }
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
-    // parse command line argument, an optional filename
+    // parse command line arguments: an optional filename followed by any arguments to pass to
+    // the program
     let matches = App::new("Eval-R-Us")
         .about("Evaluate expressions")
         .arg(
@@ -133,19 +267,187 @@ fn main() {
                 .help("Optional filename to read in")
                 .index(1),
         )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Print each instruction as it is executed"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .requires("filename")
+                .help("Parse and compile the file, reporting all errors, without running it"),
+        )
+        .arg(
+            Arg::with_name("no-type-checks")
+                .long("no-type-checks")
+                .requires("filename")
+                .help("Do not check the types of annotated function parameters"),
+        )
+        .arg(
+            Arg::with_name("check-arity")
+                .long("check-arity")
+                .help("Warn about calls to known global functions with the wrong argument count"),
+        )
+        .arg(
+            Arg::with_name("opt-level")
+                .short("O")
+                .long("opt-level")
+                .takes_value(true)
+                .possible_values(&["0", "1"])
+                .help("Optimization level, by default 0 in the REPL and 1 when running a file"),
+        )
+        .arg(
+            Arg::with_name("diagnostics")
+                .long("diagnostics")
+                .takes_value(true)
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .help("Report errors and warnings as text or as one JSON object per line"),
+        )
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .conflicts_with("filename")
+                .help(
+                    "Evaluate length-prefixed requests from stdin, writing JSON results to stdout",
+                ),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .help("Read expressions from stdin without prompting, even from a terminal"),
+        )
+        .arg(
+            Arg::with_name("prompt")
+                .long("prompt")
+                .takes_value(true)
+                .help("The REPL prompt, in which {n} is replaced by the number of the next input"),
+        )
+        .arg(
+            Arg::with_name("continuation-prompt")
+                .long("continuation-prompt")
+                .takes_value(true)
+                .help("The REPL prompt for further lines of an incomplete expression"),
+        )
+        .arg(
+            Arg::with_name("output-buffer")
+                .long("output-buffer")
+                .takes_value(true)
+                .help("How much standard output to buffer before writing it out, 0 for none"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .help("Do not print the REPL banner"),
+        )
+        .arg(
+            Arg::with_name("args")
+                .help("Arguments to the program, available through (argv)")
+                .index(2)
+                .multiple(true),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Print a file in canonical formatting")
+                .arg(
+                    Arg::with_name("filename")
+                        .help("The file to format")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Print nothing, exit with status 1 if the formatting differs"),
+                ),
+        )
         .get_matches();
 
-    if let Some(filename) = matches.value_of("filename") {
-        // if a filename was specified, read it into a String
-        read_file(filename).unwrap_or_else(|err| {
-            eprintln!("Terminated: {}", err);
+    let trace = matches.is_present("trace");
+    let arity_checks = if matches.is_present("check-arity") {
+        ArityChecks::Warn
+    } else {
+        ArityChecks::Off
+    };
+    let opt_level = matches.value_of("opt-level").and_then(OptLevel::from_name);
+    let diagnostics = match matches.value_of("diagnostics") {
+        Some("json") => DiagnosticFormat::Json,
+        _ => DiagnosticFormat::Human,
+    };
+
+    let output_buffer = match matches.value_of("output-buffer").map(str::parse::<usize>) {
+        Some(Ok(size)) => size,
+        Some(Err(_)) => {
+            eprintln!("--output-buffer must be a number of bytes");
             process::exit(1);
-        });
+        }
+        None => DEFAULT_STDOUT_BUFFER_SIZE,
+    };
+    if let Err(err) = set_stdout_buffer_size(output_buffer) {
+        terminate(RuntimeError::from(err), diagnostics, None);
+    }
+
+    if let Some(fmt_matches) = matches.subcommand_matches("fmt") {
+        let filename = fmt_matches.value_of("filename").unwrap();
+        match format_file(filename, fmt_matches.is_present("check")) {
+            Ok(false) => (),
+            Ok(true) => process::exit(1),
+            Err(err) => terminate(err, DiagnosticFormat::Human, Some(filename)),
+        }
+    } else if matches.is_present("check") {
+        // check only, exiting with status 1 if there were errors
+        let filename = matches.value_of("filename").unwrap();
+        match check_file(filename, diagnostics) {
+            Ok(false) => (),
+            Ok(true) => process::exit(1),
+            Err(err) => terminate(err, diagnostics, Some(filename)),
+        }
+    } else if let Some(filename) = matches.value_of("filename") {
+        let args = match matches.values_of("args") {
+            Some(values) => values.map(String::from).collect(),
+            None => Vec::new(),
+        };
+
+        // if a filename was specified, evaluate it as a stream
+        let type_checks = !matches.is_present("no-type-checks");
+        if let Err(err) = read_file(
+            filename,
+            args,
+            trace,
+            type_checks,
+            arity_checks,
+            opt_level.unwrap_or(OptLevel::O1),
+            diagnostics,
+        ) {
+            terminate(err, diagnostics, Some(filename));
+        }
+    } else if matches.is_present("server") {
+        if let Err(err) = serve(arity_checks, opt_level.unwrap_or(OptLevel::O0)) {
+            terminate(err, diagnostics, None);
+        }
+    } else if matches.is_present("batch") || !atty::is(atty::Stream::Stdin) {
+        // input from a pipe or file is evaluated without the interactive line editor
+        let opt_level = opt_level.unwrap_or(OptLevel::O0);
+        if let Err(err) = read_batch(trace, arity_checks, opt_level, diagnostics) {
+            terminate(err, diagnostics, None);
+        }
     } else {
         // otherwise begin a repl
-        read_print_loop().unwrap_or_else(|err| {
-            eprintln!("Terminated: {}", err);
-            process::exit(1);
-        });
+        let rep_maker = RepMaker {
+            trace,
+            prompt: String::from(matches.value_of("prompt").unwrap_or(DEFAULT_PROMPT)),
+            continuation_prompt: String::from(
+                matches
+                    .value_of("continuation-prompt")
+                    .unwrap_or(DEFAULT_CONTINUATION_PROMPT),
+            ),
+            opt_level: opt_level.unwrap_or(OptLevel::O0),
+        };
+
+        if let Err(err) = read_print_loop(rep_maker, matches.is_present("quiet")) {
+            terminate(err, DiagnosticFormat::Human, None);
+        }
     }
 }
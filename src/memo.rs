@@ -0,0 +1,202 @@
+/// Memoization of functions.
+///
+/// `(memoize f)` returns a wrapper Function of the same name and arity as `f` that passes its
+/// arguments, with a cache Dict and `f`, to a native function. The cache maps the structural hash
+/// of each argument list to the list of `(args . result)` pairs with that hash, so argument lists
+/// are compared by structure rather than identity. A call whose arguments cannot be hashed, such as
+/// one passing a Dict, is passed through to `f` without being cached.
+use crate::builder::wrap_native;
+use crate::containers::{Container, HashIndexedAnyContainer};
+use crate::dict::Dict;
+use crate::error::{err_eval, ErrorKind, RuntimeError};
+use crate::hashable::{structural_hash, structurally_equal};
+use crate::memory::MutatorView;
+use crate::native::{Arity, NativeFunction};
+use crate::native_module;
+use crate::pair::cons;
+use crate::printer::describe;
+use crate::safeptr::{TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::{Value, INLINE_INTEGER_MAX};
+use crate::vm::Thread;
+
+/// (memoized-call cache function arg1 .. argn) - return the result cached for the arguments, or
+/// call the function with them and cache its result. This is what the wrappers made by
+/// `memoize` call.
+fn memoized_call<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let cache = match *args[0].get(mem) {
+        Value::Dict(cache) => cache,
+        _ => return Err(err_eval("memoized-call expects a cache Dict")),
+    };
+    let function = args[1].get(mem);
+    let call_args = &args[2..];
+
+    // this Thread is busy calling memoized-call, so the function is called on another that can
+    // see this one's closures
+    let runner = thread.alloc_nested(mem)?;
+
+    let mut arg_list = mem.nil();
+    for arg in call_args.iter().rev() {
+        arg_list = cons(mem, arg.get(mem), arg_list)?;
+    }
+
+    let key = match structural_hash(mem, arg_list) {
+        Some(hash) => mem.number((hash & INLINE_INTEGER_MAX as u64) as isize),
+        None => return runner.quick_vm_apply(mem, function, call_args),
+    };
+
+    // a Dict has no storage to look in until something is added to it
+    let bucket = if cache.length() == 0 {
+        mem.nil()
+    } else {
+        match cache.lookup(mem, key) {
+            Ok(bucket) => bucket,
+            Err(e) if *e.error_kind() == ErrorKind::KeyError => mem.nil(),
+            Err(e) => return Err(e),
+        }
+    };
+
+    let mut entries = bucket;
+    while let Value::Pair(entry) = *entries {
+        if let Value::Pair(cached) = *entry.first.get(mem) {
+            if structurally_equal(mem, cached.first.get(mem), arg_list) {
+                return Ok(cached.second.get(mem));
+            }
+        }
+        entries = entry.second.get(mem);
+    }
+
+    let result = runner.quick_vm_apply(mem, function, call_args)?;
+    let entry = cons(mem, arg_list, result)?;
+    cache.assoc(mem, key, cons(mem, entry, bucket)?)?;
+
+    Ok(result)
+}
+
+/// (memoize f) - return a function that takes the same arguments as f and returns f's result,
+/// calling f only the first time it is given each list of arguments
+fn memoize<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let function = args[0].get(mem);
+
+    let (name, arity) = match *function {
+        Value::Function(f) => (f.name(mem), f.arity()),
+        Value::Partial(p) => (p.function(mem).name(mem), p.arity()),
+        Value::NativeFunction(native) => match native.arity() {
+            Arity::Exact(arity) => (native.name(), arity),
+            Arity::AtLeast(_) => {
+                return Err(err_eval(&format!(
+                    "Cannot memoize {}, which takes a variable number of arguments",
+                    native.name()
+                )))
+            }
+        },
+        other => {
+            return Err(err_eval(&format!(
+                "Parameter f to memoize must be a function, got {}",
+                describe(other)
+            )))
+        }
+    };
+
+    let cache = Dict::alloc(mem)?;
+    let memoized_call = NativeFunction::alloc(
+        mem,
+        "memoized-call",
+        Arity::AtLeast(2),
+        false,
+        memoized_call,
+    )?;
+    let wrapper = wrap_native(
+        mem,
+        name,
+        arity,
+        memoized_call,
+        &[cache.as_tagged(mem), function],
+    )?;
+
+    Ok(wrapper.as_tagged(mem))
+}
+
+native_module! {
+    /// The function memoization builtin functions
+    pub MEMO_MODULE = "memo" {
+        "memoize" => memoize(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+    use crate::port::Port;
+
+    #[test]
+    fn memoize_recursive_fib() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code: &str| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                // with no arithmetic, n is a list of n items and fib returns a tree of fib(n)
+                // leaves. Each call prints an x.
+                eval(
+                    "(def fib (n) (print 'x) (cond (nil? n) 'zero (nil? (cdr n)) 'one \
+                     true (cons (fib (cdr n)) (fib (cdr (cdr n))))))",
+                )?;
+                let ten = "'(a b c d e f g h i j)";
+
+                let calls = |code: &str| -> Result<(String, usize), RuntimeError> {
+                    let output = Port::open_output_string(mem)?;
+                    t.set_output_port(output);
+                    let result = format!("{}", eval(code)?);
+                    Ok((result, output.output_string(mem)?.len()))
+                };
+
+                let (plain, plain_calls) = calls(&format!("(fib {})", ten))?;
+                assert!(plain_calls == 177);
+
+                // the recursive calls go through the global, so they are memoized too
+                eval("(set 'fib (memoize fib))")?;
+                let (memoized, memoized_calls) = calls(&format!("(fib {})", ten))?;
+                assert!(memoized == plain);
+                assert!(memoized_calls == 11);
+
+                // argument lists are compared by structure
+                assert!(calls(&format!("(fib {})", ten))?.1 == 0);
+                assert!(calls("(fib '(z a b c d e f g h i j))")?.1 == 1);
+
+                // unhashable arguments are not cached
+                eval("(def id (x) (print 'x) x)")?;
+                eval("(set 'id (memoize id))")?;
+                assert!(calls("(id 'a)")?.1 == 1);
+                assert!(calls("(id 'a)")?.1 == 0);
+                assert!(calls("(id id)")?.1 == 1);
+                assert!(calls("(id id)")?.1 == 1);
+
+                let f = eval("(memoize symbol->string)")?;
+                assert!(format!("{}", f) == "(Function symbol->string (arg0))");
+                assert!(eval("(memoize print)").is_err());
+                assert!(eval("(memoize 'x)").is_err());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -2,14 +2,29 @@
 ///
 /// Defines Stack, Heap and Memory types, and a MemoryView type that gives a mutator a safe
 /// view into the stack and heap.
-use stickyimmix::{AllocObject, AllocRaw, ArraySize, RawPtr, StickyImmixHeap};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::mem::size_of;
+use std::ptr::NonNull;
+use std::rc::Rc;
 
-use crate::error::RuntimeError;
+use itertools::join;
+use stickyimmix::{AllocHeader, AllocObject, AllocRaw, ArraySize, RawPtr, StickyImmixHeap};
+
+use crate::constants::ConstantPool;
+use crate::dict::Dict;
+use crate::error::{ErrorKind, RuntimeError};
 use crate::headers::{ObjectHeader, TypeList};
+#[cfg(feature = "heap-stats")]
+use crate::heapstats::{HeapStats, HeapStatsReport, HEAP_STATS_ENV_VAR};
 use crate::pointerops::ScopedRef;
+use crate::root::{Root, RootTable};
 use crate::safeptr::{MutatorScope, ScopedPtr, TaggedScopedPtr};
 use crate::symbolmap::SymbolMap;
 use crate::taggedptr::{FatPtr, TaggedPtr};
+use crate::text::Text;
+use crate::weak::WeakRef;
 
 /// This type describes the mutator's view into memory - the heap and symbol name/ptr lookup.
 ///
@@ -29,7 +44,40 @@ impl<'memory> MutatorView<'memory> {
         TaggedScopedPtr::new(self, self.heap.lookup_sym(name))
     }
 
+    /// Get the Symbol `true` without looking up its name
+    pub fn sym_true(&self) -> TaggedScopedPtr<'_> {
+        TaggedScopedPtr::new(self, self.heap.sym_true)
+    }
+
+    /// Get the Symbol `nil` without looking up its name. The parser reads `nil` as the nil value,
+    /// but the symbol can still be made, for example by `string->symbol`.
+    pub fn sym_nil(&self) -> TaggedScopedPtr<'_> {
+        TaggedScopedPtr::new(self, self.heap.sym_nil)
+    }
+
     /// Write an object into the heap and return a scope-limited pointer to it
+    ///
+    /// ```
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, Mutator, MutatorView};
+    /// use evalrus::pair::Pair;
+    ///
+    /// struct Example {}
+    ///
+    /// impl Mutator for Example {
+    ///     type Input = ();
+    ///     type Output = ();
+    ///
+    ///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+    ///         let pair = mem.alloc(Pair::new())?;
+    ///         pair.first.set(mem.lookup_sym("a"));
+    ///         assert!(pair.first.get(mem) == mem.lookup_sym("a"));
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// Memory::new().mutate(&Example {}, ()).unwrap();
+    /// ```
     pub fn alloc<T>(&self, object: T) -> Result<ScopedPtr<'_, T>, RuntimeError>
     where
         T: AllocObject<TypeList>,
@@ -41,6 +89,30 @@ impl<'memory> MutatorView<'memory> {
     }
 
     /// Write an object into the heap and return a scope-limited runtime-tagged pointer to it
+    ///
+    /// ```
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, Mutator, MutatorView};
+    /// use evalrus::pair::Pair;
+    /// use evalrus::taggedptr::Value;
+    ///
+    /// struct Example {}
+    ///
+    /// impl Mutator for Example {
+    ///     type Input = ();
+    ///     type Output = ();
+    ///
+    ///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+    ///         let pair = mem.alloc_tagged(Pair::new())?;
+    ///         match *pair {
+    ///             Value::Pair(_) => Ok(()),
+    ///             _ => panic!("expected a Pair"),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// Memory::new().mutate(&Example {}, ()).unwrap();
+    /// ```
     pub fn alloc_tagged<T>(&self, object: T) -> Result<TaggedScopedPtr<'_>, RuntimeError>
     where
         FatPtr: From<RawPtr<T>>,
@@ -49,6 +121,80 @@ impl<'memory> MutatorView<'memory> {
         Ok(TaggedScopedPtr::new(self, self.heap.alloc_tagged(object)?))
     }
 
+    /// Return a runtime-tagged inline integer. Values outside of the inline integer range wrap
+    /// around, see `TaggedPtr::number()`.
+    ///
+    /// ```
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, Mutator, MutatorView};
+    /// use evalrus::taggedptr::Value;
+    ///
+    /// struct Example {}
+    ///
+    /// impl Mutator for Example {
+    ///     type Input = ();
+    ///     type Output = ();
+    ///
+    ///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+    ///         match *mem.number(42) {
+    ///             Value::Number(n) => assert!(n == 42),
+    ///             _ => panic!("expected a Number"),
+    ///         }
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// Memory::new().mutate(&Example {}, ()).unwrap();
+    /// ```
+    pub fn number(&self, value: isize) -> TaggedScopedPtr<'_> {
+        TaggedScopedPtr::new(self, TaggedPtr::number(value))
+    }
+
+    /// Copy a string into a new heap allocated Text object and return a runtime-tagged pointer
+    /// to it
+    ///
+    /// ```
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, Mutator, MutatorView};
+    /// use evalrus::taggedptr::Value;
+    ///
+    /// struct Example {}
+    ///
+    /// impl Mutator for Example {
+    ///     type Input = ();
+    ///     type Output = ();
+    ///
+    ///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+    ///         match *mem.text("hello")? {
+    ///             Value::Text(t) => assert!(t.as_str(mem) == "hello"),
+    ///             _ => panic!("expected a Text"),
+    ///         }
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// Memory::new().mutate(&Example {}, ()).unwrap();
+    /// ```
+    ///
+    /// If text interning is enabled, see `Memory::intern_text()`, a previously allocated Text with
+    /// the same content is returned instead.
+    pub fn text(&self, value: &str) -> Result<TaggedScopedPtr<'_>, RuntimeError> {
+        if !self.heap.intern_text {
+            return self.alloc_tagged(Text::new_from_str(self, value)?);
+        }
+
+        if let Some(ptr) = self.heap.texts.borrow().get(value) {
+            return Ok(TaggedScopedPtr::new(self, *ptr));
+        }
+
+        let text = self.alloc_tagged(Text::new_from_str(self, value)?)?;
+        self.heap
+            .texts
+            .borrow_mut()
+            .insert(String::from(value), text.get_ptr());
+        Ok(text)
+    }
+
     /// Make space for an array of bytes
     pub fn alloc_array(&self, capacity: ArraySize) -> Result<RawPtr<u8>, RuntimeError> {
         self.heap.alloc_array(capacity)
@@ -58,25 +204,284 @@ impl<'memory> MutatorView<'memory> {
     pub fn nil(&self) -> TaggedScopedPtr<'_> {
         TaggedScopedPtr::new(self, TaggedPtr::nil())
     }
+
+    /// Register a rooted handle to the given object, keeping it alive beyond this scope
+    ///
+    /// ```
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, Mutator, MutatorView};
+    /// use evalrus::root::Root;
+    ///
+    /// struct Make {}
+    ///
+    /// impl Mutator for Make {
+    ///     type Input = ();
+    ///     type Output = Root;
+    ///
+    ///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<Root, RuntimeError> {
+    ///         Ok(mem.root(mem.text("hello")?))
+    ///     }
+    /// }
+    ///
+    /// struct Show {}
+    ///
+    /// impl Mutator for Show {
+    ///     type Input = Root;
+    ///     type Output = String;
+    ///
+    ///     fn run(&self, mem: &MutatorView, root: Root) -> Result<String, RuntimeError> {
+    ///         Ok(format!("{}", root.get(mem)))
+    ///     }
+    /// }
+    ///
+    /// let mem = Memory::new();
+    /// let root = mem.mutate(&Make {}, ()).unwrap();
+    /// assert!(mem.mutate(&Show {}, root).unwrap() == "\"hello\"");
+    /// ```
+    pub fn root(&self, value: TaggedScopedPtr) -> Root {
+        Root::new(&self.heap.roots, value.get_ptr())
+    }
+
+    /// Register a function to run on a heap object after it has become unreachable, to release
+    /// resources it holds outside of the heap. See `Finalizer` for the caveats.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, Mutator, MutatorView};
+    /// use evalrus::safeptr::TaggedScopedPtr;
+    ///
+    /// static OPEN_RESOURCES: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// fn release(_mem: &MutatorView, _value: TaggedScopedPtr) {
+    ///     OPEN_RESOURCES.fetch_sub(1, Ordering::SeqCst);
+    /// }
+    ///
+    /// struct Example {}
+    ///
+    /// impl Mutator for Example {
+    ///     type Input = ();
+    ///     type Output = ();
+    ///
+    ///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+    ///         for name in &["a", "b"] {
+    ///             OPEN_RESOURCES.fetch_add(1, Ordering::SeqCst);
+    ///             mem.register_finalizer(mem.text(name)?, release);
+    ///         }
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let mem = Memory::new();
+    /// mem.mutate(&Example {}, ()).unwrap();
+    /// assert!(OPEN_RESOURCES.load(Ordering::SeqCst) == 2);
+    ///
+    /// // a collection that finds nothing live runs both finalizers, once
+    /// assert!(mem.run_finalizers(|_| false) == 2);
+    /// assert!(mem.run_finalizers(|_| false) == 0);
+    /// assert!(OPEN_RESOURCES.load(Ordering::SeqCst) == 0);
+    /// ```
+    pub fn register_finalizer(&self, value: TaggedScopedPtr, finalizer: Finalizer) {
+        self.heap
+            .finalizers
+            .borrow_mut()
+            .push((value.get_ptr(), finalizer));
+    }
+
+    /// Return the constant in the constant pool that is equivalent to the given literal value,
+    /// adding the value to the pool if there is none. Values that the pool does not share, see the
+    /// `constants` module, are returned as they are.
+    pub fn constant(&self, value: TaggedScopedPtr) -> TaggedScopedPtr<'_> {
+        TaggedScopedPtr::new(self, self.heap.constants.intern(self, value))
+    }
+
+    /// Return the count of values in the constant pool
+    pub fn constant_count(&self) -> usize {
+        self.heap.constants.len()
+    }
+
+    /// Return the total number of bytes requested by allocations so far
+    pub fn allocated_bytes(&self) -> usize {
+        self.heap.allocated.get()
+    }
+
+    /// Make allocations fail with an out of memory error once `allocated_bytes()` would exceed
+    /// the given limit. `None` removes the limit.
+    pub fn set_allocation_limit(&self, limit: Option<usize>) {
+        self.heap.allocation_limit.set(limit);
+    }
+
+    /// Summarize every object in the heap by type, see `Memory::dump_heap()`
+    pub fn dump_heap(&self) -> HeapDump {
+        self.heap.dump()
+    }
+
+    /// Return true if abandoned storage is poisoned, see `Memory::poison_storage()`
+    pub fn poisons_storage(&self) -> bool {
+        self.heap.poison_storage
+    }
 }
 
 impl<'memory> MutatorScope for MutatorView<'memory> {}
 
-/// The heap implementation
+/// The interface the heap requires of an allocator backend: `AllocRaw`'s `alloc`, `alloc_array`,
+/// `get_header` and `get_object`, for objects with an `ObjectHeader`, and a constructor.
+///
+/// `get_header` and `get_object` are associated functions because a `TaggedPtr` must find its
+/// object's header without a reference to the heap. The backend is therefore chosen for the whole
+/// crate at compile time through `HeapStorage`, rather than being a type parameter of `Memory`.
+pub trait HeapBackend: AllocRaw<Header = ObjectHeader> {
+    /// A short description of the backend, for example for the REPL banner
+    const NAME: &'static str;
+
+    /// Instantiate an empty heap
+    fn new() -> Self;
+}
+
+impl HeapBackend for StickyImmixHeap<ObjectHeader> {
+    const NAME: &'static str = "Sticky Immix heap";
+
+    fn new() -> Self {
+        StickyImmixHeap::new()
+    }
+}
+
+/// The heap implementation, the Sticky Immix heap by default
+#[cfg(not(feature = "debug-heap"))]
 pub type HeapStorage = StickyImmixHeap<ObjectHeader>;
 
+/// The heap implementation, selected by the `debug-heap` feature
+#[cfg(feature = "debug-heap")]
+pub type HeapStorage = crate::debugheap::DebugHeap;
+
+/// Byte value written over storage that has been abandoned, see `Memory::poison_storage()`
+pub const POISON_BYTE: u8 = 0xdb;
+
+/// Environment variable that enables storage poisoning for `Memory::new()` when set to anything
+/// other than an empty string or "0"
+pub const POISON_STORAGE_ENV_VAR: &str = "EVALRUS_POISON_STORAGE";
+
+/// A function run on a heap object after it has become unreachable, to release resources held
+/// outside of the heap such as file handles.
+///
+/// Finalizers run after weak references have been cleared and before anything is freed. They run
+/// in no particular order, so an object the finalized object refers to may itself have been
+/// finalized already. A finalizer must not store the object anywhere, as it is freed once
+/// finalizers have run. Each finalizer runs at most once, and those of objects that are still
+/// registered run when the Memory is dropped.
+pub type Finalizer = fn(&MutatorView, TaggedScopedPtr);
+
+/// The count and total size of the heap objects of one type
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TypeSummary {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+/// A summary of every object in the heap
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeapDump {
+    /// Count of objects, including array backing storage
+    pub objects: usize,
+    /// Total size of the objects in bytes, excluding headers
+    pub bytes: usize,
+    /// Objects of each type, keyed by type name, for example "Pair" or "Array" for array backing
+    /// storage
+    pub types: BTreeMap<String, TypeSummary>,
+}
+
+impl HeapDump {
+    /// Render the summary as a JSON object of the form
+    /// `{"objects": 2, "bytes": 48, "types": {"Pair": {"count": 2, "bytes": 48}}}`
+    pub fn to_json(&self) -> String {
+        let types = join(
+            self.types.iter().map(|(name, summary)| {
+                format!(
+                    "\"{}\": {{\"count\": {}, \"bytes\": {}}}",
+                    name, summary.count, summary.bytes
+                )
+            }),
+            ", ",
+        );
+
+        format!(
+            "{{\"objects\": {}, \"bytes\": {}, \"types\": {{{}}}}}",
+            self.objects, self.bytes, types
+        )
+    }
+}
+
 // Heap memory types.
 struct Heap {
     heap: HeapStorage,
+    /// Header of every object allocated, for walking the heap. Nothing is freed until there is a
+    /// collector, so every object allocated is still live.
+    objects: RefCell<Vec<NonNull<ObjectHeader>>>,
+    /// Objects to finalize once they are unreachable
+    finalizers: RefCell<Vec<(TaggedPtr, Finalizer)>>,
     syms: SymbolMap,
+    /// The symbols the compiler and VM treat specially, interned up front so that they can be
+    /// compared by pointer
+    sym_true: TaggedPtr,
+    sym_nil: TaggedPtr,
+    /// Poison abandoned storage
+    poison_storage: bool,
+    /// Pointers held by `Root` handles
+    roots: Rc<RootTable>,
+    /// Literal values referenced by compiled code
+    constants: ConstantPool,
+    /// Share Text objects between identical strings
+    intern_text: bool,
+    /// Interned Text objects by content
+    texts: RefCell<HashMap<String, TaggedPtr>>,
+    /// Total bytes requested by allocations
+    allocated: Cell<usize>,
+    /// Allocations that would take `allocated` over this limit fail
+    allocation_limit: Cell<Option<usize>>,
+    /// Allocation counts, sizes and ages by type
+    #[cfg(feature = "heap-stats")]
+    stats: HeapStats,
 }
 
 impl Heap {
-    fn new() -> Heap {
+    fn new(poison_storage: bool) -> Heap {
+        let syms = SymbolMap::new();
+        let sym_true = TaggedPtr::symbol(syms.lookup("true"));
+        let sym_nil = TaggedPtr::symbol(syms.lookup("nil"));
+
         Heap {
-            heap: HeapStorage::new(),
-            syms: SymbolMap::new(),
+            heap: <HeapStorage as HeapBackend>::new(),
+            objects: RefCell::new(Vec::new()),
+            finalizers: RefCell::new(Vec::new()),
+            syms,
+            sym_true,
+            sym_nil,
+            poison_storage,
+            roots: Rc::new(RootTable::new()),
+            constants: ConstantPool::new(),
+            intern_text: false,
+            texts: RefCell::new(HashMap::new()),
+            allocated: Cell::new(0),
+            allocation_limit: Cell::new(None),
+            #[cfg(feature = "heap-stats")]
+            stats: HeapStats::new(),
+        }
+    }
+
+    /// Account for an allocation of the given size ahead of making it, failing if it would exceed
+    /// the allocation limit
+    fn charge(&self, bytes: usize) -> Result<(), RuntimeError> {
+        let allocated = self.allocated.get() + bytes;
+
+        if let Some(limit) = self.allocation_limit.get() {
+            if allocated > limit {
+                return Err(RuntimeError::new(ErrorKind::OutOfMemory));
+            }
         }
+
+        self.allocated.set(allocated);
+        Ok(())
     }
 
     /// Get a Symbol pointer from its name
@@ -89,7 +494,8 @@ impl Heap {
     where
         T: AllocObject<TypeList>,
     {
-        Ok(self.heap.alloc(object)?)
+        self.charge(size_of::<T>())?;
+        Ok(self.record(self.heap.alloc(object)?))
     }
 
     /// Write an object into the heap and return a tagged pointer to it
@@ -98,11 +504,79 @@ impl Heap {
         FatPtr: From<RawPtr<T>>,
         T: AllocObject<TypeList>,
     {
-        Ok(TaggedPtr::from(FatPtr::from(self.heap.alloc(object)?)))
+        self.charge(size_of::<T>())?;
+        Ok(TaggedPtr::from(FatPtr::from(
+            self.record(self.heap.alloc(object)?),
+        )))
     }
 
     fn alloc_array(&self, capacity: ArraySize) -> Result<RawPtr<u8>, RuntimeError> {
-        Ok(self.heap.alloc_array(capacity)?)
+        self.charge(capacity as usize)?;
+        Ok(self.record(self.heap.alloc_array(capacity)?))
+    }
+
+    /// Add a new object to the list of objects in the heap
+    fn record<T>(&self, object: RawPtr<T>) -> RawPtr<T> {
+        let header = HeapStorage::get_header(object.as_untyped());
+
+        #[cfg(feature = "heap-stats")]
+        {
+            let header = unsafe { header.as_ref() };
+            self.stats.record(header.type_id(), header.size() as usize);
+        }
+
+        self.objects.borrow_mut().push(header);
+        object
+    }
+
+    /// Clear each weak reference, and remove each weak Dict entry, whose target is not live
+    fn clear_weak_refs<F>(&self, mut is_live: F)
+    where
+        F: FnMut(TaggedPtr) -> bool,
+    {
+        for header in self.objects.borrow().iter() {
+            let object = HeapStorage::get_object(*header);
+
+            match unsafe { header.as_ref() }.type_id() {
+                TypeList::WeakRef => {
+                    let weak = unsafe { object.cast::<WeakRef>().as_ref() };
+                    if !is_live(weak.target()) {
+                        weak.clear();
+                    }
+                }
+
+                TypeList::Dict => {
+                    let dict = unsafe { object.cast::<Dict>().as_ref() };
+                    if dict.has_weak_values() {
+                        dict.remove_dead_values(&mut is_live);
+                    }
+                }
+
+                _ => (),
+            }
+        }
+    }
+
+    /// Walk the heap, summarizing the objects in it by type
+    fn dump(&self) -> HeapDump {
+        let mut dump = HeapDump::default();
+
+        for header in self.objects.borrow().iter() {
+            let header = unsafe { header.as_ref() };
+            let size = header.size() as usize;
+
+            let summary = dump
+                .types
+                .entry(format!("{:?}", header.type_id()))
+                .or_default();
+            summary.count += 1;
+            summary.bytes += size;
+
+            dump.objects += 1;
+            dump.bytes += size;
+        }
+
+        dump
     }
 }
 
@@ -112,16 +586,229 @@ pub struct Memory {
 }
 
 impl Memory {
-    /// Instantiate a new memory environment
+    /// Instantiate a new memory environment. Storage poisoning is enabled if the
+    /// `EVALRUS_POISON_STORAGE` environment variable is set.
     pub fn new() -> Memory {
-        Memory { heap: Heap::new() }
+        let poison_storage = match env::var(POISON_STORAGE_ENV_VAR) {
+            Ok(value) => !value.is_empty() && value != "0",
+            Err(_) => false,
+        };
+
+        Memory {
+            heap: Heap::new(poison_storage),
+        }
+    }
+
+    /// Enable or disable storage poisoning, a debugging configuration that overwrites abandoned
+    /// storage with `POISON_BYTE` so that a dangling pointer into it fails as early as possible.
+    /// The heap does not collect, so nothing is freed and the only storage abandoned is the old
+    /// backing array of an array that has grown, see `RawArray::resize()`.
+    ///
+    /// ```
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, Mutator, MutatorView};
+    ///
+    /// struct Example {}
+    ///
+    /// impl Mutator for Example {
+    ///     type Input = ();
+    ///     type Output = ();
+    ///
+    ///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+    ///         assert!(mem.poisons_storage());
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let mem = Memory::new().poison_storage(true);
+    /// mem.mutate(&Example {}, ()).unwrap();
+    /// ```
+    pub fn poison_storage(mut self, enabled: bool) -> Memory {
+        self.heap.poison_storage = enabled;
+        self
+    }
+
+    /// Enable or disable text interning. When enabled, `MutatorView::text()` returns the same
+    /// Text object for every request for identical content, so that repeated strings share
+    /// storage. Interned Text objects are part of the root set and live as long as the Memory.
+    ///
+    /// ```
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, Mutator, MutatorView};
+    ///
+    /// struct Example {}
+    ///
+    /// impl Mutator for Example {
+    ///     type Input = ();
+    ///     type Output = ();
+    ///
+    ///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+    ///         let first = mem.text("hello")?;
+    ///         assert!(mem.text("hello")?.get_ptr() == first.get_ptr());
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let mem = Memory::new().intern_text(true);
+    /// mem.mutate(&Example {}, ()).unwrap();
+    /// ```
+    pub fn intern_text(mut self, enabled: bool) -> Memory {
+        self.heap.intern_text = enabled;
+        self
+    }
+
+    /// Walk the heap and summarize the objects in it by type, for finding leaks and checking
+    /// allocation accounting
+    ///
+    /// ```
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, Mutator, MutatorView};
+    /// use evalrus::pair::Pair;
+    ///
+    /// struct Example {}
+    ///
+    /// impl Mutator for Example {
+    ///     type Input = ();
+    ///     type Output = ();
+    ///
+    ///     fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+    ///         mem.alloc(Pair::new())?;
+    ///         mem.alloc(Pair::new())?;
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let mem = Memory::new();
+    /// mem.mutate(&Example {}, ()).unwrap();
+    ///
+    /// let dump = mem.dump_heap();
+    /// assert!(dump.types["Pair"].count == 2);
+    /// assert!(dump.to_json().starts_with("{\"objects\": 2, "));
+    /// ```
+    pub fn dump_heap(&self) -> HeapDump {
+        self.heap.dump()
+    }
+
+    /// Clear each `WeakRef` whose target is not live and remove each entry of a weak-valued Dict
+    /// whose value is not live. A collector calls this once marking is complete and before
+    /// freeing anything. `is_live` must return true for values that are never collected, such as
+    /// nil, numbers and symbols.
+    pub fn clear_weak_refs<F>(&self, is_live: F)
+    where
+        F: FnMut(TaggedPtr) -> bool,
+    {
+        self.heap.clear_weak_refs(is_live)
+    }
+
+    /// Run the finalizer of each registered object that is not live, and forget it. A collector
+    /// calls this after `clear_weak_refs()` and before freeing anything. Returns the number of
+    /// finalizers run.
+    pub fn run_finalizers<F>(&self, mut is_live: F) -> usize
+    where
+        F: FnMut(TaggedPtr) -> bool,
+    {
+        // take the finalizers to run out of the registry first so that they can register others
+        let unreachable: Vec<(TaggedPtr, Finalizer)> = {
+            let mut finalizers = self.heap.finalizers.borrow_mut();
+            let (live, unreachable) = finalizers.drain(..).partition(|(ptr, _)| is_live(*ptr));
+            *finalizers = live;
+            unreachable
+        };
+
+        let guard = MutatorView::new(self);
+        for (ptr, finalizer) in &unreachable {
+            finalizer(&guard, TaggedScopedPtr::new(&guard, *ptr));
+        }
+
+        unreachable.len()
+    }
+
+    /// Return the count of live `Root` handles
+    pub fn root_count(&self) -> usize {
+        self.heap.roots.len()
+    }
+
+    /// Call the given function with every pointer in the root set: the pointer held by each live
+    /// `Root` handle, each constant pool value and each interned Text
+    pub fn for_each_root<F>(&self, mut f: F)
+    where
+        F: FnMut(TaggedPtr),
+    {
+        self.heap.roots.for_each(&mut f);
+        self.heap.constants.for_each(&mut f);
+        for ptr in self.heap.texts.borrow().values() {
+            f(*ptr);
+        }
+    }
+
+    /// Return the allocation statistics gathered so far, see the `heapstats` module
+    #[cfg(feature = "heap-stats")]
+    pub fn heap_stats(&self) -> HeapStatsReport {
+        self.heap.stats.report()
     }
 
     /// Run a mutator process
     pub fn mutate<M: Mutator>(&self, m: &M, input: M::Input) -> Result<M::Output, RuntimeError> {
+        #[cfg(feature = "heap-stats")]
+        self.heap.stats.enter_scope();
+
         let mut guard = MutatorView::new(self);
         m.run(&mut guard, input)
     }
+
+    /// Run a mutator process that may update its own state
+    ///
+    /// ```
+    /// use evalrus::error::RuntimeError;
+    /// use evalrus::memory::{Memory, MutatorView, StatefulMutator};
+    ///
+    /// struct Counter {
+    ///     count: usize,
+    /// }
+    ///
+    /// impl StatefulMutator for Counter {
+    ///     type Input = usize;
+    ///     type Output = usize;
+    ///
+    ///     fn run(&mut self, _mem: &MutatorView, step: usize) -> Result<usize, RuntimeError> {
+    ///         self.count += step;
+    ///         Ok(self.count)
+    ///     }
+    /// }
+    ///
+    /// let mem = Memory::new();
+    /// let mut counter = Counter { count: 0 };
+    ///
+    /// mem.mutate_with_state(&mut counter, 2).unwrap();
+    /// assert!(mem.mutate_with_state(&mut counter, 3).unwrap() == 5);
+    /// ```
+    pub fn mutate_with_state<M: StatefulMutator>(
+        &self,
+        m: &mut M,
+        input: M::Input,
+    ) -> Result<M::Output, RuntimeError> {
+        #[cfg(feature = "heap-stats")]
+        self.heap.stats.enter_scope();
+
+        let guard = MutatorView::new(self);
+        m.run(&guard, input)
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        self.run_finalizers(|_| false);
+
+        #[cfg(feature = "heap-stats")]
+        {
+            if let Ok(value) = env::var(HEAP_STATS_ENV_VAR) {
+                if !value.is_empty() && value != "0" {
+                    crate::port::flush_stdout().ok();
+                    eprint!("{}", self.heap_stats());
+                }
+            }
+        }
+    }
 }
 
 /// Defines the interface a heap-mutating type must use to be allowed access to the heap
@@ -131,3 +818,13 @@ pub trait Mutator: Sized {
 
     fn run(&self, mem: &MutatorView, input: Self::Input) -> Result<Self::Output, RuntimeError>;
 }
+
+/// A `Mutator` that carries state from one run to the next. Heap pointers must not be part of
+/// that state unless they are held in a `CellPtr` or `TaggedCellPtr`, as for any other
+/// heap-external type.
+pub trait StatefulMutator: Sized {
+    type Input;
+    type Output;
+
+    fn run(&mut self, mem: &MutatorView, input: Self::Input) -> Result<Self::Output, RuntimeError>;
+}
@@ -0,0 +1,377 @@
+/// Functions implemented in Rust that can be called from the language like any other function.
+///
+/// A `NativeFunction` is a heap object that can be bound to a global symbol. When the VM calls one
+/// it passes the argument registers of the call directly, without creating a call frame.
+use std::fmt;
+
+use crate::containers::HashIndexedAnyContainer;
+use crate::convert::FromValue;
+use crate::dict::Dict;
+use crate::error::{err_eval, ErrorKind, RuntimeError};
+use crate::global::bind_global;
+use crate::memory::MutatorView;
+use crate::printer::Print;
+use crate::safeptr::{MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+use crate::text::Text;
+use crate::vm::Thread;
+
+/// The Rust signature of a native function. `args` holds exactly the arguments given in the call.
+pub type NativeFn = for<'guard> fn(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError>;
+
+/// The count of arguments a native function accepts
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Arity {
+    /// Exactly this many arguments
+    Exact(u8),
+    /// This many arguments or more
+    AtLeast(u8),
+}
+
+/// A native function object type
+#[derive(Clone)]
+pub struct NativeFunction {
+    /// The name the function is bound to
+    name: &'static str,
+    /// Number of arguments the function accepts
+    arity: Arity,
+    /// Whether the function performs I/O or otherwise reaches outside of the interpreter
+    io: bool,
+    /// The Rust implementation
+    func: NativeFn,
+}
+
+impl NativeFunction {
+    /// Allocate a NativeFunction object on the heap
+    pub fn alloc<'guard>(
+        mem: &'guard MutatorView,
+        name: &'static str,
+        arity: Arity,
+        io: bool,
+        func: NativeFn,
+    ) -> Result<ScopedPtr<'guard, NativeFunction>, RuntimeError> {
+        mem.alloc(NativeFunction {
+            name,
+            arity,
+            io,
+            func,
+        })
+    }
+
+    /// Return the name of the function
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Return the number of arguments the function accepts
+    pub fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    /// Return true if the function performs I/O or otherwise reaches outside of the interpreter
+    pub fn is_io(&self) -> bool {
+        self.io
+    }
+
+    /// Call the function after checking the argument count
+    pub fn call<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        thread: &Thread,
+        args: &[TaggedCellPtr],
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let count = args.len();
+
+        let (accepted, expected) = match self.arity {
+            Arity::Exact(n) => (count == n as usize, format!("{}", n)),
+            Arity::AtLeast(n) => (count >= n as usize, format!("at least {}", n)),
+        };
+
+        if !accepted {
+            return Err(err_eval(&format!(
+                "Function {} expected {} arguments, got {}",
+                self.name, expected, count
+            )));
+        }
+
+        (self.func)(mem, thread, args)
+    }
+}
+
+impl Print for NativeFunction {
+    /// Prints a string representation of the function
+    fn print<'guard>(
+        &self,
+        _guard: &'guard dyn MutatorScope,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "(NativeFunction {})", self.name)
+    }
+}
+
+/// A named group of native functions, usually declared with the `native_module!` macro
+pub struct NativeModule {
+    /// The module name, used when binding the module as a namespace
+    pub name: &'static str,
+    /// True if the functions perform I/O, making them unavailable in a sandbox
+    pub io: bool,
+    /// (name, arity, function) entries
+    pub functions: &'static [(&'static str, Arity, NativeFn)],
+}
+
+impl NativeModule {
+    /// Allocate a NativeFunction for each entry, returning each with its name
+    fn alloc_functions<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<Vec<(TaggedScopedPtr<'guard>, TaggedScopedPtr<'guard>)>, RuntimeError> {
+        let mut functions = Vec::with_capacity(self.functions.len());
+
+        for &(name, arity, func) in self.functions {
+            let native = NativeFunction::alloc(mem, name, arity, self.io, func)?;
+            functions.push((mem.lookup_sym(name), native.as_tagged(mem)));
+        }
+
+        Ok(functions)
+    }
+
+    /// Allocate a NativeFunction for each entry and bind it to its name in the given globals dict
+    pub fn bind<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        globals: ScopedPtr<'guard, Dict>,
+    ) -> Result<(), RuntimeError> {
+        for (name, native) in self.alloc_functions(mem)? {
+            bind_global(mem, globals, name, native)?;
+        }
+
+        Ok(())
+    }
+
+    /// Bind the functions into a new Dict and bind that Dict to the module name in the given
+    /// globals dict, keeping the function names out of the global namespace
+    pub fn bind_namespace<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        globals: ScopedPtr<'guard, Dict>,
+    ) -> Result<(), RuntimeError> {
+        let namespace = Dict::alloc(mem)?;
+        for (name, native) in self.alloc_functions(mem)? {
+            namespace.assoc(mem, name, native)?;
+        }
+
+        bind_global(
+            mem,
+            globals,
+            mem.lookup_sym(self.name),
+            namespace.as_tagged(mem),
+        )?;
+        Ok(())
+    }
+}
+
+/// Declare a `NativeModule` constant from a list of function names, arities and Rust functions.
+/// An arity of `n` means exactly n arguments, `n..` means n or more. A module whose functions
+/// perform I/O is marked `[io]` after its name.
+///
+/// ```
+/// use evalrus::error::RuntimeError;
+/// use evalrus::memory::MutatorView;
+/// use evalrus::native_module;
+/// use evalrus::safeptr::{TaggedCellPtr, TaggedScopedPtr};
+/// use evalrus::vm::Thread;
+///
+/// fn first<'guard>(
+///     mem: &'guard MutatorView,
+///     _thread: &Thread,
+///     args: &[TaggedCellPtr],
+/// ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+///     Ok(args[0].get(mem))
+/// }
+///
+/// native_module! {
+///     /// Example functions
+///     pub EXAMPLE = "example" {
+///         "first" => first(1..),
+///     }
+/// }
+///
+/// assert!(EXAMPLE.functions.len() == 1);
+/// assert!(!EXAMPLE.io);
+/// ```
+#[macro_export]
+macro_rules! native_module {
+    (
+        $(#[$attr:meta])*
+        $vis:vis $ident:ident = $module:literal $([$flag:ident])? {
+            $($name:literal => $func:ident($arity:literal $($variadic:tt)?)),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis const $ident: $crate::native::NativeModule = $crate::native::NativeModule {
+            name: $module,
+            io: $crate::native_module!(@flag $($flag)?),
+            functions: &[
+                $((
+                    $name,
+                    $crate::native_module!(@arity $arity $($variadic)?),
+                    $func as $crate::native::NativeFn,
+                )),*
+            ],
+        };
+    };
+
+    (@flag) => {
+        false
+    };
+
+    (@flag io) => {
+        true
+    };
+
+    (@arity $arity:literal) => {
+        $crate::native::Arity::Exact($arity)
+    };
+
+    (@arity $arity:literal ..) => {
+        $crate::native::Arity::AtLeast($arity)
+    };
+}
+
+/// Get the Text value of an argument, or return an error naming the function and argument
+pub fn expect_text<'guard>(
+    guard: &'guard dyn MutatorScope,
+    arg: &TaggedCellPtr,
+    fn_name: &str,
+    arg_name: &str,
+) -> Result<ScopedPtr<'guard, Text>, RuntimeError> {
+    match *arg.get(guard) {
+        Value::Text(text) => Ok(text),
+        _ => Err(err_eval(&format!(
+            "Parameter {} to {} must be a string",
+            arg_name, fn_name
+        ))),
+    }
+}
+
+/// Convert an argument to a Rust type, or return an error naming the function and argument
+/// position
+pub fn arg<T: FromValue>(
+    mem: &MutatorView,
+    args: &[TaggedCellPtr],
+    index: usize,
+    fn_name: &str,
+) -> Result<T, RuntimeError> {
+    T::from_value(mem, args[index].get(mem)).map_err(|e| match e.error_kind() {
+        ErrorKind::EvalError(reason) => err_eval(&format!(
+            "Parameter {} to {}: {}",
+            index + 1,
+            fn_name,
+            reason
+        )),
+        _ => e,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::convert::ToValue;
+    use crate::global::lookup_global;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    fn add<'guard>(
+        mem: &'guard MutatorView,
+        _thread: &Thread,
+        args: &[TaggedCellPtr],
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let mut total: isize = 0;
+        for index in 0..args.len() {
+            total += arg::<isize>(mem, args, index, "add")?;
+        }
+        total.to_value(mem)
+    }
+
+    fn pick<'guard>(
+        mem: &'guard MutatorView,
+        _thread: &Thread,
+        args: &[TaggedCellPtr],
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        Ok(args[1].get(mem))
+    }
+
+    native_module! {
+        TEST_MODULE = "test" {
+            "add" => add(0..),
+            "pick" => pick(2),
+        }
+    }
+
+    #[test]
+    fn native_module_bind_and_call() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                t.bind_native_module(mem, &TEST_MODULE)?;
+
+                let eval = |code| -> Result<TaggedScopedPtr, RuntimeError> {
+                    t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)
+                };
+
+                assert!(eval("(add)")? == mem.number(0));
+                assert!(eval("(add 1 2 3)")? == mem.number(6));
+                assert!(eval("(pick 'a 'b)")? == mem.lookup_sym("b"));
+
+                assert!(eval("(pick 'a)").is_err());
+                assert!(eval("(add 1 'b)").is_err());
+                assert!(format!("{}", eval("add")?) == "(NativeFunction add)");
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn native_module_namespace() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let globals = Dict::alloc(mem)?;
+                TEST_MODULE.bind_namespace(mem, globals)?;
+
+                assert!(!globals.exists(mem, mem.lookup_sym("add"))?);
+
+                match *lookup_global(mem, globals, mem.lookup_sym("test"))? {
+                    Value::Dict(namespace) => {
+                        let add = namespace.lookup(mem, mem.lookup_sym("add"))?;
+                        assert!(format!("{}", add) == "(NativeFunction add)");
+                    }
+                    _ => panic!("expected a Dict"),
+                }
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -1,9 +1,37 @@
-/// An integer type - TODO
+/// Numbers and the builtin functions that operate on them.
+///
+/// Integers are inline, immediate `Number`s in the range `INLINE_INTEGER_MIN` to
+/// `INLINE_INTEGER_MAX`, or heap-allocated `NumberObject`s, which are not implemented yet. They are
+/// exact. Floats are double precision and inexact. A float that single precision represents exactly
+/// is an immediate `Float`, any other is a heap-allocated `FloatObject`. Both are seen as
+/// `Value::Float`, so the difference is only one of storage.
+///
+/// The conversions between them are:
+///  * `(exact->inexact x)` - an integer becomes the nearest float, which loses precision for
+///    integers of more than 53 bits. A float is returned as it is.
+///  * `(inexact->exact x)` - a float with no fractional part becomes the same integer. A float
+///    with a fractional part, an infinity, NaN or a float outside the inline integer range is an
+///    error rather than being rounded or wrapped. An integer is returned as it is.
+///  * `(truncate x)`, `(round x)`, `(floor x)`, `(ceiling x)` - a float becomes the float with no
+///    fractional part towards zero, nearest with halves rounded to even, below or above it. An
+///    integer is returned as it is. Combine with `inexact->exact` for an integer.
+///
+/// `(min x ...)` and `(max x ...)` compare integers and floats by value and return the operand
+/// itself, so the least of `1` and `2.5` is the exact `1`. `(abs x)` keeps the representation of
+/// x. Calls to them are compiled to instructions, these builtins are what is called when they are
+/// passed as values, for example to `apply`.
+use std::cmp::Ordering;
 use std::fmt;
 
 use crate::array::Array;
-use crate::printer::Print;
-use crate::safeptr::MutatorScope;
+use crate::convert::ToValue;
+use crate::error::{err_eval, RuntimeError};
+use crate::memory::MutatorView;
+use crate::native_module;
+use crate::printer::{describe, Print};
+use crate::safeptr::{MutatorScope, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::{Value, INLINE_INTEGER_MAX, INLINE_INTEGER_MIN};
+use crate::vm::Thread;
 
 /// TODO A heap-allocated number
 pub struct NumberObject {
@@ -20,3 +48,407 @@ impl Print for NumberObject {
         write!(f, "NumberObject(nan)")
     }
 }
+
+/// A heap-allocated double precision float, for values an immediate `Float` cannot hold exactly
+pub struct FloatObject {
+    value: f64,
+}
+
+impl FloatObject {
+    pub fn new(value: f64) -> FloatObject {
+        FloatObject { value }
+    }
+
+    /// Return the float value
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+}
+
+impl Print for FloatObject {
+    fn print<'guard>(
+        &self,
+        _guard: &'guard dyn MutatorScope,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "{:?}", self.value)
+    }
+}
+
+/// Return an error for a parameter that must be a number
+fn expected_number(fn_name: &str, value: TaggedScopedPtr) -> RuntimeError {
+    err_eval(&format!(
+        "Parameter x to {} must be a number, got {}",
+        fn_name,
+        describe(*value)
+    ))
+}
+
+/// Which of two numbers `number_min_max` returns
+#[derive(Clone, Copy, PartialEq)]
+pub enum Extreme {
+    Least,
+    Greatest,
+}
+
+/// Return the least or greatest of two numbers, the first if they are equal. If either is NaN,
+/// it is the result.
+pub fn number_min_max<'guard>(
+    a: TaggedScopedPtr<'guard>,
+    b: TaggedScopedPtr<'guard>,
+    extreme: Extreme,
+    op_name: &str,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let ordering = match (*a, *b) {
+        (Value::Number(x), Value::Number(y)) => x.partial_cmp(&y),
+        (Value::Number(x), Value::Float(y)) => (x as f64).partial_cmp(&y),
+        (Value::Float(x), Value::Number(y)) => x.partial_cmp(&(y as f64)),
+        (Value::Float(x), Value::Float(y)) => x.partial_cmp(&y),
+        (Value::Number(_), _) | (Value::Float(_), _) => return Err(expected_number(op_name, b)),
+        _ => return Err(expected_number(op_name, a)),
+    };
+
+    match (ordering, extreme) {
+        (Some(Ordering::Greater), Extreme::Least) | (Some(Ordering::Less), Extreme::Greatest) => {
+            Ok(b)
+        }
+        (Some(_), _) => Ok(a),
+        // NaN is unordered
+        (None, _) => match *a {
+            Value::Float(x) if x.is_nan() => Ok(a),
+            _ => Ok(b),
+        },
+    }
+}
+
+/// Return the absolute value of a number. The absolute value of `INLINE_INTEGER_MIN` is outside
+/// the inline integer range and is an error.
+pub fn number_abs<'guard>(
+    mem: &'guard MutatorView,
+    value: TaggedScopedPtr<'guard>,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    match *value {
+        Value::Number(n) => n.abs().to_value(mem),
+        Value::Float(n) => n.abs().to_value(mem),
+        _ => Err(expected_number("abs", value)),
+    }
+}
+
+/// (min x ...) - return the least of one or more numbers
+fn min<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    fold_min_max(mem, args, Extreme::Least, "min")
+}
+
+/// (max x ...) - return the greatest of one or more numbers
+fn max<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    fold_min_max(mem, args, Extreme::Greatest, "max")
+}
+
+/// Fold arguments from left to right as the compiled `Min` and `Max` instructions do
+fn fold_min_max<'guard>(
+    mem: &'guard MutatorView,
+    args: &[TaggedCellPtr],
+    extreme: Extreme,
+    op_name: &str,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let first = args[0].get(mem);
+    args.iter().try_fold(first, |acc, arg| {
+        number_min_max(acc, arg.get(mem), extreme, op_name)
+    })
+}
+
+/// (abs x) - return the absolute value of a number
+fn abs<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    number_abs(mem, args[0].get(mem))
+}
+
+/// (integer? x) - return true if x is an integer, however it is represented
+fn is_integer<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    match *args[0].get(mem) {
+        Value::Number(_) | Value::NumberObject(_) => Ok(mem.sym_true()),
+        _ => Ok(mem.nil()),
+    }
+}
+
+/// (float? x) - return true if x is a float
+fn is_float<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    match *args[0].get(mem) {
+        Value::Float(_) => Ok(mem.sym_true()),
+        _ => Ok(mem.nil()),
+    }
+}
+
+/// (exact->inexact x) - return the float nearest to a number
+fn exact_to_inexact<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let value = args[0].get(mem);
+    match *value {
+        Value::Number(n) => (n as f64).to_value(mem),
+        Value::Float(_) => Ok(value),
+        Value::NumberObject(_) => Err(err_eval(
+            "exact->inexact of a heap allocated integer is not supported",
+        )),
+        _ => Err(expected_number("exact->inexact", value)),
+    }
+}
+
+/// (inexact->exact x) - return the integer equal to a number, which must have no fractional part
+fn inexact_to_exact<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let value = args[0].get(mem);
+    match *value {
+        Value::Float(n) if !n.is_finite() || n.fract() != 0.0 => Err(err_eval(&format!(
+            "inexact->exact of {:?} has no integer equal to it",
+            n
+        ))),
+        // the inline integer range is -2^60 to 2^60 - 1, the bounds of which are exact floats
+        Value::Float(n) if n < INLINE_INTEGER_MIN as f64 || n >= -(INLINE_INTEGER_MIN as f64) => {
+            Err(err_eval(&format!(
+                "inexact->exact of {:?} is out of the integer range {} to {}",
+                n, INLINE_INTEGER_MIN, INLINE_INTEGER_MAX
+            )))
+        }
+        Value::Float(n) => (n as isize).to_value(mem),
+        Value::Number(_) | Value::NumberObject(_) => Ok(value),
+        _ => Err(expected_number("inexact->exact", value)),
+    }
+}
+
+/// Apply a rounding function to a float, returning integers as they are
+fn round_with<'guard>(
+    mem: &'guard MutatorView,
+    args: &[TaggedCellPtr],
+    fn_name: &str,
+    round: fn(f64) -> f64,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let value = args[0].get(mem);
+    match *value {
+        Value::Float(n) => round(n).to_value(mem),
+        Value::Number(_) | Value::NumberObject(_) => Ok(value),
+        _ => Err(expected_number(fn_name, value)),
+    }
+}
+
+/// (truncate x) - return the whole number towards zero from x
+fn truncate<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    round_with(mem, args, "truncate", f64::trunc)
+}
+
+/// (round x) - return the whole number nearest to x, rounding halves to even
+fn round<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    round_with(mem, args, "round", f64::round_ties_even)
+}
+
+/// (floor x) - return the largest whole number not greater than x
+fn floor<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    round_with(mem, args, "floor", f64::floor)
+}
+
+/// (ceiling x) - return the smallest whole number not less than x
+fn ceiling<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    round_with(mem, args, "ceiling", f64::ceil)
+}
+
+native_module! {
+    /// The number type predicate, conversion and comparison builtin functions
+    pub NUMBER_MODULE = "number" {
+        "integer?" => is_integer(1),
+        "float?" => is_float(1),
+        "exact->inexact" => exact_to_inexact(1),
+        "inexact->exact" => inexact_to_exact(1),
+        "truncate" => truncate(1),
+        "round" => round(1),
+        "floor" => floor(1),
+        "ceiling" => ceiling(1),
+        "min" => min(1..),
+        "max" => max(1..),
+        "abs" => abs(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    #[test]
+    fn number_predicates_and_conversions() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code: &str| -> Result<String, RuntimeError> {
+                    Ok(format!(
+                        "{}",
+                        t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)?
+                    ))
+                };
+                let float = |n: &str| format!("(json-decode \"{}\")", n);
+
+                assert!(eval("(integer? 3)")? == "true");
+                assert!(eval(&format!("(integer? {})", float("3.0")))? == "nil");
+                assert!(eval(&format!("(float? {})", float("3.0")))? == "true");
+                assert!(eval("(float? 3)")? == "nil");
+                assert!(eval("(integer? 'a)")? == "nil");
+
+                assert!(eval("(exact->inexact -7)")? == "-7.0");
+                assert!(eval("(float? (exact->inexact 7))")? == "true");
+                assert!(eval("(inexact->exact (exact->inexact 7))")? == "7");
+                assert!(eval("(inexact->exact 7)")? == "7");
+                // floats are double precision, whether or not they are immediate
+                assert!(eval("(exact->inexact 16777217)")? == "16777217.0");
+                assert!(eval("(exact->inexact 123456789)")? == "123456789.0");
+                assert!(eval(&float("16777217.0"))? == "16777217.0");
+                assert!(eval(&format!("(floor {})", float("123456789.5")))? == "123456789.0");
+                // integers of more than 53 bits are rounded to the nearest float
+                assert!(eval("(exact->inexact 9007199254740993)")? == "9007199254740992.0");
+
+                let rounded = |f: &str, n: &str| eval(&format!("({} {})", f, float(n)));
+                assert!(rounded("truncate", "-2.7")? == "-2.0");
+                assert!(rounded("floor", "-2.7")? == "-3.0");
+                assert!(rounded("ceiling", "-2.7")? == "-2.0");
+                assert!(rounded("round", "-2.7")? == "-3.0");
+                assert!(rounded("round", "2.5")? == "2.0");
+                assert!(rounded("round", "3.5")? == "4.0");
+                assert!(eval("(floor 5)")? == "5");
+                assert!(eval(&format!("(inexact->exact (round {}))", float("7.5")))? == "8");
+
+                // a float with a fractional part or outside the integer range has no exact value
+                assert!(eval(&format!("(inexact->exact {})", float("2.5"))).is_err());
+                assert!(eval(&format!("(inexact->exact {})", float("1e30"))).is_err());
+                assert!(eval(&format!("(inexact->exact {})", float("-1e30"))).is_err());
+                let reason = |code: &str| match eval(code) {
+                    Err(e) => format!("{}", e),
+                    Ok(_) => panic!("expected an error"),
+                };
+                assert!(
+                    reason(&format!("(inexact->exact {})", float("1e20"))).contains(&format!(
+                        "inexact->exact of 1e20 is out of the integer range {} to {}",
+                        INLINE_INTEGER_MIN, INLINE_INTEGER_MAX
+                    ))
+                );
+                // the bounds of the range are exact
+                let bound = -(INLINE_INTEGER_MIN as f64);
+                assert!(eval(&format!(
+                    "(inexact->exact {})",
+                    float(&format!("{:?}", bound))
+                ))
+                .is_err());
+                let min = INLINE_INTEGER_MIN as f64;
+                assert!(
+                    eval(&format!(
+                        "(inexact->exact {})",
+                        float(&format!("{:?}", min))
+                    ))? == INLINE_INTEGER_MIN.to_string()
+                );
+
+                assert!(eval("(round 'a)").is_err());
+                assert!(eval("(exact->inexact \"1\")").is_err());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn number_min_max_and_abs() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code: &str| -> Result<String, RuntimeError> {
+                    Ok(format!(
+                        "{}",
+                        t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)?
+                    ))
+                };
+
+                // compiled to instructions
+                assert!(eval("(min 3 1 2)")? == "1");
+                assert!(eval("(max 3 1 2)")? == "3");
+                assert!(eval("(max -4)")? == "-4");
+                assert!(eval("(abs -4)")? == "4");
+                assert!(eval("(abs (json-decode \"-2.5\"))")? == "2.5");
+
+                // integers and floats are compared by value and keep their representation
+                assert!(eval("(min 2 (json-decode \"2.5\"))")? == "2");
+                assert!(eval("(max 2 (json-decode \"2.5\"))")? == "2.5");
+                assert!(eval("(max 2 (exact->inexact 2))")? == "2");
+
+                // called as a value, with any number of arguments
+                assert!(eval("(apply min '(5 -1 3 0))")? == "-1");
+                assert!(eval("(apply max '(5 -1 3 0))")? == "5");
+                assert!(eval("(apply abs '(-7))")? == "7");
+                eval("(def pick (f) (f 4 9 2))")?;
+                assert!(eval("(pick max)")? == "9");
+                assert!(eval("(apply min nil)").is_err());
+
+                assert!(eval("(min 1 'a)").is_err());
+                assert!(eval("(apply max '(1 a))").is_err());
+                assert!(eval("(abs \"1\")").is_err());
+                // the absolute value of the least inline integer is out of range
+                assert!(eval(&format!("(abs {})", crate::taggedptr::INLINE_INTEGER_MIN)).is_err());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -108,6 +108,36 @@ pub fn cons<'guard>(
     mem.alloc_tagged(pair)
 }
 
+/// Return the count of Pairs in a nil terminated list of Pair instances. A list whose Pairs form a
+/// cycle is an error rather than an endless loop: a second cursor follows at half speed, and the
+/// two meet only if the list never ends.
+pub fn pair_list_length<'guard>(
+    guard: &'guard dyn MutatorScope,
+    pair_list: TaggedScopedPtr<'guard>,
+) -> Result<usize, RuntimeError> {
+    let mut length = 0;
+    let mut next = pair_list;
+    let mut behind = pair_list;
+
+    loop {
+        match *next {
+            Value::Pair(pair) => next = pair.second.get(guard),
+            Value::Nil => return Ok(length),
+            _ => return Err(err_eval("Incorrectly terminated Pair list")),
+        }
+        length += 1;
+
+        if length % 2 == 0 {
+            if let Value::Pair(pair) = *behind {
+                behind = pair.second.get(guard);
+            }
+            if next == behind {
+                return Err(err_eval("Pair list is circular"));
+            }
+        }
+    }
+}
+
 /// Unpack a list of Pair instances into a Vec
 pub fn vec_from_pairs<'guard>(
     guard: &'guard dyn MutatorScope,
@@ -237,6 +267,38 @@ mod test {
         mem.mutate(&test, test_fn).unwrap();
     }
 
+    #[test]
+    fn pair_list_length_finite_and_circular() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let mut list = mem.nil();
+            for length in 0..6 {
+                assert!(pair_list_length(mem, list)? == length);
+                list = cons(mem, mem.number(length as isize), list)?;
+            }
+
+            // join the end of the list to its second Pair
+            let mut last = list;
+            while let Value::Pair(pair) = *last {
+                match *pair.second.get(mem) {
+                    Value::Nil => {
+                        if let Value::Pair(first) = *list {
+                            pair.second.set(first.second.get(mem));
+                        }
+                        break;
+                    }
+                    _ => last = pair.second.get(mem),
+                }
+            }
+
+            assert!(pair_list_length(mem, list).is_err());
+            assert!(pair_list_length(mem, mem.lookup_sym("a")).is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner)
+    }
+
     #[test]
     fn unpack_pair_list_bad() {
         fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
@@ -1,13 +1,14 @@
-use std::iter::Peekable;
 use std::marker::PhantomData;
 
+use crate::array::ArrayU8;
+use crate::containers::StackContainer;
+use crate::convert::ToValue;
 use crate::error::{err_parser, err_parser_wpos, RuntimeError, SourcePos};
-use crate::lexer::{tokenize, Token, TokenType};
+use crate::lexer::{is_fraction, is_integer, tokenize, Token, TokenType};
 use crate::memory::MutatorView;
 use crate::pair::Pair;
 use crate::safeptr::{MutatorScope, TaggedCellPtr, TaggedScopedPtr};
-use crate::taggedptr::Value;
-use crate::text;
+use crate::taggedptr::{Value, INLINE_INTEGER_MAX, INLINE_INTEGER_MIN};
 
 // A linked list, internal to the parser to simplify the code and is stored on the Rust stack
 struct PairList<'guard> {
@@ -78,6 +79,52 @@ impl<'guard> PairList<'guard> {
     }
 }
 
+/// A token source with one token of look-ahead. Tokens are only pulled from the underlying
+/// iterator as they are needed.
+struct Tokens<I> {
+    tokens: I,
+    peeked: Option<Token>,
+}
+
+impl<I> Tokens<I>
+where
+    I: Iterator<Item = Result<Token, RuntimeError>>,
+{
+    fn new(tokens: I) -> Tokens<I> {
+        Tokens {
+            tokens,
+            peeked: None,
+        }
+    }
+
+    /// Look at the next token without consuming it
+    fn peek(&mut self) -> Result<Option<&Token>, RuntimeError> {
+        if self.peeked.is_none() {
+            self.peeked = self.next_expression_token()?;
+        }
+        Ok(self.peeked.as_ref())
+    }
+
+    /// Consume the next token
+    fn next(&mut self) -> Result<Option<Token>, RuntimeError> {
+        match self.peeked.take() {
+            Some(token) => Ok(Some(token)),
+            None => self.next_expression_token(),
+        }
+    }
+
+    /// Pull the next token that is not trivia from the underlying iterator
+    fn next_expression_token(&mut self) -> Result<Option<Token>, RuntimeError> {
+        for token in &mut self.tokens {
+            let token = token?;
+            if !token.is_trivia() {
+                return Ok(Some(token));
+            }
+        }
+        Ok(None)
+    }
+}
+
 //
 // A list is either
 // * empty
@@ -90,26 +137,29 @@ impl<'guard> PairList<'guard> {
 // If a list token is:
 //  * a Dot, it must be followed by an s-expression and a CloseParen
 //
-fn parse_list<'guard, 'i, I: 'i>(
+fn parse_list<'guard, I>(
     mem: &'guard MutatorView,
-    tokens: &mut Peekable<I>,
+    tokens: &mut Tokens<I>,
 ) -> Result<TaggedScopedPtr<'guard>, RuntimeError>
 where
-    I: Iterator<Item = &'i Token>,
+    I: Iterator<Item = Result<Token, RuntimeError>>,
 {
     use self::TokenType::*;
 
     // peek at very first token after the open-paren
-    match tokens.peek() {
-        Some(&&Token {
-            token: CloseParen,
-            pos: _,
+    match tokens.peek()? {
+        Some(&Token {
+            token: CloseParen, ..
         }) => {
-            tokens.next();
+            tokens.next()?;
             return Ok(mem.nil());
         }
 
-        Some(&&Token { token: Dot, pos }) => {
+        Some(&Token {
+            token: Dot,
+            start: pos,
+            ..
+        }) => {
             return Err(err_parser_wpos(
                 pos,
                 "Unexpected '.' dot after open-parenthesis",
@@ -122,45 +172,26 @@ where
     // we have what looks like a valid list so far...
     let mut list = PairList::open(mem);
     loop {
-        match tokens.peek() {
-            Some(&&Token {
-                token: OpenParen,
-                pos,
+        match tokens.peek()? {
+            Some(&Token {
+                token: Dot,
+                start: pos,
+                ..
             }) => {
-                tokens.next();
-                list.push(mem, parse_list(mem, tokens)?, pos)?;
-            }
-
-            Some(&&Token {
-                token: Symbol(_),
-                pos,
-            }) => {
-                list.push(mem, parse_sexpr(mem, tokens)?, pos)?;
-            }
-
-            Some(&&Token {
-                token: Text(_),
-                pos,
-            }) => {
-                list.push(mem, parse_sexpr(mem, tokens)?, pos)?;
-            }
-
-            Some(&&Token { token: Quote, pos }) => {
-                list.push(mem, parse_sexpr(mem, tokens)?, pos)?;
-            }
-
-            Some(&&Token { token: Dot, pos }) => {
-                tokens.next();
+                tokens.next()?;
                 list.dot(mem, parse_sexpr(mem, tokens)?, pos);
 
                 // the only valid sequence here on out is Dot s-expression CloseParen
-                match tokens.peek() {
-                    Some(&&Token {
-                        token: CloseParen,
-                        pos: _,
+                match tokens.peek()? {
+                    Some(&Token {
+                        token: CloseParen, ..
                     }) => (),
 
-                    Some(&&Token { token: _, pos }) => {
+                    Some(&Token {
+                        token: _,
+                        start: pos,
+                        ..
+                    }) => {
                         return Err(err_parser_wpos(
                             pos,
                             "Dotted pair must be closed by a ')' close-parenthesis",
@@ -171,14 +202,22 @@ where
                 }
             }
 
-            Some(&&Token {
-                token: CloseParen,
-                pos: _,
+            Some(&Token {
+                token: CloseParen, ..
             }) => {
-                tokens.next();
+                tokens.next()?;
                 break;
             }
 
+            // any other token begins an s-expression
+            Some(&Token {
+                token: _,
+                start: pos,
+                ..
+            }) => {
+                list.push(mem, parse_sexpr(mem, tokens)?, pos)?;
+            }
+
             None => {
                 return Err(err_parser("Unexpected end of code stream"));
             }
@@ -188,6 +227,59 @@ where
     Ok(list.close(mem))
 }
 
+//
+// Parse the remainder of a byte array literal, after the #u8( token: a sequence of integers in
+// the range 0 to 255 followed by a CloseParen
+//
+fn parse_bytes<'guard, I>(
+    mem: &'guard MutatorView,
+    tokens: &mut Tokens<I>,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError>
+where
+    I: Iterator<Item = Result<Token, RuntimeError>>,
+{
+    use self::TokenType::*;
+
+    let bytes = ArrayU8::alloc(mem)?;
+
+    loop {
+        match tokens.next()? {
+            Some(Token {
+                token: CloseParen, ..
+            }) => break,
+
+            Some(Token {
+                token: Symbol(name),
+                start: pos,
+                ..
+            }) => match name.parse::<u8>() {
+                Ok(byte) => StackContainer::push(&*bytes, mem, byte)?,
+                Err(_) => {
+                    return Err(err_parser_wpos(
+                        pos,
+                        "A byte array literal may only contain integers from 0 to 255",
+                    ))
+                }
+            },
+
+            Some(Token {
+                token: _,
+                start: pos,
+                ..
+            }) => {
+                return Err(err_parser_wpos(
+                    pos,
+                    "A byte array literal may only contain integers from 0 to 255",
+                ))
+            }
+
+            None => return Err(err_parser("Unexpected end of code stream")),
+        }
+    }
+
+    Ok(bytes.as_tagged(mem))
+}
+
 //
 // Parse a single s-expression
 //
@@ -195,48 +287,70 @@ where
 //  * symbol
 //  * or a list
 //
-fn parse_sexpr<'guard, 'i, I: 'i>(
+fn parse_sexpr<'guard, I>(
     mem: &'guard MutatorView,
-    tokens: &mut Peekable<I>,
+    tokens: &mut Tokens<I>,
 ) -> Result<TaggedScopedPtr<'guard>, RuntimeError>
 where
-    I: Iterator<Item = &'i Token>,
+    I: Iterator<Item = Result<Token, RuntimeError>>,
 {
     use self::TokenType::*;
 
-    match tokens.peek() {
-        Some(&&Token {
-            token: OpenParen,
-            pos: _,
-        }) => {
-            tokens.next();
-            parse_list(mem, tokens)
-        }
+    match tokens.next()? {
+        Some(Token {
+            token: OpenParen, ..
+        }) => parse_list(mem, tokens),
 
-        Some(&&Token {
-            token: Symbol(ref name),
-            pos: _,
+        Some(Token {
+            token: OpenBytes, ..
+        }) => parse_bytes(mem, tokens),
+
+        Some(Token {
+            token: Symbol(name),
+            start: pos,
+            ..
         }) => {
-            tokens.next();
             // the symbol 'nil' is reinterpreted as a literal nil value
             if name == "nil" {
                 Ok(mem.nil())
+            } else if is_integer(&name) {
+                // a symbol that is entirely a decimal integer is an inline integer literal
+                match name.parse::<isize>() {
+                    Ok(number) if (INLINE_INTEGER_MIN..=INLINE_INTEGER_MAX).contains(&number) => {
+                        Ok(mem.number(number))
+                    }
+                    _ => Err(err_parser_wpos(
+                        pos,
+                        "Integer literal out of range for an inline integer",
+                    )),
+                }
+            } else if is_fraction(&name) {
+                // a symbol that is a decimal number with a fractional part is a float literal
+                match name.parse::<f64>() {
+                    Ok(number) if number.is_finite() => number.to_value(mem),
+                    _ => Err(err_parser_wpos(pos, "Float literal out of range")),
+                }
             } else {
-                Ok(mem.lookup_sym(name))
+                Ok(mem.lookup_sym(&name))
             }
         }
 
-        Some(&&Token {
-            token: Text(ref string),
-            pos: _,
+        // a quoted symbol is always a symbol, never reinterpreted as another type
+        Some(Token {
+            token: QuotedSymbol(name),
+            ..
+        }) => Ok(mem.lookup_sym(&name)),
+
+        Some(Token {
+            token: Text(string),
+            ..
+        }) => mem.text(&string),
+
+        Some(Token {
+            token: Quote,
+            start: pos,
+            ..
         }) => {
-            tokens.next();
-            let text = mem.alloc_tagged(text::Text::new_from_str(mem, &string)?)?;
-            Ok(text)
-        }
-
-        Some(&&Token { token: Quote, pos }) => {
-            tokens.next();
             // create a (quote x) pair here
             // parse_sexpr() for x
             let mut list = PairList::open(mem);
@@ -246,26 +360,55 @@ where
             Ok(list.close(mem))
         }
 
-        Some(&&Token { token: Dot, pos }) => Err(err_parser_wpos(pos, "Invalid symbol '.'")),
+        Some(Token {
+            token: Dot,
+            start: pos,
+            ..
+        }) => Err(err_parser_wpos(pos, "Invalid symbol '.'")),
 
-        Some(&&Token {
+        Some(Token {
             token: CloseParen,
-            pos,
+            start: pos,
+            ..
         }) => Err(err_parser_wpos(pos, "Unmatched close parenthesis")),
 
-        None => {
-            tokens.next();
-            Ok(mem.nil())
-        }
+        // Tokens never returns trivia
+        Some(Token {
+            token: Whitespace(_),
+            ..
+        }) => unreachable!(),
+
+        None => Ok(mem.nil()),
     }
 }
 
-fn parse_tokens<'guard>(
-    mem: &'guard MutatorView,
-    tokens: Vec<Token>,
-) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
-    let mut tokenstream = tokens.iter().peekable();
-    parse_sexpr(mem, &mut tokenstream)
+/// Parses a stream of tokens into a sequence of top level s-expressions, one at a time, reading
+/// only as many tokens as each expression needs.
+pub struct Parser<I> {
+    tokens: Tokens<I>,
+}
+
+impl<I> Parser<I>
+where
+    I: Iterator<Item = Result<Token, RuntimeError>>,
+{
+    pub fn new(tokens: I) -> Parser<I> {
+        Parser {
+            tokens: Tokens::new(tokens),
+        }
+    }
+
+    /// Parse the next top level s-expression, returning None at the end of the token stream
+    pub fn next_expr<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+    ) -> Result<Option<TaggedScopedPtr<'guard>>, RuntimeError> {
+        if self.tokens.peek()?.is_none() {
+            return Ok(None);
+        }
+
+        parse_sexpr(mem, &mut self.tokens).map(Some)
+    }
 }
 
 /// Parse the given string into an AST
@@ -273,12 +416,15 @@ pub fn parse<'guard>(
     mem: &'guard MutatorView,
     input: &str,
 ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
-    parse_tokens(mem, tokenize(input)?)
+    // the whole string is tokenized up front so that lexer errors anywhere in it are reported
+    let tokens = tokenize(input)?;
+    parse_sexpr(mem, &mut Tokens::new(tokens.into_iter().map(Ok)))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::lexer::lex_reader;
     use crate::memory::{Memory, Mutator, MutatorView};
     use crate::printer::print;
 
@@ -383,6 +529,170 @@ mod test {
         check(&input, &expect);
     }
 
+    #[test]
+    fn parse_integers() {
+        let input = String::from("(1 -23 456)");
+        let expect = input.clone();
+        check(&input, &expect);
+    }
+
+    #[test]
+    fn parse_signs_and_dotted_symbols() {
+        let input = String::from("(- -3 +3 ... .foo (a . b))");
+        let expect = String::from("(- -3 3 ... .foo (a . b))");
+        check(&input, &expect);
+    }
+
+    #[test]
+    fn parse_fractions() {
+        let input = String::from("(a .5 1.5 -0.5 +2. 0.0)");
+        let expect = String::from("(a 0.5 1.5 -0.5 2.0 0.0)");
+        check(&input, &expect);
+    }
+
+    #[test]
+    fn parse_float_out_of_range() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _: Self::Input) -> Result<Self::Output, RuntimeError> {
+                let huge = format!("(a {}.5)", "9".repeat(400));
+                match parse(mem, &huge) {
+                    Err(e) => assert!(
+                        format!("{}", e)
+                            == "Parse error: Float literal out of range (line 1, column 3)"
+                    ),
+                    Ok(_) => panic!("expected an error"),
+                }
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
+
+    #[test]
+    fn parse_integer_out_of_range() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _: Self::Input) -> Result<Self::Output, RuntimeError> {
+                let input = format!("{}", INLINE_INTEGER_MAX as i128 + 1);
+                assert!(parse(mem, &input).is_err());
+
+                let input = format!("{}", INLINE_INTEGER_MIN);
+                assert!(parse(mem, &input).is_ok());
+
+                // beyond the range of isize too, rather than being read as a symbol
+                for input in &["(a 99999999999999999999)", "(a -99999999999999999999)"] {
+                    match parse(mem, input) {
+                        Err(e) => assert!(
+                            format!("{}", e)
+                                == "Parse error: Integer literal out of range for an inline integer \
+                                    (line 1, column 3)"
+                        ),
+                        Ok(_) => panic!("expected an error"),
+                    }
+                }
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
+
+    #[test]
+    fn parse_byte_arrays() {
+        let input = String::from("(a #u8(1 2 255) #u8() #u8 (1))");
+        let expect = String::from("(a #u8(1 2 255) #u8() #u8 (1))");
+        check(&input, &expect);
+    }
+
+    #[test]
+    fn parse_byte_array_out_of_range() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _: Self::Input) -> Result<Self::Output, RuntimeError> {
+                assert!(parse(mem, "#u8(1 256)").is_err());
+                assert!(parse(mem, "#u8(1 -1)").is_err());
+                assert!(parse(mem, "#u8(a)").is_err());
+                assert!(parse(mem, "#u8((1))").is_err());
+                assert!(parse(mem, "#u8(1 2").is_err());
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
+
+    #[test]
+    fn parse_quoted_symbols() {
+        let input = String::from("(|a b| |nil| |12| |x| |'y| |a\\|b|)");
+        let expect = String::from("(|a b| |nil| |12| x |'y| a|b)");
+        check(&input, &expect);
+    }
+
+    #[test]
+    fn parse_expression_stream() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _: Self::Input) -> Result<Self::Output, RuntimeError> {
+                let source: &[u8] = b"(a b)\n'c\n  d (e . f)";
+                let mut parser = Parser::new(lex_reader(source));
+
+                let mut exprs = Vec::new();
+                while let Some(expr) = parser.next_expr(mem)? {
+                    exprs.push(print(*expr));
+                }
+
+                assert!(exprs == vec!["(a b)", "(quote c)", "d", "(e . f)"]);
+
+                // whitespace kept by the lexer is skipped
+                let mut parser = Parser::new(lex_reader(source).with_trivia());
+                let mut with_trivia = Vec::new();
+                while let Some(expr) = parser.next_expr(mem)? {
+                    with_trivia.push(print(*expr));
+                }
+                assert!(with_trivia == exprs);
+
+                // an unterminated expression is an error rather than the end of the stream
+                let source: &[u8] = b"(a b) (c";
+                let mut parser = Parser::new(lex_reader(source));
+                assert!(parser.next_expr(mem).is_ok());
+                assert!(parser.next_expr(mem).is_err());
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
+
     #[test]
     fn parse_dot_notation_with_nil() {
         let input = String::from("(a . ())");
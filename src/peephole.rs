@@ -0,0 +1,142 @@
+/// A peephole optimizer that rewrites the jumps of compiled ByteCode.
+///
+/// The compiler patches each jump to the instruction after the code it skips, which is often
+/// another jump, for example at the end of a cond nested in an arm of another cond, or the Return
+/// of the function. At optimization level 1 every jump is pointed straight at the end of such a
+/// chain of jumps, and a jump to a Return becomes that Return.
+///
+/// Instructions are only rewritten in place, never added or removed, so the offsets of the other
+/// jumps and the source spans recorded for each instruction remain valid.
+use std::convert::TryFrom;
+
+use crate::array::ArraySize;
+use crate::bytecode::{ByteCode, JumpOffset, Opcode};
+use crate::error::RuntimeError;
+use crate::memory::MutatorView;
+
+/// Return the index of the instruction a jump at the given index goes to
+fn jump_target(instruction: usize, offset: JumpOffset) -> usize {
+    (instruction as isize + 1 + offset as isize) as usize
+}
+
+/// Return the instruction that a jump to the given one ends up at, following unconditional jumps
+fn final_target(opcodes: &[Opcode], mut target: usize) -> usize {
+    // a chain can be no longer than the code, which also stops an endless loop of jumps
+    for _ in 0..opcodes.len() {
+        match opcodes.get(target) {
+            Some(Opcode::Jump { offset }) => target = jump_target(target, *offset),
+            _ => break,
+        }
+    }
+    target
+}
+
+/// Rewrite the jumps of the given ByteCode to skip chains of jumps and to return directly
+pub fn optimize<'guard>(mem: &'guard MutatorView, code: &ByteCode) -> Result<(), RuntimeError> {
+    let opcodes = code.opcodes(mem);
+
+    for (instruction, opcode) in opcodes.iter().enumerate() {
+        let offset = match opcode {
+            Opcode::Jump { offset }
+            | Opcode::JumpIfTrue { offset, .. }
+            | Opcode::JumpIfNotTrue { offset, .. } => *offset,
+            _ => continue,
+        };
+
+        let target = final_target(&opcodes, jump_target(instruction, offset));
+
+        match (opcode, opcodes.get(target)) {
+            (Opcode::Jump { .. }, Some(Opcode::Return { reg })) => {
+                code.replace(mem, instruction as ArraySize, Opcode::Return { reg: *reg })?
+            }
+
+            // the new offset may not fit, in which case the jump is left as it is
+            _ => {
+                let distance = target as isize - instruction as isize - 1;
+                if let Ok(new_offset) = JumpOffset::try_from(distance) {
+                    if new_offset != offset {
+                        code.update_jump_offset(mem, instruction as ArraySize, new_offset)?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::{compile_toplevel_in_context, CompileContext, CompileOptions, OptLevel};
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    #[test]
+    fn peephole_threads_jumps() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let opcodes = |code, opt_level| -> Result<Vec<Opcode>, RuntimeError> {
+                    let options = CompileOptions {
+                        opt_level,
+                        ..CompileOptions::default()
+                    };
+                    let context = CompileContext::new(options);
+                    let forms = [parse(mem, code)?];
+                    let function = compile_toplevel_in_context(mem, &forms, None, context)?.0;
+                    Ok(function.code(mem).opcodes(mem))
+                };
+
+                // the end of the inner cond jumps to the end of the outer, which returns
+                let code = "(cond (nil? a) (cond (nil? b) 'x (nil? c) 'y) (nil? d) 'z)";
+
+                let unoptimized = opcodes(code, OptLevel::O0)?;
+                let optimized = opcodes(code, OptLevel::O1)?;
+                assert!(unoptimized.len() == optimized.len());
+
+                let jump_targets = |opcodes: &[Opcode]| -> Vec<Opcode> {
+                    opcodes
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(index, opcode)| match opcode {
+                            Opcode::Jump { offset } | Opcode::JumpIfNotTrue { offset, .. } => {
+                                Some(opcodes[jump_target(index, *offset)])
+                            }
+                            _ => None,
+                        })
+                        .collect()
+                };
+
+                assert!(jump_targets(&unoptimized)
+                    .iter()
+                    .any(|target| matches!(target, Opcode::Jump { .. })));
+                assert!(!jump_targets(&optimized)
+                    .iter()
+                    .any(|target| matches!(target, Opcode::Jump { .. })));
+
+                // only the conditional jumps are left, the rest return
+                assert!(optimized
+                    .iter()
+                    .all(|opcode| !matches!(opcode, Opcode::Jump { .. })));
+                let returns = |opcodes: &[Opcode]| {
+                    opcodes
+                        .iter()
+                        .filter(|opcode| matches!(opcode, Opcode::Return { .. }))
+                        .count()
+                };
+                assert!(returns(&unoptimized) == 1);
+                assert!(returns(&optimized) == 5);
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
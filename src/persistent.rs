@@ -0,0 +1,998 @@
+/// Persistent lists and maps: immutable containers whose updates return a new container that
+/// shares most of its structure with the old one, which is left unchanged.
+///
+/// A `PersistentList` is a singly linked list that knows its length. Adding an item to the front
+/// allocates one node and shares the rest of the list.
+///
+/// A `PersistentMap` is a hash array mapped trie in the CHAMP layout. Each node consumes five bits
+/// of a key's hash: `datamap` has a bit set for each hash fragment holding a key and value in the
+/// node and `nodemap` a bit for each fragment holding a child node. The node's slots are the keys
+/// and values of its data entries followed by its child nodes, each in bit order. An update
+/// copies the nodes on the path to the key, about log32 of the map size, and shares every other
+/// node. Keys whose 64 bit hashes are equal end up in a collision node, where both bitmaps are
+/// zero and the slots are key and value pairs in no particular order.
+///
+/// Keys are hashed with `hash_value()`, so symbols, integers and strings can be keys, and are equal
+/// if they are the same value or strings with the same content.
+use std::fmt;
+
+use stickyimmix::ArraySize;
+
+use crate::containers::{
+    AnyContainerFromSlice, Container, ImmutableContainer, IndexedAnyContainer,
+};
+use crate::dict::key_order;
+use crate::error::{err_eval, ErrorKind, RuntimeError};
+use crate::hashable::hash_value;
+use crate::list::List;
+use crate::memory::MutatorView;
+use crate::native::arg;
+use crate::native_module;
+use crate::pair::cons;
+use crate::printer::{describe, print_elements, Print};
+use crate::safeptr::{MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+use crate::vm::Thread;
+
+/// The number of hash bits consumed by each level of a map
+const FRAGMENT_BITS: u32 = 5;
+
+/// The number of bits in a hash, after which keys are kept in collision nodes
+const HASH_BITS: u32 = 64;
+
+/// Return the bit of a node's bitmaps for the fragment of the hash at the given shift
+fn fragment_bit(hash: u64, shift: u32) -> u32 {
+    1 << ((hash >> shift) & ((1 << FRAGMENT_BITS) - 1))
+}
+
+/// Return the position among the set bits of a bitmap of the given bit
+fn bit_index(bitmap: u32, bit: u32) -> usize {
+    (bitmap & (bit - 1)).count_ones() as usize
+}
+
+/// Return true if two keys are the same value or strings with the same content
+fn keys_equal<'guard>(
+    guard: &'guard dyn MutatorScope,
+    a: TaggedScopedPtr<'guard>,
+    b: TaggedScopedPtr<'guard>,
+) -> bool {
+    match (*a, *b) {
+        (Value::Text(a), Value::Text(b)) => a.as_str(guard) == b.as_str(guard),
+        _ => a == b,
+    }
+}
+
+/// An immutable singly linked list
+pub struct PersistentList {
+    /// The first item, or nil if the list is empty
+    first: TaggedCellPtr,
+    /// The PersistentList of the items after the first, or nil if there are none
+    rest: TaggedCellPtr,
+    length: ArraySize,
+}
+
+impl PersistentList {
+    /// Allocate a list of the given items
+    pub fn from_slice<'guard>(
+        mem: &'guard MutatorView,
+        items: &[TaggedScopedPtr<'guard>],
+    ) -> Result<ScopedPtr<'guard, PersistentList>, RuntimeError> {
+        let mut list = mem.alloc(PersistentList::new())?;
+        for item in items.iter().rev() {
+            list = PersistentList::cons(mem, *item, list)?;
+        }
+        Ok(list)
+    }
+
+    /// Return a new list of the given item followed by the items of the given list
+    pub fn cons<'guard>(
+        mem: &'guard MutatorView,
+        item: TaggedScopedPtr<'guard>,
+        list: ScopedPtr<'guard, PersistentList>,
+    ) -> Result<ScopedPtr<'guard, PersistentList>, RuntimeError> {
+        let rest = match list.length {
+            0 => mem.nil(),
+            _ => list.as_tagged(mem),
+        };
+
+        mem.alloc(PersistentList {
+            first: TaggedCellPtr::new_with(item),
+            rest: TaggedCellPtr::new_with(rest),
+            length: list.length + 1,
+        })
+    }
+
+    /// Return the first item, or nil if the list is empty
+    pub fn first<'guard>(&self, guard: &'guard dyn MutatorScope) -> TaggedScopedPtr<'guard> {
+        self.first.get(guard)
+    }
+
+    /// Return the list of the items after the first, which is empty if there are none
+    pub fn rest<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<ScopedPtr<'guard, PersistentList>, RuntimeError> {
+        match *self.rest.get(mem) {
+            Value::PersistentList(rest) => Ok(rest),
+            _ => mem.alloc(PersistentList::new()),
+        }
+    }
+
+    /// Return the items of the list in order
+    pub fn items<'guard>(&self, guard: &'guard dyn MutatorScope) -> Vec<TaggedScopedPtr<'guard>> {
+        let mut items = Vec::with_capacity(self.length as usize);
+        if self.length == 0 {
+            return items;
+        }
+
+        items.push(self.first.get(guard));
+        let mut rest = self.rest.get(guard);
+        while let Value::PersistentList(list) = *rest {
+            items.push(list.first.get(guard));
+            rest = list.rest.get(guard);
+        }
+        items
+    }
+
+    /// Return the item at the given index
+    pub fn get<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        index: ArraySize,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        if index >= self.length {
+            return Err(RuntimeError::new(ErrorKind::BoundsError));
+        }
+
+        let mut item = self.first.get(guard);
+        let mut rest = self.rest.get(guard);
+        for _ in 0..index {
+            match *rest {
+                Value::PersistentList(list) => {
+                    item = list.first.get(guard);
+                    rest = list.rest.get(guard);
+                }
+                _ => return Err(RuntimeError::new(ErrorKind::BoundsError)),
+            }
+        }
+        Ok(item)
+    }
+}
+
+impl Container<TaggedCellPtr> for PersistentList {
+    fn new() -> PersistentList {
+        PersistentList {
+            first: TaggedCellPtr::new_nil(),
+            rest: TaggedCellPtr::new_nil(),
+            length: 0,
+        }
+    }
+
+    fn with_capacity<'guard>(
+        _mem: &'guard MutatorView,
+        _capacity: ArraySize,
+    ) -> Result<PersistentList, RuntimeError> {
+        Ok(PersistentList::new())
+    }
+
+    fn clear<'guard>(&self, _mem: &'guard MutatorView) -> Result<(), RuntimeError> {
+        Err(err_eval("A persistent list cannot be cleared"))
+    }
+
+    fn length(&self) -> ArraySize {
+        self.length
+    }
+}
+
+impl ImmutableContainer<TaggedCellPtr> for PersistentList {}
+
+impl Print for PersistentList {
+    /// Prints `(plist a b c)`, the call that would make the list
+    fn print<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        if self.length == 0 {
+            return write!(f, "(plist)");
+        }
+
+        let items = self.items(guard);
+        print_elements(f, "(plist ", " ", ")", items.len(), items, |f, item| {
+            fmt::Display::fmt(&item.value(), f)
+        })
+    }
+}
+
+/// An immutable hash map, and each node of its trie
+pub struct PersistentMap {
+    /// A bit for each hash fragment that has a key and value in this node
+    datamap: u32,
+    /// A bit for each hash fragment that has a child node
+    nodemap: u32,
+    /// The count of entries in this node and its children
+    length: ArraySize,
+    /// A List of the keys and values of the data entries followed by the child nodes, or nil if
+    /// the node is empty
+    slots: TaggedCellPtr,
+}
+
+impl PersistentMap {
+    /// Allocate a node with the given slots
+    fn alloc_node<'guard>(
+        mem: &'guard MutatorView,
+        datamap: u32,
+        nodemap: u32,
+        length: ArraySize,
+        slots: &[TaggedScopedPtr<'guard>],
+    ) -> Result<ScopedPtr<'guard, PersistentMap>, RuntimeError> {
+        let slots = match slots.len() {
+            0 => mem.nil(),
+            _ => List::from_slice(mem, slots)?.as_tagged(mem),
+        };
+
+        mem.alloc(PersistentMap {
+            datamap,
+            nodemap,
+            length,
+            slots: TaggedCellPtr::new_with(slots),
+        })
+    }
+
+    /// Allocate a map of the given keys and values
+    pub fn from_pairs<'guard>(
+        mem: &'guard MutatorView,
+        pairs: &[(TaggedScopedPtr<'guard>, TaggedScopedPtr<'guard>)],
+    ) -> Result<ScopedPtr<'guard, PersistentMap>, RuntimeError> {
+        let mut map = mem.alloc(PersistentMap::new())?;
+        for (key, value) in pairs {
+            map = PersistentMap::assoc(mem, map, *key, *value)?;
+        }
+        Ok(map)
+    }
+
+    /// Return a copy of the node's slots
+    fn slot_values<'guard>(&self, guard: &'guard dyn MutatorScope) -> Vec<TaggedScopedPtr<'guard>> {
+        match *self.slots.get(guard) {
+            Value::List(slots) => (0..slots.length())
+                .filter_map(|index| IndexedAnyContainer::get(&*slots, guard, index).ok())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Return the slot holding the child node for the given bit
+    fn node_slot(&self, bit: u32) -> usize {
+        2 * self.datamap.count_ones() as usize + bit_index(self.nodemap, bit)
+    }
+
+    /// Return the count of key and value entries in this node, not counting its children
+    fn data_entries(&self, slots: &[TaggedScopedPtr]) -> usize {
+        match (self.datamap, self.nodemap) {
+            // a collision node
+            (0, 0) => slots.len() / 2,
+            (datamap, _) => datamap.count_ones() as usize,
+        }
+    }
+
+    /// Return the value associated with the key, if there is one
+    pub fn lookup<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        key: TaggedScopedPtr<'guard>,
+    ) -> Result<Option<TaggedScopedPtr<'guard>>, RuntimeError> {
+        let hash = hash_value(guard, key)?;
+        self.find(guard, key, hash, 0)
+    }
+
+    fn find<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        key: TaggedScopedPtr<'guard>,
+        hash: u64,
+        shift: u32,
+    ) -> Result<Option<TaggedScopedPtr<'guard>>, RuntimeError> {
+        let slots = self.slot_values(guard);
+
+        if shift >= HASH_BITS {
+            return Ok(slots
+                .chunks(2)
+                .find(|entry| keys_equal(guard, entry[0], key))
+                .map(|entry| entry[1]));
+        }
+
+        let bit = fragment_bit(hash, shift);
+        if self.datamap & bit != 0 {
+            let index = 2 * bit_index(self.datamap, bit);
+            return match keys_equal(guard, slots[index], key) {
+                true => Ok(Some(slots[index + 1])),
+                false => Ok(None),
+            };
+        }
+
+        if self.nodemap & bit != 0 {
+            if let Value::PersistentMap(child) = *slots[self.node_slot(bit)] {
+                return child.find(guard, key, hash, shift + FRAGMENT_BITS);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Return a map with the key associated with the value, replacing any value it had
+    pub fn assoc<'guard>(
+        mem: &'guard MutatorView,
+        map: ScopedPtr<'guard, PersistentMap>,
+        key: TaggedScopedPtr<'guard>,
+        value: TaggedScopedPtr<'guard>,
+    ) -> Result<ScopedPtr<'guard, PersistentMap>, RuntimeError> {
+        let hash = hash_value(mem, key)?;
+        PersistentMap::assoc_in(mem, map, key, value, hash, 0)
+    }
+
+    fn assoc_in<'guard>(
+        mem: &'guard MutatorView,
+        node: ScopedPtr<'guard, PersistentMap>,
+        key: TaggedScopedPtr<'guard>,
+        value: TaggedScopedPtr<'guard>,
+        hash: u64,
+        shift: u32,
+    ) -> Result<ScopedPtr<'guard, PersistentMap>, RuntimeError> {
+        let mut slots = node.slot_values(mem);
+
+        if shift >= HASH_BITS {
+            match (0..slots.len())
+                .step_by(2)
+                .find(|index| keys_equal(mem, slots[*index], key))
+            {
+                Some(index) => slots[index + 1] = value,
+                None => slots.extend_from_slice(&[key, value]),
+            }
+            return PersistentMap::alloc_node(mem, 0, 0, (slots.len() / 2) as ArraySize, &slots);
+        }
+
+        let bit = fragment_bit(hash, shift);
+
+        if node.datamap & bit != 0 {
+            let index = 2 * bit_index(node.datamap, bit);
+            let (existing, existing_value) = (slots[index], slots[index + 1]);
+
+            if keys_equal(mem, existing, key) {
+                if existing_value == value {
+                    return Ok(node);
+                }
+                slots[index + 1] = value;
+                return PersistentMap::alloc_node(
+                    mem,
+                    node.datamap,
+                    node.nodemap,
+                    node.length,
+                    &slots,
+                );
+            }
+
+            // two keys share the fragment, so both move down into a new child node
+            let child = PersistentMap::merge(
+                mem,
+                (existing, existing_value, hash_value(mem, existing)?),
+                (key, value, hash),
+                shift + FRAGMENT_BITS,
+            )?;
+
+            slots.drain(index..index + 2);
+            let datamap = node.datamap ^ bit;
+            let nodemap = node.nodemap | bit;
+            let child_index = 2 * datamap.count_ones() as usize + bit_index(nodemap, bit);
+            slots.insert(child_index, child.as_tagged(mem));
+
+            return PersistentMap::alloc_node(mem, datamap, nodemap, node.length + 1, &slots);
+        }
+
+        if node.nodemap & bit != 0 {
+            let child_index = node.node_slot(bit);
+            let child = match *slots[child_index] {
+                Value::PersistentMap(child) => child,
+                _ => return Err(err_eval("A persistent map node is corrupt")),
+            };
+
+            let updated =
+                PersistentMap::assoc_in(mem, child, key, value, hash, shift + FRAGMENT_BITS)?;
+            if updated.as_tagged(mem) == child.as_tagged(mem) {
+                return Ok(node);
+            }
+
+            slots[child_index] = updated.as_tagged(mem);
+            return PersistentMap::alloc_node(
+                mem,
+                node.datamap,
+                node.nodemap,
+                node.length - child.length + updated.length,
+                &slots,
+            );
+        }
+
+        let index = 2 * bit_index(node.datamap, bit);
+        slots.splice(index..index, [key, value].iter().cloned());
+        PersistentMap::alloc_node(
+            mem,
+            node.datamap | bit,
+            node.nodemap,
+            node.length + 1,
+            &slots,
+        )
+    }
+
+    /// Return a node holding two entries whose hashes are equal up to the given shift
+    fn merge<'guard>(
+        mem: &'guard MutatorView,
+        a: (TaggedScopedPtr<'guard>, TaggedScopedPtr<'guard>, u64),
+        b: (TaggedScopedPtr<'guard>, TaggedScopedPtr<'guard>, u64),
+        shift: u32,
+    ) -> Result<ScopedPtr<'guard, PersistentMap>, RuntimeError> {
+        if shift >= HASH_BITS {
+            return PersistentMap::alloc_node(mem, 0, 0, 2, &[a.0, a.1, b.0, b.1]);
+        }
+
+        let (a_bit, b_bit) = (fragment_bit(a.2, shift), fragment_bit(b.2, shift));
+
+        if a_bit == b_bit {
+            let child = PersistentMap::merge(mem, a, b, shift + FRAGMENT_BITS)?;
+            return PersistentMap::alloc_node(mem, 0, a_bit, 2, &[child.as_tagged(mem)]);
+        }
+
+        let slots = match a_bit < b_bit {
+            true => [a.0, a.1, b.0, b.1],
+            false => [b.0, b.1, a.0, a.1],
+        };
+        PersistentMap::alloc_node(mem, a_bit | b_bit, 0, 2, &slots)
+    }
+
+    /// Return a map without the key, which is the same map if the key was not in it
+    pub fn dissoc<'guard>(
+        mem: &'guard MutatorView,
+        map: ScopedPtr<'guard, PersistentMap>,
+        key: TaggedScopedPtr<'guard>,
+    ) -> Result<ScopedPtr<'guard, PersistentMap>, RuntimeError> {
+        let hash = hash_value(mem, key)?;
+        Ok(PersistentMap::dissoc_in(mem, map, key, hash, 0)?.unwrap_or(map))
+    }
+
+    /// Return the node without the key, or None if the key is not in it
+    fn dissoc_in<'guard>(
+        mem: &'guard MutatorView,
+        node: ScopedPtr<'guard, PersistentMap>,
+        key: TaggedScopedPtr<'guard>,
+        hash: u64,
+        shift: u32,
+    ) -> Result<Option<ScopedPtr<'guard, PersistentMap>>, RuntimeError> {
+        let mut slots = node.slot_values(mem);
+
+        if shift >= HASH_BITS {
+            return match (0..slots.len())
+                .step_by(2)
+                .find(|index| keys_equal(mem, slots[*index], key))
+            {
+                Some(index) => {
+                    slots.drain(index..index + 2);
+                    let length = (slots.len() / 2) as ArraySize;
+                    Ok(Some(PersistentMap::alloc_node(mem, 0, 0, length, &slots)?))
+                }
+                None => Ok(None),
+            };
+        }
+
+        let bit = fragment_bit(hash, shift);
+
+        if node.datamap & bit != 0 {
+            let index = 2 * bit_index(node.datamap, bit);
+            if !keys_equal(mem, slots[index], key) {
+                return Ok(None);
+            }
+
+            slots.drain(index..index + 2);
+            return Ok(Some(PersistentMap::alloc_node(
+                mem,
+                node.datamap ^ bit,
+                node.nodemap,
+                node.length - 1,
+                &slots,
+            )?));
+        }
+
+        if node.nodemap & bit != 0 {
+            let child_index = node.node_slot(bit);
+            let child = match *slots[child_index] {
+                Value::PersistentMap(child) => child,
+                _ => return Err(err_eval("A persistent map node is corrupt")),
+            };
+
+            let updated =
+                match PersistentMap::dissoc_in(mem, child, key, hash, shift + FRAGMENT_BITS)? {
+                    Some(updated) => updated,
+                    None => return Ok(None),
+                };
+
+            // a child left with a single entry is replaced by the entry, so that a map has one
+            // shape for a given set of keys
+            if updated.length == 1 && updated.nodemap == 0 {
+                let entry = updated.slot_values(mem);
+                slots.remove(child_index);
+
+                let datamap = node.datamap | bit;
+                let index = 2 * bit_index(datamap, bit);
+                slots.splice(index..index, entry.iter().cloned());
+
+                return Ok(Some(PersistentMap::alloc_node(
+                    mem,
+                    datamap,
+                    node.nodemap ^ bit,
+                    node.length - 1,
+                    &slots,
+                )?));
+            }
+
+            slots[child_index] = updated.as_tagged(mem);
+            return Ok(Some(PersistentMap::alloc_node(
+                mem,
+                node.datamap,
+                node.nodemap,
+                node.length - 1,
+                &slots,
+            )?));
+        }
+
+        Ok(None)
+    }
+
+    /// Return every key and value in the map, in hash order
+    pub fn items<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+    ) -> Vec<(TaggedScopedPtr<'guard>, TaggedScopedPtr<'guard>)> {
+        let mut items = Vec::with_capacity(self.length as usize);
+        self.collect_items(guard, &mut items);
+        items
+    }
+
+    fn collect_items<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        items: &mut Vec<(TaggedScopedPtr<'guard>, TaggedScopedPtr<'guard>)>,
+    ) {
+        let slots = self.slot_values(guard);
+        let data_entries = self.data_entries(&slots);
+
+        for entry in slots[..2 * data_entries].chunks(2) {
+            items.push((entry[0], entry[1]));
+        }
+
+        for child in &slots[2 * data_entries..] {
+            if let Value::PersistentMap(child) = **child {
+                child.collect_items(guard, items);
+            }
+        }
+    }
+}
+
+impl Container<TaggedCellPtr> for PersistentMap {
+    fn new() -> PersistentMap {
+        PersistentMap {
+            datamap: 0,
+            nodemap: 0,
+            length: 0,
+            slots: TaggedCellPtr::new_nil(),
+        }
+    }
+
+    fn with_capacity<'guard>(
+        _mem: &'guard MutatorView,
+        _capacity: ArraySize,
+    ) -> Result<PersistentMap, RuntimeError> {
+        Ok(PersistentMap::new())
+    }
+
+    fn clear<'guard>(&self, _mem: &'guard MutatorView) -> Result<(), RuntimeError> {
+        Err(err_eval("A persistent map cannot be cleared"))
+    }
+
+    fn length(&self) -> ArraySize {
+        self.length
+    }
+}
+
+impl ImmutableContainer<TaggedCellPtr> for PersistentMap {}
+
+impl Print for PersistentMap {
+    /// Prints `(pmap key value ...)`, the call that would make the map, in hash order, or sorted
+    /// by key with the alternate flag, `{:#}`
+    fn print<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        if self.length == 0 {
+            return write!(f, "(pmap)");
+        }
+
+        let mut items = self.items(guard);
+        if f.alternate() {
+            items.sort_by(|(a, _), (b, _)| key_order(guard, *a, *b));
+        }
+
+        print_elements(
+            f,
+            "(pmap ",
+            " ",
+            ")",
+            items.len(),
+            items,
+            |f, (key, value)| {
+                fmt::Display::fmt(&key.value(), f)?;
+                write!(f, " ")?;
+                fmt::Display::fmt(&value.value(), f)
+            },
+        )
+    }
+}
+
+/// Get the PersistentList value of an argument, or return an error naming the function
+fn expect_plist<'guard>(
+    guard: &'guard dyn MutatorScope,
+    arg: &TaggedCellPtr,
+    fn_name: &str,
+) -> Result<ScopedPtr<'guard, PersistentList>, RuntimeError> {
+    match *arg.get(guard) {
+        Value::PersistentList(list) => Ok(list),
+        other => Err(err_eval(&format!(
+            "Parameter l to {} must be a persistent list, got {}",
+            fn_name,
+            describe(other)
+        ))),
+    }
+}
+
+/// Get the PersistentMap value of an argument, or return an error naming the function
+fn expect_pmap<'guard>(
+    guard: &'guard dyn MutatorScope,
+    arg: &TaggedCellPtr,
+    fn_name: &str,
+) -> Result<ScopedPtr<'guard, PersistentMap>, RuntimeError> {
+    match *arg.get(guard) {
+        Value::PersistentMap(map) => Ok(map),
+        other => Err(err_eval(&format!(
+            "Parameter m to {} must be a persistent map, got {}",
+            fn_name,
+            describe(other)
+        ))),
+    }
+}
+
+/// (plist x ...) - return a persistent list of the arguments
+fn plist<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let items: Vec<TaggedScopedPtr> = args.iter().map(|arg| arg.get(mem)).collect();
+    Ok(PersistentList::from_slice(mem, &items)?.as_tagged(mem))
+}
+
+/// (plist-cons x l) - return a persistent list of x followed by the items of l, which is unchanged
+fn plist_cons<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let list = expect_plist(mem, &args[1], "plist-cons")?;
+    Ok(PersistentList::cons(mem, args[0].get(mem), list)?.as_tagged(mem))
+}
+
+/// (plist-first l) - return the first item of a persistent list, or nil if it is empty
+fn plist_first<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    Ok(expect_plist(mem, &args[0], "plist-first")?.first(mem))
+}
+
+/// (plist-rest l) - return the persistent list of the items after the first
+fn plist_rest<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    Ok(expect_plist(mem, &args[0], "plist-rest")?
+        .rest(mem)?
+        .as_tagged(mem))
+}
+
+/// (plist-nth l n) - return the item at index n of a persistent list
+fn plist_nth<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let list = expect_plist(mem, &args[0], "plist-nth")?;
+    let index = arg::<isize>(mem, args, 1, "plist-nth")?;
+    if index < 0 {
+        return Err(RuntimeError::new(ErrorKind::BoundsError));
+    }
+    list.get(mem, index as ArraySize)
+}
+
+/// (plist-length l) - return the number of items in a persistent list
+fn plist_length<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let list = expect_plist(mem, &args[0], "plist-length")?;
+    Ok(mem.number(list.length() as isize))
+}
+
+/// (pmap key value ...) - return a persistent map of the keys and values
+fn pmap<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    if !args.len().is_multiple_of(2) {
+        return Err(err_eval("pmap expects keys and values in pairs"));
+    }
+
+    let pairs: Vec<(TaggedScopedPtr, TaggedScopedPtr)> = args
+        .chunks(2)
+        .map(|pair| (pair[0].get(mem), pair[1].get(mem)))
+        .collect();
+    Ok(PersistentMap::from_pairs(mem, &pairs)?.as_tagged(mem))
+}
+
+/// (pmap-get m key) - return the value of key in a persistent map, or nil if it has none
+fn pmap_get<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let map = expect_pmap(mem, &args[0], "pmap-get")?;
+    Ok(map
+        .lookup(mem, args[1].get(mem))?
+        .unwrap_or_else(|| mem.nil()))
+}
+
+/// (pmap-contains? m key) - return true if a persistent map has a value for key
+fn pmap_contains<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let map = expect_pmap(mem, &args[0], "pmap-contains?")?;
+    match map.lookup(mem, args[1].get(mem))? {
+        Some(_) => Ok(mem.sym_true()),
+        None => Ok(mem.nil()),
+    }
+}
+
+/// (pmap-assoc m key value) - return a persistent map like m with key associated with value. m is
+/// unchanged.
+fn pmap_assoc<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let map = expect_pmap(mem, &args[0], "pmap-assoc")?;
+    Ok(PersistentMap::assoc(mem, map, args[1].get(mem), args[2].get(mem))?.as_tagged(mem))
+}
+
+/// (pmap-dissoc m key) - return a persistent map like m without key. m is unchanged.
+fn pmap_dissoc<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let map = expect_pmap(mem, &args[0], "pmap-dissoc")?;
+    Ok(PersistentMap::dissoc(mem, map, args[1].get(mem))?.as_tagged(mem))
+}
+
+/// (pmap-keys m) - return a list of the keys of a persistent map, in no particular order
+fn pmap_keys<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let map = expect_pmap(mem, &args[0], "pmap-keys")?;
+
+    let mut keys = mem.nil();
+    for (key, _) in map.items(mem).into_iter().rev() {
+        keys = cons(mem, key, keys)?;
+    }
+    Ok(keys)
+}
+
+/// (pmap-length m) - return the number of keys in a persistent map
+fn pmap_length<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let map = expect_pmap(mem, &args[0], "pmap-length")?;
+    Ok(mem.number(map.length() as isize))
+}
+
+native_module! {
+    /// The persistent list and map builtin functions
+    pub PERSISTENT_MODULE = "persistent" {
+        "plist" => plist(0..),
+        "plist-cons" => plist_cons(2),
+        "plist-first" => plist_first(1),
+        "plist-rest" => plist_rest(1),
+        "plist-nth" => plist_nth(2),
+        "plist-length" => plist_length(1),
+        "pmap" => pmap(0..),
+        "pmap-get" => pmap_get(2),
+        "pmap-contains?" => pmap_contains(2),
+        "pmap-assoc" => pmap_assoc(3),
+        "pmap-dissoc" => pmap_dissoc(2),
+        "pmap-keys" => pmap_keys(1),
+        "pmap-length" => pmap_length(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    #[test]
+    fn persistent_map_versions_share_structure() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let empty = mem.alloc(PersistentMap::new())?;
+
+                // integers hash to themselves, so these fill several levels of the trie
+                let mut versions = vec![empty];
+                for n in 0..500 {
+                    let map = *versions.last().unwrap();
+                    versions.push(PersistentMap::assoc(
+                        mem,
+                        map,
+                        mem.number(n * 33),
+                        mem.number(n),
+                    )?);
+                }
+
+                // every version still has exactly the keys it was made with
+                for (length, map) in versions.iter().enumerate().step_by(50) {
+                    assert!(map.length() as usize == length);
+                    assert!(map.items(mem).len() == length);
+                    for n in 0..500 {
+                        let found = map.lookup(mem, mem.number(n * 33))?;
+                        match (n as usize) < length {
+                            true => assert!(found == Some(mem.number(n))),
+                            false => assert!(found.is_none()),
+                        }
+                    }
+                }
+
+                // removing every key in turn collapses back to an empty map
+                let full = *versions.last().unwrap();
+                let mut map = full;
+                for n in 0..500 {
+                    map = PersistentMap::dissoc(mem, map, mem.number(n * 33))?;
+                    assert!(map.length() == 499 - n as ArraySize);
+                    assert!(map.lookup(mem, mem.number(n * 33))?.is_none());
+                }
+                assert!(map.slot_values(mem).is_empty());
+                assert!(full.length() == 500);
+
+                // updates that change nothing return the same map
+                assert!(
+                    PersistentMap::dissoc(mem, full, mem.number(1))?.as_tagged(mem)
+                        == full.as_tagged(mem)
+                );
+                let same = PersistentMap::assoc(mem, full, mem.number(33), mem.number(1))?;
+                assert!(same.as_tagged(mem) == full.as_tagged(mem));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn persistent_map_hash_collisions() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                // a symbol hashes the same as a string with the same content
+                let symbol = mem.lookup_sym("key");
+                let text = mem.text("key")?;
+                assert!(hash_value(mem, symbol)? == hash_value(mem, text)?);
+
+                let map = PersistentMap::from_pairs(
+                    mem,
+                    &[(symbol, mem.number(1)), (text, mem.number(2))],
+                )?;
+                assert!(map.length() == 2);
+                assert!(map.lookup(mem, symbol)? == Some(mem.number(1)));
+                assert!(map.lookup(mem, mem.text("key")?)? == Some(mem.number(2)));
+
+                let map = PersistentMap::assoc(mem, map, mem.text("key")?, mem.number(3))?;
+                assert!(map.length() == 2);
+                let map = PersistentMap::dissoc(mem, map, symbol)?;
+                assert!(map.length() == 1);
+                assert!(map.lookup(mem, text)? == Some(mem.number(3)));
+                assert!(map.lookup(mem, symbol)?.is_none());
+
+                assert!(PersistentMap::assoc(mem, map, mem.nil(), mem.nil()).is_err());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn persistent_builtins() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+                let show =
+                    |code| -> Result<String, RuntimeError> { Ok(format!("{:#}", eval(code)?)) };
+
+                eval("(set 'l (plist 'b 'c))")?;
+                eval("(set 'l2 (plist-cons 'a l))")?;
+                assert!(show("l")? == "(plist b c)");
+                assert!(show("l2")? == "(plist a b c)");
+                assert!(show("(plist-rest l2)")? == "(plist b c)");
+                assert!(eval("(plist-first l2)")? == mem.lookup_sym("a"));
+                assert!(eval("(plist-nth l2 2)")? == mem.lookup_sym("c"));
+                assert!(eval("(plist-length l2)")? == mem.number(3));
+                assert!(show("(plist-rest (plist-rest (plist-rest l2)))")? == "(plist)");
+                assert!(eval("(plist-first (plist))")? == mem.nil());
+                assert!(eval("(plist-nth l 2)").is_err());
+                assert!(eval("(plist-cons 'a '(b))").is_err());
+
+                eval("(set 'm (pmap 'a 1 'b 2))")?;
+                eval("(set 'm2 (pmap-dissoc (pmap-assoc m 'c 3) 'a))")?;
+                assert!(show("m")? == "(pmap a 1 b 2)");
+                assert!(show("m2")? == "(pmap b 2 c 3)");
+                assert!(eval("(pmap-get m2 'c)")? == mem.number(3));
+                assert!(eval("(pmap-get m2 'a)")? == mem.nil());
+                assert!(eval("(pmap-contains? m 'a)")? == mem.sym_true());
+                assert!(eval("(pmap-contains? m2 'a)")? == mem.nil());
+                assert!(eval("(pmap-length m2)")? == mem.number(2));
+                assert!(show("(pmap-get (pmap \"x\" 'y) \"x\")")? == "y");
+                assert!(eval("(pmap-length (pmap-keys m))").is_err());
+                assert!(eval("(pmap 'a)").is_err());
+                assert!(eval("(pmap-get m '(a))").is_err());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -14,17 +14,54 @@ pub trait AsNonNull {
 
 // Pointer tag values and masks using the lowest 2 bits
 const TAG_MASK: usize = 0x3;
-pub const TAG_NUMBER: usize = 0x0;
+pub const TAG_IMMEDIATE: usize = 0x0;
 pub const TAG_SYMBOL: usize = 0x1;
 pub const TAG_PAIR: usize = 0x2;
 pub const TAG_OBJECT: usize = 0x3;
 const PTR_MASK: usize = !0x3;
 
+// Immediate values are further divided by the 3rd lowest bit: either an inline integer in the
+// upper 61 bits, or some other immediate type identified by a 5 bit subtag
+const IMMEDIATE_MASK: usize = 0x7;
+pub const IMMEDIATE_INTEGER: usize = 0x0;
+pub const IMMEDIATE_OTHER: usize = 0x4;
+pub const INTEGER_SHIFT: usize = 3;
+
+// Subtag values for non-integer immediates, stored in bits 3 to 7. The immediate value itself,
+// if it has one, is stored in the upper 32 bits of the word.
+const SUBTAG_SHIFT: usize = 3;
+const SUBTAG_MASK: usize = 0x1f;
+pub const SUBTAG_NIL: usize = 0x0;
+pub const SUBTAG_BOOL: usize = 0x1;
+pub const SUBTAG_CHAR: usize = 0x2;
+pub const SUBTAG_FLOAT: usize = 0x3;
+pub const PAYLOAD_SHIFT: usize = 32;
+
 /// Return the tag from the given word
 pub fn get_tag(tagged_word: usize) -> usize {
     tagged_word & TAG_MASK
 }
 
+/// Return the immediate kind, integer or other, from a word that has an immediate tag
+pub fn get_immediate_kind(tagged_word: usize) -> usize {
+    tagged_word & IMMEDIATE_MASK
+}
+
+/// Return the subtag from a word that is a non-integer immediate
+pub fn get_subtag(tagged_word: usize) -> usize {
+    (tagged_word >> SUBTAG_SHIFT) & SUBTAG_MASK
+}
+
+/// Compose a non-integer immediate word from a subtag and a 32 bit payload
+pub fn make_immediate(subtag: usize, payload: u32) -> usize {
+    ((payload as usize) << PAYLOAD_SHIFT) | (subtag << SUBTAG_SHIFT) | IMMEDIATE_OTHER
+}
+
+/// Return the 32 bit payload of a non-integer immediate word
+pub fn get_payload(tagged_word: usize) -> u32 {
+    (tagged_word >> PAYLOAD_SHIFT) as u32
+}
+
 /// Pointer tagging operations on RawPtr<T>
 pub trait Tagged<T> {
     fn tag(self, tag: usize) -> NonNull<T>;
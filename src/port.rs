@@ -0,0 +1,841 @@
+/// I/O port objects and the builtin functions that operate on them.
+///
+/// A `Port` wraps one of the process standard streams, an open file or a string. Ports can be
+/// explicitly closed, after which any further use is an error. Only string ports are available in
+/// a sandbox that does not allow I/O. The heap does not run destructors, so file Ports register a
+/// finalizer that closes a file that is still open. The heap does not collect yet, so an unclosed
+/// file stays open until the Memory is dropped, which runs every remaining finalizer.
+///
+/// Writes to standard output are collected in a buffer, one per OS thread and shared by every
+/// stdout Port on it, that is written out once it holds `stdout_buffer_size()` bytes, by
+/// `(flush)` or by `flush_stdout()`. The REPL flushes it before each prompt and the command line
+/// program before it exits. It is also flushed before anything is written to standard error or
+/// read from standard input, so output and error messages appear in the order they were written.
+/// The runtime's own error reports, tracebacks and traces do the same, or write to `Stderr`.
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+
+use crate::error::{err_eval, RuntimeError};
+use crate::memory::MutatorView;
+use crate::native::expect_text;
+use crate::native_module;
+use crate::printer::Print;
+use crate::safeptr::{MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+use crate::vm::Thread;
+
+/// The number of bytes of standard output that are buffered before being written out
+pub const DEFAULT_STDOUT_BUFFER_SIZE: usize = 8192;
+
+thread_local! {
+    static STDOUT_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    static STDOUT_BUFFER_SIZE: Cell<usize> = const { Cell::new(DEFAULT_STDOUT_BUFFER_SIZE) };
+}
+
+/// Write out any buffered standard output
+pub fn flush_stdout() -> io::Result<()> {
+    STDOUT_BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let result = handle.write_all(&buffer).and_then(|_| handle.flush());
+        buffer.clear();
+        result
+    })
+}
+
+/// Set the number of bytes of standard output that are buffered before being written out, after
+/// writing out anything already buffered. A size of 0 writes everything out immediately.
+pub fn set_stdout_buffer_size(size: usize) -> io::Result<()> {
+    STDOUT_BUFFER_SIZE.with(|buffer_size| buffer_size.set(size));
+    flush_stdout()
+}
+
+/// Return the number of bytes of standard output that are buffered before being written out
+pub fn stdout_buffer_size() -> usize {
+    STDOUT_BUFFER_SIZE.with(Cell::get)
+}
+
+/// Buffer bytes written to standard output, writing them out if the buffer is full
+fn write_stdout(bytes: &[u8]) -> io::Result<()> {
+    let full = STDOUT_BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        buffer.extend_from_slice(bytes);
+        buffer.len() >= stdout_buffer_size()
+    });
+
+    match full {
+        true => flush_stdout(),
+        false => Ok(()),
+    }
+}
+
+/// Standard error, writing out any buffered standard output before each write so that the two
+/// appear in the order they were written
+pub struct Stderr;
+
+impl Write for Stderr {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        flush_stdout()?;
+        io::stderr().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Whether a Port is read from or written to
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+/// The Rust I/O handle underlying a Port
+enum PortHandle {
+    Stdin,
+    Stdout,
+    Stderr,
+    FileReader(BufReader<File>),
+    FileWriter(File),
+    StringReader(Cursor<String>),
+    StringWriter(String),
+}
+
+/// An I/O port object type
+pub struct Port {
+    /// A Text name: the file path, or the name of the standard stream
+    name: TaggedCellPtr,
+    /// Input or output
+    direction: Direction,
+    /// The open handle, or None once the port has been closed
+    handle: RefCell<Option<PortHandle>>,
+}
+
+impl Port {
+    /// Allocate a Port wrapping the process standard input
+    pub fn alloc_stdin<'guard>(
+        mem: &'guard MutatorView,
+    ) -> Result<ScopedPtr<'guard, Port>, RuntimeError> {
+        Port::alloc(mem, "<stdin>", Direction::Input, PortHandle::Stdin)
+    }
+
+    /// Allocate a Port wrapping the process standard output
+    pub fn alloc_stdout<'guard>(
+        mem: &'guard MutatorView,
+    ) -> Result<ScopedPtr<'guard, Port>, RuntimeError> {
+        Port::alloc(mem, "<stdout>", Direction::Output, PortHandle::Stdout)
+    }
+
+    /// Allocate a Port wrapping the process standard error
+    pub fn alloc_stderr<'guard>(
+        mem: &'guard MutatorView,
+    ) -> Result<ScopedPtr<'guard, Port>, RuntimeError> {
+        Port::alloc(mem, "<stderr>", Direction::Output, PortHandle::Stderr)
+    }
+
+    /// Allocate a Port that reads from the given string
+    pub fn open_input_string<'guard>(
+        mem: &'guard MutatorView,
+        s: &str,
+    ) -> Result<ScopedPtr<'guard, Port>, RuntimeError> {
+        let handle = PortHandle::StringReader(Cursor::new(String::from(s)));
+        Port::alloc(mem, "<string>", Direction::Input, handle)
+    }
+
+    /// Allocate a Port that collects everything written to it in a string, which can be retrieved
+    /// with `output_string()`
+    pub fn open_output_string<'guard>(
+        mem: &'guard MutatorView,
+    ) -> Result<ScopedPtr<'guard, Port>, RuntimeError> {
+        let handle = PortHandle::StringWriter(String::new());
+        Port::alloc(mem, "<string>", Direction::Output, handle)
+    }
+
+    /// Open a file and allocate a Port for it. The mode is one of "r" (read), "w" (write,
+    /// truncating) or "a" (append).
+    pub fn open<'guard>(
+        mem: &'guard MutatorView,
+        path: &str,
+        mode: &str,
+    ) -> Result<ScopedPtr<'guard, Port>, RuntimeError> {
+        let could_not_open = |e: io::Error| err_eval(&format!("Could not open {}: {}", path, e));
+
+        let port = match mode {
+            "r" => {
+                let file = File::open(path).map_err(could_not_open)?;
+                let handle = PortHandle::FileReader(BufReader::new(file));
+                Port::alloc(mem, path, Direction::Input, handle)?
+            }
+
+            "w" | "a" => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(mode == "w")
+                    .append(mode == "a")
+                    .open(path)
+                    .map_err(could_not_open)?;
+                Port::alloc(mem, path, Direction::Output, PortHandle::FileWriter(file))?
+            }
+
+            _ => {
+                return Err(err_eval(&format!(
+                    "Invalid port mode {}, expected r, w or a",
+                    mode
+                )))
+            }
+        };
+
+        mem.register_finalizer(port.as_tagged(mem), Port::finalize);
+        Ok(port)
+    }
+
+    /// Finalizer for file Ports: close the file if it is still open
+    fn finalize(_guard: &MutatorView, port: TaggedScopedPtr) {
+        if let Value::Port(port) = *port {
+            port.handle.borrow_mut().take();
+        }
+    }
+
+    fn alloc<'guard>(
+        mem: &'guard MutatorView,
+        name: &str,
+        direction: Direction,
+        handle: PortHandle,
+    ) -> Result<ScopedPtr<'guard, Port>, RuntimeError> {
+        let name = mem.text(name)?;
+
+        mem.alloc(Port {
+            name: TaggedCellPtr::new_with(name),
+            direction,
+            handle: RefCell::new(Some(handle)),
+        })
+    }
+
+    /// Return the Port's name as a string slice
+    pub fn name<'guard>(&self, guard: &'guard dyn MutatorScope) -> &'guard str {
+        match *self.name.get(guard) {
+            Value::Text(t) => t.as_str(guard),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Return whether this Port is for input or output
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Return true if the Port has been closed
+    pub fn is_closed(&self) -> bool {
+        self.handle.borrow().is_none()
+    }
+
+    /// Return true if using the Port reaches outside of the interpreter, that is if it is open and
+    /// is not a string port
+    pub fn performs_io(&self) -> bool {
+        !matches!(
+            *self.handle.borrow(),
+            None | Some(PortHandle::StringReader(_)) | Some(PortHandle::StringWriter(_))
+        )
+    }
+
+    /// Read one line, without the line ending. Returns None at end of input.
+    pub fn read_line(&self, guard: &dyn MutatorScope) -> Result<Option<String>, RuntimeError> {
+        let mut line = String::new();
+
+        let count = match self.open_handle(guard, Direction::Input)?.as_mut() {
+            Some(PortHandle::Stdin) => {
+                flush_stdout().and_then(|_| io::stdin().read_line(&mut line))
+            }
+            Some(PortHandle::FileReader(reader)) => reader.read_line(&mut line),
+            Some(PortHandle::StringReader(reader)) => reader.read_line(&mut line),
+            _ => unreachable!(),
+        }
+        .map_err(|e| self.io_error(guard, e))?;
+
+        if count == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+
+        Ok(Some(line))
+    }
+
+    /// Write a string
+    pub fn write_str(&self, guard: &dyn MutatorScope, s: &str) -> Result<(), RuntimeError> {
+        let bytes = s.as_bytes();
+
+        match self.open_handle(guard, Direction::Output)?.as_mut() {
+            Some(PortHandle::Stdout) => write_stdout(bytes),
+            Some(PortHandle::Stderr) => flush_stdout().and_then(|_| io::stderr().write_all(bytes)),
+            Some(PortHandle::FileWriter(file)) => file.write_all(bytes),
+            Some(PortHandle::StringWriter(string)) => {
+                string.push_str(s);
+                Ok(())
+            }
+            _ => unreachable!(),
+        }
+        .map_err(|e| self.io_error(guard, e))
+    }
+
+    /// Write out anything written to the Port that is still buffered
+    pub fn flush(&self, guard: &dyn MutatorScope) -> Result<(), RuntimeError> {
+        match self.open_handle(guard, Direction::Output)?.as_mut() {
+            Some(PortHandle::Stdout) => flush_stdout(),
+            Some(PortHandle::Stderr) => io::stderr().flush(),
+            Some(PortHandle::FileWriter(file)) => file.flush(),
+            Some(PortHandle::StringWriter(_)) => Ok(()),
+            _ => unreachable!(),
+        }
+        .map_err(|e| self.io_error(guard, e))
+    }
+
+    /// Return everything written so far to a Port opened with `open_output_string()`
+    pub fn output_string(&self, guard: &dyn MutatorScope) -> Result<String, RuntimeError> {
+        match self.open_handle(guard, Direction::Output)?.as_ref() {
+            Some(PortHandle::StringWriter(string)) => Ok(string.clone()),
+            _ => Err(err_eval(&format!(
+                "Port {} is not a string port",
+                self.name(guard)
+            ))),
+        }
+    }
+
+    /// Close the Port, releasing any file handle. Closing a Port twice is an error. Closing a
+    /// stdout Port writes out buffered standard output.
+    pub fn close(&self, guard: &dyn MutatorScope) -> Result<(), RuntimeError> {
+        match self.handle.borrow_mut().take() {
+            None => Err(self.closed_error(guard)),
+            Some(PortHandle::Stdout) => flush_stdout().map_err(|e| self.io_error(guard, e)),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Borrow the handle, checking that the Port is still open and is of the expected direction
+    fn open_handle(
+        &self,
+        guard: &dyn MutatorScope,
+        direction: Direction,
+    ) -> Result<std::cell::RefMut<'_, Option<PortHandle>>, RuntimeError> {
+        if self.is_closed() {
+            return Err(self.closed_error(guard));
+        }
+
+        if self.direction != direction {
+            return Err(err_eval(&format!(
+                "Port {} is not an {} port",
+                self.name(guard),
+                match direction {
+                    Direction::Input => "input",
+                    Direction::Output => "output",
+                }
+            )));
+        }
+
+        Ok(self.handle.borrow_mut())
+    }
+
+    fn closed_error(&self, guard: &dyn MutatorScope) -> RuntimeError {
+        err_eval(&format!("Port {} is closed", self.name(guard)))
+    }
+
+    fn io_error(&self, guard: &dyn MutatorScope, e: io::Error) -> RuntimeError {
+        err_eval(&format!("I/O error on port {}: {}", self.name(guard), e))
+    }
+}
+
+impl Print for Port {
+    /// Prints a string representation of the port
+    fn print<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        let state = if self.is_closed() {
+            "closed"
+        } else {
+            match self.direction {
+                Direction::Input => "input",
+                Direction::Output => "output",
+            }
+        };
+
+        write!(f, "(Port {} {})", self.name(guard), state)
+    }
+}
+
+/// Get the Port value of an argument, or return an error naming the function
+fn expect_port<'guard>(
+    guard: &'guard dyn MutatorScope,
+    arg: &TaggedCellPtr,
+    fn_name: &str,
+) -> Result<ScopedPtr<'guard, Port>, RuntimeError> {
+    match *arg.get(guard) {
+        Value::Port(port) => Ok(port),
+        _ => Err(err_eval(&format!(
+            "Parameter to {} must be a port",
+            fn_name
+        ))),
+    }
+}
+
+/// Return an error naming the function if the sandbox forbids I/O and the Port performs it
+pub fn check_port_io(
+    thread: &Thread,
+    port: ScopedPtr<'_, Port>,
+    fn_name: &str,
+) -> Result<(), RuntimeError> {
+    if port.performs_io() {
+        thread.check_io_allowed(&format!("Function {}", fn_name))?;
+    }
+    Ok(())
+}
+
+/// (open path mode) - open a file, where mode is "r", "w" or "a" as a string or symbol
+fn open<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let path = expect_text(mem, &args[0], "open", "path")?;
+
+    let mode = args[1].get(mem);
+    let mode = match *mode {
+        Value::Text(t) => t.as_str(mem),
+        Value::Symbol(s) => s.as_str(mem),
+        _ => {
+            return Err(err_eval(
+                "Parameter mode to open must be a string or symbol",
+            ))
+        }
+    };
+
+    Ok(Port::open(mem, path.as_str(mem), mode)?.as_tagged(mem))
+}
+
+/// (open-input-string s) - return an input port that reads from the string
+fn open_input_string<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let text = expect_text(mem, &args[0], "open-input-string", "s")?;
+
+    Ok(Port::open_input_string(mem, text.as_str(mem))?.as_tagged(mem))
+}
+
+/// (with-output-to-string thunk) - call the function of no arguments, returning everything it
+/// printed as a string instead of writing it to the output port
+fn with_output_to_string<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let port = Port::open_output_string(mem)?;
+
+    // this Thread is busy calling with-output-to-string, so the thunk is evaluated on another
+    let runner = thread.alloc_nested(mem)?;
+    runner.set_output_port(port);
+    runner.quick_vm_call(mem, args[0].get(mem))?;
+
+    mem.text(&port.output_string(mem)?)
+}
+
+/// (read-line port) - read a line as a string, or nil at end of input
+fn read_line<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let port = expect_port(mem, &args[0], "read-line")?;
+    check_port_io(thread, port, "read-line")?;
+
+    match port.read_line(mem)? {
+        Some(line) => mem.text(&line),
+        None => Ok(mem.nil()),
+    }
+}
+
+/// (write-string port s) - write a string to the port, returning nil
+fn write_string<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let port = expect_port(mem, &args[0], "write-string")?;
+    check_port_io(thread, port, "write-string")?;
+    let text = expect_text(mem, &args[1], "write-string", "s")?;
+
+    port.write_str(mem, text.as_str(mem))?;
+    Ok(mem.nil())
+}
+
+/// (flush [port]) - write out anything buffered for the port, by default the Thread output port,
+/// returning nil
+fn flush<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let port = match args {
+        [] => thread.output_port(mem),
+        [port] => expect_port(mem, port, "flush")?,
+        _ => {
+            return Err(err_eval(&format!(
+                "flush takes at most 1 argument, {} given",
+                args.len()
+            )))
+        }
+    };
+    check_port_io(thread, port, "flush")?;
+
+    port.flush(mem)?;
+    Ok(mem.nil())
+}
+
+/// (close port) - close the port, returning nil
+fn close<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let port = expect_port(mem, &args[0], "close")?;
+    check_port_io(thread, port, "close")?;
+
+    port.close(mem)?;
+    Ok(mem.nil())
+}
+
+native_module! {
+    /// The builtin functions that open file ports
+    pub FILE_PORT_MODULE = "file-port" [io] {
+        "open" => open(2),
+    }
+}
+
+native_module! {
+    /// The port builtin functions that are available in the sandbox. Those that take a port check
+    /// that the sandbox allows I/O if it is not a string port.
+    pub PORT_MODULE = "port" {
+        "open-input-string" => open_input_string(1),
+        "with-output-to-string" => with_output_to_string(1),
+        "read-line" => read_line(1),
+        "write-string" => write_string(2),
+        "flush" => flush(0..),
+        "close" => close(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::error::ErrorKind;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+    use crate::root::Root;
+    use crate::sandbox::Sandbox;
+    use crate::taggedptr::TaggedPtr;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn eval_helper<'guard>(
+        mem: &'guard MutatorView,
+        thread: ScopedPtr<'guard, Thread>,
+        code: &str,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let compiled_code = compile(mem, parse(mem, code)?)?;
+        thread.quick_vm_eval(mem, compiled_code)
+    }
+
+    fn test_helper(test_fn: fn(&MutatorView) -> Result<(), RuntimeError>) {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = fn(&MutatorView) -> Result<(), RuntimeError>;
+            type Output = ();
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                test_fn: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                test_fn(mem)
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, test_fn).unwrap();
+    }
+
+    fn temp_path(name: &str) -> String {
+        let mut path = env::temp_dir();
+        path.push(format!("evalrus-{}-{}", name, process::id()));
+        String::from(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn port_write_then_read_file() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            let path = temp_path("port-write-read");
+
+            let code = format!("(set 'out (open \"{}\" 'w))", path);
+            eval_helper(mem, t, &code)?;
+            eval_helper(mem, t, "(write-string out \"hello\nworld\r\n\")")?;
+            eval_helper(mem, t, "(close out)")?;
+
+            let code = format!("(set 'in (open \"{}\" \"r\"))", path);
+            eval_helper(mem, t, &code)?;
+
+            let line = eval_helper(mem, t, "(read-line in)")?;
+            assert!(format!("{}", line) == "\"hello\"");
+
+            let line = eval_helper(mem, t, "(read-line in)")?;
+            assert!(format!("{}", line) == "\"world\"");
+
+            let line = eval_helper(mem, t, "(read-line in)")?;
+            assert!(line == mem.nil());
+
+            eval_helper(mem, t, "(close in)")?;
+            fs::remove_file(&path)?;
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn port_use_after_close() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            let path = temp_path("port-use-after-close");
+
+            let code = format!("(set 'out (open \"{}\" 'w))", path);
+            eval_helper(mem, t, &code)?;
+            eval_helper(mem, t, "(close out)")?;
+
+            assert!(eval_helper(mem, t, "(write-string out \"x\")").is_err());
+            assert!(eval_helper(mem, t, "(close out)").is_err());
+
+            let port = eval_helper(mem, t, "out")?;
+            assert!(format!("{}", port) == format!("(Port {} closed)", path));
+
+            fs::remove_file(&path)?;
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn port_closed_by_finalizer() {
+        let mem = Memory::new();
+        let path = temp_path("port-finalizer");
+
+        struct Open {}
+        impl Mutator for Open {
+            type Input = String;
+            type Output = (Root, Root, TaggedPtr);
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                path: String,
+            ) -> Result<(Root, Root, TaggedPtr), RuntimeError> {
+                let unreachable = Port::open(mem, &path, "w")?;
+                let live = Port::open(mem, &path, "a")?;
+
+                // standard streams have no finalizer
+                Port::alloc_stdout(mem)?;
+
+                Ok((
+                    mem.root(unreachable.as_tagged(mem)),
+                    mem.root(live.as_tagged(mem)),
+                    live.as_tagged(mem).get_ptr(),
+                ))
+            }
+        }
+
+        struct IsClosed {}
+        impl Mutator for IsClosed {
+            type Input = Root;
+            type Output = bool;
+
+            fn run(&self, mem: &MutatorView, port: Root) -> Result<bool, RuntimeError> {
+                Ok(expect_port(mem, &TaggedCellPtr::new_with(port.get(mem)), "test")?.is_closed())
+            }
+        }
+
+        let (unreachable, live, live_ptr) = mem.mutate(&Open {}, path.clone()).unwrap();
+
+        // stand in for a collection that found only the second port live
+        assert!(mem.run_finalizers(|ptr| ptr == live_ptr) == 1);
+        assert!(mem.mutate(&IsClosed {}, unreachable).unwrap());
+        assert!(!mem.mutate(&IsClosed {}, live).unwrap());
+
+        // finalizers of registered objects run when the Memory is dropped
+        drop(mem);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn port_string_input_and_output() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            eval_helper(mem, t, "(set 'in (open-input-string \"one\ntwo\"))")?;
+            let line = eval_helper(mem, t, "(read-line in)")?;
+            assert!(format!("{}", line) == "\"one\"");
+            let line = eval_helper(mem, t, "(read-line in)")?;
+            assert!(format!("{}", line) == "\"two\"");
+            assert!(eval_helper(mem, t, "(read-line in)")? == mem.nil());
+
+            // output is captured from functions, closures and nested captures
+            eval_helper(mem, t, "(def greet () (println 'hello))")?;
+            let output = eval_helper(mem, t, "(with-output-to-string greet)")?;
+            assert!(format!("{}", output) == "\"hello\n\"");
+
+            let code = "(let ((x 'a)) (with-output-to-string (lambda () (print x (with-output-to-string greet)))))";
+            let output = eval_helper(mem, t, code)?;
+            assert!(format!("{}", output) == "\"a hello\n\"");
+
+            eval_helper(
+                mem,
+                t,
+                "(def twice (x) (with-output-to-string (lambda () (print x x))))",
+            )?;
+            let output = eval_helper(mem, t, "(twice 'b)")?;
+            assert!(format!("{}", output) == "\"b b\"");
+
+            // which leaves the Thread's own output port in place
+            assert!(t.output_port(mem).name(mem) == "<stdout>");
+
+            assert!(eval_helper(mem, t, "(with-output-to-string 'a)").is_err());
+            assert!(eval_helper(mem, t, "(with-output-to-string (lambda (x) x))").is_err());
+            assert!(eval_helper(mem, t, "(write-string in \"x\")").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn port_string_ports_in_sandbox() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            t.set_sandbox(Some(Sandbox::new()));
+
+            eval_helper(mem, t, "(set 'in (open-input-string \"one\"))")?;
+            let line = eval_helper(mem, t, "(read-line in)")?;
+            assert!(format!("{}", line) == "\"one\"");
+            eval_helper(mem, t, "(close in)")?;
+
+            let output = eval_helper(mem, t, "(with-output-to-string (lambda () (println 'a)))")?;
+            assert!(format!("{}", output) == "\"a\n\"");
+
+            // the standard streams and files are not
+            for code in &[
+                "(print 'a)",
+                "(flush)",
+                "(write-string stdout \"a\")",
+                "(read-line stdin)",
+                "(close stdout)",
+                "(open \"x\" 'r)",
+            ] {
+                assert!(eval_helper(mem, t, code).is_err());
+            }
+            assert!(!t.output_port(mem).is_closed());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn port_stdout_is_buffered() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+            let buffered = || STDOUT_BUFFER.with(|buffer| buffer.borrow().len());
+
+            // each test runs on a thread of its own, with a buffer of its own
+            assert!(stdout_buffer_size() == DEFAULT_STDOUT_BUFFER_SIZE);
+            eval_helper(mem, t, "(print \"buffered \")")?;
+            assert!(buffered() == 9);
+            assert!(eval_helper(mem, t, "(flush)")? == mem.nil());
+            assert!(buffered() == 0);
+
+            // output is written out once the buffer is full
+            set_stdout_buffer_size(16)?;
+            eval_helper(mem, t, "(print \"first \")")?;
+            assert!(buffered() == 6);
+            eval_helper(mem, t, "(println \"and second\")")?;
+            assert!(buffered() == 0);
+
+            // and goes before anything written to stderr
+            eval_helper(mem, t, "(print \"out \")")?;
+            Port::alloc_stderr(mem)?.write_str(mem, "")?;
+            assert!(buffered() == 0);
+
+            set_stdout_buffer_size(0)?;
+            eval_helper(mem, t, "(print \"unbuffered\n\")")?;
+            assert!(buffered() == 0);
+
+            // flushing other ports
+            Port::open_output_string(mem)?.flush(mem)?;
+            eval_helper(mem, t, "(set 'in (open-input-string \"x\"))")?;
+            assert!(eval_helper(mem, t, "(flush in)").is_err());
+            assert!(eval_helper(mem, t, "(flush 'a)").is_err());
+            assert!(eval_helper(mem, t, "(flush stdout stdout)").is_err());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn port_bad_arguments() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let t = Thread::alloc(mem)?;
+
+            // wrong direction, mode, types and argument count
+            assert!(eval_helper(mem, t, "(read-line stdout)").is_err());
+            assert!(eval_helper(mem, t, "(write-string stdin \"x\")").is_err());
+            assert!(eval_helper(mem, t, "(open \"x\" 'q)").is_err());
+            assert!(eval_helper(mem, t, "(read-line 'a)").is_err());
+            assert!(eval_helper(mem, t, "(close)").is_err());
+
+            // opening a file that doesn't exist is an evaluation error, not a fatal error
+            let path = temp_path("port-does-not-exist");
+            let code = format!("(open \"{}\" 'r)", path);
+            match eval_helper(mem, t, &code) {
+                Err(e) => match e.error_kind() {
+                    ErrorKind::EvalError(reason) => assert!(reason.starts_with("Could not open")),
+                    _ => panic!("expected an evaluation error"),
+                },
+                Ok(_) => panic!("expected an error"),
+            }
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+}
@@ -29,10 +29,347 @@ pub trait Print {
     //) -> io::Result<()>;
 }
 
+/// The number of container elements printed when the format does not specify a precision
+pub const DEFAULT_MAX_ELEMENTS: usize = 100;
+
+/// Return the maximum number of container elements to print. The format precision sets the limit,
+/// so `{:.10}` prints at most ten elements of each container.
+pub fn max_elements(f: &fmt::Formatter) -> usize {
+    f.precision().unwrap_or(DEFAULT_MAX_ELEMENTS)
+}
+
+/// Write `length` container elements between `open` and `close`, separated by `separator`.
+/// Elements beyond `max_elements()` are not printed, the count of them is written instead.
+pub fn print_elements<I, F>(
+    f: &mut fmt::Formatter,
+    open: &str,
+    separator: &str,
+    close: &str,
+    length: usize,
+    items: I,
+    mut print_item: F,
+) -> fmt::Result
+where
+    I: IntoIterator,
+    F: FnMut(&mut fmt::Formatter, I::Item) -> fmt::Result,
+{
+    let max = max_elements(f);
+
+    write!(f, "{}", open)?;
+
+    for (i, item) in items.into_iter().take(max).enumerate() {
+        if i > 0 {
+            write!(f, "{}", separator)?;
+        }
+        print_item(f, item)?;
+    }
+
+    if length > max {
+        if max > 0 {
+            write!(f, "{}", separator)?;
+        }
+        write!(f, "\u{2026} (+{} more)", length - max)?;
+    }
+
+    write!(f, "{}", close)
+}
+
+/// The number of characters of a value's printed form included by `describe()`
+pub const DESCRIBE_MAX_CHARS: usize = 60;
+
+/// The number of elements of each container included by `describe()`
+const DESCRIBE_MAX_ELEMENTS: usize = 8;
+
+/// A `fmt::Write` sink that fails once it has been given `limit` characters, which stops the
+/// printing of a large or deeply nested value early
+struct LimitedWriter {
+    out: String,
+    remaining: usize,
+    truncated: bool,
+}
+
+impl fmt::Write for LimitedWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.remaining == 0 {
+                self.truncated = true;
+                return Err(fmt::Error);
+            }
+            self.out.push(c);
+            self.remaining -= 1;
+        }
+        Ok(())
+    }
+}
+
+/// Describe a value for an error message: its printed form, cut short if it is long, followed by
+/// its type, for example `(a b) (pair)`
+pub fn describe(value: Value) -> String {
+    let mut writer = LimitedWriter {
+        out: String::new(),
+        remaining: DESCRIBE_MAX_CHARS,
+        truncated: false,
+    };
+
+    // an error here only means the limit was reached
+    let _ = fmt::Write::write_fmt(
+        &mut writer,
+        format_args!("{:.*}", DESCRIBE_MAX_ELEMENTS, value),
+    );
+
+    if writer.truncated {
+        writer.out.push('\u{2026}');
+    }
+
+    format!("{} ({})", writer.out, value.type_name())
+}
+
 pub fn print(value: Value) -> String {
     format!("{}", value)
 }
 
+/// Like `print()` but strings are written as their plain content, without quotes
+pub fn display(value: Value) -> String {
+    match value {
+        Value::Text(t) => String::from(t.as_str(&value)),
+        _ => format!("{}", value),
+    }
+}
+
 pub fn debug(value: Value) -> String {
     format!("{:?}", value)
 }
+
+/// The line width `pretty()` fits source code into when formatting files
+pub const PRETTY_WIDTH: usize = 80;
+
+/// For a special form whose body is indented below it, return the count of arguments that
+/// belong on its opening line: `(def name (params)` is followed by the body expressions
+fn opening_args(name: &str) -> Option<usize> {
+    match name {
+        "def" => Some(2),
+        "lambda" | "\\" | "let" | "match" | "deftest" => Some(1),
+        _ => None,
+    }
+}
+
+/// Return true if the list element at `index` is the empty parameter list of a function
+/// definition, which is printed as `()` rather than `nil`
+fn is_empty_params<'guard>(
+    guard: &'guard dyn MutatorScope,
+    elements: &[Value<'guard>],
+    index: usize,
+) -> bool {
+    let params_index = match elements[0] {
+        Value::Symbol(s) => match s.as_str(guard) {
+            "def" => 2,
+            "lambda" | "\\" => 1,
+            _ => return false,
+        },
+        _ => return false,
+    };
+
+    index == params_index && matches!(elements.get(index), Some(Value::Nil))
+}
+
+/// Split a list into its elements and, if it is not terminated by nil, its final value
+fn list_parts<'guard>(
+    guard: &'guard dyn MutatorScope,
+    value: Value<'guard>,
+) -> (Vec<Value<'guard>>, Option<Value<'guard>>) {
+    let mut elements = Vec::new();
+    let mut next = value;
+
+    while let Value::Pair(pair) = next {
+        elements.push(*pair.first.get(guard));
+        next = *pair.second.get(guard);
+    }
+
+    match next {
+        Value::Nil => (elements, None),
+        tail => (elements, Some(tail)),
+    }
+}
+
+/// Return the quoted value if the value is a `(quote x)` form, which is printed as `'x`
+fn quoted<'guard>(guard: &'guard dyn MutatorScope, value: Value<'guard>) -> Option<Value<'guard>> {
+    match list_parts(guard, value) {
+        (elements, None) if elements.len() == 2 => match elements[0] {
+            Value::Symbol(s) if s.as_str(guard) == "quote" => Some(elements[1]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Append the value as source code on a single line
+fn pretty_flat(value: Value, out: &mut String) {
+    if let Value::Pair(_) = value {
+        if let Some(quoted) = quoted(&value, value) {
+            out.push('\'');
+            return pretty_flat(quoted, out);
+        }
+
+        let (elements, tail) = list_parts(&value, value);
+
+        out.push('(');
+        for (i, element) in elements.iter().enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+            if is_empty_params(&value, &elements, i) {
+                out.push_str("()");
+            } else {
+                pretty_flat(*element, out);
+            }
+        }
+        if let Some(tail) = tail {
+            out.push_str(" . ");
+            pretty_flat(tail, out);
+        }
+        out.push(')');
+    } else {
+        // print every element of a literal array, up to the largest precision a format accepts
+        out.push_str(&format!("{:.*}", u16::MAX as usize, value));
+    }
+}
+
+/// Return the column the next character appended to `out` will be in
+fn current_column(out: &str) -> usize {
+    match out.rfind('\n') {
+        Some(newline) => out[newline + 1..].chars().count(),
+        None => out.chars().count(),
+    }
+}
+
+/// Start a new line at the given column
+fn new_line(column: usize, out: &mut String) {
+    out.push('\n');
+    out.push_str(&" ".repeat(column));
+}
+
+/// Append the value as source code starting at the current column, breaking lists that do not fit
+/// in `width` columns across lines
+fn pretty_at(value: Value, width: usize, out: &mut String) {
+    let column = current_column(out);
+
+    let mut flat = String::new();
+    pretty_flat(value, &mut flat);
+    if column + flat.chars().count() <= width {
+        out.push_str(&flat);
+        return;
+    }
+
+    // only a list can be broken across lines
+    if let Value::Pair(_) = value {
+        if let Some(quoted) = quoted(&value, value) {
+            out.push('\'');
+            return pretty_at(quoted, width, out);
+        }
+    } else {
+        out.push_str(&flat);
+        return;
+    }
+
+    let (elements, tail) = list_parts(&value, value);
+    let (head, args) = (elements[0], &elements[1..]);
+
+    out.push('(');
+    pretty_at(head, width, out);
+
+    match head {
+        // cond clauses are test and result pairs, one pair to a line
+        Value::Symbol(s) if s.as_str(&s) == "cond" => {
+            let clause_column = current_column(out) + 1;
+            for (i, clause) in args.chunks(2).enumerate() {
+                if i == 0 {
+                    out.push(' ');
+                } else {
+                    new_line(clause_column, out);
+                }
+                pretty_at(clause[0], width, out);
+                if let Some(result) = clause.get(1) {
+                    out.push(' ');
+                    pretty_at(*result, width, out);
+                }
+            }
+        }
+
+        // a special form with a body: the opening arguments follow the name, the body is
+        // indented by two below
+        Value::Symbol(s) if matches!(opening_args(s.as_str(&s)), Some(n) if args.len() > n) => {
+            let n = opening_args(s.as_str(&s)).unwrap_or(0);
+            for (i, arg) in args[..n].iter().enumerate() {
+                out.push(' ');
+                if is_empty_params(&value, &elements, i + 1) {
+                    out.push_str("()");
+                } else {
+                    pretty_at(*arg, width, out);
+                }
+            }
+            for arg in &args[n..] {
+                new_line(column + 2, out);
+                pretty_at(*arg, width, out);
+            }
+        }
+
+        // a list in the first position, such as let bindings: every element is aligned
+        Value::Pair(_) => {
+            for arg in args {
+                new_line(column + 1, out);
+                pretty_at(*arg, width, out);
+            }
+        }
+
+        // a function call: the arguments are aligned with the first
+        _ => {
+            let arg_column = current_column(out) + 1;
+            for (i, arg) in args.iter().enumerate() {
+                if i == 0 {
+                    out.push(' ');
+                } else {
+                    new_line(arg_column, out);
+                }
+                pretty_at(*arg, width, out);
+            }
+        }
+    }
+
+    if let Some(tail) = tail {
+        out.push_str(" . ");
+        pretty_at(tail, width, out);
+    }
+    out.push(')');
+}
+
+/// Print a value as source code, breaking lists that do not fit in `width` columns across lines.
+/// Special forms such as `def` and `let` indent their bodies by two spaces, the arguments of a
+/// function call are aligned with the first and `(quote x)` is printed as `'x`.
+pub fn pretty(value: Value, width: usize) -> String {
+    let mut out = String::new();
+    pretty_at(value, width, &mut out);
+    out
+}
+
+/// Print a sequence of top level forms as source code, one after another, each fitted into
+/// `width` columns by `pretty()`. A form that spans several lines is separated from its neighbours
+/// by a blank line.
+pub fn pretty_forms(forms: &[Value], width: usize) -> String {
+    let mut out = String::new();
+    let mut previous_multiline = false;
+
+    for (i, form) in forms.iter().enumerate() {
+        let printed = pretty(*form, width);
+        let multiline = printed.contains('\n');
+
+        if i > 0 && (multiline || previous_multiline) {
+            out.push('\n');
+        }
+        out.push_str(&printed);
+        out.push('\n');
+
+        previous_multiline = multiline;
+    }
+
+    out
+}
@@ -0,0 +1,220 @@
+//! A per-opcode cost profile of the VM, enabled by the `vm-profile` feature.
+//!
+//! Every instruction is counted, but only one in `SAMPLE_INTERVAL` is timed, since reading the
+//! clock costs as much as many of the instructions it would measure. The time spent in an opcode
+//! is estimated from its sampled time, scaled up by the ratio of its executed count to its sampled
+//! count. The `(vm-profile)` builtin prints the estimates as a histogram.
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::error::RuntimeError;
+use crate::memory::MutatorView;
+use crate::native_module;
+use crate::safeptr::{TaggedCellPtr, TaggedScopedPtr};
+use crate::vm::Thread;
+
+/// Time one instruction in this many
+pub const SAMPLE_INTERVAL: u64 = 16;
+
+/// The width of the longest histogram bar
+const BAR_WIDTH: usize = 40;
+
+/// The profile of one opcode
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpcodeCost {
+    /// Count of instructions executed
+    pub executed: u64,
+    /// Count of instructions timed
+    pub sampled: u64,
+    /// Total time of the timed instructions
+    pub sampled_time: Duration,
+}
+
+impl OpcodeCost {
+    /// Estimate the total time spent executing the opcode
+    pub fn estimated_time(&self) -> Duration {
+        if self.sampled == 0 {
+            return Duration::from_secs(0);
+        }
+
+        let nanos = self.sampled_time.as_nanos() * self.executed as u128 / self.sampled as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+/// The cost of each opcode executed by a Thread
+#[derive(Default)]
+pub struct OpcodeProfile {
+    costs: HashMap<&'static str, OpcodeCost>,
+    /// Count of instructions executed, which decides which are sampled
+    executed: u64,
+}
+
+impl OpcodeProfile {
+    pub fn new() -> OpcodeProfile {
+        OpcodeProfile::default()
+    }
+
+    /// Count an instruction about to be executed, returning true if it should be timed
+    pub fn count(&mut self, opcode: &'static str) -> bool {
+        self.costs.entry(opcode).or_default().executed += 1;
+        self.executed += 1;
+        self.executed.is_multiple_of(SAMPLE_INTERVAL)
+    }
+
+    /// Record the time taken by a sampled instruction
+    pub fn record_sample(&mut self, opcode: &'static str, elapsed: Duration) {
+        let cost = self.costs.entry(opcode).or_default();
+        cost.sampled += 1;
+        cost.sampled_time += elapsed;
+    }
+
+    /// Return the cost of every opcode executed, most expensive first
+    pub fn costs(&self) -> Vec<(&'static str, OpcodeCost)> {
+        let mut costs: Vec<(&'static str, OpcodeCost)> = self
+            .costs
+            .iter()
+            .map(|(name, cost)| (*name, *cost))
+            .collect();
+
+        costs.sort_by(|a, b| {
+            b.1.estimated_time()
+                .cmp(&a.1.estimated_time())
+                .then(b.1.executed.cmp(&a.1.executed))
+                .then(a.0.cmp(b.0))
+        });
+
+        costs
+    }
+
+    /// Forget everything recorded so far
+    pub fn clear(&mut self) {
+        self.costs.clear();
+        self.executed = 0;
+    }
+
+    /// Format the profile as a histogram of estimated time, one line per opcode
+    pub fn histogram(&self) -> String {
+        let costs = self.costs();
+        let total: Duration = costs.iter().map(|(_, cost)| cost.estimated_time()).sum();
+
+        let mut report = format!(
+            "{:<16} {:>12} {:>12} {:>7}\n",
+            "opcode", "executed", "est. ms", "time"
+        );
+
+        for (name, cost) in costs {
+            let share = match total.as_nanos() {
+                0 => 0.0,
+                total => cost.estimated_time().as_nanos() as f64 / total as f64,
+            };
+
+            let _ = writeln!(
+                report,
+                "{:<16} {:>12} {:>12.3} {:>6.1}% {}",
+                name,
+                cost.executed,
+                cost.estimated_time().as_secs_f64() * 1000.0,
+                share * 100.0,
+                "#".repeat((share * BAR_WIDTH as f64).round() as usize)
+            );
+        }
+
+        report
+    }
+}
+
+/// (vm-profile) - print a histogram of the estimated time spent executing each opcode so far and
+/// start a new profile
+fn vm_profile<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    _args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let report = thread.opcode_profile().borrow().histogram();
+    thread.opcode_profile().borrow_mut().clear();
+
+    thread.output_port(mem).write_str(mem, &report)?;
+    Ok(mem.nil())
+}
+
+native_module! {
+    /// The VM profiling builtin functions
+    pub PROFILE_MODULE = "profile" [io] {
+        "vm-profile" => vm_profile(0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    #[test]
+    fn opcode_cost_estimates() {
+        let mut profile = OpcodeProfile::new();
+
+        for _ in 0..SAMPLE_INTERVAL * 2 {
+            if profile.count("call") {
+                profile.record_sample("call", Duration::from_micros(10));
+            }
+        }
+        profile.count("no-op");
+
+        let costs = profile.costs();
+        assert!(costs[0].0 == "call");
+        assert!(costs[0].1.executed == SAMPLE_INTERVAL * 2);
+        assert!(costs[0].1.sampled == 2);
+        assert!(costs[0].1.estimated_time() == Duration::from_micros(20 * SAMPLE_INTERVAL));
+
+        // an opcode that was never sampled is listed with no time
+        assert!(costs[1].0 == "no-op");
+        assert!(costs[1].1.estimated_time() == Duration::from_secs(0));
+
+        let histogram = profile.histogram();
+        assert!(histogram.lines().count() == 3);
+        assert!(histogram
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with(&"#".repeat(BAR_WIDTH)));
+    }
+
+    #[test]
+    fn vm_profile_counts_executed_opcodes() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                eval("(def f (x) (cons x x))")?;
+                for _ in 0..20 {
+                    eval("(f 'a)")?;
+                }
+
+                let costs = t.opcode_profile().borrow().costs();
+                let calls = costs.iter().find(|(name, _)| *name == "call").unwrap().1;
+                assert!(calls.executed == 20);
+                assert!(costs.iter().any(|(name, _)| *name == "make-pair"));
+
+                // the report starts a new profile
+                eval("(vm-profile)")?;
+                let costs = t.opcode_profile().borrow().costs();
+                assert!(costs.iter().all(|(name, _)| *name != "make-pair"));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+/// Promises for lazy evaluation and the builtin functions that operate on them.
+///
+/// `(delay expr)` compiles `expr` into a function of no parameters, a closure if `expr` refers to
+/// local variables, and evaluates to a `Promise` holding it without calling it. `(force p)` calls
+/// the function the first time `p` is forced and returns the same value every time after that,
+/// without calling it again.
+///
+/// `(cons-stream a b)` is `(cons a (delay b))`, the building block of lazy lists whose rest is
+/// only computed when it is forced, for example an endless stream
+/// `(def repeat (x) (cons-stream x (repeat x)))`.
+use std::cell::Cell;
+use std::fmt;
+
+use crate::error::RuntimeError;
+use crate::memory::MutatorView;
+use crate::native_module;
+use crate::printer::Print;
+use crate::safeptr::{MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+use crate::vm::Thread;
+
+/// A delayed evaluation object type
+pub struct Promise {
+    /// The function of no parameters that computes the value, or nil once it has been called
+    thunk: TaggedCellPtr,
+    /// The value, once forced
+    value: TaggedCellPtr,
+    forced: Cell<bool>,
+}
+
+impl Promise {
+    /// Allocate a promise of the value the given function returns on the heap
+    pub fn alloc<'guard>(
+        mem: &'guard MutatorView,
+        thunk: TaggedScopedPtr<'guard>,
+    ) -> Result<ScopedPtr<'guard, Promise>, RuntimeError> {
+        mem.alloc(Promise {
+            thunk: TaggedCellPtr::new_with(thunk),
+            value: TaggedCellPtr::new_nil(),
+            forced: Cell::new(false),
+        })
+    }
+
+    /// Return the value if the promise has been forced
+    pub fn value<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+    ) -> Option<TaggedScopedPtr<'guard>> {
+        match self.forced.get() {
+            true => Some(self.value.get(guard)),
+            false => None,
+        }
+    }
+
+    /// Return the function that computes the value, which is nil once the promise has been forced
+    pub fn thunk<'guard>(&self, guard: &'guard dyn MutatorScope) -> TaggedScopedPtr<'guard> {
+        self.thunk.get(guard)
+    }
+
+    /// Record the value computed by the function and return the promise's value. If the promise
+    /// was forced while its function was being called, the value from then is kept.
+    pub fn resolve<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        value: TaggedScopedPtr<'guard>,
+    ) -> TaggedScopedPtr<'guard> {
+        if !self.forced.get() {
+            self.value.set(value);
+            self.thunk.set_to_nil();
+            self.forced.set(true);
+        }
+
+        self.value.get(guard)
+    }
+}
+
+impl Print for Promise {
+    /// Prints a string representation of the promise without forcing it or printing its value
+    fn print<'guard>(
+        &self,
+        _guard: &'guard dyn MutatorScope,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self.forced.get() {
+            true => write!(f, "(Promise forced)"),
+            false => write!(f, "(Promise)"),
+        }
+    }
+}
+
+/// (force p) - return the value of a promise, computing it if this is the first time it has been
+/// forced. Any other value is returned as it is.
+fn force<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let promise = match *args[0].get(mem) {
+        Value::Promise(promise) => promise,
+        _ => return Ok(args[0].get(mem)),
+    };
+
+    if let Some(value) = promise.value(mem) {
+        return Ok(value);
+    }
+
+    // this Thread is busy calling force, so the function is called on another that can see this
+    // one's closures. If it fails the promise is left unforced.
+    let runner = thread.alloc_nested(mem)?;
+    let value = runner.quick_vm_apply(mem, promise.thunk(mem), &[])?;
+
+    Ok(promise.resolve(mem, value))
+}
+
+/// (promise? x) - return true if x is a promise
+fn is_promise<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    match *args[0].get(mem) {
+        Value::Promise(_) => Ok(mem.sym_true()),
+        _ => Ok(mem.nil()),
+    }
+}
+
+native_module! {
+    /// The lazy evaluation builtin functions
+    pub PROMISE_MODULE = "promise" {
+        "force" => force(1),
+        "promise?" => is_promise(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+    use crate::port::Port;
+
+    #[test]
+    fn delay_and_force() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code: &str| -> Result<String, RuntimeError> {
+                    Ok(format!(
+                        "{}",
+                        t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)?
+                    ))
+                };
+
+                // the delayed expression prints an x each time it is evaluated
+                let output = Port::open_output_string(mem)?;
+                t.set_output_port(output);
+                let printed = || -> Result<String, RuntimeError> {
+                    Ok(String::from(output.output_string(mem)?))
+                };
+
+                eval("(set 'p (delay (cons (print 'x) 'done)))")?;
+                assert!(eval("p")? == "(Promise)");
+                assert!(printed()?.is_empty());
+
+                assert!(eval("(force p)")? == "(nil . done)");
+                assert!(eval("(is? (force p) (force p))")? == "true");
+                assert!(printed()? == "x");
+                assert!(eval("p")? == "(Promise forced)");
+
+                // a delayed expression can refer to the variables of the function it is in
+                eval("(def later (a b) (delay (cons b a)))")?;
+                assert!(eval("(force (later 'a 'b))")? == "(b . a)");
+
+                // the rest of an endless stream is computed as it is needed
+                eval("(def repeat (x) (cons-stream x (repeat x)))")?;
+                eval(
+                    "(def take (n s) \
+                       (cond (nil? n) nil \
+                             true (cons (car s) (take (cdr n) (force (cdr s))))))",
+                )?;
+                assert!(eval("(take '(1 2 3) (repeat 'y))")? == "(y y y)");
+                assert!(eval("(promise? (cdr (repeat 'y)))")? == "true");
+
+                // values that are not promises are forced to themselves
+                assert!(eval("(force 'a)")? == "a");
+                assert!(eval("(promise? 'a)")? == "nil");
+
+                // a failed evaluation leaves the promise to be forced again
+                eval("(set 'q (delay (car 'z)))")?;
+                assert!(eval("(force q)").is_err());
+                assert!(eval("q")? == "(Promise)");
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+//! A small, fast, non-cryptographic pseudo random number generator: xorshift64*.
+//!
+//! Each Thread carries its own generator state so that a fixed seed gives a reproducible
+//! sequence.
+
+/// xorshift64* generator state. The state is never zero.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    /// Create a generator from any seed value, including zero
+    pub fn new(seed: u64) -> XorShift {
+        // Scramble the seed with a splitmix64 step so that similar seeds give unrelated
+        // sequences and a zero seed does not give a zero state
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        XorShift {
+            state: if z == 0 { 1 } else { z },
+        }
+    }
+
+    /// Return the next 64 bit value in the sequence
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Return a value in the range 0..bound. The bound must be greater than zero.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        // Reject values from the incomplete final span of the u64 range to avoid modulo bias
+        let limit = u64::MAX - (u64::MAX % bound);
+        loop {
+            let value = self.next_u64();
+            if value < limit {
+                return value % bound;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::XorShift;
+
+    #[test]
+    fn xorshift_is_deterministic() {
+        let mut a = XorShift::new(42);
+        let mut b = XorShift::new(42);
+        let mut c = XorShift::new(43);
+
+        let seq_a: Vec<u64> = (0..16).map(|_| a.next_u64()).collect();
+        let seq_b: Vec<u64> = (0..16).map(|_| b.next_u64()).collect();
+        let seq_c: Vec<u64> = (0..16).map(|_| c.next_u64()).collect();
+
+        assert!(seq_a == seq_b);
+        assert!(seq_a != seq_c);
+    }
+
+    #[test]
+    fn xorshift_zero_seed() {
+        let mut rng = XorShift::new(0);
+        assert!((0..16).map(|_| rng.next_u64()).any(|n| n != 0));
+    }
+
+    #[test]
+    fn xorshift_below_bound() {
+        let mut rng = XorShift::new(7);
+        let mut seen = [false; 6];
+
+        for _ in 0..1000 {
+            let n = rng.below(6);
+            assert!(n < 6);
+            seen[n as usize] = true;
+        }
+
+        assert!(seen.iter().all(|s| *s));
+        assert!(rng.below(1) == 0);
+    }
+}
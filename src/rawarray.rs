@@ -5,7 +5,7 @@ use std::slice::from_raw_parts_mut;
 pub use stickyimmix::ArraySize;
 
 use crate::error::{ErrorKind, RuntimeError};
-use crate::memory::MutatorView;
+use crate::memory::{MutatorView, POISON_BYTE};
 
 /// Arrays start out at this size by default
 pub const DEFAULT_ARRAY_SIZE: ArraySize = 8;
@@ -15,8 +15,9 @@ pub fn default_array_growth(capacity: ArraySize) -> Result<ArraySize, RuntimeErr
     if capacity == 0 {
         Ok(DEFAULT_ARRAY_SIZE)
     } else {
+        // grow by at least one, since half of a capacity of one is zero
         capacity
-            .checked_add(capacity / 2)
+            .checked_add((capacity / 2).max(1))
             .ok_or(RuntimeError::new(ErrorKind::BadAllocationRequest))
     }
 }
@@ -112,6 +113,13 @@ impl<T: Sized> RawArray<T> {
                     *dest = *src;
                 }
 
+                // The old array is now garbage; if poisoning, make any dangling use of it fail
+                if mem.poisons_storage() {
+                    for byte in old_slice.iter_mut() {
+                        *byte = POISON_BYTE;
+                    }
+                }
+
                 self.ptr = NonNull::new(new_ptr);
                 self.capacity = new_capacity;
 
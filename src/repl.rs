@@ -1,88 +1,1566 @@
-use crate::compiler::compile;
-use crate::error::{ErrorKind, RuntimeError};
-use crate::memory::{Mutator, MutatorView};
-use crate::parser::parse;
-use crate::safeptr::{CellPtr, TaggedScopedPtr};
-use crate::vm::Thread;
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use crate::compiler::{
+    check_toplevel, compile_toplevel_in_context, ArityChecks, CompileContext, CompileOptions,
+    OptLevel,
+};
+use crate::containers::{Container, IndexedAnyContainer};
+use crate::debug::Tracer;
+use crate::error::{Diagnostic, DiagnosticFormat, ErrorKind, RuntimeError};
+use crate::function::Function;
+use crate::image::{load_image, save_image};
+use crate::lexer::lex_reader;
+use crate::memory::{HeapBackend, HeapStorage, Mutator, MutatorView, StatefulMutator};
+use crate::parser::Parser;
+use crate::port::{flush_stdout, set_stdout_buffer_size, Stderr};
+use crate::printer::{debug, pretty_forms, PRETTY_WIDTH};
+use crate::safeptr::{CellPtr, MutatorScope, ScopedPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+use crate::vm::{Thread, ENV_REG, FIRST_ARG_REG};
+
+/// The prompt shown when the REPL is ready for an expression. In this and the continuation prompt,
+/// `{n}` is replaced by the number the next input will be given.
+pub const DEFAULT_PROMPT: &str = "In[{n}]: ";
+
+/// The prompt shown for each further line of an expression that is not yet complete
+pub const DEFAULT_CONTINUATION_PROMPT: &str = ". ";
+
+/// Return the REPL startup banner: the version, the commit it was built from and the heap
+/// backend it was built with
+pub fn banner() -> String {
+    format!(
+        "Eval-R-Us {} (git {}, {})",
+        env!("CARGO_PKG_VERSION"),
+        option_env!("EVALRUS_GIT_HASH").unwrap_or("unknown"),
+        <HeapStorage as HeapBackend>::NAME
+    )
+}
 
 /// A mutator that returns a Repl instance
-pub struct RepMaker {}
+pub struct RepMaker {
+    /// Start with instruction tracing enabled
+    pub trace: bool,
+    /// The initial prompt
+    pub prompt: String,
+    /// The initial continuation prompt
+    pub continuation_prompt: String,
+    /// The initial optimization level
+    pub opt_level: OptLevel,
+}
 
 impl Mutator for RepMaker {
     type Input = ();
     type Output = ReadEvalPrint;
 
     fn run(&self, mem: &MutatorView, _input: ()) -> Result<ReadEvalPrint, RuntimeError> {
-        ReadEvalPrint::alloc(mem)
+        let mut rep = ReadEvalPrint::alloc(mem)?;
+        rep.set_trace(mem, self.trace);
+        rep.prompt = self.prompt.clone();
+        rep.continuation_prompt = self.continuation_prompt.clone();
+        rep.options.opt_level = self.opt_level;
+        Ok(rep)
     }
 }
 
+/// Number of compiled lines the REPL keeps for reuse
+const COMPILE_CACHE_SIZE: usize = 64;
+
+/// The compiled Functions of recently entered lines, one for each form of the line, keyed by source
+/// text, so that entering a line again skips parsing and compiling it.
+///
+/// Compiled code refers to literals by their index in the Memory's constant pool, which keeps
+/// every entry for as long as the Memory exists, so a cached Function never refers to a literal
+/// that has gone. Globals are looked up by name when code runs, so redefining a global that
+/// cached code refers to does not make the cached code stale and no entry needs invalidating.
+struct CompileCache {
+    /// Compiled Functions and compiler warnings for each line
+    entries: HashMap<String, (Vec<CellPtr<Function>>, Vec<Diagnostic>)>,
+    /// Cached lines in the order they were added, oldest first
+    order: VecDeque<String>,
+}
+
+impl CompileCache {
+    fn new() -> CompileCache {
+        CompileCache {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Return the compiled Functions and warnings for a line, if it is cached
+    fn get<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        line: &str,
+    ) -> Option<(Vec<ScopedPtr<'guard, Function>>, &[Diagnostic])> {
+        self.entries.get(line).map(|(functions, warnings)| {
+            (
+                functions
+                    .iter()
+                    .map(|function| function.get(guard))
+                    .collect(),
+                warnings.as_slice(),
+            )
+        })
+    }
+
+    /// Cache the compiled Functions for a line, evicting the oldest entry if the cache is full
+    fn insert(
+        &mut self,
+        line: &str,
+        functions: &[ScopedPtr<'_, Function>],
+        warnings: Vec<Diagnostic>,
+    ) {
+        if self.order.len() >= COMPILE_CACHE_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        let line = String::from(line);
+        self.order.push_back(line.clone());
+        let functions = functions
+            .iter()
+            .map(|function| CellPtr::new_with(*function))
+            .collect();
+        self.entries.insert(line, (functions, warnings));
+    }
+}
+
+/// Number of previous results kept in the globals `$1` to `$9`
+const RESULT_HISTORY: usize = 9;
+
+/// How far the evaluation of a line got
+#[derive(Clone, Copy, Default)]
+struct LineProgress {
+    /// Count of forms on the line, once it has been parsed
+    forms: usize,
+    /// Whether the forms began to be evaluated, which they only do if all of them compiled
+    started: bool,
+    /// Count of forms evaluated without error
+    evaluated: usize,
+}
+
+/// Describe which forms of a numbered line took effect, if the line had more than one form and
+/// failed
+fn line_progress_report(number: usize, progress: LineProgress) -> Option<String> {
+    if progress.forms < 2 {
+        return None;
+    }
+
+    if !progress.started {
+        return Some(format!(
+            "In[{}]: none of the {} forms were evaluated",
+            number, progress.forms
+        ));
+    }
+
+    let failed = progress.evaluated + 1;
+    let before = match progress.evaluated {
+        0 => String::from("none before it took effect"),
+        1 => String::from("form 1 took effect"),
+        evaluated => format!("forms 1 to {} took effect", evaluated),
+    };
+    let after = match progress.forms - failed {
+        0 => String::new(),
+        1 => format!(", form {} was not evaluated", progress.forms),
+        _ => format!(
+            ", forms {} to {} were not evaluated",
+            failed + 1,
+            progress.forms
+        ),
+    };
+
+    Some(format!(
+        "In[{}]: form {} of {} failed, {}{}",
+        number, failed, progress.forms, before, after
+    ))
+}
+
 /// Mutator that implements the VM
 pub struct ReadEvalPrint {
     main_thread: CellPtr<Thread>,
+    /// The main thread's interrupt flag, available outside of a mutator scope
+    interrupt: Arc<AtomicBool>,
+    /// Print debug representations for every line, toggled by a line containing only ":d". The
+    /// frames of a failed evaluation are kept for `:regs`, and a snapshot of them for `:why`,
+    /// while it is on.
+    debug: bool,
+    /// Compiled code of recently entered lines
+    cache: CompileCache,
+    /// The prompt, set by ":set prompt"
+    prompt: String,
+    /// The continuation prompt, set by ":set continuation-prompt"
+    continuation_prompt: String,
+    /// Code generation settings, of which arity checks are set by ":set arity-checks" and the
+    /// optimization level, unoptimized to begin with so that lines compile quickly, by ":set opt"
+    options: CompileOptions,
+    /// The number of the next line to be evaluated, shown in the prompt and with its result
+    input_number: usize,
+    /// How far the evaluation of the last line got
+    progress: LineProgress,
 }
 
 impl ReadEvalPrint {
     pub fn alloc(mem: &MutatorView) -> Result<ReadEvalPrint, RuntimeError> {
+        let main_thread = Thread::alloc(mem)?;
+
         Ok(ReadEvalPrint {
-            main_thread: CellPtr::new_with(Thread::alloc(mem)?),
+            interrupt: main_thread.interrupt_flag(),
+            main_thread: CellPtr::new_with(main_thread),
+            debug: false,
+            cache: CompileCache::new(),
+            prompt: String::from(DEFAULT_PROMPT),
+            continuation_prompt: String::from(DEFAULT_CONTINUATION_PROMPT),
+            options: CompileOptions {
+                opt_level: OptLevel::O0,
+                ..CompileOptions::default()
+            },
+            input_number: 1,
+            progress: LineProgress::default(),
         })
     }
+
+    /// Return the prompt to show when ready for an expression
+    pub fn prompt(&self) -> String {
+        self.prompt.replace("{n}", &self.input_number.to_string())
+    }
+
+    /// Return the prompt to show for further lines of an incomplete expression
+    pub fn continuation_prompt(&self) -> String {
+        self.continuation_prompt
+            .replace("{n}", &self.input_number.to_string())
+    }
+
+    /// Return a handle that interrupts evaluation when set to true
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Turn tracing of each executed instruction to stderr on or off
+    pub fn set_trace(&self, guard: &dyn MutatorScope, trace: bool) {
+        set_trace(&self.main_thread.get(guard), trace);
+    }
+
+    /// Evaluate a line on the main thread, reusing its compiled code if the same line was
+    /// entered before. Debug output bypasses the cache so that every stage is shown, as do arity
+    /// checks, which depend on the globals bound when the line is compiled.
+    ///
+    /// A line may hold several forms. All of them are parsed and compiled before any is evaluated,
+    /// so a line that fails to parse or compile has no effect. The forms are then evaluated in
+    /// turn, stopping at the first that fails, and the result of the last is returned. How far
+    /// evaluation got is recorded in `progress`.
+    fn eval_line<'guard>(
+        &mut self,
+        mem: &'guard MutatorView,
+        line: &str,
+        debug: bool,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let thread = self.main_thread.get(mem);
+        let uncached = debug || self.options.arity_checks != ArityChecks::Off;
+        self.progress = LineProgress::default();
+
+        let cached = if uncached {
+            None
+        } else {
+            self.cache.get(mem, line)
+        };
+
+        let functions = match cached {
+            Some((functions, warnings)) => {
+                for warning in warnings {
+                    warning.print_with_source(line);
+                }
+                functions
+            }
+
+            None => {
+                let mut parser = Parser::new(lex_reader(line.as_bytes()));
+                let mut forms = Vec::new();
+                while let Some(form) = parser.next_expr(mem)? {
+                    forms.push(form);
+                }
+                self.progress.forms = forms.len();
+
+                if debug {
+                    println!("# Debug\n## Input:\n```\n{}\n```", line);
+                }
+
+                let mut functions = Vec::new();
+                let mut warnings = Vec::new();
+
+                for form in forms {
+                    if debug {
+                        println!("## Parsed:\n```\n{:?}\n```", form);
+                    }
+
+                    let context = CompileContext::for_thread(mem, self.options, &thread);
+                    let (function, form_warnings) =
+                        compile_toplevel_in_context(mem, &[form], None, context)?;
+
+                    if debug {
+                        println!("## Compiled:\n```\n{:?}\n```", function);
+                    }
+
+                    functions.push(function);
+                    warnings.extend(form_warnings);
+                }
+
+                for warning in &warnings {
+                    warning.print_with_source(line);
+                }
+
+                if !uncached {
+                    self.cache.insert(line, &functions, warnings);
+                }
+
+                functions
+            }
+        };
+
+        self.progress.forms = functions.len();
+        self.progress.started = true;
+
+        let mut value = mem.nil();
+        for function in functions {
+            value = thread.quick_vm_eval(mem, function)?;
+            self.progress.evaluated += 1;
+
+            if debug {
+                println!("## Evaluated:\n```\n{:?}\n```\n", value);
+            }
+
+            self.record_result(mem, value)?;
+        }
+
+        Ok(value)
+    }
+
+    /// Bind a result to the globals `_` and `$1`, moving earlier results along to `$2` to `$9`.
+    /// The globals are rooted by the main thread, keeping the values alive after the line.
+    fn record_result(
+        &self,
+        mem: &MutatorView,
+        value: TaggedScopedPtr<'_>,
+    ) -> Result<(), RuntimeError> {
+        let thread = self.main_thread.get(mem);
+
+        for n in (1..RESULT_HISTORY).rev() {
+            if let Some(previous) = thread.lookup_global(mem, &format!("${}", n)) {
+                thread.set_global(mem, &format!("${}", n + 1), previous)?;
+            }
+        }
+
+        thread.set_global(mem, "$1", value)?;
+        thread.set_global(mem, "_", value)
+    }
+}
+
+/// Register a Tracer writing to stderr on the thread, or remove it
+fn set_trace(thread: &Thread, trace: bool) {
+    if trace {
+        thread.set_debug_hook(Some(Box::new(Tracer::new(Box::new(Stderr)))));
+    } else {
+        thread.set_debug_hook(None);
+    }
+}
+
+/// Return the function bound to a global name for the `:doc` and `:dis` commands, or a message
+/// explaining why there is none
+fn global_function<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    name: &str,
+) -> Result<ScopedPtr<'guard, Function>, String> {
+    let value = match thread.lookup_global(mem, name) {
+        Some(value) => value,
+        None => return Err(format!("{} is not bound to a value", name)),
+    };
+
+    match *value {
+        Value::Function(f) => Ok(f),
+        Value::Partial(p) => Ok(p.function(mem)),
+        Value::NativeFunction(_) => Err(format!("{}\n    Builtin function", value)),
+        _ => Err(format!("{} is not a function", name)),
+    }
+}
+
+/// Describe the function bound to a global name for the `:doc` command
+fn describe_global(mem: &MutatorView, thread: &Thread, name: &str) -> String {
+    let function = match global_function(mem, thread, name) {
+        Ok(function) => function,
+        Err(message) => return message,
+    };
+
+    let mut description = format!(
+        "{}\n    {}",
+        function,
+        function.doc(mem).unwrap_or("No documentation")
+    );
+
+    if let Some(location) = function.source_location(mem) {
+        description.push_str(&format!("\n    Defined at {}", location));
+    }
+
+    description
+}
+
+/// Disassemble the function bound to a global name for the `:dis` command
+fn disassemble_global(mem: &MutatorView, thread: &Thread, name: &str) -> String {
+    let function = match global_function(mem, thread, name) {
+        Ok(function) => function,
+        Err(message) => return message,
+    };
+
+    let listing = debug(*function.as_tagged(mem));
+
+    match function.source_location(mem) {
+        Some(location) => format!("Defined at {}\n{}", location, listing),
+        None => listing,
+    }
 }
 
-impl Mutator for ReadEvalPrint {
+/// List each register of a function's frame that is not nil, whether it holds the result, the
+/// closure environment, a parameter or a temporary, and its value, one line each with the given
+/// indent
+fn list_registers(
+    mem: &MutatorView,
+    function: ScopedPtr<'_, Function>,
+    registers: &[TaggedScopedPtr<'_>],
+    indent: &str,
+) -> String {
+    let params = function.param_names(mem);
+    let role = |index: usize| match index {
+        0 => String::from("result"),
+        ENV_REG => String::from("closure environment"),
+        index if index >= FIRST_ARG_REG && index - FIRST_ARG_REG < params.length() as usize => {
+            match IndexedAnyContainer::get(&*params, mem, (index - FIRST_ARG_REG) as u32) {
+                Ok(name) => format!("parameter {}", name),
+                Err(_) => String::from("parameter"),
+            }
+        }
+        _ => String::from("temporary"),
+    };
+
+    let mut listing = String::new();
+    for (index, value) in registers.iter().enumerate() {
+        if !matches!(**value, Value::Nil) {
+            listing.push_str(&format!(
+                "\n{}r{:<3} {:<24} {:.16}",
+                indent,
+                index,
+                role(index),
+                value
+            ));
+        }
+    }
+
+    listing
+}
+
+/// Describe the register window of the innermost call frame of a failed evaluation for the
+/// `:regs` command
+fn register_listing(mem: &MutatorView, thread: &Thread) -> String {
+    let window = match thread.register_window(mem) {
+        Some(window) => window,
+        None => {
+            return String::from(
+                "no frames to inspect, the frames of a failed evaluation are kept while debug \
+                 output is on, see :d",
+            )
+        }
+    };
+
+    format!(
+        "registers of {} at instruction {}{}",
+        window.function,
+        window.ip,
+        list_registers(mem, window.function, &window.registers, "  ")
+    )
+}
+
+/// Explain the last evaluation that failed while debug output was on for the `:why` command: the
+/// error, then each call frame, innermost first, with the instruction it was executing and its
+/// registers
+fn explain_last_error(mem: &MutatorView, thread: &Thread) -> String {
+    let post_mortem =
+        match thread.last_error() {
+            Some(post_mortem) => post_mortem,
+            None => return String::from(
+                "no failed evaluation to explain, failures are recorded while debug output is on, \
+                 see :d",
+            ),
+        };
+
+    let mut explanation = post_mortem.message;
+
+    for frame in &post_mortem.frames {
+        let function = frame.function.get(mem);
+        let instruction = match frame.opcode {
+            Some(opcode) => {
+                let mut instruction = format!("({}", opcode.name());
+                for operand in opcode.operands() {
+                    instruction.push_str(&format!(" {}", operand));
+                }
+                instruction + ")"
+            }
+            None => String::from("unknown instruction"),
+        };
+
+        let registers: Vec<TaggedScopedPtr> = frame
+            .registers
+            .iter()
+            .map(|register| register.get(mem))
+            .collect();
+
+        explanation.push_str(&format!(
+            "\n  in {} at instruction {}: {}{}",
+            function,
+            frame.ip,
+            instruction,
+            list_registers(mem, function, &registers, "    ")
+        ));
+    }
+
+    explanation
+}
+
+/// Save the globals to an image file for the `:save` command
+fn save_globals(mem: &MutatorView, thread: &Thread, path: &str) -> String {
+    match save_image(mem, thread, path) {
+        Ok(image) => {
+            let mut report = format!("saved {} globals to {}", image.saved, path);
+            for (name, reason) in image.skipped {
+                report.push_str(&format!("\n    skipped {}: {}", name, reason));
+            }
+            report
+        }
+        Err(e) => format!("{}", e),
+    }
+}
+
+/// Return the text of a prompt setting, without its quotes if it is quoted
+fn prompt_text(value: &str) -> String {
+    let value = value.trim();
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        String::from(&value[1..value.len() - 1])
+    } else {
+        String::from(value)
+    }
+}
+
+/// Return the arity checking level of an arity-checks setting: off, warn or error
+fn arity_checks(value: &str) -> Option<ArityChecks> {
+    match value {
+        "off" => Some(ArityChecks::Off),
+        "warn" => Some(ArityChecks::Warn),
+        "error" => Some(ArityChecks::Error),
+        _ => None,
+    }
+}
+
+/// Bind the globals held in an image file for the `:load-image` command
+fn load_globals(mem: &MutatorView, thread: &Thread, path: &str) -> String {
+    match load_image(mem, thread, path) {
+        Ok(count) => format!("loaded {} globals from {}", count, path),
+        Err(e) => format!("{}", e),
+    }
+}
+
+impl StatefulMutator for ReadEvalPrint {
     type Input = String;
     type Output = ();
 
-    fn run(&self, mem: &MutatorView, line: String) -> Result<(), RuntimeError> {
+    fn run(&mut self, mem: &MutatorView, line: String) -> Result<(), RuntimeError> {
         let thread = self.main_thread.get(mem);
 
+        // A line of just ":d" toggles debug output for all following lines
+        if line.trim() == ":d" {
+            self.debug = !self.debug;
+            thread.set_keep_failed_frames(self.debug);
+            println!("debug output {}", if self.debug { "on" } else { "off" });
+            return Ok(());
+        }
+
+        // ":trace on" and ":trace off" turn instruction tracing on and off
+        match line.trim() {
+            ":trace on" | ":trace off" => {
+                let trace = line.trim() == ":trace on";
+                set_trace(&thread, trace);
+                println!("trace output {}", if trace { "on" } else { "off" });
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        // ":doc name" prints the signature and documentation of a global function
+        if line.trim().starts_with(":doc ") {
+            println!("{}", describe_global(mem, &thread, line.trim()[5..].trim()));
+            return Ok(());
+        }
+
+        // ":dis name" prints the bytecode of a global function
+        if line.trim().starts_with(":dis ") {
+            println!(
+                "{}",
+                disassemble_global(mem, &thread, line.trim()[5..].trim())
+            );
+            return Ok(());
+        }
+
+        // ":regs" prints the registers of the innermost frame of a failed evaluation
+        if line.trim() == ":regs" {
+            println!("{}", register_listing(mem, &thread));
+            return Ok(());
+        }
+
+        // ":why" explains the last failed evaluation, even after later lines have been evaluated
+        if line.trim() == ":why" {
+            println!("{}", explain_last_error(mem, &thread));
+            return Ok(());
+        }
+
+        // ":save path" writes the globals to an image file that ":load-image path" restores
+        if line.trim().starts_with(":save ") {
+            println!("{}", save_globals(mem, &thread, line.trim()[6..].trim()));
+            return Ok(());
+        }
+
+        if line.trim().starts_with(":load-image ") {
+            println!("{}", load_globals(mem, &thread, line.trim()[12..].trim()));
+            return Ok(());
+        }
+
+        // ":set prompt text" and ":set continuation-prompt text" change the prompts. The text
+        // may be written in double quotes to keep leading or trailing spaces. ":set arity-checks
+        // off|warn|error" sets how calls to known global functions are checked, ":set opt 0|1"
+        // the optimization level and ":set output-buffer bytes" how much standard output is
+        // buffered.
+        if line.trim().starts_with(":set ") {
+            let setting = line.trim()[5..].trim_start();
+            let (name, value) = match setting.find(' ') {
+                Some(space) => (&setting[..space], prompt_text(&setting[space + 1..])),
+                None => (setting, String::new()),
+            };
+
+            match name {
+                "prompt" => self.prompt = value,
+                "continuation-prompt" => self.continuation_prompt = value,
+                "arity-checks" => match arity_checks(&value) {
+                    Some(checks) => self.options.arity_checks = checks,
+                    None => println!("arity-checks must be off, warn or error"),
+                },
+                // lines compiled at the old level are compiled again
+                "opt" => match OptLevel::from_name(&value) {
+                    Some(opt_level) => {
+                        self.options.opt_level = opt_level;
+                        self.cache = CompileCache::new();
+                    }
+                    None => println!("opt must be 0 or 1"),
+                },
+                "output-buffer" => match value.parse::<usize>() {
+                    Ok(size) => set_stdout_buffer_size(size)?,
+                    Err(_) => println!("output-buffer must be a number of bytes"),
+                },
+                _ => println!("unknown setting {}", name),
+            }
+            return Ok(());
+        }
+
         // If the first 2 chars of the line are ":d", then the user has requested a debug
         // representation
         let (line, debug) = if line.starts_with(":d ") {
             (&line[3..], true)
         } else {
-            (line.as_str(), false)
+            (line.as_str(), self.debug)
         };
 
-        match (|mem, line| -> Result<TaggedScopedPtr, RuntimeError> {
-            let value = parse(mem, line)?;
+        if line.trim().is_empty() {
+            return Ok(());
+        }
 
-            if debug {
-                println!(
-                    "# Debug\n## Input:\n```\n{}\n```\n## Parsed:\n```\n{:?}\n```",
-                    line, value
-                );
+        // every line evaluated is numbered, whether or not it succeeds
+        let number = self.input_number;
+        self.input_number += 1;
+
+        // output the line printed comes before its result or error, and before the next prompt
+        let result = self.eval_line(mem, line, debug);
+        flush_stdout()?;
+
+        match result {
+            // the alternate format prints dict keys in sorted order
+            Ok(value) => println!("Out[{}]: {:#}", number, value),
+
+            Err(e) => {
+                match e.error_kind() {
+                    // non-fatal repl errors
+                    ErrorKind::LexerError(_) => e.print_with_source(line),
+                    ErrorKind::ParseError(_) => e.print_with_source(line),
+                    ErrorKind::EvalError(_) => e.print_with_source(line),
+                    ErrorKind::Interrupted => e.print_with_source(line),
+                    _ => return Err(e),
+                }
+
+                if let Some(report) = line_progress_report(number, self.progress) {
+                    println!("{}", report);
+                }
             }
+        }
 
-            let function = compile(mem, value)?;
+        Ok(())
+    }
+}
 
-            if debug {
-                println!("## Compiled:\n```\n{:?}\n```", function);
+/// Mutator that reads a whole source stream as a program, compiles its top level expressions into
+/// a single Function and evaluates it, stopping at the first error. Nothing is evaluated if any
+/// part of the source fails to parse or compile.
+///
+/// In batch mode the stream is treated as non-interactive REPL input instead: it is read
+/// incrementally, each expression is compiled and evaluated in turn, its result is printed and
+/// evaluation errors are reported without stopping.
+pub struct ReadEvalStream {
+    /// Command line arguments to make available to the program through `(argv)`
+    args: Vec<String>,
+    /// Trace each executed instruction to stderr
+    trace: bool,
+    /// Print each result and continue after evaluation errors
+    batch: bool,
+    /// Name of the file the source is read from, recorded on the functions it defines
+    file_name: Option<String>,
+    /// Code generation settings
+    options: CompileOptions,
+    /// How warnings, and in batch mode evaluation errors, are written to stderr
+    diagnostics: DiagnosticFormat,
+}
+
+impl ReadEvalStream {
+    pub fn new(args: Vec<String>) -> ReadEvalStream {
+        ReadEvalStream {
+            args,
+            trace: false,
+            batch: false,
+            file_name: None,
+            options: CompileOptions::default(),
+            diagnostics: DiagnosticFormat::Human,
+        }
+    }
+
+    /// Check the types of annotated function parameters, which is the default
+    pub fn type_checks(mut self, type_checks: bool) -> ReadEvalStream {
+        self.options.type_checks = type_checks;
+        self
+    }
+
+    /// Check the argument count of calls to global functions that are bound when the code is
+    /// compiled: builtins and, in batch mode, functions defined by earlier expressions
+    pub fn arity_checks(mut self, arity_checks: ArityChecks) -> ReadEvalStream {
+        self.options.arity_checks = arity_checks;
+        self
+    }
+
+    /// Optimize the generated code to the given level, 1 by default
+    pub fn opt_level(mut self, opt_level: OptLevel) -> ReadEvalStream {
+        self.options.opt_level = opt_level;
+        self
+    }
+
+    /// Trace each executed instruction to stderr
+    pub fn trace(mut self, trace: bool) -> ReadEvalStream {
+        self.trace = trace;
+        self
+    }
+
+    /// Print the result of each expression and continue after evaluation errors
+    pub fn batch(mut self, batch: bool) -> ReadEvalStream {
+        self.batch = batch;
+        self
+    }
+
+    /// Name the file the source is read from
+    pub fn file_name(mut self, file_name: &str) -> ReadEvalStream {
+        self.file_name = Some(String::from(file_name));
+        self
+    }
+
+    /// Write warnings and evaluation errors as text, the default, or as JSON lines
+    pub fn diagnostics(mut self, diagnostics: DiagnosticFormat) -> ReadEvalStream {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    /// Write a warning, or an error that evaluation continues after, to stderr
+    fn report(&self, diagnostic: &Diagnostic) {
+        flush_stdout().ok();
+        match self.diagnostics {
+            DiagnosticFormat::Human => eprintln!("{}", diagnostic),
+            DiagnosticFormat::Json => {
+                eprintln!("{}", diagnostic.to_json(self.file_name.as_deref()))
             }
+        }
+    }
+}
 
-            let value = thread.quick_vm_eval(mem, function)?;
+impl Mutator for ReadEvalStream {
+    type Input = Box<dyn Read>;
+    type Output = ();
 
-            if debug {
-                println!("## Evaluated:\n```\n{:?}\n```\n", value);
+    fn run(&self, mem: &MutatorView, source: Box<dyn Read>) -> Result<(), RuntimeError> {
+        let thread = Thread::alloc(mem)?;
+        thread.set_argv(mem, &self.args)?;
+        set_trace(&thread, self.trace);
+
+        let mut parser = Parser::new(lex_reader(source));
+
+        if !self.batch {
+            let mut forms = Vec::new();
+            while let Some(expr) = parser.next_expr(mem)? {
+                forms.push(expr);
             }
 
-            Ok(value)
-        })(mem, &line)
-        {
-            Ok(value) => println!("{}", value),
+            let context = CompileContext::for_thread(mem, self.options, &thread);
+            let (function, warnings) =
+                compile_toplevel_in_context(mem, &forms, self.file_name.as_deref(), context)?;
+            for warning in &warnings {
+                self.report(warning);
+            }
 
-            Err(e) => {
-                match e.error_kind() {
-                    // non-fatal repl errors
-                    ErrorKind::LexerError(_) => e.print_with_source(&line),
-                    ErrorKind::ParseError(_) => e.print_with_source(&line),
-                    ErrorKind::EvalError(_) => e.print_with_source(&line),
-                    _ => return Err(e),
+            let result = thread.quick_vm_eval(mem, function);
+            flush_stdout()?;
+            return result.map(|_| ());
+        }
+
+        while let Some(expr) = parser.next_expr(mem)? {
+            let context = CompileContext::for_thread(mem, self.options, &thread);
+            let compiled =
+                compile_toplevel_in_context(mem, &[expr], self.file_name.as_deref(), context);
+
+            let result = compiled.and_then(|(function, warnings)| {
+                for warning in &warnings {
+                    self.report(warning);
                 }
+
+                thread.quick_vm_eval(mem, function)
+            });
+            flush_stdout()?;
+
+            match result {
+                Ok(value) => println!("{:#}", value),
+
+                // a lexer or parser error leaves the stream in an unknown state, so only
+                // evaluation errors are survivable
+                Err(e) => match e.error_kind() {
+                    ErrorKind::EvalError(_) | ErrorKind::Interrupted => match self.diagnostics {
+                        DiagnosticFormat::Human => eprintln!("{}", e),
+                        DiagnosticFormat::Json => self.report(&Diagnostic::from_error(&e)),
+                    },
+                    _ => return Err(e),
+                },
             }
         }
 
         Ok(())
     }
 }
+
+/// A mutator that reads a stream of source code, then parses and compiles every form in it without
+/// evaluating any, returning the warnings and errors found. Globals the runtime binds, such as the
+/// builtin functions, count as defined.
+///
+/// A lexer or parser error leaves the stream in an unknown state, so it is reported as an error
+/// and the forms before it are checked.
+pub struct CheckStream {
+    /// Name of the file the source is read from
+    file_name: Option<String>,
+}
+
+impl CheckStream {
+    pub fn new() -> CheckStream {
+        CheckStream { file_name: None }
+    }
+
+    /// Name the file the source is read from
+    pub fn file_name(mut self, file_name: &str) -> CheckStream {
+        self.file_name = Some(String::from(file_name));
+        self
+    }
+}
+
+impl Default for CheckStream {
+    fn default() -> CheckStream {
+        CheckStream::new()
+    }
+}
+
+impl Mutator for CheckStream {
+    type Input = Box<dyn Read>;
+    type Output = Vec<Diagnostic>;
+
+    fn run(
+        &self,
+        mem: &MutatorView,
+        source: Box<dyn Read>,
+    ) -> Result<Vec<Diagnostic>, RuntimeError> {
+        let thread = Thread::alloc(mem)?;
+        let mut parser = Parser::new(lex_reader(source));
+
+        let mut forms = Vec::new();
+        let mut parse_error = None;
+        loop {
+            match parser.next_expr(mem) {
+                Ok(Some(expr)) => forms.push(expr),
+                Ok(None) => break,
+                Err(e) => match e.error_kind() {
+                    ErrorKind::LexerError(reason) | ErrorKind::ParseError(reason) => {
+                        parse_error = Some(Diagnostic::error(
+                            e.error_kind().code(),
+                            reason,
+                            e.error_pos(),
+                        ));
+                        break;
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+
+        let mut diagnostics = check_toplevel(mem, &forms, self.file_name.as_deref(), |name| {
+            thread.lookup_global(mem, name).is_some()
+        })?;
+        diagnostics.extend(parse_error);
+
+        Ok(diagnostics)
+    }
+}
+
+/// A mutator that reads a stream of source code and returns it in canonical formatting, as printed
+/// by `pretty_forms()`. The language has no comments, so nothing but layout is lost.
+pub struct FormatStream {
+    /// The line width to fit the source into
+    width: usize,
+}
+
+impl FormatStream {
+    pub fn new() -> FormatStream {
+        FormatStream {
+            width: PRETTY_WIDTH,
+        }
+    }
+
+    /// Set the line width to fit the source into
+    pub fn width(mut self, width: usize) -> FormatStream {
+        self.width = width;
+        self
+    }
+}
+
+impl Default for FormatStream {
+    fn default() -> FormatStream {
+        FormatStream::new()
+    }
+}
+
+impl Mutator for FormatStream {
+    type Input = Box<dyn Read>;
+    type Output = String;
+
+    fn run(&self, mem: &MutatorView, source: Box<dyn Read>) -> Result<String, RuntimeError> {
+        let mut parser = Parser::new(lex_reader(source));
+
+        let mut forms = Vec::new();
+        while let Some(expr) = parser.next_expr(mem)? {
+            forms.push(*expr);
+        }
+
+        Ok(pretty_forms(&forms, self.width))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::Memory;
+    use crate::native_module;
+    use crate::parser::parse;
+    use crate::safeptr::TaggedCellPtr;
+    use std::io;
+    use std::sync::atomic::Ordering;
+
+    fn boom<'guard>(
+        _mem: &'guard MutatorView,
+        _thread: &Thread,
+        _args: &[TaggedCellPtr],
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        panic!("boom")
+    }
+
+    fn interrupt<'guard>(
+        mem: &'guard MutatorView,
+        thread: &Thread,
+        _args: &[TaggedCellPtr],
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        thread.interrupt_flag().store(true, Ordering::SeqCst);
+        Ok(mem.nil())
+    }
+
+    native_module! {
+        PANIC_MODULE = "panic" {
+            "boom" => boom(0),
+            "interrupt" => interrupt(0),
+        }
+    }
+
+    #[test]
+    fn repl_survives_panicking_native() {
+        let mem = Memory::new();
+
+        struct Setup {}
+        impl Mutator for Setup {
+            type Input = ();
+            type Output = ReadEvalPrint;
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<ReadEvalPrint, RuntimeError> {
+                let repl = ReadEvalPrint::alloc(mem)?;
+                repl.main_thread
+                    .get(mem)
+                    .bind_native_module(mem, &PANIC_MODULE)?;
+                Ok(repl)
+            }
+        }
+
+        struct Check {}
+        impl Mutator for Check {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let thread = Thread::alloc(mem)?;
+                thread.bind_native_module(mem, &PANIC_MODULE)?;
+
+                let eval = |code| -> Result<TaggedScopedPtr, RuntimeError> {
+                    thread.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)
+                };
+
+                eval("(def f (x) (boom))")?;
+
+                match eval("(f 1)") {
+                    Err(e) => match e.error_kind() {
+                        ErrorKind::EvalError(reason) => assert!(reason.contains("boom")),
+                        _ => panic!("expected an EvalError"),
+                    },
+                    Ok(_) => panic!("expected an error"),
+                }
+
+                // the thread is usable afterwards
+                assert!(eval("(f 1)").is_err());
+                eval("(def g (x) (bit-or x 2))")?;
+                assert!(eval("(g 1)")? == mem.number(3));
+
+                Ok(())
+            }
+        }
+
+        let mut repl = mem.mutate(&Setup {}, ()).unwrap();
+
+        mem.mutate_with_state(&mut repl, String::from("(boom)"))
+            .unwrap();
+        mem.mutate_with_state(&mut repl, String::from("(def f () (boom))"))
+            .unwrap();
+        mem.mutate_with_state(&mut repl, String::from("(f)"))
+            .unwrap();
+        mem.mutate_with_state(&mut repl, String::from("(bit-or 1 2)"))
+            .unwrap();
+
+        mem.mutate(&Check {}, ()).unwrap();
+    }
+
+    #[test]
+    fn interrupt_stops_evaluation() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let thread = Thread::alloc(mem)?;
+                thread.bind_native_module(mem, &PANIC_MODULE)?;
+
+                let eval = |code| -> Result<TaggedScopedPtr, RuntimeError> {
+                    thread.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)
+                };
+
+                // a function that never returns
+                eval("(def spin () (spin))")?;
+
+                match eval("(cons (interrupt) (spin))") {
+                    Err(e) => assert!(*e.error_kind() == ErrorKind::Interrupted),
+                    Ok(_) => panic!("expected an interruption"),
+                }
+
+                // the interrupt request was consumed
+                assert!(!thread.interrupt_flag().load(Ordering::SeqCst));
+                assert!(eval("(bit-or 1 2)")? == mem.number(3));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn repl_lists_registers_of_failed_evaluation() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let thread = Thread::alloc(mem)?;
+
+                let eval = |code| -> Result<TaggedScopedPtr, RuntimeError> {
+                    thread.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)
+                };
+
+                eval("(def f (x y) (let ((z (cons x y))) (car (car z))))")?;
+                assert!(eval("(f 'a 'b)").is_err());
+                assert!(register_listing(mem, &thread).starts_with("no frames to inspect"));
+
+                // with failed frames kept, the innermost frame is f's
+                thread.set_keep_failed_frames(true);
+                assert!(eval("(f 'a 'b)").is_err());
+                assert!(thread.has_failed() && !thread.is_evaluating(mem));
+
+                let listing = register_listing(mem, &thread);
+                let lines: Vec<&str> = listing.lines().collect();
+                assert!(lines[0].starts_with("registers of (Function f (x y)) at instruction "));
+                assert!(lines[1] == "  r2   parameter x              a");
+                assert!(lines[2] == "  r3   parameter y              b");
+                assert!(lines.contains(&"  r5   temporary                (a . b)"));
+
+                // the frames are discarded when the next evaluation starts
+                assert!(eval("'ok")? == mem.lookup_sym("ok"));
+                assert!(!thread.has_failed());
+                assert!(register_listing(mem, &thread).starts_with("no frames to inspect"));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn repl_explains_last_failed_evaluation() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let thread = Thread::alloc(mem)?;
+
+                let eval = |code| -> Result<TaggedScopedPtr, RuntimeError> {
+                    thread.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)
+                };
+
+                eval("(def f (x) (car x))")?;
+                assert!(eval("(f 'a)").is_err());
+                assert!(explain_last_error(mem, &thread).starts_with("no failed evaluation"));
+
+                thread.set_keep_failed_frames(true);
+                assert!(eval("(cons 'b (f 'a))").is_err());
+
+                // the snapshot outlives the frames, which the next evaluation discards
+                assert!(eval("'ok")? == mem.lookup_sym("ok"));
+                assert!(!thread.has_failed());
+
+                let explanation = explain_last_error(mem, &thread);
+                let lines: Vec<&str> = explanation.lines().collect();
+                assert!(lines[0].contains("a (symbol)"));
+                assert!(lines[1].starts_with("  in (Function f (x)) at instruction 0"));
+                assert!(lines[1].ends_with(": (first-of-pair 3 2)"));
+                assert!(lines[2] == "    r2   parameter x              a");
+                assert!(lines
+                    .iter()
+                    .any(|line| line.starts_with("  in (Function ") && line.contains(": (call ")));
+                assert!(lines.contains(&"    r3   temporary                b"));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn repl_describes_global_functions() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let thread = Thread::alloc(mem)?;
+
+                let eval = |code| -> Result<TaggedScopedPtr, RuntimeError> {
+                    thread.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)
+                };
+
+                eval("(def second (l) \"Return the second element of a list\" (car (cdr l)))")?;
+                eval("(def first (l) (car l))")?;
+
+                assert!(
+                    describe_global(mem, &thread, "second")
+                        == "(Function second (l))\n    Return the second element of a list\n    \
+                            Defined at line 1, column 1"
+                );
+                assert!(
+                    describe_global(mem, &thread, "first")
+                        == "(Function first (l))\n    No documentation\n    \
+                            Defined at line 1, column 1"
+                );
+
+                let listing = disassemble_global(mem, &thread, "first");
+                assert!(listing.starts_with(
+                    "Defined at line 1, column 1\n(Function first (l))\nbytecode follows:"
+                ));
+                assert!(disassemble_global(mem, &thread, "stdout") == "stdout is not a function");
+                assert!(
+                    describe_global(mem, &thread, "print")
+                        == "(NativeFunction print)\n    Builtin function"
+                );
+                assert!(describe_global(mem, &thread, "stdout") == "stdout is not a function");
+                assert!(describe_global(mem, &thread, "x") == "x is not bound to a value");
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn repl_saves_and_loads_images() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let thread = Thread::alloc(mem)?;
+
+                let eval = |code| -> Result<TaggedScopedPtr, RuntimeError> {
+                    thread.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)
+                };
+
+                let mut path = std::env::temp_dir();
+                path.push(format!("evalrus-repl-image-{}", std::process::id()));
+                let path = path.to_str().unwrap();
+
+                eval("(def twice (x) (cons x x))")?;
+                eval("(set 'add (lambda (x) (lambda (y) (cons x y))))")?;
+                eval("(set 'partial-add (add 1))")?;
+
+                assert!(
+                    save_globals(mem, &thread, path)
+                        == format!(
+                            "saved 2 globals to {}\n    skipped partial-add: (Partial (y)) \
+                             closes over local variables and cannot be saved",
+                            path
+                        )
+                );
+
+                let restored = Thread::alloc(mem)?;
+                assert!(
+                    load_globals(mem, &restored, path) == format!("loaded 2 globals from {}", path)
+                );
+                assert!(restored.lookup_global(mem, "twice").is_some());
+                assert!(restored.lookup_global(mem, "partial-add").is_none());
+
+                std::fs::remove_file(path)?;
+                assert!(load_globals(mem, &restored, path).starts_with("IO Error: "));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn repl_reuses_compiled_lines() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let mut repl = ReadEvalPrint::alloc(mem)?;
+
+                repl.eval_line(mem, "(def f () 'a)", false)?;
+                assert!(repl.eval_line(mem, "(f)", false)? == mem.lookup_sym("a"));
+                assert!(repl.cache.entries.len() == 2);
+
+                // the cached code of "(f)" calls the redefined f
+                repl.eval_line(mem, "(def f () 'b)", false)?;
+                assert!(repl.eval_line(mem, "(f)", false)? == mem.lookup_sym("b"));
+                assert!(repl.cache.entries.len() == 3);
+
+                let (first, _) = repl.cache.get(mem, "(f)").unwrap();
+                repl.eval_line(mem, "(f)", false)?;
+                let (second, _) = repl.cache.get(mem, "(f)").unwrap();
+                assert!(first[0].as_tagged(mem) == second[0].as_tagged(mem));
+
+                // lines that fail to compile are not cached
+                assert!(repl.eval_line(mem, "(f", false).is_err());
+                assert!(repl.eval_line(mem, "(let)", false).is_err());
+                assert!(repl.cache.entries.len() == 3);
+
+                for i in 0..COMPILE_CACHE_SIZE {
+                    repl.eval_line(mem, &format!("(cons {} nil)", i), false)?;
+                }
+                assert!(repl.cache.entries.len() == COMPILE_CACHE_SIZE);
+                assert!(repl.cache.get(mem, "(def f () 'a)").is_none());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn repl_numbers_lines_and_evaluates_them_whole() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let mut repl = ReadEvalPrint::alloc(mem)?;
+                let thread = repl.main_thread.get(mem);
+                let bound = |name| thread.lookup_global(mem, name).is_some();
+
+                // each line evaluated takes a number, whether it succeeds or not, commands and
+                // blank lines do not
+                StatefulMutator::run(&mut repl, mem, String::from("'a"))?;
+                StatefulMutator::run(&mut repl, mem, String::from("(car 'a)"))?;
+                StatefulMutator::run(&mut repl, mem, String::from(":d"))?;
+                StatefulMutator::run(&mut repl, mem, String::from("  "))?;
+                assert!(repl.prompt() == "In[3]: ");
+                StatefulMutator::run(&mut repl, mem, String::from(":set prompt \"[{n}] \""))?;
+                assert!(repl.prompt() == "[3] ");
+
+                // every form is evaluated and the last result returned
+                let value = repl.eval_line(mem, "(set 'a 'one) (set 'b 'two)", false)?;
+                assert!(value == mem.lookup_sym("two"));
+                assert!(bound("a") && bound("b"));
+
+                // nothing is evaluated if a later form fails to parse or compile
+                assert!(repl.eval_line(mem, "(set 'c 'three) (f", false).is_err());
+                assert!(repl.eval_line(mem, "(set 'c 'three) (let)", false).is_err());
+                assert!(!bound("c"));
+                assert!(
+                    line_progress_report(7, repl.progress).unwrap()
+                        == "In[7]: none of the 2 forms were evaluated"
+                );
+
+                // evaluation stops at the first form that fails
+                let line = "(set 'c 'three) (car 'x) (set 'd 'four) (set 'e 'five)";
+                assert!(repl.eval_line(mem, line, false).is_err());
+                assert!(bound("c") && !bound("d"));
+                assert!(
+                    line_progress_report(8, repl.progress).unwrap()
+                        == "In[8]: form 2 of 4 failed, form 1 took effect, \
+                            forms 3 to 4 were not evaluated"
+                );
+
+                assert!(repl.eval_line(mem, "(car 'x) 'y", false).is_err());
+                assert!(
+                    line_progress_report(9, repl.progress).unwrap()
+                        == "In[9]: form 1 of 2 failed, none before it took effect, \
+                            form 2 was not evaluated"
+                );
+
+                // a line of one form needs no report
+                assert!(repl.eval_line(mem, "(car 'x)", false).is_err());
+                assert!(line_progress_report(10, repl.progress).is_none());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn repl_binds_previous_results() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let mut repl = ReadEvalPrint::alloc(mem)?;
+                let mut eval = |line| -> Result<String, RuntimeError> {
+                    Ok(format!("{}", repl.eval_line(mem, line, false)?))
+                };
+
+                assert!(eval("_").is_err());
+
+                eval("'(a b c)")?;
+                assert!(eval("(car _)")? == "a");
+                assert!(eval("$1")? == "a");
+                assert!(eval("(cdr $3)")? == "(b c)");
+                assert!(eval("_")? == "(b c)");
+
+                // a failed evaluation leaves the results as they were
+                assert!(eval("(car 'x)").is_err());
+                assert!(eval("$1")? == "(b c)");
+
+                for _ in 0..RESULT_HISTORY {
+                    eval("'x")?;
+                }
+                assert!(eval("$9")? == "x");
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn repl_sets_prompts() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let mut repl = ReadEvalPrint::alloc(mem)?;
+                assert!(repl.prompt() == "In[1]: ");
+                assert!(repl.continuation_prompt() == DEFAULT_CONTINUATION_PROMPT);
+
+                StatefulMutator::run(&mut repl, mem, String::from(":set prompt λ>"))?;
+                assert!(repl.prompt() == "λ>");
+
+                // quotes keep the trailing space
+                StatefulMutator::run(
+                    &mut repl,
+                    mem,
+                    String::from(":set continuation-prompt \"... \""),
+                )?;
+                assert!(repl.continuation_prompt() == "... ");
+
+                // lines are compiled unoptimized until the level is raised, which empties the
+                // cache of compiled lines
+                assert!(repl.options.opt_level == OptLevel::O0);
+                repl.eval_line(mem, "(min 2 1)", false)?;
+                assert!(repl.cache.get(mem, "(min 2 1)").is_some());
+                StatefulMutator::run(&mut repl, mem, String::from(":set opt 1"))?;
+                assert!(repl.options.opt_level == OptLevel::O1);
+                assert!(repl.cache.get(mem, "(min 2 1)").is_none());
+                StatefulMutator::run(&mut repl, mem, String::from(":set opt 2"))?;
+                assert!(repl.options.opt_level == OptLevel::O1);
+
+                // arity errors are checked against the functions defined so far
+                repl.eval_line(mem, "(def f (a) a)", false)?;
+                assert!(repl.eval_line(mem, "(def g () (f 'a 'b))", false).is_ok());
+                StatefulMutator::run(&mut repl, mem, String::from(":set arity-checks error"))?;
+                assert!(repl.eval_line(mem, "(def g () (f 'a 'b))", false).is_err());
+
+                assert!(
+                    banner().starts_with(&format!("Eval-R-Us {} (git ", env!("CARGO_PKG_VERSION")))
+                );
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn check_stream_reports_every_error() {
+        let mem = Memory::new();
+
+        let source = "(def f (x) (g x))\n\
+                      (set 'counter 0)\n\
+                      (print counter)\n\
+                      (print (h undefined))\n\
+                      (def broken)\n\
+                      (def g (y) (let ((unused y)) (car y)))\n\
+                      (oops";
+
+        let check = CheckStream::new().file_name("check.evalrus");
+        let diagnostics = mem
+            .mutate(&check, Box::new(io::Cursor::new(source)))
+            .unwrap();
+
+        // globals defined later in the source and builtins are not errors, and parsing stops at the
+        // unclosed expression
+        let found: Vec<String> = diagnostics.iter().map(|d| format!("{}", d)).collect();
+        assert!(
+            found
+                == vec![
+                    "error: Global undefined is not defined (line 4, column 8)",
+                    "error: Global h is not defined (line 4, column 8)",
+                    "error: A function definition must have at least (def name (params) expr) \
+                     (line 5, column 1)",
+                    "warning: Variable unused is never used (line 6, column 12)",
+                    "error: Unexpected end of code stream",
+                ]
+        );
+        assert!(diagnostics.iter().filter(|d| d.is_error()).count() == 4);
+    }
+
+    #[test]
+    fn format_stream_is_canonical() {
+        let mem = Memory::new();
+        let format = |source: &str| {
+            let source = io::Cursor::new(String::from(source));
+            mem.mutate(&FormatStream::new().width(40), Box::new(source))
+                .unwrap()
+        };
+
+        let source = "(def classify (x) (cond (nil? x) 'empty (atom? x) 'atom true 'pair))  \
+                      (classify   '(a . b))\n\
+                      (def f () (cons 'a 'b))";
+        let expected = "(def classify (x)\n  \
+                          (cond (nil? x) 'empty\n        \
+                                (atom? x) 'atom\n        \
+                                true 'pair))\n\
+                        \n\
+                        (classify '(a . b))\n\
+                        (def f () (cons 'a 'b))\n";
+
+        let formatted = format(source);
+        assert!(formatted == expected);
+
+        // formatting is idempotent
+        assert!(format(&formatted) == expected);
+    }
+}
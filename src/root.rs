@@ -0,0 +1,206 @@
+/// Rooted handles that keep heap objects alive outside of a mutator scope.
+///
+/// A `Root` is registered in its `Memory` instance's root table, which a collector must treat as
+/// part of the root set. Unlike `CellPtr` and `TaggedCellPtr`, a `Root` is not itself a heap
+/// object, so an embedder can hold one anywhere for as long as it likes - across `mutate()`
+/// calls, for example to keep a compiled function or a result between REPL lines. The object is
+/// released when the last `Root` referring to it is dropped.
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::safeptr::{MutatorScope, TaggedScopedPtr};
+use crate::taggedptr::TaggedPtr;
+
+/// The table of rooted pointers belonging to a `Memory` instance
+pub(crate) struct RootTable {
+    /// Rooted pointers. Unused slots are nil.
+    slots: RefCell<Vec<TaggedPtr>>,
+    /// Indexes of unused slots
+    free: RefCell<Vec<usize>>,
+}
+
+impl RootTable {
+    pub(crate) fn new() -> RootTable {
+        RootTable {
+            slots: RefCell::new(Vec::new()),
+            free: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Store a pointer in an unused slot, returning the slot index
+    fn insert(&self, ptr: TaggedPtr) -> usize {
+        let mut slots = self.slots.borrow_mut();
+
+        match self.free.borrow_mut().pop() {
+            Some(slot) => {
+                slots[slot] = ptr;
+                slot
+            }
+            None => {
+                slots.push(ptr);
+                slots.len() - 1
+            }
+        }
+    }
+
+    fn get(&self, slot: usize) -> TaggedPtr {
+        self.slots.borrow()[slot]
+    }
+
+    fn set(&self, slot: usize, ptr: TaggedPtr) {
+        self.slots.borrow_mut()[slot] = ptr;
+    }
+
+    /// Clear a slot, making it available for reuse
+    fn remove(&self, slot: usize) {
+        self.slots.borrow_mut()[slot] = TaggedPtr::nil();
+        self.free.borrow_mut().push(slot);
+    }
+
+    /// Return the count of live roots
+    pub(crate) fn len(&self) -> usize {
+        self.slots.borrow().len() - self.free.borrow().len()
+    }
+
+    /// Call the given function with every rooted pointer
+    pub(crate) fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(TaggedPtr),
+    {
+        let free = self.free.borrow();
+
+        for (slot, ptr) in self.slots.borrow().iter().enumerate() {
+            if !free.contains(&slot) {
+                f(*ptr)
+            }
+        }
+    }
+}
+
+/// A handle to a heap object that keeps the object alive for as long as the handle exists
+pub struct Root {
+    slot: usize,
+    table: Rc<RootTable>,
+}
+
+impl Root {
+    pub(crate) fn new(table: &Rc<RootTable>, ptr: TaggedPtr) -> Root {
+        Root {
+            slot: table.insert(ptr),
+            table: table.clone(),
+        }
+    }
+
+    /// Get a scope-limited pointer to the rooted object
+    pub fn get<'guard>(&self, guard: &'guard dyn MutatorScope) -> TaggedScopedPtr<'guard> {
+        TaggedScopedPtr::new(guard, self.table.get(self.slot))
+    }
+
+    /// Root a different object with this handle, releasing the previous one
+    pub fn set(&self, value: TaggedScopedPtr) {
+        self.table.set(self.slot, value.get_ptr());
+    }
+}
+
+/// Cloning a Root registers a new, independent, root for the same object
+impl Clone for Root {
+    fn clone(&self) -> Root {
+        Root::new(&self.table, self.table.get(self.slot))
+    }
+}
+
+impl Drop for Root {
+    fn drop(&mut self) {
+        self.table.remove(self.slot);
+    }
+}
+
+impl fmt::Debug for Root {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Root({})", self.slot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::RuntimeError;
+    use crate::memory::{Memory, Mutator, MutatorView};
+    use crate::root::Root;
+
+    #[test]
+    fn root_survives_mutator_scopes() {
+        let mem = Memory::new();
+
+        struct Make {}
+        impl Mutator for Make {
+            type Input = ();
+            type Output = Root;
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<Root, RuntimeError> {
+                Ok(mem.root(mem.text("kept")?))
+            }
+        }
+
+        struct Check {}
+        impl Mutator for Check {
+            type Input = Root;
+            type Output = Root;
+
+            fn run(&self, mem: &MutatorView, root: Root) -> Result<Root, RuntimeError> {
+                assert!(format!("{}", root.get(mem)) == "\"kept\"");
+
+                root.set(mem.lookup_sym("replaced"));
+                assert!(root.get(mem) == mem.lookup_sym("replaced"));
+
+                Ok(root)
+            }
+        }
+
+        let root = mem.mutate(&Make {}, ()).unwrap();
+        assert!(mem.root_count() == 1);
+
+        let root = mem.mutate(&Check {}, root).unwrap();
+        assert!(mem.root_count() == 1);
+
+        drop(root);
+        assert!(mem.root_count() == 0);
+    }
+
+    #[test]
+    fn root_clone_and_slot_reuse() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = Vec<Root>;
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<Vec<Root>, RuntimeError> {
+                let a = mem.root(mem.number(1));
+                let b = a.clone();
+
+                // the clone is independent of the original
+                b.set(mem.number(2));
+                assert!(a.get(mem) == mem.number(1));
+
+                drop(a);
+                let c = mem.root(mem.number(3));
+                assert!(b.get(mem) == mem.number(2));
+                assert!(c.get(mem) == mem.number(3));
+
+                Ok(vec![b, c])
+            }
+        }
+
+        let roots = mem.mutate(&Test {}, ()).unwrap();
+        assert!(mem.root_count() == 2);
+
+        let mut rooted = 0;
+        mem.for_each_root(|_| rooted += 1);
+        assert!(rooted == 2);
+
+        drop(roots);
+        assert!(mem.root_count() == 0);
+    }
+}
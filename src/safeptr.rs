@@ -105,6 +105,7 @@ impl<T: Sized> CellPtr<T> {
         }
     }
 
+    /// Return the pointer as a `ScopedPtr` type
     pub fn get<'guard>(&self, guard: &'guard dyn MutatorScope) -> ScopedPtr<'guard, T> {
         ScopedPtr::new(guard, self.inner.get().scoped_ref(guard))
     }
@@ -114,6 +115,11 @@ impl<T: Sized> CellPtr<T> {
     pub fn set(&self, source: ScopedPtr<T>) {
         self.inner.set(RawPtr::new(source.value))
     }
+
+    /// Take the pointer of another `CellPtr` and set this instance to point at that object too
+    pub fn copy_from(&self, other: &CellPtr<T>) {
+        self.inner.set(other.inner.get());
+    }
 }
 
 impl<T: Sized> From<ScopedPtr<'_, T>> for CellPtr<T> {
@@ -249,3 +255,113 @@ impl From<TaggedScopedPtr<'_>> for TaggedCellPtr {
         TaggedCellPtr::new_with(ptr)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::RuntimeError;
+    use crate::memory::{Memory, Mutator, MutatorView};
+    use crate::pair::Pair;
+    use crate::text::Text;
+
+    fn test_helper(test_fn: fn(&MutatorView) -> Result<(), RuntimeError>) {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = fn(&MutatorView) -> Result<(), RuntimeError>;
+            type Output = ();
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                test_fn: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                test_fn(mem)
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, test_fn).unwrap();
+    }
+
+    #[test]
+    fn scoped_ptr_deref_and_as_tagged() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let text = mem.alloc(Text::new_from_str(mem, "hello")?)?;
+
+            // Deref gives access to the object
+            assert!(text.as_str(mem) == "hello");
+
+            // converting to a tagged pointer retains the identity and the runtime type
+            let tagged = text.as_tagged(mem);
+            match *tagged {
+                Value::Text(t) => assert!(t.as_str(mem) == "hello"),
+                _ => panic!("Expected a Text value"),
+            }
+
+            let copy = text;
+            assert!(copy.as_tagged(mem) == tagged);
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn cell_ptr_get_set_copy_from() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let first = mem.alloc(Text::new_from_str(mem, "first")?)?;
+            let second = mem.alloc(Text::new_from_str(mem, "second")?)?;
+
+            let cell = CellPtr::new_with(first);
+            assert!(cell.get(mem).as_str(mem) == "first");
+
+            cell.set(second);
+            assert!(cell.get(mem).as_str(mem) == "second");
+
+            let other = CellPtr::from(first);
+            other.copy_from(&cell);
+            assert!(other.get(mem).as_tagged(mem) == second.as_tagged(mem));
+
+            // the source is unaffected by later changes to the copy
+            other.set(first);
+            assert!(cell.get(mem).as_str(mem) == "second");
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn tagged_cell_ptr_get_set_copy_from() {
+        fn test_inner(mem: &MutatorView) -> Result<(), RuntimeError> {
+            let cell = TaggedCellPtr::new_nil();
+            assert!(cell.is_nil());
+            assert!(cell.get(mem) == mem.nil());
+
+            let sym = mem.lookup_sym("sym");
+            cell.set(sym);
+            assert!(!cell.is_nil());
+            assert!(cell.get(mem) == sym);
+
+            let pair = mem.alloc_tagged(Pair::new())?;
+            let other = TaggedCellPtr::new_with(pair);
+            other.copy_from(&cell);
+            assert!(other.get(mem) == sym);
+
+            other.set_to_ptr(TaggedPtr::number(42));
+            assert!(other.get(mem) == mem.number(42));
+            assert!(cell.get(mem) == sym);
+
+            other.set_to_nil();
+            assert!(other.is_nil());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+}
@@ -0,0 +1,207 @@
+//! Restrictions for evaluating untrusted code on a Thread.
+//!
+//! A `Sandbox` is attached to a Thread with `Thread::set_sandbox()` and is enforced by the VM:
+//! calls to native functions that perform I/O fail, global assignment can be forbidden, and the
+//! instructions executed and bytes allocated by each evaluation can be capped.
+
+/// A sandbox configuration. `Sandbox::new()` disables I/O and places no other restrictions.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sandbox {
+    /// Allow native functions that perform I/O to be called
+    io: bool,
+    /// Forbid binding or rebinding globals
+    read_only_globals: bool,
+    /// Maximum number of instructions each evaluation may execute
+    max_instructions: Option<u64>,
+    /// Maximum number of bytes each evaluation may allocate
+    max_heap_bytes: Option<usize>,
+}
+
+impl Sandbox {
+    pub fn new() -> Sandbox {
+        Sandbox {
+            io: false,
+            read_only_globals: false,
+            max_instructions: None,
+            max_heap_bytes: None,
+        }
+    }
+
+    /// Allow or disallow calls to native functions that perform I/O
+    pub fn allow_io(mut self, allowed: bool) -> Sandbox {
+        self.io = allowed;
+        self
+    }
+
+    /// Make globals read-only, so that `set` and `def` of a global are errors
+    pub fn read_only_globals(mut self, read_only: bool) -> Sandbox {
+        self.read_only_globals = read_only;
+        self
+    }
+
+    /// Limit the number of instructions each evaluation may execute
+    pub fn max_instructions(mut self, limit: u64) -> Sandbox {
+        self.max_instructions = Some(limit);
+        self
+    }
+
+    /// Limit the number of bytes each evaluation may allocate
+    pub fn max_heap_bytes(mut self, limit: usize) -> Sandbox {
+        self.max_heap_bytes = Some(limit);
+        self
+    }
+
+    pub fn io_allowed(&self) -> bool {
+        self.io
+    }
+
+    pub fn globals_read_only(&self) -> bool {
+        self.read_only_globals
+    }
+
+    pub fn instruction_limit(&self) -> Option<u64> {
+        self.max_instructions
+    }
+
+    pub fn heap_limit(&self) -> Option<usize> {
+        self.max_heap_bytes
+    }
+}
+
+impl Default for Sandbox {
+    fn default() -> Sandbox {
+        Sandbox::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sandbox;
+    use crate::compiler::compile;
+    use crate::error::{ErrorKind, RuntimeError};
+    use crate::memory::{Memory, Mutator, MutatorView};
+    use crate::parser::parse;
+    use crate::safeptr::TaggedScopedPtr;
+    use crate::vm::Thread;
+
+    fn test_helper(test_fn: fn(&MutatorView, &Thread) -> Result<(), RuntimeError>) {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = fn(&MutatorView, &Thread) -> Result<(), RuntimeError>;
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, test_fn: Self::Input) -> Result<(), RuntimeError> {
+                let thread = Thread::alloc(mem)?;
+                test_fn(mem, &thread)
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, test_fn).unwrap();
+    }
+
+    fn eval_helper<'guard>(
+        mem: &'guard MutatorView,
+        thread: &Thread,
+        code: &str,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let compiled_code = compile(mem, parse(mem, code)?)?;
+        thread.quick_vm_eval(mem, compiled_code)
+    }
+
+    fn error_reason(result: Result<TaggedScopedPtr, RuntimeError>) -> String {
+        match result {
+            Err(e) => match e.error_kind() {
+                ErrorKind::EvalError(reason) => reason.clone(),
+                _ => panic!("expected an evaluation error"),
+            },
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn sandbox_disables_io() {
+        fn test_inner(mem: &MutatorView, t: &Thread) -> Result<(), RuntimeError> {
+            t.set_sandbox(Some(Sandbox::new()));
+
+            let reason = error_reason(eval_helper(mem, t, "(print \"x\")"));
+            assert!(reason == "Function print is not available in the sandbox");
+            assert!(eval_helper(mem, t, "(getenv \"HOME\")").is_err());
+            assert!(eval_helper(mem, t, "(close stdout)").is_err());
+            assert!(eval_helper(mem, t, "(time 'a)").is_err());
+
+            // functions without side effects outside of the interpreter are still available
+            let text = eval_helper(mem, t, "(format \"{}\" 1)")?;
+            assert!(format!("{}", text) == "\"1\"");
+
+            t.set_sandbox(Some(Sandbox::new().allow_io(true)));
+            assert!(eval_helper(mem, t, "(argv)")? == mem.nil());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn sandbox_read_only_globals() {
+        fn test_inner(mem: &MutatorView, t: &Thread) -> Result<(), RuntimeError> {
+            eval_helper(mem, t, "(def f () 'a)")?;
+
+            t.set_sandbox(Some(Sandbox::new().read_only_globals(true)));
+
+            let reason = error_reason(eval_helper(mem, t, "(def f () 'b)"));
+            assert!(reason == "Globals are read-only in the sandbox");
+            assert!(eval_helper(mem, t, "(f)")? == mem.lookup_sym("a"));
+
+            t.set_sandbox(None);
+            eval_helper(mem, t, "(def f () 'b)")?;
+            assert!(eval_helper(mem, t, "(f)")? == mem.lookup_sym("b"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn sandbox_instruction_limit() {
+        fn test_inner(mem: &MutatorView, t: &Thread) -> Result<(), RuntimeError> {
+            eval_helper(mem, t, "(def spin () (spin))")?;
+
+            t.set_sandbox(Some(Sandbox::new().max_instructions(5000)));
+
+            let reason = error_reason(eval_helper(mem, t, "(spin)"));
+            assert!(reason == "Sandbox instruction limit of 5000 reached");
+
+            // the count starts again for each evaluation
+            assert!(eval_helper(mem, t, "(car '(a))")? == mem.lookup_sym("a"));
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+
+    #[test]
+    fn sandbox_heap_limit() {
+        fn test_inner(mem: &MutatorView, t: &Thread) -> Result<(), RuntimeError> {
+            eval_helper(mem, t, "(def grow (l) (grow (cons l l)))")?;
+
+            t.set_sandbox(Some(Sandbox::new().max_heap_bytes(64 * 1024)));
+
+            let reason = error_reason(eval_helper(mem, t, "(grow nil)"));
+            assert!(reason == "Sandbox heap limit of 65536 bytes exceeded");
+
+            // the limit only applies during evaluation
+            mem.text("allocation outside of evaluation")?;
+            assert!(eval_helper(mem, t, "(cons 'a 'b)").is_ok());
+
+            Ok(())
+        }
+
+        test_helper(test_inner);
+    }
+}
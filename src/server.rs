@@ -0,0 +1,360 @@
+//! A text protocol for driving the interpreter from another process, such as a notebook, an
+//! editor or a test harness, served by `evalrus --server` on stdin and stdout.
+//!
+//! Each request is the source of one or more expressions, preceded by its length in bytes, in
+//! decimal on a line of its own. Blank lines between requests are skipped. A request longer than
+//! `MAX_REQUEST_LENGTH`, or that is not UTF-8, is answered with an error without being evaluated.
+//!
+//! For example, this request prints hi and returns a:
+//!
+//! ```text
+//! 22
+//! (print 'hi) (car '(a))
+//! ```
+//!
+//! The expressions are compiled together and evaluated in turn on one Thread, which keeps its
+//! globals from one request to the next. Each response is framed the same way, followed by a
+//! newline, and is a single line JSON object. The response to the request above is:
+//!
+//! ```text
+//! {"value":"a","output":"hi","diagnostics":[],"traceback":[]}
+//! ```
+//!
+//! * `value` is the printed result of the last expression, or null if the request failed
+//! * `output` is everything the expressions printed to standard output
+//! * `diagnostics` are the compiler warnings and any error, as objects in the form written by
+//!   `--diagnostics json`
+//! * `traceback` lists the functions that were being called when evaluation failed, the innermost
+//!   last, if the error was multiple calls deep
+//!
+//! Standard input carries the requests, so the evaluated code must not read from it. `(exit)`
+//! is answered like any failed request before the server exits.
+use std::io::{self, BufRead, Read, Write};
+
+use crate::compiler::{compile_toplevel_in_context, CompileContext, CompileOptions};
+use crate::error::{Diagnostic, ErrorKind, RuntimeError};
+use crate::json::encode_str;
+use crate::lexer::lex_reader;
+use crate::memory::{Memory, Mutator, MutatorView, StatefulMutator};
+use crate::parser::Parser;
+use crate::port::Port;
+use crate::safeptr::CellPtr;
+use crate::vm::Thread;
+
+/// The longest request that is read, in bytes
+pub const MAX_REQUEST_LENGTH: usize = 16 * 1024 * 1024;
+
+/// A request read from the client
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// The source of the expressions to evaluate
+    Source(String),
+    /// A request that cannot be evaluated, with the reason why
+    Rejected(String),
+}
+
+/// Read one request framed by a line holding its length, or None at the end of the input
+pub fn read_request(input: &mut dyn BufRead) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    while line.trim().is_empty() {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+    }
+
+    let length = line.trim().parse::<usize>().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "expected the length of a request, got {:?}",
+                line.trim_end()
+            ),
+        )
+    })?;
+
+    // the body of a request that is too long is read and discarded, to reach the next request
+    if length > MAX_REQUEST_LENGTH {
+        let skipped = io::copy(&mut Read::take(&mut *input, length as u64), &mut io::sink())?;
+        if skipped < length as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok(Some(Request::Rejected(format!(
+            "The request of {} bytes is longer than the limit of {} bytes",
+            length, MAX_REQUEST_LENGTH
+        ))));
+    }
+
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(match String::from_utf8(body) {
+        Ok(source) => Request::Source(source),
+        Err(_) => Request::Rejected(String::from("The request is not valid UTF-8")),
+    }))
+}
+
+/// Write one response preceded by a line holding its length
+pub fn write_response(output: &mut dyn Write, response: &str) -> io::Result<()> {
+    write!(output, "{}\n{}\n", response.len(), response)?;
+    output.flush()
+}
+
+/// What evaluating a request produced
+#[derive(Default)]
+struct Outcome {
+    /// The printed result of the last expression, if evaluation succeeded
+    value: Option<String>,
+    /// Everything printed to standard output
+    output: String,
+    /// Warnings and the error, if there was one
+    diagnostics: Vec<Diagnostic>,
+    /// The functions being called when evaluation failed
+    traceback: Vec<String>,
+    /// The status the program asked to exit with
+    exit_status: Option<i32>,
+}
+
+impl Outcome {
+    /// Return the response JSON object, without the exit status
+    fn to_json(&self) -> String {
+        let mut out = String::from("{\"value\":");
+        match self.value {
+            Some(ref value) => encode_str(value, &mut out),
+            None => out.push_str("null"),
+        }
+        out.push_str(",\"output\":");
+        encode_str(&self.output, &mut out);
+        out.push_str(",\"diagnostics\":[");
+        for (index, diagnostic) in self.diagnostics.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            out.push_str(&diagnostic.to_json(None));
+        }
+        out.push_str("],\"traceback\":[");
+        for (index, line) in self.traceback.iter().enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            encode_str(line, &mut out);
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+/// Mutator that allocates the Thread requests are evaluated on
+struct NewSession {
+    options: CompileOptions,
+}
+
+impl Mutator for NewSession {
+    type Input = ();
+    type Output = Session;
+
+    fn run(&self, mem: &MutatorView, _input: ()) -> Result<Session, RuntimeError> {
+        // standard output carries the responses, so tracebacks are returned in them instead
+        let thread = Thread::alloc(mem)?;
+        thread.set_capture_traceback(true);
+
+        Ok(Session {
+            thread: CellPtr::new_with(thread),
+            options: self.options,
+        })
+    }
+}
+
+/// The Thread requests are evaluated on and the settings they are compiled with
+struct Session {
+    thread: CellPtr<Thread>,
+    options: CompileOptions,
+}
+
+impl Session {
+    /// Parse, compile and evaluate the source of a request, capturing what it prints
+    fn eval(
+        &self,
+        mem: &MutatorView,
+        source: &str,
+        outcome: &mut Outcome,
+    ) -> Result<String, RuntimeError> {
+        let thread = self.thread.get(mem);
+
+        let mut parser = Parser::new(lex_reader(source.as_bytes()));
+        let mut forms = Vec::new();
+        while let Some(form) = parser.next_expr(mem)? {
+            forms.push(form);
+        }
+
+        let context = CompileContext::for_thread(mem, self.options, &thread);
+        let (function, warnings) = compile_toplevel_in_context(mem, &forms, None, context)?;
+        outcome.diagnostics.extend(warnings);
+
+        let output = Port::open_output_string(mem)?;
+        thread.set_output_port(output);
+        let result = thread.quick_vm_eval(mem, function);
+        outcome.output = output.output_string(mem)?;
+
+        Ok(format!("{:#}", result?))
+    }
+}
+
+impl StatefulMutator for Session {
+    type Input = String;
+    type Output = Outcome;
+
+    fn run(&mut self, mem: &MutatorView, source: String) -> Result<Outcome, RuntimeError> {
+        let mut outcome = Outcome::default();
+
+        match self.eval(mem, &source, &mut outcome) {
+            Ok(value) => outcome.value = Some(value),
+
+            Err(e) => {
+                if let ErrorKind::Exit(status) = e.error_kind() {
+                    outcome.exit_status = Some(*status);
+                }
+                outcome.diagnostics.push(Diagnostic::from_error(&e));
+                outcome.traceback = self.thread.get(mem).take_traceback();
+            }
+        }
+
+        Ok(outcome)
+    }
+}
+
+/// An evaluation session: the heap and the Thread that requests are evaluated on
+pub struct EvalServer {
+    mem: Memory,
+    session: Session,
+    /// The status to exit with, once a request has called `exit`
+    exit_status: Option<i32>,
+}
+
+impl EvalServer {
+    /// Start a session that compiles requests with the given options
+    pub fn new(options: CompileOptions) -> Result<EvalServer, RuntimeError> {
+        let mem = Memory::new();
+        let session = mem.mutate(&NewSession { options }, ())?;
+
+        Ok(EvalServer {
+            mem,
+            session,
+            exit_status: None,
+        })
+    }
+
+    /// The status the process should exit with, once a request has called `exit`
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// Evaluate the source of one request and return the response
+    pub fn handle(&mut self, source: &str) -> Result<String, RuntimeError> {
+        let outcome = self
+            .mem
+            .mutate_with_state(&mut self.session, String::from(source))?;
+        self.exit_status = outcome.exit_status;
+        Ok(outcome.to_json())
+    }
+
+    /// Return the response to a request that was rejected for the given reason
+    pub fn reject(&self, reason: &str) -> String {
+        Outcome {
+            diagnostics: vec![Diagnostic::error("bad-request", reason, None)],
+            ..Outcome::default()
+        }
+        .to_json()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn server_message_framing() {
+        let source = |source: &str| Some(Request::Source(String::from(source)));
+
+        let mut input = Cursor::new("5\n(a b)3\nabc\n\n");
+        assert!(read_request(&mut input).unwrap() == source("(a b)"));
+        assert!(read_request(&mut input).unwrap() == source("abc"));
+        assert!(read_request(&mut input).unwrap().is_none());
+
+        // the length counts bytes rather than characters
+        let mut input = Cursor::new("4\n\"λ\"");
+        assert!(read_request(&mut input).unwrap() == source("\"λ\""));
+
+        assert!(read_request(&mut Cursor::new("five\n(a b)")).is_err());
+        assert!(read_request(&mut Cursor::new("9\n(a b)")).is_err());
+
+        // requests that are too long or not UTF-8 are skipped over and rejected
+        let too_long = MAX_REQUEST_LENGTH + 1;
+        let mut input = Cursor::new(format!("{}\n{}1\nx", too_long, " ".repeat(too_long)));
+        assert!(matches!(
+            read_request(&mut input).unwrap(),
+            Some(Request::Rejected(_))
+        ));
+        assert!(read_request(&mut input).unwrap() == source("x"));
+        let mut input = Cursor::new(format!("{}\n", too_long));
+        assert!(read_request(&mut input).is_err());
+        let mut input = Cursor::new(&b"2\n\xff\xfe"[..]);
+        assert!(matches!(
+            read_request(&mut input).unwrap(),
+            Some(Request::Rejected(_))
+        ));
+
+        let mut output = Vec::new();
+        write_response(&mut output, "{\"value\":\"λ\"}").unwrap();
+        assert!(output == "14\n{\"value\":\"λ\"}\n".as_bytes());
+    }
+
+    #[test]
+    fn server_session() {
+        let mut server = EvalServer::new(CompileOptions::default()).unwrap();
+        let mut handle = |source: &str| server.handle(source).unwrap();
+
+        assert!(
+            handle("(cons 'a 'b)")
+                == "{\"value\":\"(a . b)\",\"output\":\"\",\"diagnostics\":[],\"traceback\":[]}"
+        );
+
+        // globals are kept between requests and printing is captured
+        assert!(handle("(def greet (x) (print x) x)").contains("\"value\":\"(Function greet"));
+        assert!(
+            handle("(greet \"hi\") (greet 'there)")
+                == "{\"value\":\"there\",\"output\":\"hithere\",\"diagnostics\":[],\"traceback\":[]}"
+        );
+
+        // warnings are reported with the value
+        let response = handle("(def f (a) 'x) 'y");
+        assert!(response.starts_with("{\"value\":\"y\""));
+        assert!(response.contains("\"message\":\"Parameter a is never used\""));
+        assert!(response.contains("\"severity\":\"warning\""));
+
+        // an evaluation error keeps the output printed before it
+        let response = handle("(greet 'a) (car 'b)");
+        assert!(response.starts_with("{\"value\":null,\"output\":\"a\",\"diagnostics\":[{"));
+        assert!(response.contains("\"severity\":\"error\""));
+        assert!(response.ends_with("\"traceback\":[]}"));
+
+        // an error in a function reports the calls it was made in
+        handle("(def fail (x) (car x))");
+        let response = handle("(fail 'a)");
+        assert!(response.contains("\"traceback\":[\"in (Function fail (x))"));
+
+        // as do parse errors, which leave nothing evaluated
+        let response = handle("(greet 'a");
+        assert!(response.starts_with("{\"value\":null,\"output\":\"\""));
+        assert!(response.contains("\"severity\":\"error\""));
+        assert!(server.exit_status().is_none());
+
+        let response = server.reject("The request is not valid UTF-8");
+        assert!(response.starts_with("{\"value\":null,\"output\":\"\",\"diagnostics\":[{"));
+        assert!(response.contains("\"code\":\"bad-request\""));
+
+        let response = server.handle("(exit 3)").unwrap();
+        assert!(response.starts_with("{\"value\":null"));
+        assert!(server.exit_status() == Some(3));
+    }
+}
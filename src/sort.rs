@@ -0,0 +1,206 @@
+/// Sorting lists with a comparison function.
+///
+/// `(sort xs before?)` returns a new list of the items of `xs` ordered so that, for each item,
+/// `(before? later-item item)` is not true. `before?` may be any function of two arguments,
+/// for example `string<?` or a function defined in the language, and is called by a native merge
+/// sort rather than by recursion in the language, so long lists do not deepen the call stack. The
+/// sort is stable: items that neither comes before the other keep their order.
+use crate::containers::{SliceableContainer, StackAnyContainer};
+use crate::error::{err_eval, RuntimeError};
+use crate::list::List;
+use crate::memory::MutatorView;
+use crate::native_module;
+use crate::pair::{cons, vec_from_pairs};
+use crate::persistent::PersistentList;
+use crate::printer::describe;
+use crate::safeptr::{TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+use crate::vm::Thread;
+
+/// Sort items in place of a Vec with a bottom-up merge sort, keeping items in their order unless
+/// `before(later, earlier)` returns true. An error from `before` stops the sort.
+pub fn merge_sort<T, F>(items: Vec<T>, mut before: F) -> Result<Vec<T>, RuntimeError>
+where
+    T: Copy,
+    F: FnMut(T, T) -> Result<bool, RuntimeError>,
+{
+    let mut items = items;
+    let mut width = 1;
+
+    while width < items.len() {
+        let mut merged = Vec::with_capacity(items.len());
+
+        for run in items.chunks(2 * width) {
+            let (left, right) = run.split_at(width.min(run.len()));
+            let (mut l, mut r) = (0, 0);
+
+            while l < left.len() && r < right.len() {
+                // an item from the right run only goes first if it is strictly before
+                if before(right[r], left[l])? {
+                    merged.push(right[r]);
+                    r += 1;
+                } else {
+                    merged.push(left[l]);
+                    l += 1;
+                }
+            }
+
+            merged.extend_from_slice(&left[l..]);
+            merged.extend_from_slice(&right[r..]);
+        }
+
+        items = merged;
+        width *= 2;
+    }
+
+    Ok(items)
+}
+
+/// (sort xs before?) - return a new list of the items of a Pair list, List or persistent list
+/// sorted by a function of two items that returns true if the first goes before the second
+fn sort<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let list = args[0].get(mem);
+    let function = args[1].get(mem);
+
+    let items = match *list {
+        Value::Nil | Value::Pair(_) => vec_from_pairs(mem, list)?,
+        Value::List(list) => list.access_slice(mem, |guard, items| {
+            items.iter().map(|item| item.get(guard)).collect()
+        }),
+        Value::PersistentList(list) => list.items(mem),
+        other => {
+            return Err(err_eval(&format!(
+                "Parameter xs to sort must be a list, got {}",
+                describe(other)
+            )))
+        }
+    };
+
+    // this Thread is busy calling sort, so the function is called on another that can see this
+    // one's closures
+    let runner = thread.alloc_nested(mem)?;
+    let sorted = merge_sort(items, |a, b| {
+        let args = [TaggedCellPtr::new_with(a), TaggedCellPtr::new_with(b)];
+        Ok(runner.quick_vm_apply(mem, function, &args)? == mem.sym_true())
+    })?;
+
+    match *list {
+        Value::List(_) => {
+            let result = List::alloc_with_capacity(mem, sorted.len() as u32)?;
+            for item in sorted {
+                StackAnyContainer::push(&*result, mem, item)?;
+            }
+            Ok(result.as_tagged(mem))
+        }
+
+        Value::PersistentList(_) => Ok(PersistentList::from_slice(mem, &sorted)?.as_tagged(mem)),
+
+        _ => {
+            let mut result = mem.nil();
+            for item in sorted.into_iter().rev() {
+                result = cons(mem, item, result)?;
+            }
+            Ok(result)
+        }
+    }
+}
+
+native_module! {
+    /// The list sorting builtin function
+    pub SORT_MODULE = "sort" {
+        "sort" => sort(2),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    #[test]
+    fn merge_sort_is_stable() {
+        let pairs = vec![
+            (3, 'a'),
+            (1, 'b'),
+            (2, 'c'),
+            (1, 'd'),
+            (3, 'e'),
+            (0, 'f'),
+            (2, 'g'),
+        ];
+        let sorted = merge_sort(pairs, |a, b| Ok(a.0 < b.0)).unwrap();
+        assert!(
+            sorted
+                == vec![
+                    (0, 'f'),
+                    (1, 'b'),
+                    (1, 'd'),
+                    (2, 'c'),
+                    (2, 'g'),
+                    (3, 'a'),
+                    (3, 'e')
+                ]
+        );
+
+        let empty: Vec<u8> = vec![];
+        assert!(merge_sort(empty, |a, b| Ok(a < b)).unwrap().is_empty());
+
+        let reversed: Vec<usize> = (0..1000).rev().collect();
+        let sorted = merge_sort(reversed, |a, b| Ok(a < b)).unwrap();
+        assert!(sorted == (0..1000).collect::<Vec<usize>>());
+
+        assert!(merge_sort(vec![2, 1], |_, _| Err(err_eval("no"))).is_err());
+    }
+
+    #[test]
+    fn sort_builtin() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code: &str| -> Result<String, RuntimeError> {
+                    Ok(format!(
+                        "{}",
+                        t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?)?
+                    ))
+                };
+
+                assert!(eval("(sort '(pear apple fig) symbol<?)")? == "(apple fig pear)");
+                assert!(eval("(sort '(\"b\" \"a\" \"c\") string<?)")? == "(\"a\" \"b\" \"c\")");
+                assert!(eval("(sort nil symbol<?)")? == "nil");
+                assert!(eval("(sort (plist 'b 'a) symbol<?)")? == "(plist a b)");
+
+                // a comparison defined in the language sorts pairs by their first item, keeping
+                // the order of pairs with the same first item
+                eval("(def first<? (a b) (symbol<? (car a) (car b)))")?;
+                assert!(
+                    eval("(sort '((b . 1) (a . 2) (b . 3) (a . 4)) first<?)")?
+                        == "((a . 2) (a . 4) (b . 1) (b . 3))"
+                );
+
+                // closures can be passed
+                eval("(def sort-with (less? xs) (sort xs (lambda (a b) (less? a b))))")?;
+                assert!(eval("(sort-with symbol<? '(b c a))")? == "(a b c)");
+
+                assert!(eval("(sort 'a symbol<?)").is_err());
+                assert!(eval("(sort '(a . b) symbol<?)").is_err());
+                assert!(eval("(sort '(a \"b\") symbol<?)").is_err());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
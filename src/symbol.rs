@@ -5,6 +5,7 @@ use std::slice;
 use std::str;
 
 use crate::hashable::Hashable;
+use crate::lexer::{needs_quoting, quote_symbol};
 use crate::printer::Print;
 use crate::safeptr::MutatorScope;
 
@@ -36,6 +37,13 @@ impl Symbol {
     pub fn as_str<'guard>(&self, _guard: &'guard dyn MutatorScope) -> &'guard str {
         unsafe { self.unguarded_as_str() }
     }
+
+    /// A keyword is a Symbol whose name starts with a colon, such as `:name`. Keywords evaluate to
+    /// themselves and name parameters in calls with named arguments.
+    pub fn is_keyword<'guard>(&self, guard: &'guard dyn MutatorScope) -> bool {
+        let name = self.as_str(guard);
+        name.len() > 1 && name.starts_with(':')
+    }
 }
 
 impl Print for Symbol {
@@ -45,7 +53,13 @@ impl Print for Symbol {
         guard: &'guard dyn MutatorScope,
         f: &mut fmt::Formatter,
     ) -> fmt::Result {
-        write!(f, "{}", self.as_str(guard))
+        let name = self.as_str(guard);
+
+        if needs_quoting(name) {
+            write!(f, "{}", quote_symbol(name))
+        } else {
+            write!(f, "{}", name)
+        }
     }
 }
 
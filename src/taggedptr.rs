@@ -11,7 +11,20 @@
 /// Defines a `TaggedPtr` type where the low bits of a pointer indicate the
 /// type of the object pointed to for certain types, but the object header is
 /// required to provide all other object type ids.
+///
+/// TaggedPtr word layout, by the lowest bits of the word:
+///  * `000` an inline integer in the upper 61 bits
+///  * `100` some other immediate value, identified by a 5 bit subtag in bits 3 to 7, with any
+///    32 bit payload in the upper half of the word: nil, bool, char or a float that single precision
+///    represents exactly. Other floats are heap-allocated `FloatObject`s.
+///  * `01` a Symbol pointer
+///  * `10` a Pair pointer
+///  * `11` any other object pointer, where the object header holds the type id
+///
+/// All immediate values can be type-identified and unpacked without dereferencing anything.
+/// The immediate payload layout assumes a 64 bit machine word.
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::ptr::NonNull;
 
 use stickyimmix::{AllocRaw, RawPtr};
@@ -19,16 +32,26 @@ use stickyimmix::{AllocRaw, RawPtr};
 use crate::array::{ArrayU16, ArrayU32, ArrayU8};
 use crate::dict::Dict;
 use crate::function::{Function, Partial};
+use crate::global::GlobalCell;
 use crate::list::List;
 use crate::memory::HeapStorage;
-use crate::number::NumberObject;
+use crate::native::NativeFunction;
+use crate::number::{FloatObject, NumberObject};
 use crate::pair::Pair;
-use crate::pointerops::{get_tag, ScopedRef, Tagged, TAG_NUMBER, TAG_OBJECT, TAG_PAIR, TAG_SYMBOL};
+use crate::persistent::{PersistentList, PersistentMap};
+use crate::pointerops::{
+    get_immediate_kind, get_payload, get_subtag, get_tag, make_immediate, ScopedRef, Tagged,
+    IMMEDIATE_INTEGER, INTEGER_SHIFT, SUBTAG_BOOL, SUBTAG_CHAR, SUBTAG_FLOAT, SUBTAG_NIL,
+    TAG_IMMEDIATE, TAG_OBJECT, TAG_PAIR, TAG_SYMBOL,
+};
+use crate::port::Port;
 use crate::printer::Print;
+use crate::promise::Promise;
 use crate::safeptr::{MutatorScope, ScopedPtr};
 use crate::symbol::Symbol;
 use crate::text::Text;
 use crate::vm::Upvalue;
+use crate::weak::WeakRef;
 
 /// A safe interface to GC-heap managed objects. The `'guard` lifetime must be a safe lifetime for
 /// the GC not to move or collect the referenced object.
@@ -36,6 +59,9 @@ use crate::vm::Upvalue;
 #[derive(Copy, Clone)]
 pub enum Value<'guard> {
     Nil,
+    Bool(bool),
+    Char(char),
+    Float(f64),
     Pair(ScopedPtr<'guard, Pair>),
     Symbol(ScopedPtr<'guard, Symbol>),
     Number(isize),
@@ -49,6 +75,44 @@ pub enum Value<'guard> {
     Function(ScopedPtr<'guard, Function>),
     Partial(ScopedPtr<'guard, Partial>),
     Upvalue(ScopedPtr<'guard, Upvalue>),
+    NativeFunction(ScopedPtr<'guard, NativeFunction>),
+    Port(ScopedPtr<'guard, Port>),
+    WeakRef(ScopedPtr<'guard, WeakRef>),
+    GlobalCell(ScopedPtr<'guard, GlobalCell>),
+    PersistentList(ScopedPtr<'guard, PersistentList>),
+    PersistentMap(ScopedPtr<'guard, PersistentMap>),
+    Promise(ScopedPtr<'guard, Promise>),
+}
+
+impl<'guard> Value<'guard> {
+    /// Return the name of the value's type, as used in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Char(_) => "char",
+            Value::Float(_) => "float",
+            Value::Pair(_) => "pair",
+            Value::Symbol(_) => "symbol",
+            Value::Number(_) | Value::NumberObject(_) => "integer",
+            Value::Text(_) => "text",
+            Value::List(_) => "list",
+            Value::ArrayU8(_) => "byte array",
+            Value::ArrayU16(_) => "u16 array",
+            Value::ArrayU32(_) => "u32 array",
+            Value::Dict(_) => "dict",
+            Value::Function(_) => "function",
+            Value::Partial(_) => "partial",
+            Value::Upvalue(_) => "upvalue",
+            Value::NativeFunction(_) => "native function",
+            Value::Port(_) => "port",
+            Value::WeakRef(_) => "weak reference",
+            Value::GlobalCell(_) => "global cell",
+            Value::PersistentList(_) => "persistent list",
+            Value::PersistentMap(_) => "persistent map",
+            Value::Promise(_) => "promise",
+        }
+    }
 }
 
 /// `Value` can have a safe `Display` implementation
@@ -56,6 +120,9 @@ impl<'guard> fmt::Display for Value<'guard> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", *b),
+            Value::Char(c) => write!(f, "#\\{}", *c),
+            Value::Float(n) => write!(f, "{:?}", *n),
             Value::Pair(p) => p.print(self, f),
             Value::Symbol(s) => s.print(self, f),
             Value::Number(n) => write!(f, "{}", *n),
@@ -68,6 +135,13 @@ impl<'guard> fmt::Display for Value<'guard> {
             Value::Function(n) => n.print(self, f),
             Value::Partial(p) => p.print(self, f),
             Value::Upvalue(_) => write!(f, "Upvalue"),
+            Value::NativeFunction(n) => n.print(self, f),
+            Value::Port(p) => p.print(self, f),
+            Value::WeakRef(w) => w.print(self, f),
+            Value::GlobalCell(c) => c.print(self, f),
+            Value::PersistentList(l) => l.print(self, f),
+            Value::PersistentMap(m) => m.print(self, f),
+            Value::Promise(p) => p.print(self, f),
             _ => write!(f, "<unidentified-object-type>"),
         }
     }
@@ -77,6 +151,9 @@ impl<'guard> fmt::Debug for Value<'guard> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", *b),
+            Value::Char(c) => write!(f, "#\\{}", *c),
+            Value::Float(n) => write!(f, "{:?}", *n),
             Value::Pair(p) => p.debug(self, f),
             Value::Symbol(s) => s.debug(self, f),
             Value::Number(n) => write!(f, "{}", *n),
@@ -89,6 +166,13 @@ impl<'guard> fmt::Debug for Value<'guard> {
             Value::Function(n) => n.debug(self, f),
             Value::Partial(p) => p.debug(self, f),
             Value::Upvalue(_) => write!(f, "Upvalue"),
+            Value::NativeFunction(n) => n.debug(self, f),
+            Value::Port(p) => p.debug(self, f),
+            Value::WeakRef(w) => w.debug(self, f),
+            Value::GlobalCell(c) => c.debug(self, f),
+            Value::PersistentList(l) => l.debug(self, f),
+            Value::PersistentMap(m) => m.debug(self, f),
+            Value::Promise(p) => p.debug(self, f),
             _ => write!(f, "<unidentified-object-type>"),
         }
     }
@@ -101,10 +185,14 @@ impl<'guard> MutatorScope for Value<'guard> {}
 #[derive(Copy, Clone)]
 pub enum FatPtr {
     Nil,
+    Bool(bool),
+    Char(char),
+    Float(f32),
     Pair(RawPtr<Pair>),
     Symbol(RawPtr<Symbol>),
     Number(isize),
     NumberObject(RawPtr<NumberObject>),
+    FloatObject(RawPtr<FloatObject>),
     Text(RawPtr<Text>),
     List(RawPtr<List>),
     ArrayU8(RawPtr<ArrayU8>),
@@ -114,6 +202,13 @@ pub enum FatPtr {
     Function(RawPtr<Function>),
     Partial(RawPtr<Partial>),
     Upvalue(RawPtr<Upvalue>),
+    NativeFunction(RawPtr<NativeFunction>),
+    Port(RawPtr<Port>),
+    WeakRef(RawPtr<WeakRef>),
+    GlobalCell(RawPtr<GlobalCell>),
+    PersistentList(RawPtr<PersistentList>),
+    PersistentMap(RawPtr<PersistentMap>),
+    Promise(RawPtr<Promise>),
 }
 
 impl FatPtr {
@@ -122,6 +217,9 @@ impl FatPtr {
     pub fn as_value<'guard>(&self, guard: &'guard dyn MutatorScope) -> Value<'guard> {
         match self {
             FatPtr::Nil => Value::Nil,
+            FatPtr::Bool(b) => Value::Bool(*b),
+            FatPtr::Char(c) => Value::Char(*c),
+            FatPtr::Float(n) => Value::Float(*n as f64),
             FatPtr::Pair(raw_ptr) => Value::Pair(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard))),
             FatPtr::Symbol(raw_ptr) => {
                 Value::Symbol(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard)))
@@ -130,6 +228,7 @@ impl FatPtr {
             FatPtr::NumberObject(raw_ptr) => {
                 Value::NumberObject(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard)))
             }
+            FatPtr::FloatObject(raw_ptr) => Value::Float(raw_ptr.scoped_ref(guard).value()),
             FatPtr::Text(raw_ptr) => Value::Text(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard))),
             FatPtr::List(raw_ptr) => Value::List(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard))),
             FatPtr::ArrayU8(raw_ptr) => {
@@ -151,6 +250,25 @@ impl FatPtr {
             FatPtr::Upvalue(raw_ptr) => {
                 Value::Upvalue(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard)))
             }
+            FatPtr::NativeFunction(raw_ptr) => {
+                Value::NativeFunction(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard)))
+            }
+            FatPtr::Port(raw_ptr) => Value::Port(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard))),
+            FatPtr::WeakRef(raw_ptr) => {
+                Value::WeakRef(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard)))
+            }
+            FatPtr::GlobalCell(raw_ptr) => {
+                Value::GlobalCell(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard)))
+            }
+            FatPtr::PersistentList(raw_ptr) => {
+                Value::PersistentList(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard)))
+            }
+            FatPtr::PersistentMap(raw_ptr) => {
+                Value::PersistentMap(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard)))
+            }
+            FatPtr::Promise(raw_ptr) => {
+                Value::Promise(ScopedPtr::new(guard, raw_ptr.scoped_ref(guard)))
+            }
         }
     }
 }
@@ -169,6 +287,7 @@ macro_rules! fatptr_from_rawptr {
 fatptr_from_rawptr!(Pair, Pair);
 fatptr_from_rawptr!(Symbol, Symbol);
 fatptr_from_rawptr!(NumberObject, NumberObject);
+fatptr_from_rawptr!(FloatObject, FloatObject);
 fatptr_from_rawptr!(Text, Text);
 fatptr_from_rawptr!(List, List);
 fatptr_from_rawptr!(ArrayU8, ArrayU8);
@@ -178,6 +297,13 @@ fatptr_from_rawptr!(Dict, Dict);
 fatptr_from_rawptr!(Function, Function);
 fatptr_from_rawptr!(Partial, Partial);
 fatptr_from_rawptr!(Upvalue, Upvalue);
+fatptr_from_rawptr!(NativeFunction, NativeFunction);
+fatptr_from_rawptr!(Port, Port);
+fatptr_from_rawptr!(WeakRef, WeakRef);
+fatptr_from_rawptr!(GlobalCell, GlobalCell);
+fatptr_from_rawptr!(PersistentList, PersistentList);
+fatptr_from_rawptr!(PersistentMap, PersistentMap);
+fatptr_from_rawptr!(Promise, Promise);
 
 /// Conversion from an integer type
 impl From<isize> for FatPtr {
@@ -187,6 +313,27 @@ impl From<isize> for FatPtr {
     }
 }
 
+/// Conversion from a bool type
+impl From<bool> for FatPtr {
+    fn from(b: bool) -> FatPtr {
+        FatPtr::Bool(b)
+    }
+}
+
+/// Conversion from a char type
+impl From<char> for FatPtr {
+    fn from(c: char) -> FatPtr {
+        FatPtr::Char(c)
+    }
+}
+
+/// Conversion from a single precision float type
+impl From<f32> for FatPtr {
+    fn from(n: f32) -> FatPtr {
+        FatPtr::Float(n)
+    }
+}
+
 /// Conversion from a TaggedPtr type
 impl From<TaggedPtr> for FatPtr {
     fn from(ptr: TaggedPtr) -> FatPtr {
@@ -201,16 +348,30 @@ impl PartialEq for FatPtr {
 
         match (*self, *other) {
             (Nil, Nil) => true,
+            (Bool(a), Bool(b)) => a == b,
+            (Char(a), Char(b)) => a == b,
+            (Float(a), Float(b)) => a.to_bits() == b.to_bits(),
             (Pair(p), Pair(q)) => p == q,
             (Symbol(p), Symbol(q)) => p == q,
             (Number(i), Number(j)) => i == j,
             (NumberObject(p), NumberObject(q)) => p == q,
+            (FloatObject(p), FloatObject(q)) => p == q,
             _ => false,
         }
     }
 }
 
-/// An packed Tagged Pointer which carries type information in the pointers low 2 bits
+/// The count of bits available to an inline integer: a machine word less the 3 tag bits
+pub const INLINE_INTEGER_BITS: u32 = isize::BITS - INTEGER_SHIFT as u32;
+
+/// The largest integer that can be stored inline in a TaggedPtr
+pub const INLINE_INTEGER_MAX: isize = isize::MAX >> INTEGER_SHIFT;
+
+/// The smallest integer that can be stored inline in a TaggedPtr
+pub const INLINE_INTEGER_MIN: isize = isize::MIN >> INTEGER_SHIFT;
+
+/// An packed Tagged Pointer which carries type information in the pointers low 2 bits, and
+/// immediate type information in the next few bits
 #[derive(Copy, Clone)]
 pub union TaggedPtr {
     tag: usize,
@@ -223,12 +384,35 @@ pub union TaggedPtr {
 impl TaggedPtr {
     /// Construct a nil TaggedPtr
     pub fn nil() -> TaggedPtr {
-        TaggedPtr { tag: 0 }
+        TaggedPtr {
+            tag: make_immediate(SUBTAG_NIL, 0),
+        }
     }
 
     /// Return true if the pointer is nil
     pub fn is_nil(&self) -> bool {
-        unsafe { self.tag == 0 }
+        unsafe { self.tag == make_immediate(SUBTAG_NIL, 0) }
+    }
+
+    /// Construct an immediate boolean TaggedPtr
+    pub fn boolean(value: bool) -> TaggedPtr {
+        TaggedPtr {
+            tag: make_immediate(SUBTAG_BOOL, value as u32),
+        }
+    }
+
+    /// Construct an immediate character TaggedPtr
+    pub fn character(value: char) -> TaggedPtr {
+        TaggedPtr {
+            tag: make_immediate(SUBTAG_CHAR, value as u32),
+        }
+    }
+
+    /// Construct an immediate single precision float TaggedPtr
+    pub fn float(value: f32) -> TaggedPtr {
+        TaggedPtr {
+            tag: make_immediate(SUBTAG_FLOAT, value.to_bits()),
+        }
     }
 
     /// Construct a generic object TaggedPtr
@@ -252,40 +436,54 @@ impl TaggedPtr {
         }
     }
 
-    /// Construct an inline integer TaggedPtr
+    /// Construct an inline integer TaggedPtr.
+    ///
+    /// The three tag bits are shifted in at the bottom of the word, so only the low
+    /// `INLINE_INTEGER_BITS` bits of `value` are kept. Anything outside of
+    /// `INLINE_INTEGER_MIN..=INLINE_INTEGER_MAX` wraps around, exactly as if the value had been
+    /// masked to 61 bits (on a 64 bit machine) and sign-extended back out again.
     // TODO deal with big numbers later
     pub fn number(value: isize) -> TaggedPtr {
         TaggedPtr {
-            number: (((value as usize) << 2) | TAG_NUMBER) as isize,
+            number: (((value as usize) << INTEGER_SHIFT) | IMMEDIATE_INTEGER) as isize,
         }
     }
 
     /// Construct an inline integer from a literal signed 16bit number
     pub fn literal_integer(value: i16) -> TaggedPtr {
-        TaggedPtr {
-            number: (((value as usize) << 2) | TAG_NUMBER) as isize,
-        }
+        TaggedPtr::number(value as isize)
     }
 
     fn into_fat_ptr(&self) -> FatPtr {
         unsafe {
-            if self.tag == 0 {
-                FatPtr::Nil
-            } else {
-                match get_tag(self.tag) {
-                    TAG_NUMBER => FatPtr::Number(self.number >> 2),
-                    TAG_SYMBOL => FatPtr::Symbol(RawPtr::untag(self.symbol)),
-                    TAG_PAIR => FatPtr::Pair(RawPtr::untag(self.pair)),
-
-                    TAG_OBJECT => {
-                        let untyped_object_ptr = RawPtr::untag(self.object).as_untyped();
-                        let header_ptr = HeapStorage::get_header(untyped_object_ptr);
-
-                        header_ptr.as_ref().get_object_fatptr()
+            match get_tag(self.tag) {
+                TAG_IMMEDIATE => {
+                    if get_immediate_kind(self.tag) == IMMEDIATE_INTEGER {
+                        FatPtr::Number(self.number >> INTEGER_SHIFT)
+                    } else {
+                        let payload = get_payload(self.tag);
+
+                        match get_subtag(self.tag) {
+                            SUBTAG_NIL => FatPtr::Nil,
+                            SUBTAG_BOOL => FatPtr::Bool(payload != 0),
+                            SUBTAG_CHAR => FatPtr::Char(std::char::from_u32_unchecked(payload)),
+                            SUBTAG_FLOAT => FatPtr::Float(f32::from_bits(payload)),
+                            _ => panic!("Invalid TaggedPtr immediate subtag!"),
+                        }
                     }
+                }
+
+                TAG_SYMBOL => FatPtr::Symbol(RawPtr::untag(self.symbol)),
+                TAG_PAIR => FatPtr::Pair(RawPtr::untag(self.pair)),
 
-                    _ => panic!("Invalid TaggedPtr type tag!"),
+                TAG_OBJECT => {
+                    let untyped_object_ptr = RawPtr::untag(self.object).as_untyped();
+                    let header_ptr = HeapStorage::get_header(untyped_object_ptr);
+
+                    header_ptr.as_ref().get_object_fatptr()
                 }
+
+                _ => panic!("Invalid TaggedPtr type tag!"),
             }
         }
     }
@@ -295,10 +493,14 @@ impl From<FatPtr> for TaggedPtr {
     fn from(ptr: FatPtr) -> TaggedPtr {
         match ptr {
             FatPtr::Nil => TaggedPtr::nil(),
+            FatPtr::Bool(value) => TaggedPtr::boolean(value),
+            FatPtr::Char(value) => TaggedPtr::character(value),
+            FatPtr::Float(value) => TaggedPtr::float(value),
             FatPtr::Number(value) => TaggedPtr::number(value),
             FatPtr::Symbol(raw) => TaggedPtr::symbol(raw),
             FatPtr::Pair(raw) => TaggedPtr::pair(raw),
             FatPtr::NumberObject(raw) => TaggedPtr::object(raw),
+            FatPtr::FloatObject(raw) => TaggedPtr::object(raw),
             FatPtr::Text(raw) => TaggedPtr::object(raw),
             FatPtr::List(raw) => TaggedPtr::object(raw),
             FatPtr::ArrayU8(raw) => TaggedPtr::object(raw),
@@ -308,6 +510,13 @@ impl From<FatPtr> for TaggedPtr {
             FatPtr::Function(raw) => TaggedPtr::object(raw),
             FatPtr::Partial(raw) => TaggedPtr::object(raw),
             FatPtr::Upvalue(raw) => TaggedPtr::object(raw),
+            FatPtr::NativeFunction(raw) => TaggedPtr::object(raw),
+            FatPtr::Port(raw) => TaggedPtr::object(raw),
+            FatPtr::WeakRef(raw) => TaggedPtr::object(raw),
+            FatPtr::GlobalCell(raw) => TaggedPtr::object(raw),
+            FatPtr::PersistentList(raw) => TaggedPtr::object(raw),
+            FatPtr::PersistentMap(raw) => TaggedPtr::object(raw),
+            FatPtr::Promise(raw) => TaggedPtr::object(raw),
         }
     }
 }
@@ -318,3 +527,188 @@ impl PartialEq for TaggedPtr {
         unsafe { self.tag == other.tag }
     }
 }
+
+impl Eq for TaggedPtr {}
+
+/// Identity hash, consistent with identity equality
+impl Hash for TaggedPtr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        unsafe { self.tag.hash(state) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::convert::ToValue;
+    use crate::error::RuntimeError;
+    use crate::memory::{Memory, Mutator, MutatorView};
+    use std::time::Instant;
+
+    #[test]
+    fn nil_is_not_zero() {
+        assert!(TaggedPtr::nil().is_nil());
+        assert!(!TaggedPtr::number(0).is_nil());
+        assert!(FatPtr::from(TaggedPtr::number(0)) == FatPtr::Number(0));
+        assert!(FatPtr::from(TaggedPtr::nil()) == FatPtr::Nil);
+    }
+
+    #[test]
+    fn immediate_integer_round_trip() {
+        for &n in &[0, 1, -1, 12345, INLINE_INTEGER_MAX, INLINE_INTEGER_MIN] {
+            assert!(FatPtr::from(TaggedPtr::number(n)) == FatPtr::Number(n));
+        }
+
+        // out of range values wrap around
+        assert!(
+            FatPtr::from(TaggedPtr::number(INLINE_INTEGER_MAX + 1))
+                == FatPtr::Number(INLINE_INTEGER_MIN)
+        );
+
+        assert!(FatPtr::from(TaggedPtr::literal_integer(-300)) == FatPtr::Number(-300));
+    }
+
+    #[test]
+    fn immediate_other_round_trip() {
+        assert!(FatPtr::from(TaggedPtr::boolean(true)) == FatPtr::Bool(true));
+        assert!(FatPtr::from(TaggedPtr::boolean(false)) == FatPtr::Bool(false));
+
+        for &c in &['a', '\0', 'é', '🦀'] {
+            assert!(FatPtr::from(TaggedPtr::character(c)) == FatPtr::Char(c));
+        }
+
+        for &n in &[0.0, -0.0, 1.5, f32::MAX, f32::NEG_INFINITY] {
+            assert!(FatPtr::from(TaggedPtr::float(n)) == FatPtr::Float(n));
+        }
+
+        // no immediate type should be mistaken for any other
+        assert!(TaggedPtr::boolean(false) != TaggedPtr::nil());
+        assert!(TaggedPtr::character('\0') != TaggedPtr::nil());
+        assert!(TaggedPtr::float(0.0) != TaggedPtr::number(0));
+    }
+
+    #[test]
+    fn immediate_fat_ptr_conversion() {
+        let values = [
+            FatPtr::Nil,
+            FatPtr::Bool(true),
+            FatPtr::Char('x'),
+            FatPtr::Float(2.25),
+            FatPtr::Number(-7),
+        ];
+
+        for value in values.iter() {
+            assert!(FatPtr::from(TaggedPtr::from(*value)) == *value);
+        }
+    }
+
+    #[test]
+    fn double_precision_floats_are_boxed() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                // a float single precision represents exactly is immediate
+                let half = 0.5f64.to_value(mem)?;
+                assert!(half.get_ptr() == TaggedPtr::float(0.5));
+
+                // any other keeps its full precision on the heap
+                for &n in &[0.1, 16777217.0, -1e300, f64::MAX] {
+                    let boxed = n.to_value(mem)?;
+                    assert!(matches!(
+                        FatPtr::from(boxed.get_ptr()),
+                        FatPtr::FloatObject(_)
+                    ));
+                    match *boxed {
+                        Value::Float(value) => assert!(value == n),
+                        _ => panic!("expected a float"),
+                    }
+                }
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    // Compare the cost of identifying the type of immediate values against identifying the type of
+    // values that require the object header to be read, such as floats that are not immediate.
+    // This is a timing report rather than a test:
+    // `cargo test --release dispatch_timing -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn dispatch_timing() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                _input: Self::Input,
+            ) -> Result<Self::Output, RuntimeError> {
+                const COUNT: usize = 1_000_000;
+
+                fn time_dispatch(label: &str, ptrs: &[TaggedPtr]) {
+                    let start = Instant::now();
+
+                    let mut checksum = 0usize;
+                    for ptr in ptrs {
+                        checksum += match FatPtr::from(*ptr) {
+                            FatPtr::Nil => 1,
+                            FatPtr::Bool(_) => 2,
+                            FatPtr::Char(_) => 3,
+                            FatPtr::Float(_) => 4,
+                            FatPtr::Number(_) => 5,
+                            _ => 6,
+                        };
+                    }
+
+                    let elapsed = start.elapsed();
+                    println!(
+                        "{}: {:.2}ns per dispatch (checksum {})",
+                        label,
+                        elapsed.as_nanos() as f64 / ptrs.len() as f64,
+                        checksum
+                    );
+                }
+
+                let immediates: Vec<TaggedPtr> = (0..COUNT)
+                    .map(|i| match i % 4 {
+                        0 => TaggedPtr::nil(),
+                        1 => TaggedPtr::boolean(i % 3 == 0),
+                        2 => TaggedPtr::character('a'),
+                        _ => TaggedPtr::float(i as f32),
+                    })
+                    .collect();
+
+                let mut objects = Vec::with_capacity(COUNT);
+                for _ in 0..COUNT {
+                    objects.push(mem.alloc_tagged(Text::new_empty())?.get_ptr());
+                }
+
+                let mut floats = Vec::with_capacity(COUNT);
+                for i in 0..COUNT {
+                    floats.push((i as f64 + 0.1).to_value(mem)?.get_ptr());
+                }
+
+                time_dispatch("immediate values", &immediates);
+                time_dispatch("header-typed objects", &objects);
+                time_dispatch("heap-allocated floats", &floats);
+
+                Ok(())
+            }
+        }
+
+        let test = Test {};
+        mem.mutate(&test, ()).unwrap();
+    }
+}
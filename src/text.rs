@@ -11,7 +11,8 @@ use crate::printer::Print;
 use crate::rawarray::{ArraySize, RawArray};
 use crate::safeptr::MutatorScope;
 
-/// While Text is somewhat similar to Symbol, it is instead garbage-collected heap allocated and not interned.
+/// While Text is somewhat similar to Symbol, it is instead garbage-collected heap allocated and not
+/// interned, unless text interning is enabled with `Memory::intern_text()`.
 #[derive(Copy, Clone)]
 pub struct Text {
     content: RawArray<u8>,
@@ -47,7 +48,7 @@ impl Text {
         }
     }
 
-    unsafe fn unguarded_as_str(&self) -> &str {
+    unsafe fn unguarded_as_str<'desired_lifetime>(&self) -> &'desired_lifetime str {
         if let Some(ptr) = self.content.as_ptr() {
             let slice = slice::from_raw_parts(ptr, self.content.capacity() as usize);
             str::from_utf8(slice).unwrap()
@@ -57,7 +58,7 @@ impl Text {
     }
 
     /// Using scope guarded access, get the Text content as a &str slice
-    pub fn as_str<'guard>(&self, _guard: &'guard dyn MutatorScope) -> &str {
+    pub fn as_str<'guard>(&self, _guard: &'guard dyn MutatorScope) -> &'guard str {
         unsafe { self.unguarded_as_str() }
     }
 }
@@ -0,0 +1,232 @@
+/// Tracing calls to global functions.
+///
+/// `(trace f)` rebinds the global `f` to a wrapper Function of the same name and arity that passes
+/// its arguments to a native function, which prints the call, calls the original function on a
+/// nested Thread and prints the value it returns. Output is indented by the depth of traced calls
+/// in progress. Since calls to globals go through the name's `GlobalCell`, callers that were
+/// compiled before `trace` was called are traced too. `(untrace f)` binds the original function
+/// again.
+use crate::builder::wrap_native;
+use crate::containers::{Container, HashIndexedAnyContainer};
+use crate::dict::Dict;
+use crate::error::{err_eval, ErrorKind, RuntimeError};
+use crate::global::lookup_global_cell;
+use crate::memory::MutatorView;
+use crate::native::{Arity, NativeFunction};
+use crate::native_module;
+use crate::pair::cons;
+use crate::safeptr::{ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::Value;
+use crate::vm::Thread;
+
+/// Return the (wrapper . original) pair of a traced name, if the name is traced
+fn traced_entry<'guard>(
+    mem: &'guard MutatorView,
+    traced: ScopedPtr<'guard, Dict>,
+    name: TaggedScopedPtr<'guard>,
+) -> Result<Option<TaggedScopedPtr<'guard>>, RuntimeError> {
+    // a Dict has no storage to look in until something is added to it
+    if traced.length() == 0 {
+        return Ok(None);
+    }
+
+    match traced.lookup(mem, name) {
+        Ok(entry) => Ok(Some(entry)),
+        Err(e) if *e.error_kind() == ErrorKind::KeyError => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// (trace-call name function arg1 .. argn) - print the call, call the function with the
+/// arguments and print the value it returns. This is what the wrappers made by `trace` call.
+fn trace_call<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let name = args[0].get(mem);
+    let function = args[1].get(mem);
+    let call_args = &args[2..];
+
+    let depth = thread.trace_depth();
+    let indent = "  ".repeat(depth);
+    let output = thread.output_port(mem);
+
+    let mut call = format!("{}({}", indent, name);
+    for arg in call_args {
+        call.push_str(&format!(" {}", arg.get(mem)));
+    }
+    output.write_str(mem, &format!("{})\n", call))?;
+
+    // this Thread is busy calling trace-call, so the function is called on another that can see
+    // this one's closures
+    let runner = thread.alloc_nested(mem)?;
+    runner.set_trace_depth(depth + 1);
+
+    let result = runner.quick_vm_apply(mem, function, call_args)?;
+
+    output.write_str(mem, &format!("{}=> {}\n", indent, result))?;
+    Ok(result)
+}
+
+/// (trace name) - print each call to the global function bound to the symbol `name`, with its
+/// arguments, and the value each call returns. Returns the name.
+fn trace<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let name = args[0].get(mem);
+    let name_str = match *name {
+        Value::Symbol(s) => s.as_str(mem),
+        _ => return Err(err_eval("Expected the symbol name of a global function")),
+    };
+
+    let cell = match lookup_global_cell(mem, thread.globals(mem), name)? {
+        Some(cell) => cell,
+        None => {
+            return Err(err_eval(&format!(
+                "Symbol {} is not bound to a value",
+                name
+            )))
+        }
+    };
+    let function = cell.get(mem);
+
+    // a name that is still bound to its wrapper is already traced
+    let traced = thread.traced(mem);
+    if let Some(entry) = traced_entry(mem, traced, name)? {
+        if let Value::Pair(entry) = *entry {
+            if entry.first.get(mem) == function {
+                return Ok(name);
+            }
+        }
+    }
+
+    let arity = match *function {
+        Value::Function(f) => f.arity(),
+        Value::Partial(p) => p.arity(),
+        Value::NativeFunction(native) => match native.arity() {
+            Arity::Exact(arity) => arity,
+            Arity::AtLeast(_) => {
+                return Err(err_eval(&format!(
+                    "Cannot trace {}, which takes a variable number of arguments",
+                    name
+                )))
+            }
+        },
+        _ => {
+            return Err(err_eval(&format!(
+                "Cannot trace {}, which is not a function",
+                name
+            )))
+        }
+    };
+
+    let trace_call = NativeFunction::alloc(mem, "trace-call", Arity::AtLeast(2), true, trace_call)?;
+    let wrapper = wrap_native(mem, name_str, arity, trace_call, &[name, function])?.as_tagged(mem);
+    traced.assoc(mem, name, cons(mem, wrapper, function)?)?;
+    cell.set(wrapper);
+
+    Ok(name)
+}
+
+/// (untrace name) - stop tracing calls to the global function bound to the symbol `name`,
+/// binding the original function again unless the name has been rebound since. Returns the name,
+/// or nil if it was not traced.
+fn untrace<'guard>(
+    mem: &'guard MutatorView,
+    thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    let name = args[0].get(mem);
+    let traced = thread.traced(mem);
+
+    let entry = match traced_entry(mem, traced, name)? {
+        Some(entry) => entry,
+        None => return Ok(mem.nil()),
+    };
+    traced.dissoc(mem, name)?;
+
+    if let Value::Pair(entry) = *entry {
+        if let Some(cell) = lookup_global_cell(mem, thread.globals(mem), name)? {
+            if cell.get(mem) == entry.first.get(mem) {
+                cell.set(entry.second.get(mem));
+            }
+        }
+    }
+
+    Ok(name)
+}
+
+native_module! {
+    /// The function call tracing builtin functions
+    pub TRACE_MODULE = "trace" [io] {
+        "trace" => trace(1),
+        "untrace" => untrace(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+    use crate::port::Port;
+
+    #[test]
+    fn trace_and_untrace_global_functions() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                let output = Port::open_output_string(mem)?;
+                t.set_output_port(output);
+
+                eval("(def last (l) (cond (nil? (cdr l)) (car l) true (last (cdr l))))")?;
+                eval("(def last-of-two (a b) (last (cons a (cons b nil))))")?;
+
+                // the recursive calls and a caller compiled before tracing are traced
+                assert!(eval("(trace 'last)")? == mem.lookup_sym("last"));
+                assert!(eval("(last-of-two 'a 'b)")? == mem.lookup_sym("b"));
+                assert!(output.output_string(mem)? == "(last (a b))\n  (last (b))\n  => b\n=> b\n");
+
+                // tracing twice does not trace twice
+                eval("(trace 'last)")?;
+                assert!(eval("(untrace 'last)")? == mem.lookup_sym("last"));
+                assert!(eval("(untrace 'last)")? == mem.nil());
+
+                let output = Port::open_output_string(mem)?;
+                t.set_output_port(output);
+                assert!(eval("(last-of-two 'a 'b)")? == mem.lookup_sym("b"));
+                assert!(output.output_string(mem)?.is_empty());
+
+                // natives, with their argument count checked
+                eval("(trace 'symbol->string)")?;
+                assert!(format!("{}", eval("(symbol->string 'x)")?) == "\"x\"");
+                assert!(eval("(symbol->string 'x 'y)").is_err());
+                assert!(eval("(trace 'print)").is_err());
+                assert!(eval("(trace 'stdout)").is_err());
+                assert!(eval("(trace 'unbound)").is_err());
+
+                // a name rebound while traced keeps its new binding
+                eval("(trace 'last-of-two)")?;
+                eval("(def last-of-two (a b) a)")?;
+                eval("(untrace 'last-of-two)")?;
+                assert!(eval("(last-of-two 'a 'b)")? == mem.lookup_sym("a"));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+}
@@ -1,19 +1,38 @@
-use std::cell::Cell;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use crate::array::{Array, ArraySize};
-use crate::bytecode::{ByteCode, InstructionStream, Opcode};
+use crate::builtins;
+use crate::bytecode::{ByteCode, InstructionStream, Opcode, ParamType, Register, SourceSpan};
 use crate::containers::{
     Container, FillAnyContainer, HashIndexedAnyContainer, IndexedAnyContainer, IndexedContainer,
     SliceableContainer, StackAnyContainer, StackContainer,
 };
+use crate::debug::{DebugAction, DebugHook};
 use crate::dict::Dict;
-use crate::error::{err_eval, RuntimeError};
+use crate::error::{err_eval, ErrorKind, RuntimeError};
 use crate::function::{Function, Partial};
+use crate::global::{bind_global, lookup_global_cell};
 use crate::list::List;
 use crate::memory::MutatorView;
-use crate::pair::Pair;
+use crate::native::NativeModule;
+use crate::number::{number_abs, number_min_max, Extreme};
+use crate::pair::{cons, pair_list_length, Pair};
+use crate::persistent::PersistentList;
+use crate::port::{flush_stdout, Port};
+use crate::printer::describe;
+#[cfg(feature = "vm-profile")]
+use crate::profile::OpcodeProfile;
+use crate::promise::Promise;
+use crate::random::XorShift;
 use crate::safeptr::{CellPtr, MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
-use crate::taggedptr::{TaggedPtr, Value};
+use crate::sandbox::Sandbox;
+use crate::taggedptr::{TaggedPtr, Value, INLINE_INTEGER_BITS};
 
 pub const RETURN_REG: usize = 0;
 pub const ENV_REG: usize = 1;
@@ -28,6 +47,38 @@ pub enum EvalStatus<'guard> {
     Return(TaggedScopedPtr<'guard>),
 }
 
+/// A copy of the register window of a call frame, for debuggers, from `Thread::register_window()`
+pub struct RegisterWindow<'guard> {
+    pub function: ScopedPtr<'guard, Function>,
+    /// Index of the instruction being executed, or that failed
+    pub ip: ArraySize,
+    /// The value of each register, starting with register 0
+    pub registers: Vec<TaggedScopedPtr<'guard>>,
+}
+
+/// A copy of a call frame of a failed evaluation, part of a `PostMortem`
+#[derive(Clone)]
+pub struct FrameSnapshot {
+    pub function: CellPtr<Function>,
+    /// Index of the instruction being executed, or that failed in the innermost frame
+    pub ip: ArraySize,
+    /// The instruction at `ip`
+    pub opcode: Option<Opcode>,
+    /// Copies of the values of the frame's registers, starting with register 0
+    pub registers: Vec<TaggedCellPtr>,
+}
+
+/// The state of an evaluation at the moment it failed, from `Thread::last_error()`
+#[derive(Clone)]
+pub struct PostMortem {
+    /// The error as it was displayed
+    pub message: String,
+    /// The error kind's code, see `ErrorKind::code()`
+    pub code: &'static str,
+    /// The call frames, innermost first
+    pub frames: Vec<FrameSnapshot>,
+}
+
 /// A call frame, separate from the register stack
 #[derive(Clone)]
 pub struct CallFrame {
@@ -36,19 +87,10 @@ pub struct CallFrame {
     /// Return IP when returning from a nested function call
     ip: Cell<ArraySize>,
     /// Stack base - index into the register stack where register window for this function begins
-    base: ArraySize,
+    base: Cell<ArraySize>,
 }
 
 impl CallFrame {
-    /// Instantiate an outer-level call frame at the beginning of the stack
-    pub fn new_main<'guard>(main_fn: ScopedPtr<'guard, Function>) -> CallFrame {
-        CallFrame {
-            function: CellPtr::new_with(main_fn),
-            ip: Cell::new(0),
-            base: 0,
-        }
-    }
-
     /// Instantiate a new stack frame for the given function, beginning execution at the given
     /// instruction pointer and a register window at `base`
     fn new<'guard>(
@@ -59,20 +101,133 @@ impl CallFrame {
         CallFrame {
             function: CellPtr::new_with(function),
             ip: Cell::new(ip),
-            base,
+            base: Cell::new(base),
         }
     }
 
+    /// Reinitialize a frame slot for a call to the given function
+    fn reuse<'guard>(&self, function: ScopedPtr<'guard, Function>, ip: ArraySize, base: ArraySize) {
+        self.function.set(function);
+        self.ip.set(ip);
+        self.base.set(base);
+    }
+
+    /// Return the source code span of the instruction this frame is executing, or will return
+    /// to, if known
+    pub fn source_span<'guard>(&self, guard: &'guard dyn MutatorScope) -> Option<SourceSpan> {
+        let ip = self.ip.get().checked_sub(1)?;
+        self.function.get(guard).code(guard).source_span(guard, ip)
+    }
+
     /// Return a string representation of this stack frame
     fn as_string<'guard>(&self, guard: &'guard dyn MutatorScope) -> String {
         let function = self.function.get(guard);
-        format!("in {}", function)
+        match (self.source_span(guard), function.file_name(guard)) {
+            (Some(span), Some(file_name)) => format!("in {} at {}, {}", function, file_name, span),
+            (Some(span), None) => format!("in {} at {}", function, span),
+            (None, _) => format!("in {}", function),
+        }
     }
 }
 
 /// Call frames are stored in a separate stack to the register window stack. This simplifies types
 /// and stack math.
-pub type CallFrameList = Array<CallFrame>;
+///
+/// Popped frames are not discarded: their slots are kept and reinitialized in place by later calls,
+/// so that the slot Array is only pushed to, and only reallocated, when calls nest deeper than
+/// they have before.
+pub struct CallFrameList {
+    /// Frame slots, the first `depth` of which are in use
+    slots: Array<CallFrame>,
+    /// Count of frames in use
+    depth: Cell<ArraySize>,
+}
+
+impl CallFrameList {
+    /// Allocate a new instance on the heap with the given count of preallocated frame slots
+    pub fn alloc_with_capacity<'guard>(
+        mem: &'guard MutatorView,
+        capacity: ArraySize,
+    ) -> Result<ScopedPtr<'guard, CallFrameList>, RuntimeError> {
+        mem.alloc(CallFrameList {
+            slots: Array::with_capacity(mem, capacity)?,
+            depth: Cell::new(0),
+        })
+    }
+
+    /// Return the count of frames in use
+    pub fn length(&self) -> ArraySize {
+        self.depth.get()
+    }
+
+    /// Push a frame for a call to the given function, with its register window at `base`,
+    /// reusing a slot if one is free
+    pub fn push<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        function: ScopedPtr<'guard, Function>,
+        base: ArraySize,
+    ) -> Result<(), RuntimeError> {
+        let depth = self.depth.get();
+
+        if depth < self.slots.length() {
+            self.slots.access_slice(mem, |_, slots| {
+                slots[depth as usize].reuse(function, 0, base)
+            });
+        } else {
+            self.slots.push(mem, CallFrame::new(function, 0, base))?;
+        }
+
+        self.depth.set(depth + 1);
+        Ok(())
+    }
+
+    /// Pop the top frame, leaving its slot free for reuse
+    pub fn pop(&self) -> Result<(), RuntimeError> {
+        match self.depth.get() {
+            0 => Err(RuntimeError::new(ErrorKind::BoundsError)),
+            depth => {
+                self.depth.set(depth - 1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Discard every frame
+    pub fn clear(&self) {
+        self.depth.set(0)
+    }
+
+    /// Return a copy of the frame at the given index, counting from the bottom of the stack
+    pub fn get<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+        index: ArraySize,
+    ) -> Result<CallFrame, RuntimeError> {
+        if index >= self.depth.get() {
+            return Err(RuntimeError::new(ErrorKind::BoundsError));
+        }
+        self.slots.get(guard, index)
+    }
+
+    /// Return a copy of the top frame
+    pub fn top<'guard>(&self, guard: &'guard dyn MutatorScope) -> Result<CallFrame, RuntimeError> {
+        match self.depth.get() {
+            0 => Err(RuntimeError::new(ErrorKind::BoundsError)),
+            depth => self.slots.get(guard, depth - 1),
+        }
+    }
+
+    /// Give a closure access to the frames in use as a slice, bottom of the stack first
+    pub fn access_slice<'guard, F, R>(&self, guard: &'guard dyn MutatorScope, f: F) -> R
+    where
+        F: FnOnce(&'guard dyn MutatorScope, &[CallFrame]) -> R,
+    {
+        let depth = self.depth.get() as usize;
+        self.slots
+            .access_slice(guard, |guard, slots| f(guard, &slots[..depth]))
+    }
+}
 
 /// A closure upvalue as generally described by Lua 5.1 implementation.
 /// There is one main difference - in the Lua (and Crafting Interpreters) documentation, an upvalue
@@ -166,6 +321,120 @@ fn env_upvalue_lookup<'guard>(
     }
 }
 
+/// Fetch the values of two registers that must both be inline integers. The operation name is
+/// used in the error message if either is some other type.
+fn integer_operands<'guard>(
+    guard: &'guard dyn MutatorScope,
+    window: &[TaggedCellPtr],
+    reg1: Register,
+    reg2: Register,
+    op_name: &str,
+) -> Result<(isize, isize), RuntimeError> {
+    match (
+        *window[reg1 as usize].get(guard),
+        *window[reg2 as usize].get(guard),
+    ) {
+        (Value::Number(a), Value::Number(b)) => Ok((a, b)),
+        (Value::Number(_), other) | (other, _) => Err(err_eval(&format!(
+            "Parameters to {} must be integers, got {}",
+            op_name,
+            describe(other)
+        ))),
+    }
+}
+
+/// Return the count of items of a Pair list, List, persistent list or persistent map
+fn list_length<'guard>(
+    guard: &'guard dyn MutatorScope,
+    value: TaggedScopedPtr<'guard>,
+) -> Result<usize, RuntimeError> {
+    match *value {
+        Value::Nil => Ok(0),
+        Value::Pair(_) => pair_list_length(guard, value),
+        Value::List(list) => Ok(list.length() as usize),
+        Value::PersistentList(list) => Ok(list.length() as usize),
+        Value::PersistentMap(map) => Ok(map.length() as usize),
+        other => Err(err_eval(&format!(
+            "Parameter to length is not a list: {}",
+            describe(other)
+        ))),
+    }
+}
+
+/// Return a new list of the items of a Pair list, List or persistent list in reverse order
+fn list_reverse<'guard>(
+    mem: &'guard MutatorView,
+    value: TaggedScopedPtr<'guard>,
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    match *value {
+        Value::Nil => Ok(value),
+
+        Value::Pair(_) => {
+            // the list is measured first so that a circular list is an error
+            let length = pair_list_length(mem, value)?;
+
+            let mut reversed = mem.nil();
+            let mut next = value;
+            for _ in 0..length {
+                if let Value::Pair(pair) = *next {
+                    reversed = cons(mem, pair.first.get(mem), reversed)?;
+                    next = pair.second.get(mem);
+                }
+            }
+            Ok(reversed)
+        }
+
+        Value::List(list) => {
+            let items: Vec<TaggedScopedPtr<'guard>> = list.access_slice(mem, |guard, items| {
+                items.iter().map(|item| item.get(guard)).collect()
+            });
+
+            let reversed = List::alloc_with_capacity(mem, list.length())?;
+            for item in items.into_iter().rev() {
+                StackAnyContainer::push(&*reversed, mem, item)?;
+            }
+            Ok(reversed.as_tagged(mem))
+        }
+
+        Value::PersistentList(list) => {
+            let mut items = list.items(mem);
+            items.reverse();
+            Ok(PersistentList::from_slice(mem, &items)?.as_tagged(mem))
+        }
+
+        other => Err(err_eval(&format!(
+            "Parameter to reverse is not a list: {}",
+            describe(other)
+        ))),
+    }
+}
+
+/// Validate a bit shift count: it must be non-negative and less than the inline integer width
+fn shift_count(count: isize) -> Result<u32, RuntimeError> {
+    if count < 0 || count >= INLINE_INTEGER_BITS as isize {
+        Err(err_eval(&format!(
+            "Shift count must be in the range 0..{}, got {}",
+            INLINE_INTEGER_BITS, count
+        )))
+    } else {
+        Ok(count as u32)
+    }
+}
+
+/// Convert a panic payload into an evaluation error. Payloads from `panic!` are a `&str` or a
+/// `String`.
+fn panic_error(payload: Box<dyn Any + Send>) -> RuntimeError {
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "unknown cause"
+    };
+
+    err_eval(&format!("Internal error, evaluation panicked: {}", message))
+}
+
 /// An execution Thread object.
 /// It is composed of all the data structures required for execution of a bytecode stream -
 /// register stack, call frames, closure upvalues, thread-local global associations and the current
@@ -180,10 +449,54 @@ pub struct Thread {
     upvalues: CellPtr<Dict>,
     /// A dict that should only contain Symbol keys but any type as values
     globals: CellPtr<Dict>,
+    /// The Port that print builtins write to
+    output: CellPtr<Port>,
+    /// Test functions defined with `deftest`, keyed by name
+    tests: CellPtr<Dict>,
+    /// Global functions wrapped by `trace`, keyed by name
+    traced: CellPtr<Dict>,
+    /// Count of traced calls this Thread is evaluating on behalf of, for indenting trace output
+    trace_depth: Cell<usize>,
+    /// Command line arguments given to the program, as a Pair list of Text
+    argv: TaggedCellPtr,
+    /// The point in time that the monotonic clock counts from
+    epoch: Instant,
+    /// Pseudo random number generator state
+    rng: Cell<XorShift>,
+    /// Set from any OS thread to stop evaluation at the next instruction batch boundary
+    interrupt: Arc<AtomicBool>,
+    /// Restrictions on what evaluated code may do
+    sandbox: Cell<Option<Sandbox>>,
+    /// Count of instructions executed by the current evaluation
+    executed: Cell<u64>,
+    /// Allocated bytes count when the current evaluation started
+    heap_base: Cell<usize>,
+    /// Evaluation event callbacks
+    debug_hook: RefCell<Option<Box<dyn DebugHook>>>,
+    /// Set when the debug hook paused evaluation, so that the paused instruction is not passed to
+    /// the hook a second time
+    hook_paused: Cell<bool>,
+    /// Keep the call frames and registers of a failed evaluation for inspection, see
+    /// `Thread::set_keep_failed_frames()`
+    keep_failed_frames: Cell<bool>,
+    /// Set when an evaluation failed and its frames were kept
+    failed: Cell<bool>,
+    /// The traceback lines of the last failed evaluation, when they are captured rather than
+    /// printed, see `Thread::set_capture_traceback()`
+    traceback: RefCell<Option<Vec<String>>>,
+    /// Snapshot of the last evaluation that failed while frames were kept. Unlike the frames it
+    /// survives later evaluations, so that it can be inspected by evaluated code.
+    post_mortem: RefCell<Option<PostMortem>>,
     /// The current instruction location
     instr: CellPtr<InstructionStream>,
     /// The current stack base pointer
     stack_base: Cell<ArraySize>,
+    /// The stack base of the first call frame of an evaluation: above the registers copied from
+    /// the parent of a nested Thread, zero otherwise
+    eval_base: Cell<ArraySize>,
+    /// Executed count and sampled time of each opcode
+    #[cfg(feature = "vm-profile")]
+    profile: RefCell<OpcodeProfile>,
 }
 
 impl Thread {
@@ -191,34 +504,428 @@ impl Thread {
     /// bytecode yet.
     pub fn alloc<'guard>(
         mem: &'guard MutatorView,
+    ) -> Result<ScopedPtr<'guard, Thread>, RuntimeError> {
+        // create a globals dict holding the builtin bindings, with the standard output port
+        // bound as the default print destination
+        let globals = Dict::alloc(mem)?;
+        let output = Port::alloc_stdout(mem)?;
+        builtins::register(mem, globals, output)?;
+
+        let tests = Dict::alloc(mem)?;
+        let traced = Dict::alloc(mem)?;
+        let interrupt = Arc::new(AtomicBool::new(false));
+
+        Thread::alloc_with(mem, globals, output, tests, traced, interrupt)
+    }
+
+    /// Allocate a Thread that shares this Thread's globals, output Port, tests, traced functions,
+    /// command line arguments, interrupt flag and sandbox. A native function can evaluate code on the new
+    /// Thread while this one is busy calling it.
+    pub fn alloc_child<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<ScopedPtr<'guard, Thread>, RuntimeError> {
+        let child = Thread::alloc_with(
+            mem,
+            self.globals.get(mem),
+            self.output.get(mem),
+            self.tests.get(mem),
+            self.traced.get(mem),
+            self.interrupt.clone(),
+        )?;
+
+        child.argv.set(self.argv.get(mem));
+        child.sandbox.set(self.sandbox.get());
+        child.trace_depth.set(self.trace_depth.get());
+
+        Ok(child)
+    }
+
+    /// Allocate a child Thread, as `alloc_child()`, that can also call closures made by this
+    /// Thread. Closures refer to the variables they capture by stack location until the variables
+    /// go out of scope, so the child starts with a copy of this Thread's stack and evaluates above
+    /// it. Locals cannot be assigned to, so the copy sees the same values as this Thread.
+    pub fn alloc_nested<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<ScopedPtr<'guard, Thread>, RuntimeError> {
+        let child = self.alloc_child(mem)?;
+
+        let stack = List::alloc_clone(mem, self.stack.get(mem))?;
+        child.eval_base.set(stack.length());
+        child.stack.set(stack);
+
+        Ok(child)
+    }
+
+    fn alloc_with<'guard>(
+        mem: &'guard MutatorView,
+        globals: ScopedPtr<'guard, Dict>,
+        output: ScopedPtr<'guard, Port>,
+        tests: ScopedPtr<'guard, Dict>,
+        traced: ScopedPtr<'guard, Dict>,
+        interrupt: Arc<AtomicBool>,
     ) -> Result<ScopedPtr<'guard, Thread>, RuntimeError> {
         // create an empty stack frame array
         let frames = CallFrameList::alloc_with_capacity(mem, 16)?;
 
-        // create a minimal value stack
+        // create an empty value stack, it grows as functions are called
         let stack = List::alloc_with_capacity(mem, 256)?;
-        stack.fill(mem, 256, mem.nil())?;
 
         // create an empty upvalue stack->heap mapping
         let upvalues = Dict::alloc(mem)?;
 
-        // create an empty globals dict
-        let globals = Dict::alloc(mem)?;
-
         // create an empty instruction stream
         let blank_code = ByteCode::alloc(mem)?;
         let instr = InstructionStream::alloc(mem, blank_code)?;
 
+        // seed the random number generator from the wall clock
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
         mem.alloc(Thread {
             frames: CellPtr::new_with(frames),
             stack: CellPtr::new_with(stack),
             upvalues: CellPtr::new_with(upvalues),
             globals: CellPtr::new_with(globals),
+            output: CellPtr::new_with(output),
+            tests: CellPtr::new_with(tests),
+            traced: CellPtr::new_with(traced),
+            trace_depth: Cell::new(0),
+            argv: TaggedCellPtr::new_nil(),
+            epoch: Instant::now(),
+            rng: Cell::new(XorShift::new(seed)),
+            interrupt,
+            sandbox: Cell::new(None),
+            executed: Cell::new(0),
+            heap_base: Cell::new(0),
+            debug_hook: RefCell::new(None),
+            hook_paused: Cell::new(false),
+            keep_failed_frames: Cell::new(false),
+            failed: Cell::new(false),
+            traceback: RefCell::new(None),
+            post_mortem: RefCell::new(None),
             instr: CellPtr::new_with(instr),
             stack_base: Cell::new(0),
+            eval_base: Cell::new(0),
+            #[cfg(feature = "vm-profile")]
+            profile: RefCell::new(OpcodeProfile::new()),
+        })
+    }
+
+    /// Bind each function of a native module into this Thread's globals
+    pub fn bind_native_module(
+        &self,
+        mem: &MutatorView,
+        module: &NativeModule,
+    ) -> Result<(), RuntimeError> {
+        module.bind(mem, self.globals.get(mem))
+    }
+
+    /// Return the Port that print builtins write to
+    pub fn output_port<'guard>(&self, guard: &'guard dyn MutatorScope) -> ScopedPtr<'guard, Port> {
+        self.output.get(guard)
+    }
+
+    /// Redirect print builtin output to the given Port
+    pub fn set_output_port(&self, port: ScopedPtr<'_, Port>) {
+        self.output.set(port);
+    }
+
+    /// Return the value bound to a global name, if there is one
+    pub fn lookup_global<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        name: &str,
+    ) -> Option<TaggedScopedPtr<'guard>> {
+        lookup_global_cell(mem, self.globals.get(mem), mem.lookup_sym(name))
+            .ok()
+            .flatten()
+            .map(|cell| cell.get(mem))
+    }
+
+    /// Bind a value to a global name
+    pub fn set_global(
+        &self,
+        mem: &MutatorView,
+        name: &str,
+        value: TaggedScopedPtr<'_>,
+    ) -> Result<(), RuntimeError> {
+        let globals = self.globals.get(mem);
+        bind_global(mem, globals, mem.lookup_sym(name), value)?;
+        Ok(())
+    }
+
+    /// Return the dict of global bindings, mapping each Symbol to its GlobalCell
+    pub fn globals<'guard>(&self, guard: &'guard dyn MutatorScope) -> ScopedPtr<'guard, Dict> {
+        self.globals.get(guard)
+    }
+
+    /// Return the test functions defined with `deftest`, keyed by name
+    pub fn tests<'guard>(&self, guard: &'guard dyn MutatorScope) -> ScopedPtr<'guard, Dict> {
+        self.tests.get(guard)
+    }
+
+    /// Return the global functions wrapped by `trace`, keyed by name
+    pub fn traced<'guard>(&self, guard: &'guard dyn MutatorScope) -> ScopedPtr<'guard, Dict> {
+        self.traced.get(guard)
+    }
+
+    /// Return the count of traced calls this Thread is evaluating on behalf of
+    pub fn trace_depth(&self) -> usize {
+        self.trace_depth.get()
+    }
+
+    /// Set the count of traced calls this Thread is evaluating on behalf of
+    pub fn set_trace_depth(&self, depth: usize) {
+        self.trace_depth.set(depth)
+    }
+
+    /// Return the source code span of the instruction being executed, such as a call to a native
+    /// function, if known
+    pub fn current_source_span(&self, guard: &dyn MutatorScope) -> Option<SourceSpan> {
+        let frame = self.frames.get(guard).top(guard).ok()?;
+        let ip = self.instr.get(guard).get_next_ip().checked_sub(1)?;
+        frame.function.get(guard).code(guard).source_span(guard, ip)
+    }
+
+    /// Return the count of call frames of the current evaluation
+    pub fn stack_depth(&self, guard: &dyn MutatorScope) -> ArraySize {
+        self.frames.get(guard).length()
+    }
+
+    /// Return the Function, index of the instruction being executed and its source code span, if
+    /// known, of each call frame of the current evaluation, innermost first
+    pub fn backtrace<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+    ) -> Vec<(ScopedPtr<'guard, Function>, ArraySize, Option<SourceSpan>)> {
+        let current_frame_ip = self.instr.get(guard).get_next_ip();
+
+        self.frames.get(guard).access_slice(guard, |guard, frames| {
+            frames
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(index, frame)| {
+                    // frames record the instruction to return to, the top frame's is in the
+                    // instruction stream
+                    let ip = if index + 1 == frames.len() {
+                        current_frame_ip
+                    } else {
+                        frame.ip.get()
+                    };
+
+                    let function = frame.function.get(guard);
+                    let instruction = ip.saturating_sub(1);
+                    let span = function.code(guard).source_span(guard, instruction);
+                    (function, instruction, span)
+                })
+                .collect()
+        })
+    }
+
+    /// Return the register window of the innermost call frame of the current evaluation, which may
+    /// be paused, or of an evaluation that failed and whose frames were kept
+    pub fn register_window<'guard>(
+        &self,
+        guard: &'guard dyn MutatorScope,
+    ) -> Option<RegisterWindow<'guard>> {
+        let frame = self.frames.get(guard).top(guard).ok()?;
+        let function = frame.function.get(guard);
+        let ip = self.instr.get(guard).get_next_ip().saturating_sub(1);
+
+        let base = frame.base.get() as usize;
+        let registers = self.stack.get(guard).access_slice(guard, |guard, stack| {
+            let end = (base + function.max_registers() as usize).min(stack.len());
+            stack[base.min(end)..end]
+                .iter()
+                .map(|register| register.get(guard))
+                .collect()
+        });
+
+        Some(RegisterWindow {
+            function,
+            ip,
+            registers,
         })
     }
 
+    /// Return the command line arguments given to the program as a Pair list of Text
+    pub fn argv<'guard>(&self, guard: &'guard dyn MutatorScope) -> TaggedScopedPtr<'guard> {
+        self.argv.get(guard)
+    }
+
+    /// Set the command line arguments returned by `(argv)`
+    pub fn set_argv(&self, mem: &MutatorView, args: &[String]) -> Result<(), RuntimeError> {
+        let mut list = mem.nil();
+        for arg in args.iter().rev() {
+            list = cons(mem, mem.text(arg)?, list)?;
+        }
+
+        self.argv.set(list);
+        Ok(())
+    }
+
+    /// Return the monotonic clock time in nanoseconds since the Thread was created
+    pub fn clock_ns(&self) -> isize {
+        self.epoch.elapsed().as_nanos() as isize
+    }
+
+    /// Return a pseudo random number in the range 0..bound. The bound must be greater than zero.
+    pub fn random_below(&self, bound: u64) -> u64 {
+        let mut rng = self.rng.get();
+        let value = rng.below(bound);
+        self.rng.set(rng);
+        value
+    }
+
+    /// Reset the pseudo random number generator so that it produces a repeatable sequence
+    pub fn random_seed(&self, seed: u64) {
+        self.rng.set(XorShift::new(seed));
+    }
+
+    /// Return a handle to the interrupt flag. Setting it to true, for example from a Ctrl-C
+    /// handler, stops evaluation with an Interrupted error.
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Restrict what code evaluated on this Thread may do, or lift all restrictions with `None`
+    pub fn set_sandbox(&self, sandbox: Option<Sandbox>) {
+        self.sandbox.set(sandbox);
+    }
+
+    /// Return the current sandbox restrictions, if any
+    pub fn sandbox(&self) -> Option<Sandbox> {
+        self.sandbox.get()
+    }
+
+    /// Register a debug hook, or remove it with `None`, returning the previous hook. The hook
+    /// cannot be changed by a native function during evaluation.
+    pub fn set_debug_hook(&self, hook: Option<Box<dyn DebugHook>>) -> Option<Box<dyn DebugHook>> {
+        self.debug_hook.replace(hook)
+    }
+
+    /// Keep, or stop keeping, the call frames and registers of an evaluation that fails, so that
+    /// they can be inspected with `backtrace()` and `register_window()` after the error has been
+    /// returned. They are discarded when the next evaluation starts or the Thread is reset, but a
+    /// snapshot of them is kept until the next failure, see `last_error()`.
+    pub fn set_keep_failed_frames(&self, keep: bool) {
+        self.keep_failed_frames.set(keep);
+    }
+
+    /// Capture the traceback of an evaluation that fails instead of printing it, for when standard
+    /// output is not the place to report errors. See `take_traceback()`.
+    pub fn set_capture_traceback(&self, capture: bool) {
+        *self.traceback.borrow_mut() = match capture {
+            true => Some(Vec::new()),
+            false => None,
+        };
+    }
+
+    /// Return the captured traceback of the last failed evaluation, leaving it empty. There is
+    /// none if the error was not multiple call frames deep.
+    pub fn take_traceback(&self) -> Vec<String> {
+        self.traceback
+            .borrow_mut()
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Return true if the last evaluation failed and its frames were kept
+    pub fn has_failed(&self) -> bool {
+        self.failed.get()
+    }
+
+    /// Return the snapshot of the last evaluation that failed while failed frames were kept
+    pub fn last_error(&self) -> Option<PostMortem> {
+        self.post_mortem.borrow().clone()
+    }
+
+    /// Copy the call frames, registers and failing instruction of an evaluation that has just
+    /// failed. The top frame's ip must already record the failing instruction.
+    fn post_mortem(&self, guard: &dyn MutatorScope, error: &RuntimeError) -> PostMortem {
+        let stack = self.stack.get(guard);
+
+        let frames = self.frames.get(guard).access_slice(guard, |guard, frames| {
+            frames
+                .iter()
+                .rev()
+                .map(|frame| {
+                    let function = frame.function.get(guard);
+                    let ip = frame.ip.get().saturating_sub(1);
+                    let opcode = function
+                        .code(guard)
+                        .opcodes(guard)
+                        .get(ip as usize)
+                        .copied();
+
+                    let base = frame.base.get() as usize;
+                    let registers = stack.access_slice(guard, |guard, stack| {
+                        let end = (base + function.max_registers() as usize).min(stack.len());
+                        stack[base.min(end)..end]
+                            .iter()
+                            .map(|register| TaggedCellPtr::new_with(register.get(guard)))
+                            .collect()
+                    });
+
+                    FrameSnapshot {
+                        function: CellPtr::new_with(function),
+                        ip,
+                        opcode,
+                        registers,
+                    }
+                })
+                .collect()
+        });
+
+        PostMortem {
+            message: format!("{}", error),
+            code: error.error_kind().code(),
+            frames,
+        }
+    }
+
+    /// Return the per-opcode cost profile of every instruction this Thread has executed, other
+    /// than those executed with a debug hook registered
+    #[cfg(feature = "vm-profile")]
+    pub fn opcode_profile(&self) -> &RefCell<OpcodeProfile> {
+        &self.profile
+    }
+
+    /// Return an error if the sandbox forbids I/O, naming what was attempted
+    pub fn check_io_allowed(&self, what: &str) -> Result<(), RuntimeError> {
+        match self.sandbox.get() {
+            Some(sandbox) if !sandbox.io_allowed() => Err(err_eval(&format!(
+                "{} is not available in the sandbox",
+                what
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the Thread to an idle state, ready to evaluate a new Function: all call frames, open
+    /// upvalues and register values are discarded. Globals are kept.
+    pub fn reset(&self, mem: &MutatorView) -> Result<(), RuntimeError> {
+        self.frames.get(mem).clear();
+        self.upvalues.get(mem).clear(mem)?;
+
+        self.stack.get(mem).clear(mem)?;
+        self.stack_base.set(0);
+        self.eval_base.set(0);
+        self.hook_paused.set(false);
+        self.failed.set(false);
+
+        let blank_code = ByteCode::alloc(mem)?;
+        self.instr.get(mem).switch_frame(blank_code, 0);
+
+        Ok(())
+    }
+
     /// Retrieve an Upvalue for the given absolute stack offset.
     fn upvalue_lookup<'guard>(
         &self,
@@ -257,7 +964,7 @@ impl Thread {
                 let upvalues = self.upvalues.get(mem);
                 let upvalue = Upvalue::alloc(mem, location)?;
 
-                let location_ptr = TaggedScopedPtr::new(mem, TaggedPtr::number(location as isize));
+                let location_ptr = mem.number(location as isize);
                 upvalues.assoc(mem, location_ptr, upvalue.as_tagged(mem))?;
 
                 Ok((location_ptr, upvalue))
@@ -265,6 +972,28 @@ impl Thread {
         }
     }
 
+    /// Grow the stack, if necessary, to hold the register window of the Function or Partial in
+    /// register `function` when it is called with its window starting at register `dest`
+    fn reserve_call_window(
+        &self,
+        mem: &MutatorView,
+        function: Register,
+        dest: Register,
+    ) -> Result<(), RuntimeError> {
+        let stack = self.stack.get(mem);
+        let base = self.stack_base.get();
+
+        let callee = IndexedContainer::get(&*stack, mem, base + function as ArraySize)?;
+        let window = match *callee.get(mem) {
+            Value::Function(f) => f.max_registers(),
+            Value::Partial(p) => p.function(mem).max_registers(),
+            _ => return Ok(()),
+        };
+
+        // TODO reset to nil to avoid accidental leakage of previous call values
+        stack.fill(mem, base + dest as ArraySize + window, mem.nil())
+    }
+
     /// Execute the next instruction in the current instruction stream
     fn eval_next_instr<'guard>(
         &self,
@@ -277,10 +1006,19 @@ impl Thread {
         let globals = self.globals.get(mem);
         let instr = self.instr.get(mem);
 
-        // Establish a 256-register window into the stack from the stack base
-        stack.access_slice(mem, |full_stack| {
+        // The stack cannot grow while it is accessed as a slice, so make room for the register
+        // window of a function about to be called first
+        if let Opcode::Call { function, dest, .. } = instr.peek_next_opcode(mem)? {
+            self.reserve_call_window(mem, function, dest)?;
+        }
+
+        // Establish a register window into the stack from the stack base. The stack always
+        // extends at least as far as the current function's register count from the base.
+        // Opcode handlers may allocate, which never moves the stack, but must not grow the stack:
+        // that is done above, before the window is taken.
+        stack.access_slice(mem, |_, full_stack| {
             let stack_base = self.stack_base.get() as usize;
-            let window = &mut full_stack[stack_base..stack_base + 256];
+            let window = &mut full_stack[stack_base..];
 
             // Fetch the next instruction and identify it
             let opcode = instr.get_next_opcode(mem)?;
@@ -298,7 +1036,7 @@ impl Thread {
                     window[RETURN_REG].set_to_ptr(result);
 
                     // remove this function's stack frame
-                    frames.pop(mem)?;
+                    frames.pop()?;
 
                     // if we just returned from the last stack frame, program evaluation is complete
                     if frames.length() == 0 {
@@ -306,15 +1044,14 @@ impl Thread {
                     } else {
                         // otherwise restore the previous stack frame settings
                         let frame = frames.top(mem)?;
-                        self.stack_base.set(frame.base);
+                        self.stack_base.set(frame.base.get());
                         instr.switch_frame(frame.function.get(mem).code(mem), frame.ip.get());
                     }
                 }
 
-                // Load a literal into a register from the function literals array
+                // Load a literal into a register from the function literals list
                 Opcode::LoadLiteral { dest, literal_id } => {
-                    let literal_ptr = instr.get_literal(mem, literal_id)?;
-                    window[dest as usize].set_to_ptr(literal_ptr);
+                    window[dest as usize].set(instr.code(mem).literal(mem, literal_id)?);
                 }
 
                 // Evaluate whether the `test` register contains `nil` - if so, set the `dest`
@@ -323,7 +1060,7 @@ impl Thread {
                     let test_val = window[test as usize].get(mem);
 
                     match *test_val {
-                        Value::Nil => window[dest as usize].set(mem.lookup_sym("true")),
+                        Value::Nil => window[dest as usize].set(mem.sym_true()),
                         _ => window[dest as usize].set_to_nil(),
                     }
                 }
@@ -337,7 +1074,18 @@ impl Thread {
                         Value::Pair(_) => window[dest as usize].set_to_nil(),
                         Value::Nil => window[dest as usize].set_to_nil(),
                         // TODO what other types?
-                        _ => window[dest as usize].set(mem.lookup_sym("true")),
+                        _ => window[dest as usize].set(mem.sym_true()),
+                    }
+                }
+
+                // Evaluate whether the `test` register contains a Pair. Set the `dest` register to
+                // "true" or `nil`.
+                Opcode::IsPair { dest, test } => {
+                    let test_val = window[test as usize].get(mem);
+
+                    match *test_val {
+                        Value::Pair(_) => window[dest as usize].set(mem.sym_true()),
+                        _ => window[dest as usize].set_to_nil(),
                     }
                 }
 
@@ -348,7 +1096,12 @@ impl Thread {
                     match *reg_val {
                         Value::Pair(p) => window[dest as usize].set_to_ptr(p.first.get_ptr()),
                         Value::Nil => window[dest as usize].set_to_nil(),
-                        _ => return Err(err_eval("Parameter to FirstOfPair is not a list")),
+                        other => {
+                            return Err(err_eval(&format!(
+                                "Parameter to FirstOfPair is not a list: {}",
+                                describe(other)
+                            )))
+                        }
                     }
                 }
 
@@ -359,7 +1112,51 @@ impl Thread {
                     match *reg_val {
                         Value::Pair(p) => window[dest as usize].set_to_ptr(p.second.get_ptr()),
                         Value::Nil => window[dest as usize].set_to_nil(),
-                        _ => return Err(err_eval("Parameter to SecondOfPair is not a list")),
+                        other => {
+                            return Err(err_eval(&format!(
+                                "Parameter to SecondOfPair is not a list: {}",
+                                describe(other)
+                            )))
+                        }
+                    }
+                }
+
+                // Count the items of a list, in `dest`
+                Opcode::ListLength { dest, reg } => {
+                    let length = list_length(mem, window[reg as usize].get(mem))?;
+                    window[dest as usize].set(mem.number(length as isize));
+                }
+
+                // Copy a list in reverse order into `dest`
+                Opcode::ListReverse { dest, reg } => {
+                    let reversed = list_reverse(mem, window[reg as usize].get(mem))?;
+                    window[dest as usize].set(reversed);
+                }
+
+                // Destructure - split the Pair in `src` into `first` and `second`, failing if `src`
+                // is not a Pair
+                Opcode::UnpackPair { first, second, src } => {
+                    let src_val = window[src as usize].get(mem);
+
+                    match *src_val {
+                        Value::Pair(p) => {
+                            window[first as usize].set_to_ptr(p.first.get_ptr());
+                            window[second as usize].set_to_ptr(p.second.get_ptr());
+                        }
+                        Value::Nil => return Err(err_eval("Not enough values to destructure")),
+                        _ => {
+                            return Err(err_eval(&format!(
+                                "Cannot destructure {}, it is not a list",
+                                describe(*src_val)
+                            )))
+                        }
+                    }
+                }
+
+                // Destructure - fail if the remainder of a list in `test` is not empty
+                Opcode::ExpectNil { test } => {
+                    if window[test as usize].get(mem) != mem.nil() {
+                        return Err(err_eval("Too many values to destructure"));
                     }
                 }
 
@@ -383,7 +1180,7 @@ impl Thread {
                     let test2_val = window[test2 as usize].get_ptr();
 
                     if test1_val == test2_val {
-                        window[dest as usize].set(mem.lookup_sym("true"));
+                        window[dest as usize].set(mem.sym_true());
                     } else {
                         window[dest as usize].set(mem.nil());
                     }
@@ -398,7 +1195,7 @@ impl Thread {
                 Opcode::JumpIfTrue { test, offset } => {
                     let test_val = window[test as usize].get(mem);
 
-                    let true_sym = mem.lookup_sym("true"); // TODO preload keyword syms
+                    let true_sym = mem.sym_true();
 
                     if test_val == true_sym {
                         instr.jump(offset)
@@ -409,7 +1206,7 @@ impl Thread {
                 Opcode::JumpIfNotTrue { test, offset } => {
                     let test_val = window[test as usize].get(mem);
 
-                    let true_sym = mem.lookup_sym("true");
+                    let true_sym = mem.sym_true();
 
                     if test_val != true_sym {
                         instr.jump(offset)
@@ -421,48 +1218,90 @@ impl Thread {
                     window[dest as usize].set_to_nil();
                 }
 
+                // Set the register `dest` to the symbol "true"
+                Opcode::LoadTrue { dest } => {
+                    window[dest as usize].set(mem.sym_true());
+                }
+
                 // Set the register `dest` to the inline integer literal
                 Opcode::LoadInteger { dest, integer } => {
                     let tagged_ptr = TaggedPtr::literal_integer(integer);
                     window[dest as usize].set_to_ptr(tagged_ptr);
                 }
 
-                // Lookup a global binding and put it in the register `dest`
+                // Lookup a global binding and put it in the register `dest`. The GlobalCell found
+                // is cached for this instruction, and used again while it binds the same name in
+                // this Thread's globals.
                 Opcode::LoadGlobal { dest, name } => {
                     let name_val = window[name as usize].get(mem);
-
-                    if let Value::Symbol(_) = *name_val {
-                        let lookup_result = globals.lookup(mem, name_val);
-
-                        match lookup_result {
-                            Ok(binding) => window[dest as usize].set(binding),
-                            Err(_) => {
+                    let code = instr.code(mem);
+                    let ip = instr.get_next_ip() - 1;
+
+                    let cell = match code.cached_global(mem, ip) {
+                        Some(cell) if cell.binds(mem, &globals, name_val) => cell,
+
+                        _ => {
+                            if let Value::Symbol(_) = *name_val {
+                                match lookup_global_cell(mem, globals, name_val)? {
+                                    Some(cell) => {
+                                        code.cache_global(mem, ip, cell)?;
+                                        cell
+                                    }
+                                    None => {
+                                        return Err(err_eval(&format!(
+                                            "Symbol {} is not bound to a value",
+                                            name_val
+                                        )))
+                                    }
+                                }
+                            } else {
                                 return Err(err_eval(&format!(
-                                    "Symbol {} is not bound to a value",
-                                    name_val
-                                )))
+                                    "Cannot lookup global for non-symbol type: {}",
+                                    describe(*name_val)
+                                )));
                             }
                         }
-                    } else {
-                        return Err(err_eval("Cannot lookup global for non-symbol type"));
-                    }
+                    };
+
+                    window[dest as usize].set(cell.get(mem));
                 }
 
-                // Bind a symbol to the `src` register in the globals dict
+                // Bind a symbol to the `src` register in the globals dict, updating the
+                // GlobalCell of a symbol that is already bound
                 Opcode::StoreGlobal { src, name } => {
+                    if let Some(sandbox) = self.sandbox.get() {
+                        if sandbox.globals_read_only() {
+                            return Err(err_eval("Globals are read-only in the sandbox"));
+                        }
+                    }
+
                     let name_val = window[name as usize].get(mem);
-                    if let Value::Symbol(_) = *name_val {
+                    if name_val == mem.sym_true() || name_val == mem.sym_nil() {
+                        return Err(err_eval(&format!(
+                            "Cannot bind the constant {} as a global",
+                            name_val
+                        )));
+                    } else if let Value::Symbol(s) = *name_val {
+                        if s.is_keyword(mem) {
+                            return Err(err_eval(&format!(
+                                "Cannot bind the keyword {} as a global",
+                                name_val
+                            )));
+                        }
                         let src_val = window[src as usize].get(mem);
-                        globals.assoc(mem, name_val, src_val)?;
+                        bind_global(mem, globals, name_val, src_val)?;
                     } else {
-                        return Err(err_eval("Cannot bind global to non-symbol type"));
+                        return Err(err_eval(&format!(
+                            "Cannot bind global to non-symbol type: {}",
+                            describe(*name_val)
+                        )));
                     }
                 }
 
                 // Call the function referred to by the `function` register, put the result in the
                 // `dest` register.
                 //
-                // The function can be a Function object or a Partial.
+                // The function can be a Function object, a Partial or a NativeFunction.
                 //
                 // If the arg_count is less than the function arity, return a Partial instead of
                 // entering the function.
@@ -481,7 +1320,7 @@ impl Thread {
                     let new_call_frame = |function| -> Result<(), RuntimeError> {
                         // Modify the current call frame, saving the return ip
                         let current_frame_ip = instr.get_next_ip();
-                        frames.access_slice(mem, |f| {
+                        frames.access_slice(mem, |_, f| {
                             f.last()
                                 .expect("No CallFrames in slice!")
                                 .ip
@@ -490,20 +1329,13 @@ impl Thread {
 
                         // Create a new call frame, pushing it to the frame stack
                         let new_stack_base = self.stack_base.get() + dest as ArraySize;
-                        let frame = CallFrame::new(function, 0, new_stack_base);
-                        frames.push(mem, frame)?;
+                        frames.push(mem, function, new_stack_base)?;
 
                         // Update the instruction stream to point to the new function
                         let code = function.code(mem);
                         self.stack_base.set(new_stack_base);
                         instr.switch_frame(code, 0);
 
-                        // Ensure the stack has 256 registers allocated
-                        // TODO reset to nil to avoid accidental leakage of previous call values
-                        // TODO Ruh-roh we shouldn't be able to modify the stack size from
-                        // within an access_slice() call :grimace:
-                        stack.fill(mem, new_stack_base + 256, mem.nil())?;
-
                         Ok(())
                     };
 
@@ -577,31 +1409,57 @@ impl Thread {
                             // Copy closure env pointer
                             window[dest as usize + ENV_REG] = partial.closure_env();
 
-                            // Shunt _call_ args back into the window to make space for the
-                            // partially applied args
-                            let push_dist = partial.used();
-                            let from_reg = dest as usize + FIRST_ARG_REG;
-                            let to_reg = from_reg + push_dist as usize;
-                            for index in (0..arg_count as usize).rev() {
-                                window[to_reg + index] = window[from_reg + index].clone();
+                            // A closure made by MakeClosure has no applied args, so the call args
+                            // are already where the function expects them
+                            let push_dist = partial.used() as usize;
+                            if push_dist > 0 {
+                                // Rotate _call_ args back into the window in one pass to make
+                                // space for the partially applied args
+                                let start_reg = dest as usize + FIRST_ARG_REG;
+                                let end_reg = start_reg + push_dist + arg_count as usize;
+                                window[start_reg..end_reg].rotate_right(push_dist);
+
+                                // copy args from Partial to the register window
+                                let args = partial.args(mem);
+                                args.access_slice(mem, |_, items| {
+                                    for (reg, item) in window[start_reg..].iter().zip(items) {
+                                        reg.set_to_ptr(item.get_ptr());
+                                    }
+                                });
                             }
 
-                            // copy args from Partial to the register window
-                            let args = partial.args(mem);
-                            let start_reg = dest as usize + FIRST_ARG_REG;
-                            args.access_slice(mem, |items| {
-                                for (index, item) in items.iter().enumerate() {
-                                    window[start_reg + index] = item.clone();
-                                }
-                            });
-
                             new_call_frame(partial.function(mem))?;
                         }
 
-                        _ => return Err(err_eval("Type is not callable")),
+                        // Native functions are called directly with the argument registers and
+                        // do not need a call frame
+                        Value::NativeFunction(native) => {
+                            if native.is_io() {
+                                self.check_io_allowed(&format!("Function {}", native.name()))?;
+                            }
+
+                            let args_start = dest as usize + FIRST_ARG_REG;
+                            let args_end = args_start + arg_count as usize;
+
+                            let result = native.call(mem, self, &window[args_start..args_end])?;
+                            window[dest as usize].set(result);
+                        }
+
+                        other => {
+                            return Err(err_eval(&format!(
+                                "Type is not callable: {}",
+                                describe(other)
+                            )))
+                        }
                     }
                 }
 
+                // Wrap a function of no parameters in a Promise of the value it returns
+                Opcode::MakePromise { dest, thunk } => {
+                    let promise = Promise::alloc(mem, window[thunk as usize].get(mem))?;
+                    window[dest as usize].set(promise.as_tagged(mem));
+                }
+
                 // This operation should be generated by the compiler after a function definition
                 // inside another function but only if the nested function refers to nonlocal
                 // variables.
@@ -618,29 +1476,31 @@ impl Thread {
                         let nonlocals = f.nonlocals(mem);
                         let env = List::alloc_with_capacity(mem, nonlocals.length())?;
 
-                        // Iter over function nonlocals, calculating absolute stack offset for each
-                        nonlocals.access_slice(mem, |nonlocals| -> Result<(), RuntimeError> {
-                            for compound in nonlocals {
-                                let frame_offset = (*compound >> 8) as ArraySize;
-                                let window_offset = (*compound & 0xff) as ArraySize;
-
-                                // look back frame_offset frames and add the register number
-                                let frame = frames.get(mem, frames.length() - frame_offset)?;
-                                let location = frame.base + window_offset;
+                        // Iter over function nonlocals, calculating absolute stack offset for each.
+                        // Upvalues are allocated, so the nonlocals are copied out of their array
+                        // rather than iterated over in place.
+                        let compounds = nonlocals.access_slice(mem, |_, slice| slice.to_vec());
+                        for compound in compounds {
+                            let frame_offset = (compound >> 8) as ArraySize;
+                            let window_offset = (compound & 0xff) as ArraySize;
 
-                                let (_, upvalue) = self.upvalue_lookup_or_alloc(mem, location)?;
-                                StackAnyContainer::push(&*env, mem, upvalue.as_tagged(mem))?;
-                            }
+                            // look back frame_offset frames and add the register number
+                            let frame = frames.get(mem, frames.length() - frame_offset)?;
+                            let location = frame.base.get() + window_offset;
 
-                            Ok(())
-                        })?;
+                            let (_, upvalue) = self.upvalue_lookup_or_alloc(mem, location)?;
+                            StackAnyContainer::push(&*env, mem, upvalue.as_tagged(mem))?;
+                        }
 
                         // Instantiate a Partial function application from the closure environment
                         // and set the destination register
                         let partial = Partial::alloc(mem, f, Some(env), &[])?;
                         window[dest as usize].set(partial.as_tagged(mem));
                     } else {
-                        return Err(err_eval("Cannot make a closure from a non-Function type"));
+                        return Err(err_eval(&format!(
+                            "Cannot make a closure from a non-Function type: {}",
+                            describe(*function_ptr)
+                        )));
                     }
                 }
 
@@ -661,6 +1521,63 @@ impl Thread {
                 // TODO
                 Opcode::DivideInteger { dest, num, denom } => unimplemented!(),
 
+                // Bitwise AND of two inline integers
+                Opcode::BitAnd { dest, reg1, reg2 } => {
+                    let (a, b) = integer_operands(mem, window, reg1, reg2, "bit-and")?;
+                    window[dest as usize].set_to_ptr(TaggedPtr::number(a & b));
+                }
+
+                // Bitwise OR of two inline integers
+                Opcode::BitOr { dest, reg1, reg2 } => {
+                    let (a, b) = integer_operands(mem, window, reg1, reg2, "bit-or")?;
+                    window[dest as usize].set_to_ptr(TaggedPtr::number(a | b));
+                }
+
+                // Bitwise XOR of two inline integers
+                Opcode::BitXor { dest, reg1, reg2 } => {
+                    let (a, b) = integer_operands(mem, window, reg1, reg2, "bit-xor")?;
+                    window[dest as usize].set_to_ptr(TaggedPtr::number(a ^ b));
+                }
+
+                // Shift an inline integer left. Bits shifted beyond the inline integer range are
+                // lost, see `TaggedPtr::number()`
+                Opcode::ShiftLeft { dest, value, count } => {
+                    let (value, count) = integer_operands(mem, window, value, count, "shl")?;
+                    let count = shift_count(count)?;
+                    window[dest as usize].set_to_ptr(TaggedPtr::number(value << count));
+                }
+
+                // Arithmetic (sign-preserving) right shift of an inline integer
+                Opcode::ShiftRight { dest, value, count } => {
+                    let (value, count) = integer_operands(mem, window, value, count, "shr")?;
+                    let count = shift_count(count)?;
+                    window[dest as usize].set_to_ptr(TaggedPtr::number(value >> count));
+                }
+
+                // The lesser of two numbers
+                Opcode::Min { dest, reg1, reg2 } => {
+                    let (a, b) = (
+                        window[reg1 as usize].get(mem),
+                        window[reg2 as usize].get(mem),
+                    );
+                    window[dest as usize].set(number_min_max(a, b, Extreme::Least, "min")?);
+                }
+
+                // The greater of two numbers
+                Opcode::Max { dest, reg1, reg2 } => {
+                    let (a, b) = (
+                        window[reg1 as usize].get(mem),
+                        window[reg2 as usize].get(mem),
+                    );
+                    window[dest as usize].set(number_min_max(a, b, Extreme::Greatest, "max")?);
+                }
+
+                // The absolute value of a number
+                Opcode::Abs { dest, reg } => {
+                    let value = number_abs(mem, window[reg as usize].get(mem))?;
+                    window[dest as usize].set(value);
+                }
+
                 // Follow the indirection of an Upvalue to retrieve the value, copy the value to a
                 // local register
                 Opcode::GetUpvalue { dest, src } => {
@@ -691,58 +1608,328 @@ impl Thread {
                         }
                     }
                 }
+
+                // Read the monotonic clock into the `dest` register, in nanoseconds
+                Opcode::ReadClock { dest } => {
+                    window[dest as usize].set_to_ptr(TaggedPtr::number(self.clock_ns()));
+                }
+
+                // Print the wall time elapsed since the clock reading in the `start` register to
+                // the output port
+                Opcode::PrintElapsed { start } => {
+                    self.check_io_allowed("Printing elapsed time")?;
+
+                    let elapsed = match *window[start as usize].get(mem) {
+                        Value::Number(start) => self.clock_ns() - start,
+                        other => {
+                            return Err(err_eval(&format!(
+                                "PrintElapsed start is not a clock reading: {}",
+                                describe(other)
+                            )))
+                        }
+                    };
+
+                    let report = format!("Elapsed time: {:.6} ms\n", elapsed as f64 / 1_000_000.0);
+                    self.output_port(mem).write_str(mem, &report)?;
+                }
+
+                // Check that the parameter in register `reg` is of the type it was annotated with
+                Opcode::CheckType { reg, param_type } => {
+                    let param_type = ParamType::from_id(param_type)
+                        .ok_or_else(|| err_eval("CheckType type id is out of range"))?;
+
+                    let value = window[reg as usize].get(mem);
+                    if !param_type.matches(&value) {
+                        let function = frames.top(mem)?.function.get(mem);
+                        let param_index = (reg as usize - FIRST_ARG_REG) as ArraySize;
+                        let param = IndexedAnyContainer::get(
+                            &*function.param_names(mem),
+                            mem,
+                            param_index,
+                        )?;
+
+                        return Err(err_eval(&format!(
+                            "Parameter {} to {} must be a {}, got {}",
+                            param,
+                            function.name(mem),
+                            param_type.name(),
+                            describe(*value)
+                        )));
+                    }
+                }
             }
 
             Ok(EvalStatus::Pending)
         })
     }
 
-    /// Given ByteCode, execute up to max_instr more instructions
+    /// Execute the next instruction, counting it in the opcode profile and timing it if it is
+    /// sampled
+    #[cfg(feature = "vm-profile")]
+    fn eval_next_instr_profiled<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+    ) -> Result<EvalStatus<'guard>, RuntimeError> {
+        let opcode = self.instr.get(mem).peek_next_opcode(mem)?.name();
+
+        if !self.profile.borrow_mut().count(opcode) {
+            return self.eval_next_instr(mem);
+        }
+
+        let start = Instant::now();
+        let status = self.eval_next_instr(mem);
+        self.profile
+            .borrow_mut()
+            .record_sample(opcode, start.elapsed());
+
+        status
+    }
+
+    /// Execute the next instruction, notifying the debug hook. Returns None if the hook paused
+    /// evaluation before the instruction.
+    fn eval_next_instr_hooked<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        hook: &mut dyn DebugHook,
+    ) -> Result<Option<EvalStatus<'guard>>, RuntimeError> {
+        let frames = self.frames.get(mem);
+        let instr = self.instr.get(mem);
+
+        let depth = frames.length() as usize;
+        let function = frames.top(mem)?.function.get(mem);
+        let base = self.stack_base.get();
+
+        if !self.hook_paused.replace(false) {
+            let opcode = instr.peek_next_opcode(mem)?;
+
+            let ip = instr.get_next_ip();
+            let window_end = (base + function.max_registers()) as usize;
+            let action = self.stack.get(mem).access_slice(mem, |_, full_stack| {
+                let window = &full_stack[base as usize..window_end];
+                hook.on_instruction(mem, function, ip, opcode, window)
+            });
+
+            if action == DebugAction::Pause {
+                self.hook_paused.set(true);
+                return Ok(None);
+            }
+        }
+
+        let status = self.eval_next_instr(mem)?;
+
+        let new_depth = frames.length() as usize;
+        if new_depth > depth {
+            hook.on_call(mem, frames.top(mem)?.function.get(mem), new_depth);
+        } else if new_depth < depth {
+            // the returned value is left in the first register of the returning function's window
+            let value = match status {
+                EvalStatus::Return(value) => value,
+                EvalStatus::Pending => IndexedAnyContainer::get(&*self.stack.get(mem), mem, base)?,
+            };
+            hook.on_return(mem, function, value, new_depth);
+        }
+
+        Ok(Some(status))
+    }
+
+    /// Execute up to max_instr more instructions from the current instruction stream
     fn vm_eval_stream<'guard>(
         &self,
         mem: &'guard MutatorView,
-        code: ScopedPtr<'guard, ByteCode>,
         max_instr: ArraySize,
     ) -> Result<EvalStatus<'guard>, RuntimeError> {
-        let instr = self.instr.get(mem);
-        // TODO this is broken logic, this function shouldn't switch back to this code object every
-        // time it is called
-        instr.switch_frame(code, 0);
-
-        for _ in 0..max_instr {
-            match self.eval_next_instr(mem) {
-                // Evaluation paused or completed without error
-                Ok(exit_cond) => match exit_cond {
-                    EvalStatus::Return(value) => return Ok(EvalStatus::Return(value)),
-                    _ => (),
-                },
-
-                // Evaluation hit an error
-                Err(rt_error) => {
-                    // unwind the stack, printing a trace
-                    let frames = self.frames.get(mem);
-
-                    // Print a stack trace if the error is multiple call frames deep
-                    frames.access_slice(mem, |window| {
-                        if window.len() > 1 {
-                            println!("Error traceback:");
-                        }
+        // A panic in an opcode handler or native function is caught here and treated like any
+        // other evaluation error so that the Thread remains usable
+        let sandbox = self.sandbox.get();
+
+        let batch = catch_unwind(AssertUnwindSafe(|| {
+            // An interrupt request is consumed here, unwinding like any other error
+            if self.interrupt.swap(false, Ordering::SeqCst) {
+                return Err(RuntimeError::new(ErrorKind::Interrupted));
+            }
 
-                        for frame in &window[1..] {
-                            println!("  {}", frame.as_string(mem));
-                        }
-                    });
+            let mut budget = max_instr;
+
+            if let Some(sandbox) = sandbox {
+                if let Some(limit) = sandbox.instruction_limit() {
+                    let remaining = limit.saturating_sub(self.executed.get());
+                    if remaining == 0 {
+                        return Err(err_eval(&format!(
+                            "Sandbox instruction limit of {} reached",
+                            limit
+                        )));
+                    }
+                    budget = min(budget as u64, remaining) as ArraySize;
+                }
 
-                    // Unwind by clearing all frames from the stack
-                    frames.clear(mem)?;
-                    self.stack_base.set(0);
+                if let Some(limit) = sandbox.heap_limit() {
+                    mem.set_allocation_limit(Some(self.heap_base.get() + limit));
+                }
+            }
 
-                    return Err(rt_error);
+            // the debug hook is checked for once per batch rather than per instruction
+            if let Some(hook) = self.debug_hook.borrow_mut().as_mut() {
+                for _ in 0..budget {
+                    match self.eval_next_instr_hooked(mem, hook.as_mut())? {
+                        Some(EvalStatus::Return(value)) => return Ok(EvalStatus::Return(value)),
+                        Some(EvalStatus::Pending) => self.executed.set(self.executed.get() + 1),
+                        None => break,
+                    }
                 }
+
+                return Ok(EvalStatus::Pending);
             }
+
+            for _ in 0..budget {
+                self.executed.set(self.executed.get() + 1);
+
+                #[cfg(feature = "vm-profile")]
+                let status = self.eval_next_instr_profiled(mem)?;
+                #[cfg(not(feature = "vm-profile"))]
+                let status = self.eval_next_instr(mem)?;
+
+                if let EvalStatus::Return(value) = status {
+                    return Ok(EvalStatus::Return(value));
+                }
+            }
+
+            Ok(EvalStatus::Pending)
+        }));
+
+        // The allocation limit only applies to this Thread's evaluation
+        let heap_limit = sandbox.and_then(|sandbox| sandbox.heap_limit());
+        if heap_limit.is_some() {
+            mem.set_allocation_limit(None);
         }
 
-        Ok(EvalStatus::Pending)
+        let batch = batch.unwrap_or_else(|payload| Err(panic_error(payload)));
+
+        let batch = match (batch, heap_limit) {
+            (Err(ref e), Some(limit)) if *e.error_kind() == ErrorKind::OutOfMemory => Err(
+                err_eval(&format!("Sandbox heap limit of {} bytes exceeded", limit)),
+            ),
+            (batch, _) => batch,
+        };
+
+        match batch {
+            // Evaluation paused or completed without error
+            Ok(status) => Ok(status),
+
+            // The program requested an exit: discard execution state without reporting an error
+            Err(rt_error) if matches!(rt_error.error_kind(), ErrorKind::Exit(_)) => {
+                self.reset(mem)?;
+                Err(rt_error)
+            }
+
+            // Evaluation hit an error
+            Err(rt_error) => {
+                if let Some(hook) = self.debug_hook.borrow_mut().as_mut() {
+                    hook.on_error(mem, &rt_error);
+                }
+
+                // unwind the stack, printing a trace
+                let frames = self.frames.get(mem);
+                let current_frame_ip = self.instr.get(mem).get_next_ip();
+
+                // Print a stack trace if the error is multiple call frames deep
+                frames.access_slice(mem, |guard, window| {
+                    // record the location of the failing instruction in the top frame
+                    if let Some(frame) = window.last() {
+                        frame.ip.set(current_frame_ip);
+                    }
+
+                    let lines = window.iter().skip(1).map(|frame| frame.as_string(guard));
+                    match self.traceback.borrow_mut().as_mut() {
+                        Some(traceback) => *traceback = lines.collect(),
+
+                        None => {
+                            flush_stdout().ok();
+                            if window.len() > 1 {
+                                println!("Error traceback:");
+                            }
+
+                            for line in lines {
+                                println!("  {}", line);
+                            }
+                        }
+                    }
+                });
+
+                // Unwind by discarding all execution state, unless it is being kept for inspection
+                if self.keep_failed_frames.get() {
+                    *self.post_mortem.borrow_mut() = Some(self.post_mortem(mem, &rt_error));
+                    self.failed.set(true);
+                } else {
+                    self.reset(mem)?;
+                }
+
+                Err(rt_error)
+            }
+        }
+    }
+
+    /// Return true if an evaluation has been started and has neither completed nor failed
+    pub fn is_evaluating(&self, guard: &dyn MutatorScope) -> bool {
+        self.frames.get(guard).length() > 0 && !self.failed.get()
+    }
+
+    /// Begin evaluating a Function, which should expect no arguments, without executing any
+    /// instructions yet
+    fn start_eval(
+        &self,
+        mem: &MutatorView,
+        function: ScopedPtr<'_, Function>,
+    ) -> Result<(), RuntimeError> {
+        if self.is_evaluating(mem) {
+            return Err(err_eval(
+                "Thread is already evaluating, it must be resumed to completion or reset",
+            ));
+        }
+
+        // the frames of a failed evaluation are only kept until the next one
+        if self.failed.get() {
+            self.reset(mem)?;
+        }
+
+        let base = self.eval_base.get();
+        self.frames.get(mem).push(mem, function, base)?;
+        self.instr.get(mem).switch_frame(function.code(mem), 0);
+        self.stack_base.set(base);
+
+        self.stack
+            .get(mem)
+            .fill(mem, base + function.max_registers(), mem.nil())?;
+
+        self.executed.set(0);
+        self.heap_base.set(mem.allocated_bytes());
+
+        Ok(())
+    }
+
+    /// Begin evaluating a Function, executing at most `budget` instructions. If the result is
+    /// `EvalStatus::Pending`, evaluation can be continued with `resume_with_budget()` or
+    /// abandoned with `reset()`.
+    pub fn eval_with_budget<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        function: ScopedPtr<'guard, Function>,
+        budget: ArraySize,
+    ) -> Result<EvalStatus<'guard>, RuntimeError> {
+        self.start_eval(mem, function)?;
+        self.vm_eval_stream(mem, budget)
+    }
+
+    /// Continue a pending evaluation, executing at most `budget` more instructions
+    pub fn resume_with_budget<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        budget: ArraySize,
+    ) -> Result<EvalStatus<'guard>, RuntimeError> {
+        if !self.is_evaluating(mem) {
+            return Err(err_eval("Thread has no evaluation to resume"));
+        }
+
+        self.vm_eval_stream(mem, budget)
     }
 
     /// Evaluate a Function completely, returning the result. The Function passed in should expect
@@ -752,21 +1939,349 @@ impl Thread {
         mem: &'guard MutatorView,
         function: ScopedPtr<'guard, Function>,
     ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
-        let mut status = EvalStatus::Pending;
+        let mut status = self.eval_with_budget(mem, function, 1024)?;
 
-        let frames = self.frames.get(mem);
-        frames.push(mem, CallFrame::new_main(function))?;
+        loop {
+            match status {
+                EvalStatus::Return(value) => return Ok(value),
+                EvalStatus::Pending => status = self.resume_with_budget(mem, 1024)?,
+            }
+        }
+    }
+
+    /// Evaluate a function value that needs no more arguments completely, returning the result.
+    /// The value may be a Function with no parameters, or a closure or Partial application that
+    /// has all its arguments.
+    pub fn quick_vm_call<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        callable: TaggedScopedPtr<'guard>,
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        match *callable {
+            Value::Function(function) if function.arity() == 0 => self.start_eval(mem, function)?,
+
+            Value::Partial(partial) if partial.arity() == 0 => {
+                self.start_eval(mem, partial.function(mem))?;
+
+                // set up the registers as a Call of the Partial would
+                let stack = self.stack.get(mem);
+                let base = self.stack_base.get();
+                let env = partial.closure_env().get(mem);
+                IndexedAnyContainer::set(&*stack, mem, base + ENV_REG as ArraySize, env)?;
+
+                let args = partial.args(mem);
+                let first_arg = base + FIRST_ARG_REG as ArraySize;
+                for index in 0..partial.used() as ArraySize {
+                    let arg = IndexedAnyContainer::get(&*args, mem, index)?;
+                    IndexedAnyContainer::set(&*stack, mem, first_arg + index, arg)?;
+                }
+            }
 
-        let code = function.code(mem);
+            _ => {
+                return Err(err_eval(&format!(
+                    "Expected a function of no arguments, got {}",
+                    describe(*callable)
+                )))
+            }
+        }
+
+        let mut status = self.vm_eval_stream(mem, 1024)?;
 
-        while status == EvalStatus::Pending {
-            status = self.vm_eval_stream(mem, code, 1024)?;
+        loop {
             match status {
                 EvalStatus::Return(value) => return Ok(value),
-                _ => (),
+                EvalStatus::Pending => status = self.resume_with_budget(mem, 1024)?,
+            }
+        }
+    }
+
+    /// Evaluate a call of a function value with exactly the arguments it takes completely,
+    /// returning the result. The value may be a Function, a closure or Partial application, or a
+    /// native function, which is called directly.
+    pub fn quick_vm_apply<'guard>(
+        &self,
+        mem: &'guard MutatorView,
+        function: TaggedScopedPtr<'guard>,
+        args: &[TaggedCellPtr],
+    ) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+        let arity = match *function {
+            Value::Function(f) => f.arity(),
+            Value::Partial(p) => p.arity(),
+
+            Value::NativeFunction(native) => {
+                if native.is_io() {
+                    self.check_io_allowed(&format!("Function {}", native.name()))?;
+                }
+                return native.call(mem, self, args);
+            }
+
+            other => {
+                return Err(err_eval(&format!(
+                    "Type is not callable: {}",
+                    describe(other)
+                )))
+            }
+        };
+
+        if args.len() != arity as usize {
+            return Err(err_eval(&format!(
+                "Function {} expected {} arguments, got {}",
+                function,
+                arity,
+                args.len()
+            )));
+        }
+
+        let saturated = match *function {
+            Value::Partial(p) => Partial::alloc_clone(mem, p, args)?,
+            Value::Function(f) => Partial::alloc(mem, f, None, args)?,
+            _ => unreachable!(),
+        };
+
+        self.quick_vm_call(mem, saturated.as_tagged(mem))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+
+    #[test]
+    fn eval_with_budget_resumes() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let function = |code| compile(mem, parse(mem, code)?);
+
+                t.quick_vm_eval(mem, function("(def second (l) (car (cdr l)))")?)?;
+
+                // one instruction at a time until completion
+                let code = function("(cons (second '(a b c)) (second '(d e f)))")?;
+                let mut status = t.eval_with_budget(mem, code, 1)?;
+                let mut batches = 1;
+
+                let result = loop {
+                    match status {
+                        EvalStatus::Return(value) => break value,
+                        EvalStatus::Pending => {
+                            assert!(t.is_evaluating(mem));
+                            status = t.resume_with_budget(mem, 1)?;
+                            batches += 1;
+                        }
+                    }
+                };
+
+                assert!(batches > 1);
+                assert!(format!("{}", result) == "(b . e)");
+                assert!(!t.is_evaluating(mem));
+                assert!(t.resume_with_budget(mem, 1).is_err());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn eval_with_budget_abandoned() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let function = |code| compile(mem, parse(mem, code)?);
+
+                t.quick_vm_eval(mem, function("(def spin () (spin))")?)?;
+
+                // a function that never returns stays pending
+                let status = t.eval_with_budget(mem, function("(spin)")?, 100)?;
+                assert!(status == EvalStatus::Pending);
+                assert!(t.resume_with_budget(mem, 100)? == EvalStatus::Pending);
+
+                // a second evaluation cannot start until the first is abandoned
+                assert!(t.quick_vm_eval(mem, function("'a")?).is_err());
+
+                t.reset(mem)?;
+                assert!(t.quick_vm_eval(mem, function("'a")?)? == mem.lookup_sym("a"));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn stack_grows_by_register_window() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                eval("(def walk (l) (cond (nil? l) 'done true (walk (cdr l))))")?;
+
+                let walk = match *t.lookup_global(mem, "walk").unwrap() {
+                    Value::Function(f) => f,
+                    _ => panic!("expected a Function"),
+                };
+                let window = walk.max_registers();
+                assert!(window > FIRST_ARG_REG as ArraySize && window < 16);
+
+                // a hundred nested calls, each frame based a few registers above its caller's
+                let list = format!("'({})", "a ".repeat(100));
+                assert!(eval(&format!("(walk {})", list))? == mem.lookup_sym("done"));
+
+                let length = t.stack.get(mem).length();
+                assert!(length >= 100 * FIRST_ARG_REG as ArraySize);
+                assert!(length < 100 * window);
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn call_frame_slots_are_reused() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                eval("(def walk (l) (cond (nil? l) 'done true (walk (cdr l))))")?;
+
+                let deep_walk = format!("(walk '({}))", "a ".repeat(100));
+                eval(&deep_walk)?;
+
+                // the main frame and a hundred and one nested calls
+                let frames = t.frames.get(mem);
+                assert!(frames.length() == 0);
+                let slots = frames.slots.length();
+                assert!(slots == 102);
+
+                // shallower and equally deep calls reuse the same slots
+                eval("(walk '(a b c))")?;
+                eval(&deep_walk)?;
+                assert!(frames.slots.length() == slots);
+
+                // popped frames are not visible
+                assert!(frames.top(mem).is_err());
+                assert!(frames.access_slice(mem, |_, frames| frames.is_empty()));
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn saturated_partial_and_closure_calls() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+                let expect = |code, result| -> Result<(), RuntimeError> {
+                    let value = eval(code)?;
+                    assert!(format!("{}", value) == result);
+                    Ok(())
+                };
+
+                eval("(def list3 (a b c) (cons a (cons b (cons c nil))))")?;
+                eval("(def closure (a) (lambda (b c) (list3 a b c)))")?;
+
+                // a closure has no applied args, only an environment
+                expect("((closure 'x) 'y 'z)", "(x y z)")?;
+
+                // applied args are placed ahead of the call args
+                expect("((list3 'x) 'y 'z)", "(x y z)")?;
+                expect("((list3 'x 'y) 'z)", "(x y z)")?;
+                expect("(((list3 'x) 'y) 'z)", "(x y z)")?;
+
+                // a saturating call leaves the Partial unchanged for the next call
+                eval("(set 'p (list3 'x))")?;
+                expect("(p 'y 'z)", "(x y z)")?;
+                expect("(p 'z 'y)", "(x z y)")?;
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn type_errors_describe_the_value() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+                let error = |code| match eval(code) {
+                    Err(e) => format!("{}", e),
+                    Ok(_) => panic!("expected an error"),
+                };
+
+                eval("(set 'n 3)")?;
+                assert!(
+                    error("(car n)")
+                        == "Evaluation error: Parameter to FirstOfPair is not a list: 3 (integer)"
+                );
+                assert!(
+                    error("(bit-and n \"three\")")
+                        == "Evaluation error: Parameters to bit-and must be integers, got \"three\" (text)"
+                );
+
+                // long values are cut short
+                let list = format!("(set 'l '({}))", "a ".repeat(100));
+                eval(&list)?;
+                let message = error("(l)");
+                assert!(message.starts_with("Evaluation error: Type is not callable: (a a a"));
+                assert!(message.ends_with("a a\u{2026} (pair)"));
+                assert!(message.len() < 120);
+
+                Ok(())
             }
         }
 
-        Err(err_eval("Unexpected end of evaluation"))
+        mem.mutate(&Test {}, ()).unwrap();
     }
 }
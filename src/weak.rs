@@ -0,0 +1,202 @@
+/// Weak references and the builtin functions that operate on them.
+///
+/// A `WeakRef` refers to a value without keeping it alive: its target is not traced, and when the
+/// target is collected the reference is cleared to nil. A Dict allocated with `Dict::alloc_weak()`
+/// holds its values the same way and loses an entry when its value is collected. Caches that
+/// should not pin what they hold can be built on either.
+///
+/// There is no collector yet, so nothing is cleared in practice. `Memory::clear_weak_refs()` is the
+/// step a collector runs once marking has found the live objects.
+use std::fmt;
+
+use crate::error::{err_eval, RuntimeError};
+use crate::memory::MutatorView;
+use crate::native_module;
+use crate::printer::Print;
+use crate::safeptr::{MutatorScope, ScopedPtr, TaggedCellPtr, TaggedScopedPtr};
+use crate::taggedptr::{TaggedPtr, Value};
+use crate::vm::Thread;
+
+/// A weak reference object type
+pub struct WeakRef {
+    /// The referenced value, or nil once it has been collected
+    target: TaggedCellPtr,
+}
+
+impl WeakRef {
+    /// Allocate a weak reference to the given value on the heap
+    pub fn alloc<'guard>(
+        mem: &'guard MutatorView,
+        target: TaggedScopedPtr<'guard>,
+    ) -> Result<ScopedPtr<'guard, WeakRef>, RuntimeError> {
+        mem.alloc(WeakRef {
+            target: TaggedCellPtr::new_with(target),
+        })
+    }
+
+    /// Return the referenced value, or nil if it has been collected
+    pub fn get<'guard>(&self, guard: &'guard dyn MutatorScope) -> TaggedScopedPtr<'guard> {
+        self.target.get(guard)
+    }
+
+    /// Return the referenced value as a raw pointer, for the collector
+    pub(crate) fn target(&self) -> TaggedPtr {
+        self.target.get_ptr()
+    }
+
+    /// Forget the referenced value once it has been collected
+    pub(crate) fn clear(&self) {
+        self.target.set_to_nil();
+    }
+}
+
+impl Print for WeakRef {
+    /// Prints a string representation of the reference without printing the target, which may be
+    /// large or refer back to the reference
+    fn print<'guard>(
+        &self,
+        _guard: &'guard dyn MutatorScope,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self.target.is_nil() {
+            true => write!(f, "(WeakRef cleared)"),
+            false => write!(f, "(WeakRef)"),
+        }
+    }
+}
+
+/// (weak-ref x) - return a weak reference to x
+fn weak_ref<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    Ok(WeakRef::alloc(mem, args[0].get(mem))?.as_tagged(mem))
+}
+
+/// (weak-get w) - return the value referred to by a weak reference, or nil if it has been
+/// collected
+fn weak_get<'guard>(
+    mem: &'guard MutatorView,
+    _thread: &Thread,
+    args: &[TaggedCellPtr],
+) -> Result<TaggedScopedPtr<'guard>, RuntimeError> {
+    match *args[0].get(mem) {
+        Value::WeakRef(weak) => Ok(weak.get(mem)),
+        _ => Err(err_eval("Parameter w to weak-get must be a weak reference")),
+    }
+}
+
+native_module! {
+    /// The weak reference builtin functions
+    pub WEAK_MODULE = "weak" {
+        "weak-ref" => weak_ref(1),
+        "weak-get" => weak_get(1),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::containers::HashIndexedAnyContainer;
+    use crate::dict::Dict;
+    use crate::memory::{Memory, Mutator};
+    use crate::parser::parse;
+    use crate::root::Root;
+
+    #[test]
+    fn weak_ref_builtins() {
+        let mem = Memory::new();
+
+        struct Test {}
+        impl Mutator for Test {
+            type Input = ();
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, _input: ()) -> Result<(), RuntimeError> {
+                let t = Thread::alloc(mem)?;
+                let eval = |code| t.quick_vm_eval(mem, compile(mem, parse(mem, code)?)?);
+
+                eval("(set 'w (weak-ref '(a b)))")?;
+                assert!(format!("{}", eval("w")?) == "(WeakRef)");
+                assert!(format!("{}", eval("(weak-get w)")?) == "(a b)");
+
+                assert!(eval("(weak-get (weak-ref nil))")? == mem.nil());
+                assert!(eval("(weak-get '(a b))").is_err());
+
+                Ok(())
+            }
+        }
+
+        mem.mutate(&Test {}, ()).unwrap();
+    }
+
+    #[test]
+    fn weak_refs_cleared_when_target_is_not_live() {
+        let mem = Memory::new();
+
+        struct Make {}
+        impl Mutator for Make {
+            type Input = ();
+            type Output = (Root, Root, TaggedPtr);
+
+            fn run(
+                &self,
+                mem: &MutatorView,
+                _input: (),
+            ) -> Result<(Root, Root, TaggedPtr), RuntimeError> {
+                let kept = mem.text("kept")?;
+                let lost = mem.text("lost")?;
+
+                let weak = WeakRef::alloc(mem, lost)?;
+
+                let dict = Dict::alloc_weak(mem)?;
+                assert!(dict.has_weak_values());
+                dict.assoc(mem, mem.lookup_sym("kept"), kept)?;
+                dict.assoc(mem, mem.lookup_sym("lost"), lost)?;
+
+                Ok((
+                    mem.root(weak.as_tagged(mem)),
+                    mem.root(dict.as_tagged(mem)),
+                    lost.get_ptr(),
+                ))
+            }
+        }
+
+        struct Check {}
+        impl Mutator for Check {
+            type Input = (Root, Root);
+            type Output = ();
+
+            fn run(&self, mem: &MutatorView, roots: (Root, Root)) -> Result<(), RuntimeError> {
+                let (weak, dict) = roots;
+
+                assert!(format!("{}", weak.get(mem)) == "(WeakRef cleared)");
+                match *weak.get(mem) {
+                    Value::WeakRef(weak) => assert!(weak.get(mem) == mem.nil()),
+                    _ => panic!("expected a WeakRef"),
+                }
+
+                match *dict.get(mem) {
+                    Value::Dict(dict) => {
+                        assert!(
+                            format!("{}", dict.lookup(mem, mem.lookup_sym("kept"))?) == "\"kept\""
+                        );
+                        assert!(!dict.exists(mem, mem.lookup_sym("lost"))?);
+                    }
+                    _ => panic!("expected a Dict"),
+                }
+
+                Ok(())
+            }
+        }
+
+        let (weak, dict, lost) = mem.mutate(&Make {}, ()).unwrap();
+
+        // stand in for a collection that found everything but the "lost" text live
+        mem.clear_weak_refs(|ptr| ptr != lost);
+
+        mem.mutate(&Check {}, (weak, dict)).unwrap();
+    }
+}
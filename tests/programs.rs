@@ -0,0 +1,123 @@
+//! Runs each example program in `tests/programs` and compares its output with the paired
+//! `.expected` file.
+//!
+//! The output of a program is everything it prints, with the result of each top level expression
+//! printed after it as the REPL would show it. If evaluation fails, the error is printed and the
+//! rest of the program is not evaluated.
+//!
+//! To add a case, write `tests/programs/<name>.lisp` and run the tests with `EVALRUS_BLESS=1` set
+//! to write `<name>.expected` from the current output. Check the new file before committing it.
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use evalrus::compiler::compile;
+use evalrus::error::RuntimeError;
+use evalrus::lexer::lex_reader;
+use evalrus::memory::{Memory, Mutator, MutatorView};
+use evalrus::parser::Parser;
+use evalrus::port::Port;
+use evalrus::vm::Thread;
+
+/// Mutator that evaluates a source file, returning program output and results as a string
+struct RunProgram {}
+
+impl Mutator for RunProgram {
+    type Input = PathBuf;
+    type Output = String;
+
+    fn run(&self, mem: &MutatorView, source: Self::Input) -> Result<String, RuntimeError> {
+        let thread = Thread::alloc(mem)?;
+        let port = Port::open_output_string(mem)?;
+        thread.set_output_port(port);
+
+        let mut parser = Parser::new(lex_reader(File::open(source)?));
+
+        let mut eval_all = || -> Result<(), RuntimeError> {
+            while let Some(expr) = parser.next_expr(mem)? {
+                let value = thread.quick_vm_eval(mem, compile(mem, expr)?)?;
+                port.write_str(mem, &format!("{:#}\n", value))?;
+            }
+            Ok(())
+        };
+
+        if let Err(e) = eval_all() {
+            port.write_str(mem, &format!("error: {}\n", e))?;
+        }
+
+        port.output_string(mem)
+    }
+}
+
+/// Run a program in a new Memory instance and return its output
+fn run_program(source: &Path) -> String {
+    let mem = Memory::new();
+    mem.mutate(&RunProgram {}, source.to_path_buf()).unwrap()
+}
+
+/// Describe the lines that differ between the expected and actual output
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+
+    let mut lines = Vec::new();
+    for i in 0..expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(i), actual.get(i));
+        if e != a {
+            lines.push(format!("line {}:", i + 1));
+            if let Some(e) = e {
+                lines.push(format!("  - {}", e));
+            }
+            if let Some(a) = a {
+                lines.push(format!("  + {}", a));
+            }
+        }
+    }
+
+    lines.join("\n")
+}
+
+#[test]
+fn programs() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let bless = env::var_os("EVALRUS_BLESS").is_some();
+
+    let mut sources: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "lisp"))
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty());
+
+    let mut failures = Vec::new();
+
+    for source in &sources {
+        let actual = run_program(source);
+        let expected_path = source.with_extension("expected");
+
+        if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+
+        match fs::read_to_string(&expected_path) {
+            Ok(expected) if expected == actual => (),
+            Ok(expected) => failures.push(format!(
+                "{}\n{}",
+                source.display(),
+                diff(&expected, &actual)
+            )),
+            Err(_) => failures.push(format!("{}\nno .expected file", source.display())),
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!(
+            "{} of {} programs gave unexpected output:\n\n{}",
+            failures.len(),
+            sources.len(),
+            failures.join("\n\n")
+        );
+    }
+}
@@ -0,0 +1,11 @@
+(def make-pairer (a)
+  (lambda (b) (cons a b)))
+
+(def pair-with-x (b) ((make-pairer 'x) b))
+(pair-with-x 'y)
+(pair-with-x '(z))
+
+(def triple-with (a b)
+  (lambda (c) (cons a (cons b c))))
+
+((triple-with 'p 'q) '(r))
@@ -0,0 +1,10 @@
+(def classify (x)
+  (cond (nil? x) 'empty
+        (atom? x) 'atom
+        true 'pair))
+
+(classify nil)
+(classify 'a)
+(classify '(a b))
+
+(cond (nil? 'a) 'never)
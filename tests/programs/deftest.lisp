@@ -0,0 +1,12 @@
+(def second (l) (car (cdr l)))
+
+(deftest second-of-two
+  (assert-eq (second '(a b)) 'b))
+
+(deftest second-of-one
+  (assert (nil? (second '(a))) "second of a one element list is nil"))
+
+(deftest second-of-symbol
+  (assert-eq (second 'a) 'a))
+
+(run-tests)
@@ -0,0 +1,3 @@
+(cons 'a 'b)
+(car 'not-a-pair)
+(cons 'never 'evaluated)
@@ -0,0 +1,9 @@
+(let ((a 'x)
+      (b (cons a '(y))))
+  (cons a b))
+
+(def name () 'global)
+(let ((name 'local)) name)
+(name)
+
+(let (((first second) '(1 2))) (cons second first))
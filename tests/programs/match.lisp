@@ -0,0 +1,11 @@
+(def describe (x)
+  (match x
+    (nil 'nothing)
+    ((a) (cons 'one a))
+    ((a b) (cons 'two (cons a b)))
+    (_ 'many)))
+
+(describe nil)
+(describe '(p))
+(describe '(p q))
+(describe '(p q r))
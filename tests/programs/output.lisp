@@ -0,0 +1,5 @@
+(println "hello" 'world)
+(println (format "{} and {}" 'a 2))
+(symbol->string 'text)
+#u8(1 2 255)
+(bytes->string (string->bytes "round trip"))